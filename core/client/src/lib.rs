@@ -1 +1,2 @@
+pub mod now_playing;
 pub mod track_list;
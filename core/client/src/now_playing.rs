@@ -0,0 +1,148 @@
+use std::time::Duration;
+
+use hsm_ipc::{PlaybackState, Track};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum FormatError {
+  #[error("Unknown placeholder {{{0}}}, valid placeholders are: {list}", list = NowPlaying::PLACEHOLDERS.join(", "))]
+  UnknownPlaceholder(String),
+
+  #[error("Placeholder starting at byte {0} is never closed, use {{{{ for a literal brace")]
+  UnclosedPlaceholder(usize),
+}
+
+/// `1:02:03`, or `2:03` if there are no hours
+fn format_clock(duration: Duration) -> String {
+  let secs = duration.as_secs();
+  let (hours, minutes, secs) = (secs / 3600, secs / 60 % 60, secs % 60);
+
+  if hours > 0 {
+    format!("{hours}:{minutes:02}:{secs:02}")
+  } else {
+    format!("{minutes}:{secs:02}")
+  }
+}
+
+/// The values that can be substituted into a now playing format string
+#[derive(Debug, Clone, Copy)]
+pub struct NowPlaying<'a> {
+  pub track: Option<&'a Track>,
+  pub playback_state: PlaybackState,
+  /// Position in the current track, `{position}` is missing if this is `None`
+  pub position: Option<Duration>,
+  /// `{volume}` is missing if this is `None`
+  pub volume: Option<f32>,
+}
+
+impl<'a> NowPlaying<'a> {
+  pub const PLACEHOLDERS: &'static [&'static str] = &[
+    "title",
+    "artist",
+    "album",
+    "track_number",
+    "filename",
+    "position",
+    "duration",
+    "volume",
+    "state",
+  ];
+
+  pub fn new(track: Option<&'a Track>, playback_state: PlaybackState) -> Self {
+    Self {
+      track,
+      playback_state,
+      position: None,
+      volume: None,
+    }
+  }
+
+  /// Returns `None` if the value is missing, such as when a track has no title tag
+  fn placeholder(&self, name: &str) -> Result<Option<String>, FormatError> {
+    let track = self.track;
+    let metadata = track.map(|track| &track.metadata);
+
+    let value = match name {
+      "title" => metadata.and_then(|metadata| metadata.title_or_inferred().map(Into::into)),
+      "artist" => metadata
+        .map(|metadata| metadata.artists_or_inferred())
+        .filter(|artists| !artists.is_empty())
+        .map(|artists| artists.join(", ")),
+      "album" => metadata.and_then(|metadata| metadata.album_or_inferred().map(Into::into)),
+      "track_number" => metadata
+        .and_then(|metadata| metadata.track_number_or_inferred())
+        .map(|track_number| track_number.to_string()),
+      "filename" => track
+        .and_then(|track| track.file_path.file_name())
+        .map(|file_name| file_name.to_string_lossy().into_owned()),
+      "position" => self.position.filter(|_| track.is_some()).map(format_clock),
+      "duration" => track
+        .and_then(|track| track.total_duration)
+        .map(format_clock),
+      "volume" => self
+        .volume
+        .map(|volume| format!("{}%", (volume * 100.0).round())),
+      "state" => Some(
+        match self.playback_state {
+          PlaybackState::Playing => "playing",
+          PlaybackState::Paused => "paused",
+          PlaybackState::Stopped => "stopped",
+        }
+        .into(),
+      ),
+      _ => return Err(FormatError::UnknownPlaceholder(name.into())),
+    };
+
+    Ok(value)
+  }
+
+  /// Substitutes `{placeholder}`s in `format`
+  ///
+  /// `{a|b}` uses `b` if `a` is missing, and missing values are replaced with nothing.
+  /// `{{` and `}}` are literal braces.
+  pub fn format(&self, format: &str) -> Result<String, FormatError> {
+    let mut output = String::with_capacity(format.len());
+    let mut rest = format;
+
+    while let Some(start) = rest.find(['{', '}']) {
+      output.push_str(&rest[..start]);
+      let brace = &rest[start..];
+
+      if brace.starts_with("{{") || brace.starts_with("}}") {
+        output.push_str(&brace[..1]);
+        rest = &brace[2..];
+        continue;
+      }
+
+      if let Some(after) = brace.strip_prefix('}') {
+        output.push('}');
+        rest = after;
+        continue;
+      }
+
+      let Some(end) = brace.find('}') else {
+        return Err(FormatError::UnclosedPlaceholder(format.len() - brace.len()));
+      };
+
+      let mut value = None;
+      for name in brace[1..end].split('|') {
+        // Check every alternative so typos are reported even if an earlier one is present
+        let alternative = self.placeholder(name.trim())?;
+        value = value.or(alternative);
+      }
+
+      output.push_str(value.as_deref().unwrap_or_default());
+      rest = &brace[end + 1..];
+    }
+
+    output.push_str(rest);
+    Ok(output)
+  }
+
+  /// Checks that `format` only uses known placeholders
+  pub fn validate_format(format: &str) -> Result<(), FormatError> {
+    NowPlaying::new(None, PlaybackState::Stopped)
+      .format(format)
+      .map(|_| ())
+  }
+}
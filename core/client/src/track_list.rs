@@ -1,7 +1,18 @@
 use std::{iter::FusedIterator, ops::Index};
 
-use hsm_ipc::{Track, TrackListSnapshot, TrackListUpdate};
+use hsm_ipc::{Track, TrackListDiff, TrackListSnapshot, TrackListUpdate};
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// Why a `TrackList` could not be brought up to date, after which it needs to be synced again
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+pub enum SyncError {
+  #[error("The server no longer has the changes since this track list's generation")]
+  DiffTooOld,
+
+  #[error("Track list update does not match the known track list")]
+  OutOfSync,
+}
 
 /// A representation of the player's track list
 /// `track_list.len()` will always be equal to `shuffle_indicies.len()`
@@ -10,6 +21,9 @@ pub struct TrackList {
   track_list: Vec<Track>,
   shuffle_indicies: Vec<usize>,
   needs_sync: bool,
+  /// The server's track list generation this was last synced with
+  #[serde(default)]
+  generation: u64,
 }
 
 impl TrackList {
@@ -18,6 +32,7 @@ impl TrackList {
       track_list: Vec::new(),
       shuffle_indicies: Vec::new(),
       needs_sync: false,
+      generation: 0,
     }
   }
 
@@ -28,6 +43,7 @@ impl TrackList {
       track_list: snapshot.track_list,
       shuffle_indicies: snapshot.shuffle_indicies,
       needs_sync: false,
+      generation: snapshot.generation,
     }
   }
 
@@ -36,6 +52,11 @@ impl TrackList {
     self.track_list.len()
   }
 
+  /// Use as `since_generation` in `QueryTrackListDiff`
+  pub fn generation(&self) -> u64 {
+    self.generation
+  }
+
   pub fn needs_sync(&self) -> bool {
     !self.needs_sync
   }
@@ -49,11 +70,34 @@ impl TrackList {
     self.track_list = snapshot.track_list;
     self.shuffle_indicies = snapshot.shuffle_indicies;
     self.needs_sync = false;
+    self.generation = snapshot.generation;
+  }
+
+  /// Applies the updates from a `QueryTrackListDiff` reply
+  ///
+  /// Returns a `SyncError` and sets `needs_sync` if the diff is `TooOld` or an update fails to apply,
+  /// in which case the whole track list should be queried again
+  pub fn apply_diff(&mut self, diff: TrackListDiff) -> Result<(), SyncError> {
+    let TrackListDiff::Updates {
+      generation,
+      updates,
+    } = diff
+    else {
+      self.needs_sync = true;
+      return Err(SyncError::DiffTooOld);
+    };
+
+    for update in updates {
+      self.update(update)?;
+    }
+
+    self.generation = generation;
+    Ok(())
   }
 
   /// Attempts to update the `TrackList` state based on `update`
   ///
-  /// Returns `Err` if the update would cause the `track_list` `shuffle_indicies`
+  /// Returns `SyncError::OutOfSync` if the update would cause the `track_list` `shuffle_indicies`
   /// to have different lengths. This usually indicates that the client is out of sync with the server.
   ///
   /// If this occurs `needs_sync` will be set to true, however incorrect updates
@@ -61,7 +105,7 @@ impl TrackList {
   ///
   /// `TrackListUpdate::Replace` and `TrackListUpdate::Clear` *will* reset `needs_sync`
   /// because they specify the entire known state of the `TrackList`
-  pub fn update(&mut self, update: TrackListUpdate) -> Result<(), ()> {
+  pub fn update(&mut self, update: TrackListUpdate) -> Result<(), SyncError> {
     debug_assert_eq!(self.track_list.len(), self.shuffle_indicies.len());
 
     match update {
@@ -73,7 +117,7 @@ impl TrackList {
         let new_len = self.track_list.len() + tracks.len();
         if new_len != new_shuffle_indicies.len() {
           self.needs_sync = true;
-          return Err(());
+          return Err(SyncError::OutOfSync);
         }
 
         self.track_list.splice(index..index, tracks);
@@ -84,16 +128,16 @@ impl TrackList {
         removed_indicies,
         new_shuffle_indicies,
       } => {
-        let new_len = self.track_list.len() - removed_indicies.len();
-        if new_len != new_shuffle_indicies.len() {
+        let new_len = self.track_list.len().checked_sub(removed_indicies.len());
+        if new_len != Some(new_shuffle_indicies.len()) {
           self.needs_sync = true;
-          return Err(());
+          return Err(SyncError::OutOfSync);
         }
 
         let mut index = 0;
         self
           .track_list
-          .retain(|_| (removed_indicies.contains(&index), index += 1).0);
+          .retain(|_| (!removed_indicies.contains(&index), index += 1).0);
         self.shuffle_indicies = new_shuffle_indicies;
       }
 
@@ -112,11 +156,31 @@ impl TrackList {
       } => {
         if self.track_list.len() != new_shuffle_indicies.len() {
           self.needs_sync = true;
-          return Err(());
+          return Err(SyncError::OutOfSync);
         }
 
         self.shuffle_indicies = new_shuffle_indicies;
       }
+
+      TrackListUpdate::Reorder {
+        new_order,
+        new_shuffle_indicies,
+      } => {
+        let len = self.track_list.len();
+        if new_order.len() != len
+          || new_shuffle_indicies.len() != len
+          || new_order.iter().any(|&index| index >= len)
+        {
+          self.needs_sync = true;
+          return Err(SyncError::OutOfSync);
+        }
+
+        self.track_list = new_order
+          .iter()
+          .map(|&index| self.track_list[index].clone())
+          .collect();
+        self.shuffle_indicies = new_shuffle_indicies;
+      }
     }
 
     debug_assert_eq!(self.track_list.len(), self.shuffle_indicies.len());
@@ -165,3 +229,84 @@ impl<'a> Iterator for TrackListIter<'a> {
 }
 
 impl<'a> FusedIterator for TrackListIter<'a> {}
+
+#[cfg(test)]
+mod tests {
+  use hsm_ipc::TrackMetadata;
+
+  use super::*;
+
+  fn track(title: &str) -> Track {
+    Track {
+      file_path: format!("/music/{title}.flac").into(),
+      total_duration: None,
+      metadata: TrackMetadata {
+        title: Some(title.to_owned()),
+        ..Default::default()
+      },
+    }
+  }
+
+  fn track_list(titles: &[&str], shuffle_indicies: Vec<usize>) -> TrackList {
+    TrackList::from_snapshot(TrackListSnapshot {
+      track_list: titles.iter().map(|title| track(title)).collect(),
+      shuffle_indicies,
+      instances: Vec::new(),
+      generation: 1,
+    })
+  }
+
+  fn play_order(track_list: &TrackList) -> Vec<&str> {
+    track_list
+      .iter()
+      .map(|track| track.metadata.title.as_deref().unwrap())
+      .collect()
+  }
+
+  #[test]
+  fn removes_tracks() {
+    let mut track_list = track_list(&["a", "b", "c", "d"], vec![3, 1, 0, 2]);
+
+    let update = TrackListUpdate::Remove {
+      removed_indicies: vec![1, 2],
+      new_shuffle_indicies: vec![1, 0],
+    };
+    assert_eq!(track_list.update(update), Ok(()));
+
+    assert_eq!(track_list.len(), 2);
+    assert_eq!(play_order(&track_list), ["d", "a"]);
+  }
+
+  #[test]
+  fn rejects_removal_of_unknown_tracks() {
+    let mut track_list = track_list(&["a", "b"], vec![0, 1]);
+
+    // More tracks than the client knows of
+    let update = TrackListUpdate::Remove {
+      removed_indicies: vec![0, 1, 2],
+      new_shuffle_indicies: Vec::new(),
+    };
+    assert_eq!(track_list.update(update), Err(SyncError::OutOfSync));
+
+    // The new shuffle indicies don't match the remaining tracks
+    let update = TrackListUpdate::Remove {
+      removed_indicies: vec![0],
+      new_shuffle_indicies: vec![0, 1],
+    };
+    assert_eq!(track_list.update(update), Err(SyncError::OutOfSync));
+
+    assert_eq!(play_order(&track_list), ["a", "b"]);
+  }
+
+  #[test]
+  fn needs_sync_after_a_diff_that_is_too_old() {
+    let mut track_list = track_list(&["a", "b"], vec![0, 1]);
+
+    assert_eq!(
+      track_list.apply_diff(TrackListDiff::TooOld),
+      Err(SyncError::DiffTooOld)
+    );
+    assert_eq!(track_list.generation(), 1);
+    assert_eq!(play_order(&track_list), ["a", "b"]);
+  }
+}
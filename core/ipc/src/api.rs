@@ -1,12 +1,16 @@
-use std::{fmt::Debug, time::Duration};
+use std::{fmt::Debug, path::PathBuf, time::Duration};
 
-use serde::{Serialize, de::DeserializeOwned};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
 
 pub mod client;
 pub mod requests;
+#[cfg(feature = "schema")]
+mod schema;
 pub mod server;
 mod types;
 
+#[cfg(feature = "schema")]
+pub use schema::{ApiSchema, EventSchema, FieldSchema, PayloadShape, RequestSchema, schema};
 pub use types::*;
 
 pub(crate) mod private {
@@ -41,11 +45,367 @@ where
 = Result<<R as Request>::Response, String>;
 
 /// An event than can be sent from the serverasynchronously at any time.
-#[derive(Debug, Clone)]
+///
+/// Events caused by a request are queued for plugins before the request's reply is sent,
+/// so an event received after a reply that shows an older state is stale.
+/// Requests merged by the server, such as bursts of `SetVolume`, are replied to after the merged change's event.
+///
+/// Clients connected to the ipc socket can receive events with `Subscribe`.
+/// Subscribed clients receive events as json, so variant and field names must not be renamed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+// Tracks are large, but boxing them would only save copying a few hundred bytes per event
+#[allow(clippy::large_enum_variant)]
 pub enum Event {
   PlaybackStateChanged(PlaybackState),
+  PlaybackStopped(StopReason),
+  /// The current track changed, `None` if the track list is empty
+  TrackChanged(Option<Track>),
+  /// The playing track was removed from the track list
+  ///
+  /// If `detached` is true it keeps playing until it ends, and `TrackChanged` is sent when the entry that took its place starts
+  CurrentTrackRemoved {
+    track: Track,
+    detached: bool,
+  },
+  /// The current track will finish in `remaining`, sent once per track shortly before it ends
+  TrackEnding {
+    remaining: Duration,
+  },
+  /// A line of the current track's synced lyrics started at `time`
+  LyricLine {
+    time: Duration,
+    text: String,
+  },
+  /// Sent a few times per second while a `LoadTracks` request is scanning for tracks
+  LoadProgress {
+    /// The `progress_id` of the `LoadTracks` request
+    progress_id: Option<u64>,
+    loaded: usize,
+    errored: usize,
+    scanning: Option<PathBuf>,
+  },
+  /// Sent once for every change to the track list, with the updates that make it
+  ///
+  /// The updates can be applied the same way as a `TrackListDiff`
+  TrackListChanged {
+    generation: u64,
+    updates: Vec<TrackListUpdate>,
+  },
   LoopModeChanged(LoopMode),
+  /// Sent whenever a change to the player's settings changes what happens when the current track ends
+  EndBehaviorChanged(EndBehavior),
+  /// Stop after current was turned on or off, including when it turns itself off by pausing at the end of a track
+  StopAfterCurrentChanged(bool),
   ShuffleChanged(bool),
+  QueueFilterChanged(Option<FilterExpr>),
   VolumeChanged(f32),
+  RateChanged(f32),
   Seeked(Duration),
+  OutputFormatChanged(OutputInfo),
+  /// The output stream stopped working, such as when the sound server restarted, and was opened again
+  ///
+  /// Playback continues from the position it stopped at, which is sent in a `Seeked` event
+  OutputReconnected(OutputInfo),
+  /// The player stopped handling the end of tracks for `waited`, and the server is exiting so it can be restarted
+  PlayerStalled {
+    waited: Duration,
+  },
+  /// A `PlayOneshot` file started playing, the current track is held until `InterruptFinished`
+  InterruptStarted {
+    path: PathBuf,
+  },
+  InterruptFinished,
+}
+
+/// An `Event` without its data, used to choose which events to `Subscribe` to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EventKind {
+  PlaybackStateChanged,
+  PlaybackStopped,
+  TrackChanged,
+  CurrentTrackRemoved,
+  TrackEnding,
+  LyricLine,
+  LoadProgress,
+  TrackListChanged,
+  LoopModeChanged,
+  EndBehaviorChanged,
+  StopAfterCurrentChanged,
+  ShuffleChanged,
+  QueueFilterChanged,
+  VolumeChanged,
+  RateChanged,
+  Seeked,
+  OutputFormatChanged,
+  OutputReconnected,
+  PlayerStalled,
+  InterruptStarted,
+  InterruptFinished,
+}
+
+impl EventKind {
+  pub const ALL: &'static [EventKind] = &[
+    Self::PlaybackStateChanged,
+    Self::PlaybackStopped,
+    Self::TrackChanged,
+    Self::CurrentTrackRemoved,
+    Self::TrackEnding,
+    Self::LyricLine,
+    Self::LoadProgress,
+    Self::TrackListChanged,
+    Self::LoopModeChanged,
+    Self::EndBehaviorChanged,
+    Self::StopAfterCurrentChanged,
+    Self::ShuffleChanged,
+    Self::QueueFilterChanged,
+    Self::VolumeChanged,
+    Self::RateChanged,
+    Self::Seeked,
+    Self::OutputFormatChanged,
+    Self::OutputReconnected,
+    Self::PlayerStalled,
+    Self::InterruptStarted,
+    Self::InterruptFinished,
+  ];
+}
+
+impl Event {
+  pub fn kind(&self) -> EventKind {
+    match self {
+      Self::PlaybackStateChanged(_) => EventKind::PlaybackStateChanged,
+      Self::PlaybackStopped(_) => EventKind::PlaybackStopped,
+      Self::TrackChanged(_) => EventKind::TrackChanged,
+      Self::CurrentTrackRemoved { .. } => EventKind::CurrentTrackRemoved,
+      Self::TrackEnding { .. } => EventKind::TrackEnding,
+      Self::LyricLine { .. } => EventKind::LyricLine,
+      Self::LoadProgress { .. } => EventKind::LoadProgress,
+      Self::TrackListChanged { .. } => EventKind::TrackListChanged,
+      Self::LoopModeChanged(_) => EventKind::LoopModeChanged,
+      Self::EndBehaviorChanged(_) => EventKind::EndBehaviorChanged,
+      Self::StopAfterCurrentChanged(_) => EventKind::StopAfterCurrentChanged,
+      Self::ShuffleChanged(_) => EventKind::ShuffleChanged,
+      Self::QueueFilterChanged(_) => EventKind::QueueFilterChanged,
+      Self::VolumeChanged(_) => EventKind::VolumeChanged,
+      Self::RateChanged(_) => EventKind::RateChanged,
+      Self::Seeked(_) => EventKind::Seeked,
+      Self::OutputFormatChanged(_) => EventKind::OutputFormatChanged,
+      Self::OutputReconnected(_) => EventKind::OutputReconnected,
+      Self::PlayerStalled { .. } => EventKind::PlayerStalled,
+      Self::InterruptStarted { .. } => EventKind::InterruptStarted,
+      Self::InterruptFinished => EventKind::InterruptFinished,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const TRACK_JSON: &str = r#"{"file_path":"/music/a.flac","total_duration":{"secs":61,"nanos":500000000},"metadata":{"title":"Title","artists":["Artist"],"album":"Album","album_artist":null,"track_number":1,"date":null,"genres":[],"comments":[]}}"#;
+  const OUTPUT_INFO_JSON: &str = r#"{"sample_rate":48000,"channels":2,"bit_perfect":false}"#;
+
+  fn track() -> Track {
+    Track {
+      file_path: PathBuf::from("/music/a.flac"),
+      total_duration: Some(Duration::from_millis(61500)),
+      metadata: TrackMetadata {
+        title: Some("Title".into()),
+        artists: vec!["Artist".into()],
+        album: Some("Album".into()),
+        track_number: Some(1),
+        ..Default::default()
+      },
+    }
+  }
+
+  fn output_info() -> OutputInfo {
+    OutputInfo {
+      sample_rate: 48000,
+      channels: 2,
+      bit_perfect: false,
+    }
+  }
+
+  /// One of every event, with the json subscribed clients receive for it
+  fn golden_events() -> Vec<(Event, String)> {
+    // Fails to compile when a variant is added, as a reminder to add it below
+    fn _listed(event: &Event) {
+      match event {
+        Event::PlaybackStateChanged(_)
+        | Event::PlaybackStopped(_)
+        | Event::TrackChanged(_)
+        | Event::CurrentTrackRemoved { .. }
+        | Event::TrackEnding { .. }
+        | Event::LyricLine { .. }
+        | Event::LoadProgress { .. }
+        | Event::TrackListChanged { .. }
+        | Event::LoopModeChanged(_)
+        | Event::EndBehaviorChanged(_)
+        | Event::StopAfterCurrentChanged(_)
+        | Event::ShuffleChanged(_)
+        | Event::QueueFilterChanged(_)
+        | Event::VolumeChanged(_)
+        | Event::RateChanged(_)
+        | Event::Seeked(_)
+        | Event::OutputFormatChanged(_)
+        | Event::OutputReconnected(_)
+        | Event::PlayerStalled { .. }
+        | Event::InterruptStarted { .. }
+        | Event::InterruptFinished => (),
+      }
+    }
+
+    vec![
+      (
+        Event::PlaybackStateChanged(PlaybackState::Playing),
+        r#"{"PlaybackStateChanged":"Playing"}"#.into(),
+      ),
+      (
+        Event::PlaybackStopped(StopReason::Error("No decoder".into())),
+        r#"{"PlaybackStopped":{"Error":"No decoder"}}"#.into(),
+      ),
+      (
+        Event::TrackChanged(Some(track())),
+        format!(r#"{{"TrackChanged":{TRACK_JSON}}}"#),
+      ),
+      (Event::TrackChanged(None), r#"{"TrackChanged":null}"#.into()),
+      (
+        Event::CurrentTrackRemoved {
+          track: track(),
+          detached: true,
+        },
+        format!(r#"{{"CurrentTrackRemoved":{{"track":{TRACK_JSON},"detached":true}}}}"#),
+      ),
+      (
+        Event::TrackEnding {
+          remaining: Duration::from_secs(5),
+        },
+        r#"{"TrackEnding":{"remaining":{"secs":5,"nanos":0}}}"#.into(),
+      ),
+      (
+        Event::LyricLine {
+          time: Duration::from_millis(1250),
+          text: "A line".into(),
+        },
+        r#"{"LyricLine":{"time":{"secs":1,"nanos":250000000},"text":"A line"}}"#.into(),
+      ),
+      (
+        Event::LoadProgress {
+          progress_id: Some(3),
+          loaded: 10,
+          errored: 1,
+          scanning: Some(PathBuf::from("/music/album")),
+        },
+        r#"{"LoadProgress":{"progress_id":3,"loaded":10,"errored":1,"scanning":"/music/album"}}"#
+          .into(),
+      ),
+      (
+        Event::TrackListChanged {
+          generation: 7,
+          updates: vec![
+            TrackListUpdate::Insert {
+              index: 0,
+              tracks: vec![track()],
+              new_shuffle_indicies: vec![0],
+            },
+            TrackListUpdate::Clear,
+          ],
+        },
+        format!(
+          r#"{{"TrackListChanged":{{"generation":7,"updates":[{{"Insert":{{"index":0,"tracks":[{TRACK_JSON}],"new_shuffle_indicies":[0]}}}},"Clear"]}}}}"#
+        ),
+      ),
+      (
+        Event::LoopModeChanged(LoopMode::Playlist),
+        r#"{"LoopModeChanged":"Playlist"}"#.into(),
+      ),
+      (
+        Event::EndBehaviorChanged(EndBehavior::WillStopAfterCurrent),
+        r#"{"EndBehaviorChanged":"WillStopAfterCurrent"}"#.into(),
+      ),
+      (
+        Event::StopAfterCurrentChanged(true),
+        r#"{"StopAfterCurrentChanged":true}"#.into(),
+      ),
+      (
+        Event::ShuffleChanged(false),
+        r#"{"ShuffleChanged":false}"#.into(),
+      ),
+      (
+        Event::QueueFilterChanged(Some(FilterExpr {
+          all: vec![FilterCondition {
+            field: FilterField::Artist,
+            op: FilterOp::Contains,
+            value: "art".into(),
+          }],
+        })),
+        r#"{"QueueFilterChanged":{"all":[{"field":"Artist","op":"Contains","value":"art"}]}}"#
+          .into(),
+      ),
+      (Event::VolumeChanged(0.5), r#"{"VolumeChanged":0.5}"#.into()),
+      (Event::RateChanged(1.25), r#"{"RateChanged":1.25}"#.into()),
+      (
+        Event::Seeked(Duration::from_secs(90)),
+        r#"{"Seeked":{"secs":90,"nanos":0}}"#.into(),
+      ),
+      (
+        Event::OutputFormatChanged(output_info()),
+        format!(r#"{{"OutputFormatChanged":{OUTPUT_INFO_JSON}}}"#),
+      ),
+      (
+        Event::OutputReconnected(output_info()),
+        format!(r#"{{"OutputReconnected":{OUTPUT_INFO_JSON}}}"#),
+      ),
+      (
+        Event::PlayerStalled {
+          waited: Duration::from_secs(10),
+        },
+        r#"{"PlayerStalled":{"waited":{"secs":10,"nanos":0}}}"#.into(),
+      ),
+      (
+        Event::InterruptStarted {
+          path: PathBuf::from("/sounds/bell.wav"),
+        },
+        r#"{"InterruptStarted":{"path":"/sounds/bell.wav"}}"#.into(),
+      ),
+      (Event::InterruptFinished, r#""InterruptFinished""#.into()),
+    ]
+  }
+
+  #[test]
+  fn events_serialize_to_golden_json() {
+    for (event, golden) in golden_events() {
+      assert_eq!(serde_json::to_string(&event).unwrap(), golden);
+    }
+  }
+
+  #[test]
+  fn golden_json_round_trips() {
+    for (event, golden) in golden_events() {
+      let deserialized: Event = serde_json::from_str(&golden).unwrap();
+      assert_eq!(deserialized.kind(), event.kind(), "{golden}");
+      assert_eq!(serde_json::to_string(&deserialized).unwrap(), golden);
+    }
+  }
+
+  #[test]
+  fn every_event_kind_is_listed() {
+    let kinds: Vec<EventKind> = golden_events()
+      .iter()
+      .map(|(event, _)| event.kind())
+      .collect();
+
+    for kind in EventKind::ALL {
+      assert!(kinds.contains(kind), "No golden json for {kind:?}");
+    }
+  }
+
+  #[test]
+  fn event_kinds_round_trip() {
+    for kind in EventKind::ALL {
+      let json = serde_json::to_string(kind).unwrap();
+      assert_eq!(json, format!("\"{kind:?}\""));
+      assert_eq!(serde_json::from_str::<EventKind>(&json).unwrap(), *kind);
+    }
+  }
 }
@@ -1,4 +1,4 @@
-use super::{Reply, Request, requests::private::QualifiedRequest};
+use super::{Event, Reply, Request, requests::private::QualifiedRequest};
 
 pub fn serialize_request(request: impl Request) -> String {
   let mut request_data = serde_json::to_string::<QualifiedRequest>(&request.into())
@@ -10,3 +10,8 @@ pub fn serialize_request(request: impl Request) -> String {
 pub fn deserialize_reply<R: Request>(reply_data: &str) -> serde_json::Result<Reply<R>> {
   serde_json::from_str(reply_data)
 }
+
+/// Deserializes a line sent after the reply to `Subscribe`
+pub fn deserialize_event(event_data: &str) -> serde_json::Result<Event> {
+  serde_json::from_str(event_data)
+}
@@ -1,28 +1,56 @@
-use std::{path::PathBuf, time::Duration};
+use std::{num::NonZeroUsize, path::PathBuf, time::Duration};
 
 use super::{
-  InsertPosition, LoopMode, PlaybackState, Request, SeekPosition, Track, TrackListSnapshot,
-  Version, private::SealedRequest,
+  CacheStats, ConnectionInfo, CurrentEntry, EndBehavior, EventKind, FilterExpr, InsertPosition,
+  InsertShufflePolicy, InspectedTrack, LoadTrackErrorKind, LoopMode, NormalizationMode, OutputInfo,
+  PlaybackState, PlayerStatus, QueueSummary, Request, SeekPosition, ServerStats, SessionStats,
+  StopReason, TestToneResult, Track, TrackId, TrackListDiff, TrackListSnapshot, TrackRef,
+  TransitionMode, Version, private::SealedRequest,
 };
 
 macro_rules! requests {
-  (@def $name:ident ()) => {
+  (@def $(#[$meta:meta])* $name:ident ()) => {
+    $(#[$meta])*
     #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
     pub struct $name;
   };
 
-  (@def $name:ident ( $($field:ty),* )) => {
+  (@def $(#[$meta:meta])* $name:ident ( $($field:ty),* )) => {
+    $(#[$meta])*
     #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
     pub struct $name($(pub $field),*);
   };
 
-  (@def $name:ident { $($t:tt)* } ) => {
+  (@def $(#[$meta:meta])* $name:ident { $($t:tt)* } ) => {
+    $(#[$meta])*
     #[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
     pub struct $name{$($t)*}
   };
 
+  (@payload ()) => {
+    crate::api::schema::PayloadShape::Unit
+  };
+
+  (@payload ( $($field:ty),* )) => {
+    crate::api::schema::PayloadShape::Tuple {
+      fields: vec![$(crate::api::schema::type_name(stringify!($field))),*],
+    }
+  };
+
+  (@payload { $( $(#[$field_meta:meta])* pub $field:ident : $field_ty:ty ),* $(,)? }) => {
+    crate::api::schema::PayloadShape::Struct {
+      fields: vec![$(
+        crate::api::schema::FieldSchema {
+          name: stringify!($field),
+          ty: crate::api::schema::type_name(stringify!($field_ty)),
+          optional: crate::api::schema::type_name(stringify!($($field_meta)*)).contains("serde(default)"),
+        }
+      ),*],
+    }
+  };
+
   (
-    $($name:ident $fields:tt -> $response:ty;)*
+    $($(#[$meta:meta])* $name:ident $fields:tt -> $response:ty;)*
   ) => {
 paste::paste! {
   pub(crate) mod private {
@@ -40,6 +68,56 @@ paste::paste! {
       )*
     }
 
+    /// The name of every request, in the order they are declared
+    pub const REQUEST_NAMES: &[&str] = &[$(stringify!($name)),*];
+
+    #[derive(Clone, Copy)]
+    enum RequestKind {
+      $($name,)*
+    }
+
+    impl QualifiedRequest {
+      /// The position of this request's name in `REQUEST_NAMES`
+      pub fn index(&self) -> usize {
+        match self {
+          $(
+            QualifiedRequest::$name(_) => RequestKind::$name as usize,
+          )*
+        }
+      }
+
+      pub fn name(&self) -> &'static str {
+        match self {
+          $(
+            QualifiedRequest::$name(_) => stringify!($name),
+          )*
+        }
+      }
+
+      /// Every request that does not change the server's state is named `Query*`
+      ///
+      /// `Identify` and `Subscribe` only change the connection they are sent on, so they are allowed from read-only connections
+      pub fn is_mutating(&self) -> bool {
+        Self::name_is_mutating(self.name())
+      }
+
+      fn name_is_mutating(name: &str) -> bool {
+        !name.starts_with("Query") && name != "Identify" && name != "Subscribe"
+      }
+    }
+
+    #[cfg(feature = "schema")]
+    pub fn request_schemas() -> Vec<crate::api::schema::RequestSchema> {
+      vec![$(
+        crate::api::schema::RequestSchema {
+          name: stringify!($name),
+          payload: requests!(@payload $fields),
+          response: crate::api::schema::type_name(stringify!($response)),
+          mutating: QualifiedRequest::name_is_mutating(stringify!($name)),
+        }
+      ),*]
+    }
+
     pub async fn _handle_request<E>(request: QualifiedRequest, handler: &(impl RequestHandler<Error = E> + ?Sized)) -> Result<String, E> {
       let reply_data = match request {
         $(
@@ -64,7 +142,7 @@ paste::paste! {
   use private::QualifiedRequest;
 
   $(
-    requests!(@def $name $fields);
+    requests!(@def $(#[$meta])* $name $fields);
 
     impl From<$name> for QualifiedRequest {
       fn from(value: $name) -> Self {
@@ -83,34 +161,216 @@ paste::paste! {
 
 requests! {
   QueryVersion() -> Version;
+  QueryServerStats() -> ServerStats;
+  QueryCacheStats() -> CacheStats;
+  /// How the player will move from the current track to the next one, `None` if no other track plays after it
+  QueryNextTransition() -> Option<TransitionMode>;
+  QuerySessionStats() -> SessionStats;
+  ResetSessionStats() -> ();
+  /// Names the connection this is sent on, shown in `QueryConnections`
+  Identify {
+    pub name: String,
+  } -> ();
+  QueryConnections() -> Vec<ConnectionInfo>;
+  /// Turns the ipc connection it is sent on into a stream of events, one JSON `Event` per line after the reply
+  ///
+  /// Only events of these kinds are sent, or every event if this is empty. Nothing more can be requested on the connection
+  Subscribe(Vec<EventKind>) -> ();
+  /// Shuts down plugins and stops the server
+  Shutdown() -> ();
 
+  /// The playback state, current track, position, volume, loop mode, and shuffle in one request
+  QueryStatus() -> PlayerStatus;
   QueryPlaybackState() -> PlaybackState;
+  /// `None` if playback has started since it was last stopped
+  QueryStopReason() -> Option<StopReason>;
   Play() -> ();
   Pause() -> ();
   StopPlayback() -> ();
   TogglePlayback() -> ();
 
+  /// The track at the current index, which stays where playback stopped, `None` if the track list is empty
   QueryCurrentTrack() -> Option<Track>;
   QueryCurrentTrackIndex() -> usize;
-  NextTrack() -> ();
+  /// The current track, and if starting playback would play it
+  QueryCurrentEntry() -> CurrentEntry;
+  /// Estimated time until the track at an index in play order starts playing,
+  /// `None` if it will not play with the current loop mode or a track before it has an unknown duration
+  QueryTrackEta(usize) -> Option<Duration>;
+  QueryCurrentTrackId() -> Option<TrackId>;
+  /// Loads the track at a path through the server's cache without queueing it
+  InspectTrack(PathBuf) -> InspectedTrack;
+  /// Lyrics of the track at a path, or the current track if `None`
+  QueryLyrics(Option<PathBuf>) -> Option<String>;
+  /// Timestamped lyrics of the current track, from an lrc lyrics tag or sidecar file
+  QuerySyncedLyrics() -> Option<Vec<(Duration, String)>>;
+  /// Skips this many tracks, or one if `None`
+  ///
+  /// Older clients send `NextTrack` without a count as `null`, which still parses as `None`
+  NextTrack(Option<NonZeroUsize>) -> ();
   PreviousTrack {
     /// Restarts the track instead of going to the previous track if enough time has passed
     pub soft: bool,
+    /// Goes back this many tracks, or one if `None`
+    #[serde(default)]
+    pub count: Option<NonZeroUsize>,
+  } -> ();
+  /// Jumps to the track at this index in play order, which is the order `QueryTrackList` lists tracks in
+  GoToTrack {
+    pub index: usize,
+    /// Fail with a generation mismatch instead of jumping if the track list has changed since this generation
+    #[serde(default)]
+    pub expected_generation: Option<u64>,
   } -> ();
 
+  QueryStopAfterCurrent() -> bool;
+  /// Pauses at the end of the current track instead of playing the next one
+  ///
+  /// Cleared once playback pauses, or by `NextTrack`, `PreviousTrack` or replacing the track list
+  SetStopAfterCurrent(bool) -> ();
+
   QueryLoopMode() -> LoopMode;
   SetLoopMode(LoopMode) -> ();
+  /// What happens when the current track ends, so clients don't need to work it out from the player's settings
+  QueryEndBehavior() -> EndBehavior;
 
   QueryShuffle() -> bool;
   SetShuffle(bool) -> ();
 
+  QueryQueueFilter() -> Option<FilterExpr>;
+  /// Skips entries that don't match the filter when moving through the track list, without removing them
+  ///
+  /// Fails without changing the filter if no entry matches it. Returns the number of matching entries
+  SetQueueFilter(Option<FilterExpr>) -> usize;
+
   QueryVolume() -> f32;
   SetVolume(f32) -> ();
+  /// Changes the volume relative to its current value
+  AdjustVolume(f32) -> ();
+
+  QueryRate() -> f32;
+  /// Plays faster or slower, which also changes the pitch. Clamped to `0.25..=4.0`
+  SetRate(f32) -> ();
+
+  QueryNormalization() -> NormalizationMode;
+  /// Applies ReplayGain tags to the volume, including to the track that is playing
+  SetNormalization(NormalizationMode) -> ();
 
   QueryPosition() -> Duration;
   Seek(SeekPosition) -> ();
 
+  QueryOutputInfo() -> OutputInfo;
+  /// Plays a sine wave through the player's output while stopped, to check that audio reaches the device
+  PlayTestTone {
+    pub frequency: f32,
+    pub duration: Duration,
+  } -> TestToneResult;
+  /// Plays a file over the track list without adding it, such as a notification sound
+  ///
+  /// The current track is held where it is and continues once the file ends.
+  /// Rejected while another oneshot is playing
+  PlayOneshot {
+    pub path: PathBuf,
+  } -> ();
+  QueryBitPerfect() -> bool;
+  SetBitPerfect(bool) -> ();
+
+  /// The name of each plugin, and if it is enabled
+  QueryPlugins() -> Vec<(String, bool)>;
+  /// Stops a plugin, or initializes it again
+  SetPluginEnabled {
+    pub name: String,
+    pub enabled: bool,
+  } -> ();
+
   QueryTrackList() -> TrackListSnapshot;
-  ClearTracks() -> ();
-  LoadTracks(InsertPosition, Vec<PathBuf>) -> Vec<(PathBuf, String)>;
+  /// Answered from a cached summary, so it does not wait for a large `LoadTracks` to finish
+  QueryQueueSummary() -> QueueSummary;
+  /// The changes to the track list since `since_generation`, so clients don't need to query the whole track list
+  QueryTrackListDiff {
+    pub since_generation: u64,
+  } -> TrackListDiff;
+  ClearTracks {
+    /// Fail with a generation mismatch instead of clearing if the track list has changed since this generation
+    #[serde(default)]
+    pub expected_generation: Option<u64>,
+  } -> ();
+  /// Removes the tracks at these positions in play order, returning the positions that were out of range
+  ///
+  /// If the current track is removed, the track after it becomes current
+  RemoveTracks {
+    pub positions: Vec<usize>,
+    /// Fail with a generation mismatch instead of removing if the track list has changed since this generation
+    #[serde(default)]
+    pub expected_generation: Option<u64>,
+  } -> Vec<usize>;
+  /// Exchanges two entries in play order, the current entry stays current wherever it moves
+  SwapTracks {
+    pub a: TrackRef,
+    pub b: TrackRef,
+    /// Fail with a generation mismatch instead of swapping if the track list has changed since this generation
+    #[serde(default)]
+    pub expected_generation: Option<u64>,
+  } -> ();
+  /// Moves the entry at `from` to `to`, both positions in play order, shifting the entries between them
+  ///
+  /// The current entry stays current wherever it moves
+  MoveTrack {
+    pub from: usize,
+    pub to: usize,
+    /// Fail with a generation mismatch instead of moving if the track list has changed since this generation
+    #[serde(default)]
+    pub expected_generation: Option<u64>,
+  } -> ();
+  /// Reverses the play order, the current entry stays current wherever it moves
+  ReverseQueue() -> ();
+  /// Paths of the tracks removed by the last clear, replace, or `RemoveTracks`, in track list order
+  QueryLastRemoved() -> Vec<PathBuf>;
+  /// Loads the tracks from `QueryLastRemoved` again, returning the paths that failed to load like `LoadTracks`
+  RestoreLastRemoved {
+    pub position: InsertPosition,
+    /// Fail with a generation mismatch instead of restoring if the track list has changed since this generation
+    #[serde(default)]
+    pub expected_generation: Option<u64>,
+  } -> Vec<(PathBuf, LoadTrackErrorKind)>;
+  /// Sets the gain of a single entry, taking effect the next time it starts playing
+  SetTrackGain {
+    pub track_id: TrackId,
+    pub gain_db: Option<f32>,
+  } -> ();
+  LoadTracks {
+    pub position: InsertPosition,
+    pub paths: Vec<PathBuf>,
+    /// Fail with a generation mismatch instead of inserting if the track list has changed since this generation
+    #[serde(default)]
+    pub expected_generation: Option<u64>,
+    /// Load paths that are filesystem roots or outside of the server's allowed directories
+    #[serde(default)]
+    pub force: bool,
+    /// Sent back in `Event::LoadProgress`, so clients can tell which events belong to their request
+    #[serde(default)]
+    pub progress_id: Option<u64>,
+    /// Where the tracks go in the play order if shuffle is enabled
+    #[serde(default)]
+    pub shuffle_policy: InsertShufflePolicy,
+  } -> Vec<(PathBuf, LoadTrackErrorKind)>;
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn skip_counts_are_optional() {
+    // Sent by clients from before the count was added
+    let next: NextTrack = serde_json::from_str("null").unwrap();
+    assert_eq!(next.0, None);
+    let previous: PreviousTrack = serde_json::from_str(r#"{"soft":true}"#).unwrap();
+    assert!(previous.soft);
+    assert_eq!(previous.count, None);
+
+    let next: NextTrack = serde_json::from_str("3").unwrap();
+    assert_eq!(next.0, NonZeroUsize::new(3));
+    assert!(serde_json::from_str::<NextTrack>("0").is_err());
+  }
 }
@@ -0,0 +1,289 @@
+use serde::Serialize;
+
+use super::{Event, requests};
+
+/// Description of every request and event, for generating clients in other languages
+///
+/// Requests are sent as one line of json, `{"<name>": <payload>}`, and every request gets one line back,
+/// `{"Ok": <response>}` or `{"Err": "<message>"}`.
+/// Types are described with their rust names, such as `Option<TrackId>` or `Vec<(PathBuf,String)>`.
+#[derive(Debug, Clone, Serialize)]
+pub struct ApiSchema {
+  pub version: String,
+  pub requests: Vec<RequestSchema>,
+  /// Events are not sent over the ipc socket yet, they are only passed to plugins
+  pub events: Vec<EventSchema>,
+}
+
+impl ApiSchema {
+  pub fn to_json(&self) -> String {
+    serde_json::to_string_pretty(self).expect("The api schema should not fail to serialize")
+  }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RequestSchema {
+  pub name: &'static str,
+  pub payload: PayloadShape,
+  pub response: String,
+  /// Mutating requests are rejected on read-only connections
+  pub mutating: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EventSchema {
+  pub name: &'static str,
+  pub payload: PayloadShape,
+}
+
+/// How the payload of a request or event is encoded
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum PayloadShape {
+  /// Encoded as `null`
+  Unit,
+  /// A single field is encoded as the value itself, multiple fields as an array
+  Tuple { fields: Vec<String> },
+  /// Encoded as an object
+  Struct { fields: Vec<FieldSchema> },
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FieldSchema {
+  pub name: &'static str,
+  #[serde(rename = "type")]
+  pub ty: String,
+  /// The field can be left out of requests
+  pub optional: bool,
+}
+
+/// `stringify!` spaces out the tokens of a type, `Option < u64 >` is easier to read as `Option<u64>`
+pub(crate) fn type_name(stringified: &str) -> String {
+  stringified.split_whitespace().collect()
+}
+
+impl PayloadShape {
+  fn tuple(fields: &[&str]) -> Self {
+    Self::Tuple {
+      fields: fields.iter().map(|field| type_name(field)).collect(),
+    }
+  }
+
+  fn fields(fields: &[(&'static str, &str)]) -> Self {
+    Self::Struct {
+      fields: fields
+        .iter()
+        .map(|&(name, ty)| FieldSchema {
+          name,
+          ty: type_name(ty),
+          optional: false,
+        })
+        .collect(),
+    }
+  }
+}
+
+fn event_schemas() -> Vec<EventSchema> {
+  // Fails to compile when a variant is added, as a reminder to describe it below
+  fn _described(event: &Event) {
+    match event {
+      Event::PlaybackStateChanged(_)
+      | Event::PlaybackStopped(_)
+      | Event::TrackChanged(_)
+      | Event::CurrentTrackRemoved { .. }
+      | Event::TrackEnding { .. }
+      | Event::LyricLine { .. }
+      | Event::LoadProgress { .. }
+      | Event::TrackListChanged { .. }
+      | Event::LoopModeChanged(_)
+      | Event::EndBehaviorChanged(_)
+      | Event::StopAfterCurrentChanged(_)
+      | Event::ShuffleChanged(_)
+      | Event::QueueFilterChanged(_)
+      | Event::VolumeChanged(_)
+      | Event::RateChanged(_)
+      | Event::Seeked(_)
+      | Event::OutputFormatChanged(_)
+      | Event::OutputReconnected(_)
+      | Event::PlayerStalled { .. }
+      | Event::InterruptStarted { .. }
+      | Event::InterruptFinished => (),
+    }
+  }
+
+  let event = |name, payload| EventSchema { name, payload };
+
+  vec![
+    event(
+      "PlaybackStateChanged",
+      PayloadShape::tuple(&["PlaybackState"]),
+    ),
+    event("PlaybackStopped", PayloadShape::tuple(&["StopReason"])),
+    event("TrackChanged", PayloadShape::tuple(&["Option<Track>"])),
+    event(
+      "CurrentTrackRemoved",
+      PayloadShape::fields(&[("track", "Track"), ("detached", "bool")]),
+    ),
+    event(
+      "TrackEnding",
+      PayloadShape::fields(&[("remaining", "Duration")]),
+    ),
+    event(
+      "LyricLine",
+      PayloadShape::fields(&[("time", "Duration"), ("text", "String")]),
+    ),
+    event(
+      "LoadProgress",
+      PayloadShape::fields(&[
+        ("progress_id", "Option<u64>"),
+        ("loaded", "usize"),
+        ("errored", "usize"),
+        ("scanning", "Option<PathBuf>"),
+      ]),
+    ),
+    event(
+      "TrackListChanged",
+      PayloadShape::fields(&[("generation", "u64"), ("updates", "Vec<TrackListUpdate>")]),
+    ),
+    event("LoopModeChanged", PayloadShape::tuple(&["LoopMode"])),
+    event("EndBehaviorChanged", PayloadShape::tuple(&["EndBehavior"])),
+    event("StopAfterCurrentChanged", PayloadShape::tuple(&["bool"])),
+    event("ShuffleChanged", PayloadShape::tuple(&["bool"])),
+    event(
+      "QueueFilterChanged",
+      PayloadShape::tuple(&["Option<FilterExpr>"]),
+    ),
+    event("VolumeChanged", PayloadShape::tuple(&["f32"])),
+    event("RateChanged", PayloadShape::tuple(&["f32"])),
+    event("Seeked", PayloadShape::tuple(&["Duration"])),
+    event("OutputFormatChanged", PayloadShape::tuple(&["OutputInfo"])),
+    event("OutputReconnected", PayloadShape::tuple(&["OutputInfo"])),
+    event(
+      "PlayerStalled",
+      PayloadShape::fields(&[("waited", "Duration")]),
+    ),
+    event(
+      "InterruptStarted",
+      PayloadShape::fields(&[("path", "PathBuf")]),
+    ),
+    event("InterruptFinished", PayloadShape::Unit),
+  ]
+}
+
+pub fn schema() -> ApiSchema {
+  ApiSchema {
+    version: crate::version().0,
+    requests: requests::private::request_schemas(),
+    events: event_schemas(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::api::{
+    EventKind,
+    requests::private::{QualifiedRequest, REQUEST_NAMES},
+  };
+
+  fn request(name: &str) -> RequestSchema {
+    schema()
+      .requests
+      .into_iter()
+      .find(|request| request.name == name)
+      .unwrap()
+  }
+
+  #[test]
+  fn joins_type_tokens() {
+    assert_eq!(
+      type_name("Option < Vec < (PathBuf , String) > >"),
+      "Option<Vec<(PathBuf,String)>>"
+    );
+  }
+
+  #[test]
+  fn describes_every_request() {
+    let names: Vec<&str> = schema()
+      .requests
+      .iter()
+      .map(|request| request.name)
+      .collect();
+    assert_eq!(names, REQUEST_NAMES);
+  }
+
+  #[test]
+  fn describes_request_shapes() {
+    let next = request("NextTrack");
+    assert!(next.mutating);
+    assert!(matches!(
+      next.payload,
+      PayloadShape::Tuple { fields } if fields == ["Option<NonZeroUsize>"]
+    ));
+
+    let previous = request("PreviousTrack");
+    let PayloadShape::Struct { fields } = previous.payload else {
+      panic!("PreviousTrack should be a struct");
+    };
+    let fields: Vec<_> = fields
+      .iter()
+      .map(|field| (field.name, field.ty.as_str(), field.optional))
+      .collect();
+    assert_eq!(
+      fields,
+      [
+        ("soft", "bool", false),
+        ("count", "Option<NonZeroUsize>", true)
+      ]
+    );
+
+    let query = request("QueryLoopMode");
+    assert!(!query.mutating);
+    assert!(matches!(query.payload, PayloadShape::Unit));
+    assert_eq!(query.response, "LoopMode");
+  }
+
+  /// Requests encoded the way the schema describes them are accepted
+  #[test]
+  fn described_payloads_parse() {
+    for request in schema().requests {
+      let payload = match &request.payload {
+        PayloadShape::Unit => "null",
+        PayloadShape::Struct { fields } if fields.iter().all(|field| field.optional) => "{}",
+        _ => continue,
+      };
+
+      let json = format!(r#"{{"{}":{payload}}}"#, request.name);
+      let parsed: QualifiedRequest = serde_json::from_str(&json).unwrap();
+      assert_eq!(parsed.name(), request.name);
+    }
+  }
+
+  #[test]
+  fn describes_every_event() {
+    let names: Vec<&str> = schema().events.iter().map(|event| event.name).collect();
+    let kinds: Vec<String> = EventKind::ALL
+      .iter()
+      .map(|kind| format!("{kind:?}"))
+      .collect();
+    assert_eq!(names, kinds);
+  }
+
+  #[test]
+  fn serializes_shapes_by_kind() {
+    let json: serde_json::Value = serde_json::from_str(&schema().to_json()).unwrap();
+    let previous = json["requests"]
+      .as_array()
+      .unwrap()
+      .iter()
+      .find(|request| request["name"] == "PreviousTrack")
+      .unwrap();
+
+    assert_eq!(previous["payload"]["kind"], "struct");
+    assert_eq!(
+      previous["payload"]["fields"][1]["type"],
+      "Option<NonZeroUsize>"
+    );
+    assert_eq!(previous["payload"]["fields"][1]["optional"], true);
+  }
+}
@@ -1,13 +1,149 @@
-use super::{Request, requests};
+use std::{
+  sync::{
+    Arc, Mutex,
+    atomic::{AtomicU64, Ordering},
+  },
+  time::{Duration, Instant, SystemTime},
+};
+
+use serde::Deserialize;
+
+use super::{ConnectionInfo, Event, EventKind, Request, RequestTiming, TIMING_BUCKETS, requests};
 
-use requests::private::_handle_request;
 pub use requests::private::RequestHandler;
+use requests::private::{_handle_request, QualifiedRequest, REQUEST_NAMES};
+
+/// A client connected to the ipc socket, which stays alive until the client disconnects
+#[derive(Debug)]
+pub struct Connection {
+  id: u64,
+  since: SystemTime,
+  name: Mutex<Option<String>>,
+  requests_handled: AtomicU64,
+}
+
+impl Connection {
+  pub fn new(id: u64) -> Self {
+    Self {
+      id,
+      since: SystemTime::now(),
+      name: Mutex::new(None),
+      requests_handled: AtomicU64::new(0),
+    }
+  }
+
+  pub fn id(&self) -> u64 {
+    self.id
+  }
+
+  pub fn info(&self) -> ConnectionInfo {
+    let name = self
+      .name
+      .lock()
+      .expect("Connection name lock should not be poisoned")
+      .clone()
+      .unwrap_or_else(|| format!("anonymous-{}", self.id));
+
+    ConnectionInfo {
+      id: self.id,
+      name,
+      since: self.since,
+      requests_handled: self.requests_handled.load(Ordering::Relaxed),
+    }
+  }
+}
+
+#[derive(Debug, Default)]
+struct RequestTimer {
+  count: AtomicU64,
+  total_micros: AtomicU64,
+  max_micros: AtomicU64,
+  buckets: [AtomicU64; TIMING_BUCKETS.len() + 1],
+}
+
+/// How long each kind of request took to handle, recorded with atomics so it can always be on
+#[derive(Debug)]
+pub struct RequestTimings {
+  /// Indexed like `REQUEST_NAMES`
+  timers: Vec<RequestTimer>,
+  /// Requests that take at least this long are logged, zero to disable
+  slow_threshold: Duration,
+}
+
+impl RequestTimings {
+  pub fn new(slow_threshold: Duration) -> Self {
+    Self {
+      timers: REQUEST_NAMES
+        .iter()
+        .map(|_| RequestTimer::default())
+        .collect(),
+      slow_threshold,
+    }
+  }
+
+  /// `path_count` is logged for requests that load paths
+  fn record(&self, request_index: usize, path_count: Option<usize>, elapsed: Duration) {
+    let timer = &self.timers[request_index];
+    let micros = elapsed.as_micros().try_into().unwrap_or(u64::MAX);
+    let bucket = TIMING_BUCKETS
+      .iter()
+      .position(|&bound| elapsed <= bound)
+      .unwrap_or(TIMING_BUCKETS.len());
+
+    timer.count.fetch_add(1, Ordering::Relaxed);
+    timer.total_micros.fetch_add(micros, Ordering::Relaxed);
+    timer.max_micros.fetch_max(micros, Ordering::Relaxed);
+    timer.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+
+    if !self.slow_threshold.is_zero() && elapsed >= self.slow_threshold {
+      let details = path_count.map_or_else(String::new, |count| format!(" with {count} paths"));
+
+      eprintln!(
+        "Slow request: {}{details} took {}ms",
+        REQUEST_NAMES[request_index],
+        elapsed.as_millis()
+      );
+    }
+  }
+
+  /// The timings of every kind of request that was handled at least once
+  pub fn snapshot(&self) -> Vec<RequestTiming> {
+    REQUEST_NAMES
+      .iter()
+      .zip(&self.timers)
+      .filter(|(_, timer)| timer.count.load(Ordering::Relaxed) > 0)
+      .map(|(name, timer)| RequestTiming {
+        name: name.to_string(),
+        count: timer.count.load(Ordering::Relaxed),
+        total: Duration::from_micros(timer.total_micros.load(Ordering::Relaxed)),
+        max: Duration::from_micros(timer.max_micros.load(Ordering::Relaxed)),
+        buckets: timer
+          .buckets
+          .iter()
+          .map(|bucket| bucket.load(Ordering::Relaxed))
+          .collect(),
+      })
+      .collect()
+  }
+}
 
+/// Information about the connection a request was sent from
+#[derive(Debug, Clone, Default)]
+pub struct RequestOrigin {
+  /// Only requests that do not change the server's state are allowed
+  pub read_only: bool,
+  /// `None` for requests sent by plugins
+  pub connection: Option<Arc<Connection>>,
+}
+
+/// Handles a request, and records how long the handler took in `timings`
 pub async fn handle_request<R: RequestHandler>(
   request_data: &str,
+  origin: &RequestOrigin,
   request_handler: &R,
+  timings: &RequestTimings,
 ) -> Result<String, (String, R::Error)> {
-  let request = match serde_json::from_str(request_data) {
+  let request: QualifiedRequest = match serde_json::from_str(request_data) {
     Ok(request) => request,
     Err(error) => {
       println!("{}", &error);
@@ -15,7 +151,36 @@ pub async fn handle_request<R: RequestHandler>(
     }
   };
 
-  match _handle_request(request, request_handler).await {
+  if origin.read_only && request.is_mutating() {
+    return Ok(serialize_error(&format!(
+      "{} is not allowed from a read-only connection",
+      request.name()
+    )));
+  }
+
+  if let Some(connection) = &origin.connection {
+    connection.requests_handled.fetch_add(1, Ordering::Relaxed);
+
+    if let QualifiedRequest::Identify(requests::Identify { name }) = &request {
+      *connection
+        .name
+        .lock()
+        .expect("Connection name lock should not be poisoned") = Some(name.clone());
+    }
+  }
+
+  // The request is moved into its handler, so take what the slow request log needs first
+  let request_index = request.index();
+  let path_count = match &request {
+    QualifiedRequest::LoadTracks(load_tracks) => Some(load_tracks.paths.len()),
+    _ => None,
+  };
+
+  let started = Instant::now();
+  let result = _handle_request(request, request_handler).await;
+  timings.record(request_index, path_count, started.elapsed());
+
+  match result {
     Ok(reply_data) => Ok(reply_data),
     Err(error) => Err((serialize_error(&error), error)),
   }
@@ -34,3 +199,74 @@ pub fn serialize_error(error: &impl ToString) -> String {
   reply_data.push('\n');
   reply_data
 }
+
+/// Only deserializes `Subscribe`, so other requests are rejected by their name without parsing the rest
+#[derive(Deserialize)]
+enum SubscribeRequest {
+  Subscribe(requests::Subscribe),
+}
+
+/// Checks if `request_data` is a `Subscribe` request, which the connection it was sent on handles instead of the server
+///
+/// Returns the kinds of events to send, and the reply to send before the first event
+pub fn parse_subscribe(request_data: &str) -> Option<(Vec<EventKind>, String)> {
+  let SubscribeRequest::Subscribe(requests::Subscribe(kinds)) =
+    serde_json::from_str(request_data).ok()?;
+
+  Some((kinds, serialize_response::<requests::Subscribe>(())))
+}
+
+/// Serializes an event sent to a subscribed connection, as a single line
+pub fn serialize_event(event: &Event) -> String {
+  let mut event_data = serde_json::to_string(event).expect("Events should not fail to serialize");
+  event_data.push('\n');
+  event_data
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn request_index(name: &str) -> usize {
+    REQUEST_NAMES.iter().position(|&n| n == name).unwrap()
+  }
+
+  #[test]
+  fn records_request_timings() {
+    let timings = RequestTimings::new(Duration::ZERO);
+    let index = request_index("QueryVolume");
+    for millis in [0, 1, 3, 300, 5000] {
+      timings.record(index, None, Duration::from_millis(millis));
+    }
+
+    let [timing] = timings.snapshot().try_into().unwrap();
+    assert_eq!(timing.name, "QueryVolume");
+    assert_eq!(timing.count, 5);
+    assert_eq!(timing.total, Duration::from_millis(5304));
+    assert_eq!(timing.max, Duration::from_secs(5));
+    // A request that took exactly a bucket's bound is counted in it
+    assert_eq!(timing.buckets, [2, 1, 0, 0, 0, 1, 1]);
+  }
+
+  #[test]
+  fn snapshot_lists_handled_requests_in_order() {
+    let timings = RequestTimings::new(Duration::ZERO);
+    assert!(timings.snapshot().is_empty());
+
+    timings.record(request_index("QueryVolume"), None, Duration::from_millis(2));
+    timings.record(
+      request_index("LoadTracks"),
+      Some(3),
+      Duration::from_millis(2),
+    );
+
+    let names: Vec<String> = timings
+      .snapshot()
+      .into_iter()
+      .map(|timing| timing.name)
+      .collect();
+    let mut expected = vec!["QueryVolume", "LoadTracks"];
+    expected.sort_by_key(|name| request_index(name));
+    assert_eq!(names, expected);
+  }
+}
@@ -1,5 +1,9 @@
 pub use basic::*;
+pub use filter::*;
+pub use stats::*;
 pub use tracks::*;
 
 mod basic;
+mod filter;
+mod stats;
 mod tracks;
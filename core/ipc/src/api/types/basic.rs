@@ -2,9 +2,16 @@ use std::time::Duration;
 
 use serde::{Deserialize, Serialize};
 
+use super::{Track, TrackId};
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Version(pub String);
 
+/// Slowest playback rate, lower rates are clamped to it
+pub const MIN_RATE: f32 = 0.25;
+/// Fastest playback rate, higher rates are clamped to it
+pub const MAX_RATE: f32 = 4.0;
+
 #[repr(usize)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum PlaybackState {
@@ -13,6 +20,17 @@ pub enum PlaybackState {
   Stopped,
 }
 
+/// Why playback was last stopped
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StopReason {
+  /// The end of the track list was reached without looping
+  EndOfQueue,
+  UserRequested,
+  QueueCleared,
+  /// A track could not be played
+  Error(String),
+}
+
 #[repr(usize)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum LoopMode {
@@ -21,19 +39,226 @@ pub enum LoopMode {
   Playlist,
 }
 
+/// Which ReplayGain tags are used to play tracks at the same loudness
+#[repr(usize)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NormalizationMode {
+  #[default]
+  Off,
+  /// Each track is played at the same loudness
+  Track,
+  /// Albums are played at the same loudness, keeping the differences between their tracks
+  Album,
+}
+
+/// What the player will do when the current track ends
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EndBehavior {
+  /// Play the next track, and stop after the last one
+  #[default]
+  WillStopAtQueueEnd,
+  /// Play the next track, and go back to the first after the last one
+  WillWrap,
+  /// Play the current track again
+  WillLoopTrack,
+  /// Pause at the end of the current track, set by `SetStopAfterCurrent`
+  WillStopAfterCurrent,
+}
+
+/// How the player moves from one track to the next
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TransitionMode {
+  /// The next track starts right where the current one ends
+  Gapless,
+  /// The tracks fade into each other
+  Crossfade,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum SeekPosition {
   Forward(Duration),
   Backward(Duration),
   To(Duration),
+  /// A fraction of the current track's duration, from 0 to 1
+  ToFraction(f32),
+  ForwardFraction(f32),
+  BackwardFraction(f32),
+}
+
+impl SeekPosition {
+  /// The position this seeks to from `current_position`, which may be past the end of the track
+  ///
+  /// `None` for fractions of a track with an unknown duration, or fractions that aren't finite
+  pub fn resolve(
+    &self,
+    current_position: Duration,
+    total_duration: Option<Duration>,
+  ) -> Option<Duration> {
+    let fraction_of_track = |fraction: f32| {
+      let total_duration = total_duration.filter(|_| fraction.is_finite())?;
+      Some(total_duration.mul_f64(f64::from(fraction.clamp(0.0, 1.0))))
+    };
+
+    let position = match *self {
+      Self::Forward(duration) => current_position.saturating_add(duration),
+      Self::Backward(duration) => current_position.saturating_sub(duration),
+      Self::To(position) => position,
+      Self::ToFraction(fraction) => fraction_of_track(fraction)?,
+      Self::ForwardFraction(fraction) => {
+        current_position.saturating_add(fraction_of_track(fraction)?)
+      }
+      Self::BackwardFraction(fraction) => {
+        current_position.saturating_sub(fraction_of_track(fraction)?)
+      }
+    };
+
+    Some(position)
+  }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum InsertPosition {
   Absolute(usize),
+  /// After the entry with this id
+  After(TrackId),
   Next,
   Start,
   End,
   /// Clear the current track list before inserting
   Replace,
 }
+
+/// Refers to an entry in the track list
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TrackRef {
+  /// A position in play order, which is the order `QueryTrackList` lists tracks in
+  Position(usize),
+  Id(TrackId),
+}
+
+/// Where inserted tracks go in the play order while shuffle is enabled
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum InsertShufflePolicy {
+  /// Each track is moved to a random position
+  #[default]
+  Scatter,
+  /// The tracks play in order, right after the current track
+  KeepTogetherNext,
+  /// The tracks play in order, at the insert position
+  KeepTogetherInPlace,
+}
+
+/// The format of the stream audio is currently being played through
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OutputInfo {
+  pub sample_rate: u32,
+  pub channels: u16,
+  /// If the output stream is reopened to match the sample rate of each track
+  pub bit_perfect: bool,
+}
+
+/// How much of a test tone the output stream played, see `PlayTestTone`
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct TestToneResult {
+  pub played: Duration,
+  /// If the whole tone was played before the server gave up waiting for it
+  pub completed: bool,
+}
+
+/// Everything a status bar shows, returned by `QueryStatus` so it only takes one request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlayerStatus {
+  pub playback_state: PlaybackState,
+  pub track: Option<Track>,
+  pub position: Duration,
+  pub volume: f32,
+  pub loop_mode: LoopMode,
+  pub shuffle: bool,
+  #[serde(default)]
+  pub stop_after_current: bool,
+  /// The same as `QueryEndBehavior`, so clients don't have to work it out from the other fields
+  #[serde(default)]
+  pub end_behavior: EndBehavior,
+  /// Why playback last stopped, `None` if it has started since
+  #[serde(default)]
+  pub stop_reason: Option<StopReason>,
+  /// If `track` was removed from the track list and is playing until it ends
+  #[serde(default)]
+  pub track_detached: bool,
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn status_without_stop_fields_deserializes() {
+    let json = r#"{"playback_state":"Playing","track":null,"position":{"secs":3,"nanos":0},"volume":0.5,"loop_mode":"None","shuffle":false}"#;
+
+    let status: PlayerStatus = serde_json::from_str(json).unwrap();
+    assert!(!status.stop_after_current);
+    assert_eq!(status.end_behavior, EndBehavior::WillStopAtQueueEnd);
+    assert_eq!(status.stop_reason, None);
+    assert!(!status.track_detached);
+  }
+
+  #[test]
+  fn status_stop_reason_round_trips() {
+    let reasons = [
+      StopReason::EndOfQueue,
+      StopReason::UserRequested,
+      StopReason::QueueCleared,
+      StopReason::Error("Track has no supported audio codec".into()),
+    ];
+
+    for reason in reasons {
+      let status = PlayerStatus {
+        playback_state: PlaybackState::Stopped,
+        track: None,
+        position: Duration::ZERO,
+        volume: 1.0,
+        loop_mode: LoopMode::None,
+        shuffle: false,
+        stop_after_current: false,
+        end_behavior: EndBehavior::WillStopAtQueueEnd,
+        stop_reason: Some(reason.clone()),
+        track_detached: false,
+      };
+
+      let json = serde_json::to_string(&status).unwrap();
+      let status: PlayerStatus = serde_json::from_str(&json).unwrap();
+      assert_eq!(status.stop_reason, Some(reason));
+    }
+  }
+
+  #[test]
+  fn resolves_seek_positions() {
+    let secs = Duration::from_secs;
+    let position = secs(60);
+    let total = Some(secs(200));
+    let cases = [
+      (SeekPosition::Forward(secs(30)), total, Some(secs(90))),
+      (SeekPosition::Backward(secs(90)), total, Some(secs(0))),
+      (SeekPosition::To(secs(10)), None, Some(secs(10))),
+      // Past the end of the track is left for the source to handle
+      (SeekPosition::To(secs(300)), total, Some(secs(300))),
+      (SeekPosition::ToFraction(0.5), total, Some(secs(100))),
+      (SeekPosition::ToFraction(2.0), total, Some(secs(200))),
+      (SeekPosition::ForwardFraction(0.25), total, Some(secs(110))),
+      (SeekPosition::ForwardFraction(1.0), total, Some(secs(260))),
+      (SeekPosition::BackwardFraction(0.5), total, Some(secs(0))),
+      (SeekPosition::ToFraction(0.5), None, None),
+      (SeekPosition::ForwardFraction(0.25), None, None),
+      (SeekPosition::ToFraction(f32::NAN), total, None),
+      (SeekPosition::BackwardFraction(f32::INFINITY), total, None),
+    ];
+
+    for (seek_position, total_duration, expected) in cases {
+      assert_eq!(
+        seek_position.resolve(position, total_duration),
+        expected,
+        "{seek_position:?} of {total_duration:?}"
+      );
+    }
+  }
+}
@@ -0,0 +1,216 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use super::TrackMetadata;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FilterField {
+  Title,
+  /// Matches if any of the track's artists match
+  Artist,
+  Album,
+  AlbumArtist,
+  /// Matches if any of the track's genres match
+  Genre,
+  Date,
+}
+
+impl FilterField {
+  pub const NAMES: &'static [&'static str] =
+    &["title", "artist", "album", "album_artist", "genre", "date"];
+
+  pub fn from_name(name: &str) -> Option<Self> {
+    let field = match name {
+      "title" => Self::Title,
+      "artist" => Self::Artist,
+      "album" => Self::Album,
+      "album_artist" => Self::AlbumArtist,
+      "genre" => Self::Genre,
+      "date" => Self::Date,
+      _ => return None,
+    };
+
+    Some(field)
+  }
+
+  pub fn name(&self) -> &'static str {
+    match self {
+      Self::Title => "title",
+      Self::Artist => "artist",
+      Self::Album => "album",
+      Self::AlbumArtist => "album_artist",
+      Self::Genre => "genre",
+      Self::Date => "date",
+    }
+  }
+
+  /// Uses the values inferred from the file path for tracks without tags, like the rest of the server
+  fn values<'a>(&self, metadata: &'a TrackMetadata) -> Vec<&'a str> {
+    match self {
+      Self::Title => metadata.title_or_inferred().into_iter().collect(),
+      Self::Artist => metadata.artists_or_inferred(),
+      Self::Album => metadata.album_or_inferred().into_iter().collect(),
+      Self::AlbumArtist => metadata.album_artist.as_deref().into_iter().collect(),
+      Self::Genre => metadata.genres.iter().map(String::as_str).collect(),
+      Self::Date => metadata.date.as_deref().into_iter().collect(),
+    }
+  }
+}
+
+/// Comparisons ignore case
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FilterOp {
+  Is,
+  /// Also matches tracks where the field is missing
+  IsNot,
+  Contains,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FilterCondition {
+  pub field: FilterField,
+  pub op: FilterOp,
+  pub value: String,
+}
+
+impl FilterCondition {
+  pub fn matches(&self, metadata: &TrackMetadata) -> bool {
+    let value = self.value.to_lowercase();
+    let mut values = self
+      .field
+      .values(metadata)
+      .into_iter()
+      .map(str::to_lowercase);
+
+    match self.op {
+      FilterOp::Is => values.any(|field_value| field_value == value),
+      FilterOp::IsNot => !values.any(|field_value| field_value == value),
+      FilterOp::Contains => values.any(|field_value| field_value.contains(&value)),
+    }
+  }
+}
+
+impl fmt::Display for FilterCondition {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let op = match self.op {
+      FilterOp::Is => "=",
+      FilterOp::IsNot => "!=",
+      FilterOp::Contains => "~",
+    };
+
+    write!(f, "{}{op}{:?}", self.field.name(), self.value)
+  }
+}
+
+/// Limits playback to the tracks matching every condition, see `SetQueueFilter`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FilterExpr {
+  pub all: Vec<FilterCondition>,
+}
+
+impl FilterExpr {
+  pub fn matches(&self, metadata: &TrackMetadata) -> bool {
+    self.all.iter().all(|condition| condition.matches(metadata))
+  }
+}
+
+impl fmt::Display for FilterExpr {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    for (index, condition) in self.all.iter().enumerate() {
+      if index > 0 {
+        write!(f, " and ")?;
+      }
+      write!(f, "{condition}")?;
+    }
+
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::InferredMetadata;
+
+  fn condition(field: FilterField, op: FilterOp, value: &str) -> FilterCondition {
+    FilterCondition {
+      field,
+      op,
+      value: value.into(),
+    }
+  }
+
+  fn metadata() -> TrackMetadata {
+    TrackMetadata {
+      title: Some("Windowlicker".into()),
+      artists: vec!["Aphex Twin".into(), "AFX".into()],
+      genres: vec!["IDM".into()],
+      ..Default::default()
+    }
+  }
+
+  #[test]
+  fn matches_conditions() {
+    use FilterField::*;
+    use FilterOp::*;
+
+    let metadata = metadata();
+    let cases = [
+      (condition(Title, Is, "windowlicker"), true),
+      (condition(Title, Is, "window"), false),
+      (condition(Title, Contains, "LICK"), true),
+      (condition(Title, IsNot, "Windowlicker"), false),
+      // Any of the artists can match
+      (condition(Artist, Is, "afx"), true),
+      (condition(Artist, Contains, "twin"), true),
+      (condition(Artist, IsNot, "AFX"), false),
+      (condition(Genre, Is, "idm"), true),
+      // Missing fields never match, unless the condition is that they are not a value
+      (condition(Album, Is, ""), false),
+      (condition(Album, Contains, ""), false),
+      (condition(Date, IsNot, "1999"), true),
+      (condition(AlbumArtist, IsNot, "AFX"), true),
+    ];
+
+    for (condition, expected) in cases {
+      assert_eq!(condition.matches(&metadata), expected, "{condition}");
+    }
+  }
+
+  #[test]
+  fn uses_inferred_metadata() {
+    let metadata = TrackMetadata {
+      inferred: InferredMetadata {
+        title: Some("Xtal".into()),
+        artist: Some("Aphex Twin".into()),
+        ..Default::default()
+      },
+      ..Default::default()
+    };
+
+    assert!(condition(FilterField::Title, FilterOp::Is, "xtal").matches(&metadata));
+    assert!(condition(FilterField::Artist, FilterOp::Contains, "aphex").matches(&metadata));
+  }
+
+  #[test]
+  fn every_condition_must_match() {
+    let artist = condition(FilterField::Artist, FilterOp::Is, "AFX");
+    let genre = condition(FilterField::Genre, FilterOp::Is, "Ambient");
+    let metadata = metadata();
+
+    let filter = FilterExpr {
+      all: vec![artist.clone()],
+    };
+    assert!(filter.matches(&metadata));
+
+    let filter = FilterExpr {
+      all: vec![artist, genre],
+    };
+    assert!(!filter.matches(&metadata));
+    assert_eq!(filter.to_string(), "artist=\"AFX\" and genre=\"Ambient\"");
+
+    // No conditions match everything
+    assert!(FilterExpr { all: Vec::new() }.matches(&metadata));
+  }
+}
@@ -0,0 +1,86 @@
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+/// Internal statistics about the running server
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ServerStats {
+  pub blocking: BlockingStats,
+  /// How long each kind of request has taken to handle, for requests that were sent at least once
+  #[serde(default)]
+  pub requests: Vec<RequestTiming>,
+}
+
+/// Upper bounds of the buckets in `RequestTiming::buckets`, the last bucket counts every slower request
+pub const TIMING_BUCKETS: [Duration; 6] = [
+  Duration::from_millis(1),
+  Duration::from_millis(5),
+  Duration::from_millis(25),
+  Duration::from_millis(100),
+  Duration::from_millis(250),
+  Duration::from_secs(1),
+];
+
+/// A histogram of the time taken to handle one kind of request since the server started
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RequestTiming {
+  pub name: String,
+  pub count: u64,
+  pub total: Duration,
+  pub max: Duration,
+  /// Requests that took at most each of `TIMING_BUCKETS`, followed by the requests that took longer
+  pub buckets: Vec<u64>,
+}
+
+/// Listening statistics since the server started, or since they were last reset
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionStats {
+  /// How long the server has been running, this is not reset
+  pub uptime: Duration,
+  pub since: SystemTime,
+  /// Time spent playing tracks, measured up to the position each track was left at
+  pub listening_time: Duration,
+  pub tracks_finished: u64,
+  pub tracks_skipped: u64,
+  /// The artist with the most listening time, and how long they were listened to
+  pub top_artist: Option<(String, Duration)>,
+}
+
+/// An open connection to the ipc socket
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConnectionInfo {
+  pub id: u64,
+  /// Set with `Identify`, or `anonymous-<id>`
+  pub name: String,
+  pub since: SystemTime,
+  pub requests_handled: u64,
+}
+
+/// The server's cache of loaded tracks, which tracks are shared through instead of reading their tags again
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct CacheStats {
+  /// Paths in the cache, including tracks that are no longer loaded and haven't been purged yet
+  pub entries: usize,
+  /// Entries whose track is still loaded, by the track list or the recently used tracks
+  pub live: usize,
+  /// Estimated memory used by the live tracks' metadata
+  #[serde(default)]
+  pub live_bytes: u64,
+  /// Recently used tracks kept loaded after leaving the track list
+  pub retained: usize,
+  pub max_retained: usize,
+  /// Tracks dropped from the recently used tracks to stay under `max_retained`, since the server started
+  pub evicted: u64,
+  /// Entries of tracks that were no longer loaded removed from the cache, since the server started
+  pub purged: u64,
+}
+
+/// Depths of the server's blocking task lanes
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct BlockingStats {
+  /// Bulk tasks (such as directory scans) waiting for a free slot
+  pub bulk_queued: usize,
+  pub bulk_running: usize,
+  /// Tasks that playback is waiting on, such as creating decoders
+  pub interactive_running: usize,
+}
@@ -1,16 +1,171 @@
-use std::{collections::HashSet, path::PathBuf, time::Duration};
+use std::{fmt, path::PathBuf, time::Duration};
 
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
+
+use super::NormalizationMode;
 
 #[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct TrackMetadata {
   pub title: Option<String>,
-  pub artists: HashSet<String>,
+  /// In tag order, so the first artist is the primary one
+  #[serde(deserialize_with = "deserialize_unique")]
+  pub artists: Vec<String>,
   pub album: Option<String>,
+  #[serde(default)]
+  pub album_artist: Option<String>,
   pub track_number: Option<usize>,
   pub date: Option<String>,
-  pub genres: HashSet<String>,
+  /// In tag order
+  #[serde(deserialize_with = "deserialize_unique")]
+  pub genres: Vec<String>,
   pub comments: Vec<String>,
+  /// Unsynchronized lyrics, not sent with tracks to keep track lists small, see `QueryLyrics`
+  #[serde(skip)]
+  pub lyrics: Option<String>,
+  /// Original values of tags that were repaired by decoding them with a different charset
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub charset_repairs: Vec<CharsetRepair>,
+  /// Guesses made from the file path for a track without a title tag
+  #[serde(default, skip_serializing_if = "InferredMetadata::is_empty")]
+  pub inferred: InferredMetadata,
+  /// Embedded album art written to the server's cache, or an image such as `cover.jpg` next to the file
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub art_path: Option<PathBuf>,
+  #[serde(default, skip_serializing_if = "ReplayGain::is_empty")]
+  pub replay_gain: ReplayGain,
+}
+
+impl TrackMetadata {
+  /// The title tag, or the title inferred from the file path
+  pub fn title_or_inferred(&self) -> Option<&str> {
+    self.title.as_deref().or(self.inferred.title.as_deref())
+  }
+
+  /// The album tag, or the album inferred from the file path
+  pub fn album_or_inferred(&self) -> Option<&str> {
+    self.album.as_deref().or(self.inferred.album.as_deref())
+  }
+
+  pub fn track_number_or_inferred(&self) -> Option<usize> {
+    self.track_number.or(self.inferred.track_number)
+  }
+
+  /// The artist tags in tag order, or the artist inferred from the file path if there are none
+  pub fn artists_or_inferred(&self) -> Vec<&str> {
+    if self.artists.is_empty() {
+      return self.inferred.artist.as_deref().into_iter().collect();
+    }
+
+    self.artists.iter().map(String::as_str).collect()
+  }
+
+  /// Appends `artist` unless the track already has it
+  pub fn add_artist(&mut self, artist: String) {
+    push_unique(&mut self.artists, artist);
+  }
+
+  /// Appends `genre` unless the track already has it
+  pub fn add_genre(&mut self, genre: String) {
+    push_unique(&mut self.genres, genre);
+  }
+}
+
+fn push_unique(values: &mut Vec<String>, value: String) {
+  if !values.contains(&value) {
+    values.push(value);
+  }
+}
+
+/// Artists and genres used to be sets, which were also sent as lists, so only duplicates need to be removed
+fn deserialize_unique<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+  let mut unique = Vec::new();
+  for value in Vec::<String>::deserialize(deserializer)? {
+    push_unique(&mut unique, value);
+  }
+
+  Ok(unique)
+}
+
+/// Metadata guessed from a file's name and parent directories
+///
+/// Kept apart from the real tags so a guess is never mistaken for one
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InferredMetadata {
+  pub title: Option<String>,
+  pub artist: Option<String>,
+  pub album: Option<String>,
+  pub track_number: Option<usize>,
+}
+
+impl InferredMetadata {
+  pub fn is_empty(&self) -> bool {
+    *self == Self::default()
+  }
+}
+
+/// ReplayGain tags, gains are in dB and peaks are linear sample values where 1.0 is full scale
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ReplayGain {
+  pub track_gain: Option<f32>,
+  pub track_peak: Option<f32>,
+  pub album_gain: Option<f32>,
+  pub album_peak: Option<f32>,
+}
+
+impl ReplayGain {
+  pub fn is_empty(&self) -> bool {
+    *self == Self::default()
+  }
+
+  /// The amplification for `mode`, or `None` if the track has no gain tags to use
+  ///
+  /// Falls back to the other gain if the one for `mode` is missing,
+  /// and is limited so the peak is not amplified past full scale
+  pub fn factor(&self, mode: NormalizationMode) -> Option<f32> {
+    let (gain, peak) = match mode {
+      NormalizationMode::Off => return None,
+      NormalizationMode::Track => (
+        self.track_gain.or(self.album_gain),
+        self.track_peak.or(self.album_peak),
+      ),
+      NormalizationMode::Album => (
+        self.album_gain.or(self.track_gain),
+        self.album_peak.or(self.track_peak),
+      ),
+    };
+
+    let factor = 10f32.powf(gain? / 20.0);
+    Some(match peak {
+      Some(peak) if peak > 0.0 => factor.min(1.0 / peak),
+      _ => factor,
+    })
+  }
+}
+
+/// The entry at the current index, see `QueryCurrentEntry`
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CurrentEntry {
+  /// Position in play order
+  pub index: usize,
+  /// `None` if the track list is empty
+  pub track: Option<Track>,
+  /// If starting playback plays `track`
+  ///
+  /// False while stopped at the end of the track list, where playback starts from the first track again
+  pub will_play_next: bool,
+  /// The track that starting playback plays, or `track` while something is playing
+  pub up_next: Option<Track>,
+  /// If `track` was removed from the track list and is playing until it ends, `index` is then the entry that plays after it
+  #[serde(default)]
+  pub detached: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CharsetRepair {
+  pub original: String,
+  pub repaired: String,
+  /// The name of the encoding the original bytes were decoded with
+  pub encoding: String,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -21,14 +176,75 @@ pub struct Track {
   pub metadata: TrackMetadata,
 }
 
+/// A track loaded by `InspectTrack`, with details that are not sent in track lists
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct InspectedTrack {
+  pub track: Track,
+  pub sample_rate: u32,
+  pub channels: u16,
+  /// If the start of the file could not be decoded, so the format is what the container reported
+  #[serde(default)]
+  pub format_unverified: bool,
+  /// If the file has an unsynchronized lyrics tag, see `QueryLyrics`
+  pub has_lyrics: bool,
+  /// Frames of encoder delay at the start of the file, which are skipped for gapless playback
+  pub encoder_delay: u64,
+  /// Frames of encoder padding at the end of the file, which are skipped for gapless playback
+  pub encoder_padding: u64,
+}
+
+/// Identifies a single entry in the track list
+///
+/// The same file queued twice has a different id for each entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct TrackId(pub usize);
+
+/// State that belongs to one entry in the track list rather than to the file
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct InstanceState {
+  /// Gain in dB applied on top of the player volume
+  pub gain_db: Option<f32>,
+  /// If the track could not be played
+  pub failed: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TrackInstanceInfo {
+  pub id: TrackId,
+  pub state: InstanceState,
+  /// If the entry doesn't match the queue filter, so playback skips over it
+  #[serde(default)]
+  pub filtered_out: bool,
+}
+
+/// A summary of the track list, which can be queried without waiting for a change to the track list to finish
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct QueueSummary {
+  /// The track list generation this summary was made from
+  pub generation: u64,
+  pub len: usize,
+  /// Sum of the durations of every track with a known duration
+  pub total_duration: Duration,
+  /// Number of tracks whose duration is unknown
+  pub unknown_durations: usize,
+}
+
 /// A representation of the player's track list
-/// `track_list.len()` will always be equal to `shuffle_indicies.len()`
+/// `track_list.len()` will always be equal to `shuffle_indicies.len()` and `instances.len()`
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TrackListSnapshot {
   pub track_list: Vec<Track>,
   pub shuffle_indicies: Vec<usize>,
+  /// The id and state of each entry in `track_list`
+  #[serde(default)]
+  pub instances: Vec<TrackInstanceInfo>,
+  /// Incremented every time the track list is changed, see `LoadTracks::expected_generation`
+  #[serde(default)]
+  pub generation: u64,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum TrackListUpdate {
   Insert {
     index: usize,
@@ -48,4 +264,94 @@ pub enum TrackListUpdate {
   Shuffle {
     new_shuffle_indicies: Vec<usize>,
   },
+
+  /// The entries were rearranged, the entry at `new_order[i]` in the old track list is now at `i`
+  Reorder {
+    new_order: Vec<usize>,
+    new_shuffle_indicies: Vec<usize>,
+  },
+}
+
+/// The changes made to the track list since a generation, see `QueryTrackListDiff`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum TrackListDiff {
+  /// Applying `updates` in order brings a track list up to date with `generation`
+  Updates {
+    generation: u64,
+    updates: Vec<TrackListUpdate>,
+  },
+  /// The server no longer remembers the changes since that generation, query the whole track list instead
+  TooOld,
+}
+
+/// Why a path passed to `LoadTracks` could not be loaded
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LoadTrackErrorKind {
+  NotFound,
+  /// Either the filesystem denied access, or the path is outside of the server's allowed roots
+  PermissionDenied,
+  /// The file's format or codec is not one the server was built with
+  UnsupportedCodec,
+  /// The file could not be read as audio, it may not be an audio file or be damaged
+  ProbeFailed,
+  /// The file was recognized, but its audio could not be decoded
+  DecodeFailed,
+  ReadDirFailed,
+  Other(String),
+}
+
+impl fmt::Display for LoadTrackErrorKind {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::NotFound => write!(f, "Not found"),
+      Self::PermissionDenied => write!(f, "Permission denied"),
+      Self::UnsupportedCodec => write!(f, "No supported audio codec"),
+      Self::ProbeFailed => write!(f, "Not a recognized audio file"),
+      Self::DecodeFailed => write!(f, "Failed to decode the audio"),
+      Self::ReadDirFailed => write!(f, "Failed to read the directory"),
+      Self::Other(message) => write!(f, "{message}"),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn artists_keep_tag_order() {
+    let mut metadata = TrackMetadata::default();
+    for artist in ["Zed", "Abba", "Zed"] {
+      metadata.add_artist(artist.into());
+    }
+
+    assert_eq!(metadata.artists_or_inferred(), ["Zed", "Abba"]);
+  }
+
+  #[test]
+  fn artists_fall_back_to_inferred() {
+    let mut metadata = TrackMetadata::default();
+    assert!(metadata.artists_or_inferred().is_empty());
+
+    metadata.inferred.artist = Some("Inferred".into());
+    assert_eq!(metadata.artists_or_inferred(), ["Inferred"]);
+
+    metadata.add_artist("Tagged".into());
+    assert_eq!(metadata.artists_or_inferred(), ["Tagged"]);
+  }
+
+  #[test]
+  fn deserializing_drops_duplicates() {
+    let mut metadata = TrackMetadata::default();
+    metadata.add_artist("Zed".into());
+    metadata.add_genre("Rock".into());
+
+    let mut json = serde_json::to_value(&metadata).unwrap();
+    json["artists"] = serde_json::json!(["Zed", "Abba", "Zed"]);
+    json["genres"] = serde_json::json!(["Rock", "Rock", "Pop"]);
+
+    let metadata: TrackMetadata = serde_json::from_value(json).unwrap();
+    assert_eq!(metadata.artists, ["Zed", "Abba"]);
+    assert_eq!(metadata.genres, ["Rock", "Pop"]);
+  }
 }
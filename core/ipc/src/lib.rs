@@ -1,5 +1,13 @@
 use git_version::git_version;
-use std::{env, sync::OnceLock};
+use std::{
+  env,
+  ffi::OsString,
+  fs::{self, DirBuilder},
+  io,
+  os::unix::fs::{DirBuilderExt, MetadataExt},
+  path::{Path, PathBuf},
+  sync::OnceLock,
+};
 
 pub use api::*;
 mod api;
@@ -22,16 +30,219 @@ pub fn version() -> Version {
   Version(version_string())
 }
 
-fn read_socket_path() -> String {
-  let runtime_path = env::var("XDG_RUNTIME_DIR").unwrap_or_else(|_| {
-    let uid = rustix::process::getuid();
-    format!("/run/user/{}", uid.as_raw())
-  });
+/// Creates `dir` readable only by the current user, or checks that an existing `dir` belongs to them
+fn create_private_dir(dir: &Path, uid: u32) -> io::Result<()> {
+  match DirBuilder::new().mode(0o700).create(dir) {
+    Err(error) if error.kind() == io::ErrorKind::AlreadyExists => (),
+    result => return result,
+  }
+
+  // The directory is in a shared location, so anyone could have created it first
+  let metadata = fs::symlink_metadata(dir)?;
+  if !metadata.is_dir() || metadata.uid() != uid || metadata.mode() & 0o077 != 0 {
+    return Err(io::Error::new(
+      io::ErrorKind::PermissionDenied,
+      "it is not a private directory owned by the current user",
+    ));
+  }
+
+  Ok(())
+}
+
+/// The parts of the environment the runtime directory and socket path are resolved from
+///
+/// `RuntimeEnv::current` reads them from the process, tests can fill them in instead
+#[derive(Debug, Clone)]
+pub struct RuntimeEnv {
+  /// `$XDG_RUNTIME_DIR`
+  pub xdg_runtime_dir: Option<OsString>,
+  /// `$HSM_SOCKET_PATH`
+  pub socket_path: Option<OsString>,
+  pub uid: u32,
+  /// The directory `<uid>` runtime directories are created in by systemd or elogind, `/run/user`
+  pub run_user_dir: PathBuf,
+  /// `$TMPDIR`, or `/tmp` if it is not set
+  pub temp_dir: PathBuf,
+}
+
+impl RuntimeEnv {
+  pub fn current() -> Self {
+    Self {
+      xdg_runtime_dir: env::var_os("XDG_RUNTIME_DIR"),
+      socket_path: env::var_os(SOCKET_PATH_VAR),
+      uid: rustix::process::getuid().as_raw(),
+      run_user_dir: PathBuf::from("/run/user"),
+      temp_dir: env::temp_dir(),
+    }
+  }
+
+  /// `$XDG_RUNTIME_DIR`, or `/run/user/<uid>` if it is not set
+  ///
+  /// If neither exists, `$TMPDIR/homeslashmusic-<uid>` is created and used instead
+  pub fn runtime_dir(&self) -> PathBuf {
+    if let Some(runtime_dir) = self.xdg_runtime_dir.as_ref().filter(|dir| !dir.is_empty()) {
+      return runtime_dir.into();
+    }
+
+    let run_user_dir = self.run_user_dir.join(self.uid.to_string());
+    if run_user_dir.is_dir() {
+      return run_user_dir;
+    }
 
-  format!("{runtime_path}/homeslashmusic.sock")
+    // Systems without systemd or elogind usually don't create `/run/user`
+    let tmp_dir = self.temp_dir.join(format!("homeslashmusic-{}", self.uid));
+    match create_private_dir(&tmp_dir, self.uid) {
+      Ok(()) => eprintln!(
+        "XDG_RUNTIME_DIR is not set and {} does not exist, using {}",
+        run_user_dir.display(),
+        tmp_dir.display()
+      ),
+      Err(error) => eprintln!(
+        "XDG_RUNTIME_DIR is not set and {} does not exist, {} can not be used either: {error}",
+        run_user_dir.display(),
+        tmp_dir.display()
+      ),
+    }
+
+    tmp_dir
+  }
+
+  /// `override_path` if there is one, otherwise `$HSM_SOCKET_PATH`, or `homeslashmusic.sock` in `runtime_dir`
+  pub fn socket_path(&self, override_path: Option<&Path>) -> PathBuf {
+    if let Some(override_path) = override_path {
+      return override_path.into();
+    }
+
+    match &self.socket_path {
+      Some(socket_path) if !socket_path.is_empty() => socket_path.into(),
+      _ => self.runtime_dir().join("homeslashmusic.sock"),
+    }
+  }
 }
 
-pub fn socket_path() -> &'static str {
+/// `RuntimeEnv::runtime_dir` of the process environment, read once
+///
+/// The fallback directory is always the same, so the server and clients agree on where the socket is.
+pub fn runtime_dir() -> &'static str {
   static PATH: OnceLock<String> = OnceLock::new();
-  PATH.get_or_init(read_socket_path)
+  PATH.get_or_init(|| {
+    RuntimeEnv::current()
+      .runtime_dir()
+      .to_string_lossy()
+      .into_owned()
+  })
+}
+
+/// Overrides the default socket path, but not a path passed to `socket_path_with`
+pub const SOCKET_PATH_VAR: &str = "HSM_SOCKET_PATH";
+
+/// `RuntimeEnv::socket_path` of the process environment
+pub fn socket_path_with(override_path: Option<&Path>) -> PathBuf {
+  let env = RuntimeEnv {
+    // Resolved once, so the fallback directory is only reported once
+    xdg_runtime_dir: Some(runtime_dir().into()),
+    ..RuntimeEnv::current()
+  };
+  env.socket_path(override_path)
+}
+
+/// `socket_path_with(None)`, read once
+pub fn socket_path() -> &'static Path {
+  static PATH: OnceLock<PathBuf> = OnceLock::new();
+  PATH.get_or_init(|| socket_path_with(None))
+}
+
+/// The read-only socket that goes with `socket_path`, such as `homeslashmusic.ro.sock` for `homeslashmusic.sock`
+///
+/// Connections to this socket may only send `Query*` requests
+pub fn read_only_socket_path_for(socket_path: &Path) -> PathBuf {
+  socket_path.with_extension("ro.sock")
+}
+
+pub fn read_only_socket_path() -> &'static Path {
+  static PATH: OnceLock<PathBuf> = OnceLock::new();
+  PATH.get_or_init(|| read_only_socket_path_for(socket_path()))
+}
+
+#[cfg(test)]
+mod tests {
+  use std::os::unix::fs::PermissionsExt;
+
+  use super::*;
+
+  /// An environment with nothing set, whose `/run/user` and temp directories are in `dir`
+  fn scrubbed_env(dir: &Path) -> RuntimeEnv {
+    RuntimeEnv {
+      xdg_runtime_dir: None,
+      socket_path: None,
+      uid: rustix::process::getuid().as_raw(),
+      run_user_dir: dir.join("run/user"),
+      temp_dir: dir.join("tmp"),
+    }
+  }
+
+  #[test]
+  fn socket_path_precedence() {
+    let dir = tempfile::tempdir().unwrap();
+    let env = RuntimeEnv {
+      xdg_runtime_dir: Some("/xdg".into()),
+      socket_path: Some("/env.sock".into()),
+      ..scrubbed_env(dir.path())
+    };
+
+    let flag = Path::new("/flag.sock");
+    assert_eq!(env.socket_path(Some(flag)), flag);
+    assert_eq!(env.socket_path(None), Path::new("/env.sock"));
+
+    // An empty variable is the same as an unset one
+    for socket_path in [None, Some(OsString::new())] {
+      let env = RuntimeEnv {
+        socket_path,
+        ..env.clone()
+      };
+      assert_eq!(env.socket_path(None), Path::new("/xdg/homeslashmusic.sock"));
+    }
+  }
+
+  #[test]
+  fn runtime_dir_falls_back_to_run_user() {
+    let dir = tempfile::tempdir().unwrap();
+    let env = scrubbed_env(dir.path());
+    let run_user_dir = env.run_user_dir.join(env.uid.to_string());
+    fs::create_dir_all(&run_user_dir).unwrap();
+
+    assert_eq!(env.runtime_dir(), run_user_dir);
+    assert_eq!(
+      env.socket_path(None),
+      run_user_dir.join("homeslashmusic.sock")
+    );
+  }
+
+  #[test]
+  fn runtime_dir_falls_back_to_a_private_temp_dir() {
+    let dir = tempfile::tempdir().unwrap();
+    let env = scrubbed_env(dir.path());
+    fs::create_dir(&env.temp_dir).unwrap();
+
+    let expected = env.temp_dir.join(format!("homeslashmusic-{}", env.uid));
+    assert_eq!(env.runtime_dir(), expected);
+    let mode = fs::metadata(&expected).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o700);
+
+    // The directory is reused by the next client
+    assert_eq!(env.runtime_dir(), expected);
+  }
+
+  #[test]
+  fn shared_temp_dir_must_be_private() {
+    let dir = tempfile::tempdir().unwrap();
+    let uid = rustix::process::getuid().as_raw();
+    let shared = dir.path().join("shared");
+    fs::create_dir(&shared).unwrap();
+    fs::set_permissions(&shared, fs::Permissions::from_mode(0o777)).unwrap();
+
+    let error = create_private_dir(&shared, uid).unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::PermissionDenied);
+    assert!(create_private_dir(&dir.path().join("private"), uid).is_ok());
+  }
 }
@@ -3,16 +3,30 @@ use std::{error::Error, sync::Arc};
 use hsm_ipc::{
   Event, Reply, Request,
   client::{deserialize_reply, serialize_request},
+  server::RequestOrigin,
 };
 use smol::Executor;
 
+pub use shared_state::{SharedPlayerState, SharedStateHandle};
+
+mod shared_state;
+
 async fn send_request<R: Request>(sender: &(impl RequestSender + ?Sized), request: R) -> Reply<R> {
   let reply_data = sender.send_json(serialize_request(request)).await;
   deserialize_reply::<R>(&reply_data).expect("Hsm plugins should not fail json parsing")
 }
 
 pub trait RequestSender {
-  fn send_json(&self, request_data: String) -> impl Future<Output = String> + Send + Sync;
+  /// Sends a request, which will be rejected if it is not allowed from `origin`
+  fn send_json_from(
+    &self,
+    origin: RequestOrigin,
+    request_data: String,
+  ) -> impl Future<Output = String> + Send + Sync;
+
+  fn send_json(&self, request_data: String) -> impl Future<Output = String> + Send + Sync {
+    self.send_json_from(RequestOrigin::default(), request_data)
+  }
 
   fn send_request<R: Request>(&self, request: R) -> impl Future<Output = Reply<R>> + Send + Sync
   where
@@ -24,11 +38,74 @@ pub trait RequestSender {
 
 /// An ipc client that is compiled into the `hsm-server` binary
 /// Communication is done via channels instead of json.
+///
+/// # Lifecycle
+///
+/// `init` is called when the server starts, or when a disabled plugin is enabled with `SetPluginEnabled`.
+/// Once the server is handling requests, `on_ready` is called, then `run` and `on_event` are polled on the same task
+/// until `run` returns, an error is returned, or the plugin is disabled or the server shuts down.
+/// In the last two cases `shutdown` is called before the plugin is dropped, and a re-enabled plugin is initialized again.
+///
+/// # Events
+///
+/// - Events describing the current state (track, playback state, loop mode, end behavior, shuffle, volume and rate)
+///   are delivered first, so a plugin does not have to query them
+/// - Events are delivered in the order they happened, and `on_event` is not called again until the previous call returns
+/// - The events caused by a request are sent before its reply, but may be delivered after the reply arrives
+/// - Events that happen while a plugin is disabled are not delivered
+///
+/// # Example
+///
+/// ```
+/// use std::{convert::Infallible, sync::Arc};
+///
+/// use hsm_ipc::Event;
+/// use hsm_plugin::{Plugin, RequestSender, SharedStateHandle};
+/// use smol::Executor;
+///
+/// struct NowPlayingLogger<Tx> {
+///   request_tx: Tx,
+/// }
+///
+/// impl<'ex, Tx: RequestSender + Send + Sync + 'ex> Plugin<'ex, Tx> for NowPlayingLogger<Tx> {
+///   type Error = Infallible;
+///   type Config = ();
+///   const NAME: &'static str = "now-playing-logger";
+///
+///   async fn init(
+///     _config: (),
+///     request_tx: Tx,
+///     _shared_state: SharedStateHandle,
+///     _executor: Arc<Executor<'ex>>,
+///   ) -> Result<Self, Self::Error> {
+///     Ok(Self { request_tx })
+///   }
+///
+///   async fn on_event(&self, event: Event) -> Result<(), Self::Error> {
+///     if let Event::TrackChanged(Some(track)) = event {
+///       println!("Now playing {:?}", track.metadata.title_or_inferred());
+///     }
+///
+///     Ok(())
+///   }
+///
+///   async fn run(&self) -> Result<(), Self::Error> {
+///     // Returning would stop the plugin, this one only reacts to events
+///     std::future::pending().await
+///   }
+/// }
+/// ```
 pub trait Plugin<'ex, Tx: RequestSender> {
   type Error: Error + 'static;
+  /// Options passed to the plugin when it is loaded
+  type Config;
+  /// Identifies the plugin in `QueryPlugins` and `SetPluginEnabled`
+  const NAME: &'static str;
 
   fn init(
+    config: Self::Config,
     request_tx: Tx,
+    shared_state: SharedStateHandle,
     executor: Arc<Executor<'ex>>,
   ) -> impl Future<Output = Result<Self, Self::Error>> + Send
   where
@@ -37,4 +114,18 @@ pub trait Plugin<'ex, Tx: RequestSender> {
   fn on_event(&self, event: Event) -> impl Future<Output = Result<(), Self::Error>> + Send;
 
   fn run(&self) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+  /// Called once the server is handling requests, before `run` and the first `on_event`
+  ///
+  /// Requests sent from `init` are not answered until the server starts, so startup queries belong here
+  fn on_ready(&self) -> impl Future<Output = Result<(), Self::Error>> + Send {
+    async { Ok(()) }
+  }
+
+  /// Called before the plugin is dropped, when the server shuts down or the plugin is disabled
+  ///
+  /// Requests can still be sent while this runs
+  fn shutdown(&self) -> impl Future<Output = Result<(), Self::Error>> + Send {
+    async { Ok(()) }
+  }
 }
@@ -0,0 +1,117 @@
+use std::{
+  sync::{
+    Arc,
+    atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering},
+  },
+  time::Duration,
+};
+
+use hsm_ipc::PlaybackState;
+
+/// Frequently read player values, published by the audio server so plugins can read them without sending requests
+///
+/// Only the audio server should update these values, plugins get a read only `SharedStateHandle`
+#[derive(Debug)]
+pub struct SharedPlayerState {
+  position_micros: AtomicU64,
+  playback_state: AtomicUsize,
+  volume_bits: AtomicU32,
+  rate_bits: AtomicU32,
+  track_generation: AtomicU64,
+}
+
+impl Default for SharedPlayerState {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl SharedPlayerState {
+  pub fn new() -> Self {
+    Self {
+      position_micros: AtomicU64::new(0),
+      playback_state: AtomicUsize::new(PlaybackState::Stopped as usize),
+      volume_bits: AtomicU32::new(1.0f32.to_bits()),
+      rate_bits: AtomicU32::new(1.0f32.to_bits()),
+      track_generation: AtomicU64::new(0),
+    }
+  }
+
+  pub fn handle(self: &Arc<Self>) -> SharedStateHandle {
+    SharedStateHandle(self.clone())
+  }
+
+  pub fn position(&self) -> Duration {
+    Duration::from_micros(self.position_micros.load(Ordering::Relaxed))
+  }
+
+  pub fn set_position(&self, position: Duration) {
+    let micros = u64::try_from(position.as_micros()).unwrap_or(u64::MAX);
+    self.position_micros.store(micros, Ordering::Relaxed);
+  }
+
+  pub fn playback_state(&self) -> PlaybackState {
+    match self.playback_state.load(Ordering::Acquire) {
+      state if state == PlaybackState::Playing as usize => PlaybackState::Playing,
+      state if state == PlaybackState::Paused as usize => PlaybackState::Paused,
+      _ => PlaybackState::Stopped,
+    }
+  }
+
+  pub fn set_playback_state(&self, playback_state: PlaybackState) {
+    self
+      .playback_state
+      .store(playback_state as usize, Ordering::Release);
+  }
+
+  pub fn volume(&self) -> f32 {
+    f32::from_bits(self.volume_bits.load(Ordering::Relaxed))
+  }
+
+  pub fn set_volume(&self, volume: f32) {
+    self.volume_bits.store(volume.to_bits(), Ordering::Relaxed);
+  }
+
+  pub fn rate(&self) -> f32 {
+    f32::from_bits(self.rate_bits.load(Ordering::Relaxed))
+  }
+
+  pub fn set_rate(&self, rate: f32) {
+    self.rate_bits.store(rate.to_bits(), Ordering::Relaxed);
+  }
+
+  /// Incremented every time the current track changes
+  pub fn track_generation(&self) -> u64 {
+    self.track_generation.load(Ordering::Acquire)
+  }
+
+  pub fn next_track_generation(&self) {
+    self.track_generation.fetch_add(1, Ordering::AcqRel);
+  }
+}
+
+/// Read only access to the `SharedPlayerState`
+#[derive(Debug, Clone)]
+pub struct SharedStateHandle(Arc<SharedPlayerState>);
+
+impl SharedStateHandle {
+  pub fn position(&self) -> Duration {
+    self.0.position()
+  }
+
+  pub fn playback_state(&self) -> PlaybackState {
+    self.0.playback_state()
+  }
+
+  pub fn volume(&self) -> f32 {
+    self.0.volume()
+  }
+
+  pub fn rate(&self) -> f32 {
+    self.0.rate()
+  }
+
+  pub fn track_generation(&self) -> u64 {
+    self.0.track_generation()
+  }
+}
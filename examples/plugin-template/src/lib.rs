@@ -0,0 +1,134 @@
+//! A small plugin to copy when writing your own, built into `hsm-server` with the `example-plugin` feature
+//!
+//! It prints a now playing line whenever it changes, where a real plugin might draw to a display
+
+use std::sync::Arc;
+
+use hsm_ipc::{Event, PlaybackState, Track, requests};
+use hsm_plugin::{Plugin, RequestSender, SharedStateHandle};
+use smol::{Executor, lock::Mutex};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum TemplateError {
+  #[error("Template plugin could not query the server: {0}")]
+  RequestFailed(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct TemplateOptions {
+  /// Printed before the now playing line
+  pub prefix: String,
+}
+
+impl Default for TemplateOptions {
+  fn default() -> Self {
+    Self {
+      prefix: "Now playing".into(),
+    }
+  }
+}
+
+/// What is shown on the "display"
+#[derive(Debug)]
+struct Display {
+  track: Option<Track>,
+  playback_state: PlaybackState,
+  /// The last line that was drawn, so unrelated events don't redraw it
+  line: String,
+}
+
+pub struct TemplatePlugin<Tx> {
+  prefix: String,
+  request_tx: Tx,
+  display: Mutex<Display>,
+}
+
+impl<Tx> TemplatePlugin<Tx> {
+  fn format_line(&self, display: &Display) -> String {
+    let Some(track) = &display.track else {
+      return format!("{}: nothing", self.prefix);
+    };
+
+    let metadata = &track.metadata;
+    let title = metadata.title_or_inferred().unwrap_or("Unknown title");
+    let paused = match display.playback_state {
+      PlaybackState::Playing => "",
+      PlaybackState::Paused => " (paused)",
+      PlaybackState::Stopped => " (stopped)",
+    };
+
+    match metadata.artists_or_inferred().as_slice() {
+      [] => format!("{}: {title}{paused}", self.prefix),
+      artists => format!("{}: {title} by {}{paused}", self.prefix, artists.join(", ")),
+    }
+  }
+
+  async fn draw(&self) {
+    let mut display = self.display.lock().await;
+    let line = self.format_line(&display);
+
+    if line != display.line {
+      println!("{line}");
+      display.line = line;
+    }
+  }
+}
+
+impl<'ex, Tx: RequestSender + Send + Sync + 'ex> Plugin<'ex, Tx> for TemplatePlugin<Tx> {
+  type Error = TemplateError;
+  type Config = TemplateOptions;
+  const NAME: &'static str = "template";
+
+  async fn init(
+    options: TemplateOptions,
+    request_tx: Tx,
+    _shared_state: SharedStateHandle,
+    _executor: Arc<Executor<'ex>>,
+  ) -> Result<Self, Self::Error> {
+    Ok(Self {
+      prefix: options.prefix,
+      request_tx,
+      display: Mutex::new(Display {
+        track: None,
+        playback_state: PlaybackState::Stopped,
+        line: String::new(),
+      }),
+    })
+  }
+
+  async fn on_ready(&self) -> Result<(), Self::Error> {
+    let version = self
+      .request_tx
+      .send_request(requests::QueryVersion)
+      .await
+      .map_err(TemplateError::RequestFailed)?;
+    println!("Template plugin connected to hsm-server {}", version.0);
+
+    Ok(())
+  }
+
+  async fn on_event(&self, event: Event) -> Result<(), Self::Error> {
+    // The current track and playback state are the first events, so they don't need to be queried
+    match event {
+      Event::TrackChanged(track) => self.display.lock().await.track = track,
+      Event::PlaybackStateChanged(playback_state) => {
+        self.display.lock().await.playback_state = playback_state
+      }
+      _ => return Ok(()),
+    }
+
+    self.draw().await;
+    Ok(())
+  }
+
+  async fn run(&self) -> Result<(), Self::Error> {
+    // Everything happens in `on_event`, returning would stop the plugin
+    std::future::pending().await
+  }
+
+  async fn shutdown(&self) -> Result<(), Self::Error> {
+    println!("{}: cleared", self.prefix);
+    Ok(())
+  }
+}
@@ -29,6 +29,15 @@
 
     packages.${system}.homeslashmusic = pkgs.callPackage ./nix/homeslashmusic.nix {};
 
+    # Only the ipc socket and the audio engine, without zbus or mpris-server
+    packages.${system}.homeslashmusic-minimal = pkgs.callPackage ./nix/homeslashmusic.nix {
+      buildNoDefaultFeatures = true;
+      buildFeatures = ["hsm-server/hsm-plugin-ipc"];
+    };
+
+    # `nix flake check` makes sure the minimal build keeps compiling
+    checks.${system}.no-dbus = self.packages.${system}.homeslashmusic-minimal;
+
     defaultPackage.${system} = self.packages.${system}.homeslashmusic;
 
     overlays.default = final: prev: {
@@ -6,6 +6,7 @@ use std::io::Error;
 mod src {
   #![allow(unused)]
   mod cli;
+  mod duration;
 
   pub use cli::Cli;
 }
@@ -1,10 +1,25 @@
-use std::{num::ParseFloatError, path::PathBuf, time::Duration};
+use std::{
+  num::{NonZeroUsize, ParseFloatError},
+  ops::RangeInclusive,
+  path::PathBuf,
+  time::Duration,
+};
 
 use clap::{Args, Parser, Subcommand, ValueEnum};
-use hsm_ipc::SeekPosition;
+use hsm_ipc::{EventKind, FilterCondition, FilterField, FilterOp, SeekPosition};
+
+use super::duration::parse_duration;
 
 #[derive(Debug, Parser)]
 pub struct Cli {
+  /// Connect to this socket instead of `$HSM_SOCKET_PATH` or `$XDG_RUNTIME_DIR/homeslashmusic.sock`
+  #[arg(long, global = true)]
+  pub socket: Option<PathBuf>,
+
+  /// Print replies as JSON, and errors as JSON on stderr
+  #[arg(long, global = true)]
+  pub json: bool,
+
   #[command(subcommand)]
   pub command: Command,
 }
@@ -14,18 +29,41 @@ pub enum Command {
   Play {
     #[command(flatten)]
     tracks: Option<TrackPaths>,
+    /// Play a single file over the queue without adding it, then continue the current track where it was
+    #[arg(long, value_name = "FILE", conflicts_with = "paths")]
+    oneshot: Option<PathBuf>,
   },
 
   Pause,
   PlayPause,
   Stop,
 
-  Next,
+  /// Print the current track, position, volume, loop mode, and shuffle
+  Status,
+
+  Next {
+    /// Number of tracks to skip
+    count: Option<NonZeroUsize>,
+  },
   #[command(alias = "prev")]
-  Previous,
+  Previous {
+    /// Number of tracks to go back, restarting the current track counts as one
+    count: Option<NonZeroUsize>,
+  },
 
   Volume {
-    volume: Option<f32>,
+    /// Prefix with + or - to change the volume relative to its current value
+    #[arg(value_parser = parse_volume_change)]
+    #[arg(allow_negative_numbers = true)]
+    volume: Option<VolumeChange>,
+  },
+  /// Print the playback rate, or set it, such as `1.5` to play one and a half times as fast
+  Rate {
+    rate: Option<f32>,
+  },
+  /// Print the normalization mode, or play tracks at the same loudness using their ReplayGain tags
+  Normalize {
+    mode: Option<NormalizationMode>,
   },
   Loop {
     loop_mode: Option<LoopMode>,
@@ -33,13 +71,26 @@ pub enum Command {
   Shuffle {
     shuffle: Option<ShuffleMode>,
   },
+  /// Pause at the end of the current track, toggles it if no state is given
+  StopAfter {
+    state: Option<StopAfterState>,
+  },
 
+  /// Seek to a position, or by an offset starting with + or -, such as `1:23`, `+30s`, `-1m`, `50%`, or `+5%`
   Seek {
     #[arg(value_parser = parse_seek_position)]
     #[arg(allow_negative_numbers = true)]
     seek_position: SeekPosition,
   },
 
+  /// Print the position in the current track, or seek to a new one
+  Position {
+    /// A time such as `1:23`, an offset such as `+30s`, or a percentage of the track such as `50%`
+    #[arg(value_parser = parse_position_target)]
+    #[arg(allow_negative_numbers = true)]
+    target: Option<PositionTarget>,
+  },
+
   #[command(args_conflicts_with_subcommands = true)]
   Queue {
     #[command(subcommand)]
@@ -47,6 +98,79 @@ pub enum Command {
     #[command(flatten)]
     tracks: Option<TrackPaths>,
   },
+
+  /// List plugins, or enable or disable one while the server is running
+  Plugins {
+    name: Option<String>,
+    #[arg(requires = "name")]
+    state: Option<PluginState>,
+  },
+
+  /// Print what the server reads from a file, without adding it to the queue
+  Inspect {
+    path: PathBuf,
+  },
+
+  /// Print the lyrics of the current track, or of a file
+  Lyrics {
+    path: Option<PathBuf>,
+  },
+
+  /// Diagnose problems connecting to the server
+  Doctor,
+
+  /// Print the server's blocking task queues, or listening stats with `--session`
+  Stats {
+    /// Listening time, tracks played and skipped, and the most played artist since the server started
+    #[arg(long)]
+    session: bool,
+    /// Reset the session stats
+    #[arg(long, requires = "session")]
+    reset: bool,
+    /// Also print how long each kind of request has taken to handle
+    #[arg(long, conflicts_with = "session")]
+    timing: bool,
+  },
+
+  /// Inspect the server's internal state
+  Debug {
+    #[command(subcommand)]
+    command: DebugCommand,
+  },
+
+  /// Shut down plugins and stop the server
+  Quit,
+
+  /// Print the current track using a format string, or nothing if playback is stopped
+  NowPlaying {
+    /// Placeholders: title, artist, album, track_number, filename, position, duration, volume, state.
+    /// `{a|b}` uses b if a is missing
+    #[arg(long, default_value = "{artist} - {title|filename}")]
+    format: String,
+    /// Exit with code 7 instead of 0 if playback is stopped
+    #[arg(long)]
+    fail_if_stopped: bool,
+  },
+
+  /// Print each event the server sends as a line of JSON, until the server stops
+  Watch {
+    /// Only print these kinds of events, such as `TrackChanged`. Prints every event if none are given
+    #[arg(value_parser = parse_event_kind)]
+    events: Vec<EventKind>,
+  },
+
+  /// Print the current track as JSON for waybar's custom module
+  Waybar {
+    /// Placeholders: title, artist, album, track_number, filename, state. `{a|b}` uses b if a is missing
+    #[arg(long, default_value = "{artist} - {title|filename}")]
+    format: String,
+    /// Maximum length of the text in characters
+    #[arg(long, default_value_t = 40)]
+    max_length: usize,
+    /// Print a new line every time the output changes, for waybar's continuous mode
+    #[arg(long)]
+    follow: bool,
+  },
 }
 
 #[derive(Debug, Subcommand)]
@@ -60,10 +184,93 @@ pub enum QueueCommand {
   Add {
     #[command(flatten)]
     tracks: TrackPaths,
+    /// With shuffle enabled, play the tracks in order after the rest of the queue instead of shuffling them in
+    #[arg(long)]
+    keep_order: bool,
   },
   Next {
     #[command(flatten)]
     tracks: TrackPaths,
+    /// With shuffle enabled, play all of the tracks in order after the current track instead of shuffling them in
+    #[arg(long)]
+    keep_order: bool,
+  },
+  /// Remove tracks from the queue
+  #[command(alias = "rm")]
+  Remove {
+    /// Positions of the tracks starting at 1, or ranges such as `2..5` to remove tracks 2 through 5
+    #[arg(required = true, value_parser = parse_positions)]
+    positions: Vec<RangeInclusive<usize>>,
+  },
+  /// Exchange two tracks in the queue
+  Swap {
+    /// Positions of the tracks starting at 1
+    #[arg(value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    a: usize,
+    #[arg(value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    b: usize,
+  },
+  /// Move a track to another position in the queue
+  Move {
+    /// Positions starting at 1
+    #[arg(value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    from: usize,
+    #[arg(value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    to: usize,
+  },
+  /// Reverse the order the queue plays in
+  Reverse,
+  /// Add back the tracks removed by the last clear, replace, or remove
+  Restore {
+    /// Insert them after the current track instead of at the end
+    #[arg(long)]
+    next: bool,
+    /// Print the removed tracks instead of adding them back
+    #[arg(long, conflicts_with = "next")]
+    list: bool,
+  },
+  /// Jump to a track in the queue
+  Play {
+    /// Position of the track in the queue, starting at 1
+    #[arg(value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    position: usize,
+  },
+  /// Only play the tracks matching a filter such as `artist="Boards of Canada"`, without removing the others
+  ///
+  /// Prints the current filter if no conditions are given
+  Filter {
+    /// `field=value`, `field!=value`, or `field~value` to match part of a value, ignoring case.
+    /// Every condition must match. Fields: title, artist, album, album_artist, genre, date
+    #[arg(value_parser = parse_filter_condition)]
+    conditions: Vec<FilterCondition>,
+    /// Play every track again
+    #[arg(long, conflicts_with = "conditions")]
+    clear: bool,
+  },
+  /// Estimate how long until a track starts playing
+  Eta {
+    /// Position of the track in the queue, starting at 1
+    #[arg(value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    position: usize,
+  },
+}
+
+#[derive(Debug, Subcommand)]
+pub enum DebugCommand {
+  /// List clients connected to the ipc socket
+  Connections,
+  /// Print how many tracks the server's track cache holds
+  Cache,
+  /// Print if the move to the next track will be gapless or crossfade
+  Transition,
+  /// Play a sine wave through the server's output while playback is stopped, exiting with an error if it didn't play
+  Tone {
+    /// Frequency in Hz
+    #[arg(default_value_t = 440.0)]
+    frequency: f32,
+    /// How long to play the tone, such as 2s or 1.5
+    #[arg(default_value = "2s", value_parser = parse_duration)]
+    duration: Duration,
   },
 }
 
@@ -71,6 +278,22 @@ pub enum QueueCommand {
 pub struct TrackPaths {
   #[arg(num_args = 1..)]
   pub paths: Vec<PathBuf>,
+  /// Load paths that are filesystem roots or outside of the server's allowed directories
+  #[arg(long)]
+  pub force: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum VolumeChange {
+  Set(f32),
+  Adjust(f32),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum PositionTarget {
+  Seek(SeekPosition),
+  /// A fraction of the current track's duration, from 0 to 1
+  Fraction(f64),
 }
 
 #[derive(Debug, Clone, ValueEnum)]
@@ -92,6 +315,23 @@ impl Into<hsm_ipc::LoopMode> for LoopMode {
   }
 }
 
+#[derive(Debug, Clone, ValueEnum)]
+pub enum NormalizationMode {
+  Off,
+  Track,
+  Album,
+}
+
+impl Into<hsm_ipc::NormalizationMode> for NormalizationMode {
+  fn into(self) -> hsm_ipc::NormalizationMode {
+    match self {
+      Self::Off => hsm_ipc::NormalizationMode::Off,
+      Self::Track => hsm_ipc::NormalizationMode::Track,
+      Self::Album => hsm_ipc::NormalizationMode::Album,
+    }
+  }
+}
+
 #[derive(Debug, Clone, ValueEnum)]
 pub enum ShuffleMode {
   Off,
@@ -107,17 +347,228 @@ impl Into<bool> for ShuffleMode {
   }
 }
 
-fn parse_seek_position(s: &str) -> Result<SeekPosition, ParseFloatError> {
+#[derive(Debug, Clone, ValueEnum)]
+pub enum StopAfterState {
+  Off,
+  On,
+}
+
+impl Into<bool> for StopAfterState {
+  fn into(self) -> bool {
+    match self {
+      Self::Off => false,
+      Self::On => true,
+    }
+  }
+}
+
+#[derive(Debug, Clone, ValueEnum)]
+pub enum PluginState {
+  Enable,
+  Disable,
+}
+
+impl Into<bool> for PluginState {
+  fn into(self) -> bool {
+    match self {
+      Self::Enable => true,
+      Self::Disable => false,
+    }
+  }
+}
+
+/// Parses a position starting at 1, or an inclusive range of positions like `2..5`
+fn parse_positions(s: &str) -> Result<RangeInclusive<usize>, String> {
+  let parse_position = |s: &str| match s.trim().parse::<usize>() {
+    Ok(position) if position > 0 => Ok(position),
+    _ => Err(format!("{s} is not a track position, positions start at 1")),
+  };
+
+  let Some((start, end)) = s.split_once("..") else {
+    let position = parse_position(s)?;
+    return Ok(position..=position);
+  };
+
+  let (start, end) = (parse_position(start)?, parse_position(end)?);
+  if start > end {
+    return Err(format!(
+      "{s} is an empty range, the start must come before the end"
+    ));
+  }
+
+  Ok(start..=end)
+}
+
+/// Parses the name of an event, ignoring case
+fn parse_event_kind(s: &str) -> Result<EventKind, String> {
+  EventKind::ALL
+    .iter()
+    .find(|kind| format!("{kind:?}").eq_ignore_ascii_case(s))
+    .copied()
+    .ok_or_else(|| {
+      let names: Vec<String> = EventKind::ALL
+        .iter()
+        .map(|kind| format!("{kind:?}"))
+        .collect();
+      format!("Unknown event {s}, valid events are: {}", names.join(", "))
+    })
+}
+
+/// Parses `field=value`, `field!=value`, or `field~value`
+fn parse_filter_condition(s: &str) -> Result<FilterCondition, String> {
+  let op_start = s.find(['=', '!', '~']).ok_or_else(|| {
+    format!("{s} is not a condition, use field=value, field!=value, or field~value")
+  })?;
+  let (name, rest) = s.split_at(op_start);
+
+  let (op, value) = if let Some(value) = rest.strip_prefix("!=") {
+    (FilterOp::IsNot, value)
+  } else if let Some(value) = rest.strip_prefix('=') {
+    (FilterOp::Is, value)
+  } else if let Some(value) = rest.strip_prefix('~') {
+    (FilterOp::Contains, value)
+  } else {
+    return Err(format!(
+      "{s} is not a condition, use field=value, field!=value, or field~value"
+    ));
+  };
+
+  let field = FilterField::from_name(name.trim()).ok_or_else(|| {
+    format!(
+      "Unknown field {name}, valid fields are: {}",
+      FilterField::NAMES.join(", ")
+    )
+  })?;
+
+  Ok(FilterCondition {
+    field,
+    op,
+    value: value.into(),
+  })
+}
+
+/// A percentage from 0% to 100% as a fraction from 0 to 1
+fn parse_percentage(s: &str) -> Option<Result<f64, String>> {
+  let percent = s.strip_suffix('%')?;
+
+  let fraction = match percent.trim().parse::<f64>() {
+    Ok(percent) if (0.0..=100.0).contains(&percent) => Ok(percent / 100.0),
+    _ => Err(format!("{s} is not a percentage from 0% to 100%")),
+  };
+
+  Some(fraction)
+}
+
+fn parse_seek_position(s: &str) -> Result<SeekPosition, String> {
   if let Some(s) = s.strip_prefix("+") {
-    let secs: f64 = s.parse()?;
-    return Ok(SeekPosition::Forward(Duration::from_secs_f64(secs)));
+    if let Some(fraction) = parse_percentage(s) {
+      return Ok(SeekPosition::ForwardFraction(fraction? as f32));
+    }
+    return Ok(SeekPosition::Forward(parse_duration(s)?));
   }
 
   if let Some(s) = s.strip_prefix("-") {
-    let secs: f64 = s.parse()?;
-    return Ok(SeekPosition::Backward(Duration::from_secs_f64(secs)));
+    if let Some(fraction) = parse_percentage(s) {
+      return Ok(SeekPosition::BackwardFraction(fraction? as f32));
+    }
+    return Ok(SeekPosition::Backward(parse_duration(s)?));
+  }
+
+  if let Some(fraction) = parse_percentage(s) {
+    return Ok(SeekPosition::ToFraction(fraction? as f32));
+  }
+
+  Ok(SeekPosition::To(parse_duration(s)?))
+}
+
+fn parse_position_target(s: &str) -> Result<PositionTarget, String> {
+  // Offsets are sent to the server as they are, including offsets by a percentage
+  if s.starts_with(['+', '-']) {
+    return parse_seek_position(s).map(PositionTarget::Seek);
+  }
+
+  match parse_percentage(s) {
+    Some(fraction) => Ok(PositionTarget::Fraction(fraction?)),
+    None => parse_seek_position(s).map(PositionTarget::Seek),
+  }
+}
+
+fn parse_volume_change(s: &str) -> Result<VolumeChange, ParseFloatError> {
+  if s.starts_with(['+', '-']) {
+    return Ok(VolumeChange::Adjust(s.parse()?));
+  }
+
+  Ok(VolumeChange::Set(s.parse()?))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn parses_seek_positions() {
+    let secs = Duration::from_secs;
+    let cases = [
+      ("30", SeekPosition::To(secs(30))),
+      ("1:30", SeekPosition::To(secs(90))),
+      ("+10", SeekPosition::Forward(secs(10))),
+      ("+1m", SeekPosition::Forward(secs(60))),
+      ("-1:00", SeekPosition::Backward(secs(60))),
+      ("-5s", SeekPosition::Backward(secs(5))),
+      ("50%", SeekPosition::ToFraction(0.5)),
+      ("+5%", SeekPosition::ForwardFraction(0.05)),
+      ("-100%", SeekPosition::BackwardFraction(1.0)),
+    ];
+
+    for (s, expected) in cases {
+      assert_eq!(parse_seek_position(s), Ok(expected), "{s}");
+    }
+  }
+
+  #[test]
+  fn rejects_invalid_seek_positions() {
+    for s in [
+      "", "+", "-", "--5", "+-5", "+5:-1", "150%", "-101%", "+x%", "abc", "1e30", "+inf", "NaN",
+    ] {
+      assert!(parse_seek_position(s).is_err(), "{s}");
+    }
   }
 
-  let secs: f64 = s.parse()?;
-  Ok(SeekPosition::To(Duration::from_secs_f64(secs)))
+  #[test]
+  fn parses_position_targets() {
+    assert!(matches!(
+      parse_position_target("25%"),
+      Ok(PositionTarget::Fraction(0.25))
+    ));
+    assert!(matches!(
+      parse_position_target("+25%"),
+      Ok(PositionTarget::Seek(SeekPosition::ForwardFraction(0.25)))
+    ));
+    assert!(matches!(
+      parse_position_target("1:00"),
+      Ok(PositionTarget::Seek(SeekPosition::To(position))) if position == Duration::from_secs(60)
+    ));
+    assert!(parse_position_target("101%").is_err());
+  }
+
+  #[test]
+  fn parses_track_positions() {
+    let cases = [
+      ("2", 2..=2),
+      ("2..5", 2..=5),
+      (" 1 .. 3 ", 1..=3),
+      ("4..4", 4..=4),
+    ];
+
+    for (s, expected) in cases {
+      assert_eq!(parse_positions(s), Ok(expected), "{s}");
+    }
+  }
+
+  #[test]
+  fn rejects_invalid_track_positions() {
+    for s in ["", "0", "0..2", "x", "2..", "..2", "5..2", "-1"] {
+      assert!(parse_positions(s).is_err(), "{s}");
+    }
+  }
 }
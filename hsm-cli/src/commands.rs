@@ -1,116 +1,1092 @@
-use std::path::{self, PathBuf};
+use std::{
+  cmp::Reverse,
+  collections::BTreeMap,
+  env,
+  io::{self, IsTerminal, Write},
+  path,
+  process::{Command as Process, Stdio},
+  time::{Duration, SystemTime},
+};
 
-use crate::cli::{Cli, Command, QueueCommand};
+use crate::cli::{
+  Cli, Command, DebugCommand, PositionTarget, QueueCommand, TrackPaths, VolumeChange,
+};
+use crate::duration::{DurationStyle, format_duration};
 use crate::ipc::send_request;
-use hsm_client::track_list::TrackList;
-use hsm_ipc::{InsertPosition, LoopMode, TrackListSnapshot, requests};
+use crate::load_report::{LoadError, LoadReport, print_load_errors};
+use crate::spinner::Spinner;
+use crate::{doctor, waybar};
+use hsm_client::{now_playing::NowPlaying, track_list::TrackList};
+use hsm_ipc::{
+  FilterExpr, InsertPosition, InsertShufflePolicy, InspectedTrack, LoopMode, NormalizationMode,
+  PlaybackState, PlayerStatus, Request, RequestTiming, SeekPosition, StopReason, TIMING_BUCKETS,
+  TrackListSnapshot, TrackRef, TransitionMode, requests,
+};
+use serde::Serialize;
 
-fn try_load_tracks(position: InsertPosition, paths: &[PathBuf]) -> Result<(), crate::Error> {
+/// Loads that take longer than this print a summary
+const SLOW_LOAD: Duration = Duration::from_secs(1);
+
+/// `--json` output of `hsm queue`
+#[derive(Debug, Serialize)]
+struct QueueOutput {
+  /// Position of the current track in play order
+  current_index: usize,
+  #[serde(flatten)]
+  snapshot: TrackListSnapshot,
+}
+
+/// `--json` output of `hsm position`, `null` if there is no current track
+#[derive(Debug, Serialize)]
+struct PositionOutput {
+  position: Duration,
+  total_duration: Option<Duration>,
+  /// Percentage of the track that has played, if its duration is known
+  percentage: Option<f64>,
+}
+
+impl PositionOutput {
+  fn new(status: PlayerStatus) -> Option<Self> {
+    let total_duration = status.track?.total_duration;
+    let percentage = total_duration
+      .filter(|duration| !duration.is_zero())
+      .map(|duration| status.position.as_secs_f64() / duration.as_secs_f64() * 100.0);
+
+    Some(Self {
+      position: status.position,
+      total_duration,
+      percentage,
+    })
+  }
+}
+
+pub fn print_json(value: &impl Serialize) {
+  let data = serde_json::to_string(value).expect("Replies should not fail to serialize");
+  println!("{data}");
+}
+
+/// Sends a request that has no reply, printing `{"ok":true}` with `--json`
+fn send_command<R: Request<Response = ()>>(request: R, json: bool) -> Result<(), crate::Error> {
+  send_request(request)?;
+  if json {
+    print_json(&serde_json::json!({ "ok": true }));
+  }
+
+  Ok(())
+}
+
+/// Prints `value` as JSON with `--json`, or with `print` otherwise
+fn print_reply<T: Serialize>(value: T, json: bool, print: impl FnOnce(T)) {
+  if json {
+    print_json(&value);
+  } else {
+    print(value);
+  }
+}
+
+/// With `--json`, prints the number of loaded tracks and any errors, exiting with code 6 if only some tracks failed
+fn try_load_tracks(
+  position: InsertPosition,
+  shuffle_policy: InsertShufflePolicy,
+  tracks: &TrackPaths,
+  json: bool,
+) -> Result<(), crate::Error> {
   let mut absolute_paths = Vec::new();
-  for path in paths {
+  for path in tracks.paths.iter() {
     absolute_paths.push(path::absolute(path).map_err(crate::Error::GetCurrentDirFailed)?);
   }
 
-  let errors = send_request(requests::LoadTracks(position, absolute_paths))?;
+  // The server doesn't report how many tracks were loaded, so compare the track list length
+  let len_before = match (json, position) {
+    (false, _) | (true, InsertPosition::Replace) => 0,
+    (true, _) => send_request(requests::QueryTrackList)?.track_list.len(),
+  };
+
+  let spinner = Spinner::start("Loading tracks");
+  let result = send_request(requests::LoadTracks {
+    position,
+    paths: absolute_paths,
+    expected_generation: None,
+    force: tracks.force,
+    // The cli can't subscribe to events yet, so progress events are not used
+    progress_id: None,
+    shuffle_policy,
+  });
+
+  let elapsed = spinner.elapsed();
+  spinner.stop();
+  let errors = result?;
+
+  if json {
+    let len_after = send_request(requests::QueryTrackList)?.track_list.len();
+    let report = LoadReport {
+      loaded: len_after.saturating_sub(len_before),
+      errors: errors
+        .into_iter()
+        .map(|(path, kind)| LoadError::new(path, kind))
+        .collect(),
+    };
+
+    print_json(&report);
+
+    return match (report.loaded, report.errors.len()) {
+      (_, 0) => Ok(()),
+      (0, failed) => Err(crate::Error::LoadFailed(failed)),
+      (_, failed) => Err(crate::Error::PartialLoad(failed)),
+    };
+  }
+
+  print_load_errors(&errors);
 
-  for (path, error) in errors {
-    eprintln!("Failed to load track {path:?}: {error}")
+  if elapsed >= SLOW_LOAD {
+    println!(
+      "Finished loading in {:.1}s, {} failed",
+      elapsed.as_secs_f32(),
+      errors.len()
+    );
   }
 
   Ok(())
 }
 
-fn handle_queue_command(command: QueueCommand) -> Result<(), crate::Error> {
+fn handle_queue_command(command: QueueCommand, json: bool) -> Result<(), crate::Error> {
   match command {
-    QueueCommand::Clear => send_request(requests::ClearTracks)?,
-    QueueCommand::Replace { tracks } => try_load_tracks(InsertPosition::Replace, &tracks.paths)?,
-    QueueCommand::Add { tracks } => try_load_tracks(InsertPosition::End, &tracks.paths)?,
-    QueueCommand::Next { tracks } => try_load_tracks(InsertPosition::Next, &tracks.paths)?,
+    QueueCommand::Clear => send_command(
+      requests::ClearTracks {
+        expected_generation: None,
+      },
+      json,
+    )?,
+    QueueCommand::Replace { tracks } => try_load_tracks(
+      InsertPosition::Replace,
+      InsertShufflePolicy::Scatter,
+      &tracks,
+      json,
+    )?,
+    QueueCommand::Add { tracks, keep_order } => {
+      let shuffle_policy = if keep_order {
+        InsertShufflePolicy::KeepTogetherInPlace
+      } else {
+        InsertShufflePolicy::Scatter
+      };
+      try_load_tracks(InsertPosition::End, shuffle_policy, &tracks, json)?
+    }
+    QueueCommand::Next { tracks, keep_order } => {
+      let shuffle_policy = if keep_order {
+        InsertShufflePolicy::KeepTogetherNext
+      } else {
+        InsertShufflePolicy::Scatter
+      };
+      try_load_tracks(InsertPosition::Next, shuffle_policy, &tracks, json)?
+    }
+    QueueCommand::Remove { positions } => {
+      let positions = positions.into_iter().flatten().map(|position| position - 1);
+      let out_of_range: Vec<usize> = send_request(requests::RemoveTracks {
+        positions: positions.collect(),
+        expected_generation: None,
+      })?
+      .into_iter()
+      .map(|position| position + 1)
+      .collect();
+
+      if json {
+        print_json(&serde_json::json!({ "out_of_range": out_of_range }));
+      } else if !out_of_range.is_empty() {
+        let out_of_range: Vec<String> = out_of_range
+          .iter()
+          .map(|position| position.to_string())
+          .collect();
+        eprintln!(
+          "Warning: no tracks at positions {}, they were not removed",
+          out_of_range.join(", ")
+        );
+      }
+    }
+    QueueCommand::Swap { a, b } => send_command(
+      requests::SwapTracks {
+        a: TrackRef::Position(a - 1),
+        b: TrackRef::Position(b - 1),
+        expected_generation: None,
+      },
+      json,
+    )?,
+    QueueCommand::Move { from, to } => send_command(
+      requests::MoveTrack {
+        from: from - 1,
+        to: to - 1,
+        expected_generation: None,
+      },
+      json,
+    )?,
+    QueueCommand::Reverse => send_command(requests::ReverseQueue, json)?,
+    QueueCommand::Restore { list: true, .. } => {
+      print_reply(send_request(requests::QueryLastRemoved)?, json, |paths| {
+        for path in paths {
+          println!("{}", path.display());
+        }
+      })
+    }
+    QueueCommand::Restore { next, .. } => {
+      let position = if next {
+        InsertPosition::Next
+      } else {
+        InsertPosition::End
+      };
+
+      let errors = send_request(requests::RestoreLastRemoved {
+        position,
+        expected_generation: None,
+      })?;
+      if json {
+        let errors: Vec<LoadError> = errors
+          .into_iter()
+          .map(|(path, kind)| LoadError::new(path, kind))
+          .collect();
+        print_json(&serde_json::json!({ "errors": errors }));
+      } else {
+        print_load_errors(&errors);
+      }
+    }
+    QueueCommand::Play { position } => send_command(
+      requests::GoToTrack {
+        index: position - 1,
+        expected_generation: None,
+      },
+      json,
+    )?,
+    QueueCommand::Filter { conditions, clear } => {
+      if clear {
+        print_reply(send_request(requests::SetQueueFilter(None))?, json, |_| {
+          println!("Filter cleared")
+        })
+      } else if !conditions.is_empty() {
+        let filter = FilterExpr { all: conditions };
+        print_reply(
+          send_request(requests::SetQueueFilter(Some(filter)))?,
+          json,
+          |matching| println!("{matching} tracks match the filter"),
+        )
+      } else {
+        print_reply(
+          send_request(requests::QueryQueueFilter)?,
+          json,
+          |filter| match filter {
+            Some(filter) => println!("Filter: {filter}"),
+            None => println!("Filter: none"),
+          },
+        )
+      }
+    }
+    QueueCommand::Eta { position } => print_reply(
+      send_request(requests::QueryTrackEta(position - 1))?,
+      json,
+      |eta| match eta {
+        Some(eta) if eta.is_zero() => println!("Track {position} is the current track"),
+        Some(eta) => println!(
+          "Track {position} starts in {}",
+          format_duration(eta, DurationStyle::Long)
+        ),
+        None => println!(
+          "Track {position} is not in the queue, will not play with the current loop mode, or comes after a track with an unknown length"
+        ),
+      },
+    ),
   };
 
   Ok(())
 }
 
 fn print_track_list(snapshot: TrackListSnapshot) {
+  // In play order, like the tracks below
+  let filtered_out: Vec<bool> = snapshot
+    .shuffle_indicies
+    .iter()
+    .map(|&index| {
+      snapshot
+        .instances
+        .get(index)
+        .is_some_and(|instance| instance.filtered_out)
+    })
+    .collect();
   let track_list = TrackList::from_snapshot(snapshot);
 
   if track_list.len() == 0 {
     println!("No tracks loaded");
   }
 
-  for track in track_list.iter() {
+  for (track, filtered_out) in track_list.iter().zip(filtered_out) {
     let title = track
       .metadata
-      .title
-      .as_ref()
-      .map(|title| title.clone())
+      .title_or_inferred()
+      .map(|title| title.to_owned())
       .unwrap_or_else(|| track.file_path.to_string_lossy().into_owned());
+    let skipped = if filtered_out { " [filtered out]" } else { "" };
 
-    println!("| {title}")
+    match track.total_duration {
+      Some(duration) => println!(
+        "| {title} ({}){skipped}",
+        format_duration(duration, DurationStyle::Short)
+      ),
+      None => println!("| {title}{skipped}"),
+    }
   }
 }
 
+/// The slowest kinds of request first, each followed by a histogram of how long it took
+fn request_timing_lines(mut timings: Vec<RequestTiming>) -> Vec<String> {
+  timings.sort_by_key(|timing| Reverse(timing.total));
+
+  let mut lines = Vec::new();
+  for timing in timings {
+    let average = timing.total / timing.count.max(1) as u32;
+    lines.push(format!(
+      "{}: {} requests, {:.1}ms average, {:.1}ms max",
+      timing.name,
+      timing.count,
+      average.as_secs_f64() * 1000.0,
+      timing.max.as_secs_f64() * 1000.0
+    ));
+
+    let bucket_labels = TIMING_BUCKETS
+      .iter()
+      .map(|bound| format!("<={}ms", bound.as_millis()))
+      .chain([format!(
+        ">{}ms",
+        TIMING_BUCKETS[TIMING_BUCKETS.len() - 1].as_millis()
+      )]);
+    let buckets: Vec<String> = bucket_labels
+      .zip(timing.buckets)
+      .filter(|(_, count)| *count > 0)
+      .map(|(label, count)| format!("{label}: {count}"))
+      .collect();
+    lines.push(format!("  {}", buckets.join(", ")));
+  }
+
+  lines
+}
+
+/// The fields of an inspected track for people, one per line
+fn inspected_track_lines(inspected: &InspectedTrack) -> Vec<String> {
+  let mut lines = Vec::new();
+  let track = &inspected.track;
+  let metadata = &track.metadata;
+  let unknown = || "unknown".to_owned();
+
+  lines.push(format!("Path: {}", track.file_path.display()));
+  lines.push(format!(
+    "Duration: {}",
+    track
+      .total_duration
+      .map_or_else(unknown, |duration| format_duration(
+        duration,
+        DurationStyle::Short
+      ))
+  ));
+  lines.push(format!(
+    "Format: {} Hz, {} channels{}",
+    inspected.sample_rate,
+    inspected.channels,
+    if inspected.format_unverified {
+      " (unverified, the start of the file could not be decoded)"
+    } else {
+      ""
+    }
+  ));
+  if inspected.encoder_delay > 0 || inspected.encoder_padding > 0 {
+    lines.push(format!(
+      "Gapless trim: {} frames of delay, {} of padding",
+      inspected.encoder_delay, inspected.encoder_padding
+    ));
+  }
+
+  lines.push(format!(
+    "Title: {}",
+    metadata.title.clone().unwrap_or_else(unknown)
+  ));
+  lines.push(format!("Artists: {}", metadata.artists.join(", ")));
+  lines.push(format!(
+    "Album: {}",
+    metadata.album.clone().unwrap_or_else(unknown)
+  ));
+  if let Some(album_artist) = &metadata.album_artist {
+    lines.push(format!("Album artist: {album_artist}"));
+  }
+  if let Some(track_number) = metadata.track_number {
+    lines.push(format!("Track number: {track_number}"));
+  }
+  if let Some(date) = &metadata.date {
+    lines.push(format!("Date: {date}"));
+  }
+  if !metadata.genres.is_empty() {
+    lines.push(format!("Genres: {}", metadata.genres.join(", ")));
+  }
+  lines.push(format!(
+    "Lyrics tag: {}",
+    if inspected.has_lyrics { "yes" } else { "no" }
+  ));
+  let replay_gain = &metadata.replay_gain;
+  if let Some(track_gain) = replay_gain.track_gain {
+    lines.push(format!("ReplayGain track: {track_gain:+.2} dB"));
+  }
+  if let Some(album_gain) = replay_gain.album_gain {
+    lines.push(format!("ReplayGain album: {album_gain:+.2} dB"));
+  }
+
+  if !metadata.inferred.is_empty() {
+    lines.push(format!("Inferred from the path: {:?}", metadata.inferred));
+  }
+  for repair in &metadata.charset_repairs {
+    lines.push(format!(
+      "Repaired tag {:?} as {}: {:?}",
+      repair.original, repair.encoding, repair.repaired
+    ));
+  }
+
+  lines
+}
+
+fn print_position(output: Option<PositionOutput>) {
+  let Some(output) = output else {
+    println!("No track is playing");
+    return;
+  };
+
+  let position = format_duration(output.position, DurationStyle::Short);
+  match (output.total_duration, output.percentage) {
+    (Some(duration), Some(percentage)) => println!(
+      "{position} / {} ({}%)",
+      format_duration(duration, DurationStyle::Short),
+      percentage.floor()
+    ),
+    (Some(duration), None) => println!(
+      "{position} / {}",
+      format_duration(duration, DurationStyle::Short)
+    ),
+    (None, _) => println!("{position}"),
+  }
+}
+
+/// Seeks to a fraction of the current track's duration
+fn seek_to_fraction(fraction: f64, json: bool) -> Result<(), crate::Error> {
+  let track = send_request(requests::QueryCurrentTrack)?.ok_or(crate::Error::NoCurrentTrack)?;
+  let duration = track.total_duration.ok_or(crate::Error::UnknownDuration)?;
+
+  send_command(
+    requests::Seek(SeekPosition::To(duration.mul_f64(fraction))),
+    json,
+  )
+}
+
+/// Prints nothing if playback is stopped, so status bars render an empty module
+fn print_now_playing(format: &str, fail_if_stopped: bool, json: bool) -> Result<(), crate::Error> {
+  // Report a bad format even if nothing is playing, instead of only once a track starts
+  NowPlaying::validate_format(format)?;
+
+  let status = send_request(requests::QueryStatus)?;
+  let text = match &status.track {
+    Some(track) if status.playback_state != PlaybackState::Stopped => Some(
+      NowPlaying {
+        position: Some(status.position),
+        volume: Some(status.volume),
+        ..NowPlaying::new(Some(track), status.playback_state)
+      }
+      .format(format)?,
+    ),
+    _ => None,
+  };
+
+  if text.is_none() && fail_if_stopped {
+    return Err(crate::Error::NothingPlaying);
+  }
+
+  print_reply(text, json, |text| {
+    if let Some(text) = text {
+      println!("{text}")
+    }
+  });
+  Ok(())
+}
+
+/// The playback state, with the reason playback stopped if it is known
+fn format_state(playback_state: PlaybackState, stop_reason: Option<&StopReason>) -> String {
+  match (playback_state, stop_reason) {
+    (PlaybackState::Playing, _) => "Playing".into(),
+    (PlaybackState::Paused, _) => "Paused".into(),
+    (PlaybackState::Stopped, None) => "Stopped".into(),
+    (PlaybackState::Stopped, Some(StopReason::EndOfQueue)) => "Stopped (end of queue)".into(),
+    (PlaybackState::Stopped, Some(StopReason::UserRequested)) => "Stopped (by request)".into(),
+    (PlaybackState::Stopped, Some(StopReason::QueueCleared)) => "Stopped (queue cleared)".into(),
+    (PlaybackState::Stopped, Some(StopReason::Error(error))) => {
+      format!("Stopped (error: {error})")
+    }
+  }
+}
+
+fn print_status(status: &PlayerStatus) {
+  let state = format_state(status.playback_state, status.stop_reason.as_ref());
+
+  match &status.track {
+    Some(track) => {
+      let metadata = &track.metadata;
+      let title = metadata
+        .title_or_inferred()
+        .map(|title| title.to_owned())
+        .unwrap_or_else(|| track.file_path.to_string_lossy().into_owned());
+      println!("{state}: {title}");
+
+      let artists = metadata.artists_or_inferred();
+      if !artists.is_empty() {
+        println!("Artist: {}", artists.join(", "));
+      }
+      if let Some(album) = metadata.album_or_inferred() {
+        println!("Album: {album}");
+      }
+
+      let position = format_duration(status.position, DurationStyle::Short);
+      match track.total_duration {
+        Some(duration) => println!(
+          "Position: {position} / {}",
+          format_duration(duration, DurationStyle::Short)
+        ),
+        None => println!("Position: {position}"),
+      }
+    }
+    None => println!("{state} — no track"),
+  }
+  if status.track_detached {
+    println!("Removed from the queue, playing until it ends");
+  }
+
+  println!("Volume: {}", status.volume);
+  match status.loop_mode {
+    LoopMode::None => println!("Loop: none"),
+    LoopMode::Track => println!("Loop: track"),
+    LoopMode::Playlist => println!("Loop: playlist"),
+  }
+  println!("Shuffle: {}", if status.shuffle { "on" } else { "off" });
+  if status.stop_after_current {
+    println!("Stopping after the current track");
+  }
+}
+
+/// Prints `text` through `$PAGER` if stdout is a terminal, falling back to printing it directly
+fn print_paged(text: &str) {
+  if io::stdout().is_terminal() {
+    let pager = env::var("PAGER").unwrap_or_else(|_| "less".into());
+    let child = Process::new(&pager).stdin(Stdio::piped()).spawn();
+
+    if let Ok(mut child) = child {
+      if let Some(mut stdin) = child.stdin.take() {
+        // The pager may be closed before reading everything
+        let _ = stdin.write_all(text.as_bytes());
+      }
+
+      let _ = child.wait();
+      return;
+    }
+  }
+
+  println!("{text}");
+}
+
 pub fn handle_command(command: Cli) -> Result<(), crate::Error> {
+  let json = command.json;
+
   match command.command {
-    Command::Play { tracks } => {
+    Command::Play {
+      oneshot: Some(path),
+      ..
+    } => {
+      let path = path::absolute(path).map_err(crate::Error::GetCurrentDirFailed)?;
+      send_command(requests::PlayOneshot { path }, json)?
+    }
+    Command::Play { tracks, .. } => {
       if let Some(tracks) = tracks {
-        try_load_tracks(InsertPosition::Replace, &tracks.paths)?;
+        try_load_tracks(
+          InsertPosition::Replace,
+          InsertShufflePolicy::Scatter,
+          &tracks,
+          json,
+        )?;
+        // The load report is the reply with `--json`
+        send_request(requests::Play)?
+      } else {
+        send_command(requests::Play, json)?
       }
-
-      send_request(requests::Play)?
     }
-    Command::Pause => send_request(requests::Pause)?,
-    Command::PlayPause => send_request(requests::TogglePlayback)?,
-    Command::Stop => send_request(requests::StopPlayback)?,
+    Command::Pause => send_command(requests::Pause, json)?,
+    Command::PlayPause => send_command(requests::TogglePlayback, json)?,
+    Command::Stop => send_command(requests::StopPlayback, json)?,
 
-    Command::Next => send_request(requests::NextTrack)?,
-    Command::Previous => send_request(requests::PreviousTrack { soft: true })?,
+    Command::Status => print_reply(send_request(requests::QueryStatus)?, json, |status| {
+      print_status(&status)
+    }),
+
+    Command::Next { count } => send_command(requests::NextTrack(count), json)?,
+    Command::Previous { count } => {
+      send_command(requests::PreviousTrack { soft: true, count }, json)?
+    }
 
     Command::Loop { loop_mode } => {
       if let Some(loop_mode) = loop_mode {
-        send_request(requests::SetLoopMode(loop_mode.into()))?
+        send_command(requests::SetLoopMode(loop_mode.into()), json)?
       } else {
-        let loop_mode = send_request(requests::QueryLoopMode)?;
-        match loop_mode {
-          LoopMode::None => println!("Loop: none"),
-          LoopMode::Track => println!("Loop: track"),
-          LoopMode::Playlist => println!("Loop: playlist"),
-        }
+        print_reply(
+          send_request(requests::QueryLoopMode)?,
+          json,
+          |loop_mode| match loop_mode {
+            LoopMode::None => println!("Loop: none"),
+            LoopMode::Track => println!("Loop: track"),
+            LoopMode::Playlist => println!("Loop: playlist"),
+          },
+        )
       }
     }
     Command::Shuffle { shuffle } => {
       if let Some(shuffle) = shuffle {
-        send_request(requests::SetShuffle(shuffle.into()))?
+        send_command(requests::SetShuffle(shuffle.into()), json)?
       } else {
-        let shuffle = send_request(requests::QueryShuffle)?;
-        match shuffle {
-          true => println!("Shuffle: on"),
-          false => println!("Shuffle: off"),
-        }
+        print_reply(
+          send_request(requests::QueryShuffle)?,
+          json,
+          |shuffle| match shuffle {
+            true => println!("Shuffle: on"),
+            false => println!("Shuffle: off"),
+          },
+        )
       }
     }
+    Command::StopAfter { state } => match state {
+      Some(state) => send_command(requests::SetStopAfterCurrent(state.into()), json)?,
+      // Prints the new state, since toggling doesn't say which way it went
+      None => {
+        let stop_after_current = !send_request(requests::QueryStopAfterCurrent)?;
+        send_request(requests::SetStopAfterCurrent(stop_after_current))?;
+        print_reply(
+          stop_after_current,
+          json,
+          |stop_after_current| match stop_after_current {
+            true => println!("Stop after current track: on"),
+            false => println!("Stop after current track: off"),
+          },
+        )
+      }
+    },
     Command::Volume { volume } => {
       if let Some(volume) = volume {
-        send_request(requests::SetVolume(volume))?
+        match volume {
+          VolumeChange::Set(volume) => send_command(requests::SetVolume(volume), json)?,
+          VolumeChange::Adjust(delta) => send_command(requests::AdjustVolume(delta), json)?,
+        }
       } else {
-        let volume = send_request(requests::QueryVolume)?;
-        println!("Volume: {volume}");
+        print_reply(send_request(requests::QueryVolume)?, json, |volume| {
+          println!("Volume: {volume}")
+        })
       }
     }
+    Command::Rate { rate } => match rate {
+      Some(rate) => send_command(requests::SetRate(rate), json)?,
+      None => print_reply(send_request(requests::QueryRate)?, json, |rate| {
+        println!("Rate: {rate}x")
+      }),
+    },
+
+    Command::Normalize { mode } => match mode {
+      Some(mode) => send_command(requests::SetNormalization(mode.into()), json)?,
+      None => print_reply(
+        send_request(requests::QueryNormalization)?,
+        json,
+        |mode| match mode {
+          NormalizationMode::Off => println!("Normalization: off"),
+          NormalizationMode::Track => println!("Normalization: track"),
+          NormalizationMode::Album => println!("Normalization: album"),
+        },
+      ),
+    },
 
-    Command::Seek { seek_position } => send_request(requests::Seek(seek_position))?,
+    Command::Seek { seek_position } => send_command(requests::Seek(seek_position), json)?,
+    Command::Position { target } => match target {
+      Some(PositionTarget::Seek(seek_position)) => {
+        send_command(requests::Seek(seek_position), json)?
+      }
+      Some(PositionTarget::Fraction(fraction)) => seek_to_fraction(fraction, json)?,
+      None => print_reply(
+        PositionOutput::new(send_request(requests::QueryStatus)?),
+        json,
+        print_position,
+      ),
+    },
 
     Command::Queue { command, tracks } => {
       if let Some(command) = command {
-        handle_queue_command(command)?
+        handle_queue_command(command, json)?
       } else if let Some(tracks) = tracks {
-        handle_queue_command(QueueCommand::Add { tracks })?
+        handle_queue_command(
+          QueueCommand::Add {
+            tracks,
+            keep_order: false,
+          },
+          json,
+        )?
+      } else if json {
+        // The index is queried first, so it can't point past a track list that was cleared in between
+        let current_index = send_request(requests::QueryCurrentTrackIndex)?;
+        let snapshot = send_request(requests::QueryTrackList)?;
+        print_json(&QueueOutput {
+          current_index,
+          snapshot,
+        });
       } else {
         let track_list = send_request(requests::QueryTrackList)?;
         print_track_list(track_list);
       }
     }
+
+    Command::Plugins { name, state } => match (name, state) {
+      (Some(name), Some(state)) => send_command(
+        requests::SetPluginEnabled {
+          name,
+          enabled: state.into(),
+        },
+        json,
+      )?,
+      (name, _) => {
+        let plugins = send_request(requests::QueryPlugins)?
+          .into_iter()
+          .filter(|(plugin, _)| name.as_ref().is_none_or(|name| name == plugin));
+
+        if json {
+          print_json(&plugins.collect::<BTreeMap<String, bool>>());
+        } else {
+          for (plugin, enabled) in plugins {
+            let state = if enabled { "enabled" } else { "disabled" };
+            println!("{plugin}: {state}");
+          }
+        }
+      }
+    },
+
+    Command::Inspect { path } => {
+      let path = path::absolute(path).map_err(crate::Error::GetCurrentDirFailed)?;
+      print_reply(
+        send_request(requests::InspectTrack(path))?,
+        json,
+        |inspected| {
+          for line in inspected_track_lines(&inspected) {
+            println!("{line}");
+          }
+        },
+      )
+    }
+
+    Command::Lyrics { path } => {
+      let path = path
+        .map(|path| path::absolute(path).map_err(crate::Error::GetCurrentDirFailed))
+        .transpose()?;
+
+      print_reply(
+        send_request(requests::QueryLyrics(path))?,
+        json,
+        |lyrics| match lyrics {
+          Some(lyrics) => print_paged(&lyrics),
+          None => println!("No lyrics found"),
+        },
+      )
+    }
+
+    Command::NowPlaying {
+      format,
+      fail_if_stopped,
+    } => print_now_playing(&format, fail_if_stopped, json)?,
+
+    // Events are always printed as JSON
+    Command::Watch { events } => crate::ipc::subscribe(events, |event_data| {
+      println!("{event_data}");
+      let _ = io::stdout().flush();
+    })?,
+
+    // Waybar output is always JSON
+    Command::Waybar {
+      format,
+      max_length,
+      follow,
+    } => waybar::run(&format, max_length, follow)?,
+
+    Command::Quit => send_command(requests::Shutdown, json)?,
+
+    Command::Stats {
+      session: true,
+      reset: true,
+      ..
+    } => send_command(requests::ResetSessionStats, json)?,
+    Command::Stats {
+      session: true,
+      reset: false,
+      ..
+    } => print_reply(send_request(requests::QuerySessionStats)?, json, |stats| {
+      let session_length = SystemTime::now()
+        .duration_since(stats.since)
+        .unwrap_or_default();
+
+      println!(
+        "Uptime: {}",
+        format_duration(stats.uptime, DurationStyle::Long)
+      );
+      println!(
+        "Listened for {} in the last {}",
+        format_duration(stats.listening_time, DurationStyle::Long),
+        format_duration(session_length, DurationStyle::Long)
+      );
+      println!(
+        "Tracks finished: {}, skipped: {}",
+        stats.tracks_finished, stats.tracks_skipped
+      );
+      if let Some((artist, listened)) = stats.top_artist {
+        println!(
+          "Most played artist: {artist} ({})",
+          format_duration(listened, DurationStyle::Long)
+        );
+      }
+    }),
+    Command::Stats {
+      session: false,
+      timing,
+      ..
+    } => print_reply(send_request(requests::QueryServerStats)?, json, |stats| {
+      let blocking = stats.blocking;
+      println!(
+        "Bulk tasks: {} running, {} queued",
+        blocking.bulk_running, blocking.bulk_queued
+      );
+      println!(
+        "Interactive tasks: {} running",
+        blocking.interactive_running
+      );
+
+      if timing {
+        for line in request_timing_lines(stats.requests) {
+          println!("{line}");
+        }
+      }
+    }),
+
+    Command::Debug {
+      command: DebugCommand::Connections,
+    } => print_reply(
+      send_request(requests::QueryConnections)?,
+      json,
+      |connections| {
+        for connection in connections {
+          let connected_for = SystemTime::now()
+            .duration_since(connection.since)
+            .unwrap_or_default();
+
+          println!(
+            "{}: {}, {} requests, connected for {}s",
+            connection.id,
+            connection.name,
+            connection.requests_handled,
+            connected_for.as_secs()
+          );
+        }
+      },
+    ),
+
+    Command::Debug {
+      command: DebugCommand::Cache,
+    } => print_reply(send_request(requests::QueryCacheStats)?, json, |stats| {
+      println!(
+        "Entries: {} ({} live, {} not purged yet)",
+        stats.entries,
+        stats.live,
+        stats.entries.saturating_sub(stats.live)
+      );
+      println!(
+        "Memory used by live tracks: about {:.1} MiB",
+        stats.live_bytes as f64 / (1024.0 * 1024.0)
+      );
+      println!(
+        "Recently used tracks kept loaded: {} of {}",
+        stats.retained, stats.max_retained
+      );
+      println!("Evicted: {}, purged: {}", stats.evicted, stats.purged);
+    }),
+
+    Command::Debug {
+      command: DebugCommand::Transition,
+    } => print_reply(
+      send_request(requests::QueryNextTransition)?,
+      json,
+      |transition| match transition {
+        Some(TransitionMode::Gapless) => println!("The next track will play gapless"),
+        Some(TransitionMode::Crossfade) => println!("The next track will crossfade"),
+        None => println!("No other track plays after the current one"),
+      },
+    ),
+
+    Command::Debug {
+      command: DebugCommand::Tone {
+        frequency,
+        duration,
+      },
+    } => {
+      let result = send_request(requests::PlayTestTone {
+        frequency,
+        duration,
+      })?;
+      print_reply(result, json, |result| {
+        println!(
+          "Played {:.2}s of a {frequency} Hz tone",
+          result.played.as_secs_f64()
+        );
+      });
+
+      if !result.completed {
+        return Err(crate::Error::TestToneFailed(result.played));
+      }
+    }
+
+    Command::Doctor => {
+      let failed = doctor::run_checks(json);
+      if failed > 0 {
+        return Err(crate::Error::DoctorChecksFailed(failed));
+      }
+    }
   };
 
   Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+  use hsm_ipc::{Track, TrackMetadata};
+
+  use super::*;
+
+  #[test]
+  fn formats_playback_state() {
+    let error = StopReason::Error("Track has no supported audio codec".into());
+    let cases = [
+      (PlaybackState::Playing, None, "Playing"),
+      (PlaybackState::Paused, None, "Paused"),
+      (PlaybackState::Stopped, None, "Stopped"),
+      (
+        PlaybackState::Stopped,
+        Some(&StopReason::EndOfQueue),
+        "Stopped (end of queue)",
+      ),
+      (
+        PlaybackState::Stopped,
+        Some(&StopReason::UserRequested),
+        "Stopped (by request)",
+      ),
+      (
+        PlaybackState::Stopped,
+        Some(&StopReason::QueueCleared),
+        "Stopped (queue cleared)",
+      ),
+      (
+        PlaybackState::Stopped,
+        Some(&error),
+        "Stopped (error: Track has no supported audio codec)",
+      ),
+      // A reason left over from before playback started again is not shown
+      (
+        PlaybackState::Playing,
+        Some(&StopReason::EndOfQueue),
+        "Playing",
+      ),
+    ];
+
+    for (playback_state, stop_reason, expected) in cases {
+      assert_eq!(format_state(playback_state, stop_reason), expected);
+    }
+  }
+
+  fn inspected_track(metadata: TrackMetadata) -> InspectedTrack {
+    InspectedTrack {
+      track: Track {
+        file_path: "/music/a.flac".into(),
+        total_duration: Some(Duration::from_secs(83)),
+        metadata,
+      },
+      sample_rate: 44100,
+      channels: 2,
+      format_unverified: false,
+      has_lyrics: false,
+      encoder_delay: 0,
+      encoder_padding: 0,
+    }
+  }
+
+  #[test]
+  fn formats_inspected_track() {
+    let mut metadata = TrackMetadata {
+      title: Some("Title".into()),
+      artists: vec!["Zed".into(), "Abba".into()],
+      genres: vec!["Rock".into()],
+      ..Default::default()
+    };
+    metadata.replay_gain.track_gain = Some(-6.5);
+
+    assert_eq!(
+      inspected_track_lines(&inspected_track(metadata)),
+      [
+        "Path: /music/a.flac",
+        "Duration: 1:23",
+        "Format: 44100 Hz, 2 channels",
+        "Title: Title",
+        "Artists: Zed, Abba",
+        "Album: unknown",
+        "Genres: Rock",
+        "Lyrics tag: no",
+        "ReplayGain track: -6.50 dB",
+      ]
+    );
+  }
+
+  #[test]
+  fn formats_inspected_format_details() {
+    let mut inspected = inspected_track(TrackMetadata::default());
+    inspected.format_unverified = true;
+    inspected.has_lyrics = true;
+    inspected.encoder_delay = 2112;
+    inspected.encoder_padding = 458;
+
+    let lines = inspected_track_lines(&inspected);
+    assert_eq!(
+      lines[2],
+      "Format: 44100 Hz, 2 channels (unverified, the start of the file could not be decoded)"
+    );
+    assert_eq!(
+      lines[3],
+      "Gapless trim: 2112 frames of delay, 458 of padding"
+    );
+    assert!(lines.contains(&"Lyrics tag: yes".to_owned()));
+  }
+
+  #[test]
+  fn formats_request_timings() {
+    let timing = |name: &str, count, total_ms, max_ms, buckets: [u64; 7]| RequestTiming {
+      name: name.to_owned(),
+      count,
+      total: Duration::from_millis(total_ms),
+      max: Duration::from_millis(max_ms),
+      buckets: buckets.to_vec(),
+    };
+    let timings = vec![
+      timing("QueryVolume", 4, 2, 1, [4, 0, 0, 0, 0, 0, 0]),
+      timing("LoadTracks", 2, 3000, 2500, [0, 0, 0, 0, 0, 1, 1]),
+    ];
+
+    assert_eq!(
+      request_timing_lines(timings),
+      [
+        "LoadTracks: 2 requests, 1500.0ms average, 2500.0ms max",
+        "  <=1000ms: 1, >1000ms: 1",
+        "QueryVolume: 4 requests, 0.5ms average, 1.0ms max",
+        "  <=1ms: 4",
+      ]
+    );
+  }
+}
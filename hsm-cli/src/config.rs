@@ -0,0 +1,199 @@
+use std::{
+  collections::BTreeMap,
+  env, fs, io, mem,
+  path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+  #[error("Failed to read config file {path:?}: {source}")]
+  ReadFailed { path: PathBuf, source: io::Error },
+
+  #[error("Failed to parse config file {path:?}: {source}")]
+  ParseFailed {
+    path: PathBuf,
+    source: toml::de::Error,
+  },
+
+  #[error("Unknown subcommand `{0}` in the [defaults] of the cli config")]
+  UnknownSubcommand(String),
+
+  #[error("`hsm {subcommand}` has no argument named `{argument}`")]
+  UnknownArgument {
+    subcommand: String,
+    argument: String,
+  },
+
+  #[error("The default for `hsm {subcommand} --{argument}` must be a string, number, or boolean")]
+  InvalidValue {
+    subcommand: String,
+    argument: String,
+  },
+}
+
+/// `hsm` configuration, read from `$XDG_CONFIG_HOME/homeslashmusic/cli.toml`
+///
+/// Arguments passed on the command line always take precedence
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CliConfig {
+  /// Used if neither `--socket` nor `$HSM_SOCKET_PATH` are set
+  pub socket: Option<PathBuf>,
+  /// Default argument values for each subcommand, such as `[defaults.waybar] max_length = 30`
+  ///
+  /// Nested subcommands are separated by spaces, such as `[defaults."queue add"]`
+  pub defaults: BTreeMap<String, BTreeMap<String, toml::Value>>,
+}
+
+impl CliConfig {
+  pub fn config_path() -> Option<PathBuf> {
+    env::var_os("XDG_CONFIG_HOME")
+      .map(PathBuf::from)
+      .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+      .map(|config_home| config_home.join("homeslashmusic").join("cli.toml"))
+  }
+
+  /// Loads the config file, or the default config if it does not exist
+  pub fn load() -> Result<Self, ConfigError> {
+    let Some(path) = Self::config_path() else {
+      return Ok(Self::default());
+    };
+
+    let config_data = match fs::read_to_string(&path) {
+      Ok(config_data) => config_data,
+      Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+      Err(source) => return Err(ConfigError::ReadFailed { path, source }),
+    };
+
+    toml::from_str(&config_data).map_err(|source| ConfigError::ParseFailed { path, source })
+  }
+
+  /// Sets the `defaults` as the default values of `command`'s arguments
+  pub fn apply_defaults(&self, mut command: clap::Command) -> Result<clap::Command, ConfigError> {
+    for (path, arguments) in &self.defaults {
+      let mut subcommand = &mut command;
+      for name in path.split_whitespace() {
+        subcommand = subcommand
+          .find_subcommand_mut(name)
+          .ok_or_else(|| ConfigError::UnknownSubcommand(path.clone()))?;
+      }
+
+      for (argument, value) in arguments {
+        // Argument ids are the field names, while long flags use dashes
+        let id = argument.replace('-', "_");
+        if !subcommand
+          .get_arguments()
+          .any(|arg| arg.get_id() == id.as_str())
+        {
+          return Err(ConfigError::UnknownArgument {
+            subcommand: path.clone(),
+            argument: argument.clone(),
+          });
+        }
+
+        let value = match value {
+          toml::Value::String(value) => value.clone(),
+          toml::Value::Integer(value) => value.to_string(),
+          toml::Value::Float(value) => value.to_string(),
+          toml::Value::Boolean(value) => value.to_string(),
+          _ => {
+            return Err(ConfigError::InvalidValue {
+              subcommand: path.clone(),
+              argument: argument.clone(),
+            });
+          }
+        };
+
+        *subcommand = mem::take(subcommand).mut_arg(id, |arg| arg.default_value(value));
+      }
+    }
+
+    Ok(command)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use clap::{CommandFactory, FromArgMatches};
+
+  use super::*;
+  use crate::cli::{Cli, Command};
+
+  fn parse(config_data: &str, args: &[&str]) -> Result<Cli, ConfigError> {
+    let config: CliConfig = toml::from_str(config_data).unwrap();
+    let command = config.apply_defaults(Cli::command())?;
+    let matches = command.try_get_matches_from(args).unwrap();
+    Ok(Cli::from_arg_matches(&matches).unwrap())
+  }
+
+  fn waybar_args(cli: Cli) -> (String, usize, bool) {
+    match cli.command {
+      Command::Waybar {
+        format,
+        max_length,
+        follow,
+      } => (format, max_length, follow),
+      command => panic!("Parsed {command:?}"),
+    }
+  }
+
+  #[test]
+  fn defaults_are_used_without_arguments() {
+    let config_data = r#"
+      socket = "/tmp/hsm.sock"
+
+      [defaults.waybar]
+      max_length = 30
+      follow = true
+    "#;
+
+    let cli = parse(config_data, &["hsm", "waybar"]).unwrap();
+    // Only the arguments in the config change, the others keep the built in defaults
+    assert_eq!(
+      waybar_args(cli),
+      ("{artist} - {title|filename}".into(), 30, true)
+    );
+
+    let config: CliConfig = toml::from_str(config_data).unwrap();
+    assert_eq!(config.socket, Some(PathBuf::from("/tmp/hsm.sock")));
+  }
+
+  #[test]
+  fn arguments_take_precedence_over_defaults() {
+    let config_data = r#"
+      [defaults.waybar]
+      max-length = 30
+      format = "{title}"
+    "#;
+
+    let cli = parse(config_data, &["hsm", "waybar", "--max-length", "10"]).unwrap();
+    assert_eq!(waybar_args(cli), ("{title}".into(), 10, false));
+  }
+
+  #[test]
+  fn rejects_unknown_defaults() {
+    let unknown_subcommand = parse("[defaults.nope]\na = 1", &["hsm", "waybar"]);
+    assert!(matches!(
+      unknown_subcommand,
+      Err(ConfigError::UnknownSubcommand(_))
+    ));
+
+    let unknown_argument = parse(
+      "[defaults.\"queue add\"]\nshuffle = true",
+      &["hsm", "waybar"],
+    );
+    assert!(matches!(
+      unknown_argument,
+      Err(ConfigError::UnknownArgument { .. })
+    ));
+
+    let invalid_value = parse("[defaults.waybar]\nformat = [1]", &["hsm", "waybar"]);
+    assert!(matches!(
+      invalid_value,
+      Err(ConfigError::InvalidValue { .. })
+    ));
+  }
+}
@@ -0,0 +1,563 @@
+use std::{
+  env,
+  io::{self, ErrorKind, Write},
+  os::unix::{fs::FileTypeExt, net::UnixStream},
+  path::Path,
+};
+
+use hsm_ipc::requests;
+use serde::Serialize;
+
+use crate::ipc::{self, send_request};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+  Ok,
+  Warning,
+  Failed,
+}
+
+/// The result of a single diagnostic check
+#[derive(Debug, Serialize)]
+pub struct Check {
+  pub name: &'static str,
+  pub status: Status,
+  pub message: String,
+  /// What the user can do to fix a warning or failure
+  pub suggestion: Option<String>,
+}
+
+impl Check {
+  fn ok(name: &'static str, message: impl Into<String>) -> Self {
+    Self {
+      name,
+      status: Status::Ok,
+      message: message.into(),
+      suggestion: None,
+    }
+  }
+
+  fn warning(
+    name: &'static str,
+    message: impl Into<String>,
+    suggestion: impl Into<String>,
+  ) -> Self {
+    Self {
+      name,
+      status: Status::Warning,
+      message: message.into(),
+      suggestion: Some(suggestion.into()),
+    }
+  }
+
+  fn failed(name: &'static str, message: impl Into<String>, suggestion: impl Into<String>) -> Self {
+    Self {
+      name,
+      status: Status::Failed,
+      message: message.into(),
+      suggestion: Some(suggestion.into()),
+    }
+  }
+
+  fn write(&self, out: &mut impl Write) -> io::Result<()> {
+    let status = match self.status {
+      Status::Ok => "ok",
+      Status::Warning => "warn",
+      Status::Failed => "fail",
+    };
+
+    writeln!(out, "[{status:>4}] {}: {}", self.name, self.message)?;
+    if let Some(suggestion) = &self.suggestion {
+      writeln!(out, "       {suggestion}")?;
+    }
+
+    Ok(())
+  }
+}
+
+/// What the checks read from the environment, the filesystem and the server, so tests can fake them
+pub trait Probes {
+  fn runtime_dir_var(&self) -> Option<String>;
+  fn is_dir(&self, path: &Path) -> bool;
+  /// Whether the file at `path` is a socket
+  fn is_socket(&self, path: &Path) -> io::Result<bool>;
+  fn connect(&self, socket_path: &Path) -> io::Result<()>;
+  fn server_version(&self) -> Result<hsm_ipc::Version, crate::Error>;
+  fn output_info(&self) -> Result<hsm_ipc::OutputInfo, crate::Error>;
+  #[cfg(feature = "dbus")]
+  fn bus_name_has_owner(&self, bus_name: &str) -> zbus::Result<bool>;
+}
+
+/// Probes the real system and the running server
+pub struct SystemProbes;
+
+impl Probes for SystemProbes {
+  fn runtime_dir_var(&self) -> Option<String> {
+    env::var("XDG_RUNTIME_DIR").ok()
+  }
+
+  fn is_dir(&self, path: &Path) -> bool {
+    path.is_dir()
+  }
+
+  fn is_socket(&self, path: &Path) -> io::Result<bool> {
+    Ok(path.metadata()?.file_type().is_socket())
+  }
+
+  fn connect(&self, socket_path: &Path) -> io::Result<()> {
+    UnixStream::connect(socket_path).map(|_| ())
+  }
+
+  fn server_version(&self) -> Result<hsm_ipc::Version, crate::Error> {
+    send_request(requests::QueryVersion)
+  }
+
+  fn output_info(&self) -> Result<hsm_ipc::OutputInfo, crate::Error> {
+    send_request(requests::QueryOutputInfo)
+  }
+
+  #[cfg(feature = "dbus")]
+  fn bus_name_has_owner(&self, bus_name: &str) -> zbus::Result<bool> {
+    use zbus::{blocking::Connection, blocking::fdo::DBusProxy, names::BusName};
+
+    let connection = Connection::session()?;
+    DBusProxy::new(&connection)?
+      .name_has_owner(BusName::try_from(bus_name)?)
+      .map_err(zbus::Error::from)
+  }
+}
+
+pub fn check_runtime_dir(probes: &impl Probes) -> Check {
+  const NAME: &str = "Runtime directory";
+
+  match probes.runtime_dir_var() {
+    Some(runtime_dir) if probes.is_dir(Path::new(&runtime_dir)) => {
+      Check::ok(NAME, format!("XDG_RUNTIME_DIR is {runtime_dir}"))
+    }
+    Some(runtime_dir) => Check::failed(
+      NAME,
+      format!("XDG_RUNTIME_DIR is {runtime_dir}, but it is not a directory"),
+      "Make sure your session manager creates the runtime directory",
+    ),
+    None => Check::warning(
+      NAME,
+      format!(
+        "XDG_RUNTIME_DIR is not set, the socket path falls back to {}",
+        hsm_ipc::runtime_dir()
+      ),
+      "Set XDG_RUNTIME_DIR in the environment of both hsm-server and hsm",
+    ),
+  }
+}
+
+pub fn check_socket(probes: &impl Probes, socket_path: &Path) -> Check {
+  const NAME: &str = "Socket";
+
+  let is_socket = match probes.is_socket(socket_path) {
+    Ok(is_socket) => is_socket,
+    Err(error) if error.kind() == ErrorKind::NotFound => {
+      return Check::failed(
+        NAME,
+        format!("{socket_path:?} does not exist"),
+        "Start hsm-server, or check that it uses the same XDG_RUNTIME_DIR as hsm",
+      );
+    }
+    Err(error) => {
+      return Check::failed(
+        NAME,
+        format!("Could not read {socket_path:?}: {error}"),
+        "Check the permissions of the runtime directory",
+      );
+    }
+  };
+
+  if !is_socket {
+    return Check::failed(
+      NAME,
+      format!("{socket_path:?} exists but is not a socket"),
+      "Remove the file and restart hsm-server",
+    );
+  }
+
+  match probes.connect(socket_path) {
+    Ok(_) => Check::ok(NAME, format!("{socket_path:?} accepts connections")),
+    Err(error) if error.kind() == ErrorKind::ConnectionRefused => Check::failed(
+      NAME,
+      format!("{socket_path:?} is stale, nothing is listening on it"),
+      "hsm-server probably crashed, remove the socket file and restart it",
+    ),
+    Err(error) => Check::failed(
+      NAME,
+      format!("Could not connect to {socket_path:?}: {error}"),
+      "Check that hsm-server is running as the same user",
+    ),
+  }
+}
+
+pub fn check_version(probes: &impl Probes) -> Check {
+  const NAME: &str = "Version";
+
+  let cli_version = hsm_ipc::version();
+  match probes.server_version() {
+    Ok(server_version) if server_version.0 == cli_version.0 => Check::ok(
+      NAME,
+      format!("hsm-server and hsm are both {}", cli_version.0),
+    ),
+    Ok(server_version) => Check::warning(
+      NAME,
+      format!(
+        "hsm-server is {} but hsm is {}",
+        server_version.0, cli_version.0
+      ),
+      "Restart hsm-server after upgrading so both use the same protocol",
+    ),
+    Err(error) => Check::failed(
+      NAME,
+      format!("hsm-server did not answer a version query: {error}"),
+      "Restart hsm-server and check its output for errors",
+    ),
+  }
+}
+
+pub fn check_audio_output(probes: &impl Probes) -> Check {
+  const NAME: &str = "Audio output";
+
+  match probes.output_info() {
+    Ok(output_info) => Check::ok(
+      NAME,
+      format!(
+        "{}Hz, {} channels{}",
+        output_info.sample_rate,
+        output_info.channels,
+        if output_info.bit_perfect {
+          ", bit perfect"
+        } else {
+          ""
+        }
+      ),
+    ),
+    Err(error) => Check::failed(
+      NAME,
+      format!("Could not query the audio output: {error}"),
+      "Check that an audio device is available to hsm-server",
+    ),
+  }
+}
+
+#[cfg(feature = "dbus")]
+pub fn check_mpris(probes: &impl Probes) -> Check {
+  const NAME: &str = "MPRIS";
+  const BUS_NAME: &str = "org.mpris.MediaPlayer2.dev.djlaser.HomeSlashMusic";
+
+  match probes.bus_name_has_owner(BUS_NAME) {
+    Ok(true) => Check::ok(NAME, format!("{BUS_NAME} is owned on the session bus")),
+    Ok(false) => Check::warning(
+      NAME,
+      format!("{BUS_NAME} is not owned on the session bus"),
+      "hsm-server may have been built without the mpris plugin, or is not running",
+    ),
+    Err(error) => Check::warning(
+      NAME,
+      format!("Could not connect to the session bus: {error}"),
+      "Make sure DBUS_SESSION_BUS_ADDRESS is set, media keys and playerctl will not work without it",
+    ),
+  }
+}
+
+/// Runs every check and prints a summary
+///
+/// Returns the number of failed checks
+pub fn run_checks(json: bool) -> usize {
+  write_checks(
+    &SystemProbes,
+    ipc::socket_path(),
+    json,
+    &mut io::stdout().lock(),
+  )
+  .expect("Failed to write to stdout")
+}
+
+/// Runs every check with `probes` and writes a summary to `out`
+fn write_checks(
+  probes: &impl Probes,
+  socket_path: &Path,
+  json: bool,
+  out: &mut impl Write,
+) -> io::Result<usize> {
+  let socket = check_socket(probes, socket_path);
+  let connected = socket.status == Status::Ok;
+
+  let mut checks = vec![check_runtime_dir(probes), socket];
+
+  if connected {
+    checks.push(check_version(probes));
+    checks.push(check_audio_output(probes));
+  }
+
+  #[cfg(feature = "dbus")]
+  checks.push(check_mpris(probes));
+
+  let failed = checks
+    .iter()
+    .filter(|check| check.status == Status::Failed)
+    .count();
+
+  if json {
+    serde_json::to_writer(&mut *out, &checks).expect("Checks should not fail to serialize");
+    writeln!(out)?;
+    return Ok(failed);
+  }
+
+  for check in checks.iter() {
+    check.write(out)?;
+  }
+
+  let warnings = checks
+    .iter()
+    .filter(|check| check.status == Status::Warning)
+    .count();
+
+  writeln!(out)?;
+  match (failed, warnings) {
+    (0, 0) => writeln!(out, "Everything looks good")?,
+    (failed, warnings) => writeln!(out, "{failed} checks failed, {warnings} warnings")?,
+  }
+
+  Ok(failed)
+}
+
+#[cfg(test)]
+mod tests {
+  use hsm_ipc::{OutputInfo, Version};
+
+  use super::*;
+
+  const SOCKET_PATH: &str = "/run/user/1000/hsm.sock";
+
+  struct FakeProbes {
+    runtime_dir: Option<&'static str>,
+    runtime_dir_exists: bool,
+    is_socket: Result<bool, ErrorKind>,
+    connect: Result<(), ErrorKind>,
+    /// The server answers with the cli's version if this is `None`
+    server_version: Option<&'static str>,
+    output_info: Option<OutputInfo>,
+    bus_name_owned: Option<bool>,
+  }
+
+  impl FakeProbes {
+    /// A running server reachable through an existing runtime directory
+    fn healthy() -> Self {
+      Self {
+        runtime_dir: Some("/run/user/1000"),
+        runtime_dir_exists: true,
+        is_socket: Ok(true),
+        connect: Ok(()),
+        server_version: None,
+        output_info: Some(OutputInfo {
+          sample_rate: 48000,
+          channels: 2,
+          bit_perfect: false,
+        }),
+        bus_name_owned: Some(true),
+      }
+    }
+  }
+
+  impl Probes for FakeProbes {
+    fn runtime_dir_var(&self) -> Option<String> {
+      self.runtime_dir.map(String::from)
+    }
+
+    fn is_dir(&self, path: &Path) -> bool {
+      assert_eq!(Some(path), self.runtime_dir.map(Path::new));
+      self.runtime_dir_exists
+    }
+
+    fn is_socket(&self, _path: &Path) -> io::Result<bool> {
+      self.is_socket.map_err(io::Error::from)
+    }
+
+    fn connect(&self, _socket_path: &Path) -> io::Result<()> {
+      self.connect.map_err(io::Error::from)
+    }
+
+    fn server_version(&self) -> Result<Version, crate::Error> {
+      Ok(
+        self
+          .server_version
+          .map_or_else(hsm_ipc::version, |version| Version(version.into())),
+      )
+    }
+
+    fn output_info(&self) -> Result<OutputInfo, crate::Error> {
+      self.output_info.ok_or(crate::Error::Disconnected)
+    }
+
+    #[cfg(feature = "dbus")]
+    fn bus_name_has_owner(&self, _bus_name: &str) -> zbus::Result<bool> {
+      self
+        .bus_name_owned
+        .ok_or_else(|| zbus::Error::Failure("No session bus".into()))
+    }
+  }
+
+  fn output(probes: &FakeProbes, json: bool) -> (usize, String) {
+    let mut out = Vec::new();
+    let failed = write_checks(probes, Path::new(SOCKET_PATH), json, &mut out).unwrap();
+    (failed, String::from_utf8(out).unwrap())
+  }
+
+  #[test]
+  fn healthy_setup_passes() {
+    let (failed, output) = output(&FakeProbes::healthy(), false);
+    let version = hsm_ipc::version().0;
+
+    let mut expected = format!(
+      "[  ok] Runtime directory: XDG_RUNTIME_DIR is /run/user/1000\n\
+       [  ok] Socket: \"{SOCKET_PATH}\" accepts connections\n\
+       [  ok] Version: hsm-server and hsm are both {version}\n\
+       [  ok] Audio output: 48000Hz, 2 channels\n"
+    );
+    if cfg!(feature = "dbus") {
+      expected += "[  ok] MPRIS: org.mpris.MediaPlayer2.dev.djlaser.HomeSlashMusic is owned on the session bus\n";
+    }
+    expected += "\nEverything looks good\n";
+
+    assert_eq!(failed, 0);
+    assert_eq!(output, expected);
+  }
+
+  #[test]
+  fn warnings_include_suggestions() {
+    let probes = FakeProbes {
+      runtime_dir: None,
+      server_version: Some("0.0.1"),
+      ..FakeProbes::healthy()
+    };
+    let (failed, output) = output(&probes, false);
+    let lines: Vec<_> = output.lines().collect();
+
+    assert_eq!(failed, 0);
+    assert!(lines[0].starts_with("[warn] Runtime directory: XDG_RUNTIME_DIR is not set"));
+    assert_eq!(
+      lines[1],
+      "       Set XDG_RUNTIME_DIR in the environment of both hsm-server and hsm"
+    );
+    assert_eq!(
+      lines[3],
+      format!(
+        "[warn] Version: hsm-server is 0.0.1 but hsm is {}",
+        hsm_ipc::version().0
+      )
+    );
+    assert_eq!(lines.last(), Some(&"0 checks failed, 2 warnings"));
+  }
+
+  #[test]
+  fn unreachable_server_skips_server_checks() {
+    let probes = FakeProbes {
+      connect: Err(ErrorKind::ConnectionRefused),
+      // Would fail the audio output check if it was run
+      output_info: None,
+      ..FakeProbes::healthy()
+    };
+    let (failed, output) = output(&probes, false);
+
+    assert_eq!(failed, 1);
+    assert!(output.contains(&format!(
+      "[fail] Socket: \"{SOCKET_PATH}\" is stale, nothing is listening on it\n       hsm-server probably crashed, remove the socket file and restart it\n"
+    )));
+    assert!(!output.contains("Version"));
+    assert!(!output.contains("Audio output"));
+    assert!(output.ends_with("\n1 checks failed, 0 warnings\n"));
+  }
+
+  #[test]
+  fn socket_failures() {
+    let cases = [
+      (Err(ErrorKind::NotFound), Ok(()), "does not exist"),
+      (Err(ErrorKind::PermissionDenied), Ok(()), "Could not read"),
+      (Ok(false), Ok(()), "exists but is not a socket"),
+      (
+        Ok(true),
+        Err(ErrorKind::PermissionDenied),
+        "Could not connect to",
+      ),
+    ];
+
+    for (is_socket, connect, message) in cases {
+      let probes = FakeProbes {
+        is_socket,
+        connect,
+        ..FakeProbes::healthy()
+      };
+      let check = check_socket(&probes, Path::new(SOCKET_PATH));
+
+      assert_eq!(check.status, Status::Failed);
+      assert!(check.message.contains(message), "{}", check.message);
+      assert!(check.suggestion.is_some());
+    }
+  }
+
+  #[test]
+  fn missing_runtime_dir_fails() {
+    let probes = FakeProbes {
+      runtime_dir_exists: false,
+      ..FakeProbes::healthy()
+    };
+    let check = check_runtime_dir(&probes);
+
+    assert_eq!(check.status, Status::Failed);
+    assert_eq!(
+      check.message,
+      "XDG_RUNTIME_DIR is /run/user/1000, but it is not a directory"
+    );
+  }
+
+  #[test]
+  fn failed_queries_fail_their_checks() {
+    let probes = FakeProbes {
+      output_info: None,
+      bus_name_owned: None,
+      ..FakeProbes::healthy()
+    };
+
+    assert_eq!(check_audio_output(&probes).status, Status::Failed);
+    // MPRIS is optional, so not reaching the session bus only warns
+    #[cfg(feature = "dbus")]
+    assert_eq!(check_mpris(&probes).status, Status::Warning);
+  }
+
+  #[test]
+  fn json_output_lists_checks() {
+    let probes = FakeProbes {
+      is_socket: Ok(false),
+      ..FakeProbes::healthy()
+    };
+    let (failed, output) = output(&probes, true);
+    let checks: serde_json::Value = serde_json::from_str(&output).unwrap();
+    let statuses: Vec<_> = checks
+      .as_array()
+      .unwrap()
+      .iter()
+      .map(|check| {
+        (
+          check["name"].as_str().unwrap(),
+          check["status"].as_str().unwrap(),
+        )
+      })
+      .collect();
+
+    assert_eq!(failed, 1);
+    assert_eq!(
+      &statuses[..2],
+      [("Runtime directory", "ok"), ("Socket", "failed")]
+    );
+    assert_eq!(
+      checks[1]["suggestion"],
+      "Remove the file and restart hsm-server"
+    );
+  }
+}
@@ -0,0 +1,161 @@
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy)]
+pub enum DurationStyle {
+  /// `1:02:03`, or `2:03` if there are no hours
+  Short,
+  /// `1h 02m 03s`, or `2m 03s` if there are no hours
+  Long,
+}
+
+pub fn format_duration(duration: Duration, style: DurationStyle) -> String {
+  let secs = duration.as_secs();
+  let (hours, minutes, secs) = (secs / 3600, secs / 60 % 60, secs % 60);
+
+  match style {
+    DurationStyle::Short if hours > 0 => format!("{hours}:{minutes:02}:{secs:02}"),
+    DurationStyle::Short => format!("{minutes}:{secs:02}"),
+    DurationStyle::Long if hours > 0 => format!("{hours}h {minutes:02}m {secs:02}s"),
+    DurationStyle::Long => format!("{minutes}m {secs:02}s"),
+  }
+}
+
+/// Parses `1:23:45` or `2:03`, where only the seconds can have a fraction
+fn parse_clock(s: &str) -> Option<f64> {
+  let parts: Vec<&str> = s.split(':').collect();
+  let (secs, hours_and_minutes) = parts.split_last()?;
+  if hours_and_minutes.len() > 2 {
+    return None;
+  }
+
+  let mut minutes = 0.0;
+  for part in hours_and_minutes {
+    minutes = minutes * 60.0 + part.parse::<u64>().ok()? as f64;
+  }
+
+  Some(minutes * 60.0 + secs.parse::<f64>().ok()?)
+}
+
+/// Parses numbers followed by `h`, `m`, `s`, or `ms`, such as `1h30m`, `1h 30m` or `1.5m`
+fn parse_units(s: &str) -> Option<f64> {
+  let mut total = 0.0;
+  let mut rest = s;
+
+  while !rest.is_empty() {
+    let number_len = rest.find(|c: char| !(c.is_ascii_digit() || c == '.'))?;
+    let (number, after_number) = rest.split_at(number_len);
+
+    let unit_len = after_number
+      .find(|c: char| !c.is_ascii_alphabetic())
+      .unwrap_or(after_number.len());
+    let (unit, after_unit) = after_number.split_at(unit_len);
+
+    let scale = match unit {
+      "h" => 3600.0,
+      "m" => 60.0,
+      "s" => 1.0,
+      "ms" => 0.001,
+      _ => return None,
+    };
+
+    total += number.parse::<f64>().ok()? * scale;
+    // So the long style of `format_duration` can be parsed back
+    rest = after_unit.trim_start();
+  }
+
+  Some(total)
+}
+
+/// Parses a number of seconds, `1:23:45`, `2:03`, or unit suffixed durations like `95s`, `1.5m`, `2h`, and `1h30m`
+pub fn parse_duration(s: &str) -> Result<Duration, String> {
+  let invalid = || format!("{s} is not a valid duration, try 95, 1:35, or 1m35s");
+
+  // Offsets are signed by the caller, and `f64` parsing would accept a sign in the seconds of `1:-5`
+  if s.contains(['+', '-']) {
+    return Err(invalid());
+  }
+
+  let secs = if s.contains(':') {
+    parse_clock(s)
+  } else if s.ends_with(|c: char| c.is_ascii_alphabetic()) {
+    parse_units(s)
+  } else {
+    s.parse().ok()
+  };
+
+  // Negative, infinite, and NaN seconds are rejected here
+  Duration::try_from_secs_f64(secs.ok_or_else(invalid)?).map_err(|_| invalid())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn formats_durations() {
+    // (seconds, short, long)
+    let cases = [
+      (0.0, "0:00", "0m 00s"),
+      (59.9, "0:59", "0m 59s"),
+      (61.0, "1:01", "1m 01s"),
+      (600.0, "10:00", "10m 00s"),
+      (3600.0, "1:00:00", "1h 00m 00s"),
+      (3723.0, "1:02:03", "1h 02m 03s"),
+      (90000.0, "25:00:00", "25h 00m 00s"),
+    ];
+
+    for (secs, short, long) in cases {
+      let duration = Duration::from_secs_f64(secs);
+      assert_eq!(format_duration(duration, DurationStyle::Short), short);
+      assert_eq!(format_duration(duration, DurationStyle::Long), long);
+    }
+  }
+
+  #[test]
+  fn parses_durations() {
+    let cases = [
+      ("95", 95.0),
+      ("1.5", 1.5),
+      ("0", 0.0),
+      ("1:35", 95.0),
+      ("0:05.5", 5.5),
+      ("1:02:03", 3723.0),
+      ("01:02:03", 3723.0),
+      ("95s", 95.0),
+      ("1.5m", 90.0),
+      ("2h", 7200.0),
+      ("1h30m", 5400.0),
+      ("1h 30m", 5400.0),
+      ("250ms", 0.25),
+      ("1m500ms", 60.5),
+    ];
+
+    for (s, secs) in cases {
+      assert_eq!(parse_duration(s), Ok(Duration::from_secs_f64(secs)), "{s}");
+    }
+  }
+
+  #[test]
+  fn round_trips_whole_seconds() {
+    for secs in [0, 5, 59, 60, 95, 3599, 3600, 3723, 86399, 90000] {
+      let duration = Duration::from_secs(secs);
+
+      for style in [DurationStyle::Short, DurationStyle::Long] {
+        let formatted = format_duration(duration, style);
+        assert_eq!(parse_duration(&formatted), Ok(duration), "{formatted}");
+      }
+    }
+  }
+
+  #[test]
+  fn rejects_invalid_durations() {
+    let cases = [
+      "", " ", "abc", "m", "5x", "1h30", "1 h", "1.2.3", "1:", ":5", "1:2:3:4", "1:a", "-5", "+5",
+      "-1:00", "1:-5", "-5s", "inf", "NaN", "1e400",
+    ];
+
+    for s in cases {
+      assert!(parse_duration(s).is_err(), "{s}");
+    }
+  }
+}
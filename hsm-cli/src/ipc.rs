@@ -2,22 +2,39 @@ use std::{
   io::{BufRead, BufReader, Write},
   net::Shutdown,
   os::unix::net::UnixStream,
+  path::{Path, PathBuf},
+  sync::OnceLock,
 };
 
 use hsm_ipc::{
-  Request,
+  EventKind, Request,
   client::{deserialize_reply, serialize_request},
+  requests,
 };
 
 use crate::Error;
 
+static SOCKET_PATH: OnceLock<PathBuf> = OnceLock::new();
+
+/// Overrides the socket used by `send_request`, must be called before any requests are sent
+pub fn set_socket_path(socket_path: PathBuf) {
+  let _ = SOCKET_PATH.set(socket_path);
+}
+
+pub fn socket_path() -> &'static Path {
+  SOCKET_PATH.get_or_init(|| hsm_ipc::socket_path().into())
+}
+
+fn connect() -> Result<UnixStream, crate::Error> {
+  let socket_path = socket_path();
+  UnixStream::connect(socket_path).map_err(|source| crate::Error::FailedToConnectToSocket {
+    path: socket_path.to_string_lossy().into_owned(),
+    source,
+  })
+}
+
 pub fn send_request<R: Request>(request: R) -> Result<R::Response, crate::Error> {
-  let socket_path = hsm_ipc::socket_path();
-  let mut stream =
-    UnixStream::connect(socket_path).map_err(|source| crate::Error::FailedToConnectToSocket {
-      path: socket_path.into(),
-      source,
-    })?;
+  let mut stream = connect()?;
 
   stream
     .write_all(serialize_request(request).as_bytes())
@@ -38,3 +55,38 @@ pub fn send_request<R: Request>(request: R) -> Result<R::Response, crate::Error>
 
   reply.map_err(|error| Error::Server(error))
 }
+
+/// Subscribes to `kinds` of events, or every event if it is empty, and calls `on_event` with each line of JSON the server sends
+///
+/// Only returns once the connection fails or the server closes it
+pub fn subscribe(
+  kinds: Vec<EventKind>,
+  mut on_event: impl FnMut(&str),
+) -> Result<(), crate::Error> {
+  let mut stream = connect()?;
+  stream
+    .write_all(serialize_request(requests::Subscribe(kinds)).as_bytes())
+    .map_err(crate::Error::StreamReadWrite)?;
+
+  let mut stream_reader = BufReader::new(stream);
+  let mut line = String::new();
+  stream_reader
+    .read_line(&mut line)
+    .map_err(crate::Error::StreamReadWrite)?;
+
+  deserialize_reply::<requests::Subscribe>(&line)
+    .map_err(crate::Error::Deserialize)?
+    .map_err(Error::Server)?;
+
+  loop {
+    line.clear();
+    let read = stream_reader
+      .read_line(&mut line)
+      .map_err(crate::Error::StreamReadWrite)?;
+    if read == 0 {
+      return Err(crate::Error::Disconnected);
+    }
+
+    on_event(line.trim_end());
+  }
+}
@@ -0,0 +1,104 @@
+use std::path::{Path, PathBuf};
+
+use hsm_ipc::LoadTrackErrorKind;
+use serde::Serialize;
+
+/// The result of loading tracks, printed by `--json`
+#[derive(Debug, Serialize)]
+pub struct LoadReport {
+  pub loaded: usize,
+  pub errors: Vec<LoadError>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LoadError {
+  pub path: PathBuf,
+  pub kind: &'static str,
+  pub message: String,
+}
+
+impl LoadError {
+  fn kind_name(kind: &LoadTrackErrorKind) -> &'static str {
+    match kind {
+      LoadTrackErrorKind::NotFound => "not_found",
+      LoadTrackErrorKind::PermissionDenied => "permission_denied",
+      LoadTrackErrorKind::UnsupportedCodec => "unsupported",
+      LoadTrackErrorKind::ProbeFailed => "probe_failed",
+      LoadTrackErrorKind::DecodeFailed => "decode_failed",
+      LoadTrackErrorKind::ReadDirFailed => "read_dir_failed",
+      LoadTrackErrorKind::Other(_) => "unknown",
+    }
+  }
+
+  pub fn new(path: PathBuf, kind: LoadTrackErrorKind) -> Self {
+    Self {
+      path,
+      kind: Self::kind_name(&kind),
+      message: kind.to_string(),
+    }
+  }
+}
+
+/// What the user can do about an error of this kind
+fn remedy(kind: &LoadTrackErrorKind) -> Option<&'static str> {
+  match kind {
+    LoadTrackErrorKind::NotFound => {
+      Some("check that the path is spelled correctly and still exists")
+    }
+    LoadTrackErrorKind::PermissionDenied => Some(
+      "check that the server can read the file, and that it is inside of queue.allowed_roots in the server config",
+    ),
+    LoadTrackErrorKind::UnsupportedCodec => Some(
+      "install a codec feature by building hsm-server with the symphonia feature for this format enabled",
+    ),
+    LoadTrackErrorKind::ProbeFailed => Some("the file may not be audio, or it may be damaged"),
+    LoadTrackErrorKind::DecodeFailed => Some("the file may be damaged or only partly downloaded"),
+    LoadTrackErrorKind::ReadDirFailed => Some("check that the server can read the directory"),
+    LoadTrackErrorKind::Other(_) => None,
+  }
+}
+
+/// Prints each error with a suggestion for fixing it
+pub fn print_load_errors(errors: &[(PathBuf, LoadTrackErrorKind)]) {
+  for (path, kind) in errors {
+    print_load_error(path, kind);
+  }
+}
+
+fn print_load_error(path: &Path, kind: &LoadTrackErrorKind) {
+  match remedy(kind) {
+    Some(remedy) => eprintln!("Failed to load track {path:?}: {kind} ({remedy})"),
+    None => eprintln!("Failed to load track {path:?}: {kind}"),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn reports_error_kinds() {
+    let cases = [
+      (LoadTrackErrorKind::NotFound, "not_found"),
+      (LoadTrackErrorKind::PermissionDenied, "permission_denied"),
+      (LoadTrackErrorKind::UnsupportedCodec, "unsupported"),
+      (LoadTrackErrorKind::ProbeFailed, "probe_failed"),
+      (LoadTrackErrorKind::DecodeFailed, "decode_failed"),
+      (LoadTrackErrorKind::ReadDirFailed, "read_dir_failed"),
+      (LoadTrackErrorKind::Other("Playlist".into()), "unknown"),
+    ];
+
+    for (kind, expected) in cases {
+      let error = LoadError::new("/music/a.flac".into(), kind.clone());
+      assert_eq!(error.kind, expected, "{kind:?}");
+      assert_eq!(error.message, kind.to_string());
+    }
+  }
+
+  #[test]
+  fn only_known_errors_have_remedies() {
+    assert!(remedy(&LoadTrackErrorKind::NotFound).is_some());
+    assert!(remedy(&LoadTrackErrorKind::UnsupportedCodec).is_some());
+    assert_eq!(remedy(&LoadTrackErrorKind::Other("Playlist".into())), None);
+  }
+}
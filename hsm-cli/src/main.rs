@@ -1,14 +1,24 @@
-use std::io;
+use std::{env, error::Error as _, io, process, time::Duration};
 
-use clap::Parser;
+use clap::{CommandFactory, FromArgMatches};
 use thiserror::Error;
 
 use cli::Cli;
 use commands::handle_command;
+use config::{CliConfig, ConfigError};
 
 mod cli;
 mod commands;
+mod config;
+mod doctor;
+mod duration;
 mod ipc;
+mod load_report;
+mod spinner;
+mod waybar;
+
+const PARTIAL_LOAD_EXIT_CODE: i32 = 6;
+const NOTHING_PLAYING_EXIT_CODE: i32 = 7;
 
 #[derive(Debug, Error)]
 pub enum Error {
@@ -18,6 +28,9 @@ pub enum Error {
   #[error("Error communicating with server")]
   StreamReadWrite(#[source] io::Error),
 
+  #[error("The server closed the connection")]
+  Disconnected,
+
   #[error("Failed to get the working directory: {0}")]
   GetCurrentDirFailed(io::Error),
 
@@ -26,9 +39,103 @@ pub enum Error {
 
   #[error("Error: {0}")]
   Server(String),
+
+  #[error(transparent)]
+  Format(#[from] hsm_client::now_playing::FormatError),
+
+  #[error("Failed to load {0} tracks")]
+  LoadFailed(usize),
+
+  #[error("Failed to load {0} tracks, the rest were loaded")]
+  PartialLoad(usize),
+
+  #[error("No track is playing")]
+  NoCurrentTrack,
+
+  #[error("Playback is stopped")]
+  NothingPlaying,
+
+  #[error("The current track's duration is unknown, seek to a time instead")]
+  UnknownDuration,
+
+  #[error("{0} doctor checks failed")]
+  DoctorChecksFailed(usize),
+
+  #[error("The output device only played {:.2}s of the test tone", .0.as_secs_f64())]
+  TestToneFailed(Duration),
+
+  #[error(transparent)]
+  Config(#[from] ConfigError),
 }
+
+impl Error {
+  /// Identifies the error in `--json` output, so scripts don't have to match messages
+  fn kind(&self) -> &'static str {
+    match self {
+      Self::FailedToConnectToSocket { .. } => "not_running",
+      Self::StreamReadWrite(_) => "connection",
+      Self::Disconnected => "disconnected",
+      Self::GetCurrentDirFailed(_) => "io",
+      Self::Deserialize(_) => "protocol",
+      Self::Server(_) => "server",
+      Self::Format(_) => "invalid_format",
+      Self::LoadFailed(_) => "load_failed",
+      Self::PartialLoad(_) => "partial_load",
+      Self::NoCurrentTrack => "no_track",
+      Self::NothingPlaying => "stopped",
+      Self::UnknownDuration => "unknown_duration",
+      Self::DoctorChecksFailed(_) => "doctor_failed",
+      Self::TestToneFailed(_) => "test_tone_failed",
+      Self::Config(_) => "config",
+    }
+  }
+
+  fn exit_code(&self) -> i32 {
+    match self {
+      // Lets scripts tell partial failures apart from requests that failed completely
+      Self::PartialLoad(_) => PARTIAL_LOAD_EXIT_CODE,
+      // Lets status bars hide their module without treating it as a failure
+      Self::NothingPlaying => NOTHING_PLAYING_EXIT_CODE,
+      _ => 1,
+    }
+  }
+
+  fn print_json(&self) {
+    let mut message = self.to_string();
+    let mut source = self.source();
+    while let Some(error) = source {
+      message.push_str(&format!(": {error}"));
+      source = error.source();
+    }
+
+    let error = serde_json::json!({ "error": { "kind": self.kind(), "message": message } });
+    eprintln!("{error}");
+  }
+}
+
 fn main() -> Result<(), crate::Error> {
-  let command = Cli::parse();
+  let config = CliConfig::load()?;
+  let matches = config.apply_defaults(Cli::command())?.get_matches();
+  let command = Cli::from_arg_matches(&matches).unwrap_or_else(|error| error.exit());
+
+  // `--socket` takes precedence over `$HSM_SOCKET_PATH`, which takes precedence over the config file
+  let socket_path = match (&command.socket, env::var_os(hsm_ipc::SOCKET_PATH_VAR)) {
+    (Some(socket_path), _) => Some(socket_path.clone()),
+    (None, None) => config.socket.clone(),
+    (None, Some(_)) => None,
+  };
+
+  if let Some(socket_path) = socket_path {
+    ipc::set_socket_path(socket_path);
+  }
 
-  handle_command(command)
+  let json = command.json;
+  match handle_command(command) {
+    Err(error) if json => {
+      error.print_json();
+      process::exit(error.exit_code());
+    }
+    Err(error @ Error::PartialLoad(_)) => process::exit(error.exit_code()),
+    result => result,
+  }
 }
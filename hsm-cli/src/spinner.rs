@@ -0,0 +1,65 @@
+use std::{
+  io::{self, IsTerminal, Write},
+  sync::{
+    Arc,
+    atomic::{AtomicBool, Ordering},
+  },
+  thread::{self, JoinHandle},
+  time::{Duration, Instant},
+};
+
+/// Shows that a slow request is still running, on stderr so it doesn't mix with output
+pub struct Spinner {
+  done: Arc<AtomicBool>,
+  thread: Option<JoinHandle<()>>,
+  started: Instant,
+}
+
+impl Spinner {
+  const FRAMES: &[char] = &['|', '/', '-', '\\'];
+  const INTERVAL: Duration = Duration::from_millis(100);
+
+  /// Nothing is drawn if stderr is not a terminal
+  pub fn start(message: &'static str) -> Self {
+    let done = Arc::new(AtomicBool::new(false));
+    let started = Instant::now();
+
+    let thread = io::stderr().is_terminal().then(|| {
+      let done = done.clone();
+
+      thread::spawn(move || {
+        for frame in Self::FRAMES.iter().cycle() {
+          if done.load(Ordering::Acquire) {
+            break;
+          }
+
+          let elapsed = started.elapsed().as_secs_f32();
+          eprint!("\r{frame} {message} ({elapsed:.1}s)");
+          let _ = io::stderr().flush();
+          thread::sleep(Self::INTERVAL);
+        }
+
+        // Clear the spinner line
+        eprint!("\r\x1b[2K");
+      })
+    });
+
+    Self {
+      done,
+      thread,
+      started,
+    }
+  }
+
+  pub fn elapsed(&self) -> Duration {
+    self.started.elapsed()
+  }
+
+  pub fn stop(mut self) {
+    self.done.store(true, Ordering::Release);
+
+    if let Some(thread) = self.thread.take() {
+      let _ = thread.join();
+    }
+  }
+}
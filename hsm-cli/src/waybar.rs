@@ -0,0 +1,210 @@
+use std::{
+  io::{self, Write},
+  thread,
+  time::Duration,
+};
+
+use hsm_client::now_playing::NowPlaying;
+use hsm_ipc::{PlaybackState, Track, requests};
+use serde::Serialize;
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::ipc::send_request;
+
+/// The JSON format read by waybar's `custom` module
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct WaybarOutput {
+  pub text: String,
+  pub tooltip: String,
+  pub class: String,
+  pub alt: String,
+}
+
+/// Shortens `text` to at most `max_length` graphemes, ending with an ellipsis if anything was removed
+pub fn truncate(text: &str, max_length: usize) -> String {
+  if text.graphemes(true).count() <= max_length {
+    return text.into();
+  }
+
+  let mut truncated: String = text
+    .graphemes(true)
+    .take(max_length.saturating_sub(1))
+    .collect();
+  truncated.truncate(truncated.trim_end().len());
+  truncated.push('…');
+  truncated
+}
+
+fn tooltip(track: &Track, index: usize, len: usize) -> String {
+  let metadata = &track.metadata;
+  let mut lines = Vec::new();
+
+  match metadata.title_or_inferred() {
+    Some(title) => lines.push(title.to_owned()),
+    None => lines.push(track.file_path.to_string_lossy().into_owned()),
+  }
+
+  let artists = metadata.artists_or_inferred();
+  if !artists.is_empty() {
+    lines.push(format!("Artist: {}", artists.join(", ")));
+  }
+
+  if let Some(album) = metadata.album_or_inferred() {
+    lines.push(format!("Album: {album}"));
+  }
+
+  lines.push(format!("Track {} of {len}", index + 1));
+  lines.join("\n")
+}
+
+pub fn build_output(
+  now_playing: NowPlaying,
+  index: usize,
+  len: usize,
+  format: &str,
+  max_length: usize,
+) -> Result<WaybarOutput, crate::Error> {
+  let class = match now_playing.playback_state {
+    PlaybackState::Playing => "playing",
+    PlaybackState::Paused => "paused",
+    PlaybackState::Stopped => "stopped",
+  };
+
+  let (text, tooltip) = match now_playing.track {
+    Some(track) if now_playing.playback_state != PlaybackState::Stopped => (
+      truncate(&now_playing.format(format)?, max_length),
+      tooltip(track, index, len),
+    ),
+    _ => (String::new(), String::new()),
+  };
+
+  Ok(WaybarOutput {
+    text,
+    tooltip,
+    class: class.into(),
+    alt: class.into(),
+  })
+}
+
+fn query_output(format: &str, max_length: usize) -> Result<WaybarOutput, crate::Error> {
+  let track = send_request(requests::QueryCurrentTrack)?;
+  let playback_state = send_request(requests::QueryPlaybackState)?;
+  let index = send_request(requests::QueryCurrentTrackIndex)?;
+  let len = send_request(requests::QueryQueueSummary)?.len;
+
+  build_output(
+    NowPlaying::new(track.as_ref(), playback_state),
+    index,
+    len,
+    format,
+    max_length,
+  )
+}
+
+fn print_output(output: &WaybarOutput) {
+  let line = serde_json::to_string(output).expect("Waybar output should not fail to serialize");
+  println!("{line}");
+  let _ = io::stdout().flush();
+}
+
+/// Prints the waybar JSON once, or a new line every time it changes if `follow` is set
+pub fn run(format: &str, max_length: usize, follow: bool) -> Result<(), crate::Error> {
+  const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+  NowPlaying::validate_format(format)?;
+
+  if !follow {
+    print_output(&query_output(format, max_length)?);
+    return Ok(());
+  }
+
+  let mut last_output = None;
+  loop {
+    // Keep running while the server is down, so waybar doesn't need to restart the module
+    let output = query_output(format, max_length).unwrap_or_else(|_| WaybarOutput {
+      text: String::new(),
+      tooltip: String::new(),
+      class: "disconnected".into(),
+      alt: "disconnected".into(),
+    });
+
+    if last_output.as_ref() != Some(&output) {
+      print_output(&output);
+      last_output = Some(output);
+    }
+
+    thread::sleep(POLL_INTERVAL);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use hsm_ipc::TrackMetadata;
+
+  use super::*;
+
+  fn track() -> Track {
+    Track {
+      file_path: "/music/song.flac".into(),
+      total_duration: None,
+      metadata: TrackMetadata {
+        title: Some("Song".into()),
+        artists: vec!["Artist".into()],
+        ..Default::default()
+      },
+    }
+  }
+
+  #[test]
+  fn output_has_waybar_keys() {
+    let track = track();
+    let now_playing = NowPlaying::new(Some(&track), PlaybackState::Paused);
+    let output = build_output(now_playing, 1, 3, "{artist} - {title}", 30).unwrap();
+
+    let json = serde_json::to_value(&output).unwrap();
+    let expected = serde_json::json!({
+      "text": "Artist - Song",
+      "tooltip": "Song\nArtist: Artist\nTrack 2 of 3",
+      "class": "paused",
+      "alt": "paused",
+    });
+    assert_eq!(json, expected);
+  }
+
+  #[test]
+  fn stopped_output_is_empty() {
+    let track = track();
+    let now_playing = NowPlaying::new(Some(&track), PlaybackState::Stopped);
+    let output = build_output(now_playing, 0, 1, "{title}", 30).unwrap();
+
+    assert_eq!(output.text, "");
+    assert_eq!(output.tooltip, "");
+    assert_eq!(output.class, "stopped");
+  }
+
+  #[test]
+  fn truncates_graphemes() {
+    let cases = [
+      ("short", 10, "short"),
+      ("exactly", 7, "exactly"),
+      ("truncated", 5, "trun…"),
+      // Whitespace before the ellipsis is removed
+      ("two words", 5, "two…"),
+      // Combining accents stay with their letter
+      ("e\u{301}e\u{301}e\u{301}e\u{301}", 3, "e\u{301}e\u{301}…"),
+      ("e\u{301}e\u{301}", 2, "e\u{301}e\u{301}"),
+      // Emoji joined into one grapheme are not split
+      (
+        "👨\u{200d}👩\u{200d}👧👨\u{200d}👩\u{200d}👧👍",
+        2,
+        "👨\u{200d}👩\u{200d}👧…",
+      ),
+      ("🇫🇷🇩🇪🇯🇵", 2, "🇫🇷…"),
+      ("👍🏽👍🏽", 2, "👍🏽👍🏽"),
+    ];
+
+    for (text, max_length, expected) in cases {
+      assert_eq!(truncate(text, max_length), expected, "{text:?}");
+    }
+  }
+}
@@ -1,19 +1,48 @@
-use std::{error::Error, fmt};
+use std::{
+  collections::HashMap,
+  error::Error,
+  fmt, mem,
+  path::{Path, PathBuf},
+  sync::Arc,
+  time::{Duration, Instant},
+};
 
-use super::plugin_manager::RequestJson;
+use super::{
+  config::Config,
+  plugin_manager::{PluginRegistry, RequestJson},
+};
+use async_oneshot as oneshot;
+use blocking::BlockingScheduler;
+use coalesce::RequestCoalescer;
+use connections::ConnectionList;
 use futures_concurrency::future::Race;
-use hsm_ipc::Event;
-use rodio::OutputStream;
-use smol::channel::{Receiver, Sender};
+use hsm_ipc::{Event, OutputInfo, ServerStats, TestToneResult, server::RequestTimings};
+use hsm_plugin::SharedPlayerState;
+use lyrics_timer::LyricsTimer;
+use output_stream::{AudioOutput, StreamWatchdog};
+use persist::FlushScheduler;
+use smol::{
+  Timer,
+  channel::{self, Receiver, Sender},
+  lock::Mutex,
+};
 
-use player::Player;
+use player::{Player, StallWatchdog};
+use saved_state::{RestoredOrder, SavedState};
 
+mod blocking;
+mod coalesce;
+mod connections;
+mod lyrics_timer;
+mod output_stream;
+mod persist;
 mod player;
 mod request_handler;
+mod saved_state;
 mod track;
 
 use thiserror::Error;
-use track::TrackCache;
+use track::{LoadedTrack, ScanOptions, TrackCache};
 
 #[derive(Debug, Error)]
 pub enum AudioServerError {
@@ -23,58 +52,192 @@ pub enum AudioServerError {
   #[error(transparent)]
   PlayerError(#[from] player::PlayerError),
 
+  #[error("Could not load track {0:?}: {1}")]
+  LoadTrackFailed(PathBuf, track::LoadTrackError),
+
+  #[error(transparent)]
+  ScanFailed(#[from] track::ScanError),
+
+  #[error("{0:?} does not contain exactly one track")]
+  NotASingleTrack(PathBuf),
+
+  #[error("No plugin named {0:?}")]
+  UnknownPlugin(String),
+
+  #[error("No removed tracks to restore")]
+  NothingToRestore,
+
+  #[error("Subscribe can only be sent over the ipc socket")]
+  SubscribeWithoutConnection,
+
   #[error(transparent)]
   PluginError(Box<dyn Error>),
+
+  #[error("The player has not handled the end of a track for {}s, exiting so the server can be restarted", .0.as_secs())]
+  PlayerStalled(Duration),
 }
 
 impl AudioServerError {
   pub fn is_recoverable(&self) -> bool {
     match self {
       AudioServerError::PlayerError(error) => error.is_recoverable(),
+      AudioServerError::LoadTrackFailed(..) => true,
+      AudioServerError::UnknownPlugin(_) => true,
+      AudioServerError::NothingToRestore => true,
+      AudioServerError::SubscribeWithoutConnection => true,
+      AudioServerError::ScanFailed(_) => true,
+      AudioServerError::NotASingleTrack(_) => true,
       _ => false,
     }
   }
 }
 
 pub struct AudioServer {
-  #[allow(dead_code)]
-  output_stream: OutputStream,
+  output: Mutex<AudioOutput>,
+  output_errors: Receiver<String>,
   player: Player,
+  coalescer: RequestCoalescer,
+  lyrics_timer: LyricsTimer,
   /// Mapping from cannonical path to track
   track_cache: TrackCache,
+  scheduler: Arc<BlockingScheduler>,
+  plugins: Arc<PluginRegistry>,
+  connections: ConnectionList,
+  request_timings: RequestTimings,
+  shutdown_tx: Sender<()>,
+  shutdown_rx: Receiver<()>,
 
   request_data_rx: Receiver<RequestJson>,
+  /// Set by handlers whose change is applied after they return, the reply waits for it to resolve
+  reply_after: Mutex<Option<oneshot::Receiver<()>>>,
+  deferred_reply_tx: Sender<DeferredReply>,
+  deferred_reply_rx: Receiver<DeferredReply>,
+  /// The state that was last saved or restored, so an unchanged state isn't written again
+  last_saved_state: Mutex<Option<SavedState>>,
+  state_flush: FlushScheduler,
+}
+
+/// A reply held back until the change its request caused has been applied and its event emitted
+struct DeferredReply {
+  applied: oneshot::Receiver<()>,
+  reply_tx: oneshot::Sender<String>,
+  reply_data: String,
 }
 
 impl AudioServer {
-  pub fn init((request_data_rx, event_tx): (Receiver<RequestJson>, Sender<Event>)) -> Self {
-    let output_stream = rodio::OutputStreamBuilder::open_default_stream()
-      .expect("Could not open default audio stream");
+  pub fn init(
+    (request_data_rx, event_tx): (Receiver<RequestJson>, Sender<Event>),
+    shared_state: Arc<SharedPlayerState>,
+    plugins: Arc<PluginRegistry>,
+    config: &Config,
+  ) -> Self {
+    let output = AudioOutput::open_default().expect("Could not open default audio stream");
+    let scheduler = Arc::new(BlockingScheduler::new());
+
+    let (shutdown_tx, shutdown_rx) = channel::bounded(1);
+    let (deferred_reply_tx, deferred_reply_rx) = channel::unbounded();
+    let player = Player::connect_new(event_tx, output.stream(), scheduler.clone(), shared_state);
+    player.set_track_ending_notice(
+      Duration::try_from_secs_f64(config.player.track_ending_notice).unwrap_or(Duration::ZERO),
+    );
+    player.set_decode_ahead(
+      Duration::try_from_secs_f64(config.player.decode_ahead).unwrap_or(Duration::ZERO),
+    );
+    player.set_high_quality_resampling(config.player.high_quality_resampling);
+    player.set_normalization(config.player.normalization);
+    player.set_normalization_fallback_gain(config.player.normalization_fallback_gain);
+    player.set_volume_backend(config.player.volume_backend);
+    player.set_removed_current(config.player.removed_current);
+    player.set_gapless_policy(config.player.gapless);
+    player.set_max_queue_length(config.queue.max_length);
 
     Self {
-      player: Player::connect_new(event_tx, output_stream.mixer()),
-      track_cache: TrackCache::new(),
-      output_stream,
+      player,
+      coalescer: RequestCoalescer::new(),
+      lyrics_timer: LyricsTimer::new(),
+      track_cache: TrackCache::new(scheduler.clone(), config.tags.clone(), config.queue.clone()),
+      scheduler,
+      plugins,
+      connections: ConnectionList::new(),
+      request_timings: RequestTimings::new(
+        Duration::try_from_secs_f64(config.ipc.slow_request_threshold).unwrap_or(Duration::ZERO),
+      ),
+      shutdown_tx,
+      shutdown_rx,
+      output_errors: output.errors(),
+      output: Mutex::new(output),
 
       request_data_rx,
+      reply_after: Mutex::new(None),
+      deferred_reply_tx,
+      deferred_reply_rx,
+      last_saved_state: Mutex::new(None),
+      state_flush: FlushScheduler::new(),
+    }
+  }
+
+  /// Holds back the reply to the request being handled until `applied` resolves
+  ///
+  /// Replies are only sent after the events for the changes their request made,
+  /// so handlers that leave a change to be applied later must call this
+  async fn reply_after(&self, applied: oneshot::Receiver<()>) {
+    *self.reply_after.lock().await = Some(applied);
+  }
+
+  async fn send_reply(&self, mut reply_tx: oneshot::Sender<String>, reply_data: String) {
+    match self.reply_after.lock().await.take() {
+      Some(applied) => {
+        // The receiver is held by the audio server, so the channel can't close
+        let _ = self.deferred_reply_tx.try_send(DeferredReply {
+          applied,
+          reply_tx,
+          reply_data,
+        });
+      }
+      None => {
+        let _ = reply_tx.send(reply_data);
+      }
+    }
+  }
+
+  async fn send_deferred_replies(&self) -> Result<(), AudioServerError> {
+    loop {
+      let DeferredReply {
+        applied,
+        mut reply_tx,
+        reply_data,
+      } = self
+        .deferred_reply_rx
+        .recv()
+        .await
+        .map_err(|_| AudioServerError::MessageChannelClosed)?;
+
+      // The sender is only dropped if the coalescer stopped, which also stops the server
+      let _ = applied.await;
+      let _ = reply_tx.send(reply_data);
     }
   }
 
   async fn handle_requests(&self) -> Result<(), AudioServerError> {
     loop {
-      let (request_data, mut reply_tx) = self
+      let (request_data, origin, reply_tx) = self
         .request_data_rx
         .recv()
         .await
         .map_err(|_| AudioServerError::MessageChannelClosed)?;
 
-      match hsm_ipc::server::handle_request(&request_data, self).await {
-        Ok(reply_data) => {
-          let _ = reply_tx.send(reply_data);
-        }
+      self.connections.track(&origin).await;
+      let result =
+        hsm_ipc::server::handle_request(&request_data, &origin, self, &self.request_timings).await;
+      // Any request could have started playback or seeked
+      self.lyrics_timer.wake();
+      self.state_flush.request_flush();
+
+      match result {
+        Ok(reply_data) => self.send_reply(reply_tx, reply_data).await,
 
         Err((reply_data, error)) => {
-          let _ = reply_tx.send(reply_data);
+          self.send_reply(reply_tx, reply_data).await;
 
           if error.is_recoverable() {
             eprintln!("{error}");
@@ -86,6 +249,300 @@ impl AudioServer {
     }
   }
 
+  fn request_shutdown(&self) {
+    // If the channel is full, shutdown was already requested
+    let _ = self.shutdown_tx.try_send(());
+  }
+
+  /// Waits for a `Shutdown` request
+  pub async fn wait_for_shutdown(&self) {
+    // The audio server holds the sender, so the channel can't close
+    let _ = self.shutdown_rx.recv().await;
+  }
+
+  /// Loads the track at `path` through the cache without queueing it
+  ///
+  /// `None` if `path` is a directory that does not contain exactly one track
+  async fn load_single_track(
+    &self,
+    path: PathBuf,
+  ) -> Result<Option<Arc<LoadedTrack>>, AudioServerError> {
+    // Only the scan limit applies, since nothing is added to the queue
+    let (mut tracks, mut errors) = self
+      .track_cache
+      .get_or_load_tracks(vec![path], ScanOptions { force: true }, &mut |_, _, _| ())
+      .await?;
+    if let Some((path, error)) = errors.pop() {
+      return Err(AudioServerError::LoadTrackFailed(path, error));
+    }
+
+    match tracks.as_slice() {
+      [_] => Ok(tracks.pop()),
+      _ => Ok(None),
+    }
+  }
+
+  fn stats(&self) -> ServerStats {
+    ServerStats {
+      blocking: self.scheduler.stats(),
+      requests: self.request_timings.snapshot(),
+    }
+  }
+
+  async fn output_info(&self) -> OutputInfo {
+    self.output.lock().await.info(self.player.bit_perfect())
+  }
+
+  async fn set_bit_perfect(&self, bit_perfect: bool) -> Result<(), AudioServerError> {
+    if self.player.set_bit_perfect(bit_perfect) != bit_perfect {
+      let output_info = self.output_info().await;
+      self.player.emit(Event::OutputFormatChanged(output_info))?;
+    }
+
+    Ok(())
+  }
+
+  /// Reopens the output stream when the player requests a new sample rate in bit perfect mode
+  async fn handle_output_rate_requests(&self) -> Result<(), AudioServerError> {
+    loop {
+      let sample_rate = self.player.recieve_output_rate_request().await?;
+      let mut output = self.output.lock().await;
+
+      if output.sample_rate() == sample_rate {
+        continue;
+      }
+
+      if !output.can_reopen() {
+        println!("Output stream was reopened recently, resampling track to {sample_rate}Hz");
+        self.player.decline_output_rate(sample_rate);
+        continue;
+      }
+
+      match output.reopen_with_sample_rate(sample_rate, &self.player) {
+        Ok(()) => {
+          println!("Reopened output stream at {sample_rate}Hz");
+          let output_info = output.info(self.player.bit_perfect());
+          self.player.emit(Event::OutputFormatChanged(output_info))?;
+        }
+        Err(error) => {
+          eprintln!(
+            "Warning: Could not open output stream at {sample_rate}Hz, resampling instead: {error}"
+          );
+          self.player.decline_output_rate(sample_rate);
+        }
+      }
+    }
+  }
+
+  /// Restores the queue and settings saved before the last shutdown
+  ///
+  /// Tracks that can no longer be loaded are skipped, and playback is never started
+  pub async fn restore_saved_state(&self) -> Result<(), AudioServerError> {
+    let state = match SavedState::load().await {
+      Ok(Some(state)) => state,
+      Ok(None) => return Ok(()),
+      Err(error) => {
+        eprintln!("Warning: Not restoring saved state: {error}");
+        return Ok(());
+      }
+    };
+
+    // The system mixer may not be reachable yet, which shouldn't stop the queue from being restored
+    if let Err(error) = self.player.set_volume(state.volume).await {
+      eprintln!("Warning: Could not restore the volume: {error}");
+    }
+    self.player.set_loop_mode(state.loop_mode).await?;
+
+    if !state.tracks.is_empty() {
+      self.restore_saved_tracks(&state).await?;
+    }
+
+    println!("Restored saved state");
+    *self.last_saved_state.lock().await = Some(self.player.saved_state().await);
+    Ok(())
+  }
+
+  async fn restore_saved_tracks(&self, state: &SavedState) -> Result<(), AudioServerError> {
+    println!("Restoring {} saved tracks", state.tracks.len());
+    let (tracks, errors) = self
+      .track_cache
+      .get_or_load_tracks(
+        state.tracks.clone(),
+        ScanOptions { force: true },
+        &mut |_, _, _| (),
+      )
+      .await?;
+
+    for (path, error) in errors.iter() {
+      eprintln!("Could not restore track {path:?}: {error}")
+    }
+
+    // Saved paths are the tracks' canonical paths, which the cache keys them by
+    let loaded: HashMap<&Path, &Arc<LoadedTrack>> = tracks
+      .iter()
+      .map(|track| (track.file_path(), track))
+      .collect();
+
+    // The new index of every saved track that could be loaded
+    let mut new_indicies = Vec::with_capacity(state.tracks.len());
+    let mut restored = Vec::with_capacity(tracks.len());
+    for path in &state.tracks {
+      let track = loaded.get(path.as_path());
+      new_indicies.push(track.map(|_| restored.len()));
+      restored.extend(track.map(|&track| track.clone()));
+    }
+
+    let Some(RestoredOrder {
+      play_order,
+      current_index,
+      current_restored,
+    }) = state.restored_order(&new_indicies)
+    else {
+      return Ok(());
+    };
+
+    self
+      .player
+      .restore_track_list(&restored, play_order, state.shuffle, current_index)
+      .await?;
+
+    if !state.stopped {
+      let position = if current_restored {
+        state.position
+      } else {
+        Duration::ZERO
+      };
+      self.player.restore_paused_at(position).await?;
+    }
+
+    Ok(())
+  }
+
+  /// Saves the queue and settings if they changed since they were last saved
+  pub async fn save_state(&self) {
+    let state = self.player.saved_state().await;
+    let mut last_saved_state = self.last_saved_state.lock().await;
+    if last_saved_state.as_ref() == Some(&state) {
+      return;
+    }
+
+    match state.save().await {
+      Ok(()) => *last_saved_state = Some(state),
+      Err(error) => eprintln!("Warning: {error}"),
+    }
+  }
+
+  /// Plays a sine wave through the player, see `Player::play_test_tone`
+  pub async fn play_test_tone(
+    &self,
+    frequency: f32,
+    duration: Duration,
+  ) -> Result<TestToneResult, AudioServerError> {
+    Ok(self.player.play_test_tone(frequency, duration).await?)
+  }
+
+  /// Deletes the saved state, it is written again once the queue or settings change
+  pub async fn forget_saved_state(&self) {
+    let mut last_saved_state = self.last_saved_state.lock().await;
+    match SavedState::remove().await {
+      Ok(()) => *last_saved_state = Some(self.player.saved_state().await),
+      Err(error) => eprintln!("Warning: {error}"),
+    }
+  }
+
+  /// Saves the state shortly after a burst of requests, and at least every `MAX_SAVE_INTERVAL` for position changes
+  async fn save_state_when_changed(&self) -> Result<(), AudioServerError> {
+    const SAVE_DEBOUNCE: Duration = Duration::from_secs(2);
+    const MAX_SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+    self
+      .state_flush
+      .run(SAVE_DEBOUNCE, MAX_SAVE_INTERVAL, || self.save_state())
+      .await;
+    Ok(())
+  }
+
+  /// Reconnects the output stream if it reports an error or stops pulling samples, such as when the sound server restarts
+  async fn watch_output_stream(&self) -> Result<(), AudioServerError> {
+    let mut watchdog = StreamWatchdog::new(self.player.samples_pulled(), Instant::now());
+
+    loop {
+      let stream_error = (async { self.output_errors.recv().await.ok() }, async {
+        Timer::after(StreamWatchdog::CHECK_INTERVAL).await;
+        None
+      })
+        .race()
+        .await;
+
+      let should_reconnect = match stream_error {
+        Some(error) => {
+          eprintln!("Output stream error: {error}");
+          watchdog.stream_failed()
+        }
+        None => {
+          let should_reconnect = watchdog.check(self.player.samples_pulled(), Instant::now());
+          if should_reconnect {
+            eprintln!("Output stream stopped playing");
+          }
+          should_reconnect
+        }
+      };
+
+      if should_reconnect {
+        self.reconnect_output(&mut watchdog).await?;
+      }
+    }
+  }
+
+  /// Ends the server if `Player::run` stops taking source events, which means tracks no longer advance
+  ///
+  /// The player can't be restarted while its loop is stuck holding its locks,
+  /// so the server exits with an error for a supervisor such as systemd to restart it
+  async fn watch_player(&self) -> Result<(), AudioServerError> {
+    let (events_taken, _) = self.player.source_event_progress();
+    let mut watchdog = StallWatchdog::new(events_taken);
+
+    loop {
+      Timer::after(StallWatchdog::CHECK_INTERVAL).await;
+
+      let (events_taken, events_waiting) = self.player.source_event_progress();
+      let Some(waited) = watchdog.check(events_taken, events_waiting, Instant::now()) else {
+        continue;
+      };
+
+      eprintln!("Error: The player stopped handling source events, {events_waiting} are waiting");
+      // Plugins are given a moment to deliver the event before the server exits
+      let _ = self.player.emit(Event::PlayerStalled { waited });
+      Timer::after(Duration::from_millis(500)).await;
+      return Err(AudioServerError::PlayerStalled(waited));
+    }
+  }
+
+  async fn reconnect_output(&self, watchdog: &mut StreamWatchdog) -> Result<(), AudioServerError> {
+    let mut output = self.output.lock().await;
+    let position = self.player.detach_sources().await;
+
+    if let Err(error) = output.reconnect(&self.player) {
+      let delay = watchdog.reconnect_failed(Instant::now());
+      eprintln!(
+        "Warning: Could not reconnect output stream, retrying in {}s: {error}",
+        delay.as_secs()
+      );
+      return Ok(());
+    }
+
+    watchdog.reconnected(self.player.samples_pulled(), Instant::now());
+    println!("Reconnected output stream");
+    let output_info = output.info(self.player.bit_perfect());
+    // Bit perfect rate changes wait for the lock, and would find the old stream's sample rate otherwise
+    mem::drop(output);
+
+    self.player.emit(Event::OutputReconnected(output_info))?;
+    self.player.resume_at(position).await?;
+
+    Ok(())
+  }
+
   pub async fn run(&self) -> Result<(), AudioServerError> {
     (
       async {
@@ -95,7 +552,36 @@ impl AudioServer {
           .await
           .map_err(AudioServerError::PlayerError)
       },
+      async {
+        self
+          .coalescer
+          .run(&self.player)
+          .await
+          .map_err(AudioServerError::PlayerError)
+      },
+      async {
+        self
+          .lyrics_timer
+          .run(&self.player)
+          .await
+          .map_err(AudioServerError::PlayerError)
+      },
       self.handle_requests(),
+      self.send_deferred_replies(),
+      self.handle_output_rate_requests(),
+      // Race only takes up to 8 futures
+      async {
+        (self.watch_output_stream(), self.watch_player(), async {
+          self
+            .player
+            .sync_system_volume()
+            .await
+            .map_err(AudioServerError::PlayerError)
+        })
+          .race()
+          .await
+      },
+      self.save_state_when_changed(),
     )
       .race()
       .await
@@ -105,10 +591,36 @@ impl AudioServer {
 impl fmt::Debug for AudioServer {
   fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
     f.debug_struct("AudioServer")
-      .field("output_stream", &"OutputStream")
+      .field("output", &"AudioOutput")
       .field("player", &self.player)
+      .field("coalescer", &self.coalescer)
+      .field("lyrics_timer", &self.lyrics_timer)
       .field("track_cache", &self.track_cache)
+      .field("scheduler", &self.scheduler)
+      .field("plugins", &self.plugins)
       .field("request_data_rx", &self.request_data_rx)
+      .field("deferred_reply_rx", &self.deferred_reply_rx)
       .finish()
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use hsm_ipc::{client::deserialize_reply, requests};
+
+  use super::*;
+
+  #[test]
+  fn subscribe_from_a_plugin_is_a_recoverable_error() {
+    // Plugins have no connection to stream events on, so the request handler rejects `Subscribe` from them
+    let error = AudioServerError::SubscribeWithoutConnection;
+    assert!(error.is_recoverable());
+
+    let reply_data = hsm_ipc::server::serialize_error(&error);
+    let reply = deserialize_reply::<requests::Subscribe>(&reply_data).unwrap();
+    assert_eq!(
+      reply,
+      Err("Subscribe can only be sent over the ipc socket".into())
+    );
+  }
+}
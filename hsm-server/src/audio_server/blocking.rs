@@ -0,0 +1,98 @@
+use std::{
+  sync::atomic::{AtomicUsize, Ordering},
+  thread,
+};
+
+use hsm_ipc::BlockingStats;
+use smol::lock::Semaphore;
+
+/// Which lane a blocking task should be scheduled in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lane {
+  /// Large batches of work such as scanning directories, limited to a few tasks at a time
+  Bulk,
+  /// Work that playback is waiting on, such as creating decoders. Never waits for bulk work
+  Interactive,
+}
+
+/// Schedules synchronous work onto the blocking thread pool
+///
+/// Bulk tasks are limited by a semaphore so a large scan can't starve the pool
+/// and delay the interactive tasks needed for playback.
+#[derive(Debug)]
+pub struct BlockingScheduler {
+  bulk_limit: usize,
+  bulk_permits: Semaphore,
+  bulk_queued: AtomicUsize,
+  bulk_running: AtomicUsize,
+  interactive_running: AtomicUsize,
+}
+
+impl BlockingScheduler {
+  pub fn new() -> Self {
+    let bulk_limit = thread::available_parallelism()
+      .map(|parallelism| parallelism.get())
+      .unwrap_or(4);
+
+    Self {
+      bulk_limit,
+      bulk_permits: Semaphore::new(bulk_limit),
+      bulk_queued: AtomicUsize::new(0),
+      bulk_running: AtomicUsize::new(0),
+      interactive_running: AtomicUsize::new(0),
+    }
+  }
+
+  /// How many bulk tasks can run at the same time
+  pub fn bulk_limit(&self) -> usize {
+    self.bulk_limit
+  }
+
+  /// Runs `f` on the blocking thread pool in the specified `lane`
+  pub async fn unblock<T: Send + 'static>(
+    &self,
+    lane: Lane,
+    f: impl FnOnce() -> T + Send + 'static,
+  ) -> T {
+    match lane {
+      Lane::Bulk => {
+        let _permit = {
+          let _queued = Counted::new(&self.bulk_queued);
+          self.bulk_permits.acquire().await
+        };
+
+        let _running = Counted::new(&self.bulk_running);
+        smol::unblock(f).await
+      }
+
+      Lane::Interactive => {
+        let _running = Counted::new(&self.interactive_running);
+        smol::unblock(f).await
+      }
+    }
+  }
+
+  pub fn stats(&self) -> BlockingStats {
+    BlockingStats {
+      bulk_queued: self.bulk_queued.load(Ordering::Relaxed),
+      bulk_running: self.bulk_running.load(Ordering::Relaxed),
+      interactive_running: self.interactive_running.load(Ordering::Relaxed),
+    }
+  }
+}
+
+/// Increments a counter for as long as it is alive, so cancelled tasks are still accounted for
+struct Counted<'a>(&'a AtomicUsize);
+
+impl<'a> Counted<'a> {
+  fn new(counter: &'a AtomicUsize) -> Self {
+    counter.fetch_add(1, Ordering::Relaxed);
+    Self(counter)
+  }
+}
+
+impl Drop for Counted<'_> {
+  fn drop(&mut self) {
+    self.0.fetch_sub(1, Ordering::Relaxed);
+  }
+}
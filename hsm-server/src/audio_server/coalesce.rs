@@ -0,0 +1,477 @@
+use std::{mem, time::Duration};
+
+use async_oneshot as oneshot;
+use hsm_ipc::SeekPosition;
+use smol::{
+  channel::{self, Receiver, Sender},
+  lock::Mutex,
+};
+
+use super::player::{Player, PlayerError};
+
+/// The player operations performed by the coalescer
+pub trait CoalescedPlayer {
+  async fn volume(&self) -> f32;
+  async fn set_volume(&self, volume: f32) -> Result<(), PlayerError>;
+  async fn seek(&self, seek_position: SeekPosition) -> Result<(), PlayerError>;
+  async fn skip_to_next_track(&self, count: usize) -> Result<(), PlayerError>;
+}
+
+impl CoalescedPlayer for Player {
+  async fn volume(&self) -> f32 {
+    self.volume().await
+  }
+
+  async fn set_volume(&self, volume: f32) -> Result<(), PlayerError> {
+    self.set_volume(volume).await
+  }
+
+  async fn seek(&self, seek_position: SeekPosition) -> Result<(), PlayerError> {
+    self.seek(seek_position).await
+  }
+
+  async fn skip_to_next_track(&self, count: usize) -> Result<(), PlayerError> {
+    self.skip_to_next_track(count).await
+  }
+}
+
+/// Collapses bursts of volume, relative seek and skip requests, such as those sent by a held key binding,
+/// into fewer operations on the player
+///
+/// Volume requests are replaced by the latest value and applied at most every `VOLUME_INTERVAL`.
+/// Relative seeks and skips are summed into a single operation each time the player finishes the previous one.
+///
+/// Each request gets a receiver that resolves once the value it was merged into is applied,
+/// so its reply can be held back until the event for the change has been emitted.
+#[derive(Debug)]
+pub struct RequestCoalescer {
+  volume: Mutex<Option<f32>>,
+  /// Net relative seek in seconds, negative values seek backward
+  seek_offset: Mutex<Option<f64>>,
+  /// Tracks to skip forward
+  skip_count: Mutex<usize>,
+  /// Requests waiting for the pending volume, seek or skip to be applied
+  volume_waiters: Mutex<Vec<oneshot::Sender<()>>>,
+  seek_waiters: Mutex<Vec<oneshot::Sender<()>>>,
+  skip_waiters: Mutex<Vec<oneshot::Sender<()>>>,
+
+  wake_tx: Sender<()>,
+  wake_rx: Receiver<()>,
+}
+
+impl RequestCoalescer {
+  /// Limits volume changes to 20 per second
+  const VOLUME_INTERVAL: Duration = Duration::from_millis(50);
+
+  pub fn new() -> Self {
+    let (wake_tx, wake_rx) = channel::bounded(1);
+
+    Self {
+      volume: Mutex::new(None),
+      seek_offset: Mutex::new(None),
+      skip_count: Mutex::new(0),
+      volume_waiters: Mutex::new(Vec::new()),
+      seek_waiters: Mutex::new(Vec::new()),
+      skip_waiters: Mutex::new(Vec::new()),
+
+      wake_tx,
+      wake_rx,
+    }
+  }
+
+  fn wake(&self) {
+    // If the channel is full, the coalescer will already apply the pending requests
+    let _ = self.wake_tx.try_send(());
+  }
+
+  async fn wait_for(waiters: &Mutex<Vec<oneshot::Sender<()>>>) -> oneshot::Receiver<()> {
+    let (tx, rx) = oneshot::oneshot();
+    waiters.lock().await.push(tx);
+    rx
+  }
+
+  fn notify(waiters: &mut Vec<oneshot::Sender<()>>) {
+    for mut waiter in mem::take(waiters) {
+      let _ = waiter.send(());
+    }
+  }
+
+  /// The volume that will be applied next, if any
+  pub async fn pending_volume(&self) -> Option<f32> {
+    *self.volume.lock().await
+  }
+
+  /// The receiver resolves once `volume`, or a volume that replaced it, has been applied
+  pub async fn set_volume(&self, volume: f32) -> oneshot::Receiver<()> {
+    let applied = {
+      let mut pending_volume = self.volume.lock().await;
+      *pending_volume = Some(volume);
+      Self::wait_for(&self.volume_waiters).await
+    };
+
+    self.wake();
+    applied
+  }
+
+  /// Adjusts the pending volume, or the player's volume if there is none
+  pub async fn adjust_volume(
+    &self,
+    delta: f32,
+    player: &impl CoalescedPlayer,
+  ) -> oneshot::Receiver<()> {
+    let mut pending_volume = self.volume.lock().await;
+    let volume = match *pending_volume {
+      Some(volume) => volume,
+      None => player.volume().await,
+    };
+
+    *pending_volume = Some((volume + delta).clamp(0.0, 1.0));
+    let applied = Self::wait_for(&self.volume_waiters).await;
+    mem::drop(pending_volume);
+
+    self.wake();
+    applied
+  }
+
+  /// Adds a relative seek to the pending offset
+  ///
+  /// Absolute seeks should not be coalesced, call `cancel_seek` before performing them instead
+  ///
+  /// The receiver resolves once the summed seek has been performed
+  pub async fn seek(&self, seek_position: SeekPosition) -> oneshot::Receiver<()> {
+    let offset = match seek_position {
+      SeekPosition::Forward(duration) => duration.as_secs_f64(),
+      SeekPosition::Backward(duration) => -duration.as_secs_f64(),
+      SeekPosition::To(_) | SeekPosition::ToFraction(_) => {
+        unreachable!("Absolute seeks can not be coalesced")
+      }
+      SeekPosition::ForwardFraction(_) | SeekPosition::BackwardFraction(_) => {
+        unreachable!("Fraction seeks can not be coalesced")
+      }
+    };
+
+    let applied = {
+      let mut seek_offset = self.seek_offset.lock().await;
+      *seek_offset.get_or_insert(0.0) += offset;
+      Self::wait_for(&self.seek_waiters).await
+    };
+
+    self.wake();
+    applied
+  }
+
+  /// Discards the pending relative seek, so it is not applied after an absolute seek
+  pub async fn cancel_seek(&self) {
+    let mut seek_offset = self.seek_offset.lock().await;
+    *seek_offset = None;
+    // The absolute seek replaces them, and is performed before its own reply
+    Self::notify(&mut *self.seek_waiters.lock().await);
+  }
+
+  /// Adds `count` to the tracks to skip forward
+  ///
+  /// The receiver resolves once the summed skip has been performed
+  pub async fn skip(&self, count: usize) -> oneshot::Receiver<()> {
+    // A pending seek was meant for the track being skipped
+    self.cancel_seek().await;
+
+    let applied = {
+      let mut skip_count = self.skip_count.lock().await;
+      *skip_count = skip_count.saturating_add(count);
+      Self::wait_for(&self.skip_waiters).await
+    };
+
+    self.wake();
+    applied
+  }
+
+  async fn apply_skip(&self, player: &impl CoalescedPlayer) -> Result<(), PlayerError> {
+    let (count, mut waiters) = {
+      let mut skip_count = self.skip_count.lock().await;
+      let waiters = mem::take(&mut *self.skip_waiters.lock().await);
+      (mem::take(&mut *skip_count), waiters)
+    };
+    if count == 0 {
+      Self::notify(&mut waiters);
+      return Ok(());
+    }
+
+    let result = match player.skip_to_next_track(count).await {
+      Err(error) if error.is_recoverable() => {
+        eprintln!("{error}");
+        Ok(())
+      }
+      result => result,
+    };
+
+    Self::notify(&mut waiters);
+    result
+  }
+
+  async fn apply_seek(&self, player: &impl CoalescedPlayer) -> Result<(), PlayerError> {
+    let (offset, mut waiters) = {
+      let mut seek_offset = self.seek_offset.lock().await;
+      let waiters = mem::take(&mut *self.seek_waiters.lock().await);
+      (seek_offset.take(), waiters)
+    };
+    let Some(offset) = offset else {
+      Self::notify(&mut waiters);
+      return Ok(());
+    };
+
+    // The summed offset can be too large for a `Duration`, seeking that far lands at an end of the track anyway
+    let duration = Duration::try_from_secs_f64(offset.abs()).unwrap_or(Duration::MAX);
+    let seek_position = if offset >= 0.0 {
+      SeekPosition::Forward(duration)
+    } else {
+      SeekPosition::Backward(duration)
+    };
+
+    let result = match player.seek(seek_position).await {
+      Err(error) if error.is_recoverable() => {
+        eprintln!("{error}");
+        Ok(())
+      }
+      result => result,
+    };
+
+    Self::notify(&mut waiters);
+    result
+  }
+
+  async fn apply_volume(&self, player: &impl CoalescedPlayer) -> Result<(), PlayerError> {
+    let (volume, mut waiters) = {
+      let mut pending_volume = self.volume.lock().await;
+      let waiters = mem::take(&mut *self.volume_waiters.lock().await);
+      (pending_volume.take(), waiters)
+    };
+    let Some(volume) = volume else {
+      Self::notify(&mut waiters);
+      return Ok(());
+    };
+
+    player.set_volume(volume).await?;
+    Self::notify(&mut waiters);
+
+    // Volume requests sent while waiting replace the pending value and wake the coalescer again
+    smol::Timer::after(Self::VOLUME_INTERVAL).await;
+
+    Ok(())
+  }
+
+  pub async fn run(&self, player: &impl CoalescedPlayer) -> Result<(), PlayerError> {
+    loop {
+      // The coalescer holds the sender, so the channel can't close
+      let _ = self.wake_rx.recv().await;
+
+      // Skips cancel the seeks sent before them, so any pending seek is for the track they skip to
+      self.apply_skip(player).await?;
+      self.apply_seek(player).await?;
+      self.apply_volume(player).await?;
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{sync, time::Duration};
+
+  use futures_concurrency::future::Join;
+  use smol::{Timer, future};
+
+  use super::*;
+
+  /// Records the operations the coalescer performs instead of playing anything
+  #[derive(Default)]
+  struct CountingPlayer {
+    volume: sync::Mutex<f32>,
+    volumes: sync::Mutex<Vec<f32>>,
+    seeks: sync::Mutex<Vec<SeekPosition>>,
+    skips: sync::Mutex<Vec<usize>>,
+    /// How long each seek takes, so requests can arrive while one is performed
+    seek_time: Duration,
+  }
+
+  impl CoalescedPlayer for CountingPlayer {
+    async fn volume(&self) -> f32 {
+      *self.volume.lock().unwrap()
+    }
+
+    async fn set_volume(&self, volume: f32) -> Result<(), PlayerError> {
+      *self.volume.lock().unwrap() = volume;
+      self.volumes.lock().unwrap().push(volume);
+      Ok(())
+    }
+
+    async fn seek(&self, seek_position: SeekPosition) -> Result<(), PlayerError> {
+      Timer::after(self.seek_time).await;
+      self.seeks.lock().unwrap().push(seek_position);
+      Ok(())
+    }
+
+    async fn skip_to_next_track(&self, count: usize) -> Result<(), PlayerError> {
+      self.skips.lock().unwrap().push(count);
+      Ok(())
+    }
+  }
+
+  /// Runs the coalescer until every request in `replies` has been answered
+  async fn run_until_replied(
+    coalescer: &RequestCoalescer,
+    player: &CountingPlayer,
+    replies: Vec<oneshot::Receiver<()>>,
+  ) {
+    future::or(
+      async {
+        coalescer.run(player).await.unwrap();
+        unreachable!("The coalescer runs until it fails");
+      },
+      async {
+        for reply in replies.join().await {
+          reply.expect("Every request should be answered");
+        }
+      },
+    )
+    .await
+  }
+
+  fn forward(secs: u64) -> SeekPosition {
+    SeekPosition::Forward(Duration::from_secs(secs))
+  }
+
+  #[test]
+  fn volume_bursts_apply_the_latest_volume_once() {
+    smol::block_on(async {
+      let coalescer = RequestCoalescer::new();
+      let player = CountingPlayer::default();
+
+      let mut replies = Vec::new();
+      for step in 1..=30 {
+        replies.push(coalescer.set_volume(step as f32 / 100.0).await);
+      }
+      run_until_replied(&coalescer, &player, replies).await;
+
+      assert_eq!(*player.volumes.lock().unwrap(), [0.3]);
+    });
+  }
+
+  #[test]
+  fn volume_adjustments_add_to_the_pending_volume() {
+    smol::block_on(async {
+      let coalescer = RequestCoalescer::new();
+      let player = CountingPlayer::default();
+      *player.volume.lock().unwrap() = 0.5;
+
+      let mut replies = Vec::new();
+      for _ in 0..4 {
+        replies.push(coalescer.adjust_volume(0.125, &player).await);
+      }
+      run_until_replied(&coalescer, &player, replies).await;
+
+      // The volume is clamped, but only after each adjustment
+      assert_eq!(*player.volumes.lock().unwrap(), [1.0]);
+    });
+  }
+
+  #[test]
+  fn relative_seek_bursts_are_summed() {
+    smol::block_on(async {
+      let coalescer = RequestCoalescer::new();
+      let player = CountingPlayer::default();
+
+      let mut replies = Vec::new();
+      for _ in 0..20 {
+        replies.push(coalescer.seek(forward(1)).await);
+      }
+      for _ in 0..25 {
+        replies.push(
+          coalescer
+            .seek(SeekPosition::Backward(Duration::from_secs(1)))
+            .await,
+        );
+      }
+      run_until_replied(&coalescer, &player, replies).await;
+
+      assert_eq!(
+        *player.seeks.lock().unwrap(),
+        [SeekPosition::Backward(Duration::from_secs(5))]
+      );
+    });
+  }
+
+  #[test]
+  fn seeks_sent_during_a_seek_are_summed_into_the_next_one() {
+    smol::block_on(async {
+      let coalescer = RequestCoalescer::new();
+      let player = CountingPlayer {
+        seek_time: Duration::from_millis(50),
+        ..Default::default()
+      };
+
+      let mut replies = Vec::new();
+      for _ in 0..5 {
+        replies.push(coalescer.seek(forward(1)).await);
+      }
+      let late_replies = async {
+        // Sent while the first seek is still being performed
+        Timer::after(Duration::from_millis(10)).await;
+        let mut replies = Vec::new();
+        for _ in 0..5 {
+          replies.push(coalescer.seek(forward(2)).await);
+        }
+        replies
+      };
+
+      future::or(
+        async {
+          coalescer.run(&player).await.unwrap();
+          unreachable!("The coalescer runs until it fails");
+        },
+        async {
+          let late_replies = late_replies.await;
+          for reply in replies.into_iter().chain(late_replies) {
+            reply.await.expect("Every request should be answered");
+          }
+        },
+      )
+      .await;
+
+      assert_eq!(*player.seeks.lock().unwrap(), [forward(5), forward(10)]);
+    });
+  }
+
+  #[test]
+  fn skip_bursts_are_summed() {
+    smol::block_on(async {
+      let coalescer = RequestCoalescer::new();
+      let player = CountingPlayer::default();
+
+      let mut replies = Vec::new();
+      for count in [1, 1, 3, 1] {
+        replies.push(coalescer.skip(count).await);
+      }
+      run_until_replied(&coalescer, &player, replies).await;
+
+      assert_eq!(*player.skips.lock().unwrap(), [6]);
+    });
+  }
+
+  #[test]
+  fn skips_cancel_earlier_seeks() {
+    smol::block_on(async {
+      let coalescer = RequestCoalescer::new();
+      let player = CountingPlayer::default();
+
+      let replies = vec![
+        coalescer.seek(forward(30)).await,
+        coalescer.skip(1).await,
+        coalescer.seek(forward(10)).await,
+      ];
+      run_until_replied(&coalescer, &player, replies).await;
+
+      // Only the seek sent after the skip applies to the track it skipped to
+      assert_eq!(*player.skips.lock().unwrap(), [1]);
+      assert_eq!(*player.seeks.lock().unwrap(), [forward(10)]);
+    });
+  }
+}
@@ -0,0 +1,45 @@
+use std::sync::{Arc, Weak};
+
+use hsm_ipc::{
+  ConnectionInfo,
+  server::{Connection, RequestOrigin},
+};
+use smol::lock::Mutex;
+
+/// Ipc connections that have sent requests
+///
+/// Only weak references are kept, so connections are forgotten as soon as the client disconnects
+#[derive(Debug, Default)]
+pub struct ConnectionList {
+  connections: Mutex<Vec<Weak<Connection>>>,
+}
+
+impl ConnectionList {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Adds the connection `origin` was sent from, if it isn't already listed
+  pub async fn track(&self, origin: &RequestOrigin) {
+    let Some(connection) = &origin.connection else {
+      return;
+    };
+
+    let mut connections = self.connections.lock().await;
+    connections.retain(|connection| connection.strong_count() > 0);
+
+    let connection = Arc::downgrade(connection);
+    if !connections.iter().any(|listed| listed.ptr_eq(&connection)) {
+      connections.push(connection);
+    }
+  }
+
+  pub async fn list(&self) -> Vec<ConnectionInfo> {
+    let connections = self.connections.lock().await;
+    connections
+      .iter()
+      .filter_map(Weak::upgrade)
+      .map(|connection| connection.info())
+      .collect()
+  }
+}
@@ -0,0 +1,239 @@
+use std::time::{Duration, Instant};
+
+use futures_concurrency::future::Race;
+use hsm_ipc::{Event, PlaybackState, TrackId};
+use smol::channel::{self, Receiver, Sender};
+
+use super::{
+  player::{Player, PlayerError},
+  track,
+};
+
+type SyncedLyrics = Vec<(Duration, String)>;
+
+/// Sends `Event::LyricLine` when playback reaches each line of the current track's synced lyrics
+///
+/// The next line is found from the live position every time the timer wakes, so seeks and track changes
+/// are picked up on the next tick. While playback is paused or stopped the timer only wakes when requested.
+#[derive(Debug)]
+pub struct LyricsTimer {
+  wake_tx: Sender<()>,
+  wake_rx: Receiver<()>,
+}
+
+impl LyricsTimer {
+  /// Longest time between position checks, so seeks and track changes are noticed while playing
+  const MAX_TICK: Duration = Duration::from_millis(250);
+
+  pub fn new() -> Self {
+    let (wake_tx, wake_rx) = channel::bounded(1);
+
+    Self { wake_tx, wake_rx }
+  }
+
+  /// Makes the timer check the player's state, should be called after anything that may have changed it
+  pub fn wake(&self) {
+    // If the channel is full, the timer will already wake
+    let _ = self.wake_tx.try_send(());
+  }
+
+  async fn wait(&self, deadline: Option<Instant>) {
+    let wake = async {
+      // The timer holds the sender, so the channel can't close
+      let _ = self.wake_rx.recv().await;
+    };
+
+    match deadline {
+      Some(deadline) => {
+        (wake, async {
+          smol::Timer::at(deadline).await;
+        })
+          .race()
+          .await
+      }
+      None => wake.await,
+    }
+  }
+
+  pub async fn run(&self, player: &Player) -> Result<(), PlayerError> {
+    let mut loaded_id: Option<TrackId> = None;
+    let mut lyrics: Option<SyncedLyrics> = None;
+    let mut lines = LineTracker::default();
+
+    loop {
+      let track_id = player.current_track_id().await;
+      if track_id != loaded_id {
+        lyrics = match player.current_track().await {
+          Some(track) => track::read_synced_lyrics(&track).await,
+          None => None,
+        };
+
+        loaded_id = track_id;
+        lines = LineTracker::default();
+      }
+
+      let (line, deadline) = lines.update(
+        lyrics.as_ref(),
+        player.playback_state(),
+        player.position().await,
+        Instant::now(),
+      );
+
+      if let Some((time, text)) = line {
+        player.emit(Event::LyricLine {
+          time: *time,
+          text: text.clone(),
+        })?;
+      }
+
+      self.wait(deadline).await;
+    }
+  }
+}
+
+/// Finds the line to send and when to check the position again, separate from the player so it can be tested with any clock
+#[derive(Debug, Default)]
+struct LineTracker {
+  /// Number of lines that have started, the last of them is the line that was sent
+  started_lines: usize,
+}
+
+impl LineTracker {
+  /// Returns the line that started since the last update, if any, and when the timer should wake next
+  ///
+  /// The timer should only wake when requested if there is no deadline
+  fn update<'a>(
+    &mut self,
+    lyrics: Option<&'a SyncedLyrics>,
+    playback_state: PlaybackState,
+    position: Duration,
+    now: Instant,
+  ) -> (Option<&'a (Duration, String)>, Option<Instant>) {
+    if playback_state != PlaybackState::Playing {
+      return (None, None);
+    }
+
+    let Some(lines) = lyrics else {
+      // The next track may have lyrics
+      return (None, Some(now + LyricsTimer::MAX_TICK));
+    };
+
+    let current_lines = lines.partition_point(|(time, _)| *time <= position);
+    let mut line = None;
+    if current_lines != self.started_lines {
+      self.started_lines = current_lines;
+      line = current_lines.checked_sub(1).map(|index| &lines[index]);
+    }
+
+    let until_next_line = lines
+      .get(current_lines)
+      .map(|(time, _)| time.saturating_sub(position))
+      .unwrap_or(LyricsTimer::MAX_TICK);
+
+    (line, Some(now + until_next_line.min(LyricsTimer::MAX_TICK)))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const SECOND: Duration = Duration::from_secs(1);
+
+  fn lyrics() -> SyncedLyrics {
+    vec![
+      (Duration::from_millis(100), "First".into()),
+      (Duration::from_millis(200), "Second".into()),
+      (Duration::from_secs(10), "Third".into()),
+    ]
+  }
+
+  fn text(line: Option<&(Duration, String)>) -> Option<&str> {
+    line.map(|(_, text)| text.as_str())
+  }
+
+  #[test]
+  fn waits_for_the_next_line() {
+    let now = Instant::now();
+    let lyrics = lyrics();
+    let mut lines = LineTracker::default();
+
+    let (line, deadline) = lines.update(Some(&lyrics), PlaybackState::Playing, Duration::ZERO, now);
+    assert_eq!(line, None);
+    assert_eq!(deadline, Some(now + Duration::from_millis(100)));
+
+    let (line, deadline) = lines.update(
+      Some(&lyrics),
+      PlaybackState::Playing,
+      Duration::from_millis(150),
+      now,
+    );
+    assert_eq!(text(line), Some("First"));
+    assert_eq!(deadline, Some(now + Duration::from_millis(50)));
+
+    // A line is only sent once, and lines far away are checked for at least every `MAX_TICK`
+    let (line, deadline) = lines.update(
+      Some(&lyrics),
+      PlaybackState::Playing,
+      Duration::from_millis(250),
+      now,
+    );
+    assert_eq!(text(line), Some("Second"));
+    assert_eq!(deadline, Some(now + LyricsTimer::MAX_TICK));
+    let (line, _) = lines.update(Some(&lyrics), PlaybackState::Playing, SECOND, now);
+    assert_eq!(line, None);
+  }
+
+  #[test]
+  fn seeking_sends_the_line_seeked_to() {
+    let now = Instant::now();
+    let lyrics = lyrics();
+    let mut lines = LineTracker::default();
+
+    let (line, _) = lines.update(Some(&lyrics), PlaybackState::Playing, 11 * SECOND, now);
+    assert_eq!(text(line), Some("Third"));
+
+    let (line, _) = lines.update(
+      Some(&lyrics),
+      PlaybackState::Playing,
+      Duration::from_millis(150),
+      now,
+    );
+    assert_eq!(text(line), Some("First"));
+
+    // Seeking before the first line sends nothing
+    let (line, _) = lines.update(Some(&lyrics), PlaybackState::Playing, Duration::ZERO, now);
+    assert_eq!(line, None);
+  }
+
+  #[test]
+  fn only_ticks_while_playing() {
+    let now = Instant::now();
+    let lyrics = lyrics();
+    let mut lines = LineTracker::default();
+
+    for playback_state in [PlaybackState::Paused, PlaybackState::Stopped] {
+      let (line, deadline) = lines.update(Some(&lyrics), playback_state, SECOND, now);
+      assert_eq!(line, None);
+      assert_eq!(deadline, None);
+    }
+
+    // Lines that started while paused are sent once playback resumes
+    let (line, _) = lines.update(Some(&lyrics), PlaybackState::Playing, SECOND, now);
+    assert_eq!(text(line), Some("Second"));
+  }
+
+  #[test]
+  fn tracks_without_lyrics_are_polled() {
+    let now = Instant::now();
+    let mut lines = LineTracker::default();
+
+    let (line, deadline) = lines.update(None, PlaybackState::Playing, SECOND, now);
+    assert_eq!(line, None);
+    assert_eq!(deadline, Some(now + LyricsTimer::MAX_TICK));
+    assert_eq!(
+      lines.update(None, PlaybackState::Paused, SECOND, now),
+      (None, None)
+    );
+  }
+}
@@ -0,0 +1,272 @@
+use std::time::{Duration, Instant};
+
+use hsm_ipc::OutputInfo;
+use rodio::{OutputStream, OutputStreamBuilder, SampleRate, StreamError, cpal};
+use smol::channel::{self, Receiver, Sender};
+
+use super::player::Player;
+
+/// Manages the `OutputStream` that the player's audio is sent through
+pub struct AudioOutput {
+  stream: OutputStream,
+  last_reopened: Option<Instant>,
+  /// Errors reported by the backend for any stream opened through this, such as the sound server going away
+  error_tx: Sender<String>,
+  error_rx: Receiver<String>,
+}
+
+impl AudioOutput {
+  /// Minimum time between reopening the stream, so alternating sample rates don't cause constant gaps
+  const REOPEN_HYSTERESIS: Duration = Duration::from_secs(2);
+
+  pub fn open_default() -> Result<Self, StreamError> {
+    // Further errors are dropped until the first one is handled, since the stream is reopened either way
+    let (error_tx, error_rx) = channel::bounded(1);
+
+    let stream = OutputStreamBuilder::from_default_device()
+      .and_then(|builder| {
+        builder
+          .with_error_callback(Self::error_callback(&error_tx))
+          .open_stream_or_fallback()
+      })
+      // Other devices are only tried at startup, the watchdog notices if their stream stops
+      .or_else(|_| OutputStreamBuilder::open_default_stream())?;
+
+    Ok(Self {
+      stream,
+      last_reopened: None,
+      error_tx,
+      error_rx,
+    })
+  }
+
+  fn error_callback(
+    error_tx: &Sender<String>,
+  ) -> impl FnMut(cpal::StreamError) + Clone + Send + use<> {
+    let error_tx = error_tx.clone();
+    move |error| {
+      let _ = error_tx.try_send(error.to_string());
+    }
+  }
+
+  /// Receives the errors reported for the current stream
+  pub fn errors(&self) -> Receiver<String> {
+    self.error_rx.clone()
+  }
+
+  pub fn stream(&self) -> &OutputStream {
+    &self.stream
+  }
+
+  pub fn sample_rate(&self) -> SampleRate {
+    self.stream.config().sample_rate()
+  }
+
+  pub fn info(&self, bit_perfect: bool) -> OutputInfo {
+    let config = self.stream.config();
+
+    OutputInfo {
+      sample_rate: config.sample_rate(),
+      channels: config.channel_count(),
+      bit_perfect,
+    }
+  }
+
+  /// If enough time has passed since the stream was last reopened
+  pub fn can_reopen(&self) -> bool {
+    self
+      .last_reopened
+      .is_none_or(|last_reopened| last_reopened.elapsed() >= Self::REOPEN_HYSTERESIS)
+  }
+
+  /// Opens a new stream on the default device with `sample_rate` and moves the player's output to it
+  ///
+  /// The current stream is only replaced if the new one was opened successfully
+  pub fn reopen_with_sample_rate(
+    &mut self,
+    sample_rate: SampleRate,
+    player: &Player,
+  ) -> Result<(), StreamError> {
+    let stream = OutputStreamBuilder::from_default_device()?
+      .with_sample_rate(sample_rate)
+      .with_error_callback(Self::error_callback(&self.error_tx))
+      .open_stream()?;
+
+    self.replace_stream(stream, player);
+    Ok(())
+  }
+
+  /// Opens a new stream on the default device and moves the player's output to it, after the current one stopped working
+  ///
+  /// The current sample rate is tried first, so bit perfect playback doesn't need to reopen it again.
+  /// The sources playing on the old stream are dropped with it, the player must queue them again.
+  pub fn reconnect(&mut self, player: &Player) -> Result<(), StreamError> {
+    let stream = OutputStreamBuilder::from_default_device()?
+      .with_sample_rate(self.sample_rate())
+      .with_error_callback(Self::error_callback(&self.error_tx))
+      .open_stream_or_fallback()?;
+
+    self.replace_stream(stream, player);
+    // Errors from the old stream were queued before it was dropped
+    while self.error_rx.try_recv().is_ok() {}
+
+    Ok(())
+  }
+
+  fn replace_stream(&mut self, stream: OutputStream, player: &Player) {
+    stream.mixer().add(player.audio_output(
+      stream.config().sample_rate(),
+      stream.config().channel_count(),
+    ));
+
+    let mut old_stream = std::mem::replace(&mut self.stream, stream);
+    old_stream.log_on_drop(false);
+    self.last_reopened = Some(Instant::now());
+  }
+}
+
+/// Decides when the output stream should be reconnected
+///
+/// The player's output never ends, it plays silence while nothing is queued,
+/// so a stream that stops pulling samples has stopped working, even while paused or stopped
+#[derive(Debug)]
+pub struct StreamWatchdog {
+  state: WatchdogState,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum WatchdogState {
+  /// The stream has pulled `samples_pulled` samples, the last of them at `since`
+  Healthy { samples_pulled: u64, since: Instant },
+  /// Reconnecting failed `attempts` times, the next attempt is at `retry_at`
+  Reconnecting { attempts: u32, retry_at: Instant },
+}
+
+impl StreamWatchdog {
+  /// How often the sample count is checked
+  pub const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+  /// How long the stream can go without pulling samples before it is reconnected
+  const STALL_TIMEOUT: Duration = Duration::from_secs(3);
+  /// Longest wait between reconnect attempts while the sound server is gone
+  const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+  pub fn new(samples_pulled: u64, now: Instant) -> Self {
+    Self {
+      state: WatchdogState::Healthy {
+        samples_pulled,
+        since: now,
+      },
+    }
+  }
+
+  /// Returns true if the stream should be reconnected now
+  pub fn check(&mut self, samples_pulled: u64, now: Instant) -> bool {
+    match self.state {
+      WatchdogState::Healthy {
+        samples_pulled: prev_samples_pulled,
+        since,
+      } => {
+        if samples_pulled != prev_samples_pulled {
+          self.state = WatchdogState::Healthy {
+            samples_pulled,
+            since: now,
+          };
+          return false;
+        }
+
+        now.duration_since(since) >= Self::STALL_TIMEOUT
+      }
+      WatchdogState::Reconnecting { retry_at, .. } => now >= retry_at,
+    }
+  }
+
+  /// Returns true if the stream should be reconnected after the backend reported an error
+  ///
+  /// Errors while waiting to retry are most likely from the same failure, so they don't skip the wait
+  pub fn stream_failed(&self) -> bool {
+    matches!(self.state, WatchdogState::Healthy { .. })
+  }
+
+  pub fn reconnected(&mut self, samples_pulled: u64, now: Instant) {
+    self.state = WatchdogState::Healthy {
+      samples_pulled,
+      since: now,
+    };
+  }
+
+  /// Waits twice as long after each failed attempt, up to `MAX_RETRY_DELAY`
+  pub fn reconnect_failed(&mut self, now: Instant) -> Duration {
+    let attempts = match self.state {
+      WatchdogState::Healthy { .. } => 1,
+      WatchdogState::Reconnecting { attempts, .. } => attempts.saturating_add(1),
+    };
+
+    let delay = Self::CHECK_INTERVAL
+      .saturating_mul(2u32.saturating_pow(attempts - 1))
+      .min(Self::MAX_RETRY_DELAY);
+    self.state = WatchdogState::Reconnecting {
+      attempts,
+      retry_at: now + delay,
+    };
+
+    delay
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const SECOND: Duration = Duration::from_secs(1);
+
+  #[test]
+  fn reconnects_stalled_stream() {
+    let start = Instant::now();
+    let mut watchdog = StreamWatchdog::new(0, start);
+
+    // Pulling samples keeps the stream healthy
+    assert!(!watchdog.check(100, start + 2 * SECOND));
+    assert!(!watchdog.check(200, start + 4 * SECOND));
+    assert!(!watchdog.check(200, start + 6 * SECOND));
+    assert!(watchdog.check(200, start + 7 * SECOND));
+  }
+
+  #[test]
+  fn backs_off_between_failed_reconnects() {
+    let start = Instant::now();
+    let mut watchdog = StreamWatchdog::new(0, start);
+
+    let delays: Vec<u64> = (0..7)
+      .map(|_| watchdog.reconnect_failed(start).as_secs())
+      .collect();
+    assert_eq!(delays, [1, 2, 4, 8, 16, 30, 30]);
+  }
+
+  #[test]
+  fn retries_once_the_delay_passes() {
+    let start = Instant::now();
+    let mut watchdog = StreamWatchdog::new(0, start);
+    let delay = watchdog.reconnect_failed(start);
+    assert_eq!(delay, SECOND);
+
+    // Samples pulled while reconnecting don't matter, only the retry time does
+    assert!(!watchdog.check(100, start + SECOND / 2));
+    assert!(watchdog.check(100, start + SECOND));
+  }
+
+  #[test]
+  fn stream_errors_wait_for_the_retry() {
+    let start = Instant::now();
+    let mut watchdog = StreamWatchdog::new(0, start);
+    assert!(watchdog.stream_failed());
+
+    watchdog.reconnect_failed(start);
+    assert!(!watchdog.stream_failed());
+
+    // A successful reconnect starts over with the shortest delay
+    watchdog.reconnected(0, start + SECOND);
+    assert!(watchdog.stream_failed());
+    assert!(!watchdog.check(0, start + 2 * SECOND));
+    assert_eq!(watchdog.reconnect_failed(start + 2 * SECOND), SECOND);
+  }
+}
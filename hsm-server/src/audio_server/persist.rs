@@ -0,0 +1,515 @@
+use std::{
+  ffi::OsString,
+  io,
+  path::{Path, PathBuf},
+  time::{Duration, Instant},
+};
+
+use futures_concurrency::future::Race;
+use serde::{
+  Serialize,
+  de::{DeserializeOwned, IgnoredAny},
+};
+use smol::{
+  Timer,
+  channel::{self, Receiver, Sender},
+  fs,
+  io::AsyncWriteExt,
+};
+use thiserror::Error;
+
+/// Starts the first line of every persisted file, followed by the format version and a checksum of the rest of the file
+const HEADER_MAGIC: &str = "homeslashmusic-state";
+/// Increased when the envelope changes, files with a newer version are left alone
+const FORMAT_VERSION: u32 = 1;
+
+#[derive(Debug, Error)]
+pub enum PersistError {
+  #[error("Failed to read {path:?}: {source}")]
+  ReadFailed { path: PathBuf, source: io::Error },
+
+  #[error("Failed to write {path:?}: {source}")]
+  WriteFailed { path: PathBuf, source: io::Error },
+
+  #[error("{path:?} was written by a newer version of hsm-server (format version {version})")]
+  UnsupportedVersion { path: PathBuf, version: u32 },
+}
+
+/// Why the contents of a file could not be used
+#[derive(Debug, Error)]
+enum Corruption {
+  #[error("Invalid header")]
+  InvalidHeader,
+
+  #[error("Checksum mismatch, the file was truncated or modified")]
+  ChecksumMismatch,
+
+  #[error("Format version {0} is newer than this server")]
+  NewerVersion(u32),
+
+  #[error(transparent)]
+  ParseFailed(#[from] serde_json::Error),
+}
+
+/// 64 bit FNV-1a, which stays the same across builds so it can be stored on disk
+pub fn content_hash(data: &[u8]) -> u64 {
+  data.iter().fold(0xcbf29ce484222325, |hash, &byte| {
+    (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+  })
+}
+
+/// `path` with `suffix` added after its extension, such as `state.json.bak`
+fn sibling_path(path: &Path, suffix: &str) -> PathBuf {
+  let mut file_name = path.file_name().map(OsString::from).unwrap_or_default();
+  file_name.push(suffix);
+  path.with_file_name(file_name)
+}
+
+pub fn backup_path(path: &Path) -> PathBuf {
+  sibling_path(path, ".bak")
+}
+
+fn wrap(payload: &[u8]) -> Vec<u8> {
+  let header = format!(
+    "{HEADER_MAGIC} {FORMAT_VERSION} {:016x}\n",
+    content_hash(payload)
+  );
+
+  let mut data = header.into_bytes();
+  data.extend_from_slice(payload);
+  data
+}
+
+/// Checks the envelope of `data` and returns the payload inside of it
+///
+/// Files without a header were written before the envelope existed, and are returned as they are
+fn unwrap(data: &[u8]) -> Result<&[u8], Corruption> {
+  if !data.starts_with(HEADER_MAGIC.as_bytes()) {
+    return Ok(data);
+  }
+
+  let header_len = data
+    .iter()
+    .position(|&byte| byte == b'\n')
+    .ok_or(Corruption::InvalidHeader)?;
+  let (header, payload) = (&data[..header_len], &data[header_len + 1..]);
+
+  let header = std::str::from_utf8(header).map_err(|_| Corruption::InvalidHeader)?;
+  let mut fields = header.split(' ').skip(1);
+  let (Some(version), Some(checksum), None) = (fields.next(), fields.next(), fields.next()) else {
+    return Err(Corruption::InvalidHeader);
+  };
+
+  let version: u32 = version.parse().map_err(|_| Corruption::InvalidHeader)?;
+  if version > FORMAT_VERSION {
+    return Err(Corruption::NewerVersion(version));
+  }
+
+  let checksum = u64::from_str_radix(checksum, 16).map_err(|_| Corruption::InvalidHeader)?;
+  if checksum != content_hash(payload) {
+    return Err(Corruption::ChecksumMismatch);
+  }
+
+  Ok(payload)
+}
+
+fn decode<T: DeserializeOwned>(data: &[u8]) -> Result<T, Corruption> {
+  Ok(serde_json::from_slice(unwrap(data)?)?)
+}
+
+/// `None` if the file does not exist
+async fn read_file(path: &Path) -> Result<Option<Vec<u8>>, PersistError> {
+  match fs::read(path).await {
+    Ok(data) => Ok(Some(data)),
+    Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(None),
+    Err(source) => Err(PersistError::ReadFailed {
+      path: path.to_path_buf(),
+      source,
+    }),
+  }
+}
+
+/// Reads the value saved at `path`, falling back to the backup of the last good version if it is missing or corrupt
+///
+/// A corrupt file is moved aside to `<path>.corrupt` so it can be inspected, and what was lost is logged.
+/// Returns `None` if there was nothing usable to read, so a damaged file never stops the server from starting.
+pub async fn read_recovering<T: DeserializeOwned>(path: &Path) -> Result<Option<T>, PersistError> {
+  let backup_path = backup_path(path);
+
+  match read_file(path).await? {
+    Some(data) => match decode(&data) {
+      Ok(value) => return Ok(Some(value)),
+      Err(Corruption::NewerVersion(version)) => {
+        return Err(PersistError::UnsupportedVersion {
+          path: path.to_path_buf(),
+          version,
+        });
+      }
+      Err(corruption) => {
+        let corrupt_path = sibling_path(path, ".corrupt");
+        eprintln!("Warning: {path:?} is corrupt, moving it to {corrupt_path:?}: {corruption}");
+        if let Err(error) = fs::rename(path, &corrupt_path).await {
+          eprintln!("Warning: Failed to move {path:?} aside: {error}");
+        }
+      }
+    },
+    // The server may have stopped between moving the old file to the backup and replacing it
+    None => {
+      if fs::metadata(&backup_path).await.is_err() {
+        return Ok(None);
+      }
+      eprintln!("Warning: {path:?} is missing");
+    }
+  }
+
+  let Some(data) = read_file(&backup_path).await? else {
+    eprintln!("Warning: No backup of {path:?} to recover from, it will start empty");
+    return Ok(None);
+  };
+
+  match decode(&data) {
+    Ok(value) => {
+      eprintln!(
+        "Recovered {path:?} from {backup_path:?}, changes made after the backup was written are lost"
+      );
+      Ok(Some(value))
+    }
+    Err(corruption) => {
+      eprintln!(
+        "Warning: The backup {backup_path:?} is also unusable, {path:?} will start empty: {corruption}"
+      );
+      Ok(None)
+    }
+  }
+}
+
+/// Writes `value` to `path` so a crash or power loss at any point leaves either the old or the new version
+///
+/// The data is written and synced to a temporary file that is renamed over `path`.
+/// The previous version is kept at `<path>.bak` if it was intact, for `read_recovering` to fall back to.
+pub async fn write_atomic<T: Serialize>(path: &Path, value: &T) -> Result<(), PersistError> {
+  let payload = serde_json::to_vec(value).expect("Persisted values should not fail to serialize");
+  let data = wrap(&payload);
+  let tmp_path = sibling_path(path, ".tmp");
+
+  let result = async {
+    if let Some(dir) = path.parent() {
+      fs::create_dir_all(dir).await?;
+    }
+
+    let mut file = fs::File::create(&tmp_path).await?;
+    file.write_all(&data).await?;
+    file.sync_all().await?;
+    drop(file);
+
+    // A corrupt file is never rotated, so it can't replace a good backup
+    let current_is_intact = match fs::read(path).await {
+      // Files without a header are only checked for being JSON
+      Ok(current) => decode::<IgnoredAny>(&current).is_ok(),
+      Err(error) if error.kind() == io::ErrorKind::NotFound => false,
+      Err(error) => return Err(error),
+    };
+    if current_is_intact {
+      fs::rename(path, backup_path(path)).await?;
+    }
+
+    fs::rename(&tmp_path, path).await?;
+    sync_dir(path).await
+  }
+  .await;
+
+  result.map_err(|source| PersistError::WriteFailed {
+    path: path.to_path_buf(),
+    source,
+  })
+}
+
+/// Syncs the directory containing `path`, so the renames in it survive a power loss
+async fn sync_dir(path: &Path) -> io::Result<()> {
+  let Some(dir) = path.parent() else {
+    return Ok(());
+  };
+
+  fs::File::open(dir).await?.sync_all().await
+}
+
+/// Deletes the file at `path` and its backup
+pub async fn remove(path: &Path) -> Result<(), PersistError> {
+  for path in [path.to_path_buf(), backup_path(path)] {
+    match fs::remove_file(&path).await {
+      Err(error) if error.kind() != io::ErrorKind::NotFound => {
+        return Err(PersistError::WriteFailed {
+          path,
+          source: error,
+        });
+      }
+      _ => (),
+    }
+  }
+
+  Ok(())
+}
+
+/// Decides when persisted state is written, shared by everything that saves to disk
+///
+/// Changes are flushed once no more were requested for the debounce time,
+/// so a burst of requests is written once instead of after every request.
+#[derive(Debug)]
+pub struct FlushScheduler {
+  wake_tx: Sender<()>,
+  wake_rx: Receiver<()>,
+}
+
+impl FlushScheduler {
+  pub fn new() -> Self {
+    let (wake_tx, wake_rx) = channel::bounded(1);
+    Self { wake_tx, wake_rx }
+  }
+
+  /// Marks the state as possibly changed
+  pub fn request_flush(&self) {
+    // If the channel is full, a flush is already pending
+    let _ = self.wake_tx.try_send(());
+  }
+
+  /// Waits for the scheduler to hold a request, or `timeout` to pass
+  async fn wait_for_request(&self, timeout: Duration) -> bool {
+    (
+      async {
+        // The scheduler holds the sender, so the channel can't close
+        self.wake_rx.recv().await.is_ok()
+      },
+      async {
+        Timer::after(timeout).await;
+        false
+      },
+    )
+      .race()
+      .await
+  }
+
+  /// Calls `flush` once `debounce` passes without another request,
+  /// and at least every `max_interval` for changes that aren't requested, such as the playback position
+  ///
+  /// Requests that keep arriving delay a flush by at most `max_interval`
+  pub async fn run<F: Future<Output = ()>>(
+    &self,
+    debounce: Duration,
+    max_interval: Duration,
+    mut flush: impl FnMut() -> F,
+  ) {
+    loop {
+      let deadline = Instant::now() + max_interval;
+
+      if self.wait_for_request(max_interval).await {
+        while let Some(remaining) = deadline.checked_duration_since(Instant::now()) {
+          if !self.wait_for_request(debounce.min(remaining)).await {
+            break;
+          }
+        }
+      }
+
+      flush().await;
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{cell::Cell, fs as sync_fs};
+
+  use super::*;
+
+  #[test]
+  fn hashes_like_fnv1a() {
+    assert_eq!(content_hash(b""), 0xcbf29ce484222325);
+    assert_eq!(content_hash(b"a"), 0xaf63dc4c8601ec8c);
+    assert_eq!(content_hash(b"foobar"), 0x85944171f73967e8);
+  }
+
+  #[test]
+  fn unwraps_wrapped_payload() {
+    let payload = br#"{"volume":0.5}"#;
+    assert_eq!(unwrap(&wrap(payload)).unwrap(), payload);
+    // Written before the envelope existed
+    assert_eq!(unwrap(payload).unwrap(), payload);
+  }
+
+  #[test]
+  fn rejects_damaged_envelopes() {
+    let data = wrap(br#"{"volume":0.5}"#);
+
+    let truncated = &data[..data.len() - 2];
+    assert!(matches!(
+      unwrap(truncated),
+      Err(Corruption::ChecksumMismatch)
+    ));
+
+    let mut modified = data.clone();
+    *modified.last_mut().unwrap() = b']';
+    assert!(matches!(
+      unwrap(&modified),
+      Err(Corruption::ChecksumMismatch)
+    ));
+
+    let cut_in_header = &data[..HEADER_MAGIC.len() + 3];
+    assert!(matches!(
+      unwrap(cut_in_header),
+      Err(Corruption::InvalidHeader)
+    ));
+
+    let newer = format!("{HEADER_MAGIC} 99 0000000000000000\n{{}}");
+    assert!(matches!(
+      unwrap(newer.as_bytes()),
+      Err(Corruption::NewerVersion(99))
+    ));
+  }
+
+  /// A state file path in a new directory, which is removed when the returned `TempDir` is dropped
+  fn state_path() -> (tempfile::TempDir, PathBuf) {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("state.json");
+    (dir, path)
+  }
+
+  fn read(path: &Path) -> Option<Vec<u32>> {
+    smol::block_on(read_recovering(path)).unwrap()
+  }
+
+  fn write(path: &Path, value: &[u32]) {
+    smol::block_on(write_atomic(path, &value)).unwrap();
+  }
+
+  #[test]
+  fn reads_back_written_values() {
+    let (_dir, path) = state_path();
+    assert_eq!(read(&path), None);
+
+    write(&path, &[1]);
+    assert_eq!(read(&path), Some(vec![1]));
+    assert!(!backup_path(&path).exists());
+
+    // The previous version becomes the backup
+    write(&path, &[2]);
+    assert_eq!(read(&path), Some(vec![2]));
+    let backup = sync_fs::read(backup_path(&path)).unwrap();
+    assert_eq!(decode::<Vec<u32>>(&backup).unwrap(), [1]);
+  }
+
+  #[test]
+  fn recovers_corrupt_file_from_backup() {
+    let (_dir, path) = state_path();
+    write(&path, &[1]);
+    write(&path, &[2]);
+
+    let data = sync_fs::read(&path).unwrap();
+    sync_fs::write(&path, &data[..data.len() - 1]).unwrap();
+
+    assert_eq!(read(&path), Some(vec![1]));
+    assert!(!path.exists());
+    assert!(sibling_path(&path, ".corrupt").exists());
+
+    // The corrupt file is never rotated into the backup
+    sync_fs::write(&path, b"garbage").unwrap();
+    write(&path, &[3]);
+    let backup = sync_fs::read(backup_path(&path)).unwrap();
+    assert_eq!(decode::<Vec<u32>>(&backup).unwrap(), [1]);
+  }
+
+  #[test]
+  fn recovers_missing_file_from_backup() {
+    let (_dir, path) = state_path();
+    write(&path, &[1]);
+    write(&path, &[2]);
+    sync_fs::remove_file(&path).unwrap();
+
+    assert_eq!(read(&path), Some(vec![1]));
+  }
+
+  #[test]
+  fn starts_empty_without_usable_data() {
+    let (_dir, path) = state_path();
+    sync_fs::write(&path, b"{ not json").unwrap();
+    assert_eq!(read(&path), None);
+
+    sync_fs::write(&path, b"garbage").unwrap();
+    sync_fs::write(backup_path(&path), b"garbage").unwrap();
+    assert_eq!(read(&path), None);
+  }
+
+  #[test]
+  fn leaves_newer_files_alone() {
+    let (_dir, path) = state_path();
+    let newer = format!("{HEADER_MAGIC} 99 0000000000000000\n[]");
+    sync_fs::write(&path, &newer).unwrap();
+
+    assert!(matches!(
+      smol::block_on(read_recovering::<Vec<u32>>(&path)),
+      Err(PersistError::UnsupportedVersion { version: 99, .. })
+    ));
+    assert_eq!(sync_fs::read_to_string(&path).unwrap(), newer);
+  }
+
+  #[test]
+  fn removes_file_and_backup() {
+    let (_dir, path) = state_path();
+    write(&path, &[1]);
+    write(&path, &[2]);
+
+    smol::block_on(remove(&path)).unwrap();
+    assert!(!path.exists());
+    assert!(!backup_path(&path).exists());
+    // Removing again is not an error
+    smol::block_on(remove(&path)).unwrap();
+  }
+
+  /// Runs `scheduler` for `duration`, returning how many times it flushed
+  async fn count_flushes(
+    scheduler: &FlushScheduler,
+    debounce: Duration,
+    max_interval: Duration,
+    duration: Duration,
+  ) -> usize {
+    let flushes = Cell::new(0);
+    (
+      scheduler.run(debounce, max_interval, || {
+        flushes.set(flushes.get() + 1);
+        async {}
+      }),
+      async {
+        Timer::after(duration).await;
+      },
+    )
+      .race()
+      .await;
+    flushes.get()
+  }
+
+  #[test]
+  fn flushes_a_burst_of_requests_once() {
+    let scheduler = FlushScheduler::new();
+    for _ in 0..5 {
+      scheduler.request_flush();
+    }
+
+    let flushes = smol::block_on(count_flushes(
+      &scheduler,
+      Duration::from_millis(20),
+      Duration::from_secs(60),
+      Duration::from_millis(300),
+    ));
+    assert_eq!(flushes, 1);
+  }
+
+  #[test]
+  fn flushes_without_requests_every_max_interval() {
+    let scheduler = FlushScheduler::new();
+    let flushes = smol::block_on(count_flushes(
+      &scheduler,
+      Duration::from_millis(20),
+      Duration::from_millis(100),
+      Duration::from_millis(350),
+    ));
+    assert_eq!(flushes, 3);
+  }
+}
@@ -1,59 +1,166 @@
 use std::{
   mem,
+  path::PathBuf,
   sync::{
     Arc,
-    atomic::{AtomicUsize, Ordering},
+    atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering},
   },
   time::Duration,
 };
 
 use async_oneshot as oneshot;
-use controlled_source::{SeekError, SourceEvent, wrap_source};
+use controlled_source::{SOURCE_UPDATE_INTERVAL, SeekError, SourceEvent, wrap_source};
+use decode_ahead::DecodeAhead;
 use decoder::TrackDecoder;
+use futures_concurrency::future::Race;
 use hsm_ipc::{
-  Event, InsertPosition, LoopMode, PlaybackState, SeekPosition, Track, TrackListSnapshot,
+  CurrentEntry, EndBehavior, Event, FilterExpr, InsertPosition, InsertShufflePolicy, LoopMode,
+  MAX_RATE, MIN_RATE, NormalizationMode, PlaybackState, QueueSummary, SeekPosition, SessionStats,
+  StopReason, TestToneResult, Track, TrackId, TrackListDiff, TrackListSnapshot, TrackRef,
+  TransitionMode,
 };
-use output::SourceQueueState;
-use rodio::{Source, mixer::Mixer};
+use hsm_plugin::SharedPlayerState;
+use output::{Interrupt, InterruptGuard, SourceQueueState};
+use resampler::SincResampler;
+use rodio::{ChannelCount, OutputStream, SampleRate, Source};
 use smol::{
+  Timer,
   channel::{self, Receiver, Sender},
   lock::Mutex,
 };
 
-use atomic_control_status::{AtomicLoopMode, AtomicPlaybackState};
+use atomic_control_status::{AtomicLoopMode, AtomicNormalizationMode, AtomicPlaybackState};
+use session_stats::{SessionTracker, TrackOutcome};
+use system_volume::{StreamChange, SystemVolume, SystemVolumeError};
+use test_tone::TestTone;
 use thiserror::Error;
-use track_list::TrackList;
+use track_list::{TrackInstance, TrackList};
 
-use super::track::{LoadTrackError, LoadedTrack};
+use super::{
+  blocking::BlockingScheduler,
+  saved_state::SavedState,
+  track::{LoadTrackError, LoadedTrack},
+};
+use crate::config::{GaplessPolicy, RemovedCurrent, VolumeBackend};
 pub use output::PlayerAudioOutput;
+pub use stall_watchdog::StallWatchdog;
 
 mod atomic_control_status;
 mod controlled_source;
+mod decode_ahead;
 mod decoder;
 mod output;
+mod resampler;
+mod session_stats;
+mod stall_watchdog;
+mod system_volume;
+mod test_tone;
+#[cfg(test)]
+pub mod tests;
 mod track_list;
+mod transition;
+
+/// How long `queue_track` waits for the queued source to start playing before checking the queue again
+const QUEUE_WAIT_TIMEOUT: Duration = Duration::from_secs(1);
+/// Never given to a track, so the test tone can be told apart from tracks in the source queue
+const TEST_TONE_ID: TrackId = TrackId(usize::MAX);
+/// Longest test tone that can be played
+const MAX_TEST_TONE: Duration = Duration::from_secs(30);
+/// How long to wait before watching the system volume again after `pactl subscribe` stopped
+const SYSTEM_VOLUME_RETRY: Duration = Duration::from_secs(5);
 
 #[derive(Debug)]
 struct Controls {
   pub playback_state: AtomicPlaybackState,
   pub loop_mode: AtomicLoopMode,
   pub volume: Mutex<f32>,
+  /// The volume is applied by the system mixer, so sources are played without amplifying them
+  pub system_volume: AtomicBool,
+  /// Playback speed, which also changes the pitch
+  pub rate: Mutex<f32>,
   pub to_skip: AtomicUsize,
   pub position: Mutex<Duration>,
-  pub seek_position: Mutex<Option<(SeekPosition, oneshot::Sender<Result<(), SeekError>>)>>,
+  /// Applied by the playing source, which replies with the position it seeked to
+  pub seek_position: Mutex<Option<(SeekPosition, oneshot::Sender<Result<Duration, SeekError>>)>>,
   pub source_queue: Mutex<SourceQueueState>,
+  /// Woken when the source in `source_queue` stops being queued, so `queue_track` can queue the next one
+  ///
+  /// Locked from the output stream's thread, so this doesn't use an async mutex
+  pub queue_waiters: std::sync::Mutex<Vec<oneshot::Sender<()>>>,
+  /// Played by the output instead of the source queue until it ends, taken by the output once `interrupt_pending` is set
+  ///
+  /// Locked from the output stream's thread, so this doesn't use an async mutex
+  pub interrupt: std::sync::Mutex<Option<Interrupt>>,
+  pub interrupt_pending: AtomicBool,
+  /// Incremented every time a track starts loading to be queued
+  pub queue_sequence: AtomicU64,
+  /// The highest sequence number of a source put in `source_queue`, only changed while it is locked
+  pub accepted_sequence: AtomicU64,
+  /// The sequence number of the last source queued as the current track, only changed while `source_queue` is locked
+  pub accepted_current_sequence: AtomicU64,
+  pub bit_perfect: AtomicBool,
+  /// A sample rate that the output stream could not be reopened with, so the queued source should be resampled
+  pub declined_output_rate: AtomicU32,
+  /// Copies of values that plugins read without sending requests
+  pub shared: Arc<SharedPlayerState>,
+  /// How long before the end of a track `Event::TrackEnding` is sent, zero to disable
+  pub track_ending_notice_micros: AtomicU64,
+  /// Samples pulled from the player's output by the output stream, updated every few hundred samples
+  pub samples_pulled: AtomicU64,
+  /// Pauses playback at the end of the current track instead of moving to the next one, cleared once it has
+  pub stop_after_current: AtomicBool,
+  pub normalization: AtomicNormalizationMode,
+  /// Amplification for tracks without ReplayGain tags while normalization is enabled
+  pub normalization_fallback: Mutex<f32>,
 }
 
 impl Controls {
-  fn new() -> Self {
+  fn new(shared: Arc<SharedPlayerState>) -> Self {
     Self {
       playback_state: AtomicPlaybackState::new(PlaybackState::Stopped),
       loop_mode: AtomicLoopMode::new(LoopMode::None),
       to_skip: AtomicUsize::new(0),
       volume: Mutex::new(1.0),
+      system_volume: AtomicBool::new(false),
+      rate: Mutex::new(1.0),
       position: Mutex::new(Duration::ZERO),
       seek_position: Mutex::new(None),
       source_queue: Mutex::new(SourceQueueState::None),
+      queue_waiters: std::sync::Mutex::new(Vec::new()),
+      interrupt: std::sync::Mutex::new(None),
+      interrupt_pending: AtomicBool::new(false),
+      queue_sequence: AtomicU64::new(0),
+      accepted_sequence: AtomicU64::new(0),
+      accepted_current_sequence: AtomicU64::new(0),
+      bit_perfect: AtomicBool::new(false),
+      declined_output_rate: AtomicU32::new(0),
+      shared,
+      track_ending_notice_micros: AtomicU64::new(5_000_000),
+      samples_pulled: AtomicU64::new(0),
+      stop_after_current: AtomicBool::new(false),
+      normalization: AtomicNormalizationMode::new(NormalizationMode::Off),
+      normalization_fallback: Mutex::new(1.0),
+    }
+  }
+
+  /// Makes the playing source end, so the queued one plays next
+  ///
+  /// Skipping again before the playing source applies the skip must not also skip the queued source
+  fn skip_playing_source(&self) {
+    self.to_skip.fetch_max(1, Ordering::AcqRel);
+  }
+
+  /// Must be called after a queued source is played, replaced with `Playing`, or dropped
+  fn wake_queue_waiters(&self) {
+    let waiters = mem::take(
+      &mut *self
+        .queue_waiters
+        .lock()
+        .expect("Queue waiters lock should not be poisoned"),
+    );
+
+    for mut waiter in waiters {
+      let _ = waiter.send(());
     }
   }
 }
@@ -68,11 +175,47 @@ pub enum PlayerError {
   #[error("Event channel closed")]
   EventChannelClosed,
 
+  /// Should never happen since the player managers both ends of the channel
+  #[error("Internal Player Error: output rate request channel closed")]
+  OutputRateChannelClosed,
+
   #[error("Failed to load track: {0}")]
   LoadTrack(#[from] LoadTrackError),
 
   #[error("failed to seek: ")]
   SeekFailed(#[from] SeekError),
+
+  #[error("No track with id {0:?} in the track list")]
+  UnknownTrackId(TrackId),
+
+  #[error(
+    "Track list generation is {current}, but {expected} was expected. Query the track list and try again"
+  )]
+  GenerationMismatch { expected: u64, current: u64 },
+
+  #[error("The queue is limited to {max_length} tracks, {dropped} tracks were not added")]
+  QueueFull { max_length: usize, dropped: usize },
+
+  #[error("No track at index {index}, the track list has {len} tracks")]
+  TrackIndexOutOfRange { index: usize, len: usize },
+
+  #[error("Rate must be a finite number, got {0}")]
+  InvalidRate(f32),
+
+  #[error("Frequency must be between 20 Hz and half the output's sample rate, got {0}")]
+  InvalidFrequency(f32),
+
+  #[error("Stop playback before playing a test tone")]
+  TestToneWhilePlaying,
+
+  #[error("A oneshot is already playing")]
+  OneshotActive,
+
+  #[error("No track in the queue matches the filter, it was not applied")]
+  FilterMatchesNothing,
+
+  #[error("Failed to change the system volume: {0}")]
+  SystemVolume(#[from] SystemVolumeError),
 }
 
 impl PlayerError {
@@ -80,48 +223,204 @@ impl PlayerError {
     match self {
       Self::LoadTrack(_) => true,
       Self::SeekFailed(_) => true,
+      Self::UnknownTrackId(_) => true,
+      Self::GenerationMismatch { .. } => true,
+      Self::QueueFull { .. } => true,
+      Self::TrackIndexOutOfRange { .. } => true,
+      Self::InvalidRate(_) => true,
+      Self::InvalidFrequency(_) => true,
+      Self::TestToneWhilePlaying => true,
+      Self::OneshotActive => true,
+      Self::FilterMatchesNothing => true,
+      Self::SystemVolume(_) => true,
       _ => false,
     }
   }
 }
 
+/// What the player does at the end of the current track with these settings
+fn end_behavior_for(loop_mode: LoopMode, stop_after_current: bool) -> EndBehavior {
+  if stop_after_current {
+    return EndBehavior::WillStopAfterCurrent;
+  }
+
+  match loop_mode {
+    LoopMode::None => EndBehavior::WillStopAtQueueEnd,
+    LoopMode::Track => EndBehavior::WillLoopTrack,
+    LoopMode::Playlist => EndBehavior::WillWrap,
+  }
+}
+
+/// The entry a source passed to `Player::queue_track` is queued for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum QueueRole {
+  /// Replaces any queued source. If `skip_playing` is true, the playing source is skipped once this one is queued,
+  /// so it plays right away
+  Current { skip_playing: bool },
+  /// Plays after the current track. If `wait_for_empty_queue` is false,
+  /// this waits until the source already in the queue starts playing, otherwise that source is replaced
+  Next { wait_for_empty_queue: bool },
+}
+
 #[derive(Debug)]
 pub struct Player {
   tracks: TrackList,
-  current_track_index: AtomicUsize,
+  stop_reason: Mutex<Option<StopReason>>,
+  /// The track playback stopped on after reaching the end of the track list, cleared when playback starts
+  ended_on: Mutex<Option<TrackId>>,
+  /// The removed entry that is still playing, see `RemovedCurrent::Continue`. The current index is the entry that plays after it
+  detached: Mutex<Option<TrackInstance>>,
+  /// Skip to the next entry when the playing one is removed, instead of detaching it
+  skip_removed_current: AtomicBool,
+  gapless_policy: std::sync::Mutex<GaplessPolicy>,
+  session: SessionTracker,
 
   controls: Arc<Controls>,
+  scheduler: Arc<BlockingScheduler>,
+  /// How far ahead of playback sources are decoded, 0 decodes on the output thread
+  decode_ahead_micros: AtomicU64,
+  /// Resample sources that don't match the output stream with `SincResampler` instead of the mixer's linear conversion
+  high_quality_resampling: AtomicBool,
+  /// The sample rate of the output stream that was last connected
+  output_rate: AtomicU32,
   event_tx: Sender<Event>,
   source_tx: Sender<SourceEvent>,
   source_rx: Receiver<SourceEvent>,
+  /// Counts the source events `run` has taken from `source_rx`, for the `StallWatchdog`
+  source_events_taken: AtomicU64,
+  output_rate_tx: Sender<SampleRate>,
+  output_rate_rx: Receiver<SampleRate>,
+  /// Set while a test tone is playing, and sent to when it ends instead of moving to the next track
+  test_tone_done: Mutex<Option<oneshot::Sender<()>>>,
+  /// Set from when a oneshot is accepted until its interrupt finishes
+  oneshot_active: AtomicBool,
+  system_volume: SystemVolume,
 }
 
 impl Player {
-  pub fn connect_new(event_tx: Sender<Event>, mixer: &Mixer) -> Self {
-    let (player, source) = Self::new(event_tx);
-    mixer.add(source);
+  pub fn connect_new(
+    event_tx: Sender<Event>,
+    output_stream: &OutputStream,
+    scheduler: Arc<BlockingScheduler>,
+    shared_state: Arc<SharedPlayerState>,
+  ) -> Self {
+    let (player, source) = Self::new(
+      event_tx,
+      output_stream.config().sample_rate(),
+      output_stream.config().channel_count(),
+      scheduler,
+      shared_state,
+    );
+    output_stream.mixer().add(source);
     player
   }
 
-  pub fn new(event_tx: Sender<Event>) -> (Self, PlayerAudioOutput) {
+  pub fn new(
+    event_tx: Sender<Event>,
+    sample_rate: SampleRate,
+    channels: ChannelCount,
+    scheduler: Arc<BlockingScheduler>,
+    shared_state: Arc<SharedPlayerState>,
+  ) -> (Self, PlayerAudioOutput) {
     let (source_tx, source_rx) = channel::unbounded();
+    let (output_rate_tx, output_rate_rx) = channel::unbounded();
 
     let player = Self {
       tracks: TrackList::new(),
-      current_track_index: AtomicUsize::new(0),
-
-      controls: Arc::new(Controls::new()),
+      stop_reason: Mutex::new(None),
+      ended_on: Mutex::new(None),
+      detached: Mutex::new(None),
+      skip_removed_current: AtomicBool::new(false),
+      gapless_policy: std::sync::Mutex::new(GaplessPolicy::Auto),
+      session: SessionTracker::new(),
+
+      controls: Arc::new(Controls::new(shared_state)),
+      scheduler,
+      decode_ahead_micros: AtomicU64::new(0),
+      high_quality_resampling: AtomicBool::new(false),
+      output_rate: AtomicU32::new(sample_rate),
       event_tx,
       source_tx,
       source_rx,
+      source_events_taken: AtomicU64::new(0),
+      output_rate_tx,
+      output_rate_rx,
+      test_tone_done: Mutex::new(None),
+      oneshot_active: AtomicBool::new(false),
+      system_volume: SystemVolume::new(),
     };
 
-    let audio_source = PlayerAudioOutput::new(player.controls.clone());
+    let audio_source = player.audio_output(sample_rate, channels);
 
     (player, audio_source)
   }
 
-  fn emit(&self, event: Event) -> Result<(), PlayerError> {
+  /// Creates a new output sharing this player's controls, to be added to an output stream with `sample_rate` and `channels`
+  pub fn audio_output(&self, sample_rate: SampleRate, channels: ChannelCount) -> PlayerAudioOutput {
+    self.output_rate.store(sample_rate, Ordering::Relaxed);
+    PlayerAudioOutput::new(
+      self.controls.clone(),
+      self.output_rate_tx.clone(),
+      sample_rate,
+      channels,
+    )
+  }
+
+  /// The number of source events `run` has taken, and the number waiting for it
+  pub fn source_event_progress(&self) -> (u64, usize) {
+    (
+      self.source_events_taken.load(Ordering::Relaxed),
+      self.source_rx.len(),
+    )
+  }
+
+  /// Increases as long as the output stream is working, even if nothing is playing
+  pub fn samples_pulled(&self) -> u64 {
+    self.controls.samples_pulled.load(Ordering::Relaxed)
+  }
+
+  /// Drops the queued sources before the output stream is replaced, returning the position to resume at
+  ///
+  /// The old stream may have stopped in the middle of a track, so the new one must not start the next track instead
+  pub async fn detach_sources(&self) -> Duration {
+    let mut source_queue = self.controls.source_queue.lock().await;
+    *source_queue = SourceQueueState::None;
+    self.controls.wake_queue_waiters();
+    // The playing source is dropped with the old stream, so there is nothing to skip
+    self.controls.to_skip.store(0, Ordering::Release);
+
+    *self.controls.position.lock().await
+  }
+
+  /// Queues the current track again on a new output stream, and seeks to `position` in it
+  ///
+  /// Must be called after `detach_sources`, and does nothing if playback is stopped
+  pub async fn resume_at(&self, position: Duration) -> Result<(), PlayerError> {
+    if self.is_stopped() {
+      return Ok(());
+    }
+
+    // A detached track's source was dropped with the old stream, so move on to the entry that took its place
+    if self.track_detached().await {
+      return self.go_to_next_track(1, true).await;
+    }
+
+    if !self.queue_current_track(false).await? {
+      return Ok(());
+    }
+
+    if !position.is_zero() {
+      self.seek(SeekPosition::To(position)).await?;
+    }
+
+    Ok(())
+  }
+
+  /// Hands `event` to the plugin manager without waiting for it to be delivered
+  ///
+  /// Methods that change the player's state emit their events before returning,
+  /// so the reply to the request that caused a change is always sent after its event
+  pub fn emit(&self, event: Event) -> Result<(), PlayerError> {
     self
       .event_tx
       .try_send(event)
@@ -130,14 +429,37 @@ impl Player {
 
   async fn load_track_source(
     &self,
-    track: &Arc<LoadedTrack>,
+    track: &TrackInstance,
   ) -> Result<Box<dyn Source + Send + 'static>, LoadTrackError> {
-    let decoder = TrackDecoder::new(track.clone()).await?;
+    let decoder = TrackDecoder::new(track.loaded_track().clone(), &self.scheduler).await?;
+    let decode_ahead = Duration::from_micros(self.decode_ahead_micros.load(Ordering::Relaxed));
+    let decoder: Box<dyn Source + Send> = if decode_ahead.is_zero() {
+      Box::new(decoder)
+    } else {
+      Box::new(DecodeAhead::new(decoder, decode_ahead))
+    };
+
+    // In bit perfect mode the output stream is reopened at the source's rate instead
+    let output_rate = self.output_rate.load(Ordering::Relaxed);
+    let decoder: Box<dyn Source + Send> = if self.high_quality_resampling.load(Ordering::Relaxed)
+      && !self.bit_perfect()
+      && decoder.sample_rate() != output_rate
+    {
+      Box::new(SincResampler::new(decoder, output_rate))
+    } else {
+      decoder
+    };
+
+    let gain = track
+      .state()
+      .gain_db
+      .map_or(1.0, |gain_db| 10f32.powf(gain_db / 20.0));
 
     Ok(Box::new(wrap_source(
-      decoder,
+      decoder.amplify(gain),
       self.controls.clone(),
       self.source_tx.clone(),
+      Some(track.loaded_track().metadata().replay_gain),
     )))
   }
 
@@ -146,87 +468,231 @@ impl Player {
 
     if source_queue.is_playing() {
       source_queue.invalidate();
-      self.controls.to_skip.fetch_add(1, Ordering::AcqRel);
+      self.controls.wake_queue_waiters();
+      self.controls.skip_playing_source();
+    } else {
+      // Skips left over from a source that ended on its own would skip the next track played
+      self.controls.to_skip.store(0, Ordering::Release);
     }
   }
 
-  /// If `wait_for_empty_queue` is true, this function will loop until the queue become open for replacement
-  /// If it is not ensured that the queue is empty beforehand it can cause an infinite loop and lockup
+  /// Queues a source for `track`, which plays as `role`
+  ///
+  /// The source is discarded if its track is no longer the entry it was queued for once it has loaded,
+  /// or if a newer call to this function queued a source for the same role while it was loading or waiting
   async fn queue_track(
     &self,
-    track: &Arc<LoadedTrack>,
-    wait_for_empty_queue: bool,
+    track: &TrackInstance,
+    role: QueueRole,
   ) -> Result<(), LoadTrackError> {
-    let source = self.load_track_source(track).await?;
+    let sequence = self.controls.queue_sequence.fetch_add(1, Ordering::AcqRel) + 1;
+
+    let loaded = self.load_track_source(track).await;
+    // The entry may have been removed while it was loading
+    let _ = self
+      .tracks
+      .update_instance(track.track_id(), |state| state.failed = loaded.is_err())
+      .await;
+    let source = loaded?;
+    // Sequence numbers follow the order loads started in, not the order the current index moved in,
+    // so racing skips can finish loading a track that was skipped past after the one they settled on
+    let still_wanted = async || {
+      let wanted = match role {
+        QueueRole::Current { .. } => self
+          .tracks_to_queue()
+          .await
+          .map(|(current, _)| current.track_id()),
+        QueueRole::Next { .. } => self.next_track_to_queue().await,
+      };
+      wanted == Some(track.track_id())
+    };
+    let outdated = |role| match role {
+      QueueRole::Current { .. } => {
+        sequence
+          <= self
+            .controls
+            .accepted_current_sequence
+            .load(Ordering::Acquire)
+      }
+      QueueRole::Next { .. } => sequence <= self.controls.accepted_sequence.load(Ordering::Acquire),
+    };
+    let waits = matches!(
+      role,
+      QueueRole::Next {
+        wait_for_empty_queue: false
+      }
+    );
     let mut source_queue = self.controls.source_queue.lock().await;
 
-    while !wait_for_empty_queue && source_queue.is_queued() {
-      // Unlock the queue mutex
+    // Another call may have queued this track already, then the current track would have to end before this one is queued again
+    while waits
+      && source_queue.is_queued()
+      && source_queue.queued_track_id() != Some(track.track_id())
+      && !outdated(role)
+      && still_wanted().await
+    {
+      // Registered while the queue is locked, so the source can't be played before the waiter is added
+      let (tx, rx) = oneshot::oneshot();
+      self
+        .controls
+        .queue_waiters
+        .lock()
+        .expect("Queue waiters lock should not be poisoned")
+        .push(tx);
       mem::drop(source_queue);
 
-      smol::Timer::after(controlled_source::SOURCE_UPDATE_INTERVAL * 3).await;
+      let woken = async {
+        let _ = rx.await;
+      };
+      // The queue is checked again after the timeout, in case a change to it didn't wake this
+      let timeout = async {
+        smol::Timer::after(QUEUE_WAIT_TIMEOUT).await;
+      };
+      (woken, timeout).race().await;
       source_queue = self.controls.source_queue.lock().await;
     }
 
+    let already_queued = waits && source_queue.queued_track_id() == Some(track.track_id());
+    if outdated(role) || already_queued || !still_wanted().await {
+      println!(
+        "Discarding outdated source for {:?}",
+        track.loaded_track().file_path()
+      );
+      return Ok(());
+    }
+
+    if let QueueRole::Current { .. } = role {
+      self
+        .controls
+        .accepted_current_sequence
+        .store(sequence, Ordering::Release);
+    }
+    // A current track replaces a next track queued by a newer load, which was for the track it replaces
+    self
+      .controls
+      .accepted_sequence
+      .fetch_max(sequence, Ordering::AcqRel);
     source_queue.invalidate();
-    *source_queue = SourceQueueState::Queued(source);
+    *source_queue = SourceQueueState::Queued(track.track_id(), source);
+    if let QueueRole::Current { skip_playing: true } = role {
+      self.controls.skip_playing_source();
+    }
 
     Ok(())
   }
 
+  /// The current track and the track that plays after it
+  ///
+  /// There is no next track while looping the current one, so no decoder is kept open for a track that won't play
+  async fn tracks_to_queue(&self) -> Option<(TrackInstance, Option<TrackInstance>)> {
+    let (current_track, next_track) = self.tracks.get_tracks_to_queue().await?;
+    let next_track = next_track.filter(|_| self.loop_mode() != LoopMode::Track);
+    Some((current_track, next_track))
+  }
+
   /// Returns true if there was a current track to queue
   ///
   /// If `use_queued` is true this function will use the source waiting in queue instead of reloading the current track
-  /// Because this function queues the next track, `use_queued` should only be true if the `current_track_index` is exactly one more
+  /// Because this function queues the next track, `use_queued` should only be true if the current index is exactly one more
   /// than the last call to `queue_current_track`
+  ///
+  /// Only failing to load the current track is an error. If the next track can't be loaded it is marked as failed,
+  /// and is loaded again once it becomes the current track
   async fn queue_current_track(&self, use_queued: bool) -> Result<bool, LoadTrackError> {
-    let Some((current_track, next_track)) = self
-      .tracks
-      .get_tracks_to_queue(self.current_track_index.load(Ordering::Acquire))
-      .await
-    else {
+    self.queue_current_track_after(use_queued, false).await
+  }
+
+  /// `queue_current_track`, where `source_ended` is true if the previous source already ended on its own
+  ///
+  /// The output moves on to the queued source by itself then, so it must not be skipped even if the output
+  /// hasn't taken it from the queue yet
+  async fn queue_current_track_after(
+    &self,
+    use_queued: bool,
+    source_ended: bool,
+  ) -> Result<bool, LoadTrackError> {
+    // The current entry replaces a detached track
+    *self.detached.lock().await = None;
+
+    let Some((current_track, next_track)) = self.tracks_to_queue().await else {
       return Ok(false);
     };
+    // A track that failed to load as the next track was never queued
+    let use_queued = use_queued && !current_track.state().failed;
 
-    let load_necessary = {
+    // `Some` if the current track has to be loaded, with whether the playing source should be skipped for it.
+    // The skip is left to `queue_track`, since racing skips may not queue the source they loaded
+    let load = {
       let mut source_queue = self.controls.source_queue.lock().await;
       match *source_queue {
         // Skip the current track so the queued one plays
-        SourceQueueState::Queued(_) => {
-          self.controls.to_skip.fetch_add(1, Ordering::AcqRel);
-          if !use_queued {
+        SourceQueueState::Queued(queued_id, _) => {
+          // Skips that raced each other may have moved past the queued track
+          let use_queued = use_queued && queued_id == current_track.track_id();
+          if use_queued {
+            if !source_ended {
+              self.controls.skip_playing_source();
+            }
+            None
+          } else {
             source_queue.invalidate();
+            self.controls.wake_queue_waiters();
+            Some(!source_ended)
           }
-
-          !use_queued
         }
 
         // No queued track means the current track ended and the next (queued) track began playing
         // If `use_queued` is false skip and load a new track
         SourceQueueState::Playing => {
-          if !use_queued {
-            self.controls.to_skip.fetch_add(1, Ordering::AcqRel);
-          }
-
-          !use_queued
+          // A skip can't tell which source is playing, the track it skips to may not have been queued yet
+          let use_queued = use_queued && source_ended;
+          (!use_queued).then_some(true)
         }
 
         // No track playing, load the current track
-        SourceQueueState::None => true,
+        SourceQueueState::None => Some(false),
       }
     };
 
-    if load_necessary {
-      self.queue_track(&current_track, true).await?;
+    if let Some(skip_playing) = load {
+      let queued = self
+        .queue_track(&current_track, QueueRole::Current { skip_playing })
+        .await;
+      if queued.is_err() && skip_playing {
+        // The current index already moved past the playing track, so it must not keep playing
+        let _source_queue = self.controls.source_queue.lock().await;
+        self.controls.skip_playing_source();
+      }
+      queued?;
     }
 
     if let Some(next_track) = next_track {
-      self.queue_track(&next_track, false).await?;
+      self.prequeue_track(&next_track, false).await;
     }
 
     Ok(true)
   }
 
+  /// Queues `track` to play after the current source like `queue_track`, logging instead of failing if it can't be loaded
+  ///
+  /// The current track can still play to its end, so an error here must not stop playback
+  async fn prequeue_track(&self, track: &TrackInstance, wait_for_empty_queue: bool) {
+    if let Err(error) = self
+      .queue_track(
+        track,
+        QueueRole::Next {
+          wait_for_empty_queue,
+        },
+      )
+      .await
+    {
+      eprintln!(
+        "Could not queue the next track {:?}: {error}",
+        track.loaded_track().file_path()
+      );
+    }
+  }
+
   pub fn playback_state(&self) -> PlaybackState {
     self.controls.playback_state.load(Ordering::Acquire)
   }
@@ -240,6 +706,7 @@ impl Player {
       .controls
       .playback_state
       .swap(new_state, Ordering::Relaxed);
+    self.controls.shared.set_playback_state(new_state);
     if prev_state != new_state {
       self.emit(Event::PlaybackStateChanged(new_state))?;
       println!("Setting playback state to {new_state:?}")
@@ -250,6 +717,11 @@ impl Player {
 
   pub async fn play(&self) -> Result<(), PlayerError> {
     if self.is_stopped() {
+      if self.stopped_at_end().await {
+        self.tracks.wrap_current(false).await;
+        self.emit_track_changed().await?;
+      }
+
       let had_tracks = self.queue_current_track(true).await?;
       if !had_tracks {
         return Ok(());
@@ -257,6 +729,8 @@ impl Player {
     }
 
     self.set_playback_state(PlaybackState::Playing)?;
+    *self.stop_reason.lock().await = None;
+    *self.ended_on.lock().await = None;
 
     Ok(())
   }
@@ -282,45 +756,226 @@ impl Player {
     Ok(())
   }
 
-  pub async fn stop(&self) -> Result<(), PlayerError> {
+  pub async fn stop(&self, reason: StopReason) -> Result<(), PlayerError> {
+    // For other reasons, the current track already finished or never played
+    if matches!(reason, StopReason::UserRequested | StopReason::QueueCleared) {
+      self.record_listened(TrackOutcome::Left).await;
+    }
+
     self.clear_source_queue().await;
+
+    // The source that would have applied a pending seek is gone, so fail it instead of leaving the request waiting
+    if let Some((_, mut tx)) = self.controls.seek_position.lock().await.take() {
+      let _ = tx.send(Err(SeekError::PlaybackStopped));
+    }
+
+    let prev_reason = self.stop_reason.lock().await.replace(reason.clone());
     self.set_playback_state(PlaybackState::Stopped)?;
+    if prev_reason.as_ref() != Some(&reason) {
+      println!("Playback stopped: {reason:?}");
+      self.emit(Event::PlaybackStopped(reason))?;
+    }
+
+    // Playback starts again from the entry that took a detached track's place
+    if self.detached.lock().await.take().is_some() {
+      self.emit_track_changed().await?;
+    }
+
     *self.controls.position.lock().await = Duration::ZERO;
+    self.controls.shared.set_position(Duration::ZERO);
     Ok(())
   }
 
+  /// `None` if playback has started since it was last stopped
+  pub async fn stop_reason(&self) -> Option<StopReason> {
+    self.stop_reason.lock().await.clone()
+  }
+
+  /// If playback stopped at the end of the track list and hasn't moved from the track it stopped on,
+  /// so starting it again plays from the first track
+  async fn stopped_at_end(&self) -> bool {
+    let Some(ended_on) = *self.ended_on.lock().await else {
+      return false;
+    };
+
+    self.is_stopped() && self.current_track_id().await == Some(ended_on)
+  }
+
+  pub async fn current_entry(&self) -> CurrentEntry {
+    let track = self.current_track().await;
+    let will_play_next = !self.stopped_at_end().await;
+    let up_next = if will_play_next {
+      track.clone()
+    } else {
+      self.tracks.first_track().await
+    };
+
+    CurrentEntry {
+      index: self.current_track_index(),
+      track,
+      will_play_next,
+      up_next,
+      detached: self.track_detached().await,
+    }
+  }
+
+  /// The detached track while one is playing, so the metadata matches the audio
   pub async fn current_track(&self) -> Option<Track> {
+    if let Some(detached) = &*self.detached.lock().await {
+      return Some(detached.loaded_track().clone_track());
+    }
+
+    self.tracks.current_track().await
+  }
+
+  /// If the playing track was removed from the track list, and is playing until it ends
+  pub async fn track_detached(&self) -> bool {
+    self.detached.lock().await.is_some()
+  }
+
+  /// May be stale if the track list is being changed
+  pub fn current_track_index(&self) -> usize {
+    self.tracks.current_index()
+  }
+
+  pub async fn current_track_id(&self) -> Option<TrackId> {
+    if let Some(detached) = &*self.detached.lock().await {
+      return Some(detached.track_id());
+    }
+
+    self.tracks.current_track_id().await
+  }
+
+  pub async fn set_track_gain(
+    &self,
+    track_id: TrackId,
+    gain_db: Option<f32>,
+  ) -> Result<(), PlayerError> {
     self
       .tracks
-      .get_track(self.current_track_index.load(Ordering::Acquire))
+      .update_instance(track_id, |state| state.gain_db = gain_db)
+      .await?;
+    println!("Gain of track {track_id:?} set to {gain_db:?}");
+
+    self
+      .invalidate_prequeued_if(|queued_id| queued_id == track_id)
       .await
   }
 
-  pub fn current_track_index(&self) -> usize {
-    self.current_track_index.load(Ordering::Acquire)
+  /// Loads the track after the current one again, replacing the source waiting in the queue
+  ///
+  /// If there is no longer a track after the current one, the waiting source is dropped
+  async fn requeue_next_track(&self) -> Result<(), PlayerError> {
+    if self.is_stopped() {
+      return Ok(());
+    }
+
+    let detached_id = self
+      .detached
+      .lock()
+      .await
+      .as_ref()
+      .map(TrackInstance::track_id);
+    match (self.tracks_to_queue().await, detached_id.is_some()) {
+      // The entry that took a detached track's place plays after it
+      (Some((current_track, _)), true) if self.loop_mode() != LoopMode::Track => {
+        // A track is queued while skipping to it, so a detached track may not have started yet
+        let detached_queued =
+          self.controls.source_queue.lock().await.queued_track_id() == detached_id;
+        self.prequeue_track(&current_track, !detached_queued).await
+      }
+      // Nothing plays after a detached track that loops, or that was at the end of the track list
+      (_, true) => {
+        let mut source_queue = self.controls.source_queue.lock().await;
+        if source_queue.queued_track_id() != detached_id {
+          source_queue.invalidate();
+          self.controls.wake_queue_waiters();
+        }
+      }
+      (Some((_, Some(next_track))), false) => self.prequeue_track(&next_track, true).await,
+      (Some((current_track, None)), false) => {
+        let mut source_queue = self.controls.source_queue.lock().await;
+        // The current track is queued while skipping to it, and must not be dropped
+        if source_queue.queued_track_id() != Some(current_track.track_id()) {
+          source_queue.invalidate();
+          self.controls.wake_queue_waiters();
+        }
+      }
+      (None, false) => (),
+    }
+
+    Ok(())
+  }
+
+  /// Rebuilds the pre-queued next source if `predicate` returns true for its track id
+  ///
+  /// Requests that change how sources are built should call this, so the change applies to the next track
+  /// instead of the one after it. The currently playing source is never affected.
+  pub async fn invalidate_prequeued_if(
+    &self,
+    predicate: impl FnOnce(TrackId) -> bool,
+  ) -> Result<(), PlayerError> {
+    let queued_id = self.controls.source_queue.lock().await.queued_track_id();
+    let Some(queued_id) = queued_id else {
+      return Ok(());
+    };
+
+    // The current track is queued while skipping to it, and must not be reloaded
+    if !predicate(queued_id) || self.current_track_id().await == Some(queued_id) {
+      return Ok(());
+    }
+
+    println!("Reloading queued track {queued_id:?}");
+    self.requeue_next_track().await
+  }
+
+  async fn emit_track_changed(&self) -> Result<(), PlayerError> {
+    self.controls.shared.next_track_generation();
+    self.emit(Event::TrackChanged(self.current_track().await))
+  }
+
+  /// Emits `TrackChanged` if the current track is no longer the `prev_track_id` instance
+  ///
+  /// Two entries of the same file are different tracks, so moving between them is a change
+  async fn emit_if_track_changed(&self, prev_track_id: Option<TrackId>) -> Result<(), PlayerError> {
+    if self.current_track_id().await != prev_track_id {
+      self.emit_track_changed().await?;
+    }
+
+    Ok(())
+  }
+
+  /// Moves to the other end of the track list while stopped if looping, otherwise stays on the track at this end
+  ///
+  /// Nothing is playing, so this doesn't stop playback, and the current track is what the next `play` starts
+  async fn clamp_or_wrap_stopped(&self, reverse: bool) {
+    let should_loop = !matches!(
+      self.controls.loop_mode.load(Ordering::Acquire),
+      LoopMode::None
+    );
+
+    self.tracks.wrap_current(should_loop == reverse).await;
   }
 
   async fn stop_or_wrap_track(&self, reverse: bool) -> Result<(), PlayerError> {
     let printed_position = if reverse { "beginning" } else { "end" };
     let printed_loop_position = if reverse { "end" } else { "beginning" };
 
+    // An empty track list can't loop, it may have been cleared while skipping
     let should_loop = !matches!(
       self.controls.loop_mode.load(Ordering::Acquire),
       LoopMode::None
-    );
-
-    // Don't skip to end if loop is off
-    let new_index = if should_loop && reverse {
-      self.tracks.len() - 1
-    } else {
-      0
-    };
+    ) && self.tracks.len() > 0;
 
-    self.current_track_index.store(new_index, Ordering::Release);
+    // Without looping, playback stays on the track it stopped at, and starts from the first track when played again
+    self.tracks.wrap_current(should_loop == reverse).await;
 
     if !should_loop {
       println!("Track list reached {printed_position}, stopping");
-      self.stop().await?;
+      if !reverse {
+        *self.ended_on.lock().await = self.current_track_id().await;
+      }
+      self.stop(StopReason::EndOfQueue).await?;
     } else {
       println!("Track list reached {printed_position}, looping to {printed_loop_position}");
 
@@ -332,48 +987,122 @@ impl Player {
     Ok(())
   }
 
-  pub async fn go_to_next_track(&self) -> Result<(), PlayerError> {
-    let new_index = 1 + self.current_track_index.fetch_add(1, Ordering::Release);
-
+  /// Adds the current track to the session stats, must be called before the player moves away from it
+  async fn record_listened(&self, outcome: TrackOutcome) {
     if self.is_stopped() {
-      if new_index >= self.tracks.len() {
-        self.stop_or_wrap_track(false).await?;
-      }
-
-      return Ok(());
+      return;
     }
 
-    if !self.queue_current_track(true).await? {
+    let Some(track) = self.current_track().await else {
+      return;
+    };
+
+    let listened = match (outcome, track.total_duration) {
+      // The position is only updated periodically, so it may stop short of the end
+      (TrackOutcome::Finished, Some(total_duration)) => total_duration,
+      _ => self.position().await,
+    };
+
+    self.session.record(&track, listened, outcome);
+  }
+
+  pub fn session_stats(&self) -> SessionStats {
+    self.session.stats()
+  }
+
+  pub fn reset_session_stats(&self) {
+    self.session.reset();
+  }
+
+  /// `go_to_next_track`, counted as a skip in the session stats
+  pub async fn skip_to_next_track(&self, count: usize) -> Result<(), PlayerError> {
+    self.set_stop_after_current(false)?;
+    self.record_listened(TrackOutcome::Skipped).await;
+    self.go_to_next_track(count, false).await
+  }
+
+  /// Moves forward `count` tracks, stopping or wrapping once if that goes past the end
+  ///
+  /// `source_ended` is true if the current track's source ended on its own, see `queue_current_track_after`.
+  /// While stopped, going past the end stays on the last track unless looping
+  async fn go_to_next_track(&self, count: usize, source_ended: bool) -> Result<(), PlayerError> {
+    // The entry that took a detached track's place is already current, and counts as the first track
+    let detached = self.detached.lock().await.take().is_some();
+    let in_range = match count.saturating_sub(usize::from(detached)) {
+      0 => self.tracks.current_index() < self.tracks.len(),
+      count => self.tracks.advance(count).await,
+    };
+    // Nothing is queued after a track that is looping
+    let use_queued = count == 1 && self.loop_mode() != LoopMode::Track;
+
+    if self.is_stopped() {
+      if !in_range {
+        self.clamp_or_wrap_stopped(false).await;
+      }
+    } else if !self
+      .queue_current_track_after(use_queued, source_ended)
+      .await?
+    {
+      // Only the track right after the current one is waiting in the queue
       self.stop_or_wrap_track(false).await?;
     }
 
-    Ok(())
+    self.emit_track_changed().await
   }
 
-  pub async fn go_to_previous_track(&self, soft: bool) -> Result<(), PlayerError> {
+  /// Moves back `count` tracks, stopping or wrapping once if that goes past the beginning
+  ///
+  /// If `soft` is set and the current track has played for a while, restarting it counts as one of the tracks.
+  /// While stopped, going past the beginning stays on the first track unless looping
+  pub async fn go_to_previous_track(&self, soft: bool, count: usize) -> Result<(), PlayerError> {
     const RESTART_THRESHOLD: Duration = Duration::from_secs(5);
 
-    if soft && self.position().await > RESTART_THRESHOLD {
+    self.set_stop_after_current(false)?;
+    let restart = soft && self.position().await > RESTART_THRESHOLD;
+    let count = if restart { count - 1 } else { count };
+
+    if count == 0 {
       self.seek(SeekPosition::To(Duration::ZERO)).await
     } else {
-      let current_index = self.current_track_index.load(Ordering::Acquire);
-
-      if current_index == 0 {
-        self.stop_or_wrap_track(true).await?;
-      } else {
-        self
-          .current_track_index
-          .store(current_index - 1, Ordering::Release);
+      self.record_listened(TrackOutcome::Left).await;
+      // The current index already moved off a detached track, so going back starts from there
+      *self.detached.lock().await = None;
 
+      if self.tracks.retreat(count).await {
         if !self.is_stopped() {
           self.queue_current_track(false).await?;
         }
+      } else if self.is_stopped() {
+        self.clamp_or_wrap_stopped(true).await;
+      } else {
+        self.stop_or_wrap_track(true).await?;
       }
 
-      Ok(())
+      self.emit_track_changed().await
     }
   }
 
+  /// Jumps to the track at `index` in play order, which is the shuffled order if shuffle is on
+  pub async fn go_to_track(&self, index: usize) -> Result<(), PlayerError> {
+    let len = self.tracks.len();
+    if index >= len {
+      return Err(PlayerError::TrackIndexOutOfRange { index, len });
+    }
+
+    self.record_listened(TrackOutcome::Skipped).await;
+    self
+      .tracks
+      .go_to(index)
+      .await
+      .map_err(|len| PlayerError::TrackIndexOutOfRange { index, len })?;
+
+    if !self.is_stopped() {
+      self.queue_current_track(false).await?;
+    }
+
+    self.emit_track_changed().await
+  }
+
   pub async fn shuffle(&self) -> bool {
     self.tracks.shuffle_enabled()
   }
@@ -381,22 +1110,43 @@ impl Player {
   pub async fn set_shuffle(&self, shuffle: bool) -> Result<(), PlayerError> {
     let prev_shuffle = self.shuffle().await;
     if shuffle != prev_shuffle {
-      let current_index = self.current_track_index.load(Ordering::Acquire);
-      let new_index = self.tracks.set_shuffle(shuffle, current_index).await?;
-
-      self.current_track_index.store(new_index, Ordering::Release);
+      let change = self.tracks.set_shuffle(shuffle).await?;
 
+      self.emit(change.into())?;
       self.emit(Event::ShuffleChanged(shuffle))?;
       println!("Shuffle set to {shuffle}");
 
-      if !self.is_stopped() {
-        let current_track_index = self.current_track_index.load(Ordering::Acquire);
-        if let Some((_, Some(next_track))) =
-          self.tracks.get_tracks_to_queue(current_track_index).await
-        {
-          self.queue_track(&next_track, true).await?;
-        }
-      }
+      // The next track is different after shuffling, so it is queued even if nothing was queued before
+      self.requeue_next_track().await?;
+    }
+
+    Ok(())
+  }
+
+  pub async fn queue_filter(&self) -> Option<FilterExpr> {
+    self.tracks.filter().await
+  }
+
+  /// Returns the number of entries that match `filter`
+  pub async fn set_queue_filter(&self, filter: Option<FilterExpr>) -> Result<usize, PlayerError> {
+    let matching = self.tracks.set_filter(filter.clone()).await?;
+
+    match &filter {
+      Some(filter) => println!("Queue filter set to {filter}, {matching} tracks match"),
+      None => println!("Queue filter cleared"),
+    }
+    self.emit(Event::QueueFilterChanged(filter))?;
+
+    // The track after the current one may have been filtered out, or be different now
+    self.requeue_next_track().await?;
+    Ok(matching)
+  }
+
+  /// Clears the queue filter if the track list no longer has any entry matching it
+  async fn clear_unmatched_filter(&self) -> Result<(), PlayerError> {
+    if self.tracks.clear_unmatched_filter().await {
+      println!("No track matches the queue filter anymore, clearing it");
+      self.emit(Event::QueueFilterChanged(None))?;
     }
 
     Ok(())
@@ -406,37 +1156,272 @@ impl Player {
     self.controls.loop_mode.load(Ordering::Relaxed)
   }
 
+  /// The only place the end behavior is worked out, every setting it depends on must emit `EndBehaviorChanged`
+  pub fn end_behavior(&self) -> EndBehavior {
+    end_behavior_for(self.loop_mode(), self.stop_after_current())
+  }
+
+  /// Emits `EndBehaviorChanged` if the end behavior is no longer `prev_behavior`
+  fn emit_if_end_behavior_changed(&self, prev_behavior: EndBehavior) -> Result<(), PlayerError> {
+    let end_behavior = self.end_behavior();
+    if end_behavior != prev_behavior {
+      self.emit(Event::EndBehaviorChanged(end_behavior))?;
+    }
+
+    Ok(())
+  }
+
   pub async fn set_loop_mode(&self, loop_mode: LoopMode) -> Result<(), PlayerError> {
+    let prev_behavior = self.end_behavior();
     let prev_mode = self.controls.loop_mode.swap(loop_mode, Ordering::Relaxed);
     if loop_mode != prev_mode {
       self.emit(Event::LoopModeChanged(loop_mode))?;
       println!("Loop mode set to {loop_mode:?}");
     }
 
-    Ok(())
+    // Leaving track looping queues the next track now instead of loading it at the boundary,
+    // and entering it drops the queued next track, which would not play
+    if (prev_mode == LoopMode::Track) != (loop_mode == LoopMode::Track) {
+      self.requeue_next_track().await?;
+    }
+
+    self.emit_if_end_behavior_changed(prev_behavior)
   }
 
   pub async fn volume(&self) -> f32 {
     *self.controls.volume.lock().await
   }
 
+  pub async fn rate(&self) -> f32 {
+    *self.controls.rate.lock().await
+  }
+
+  pub async fn set_rate(&self, rate: f32) -> Result<(), PlayerError> {
+    if !rate.is_finite() {
+      return Err(PlayerError::InvalidRate(rate));
+    }
+
+    let clamped_rate = rate.clamp(MIN_RATE, MAX_RATE);
+    let prev_rate = mem::replace(&mut *self.controls.rate.lock().await, clamped_rate);
+    self.controls.shared.set_rate(clamped_rate);
+
+    if clamped_rate != prev_rate {
+      self.emit(Event::RateChanged(clamped_rate))?;
+      println!("Rate set to {clamped_rate:?}");
+    }
+
+    Ok(())
+  }
+
+  pub fn stop_after_current(&self) -> bool {
+    self.controls.stop_after_current.load(Ordering::Acquire)
+  }
+
+  /// Pauses at the end of the current track instead of playing the next one
+  ///
+  /// Cleared once it applies, and by skipping tracks or replacing the track list
+  pub fn set_stop_after_current(&self, stop_after_current: bool) -> Result<(), PlayerError> {
+    let prev_behavior = self.end_behavior();
+    let prev = self
+      .controls
+      .stop_after_current
+      .swap(stop_after_current, Ordering::AcqRel);
+    if prev != stop_after_current {
+      self.emit(Event::StopAfterCurrentChanged(stop_after_current))?;
+      println!("Stop after current track set to {stop_after_current}");
+    }
+
+    self.emit_if_end_behavior_changed(prev_behavior)
+  }
+
+  pub fn normalization(&self) -> NormalizationMode {
+    self.controls.normalization.load(Ordering::Relaxed)
+  }
+
+  pub fn set_normalization(&self, mode: NormalizationMode) {
+    let prev_mode = self.controls.normalization.swap(mode, Ordering::Relaxed);
+    if mode != prev_mode {
+      println!("Normalization set to {mode:?}");
+    }
+  }
+
+  /// Sets the gain in dB for tracks without ReplayGain tags, used while normalization is enabled
+  pub fn set_normalization_fallback_gain(&self, gain_db: f32) {
+    *self.controls.normalization_fallback.lock_blocking() = 10f32.powf(gain_db / 20.0);
+  }
+
   pub async fn set_volume(&self, volume: f32) -> Result<(), PlayerError> {
     let clamped_volume = volume.clamp(0.0, 1.0);
+    self.store_volume(clamped_volume).await?;
+
+    if self.controls.system_volume.load(Ordering::Relaxed) {
+      self.system_volume.set_volume(clamped_volume).await?;
+    }
+
+    Ok(())
+  }
+
+  /// Updates the volume reported to clients, without applying it to the system mixer
+  async fn store_volume(&self, volume: f32) -> Result<(), PlayerError> {
     let prev_volume = {
       let mut volume_control = self.controls.volume.lock().await;
       let prev_volume = *volume_control;
-      *volume_control = clamped_volume;
+      *volume_control = volume;
+      self.controls.shared.set_volume(volume);
       prev_volume
     };
 
-    if clamped_volume != prev_volume {
-      self.emit(Event::VolumeChanged(clamped_volume))?;
+    if volume != prev_volume {
+      self.emit(Event::VolumeChanged(volume))?;
       println!("volume set to {volume:?}");
     }
 
     Ok(())
   }
 
+  pub fn set_volume_backend(&self, volume_backend: VolumeBackend) {
+    self.controls.system_volume.store(
+      matches!(volume_backend, VolumeBackend::System),
+      Ordering::Relaxed,
+    );
+  }
+
+  /// Keeps the volume in sync with the server's stream in the system mixer, while that is the volume backend
+  ///
+  /// A new stream, such as after the output reconnects, is set to the player's volume,
+  /// and changes made by other mixers are reported as `VolumeChanged`
+  pub async fn sync_system_volume(&self) -> Result<(), PlayerError> {
+    if !self.controls.system_volume.load(Ordering::Relaxed) {
+      return std::future::pending().await;
+    }
+
+    loop {
+      let result = self
+        .system_volume
+        .watch(|change| self.handle_stream_change(change))
+        .await;
+
+      if let Err(error) = result {
+        eprintln!(
+          "Warning: Not watching the system volume, retrying in {}s: {error}",
+          SYSTEM_VOLUME_RETRY.as_secs()
+        );
+      }
+      Timer::after(SYSTEM_VOLUME_RETRY).await;
+    }
+  }
+
+  async fn handle_stream_change(&self, change: StreamChange) {
+    let result = match change {
+      StreamChange::Opened => self.set_volume(self.volume().await).await,
+      StreamChange::VolumeChanged(volume) => self.store_volume(volume).await,
+    };
+
+    if let Err(error) = result {
+      eprintln!("Warning: Failed to sync the system volume: {error}");
+    }
+  }
+
+  pub fn set_max_queue_length(&self, max_length: usize) {
+    self.tracks.set_max_length(max_length);
+  }
+
+  pub fn set_track_ending_notice(&self, notice: Duration) {
+    let micros = u64::try_from(notice.as_micros()).unwrap_or(u64::MAX);
+    self
+      .controls
+      .track_ending_notice_micros
+      .store(micros, Ordering::Relaxed);
+  }
+
+  pub fn set_removed_current(&self, removed_current: RemovedCurrent) {
+    self.skip_removed_current.store(
+      matches!(removed_current, RemovedCurrent::Skip),
+      Ordering::Relaxed,
+    );
+  }
+
+  pub fn set_gapless_policy(&self, gapless_policy: GaplessPolicy) {
+    *self
+      .gapless_policy
+      .lock()
+      .expect("Gapless policy lock should not be poisoned") = gapless_policy;
+  }
+
+  /// How playback will move from the playing track to the one after it, `None` if it won't move to another track
+  pub async fn next_transition(&self) -> Option<TransitionMode> {
+    if matches!(
+      self.end_behavior(),
+      EndBehavior::WillLoopTrack | EndBehavior::WillStopAfterCurrent
+    ) {
+      return None;
+    }
+
+    let (current_track, next_track) = self.tracks_to_queue().await?;
+    // The entry that took a detached track's place plays after it
+    let (current_track, next_track) = match self.detached.lock().await.clone() {
+      Some(detached) => (detached, current_track),
+      None => (current_track, next_track?),
+    };
+
+    let policy = *self
+      .gapless_policy
+      .lock()
+      .expect("Gapless policy lock should not be poisoned");
+    Some(transition::transition_mode(
+      policy,
+      current_track.loaded_track().metadata(),
+      next_track.loaded_track().metadata(),
+    ))
+  }
+
+  pub fn set_decode_ahead(&self, decode_ahead: Duration) {
+    let micros = u64::try_from(decode_ahead.as_micros()).unwrap_or(u64::MAX);
+    self.decode_ahead_micros.store(micros, Ordering::Relaxed);
+  }
+
+  pub fn set_high_quality_resampling(&self, high_quality_resampling: bool) {
+    self
+      .high_quality_resampling
+      .store(high_quality_resampling, Ordering::Relaxed);
+  }
+
+  pub fn bit_perfect(&self) -> bool {
+    self.controls.bit_perfect.load(Ordering::Acquire)
+  }
+
+  /// Returns the previous value
+  pub fn set_bit_perfect(&self, bit_perfect: bool) -> bool {
+    let prev_bit_perfect = self
+      .controls
+      .bit_perfect
+      .swap(bit_perfect, Ordering::AcqRel);
+    if bit_perfect != prev_bit_perfect {
+      println!("Bit perfect output set to {bit_perfect}");
+    }
+
+    prev_bit_perfect
+  }
+
+  /// Waits for the audio output to request that the output stream be reopened with a new sample rate
+  ///
+  /// In bit perfect mode, the queued source will not start until the stream is reopened or the rate is declined
+  pub async fn recieve_output_rate_request(&self) -> Result<SampleRate, PlayerError> {
+    self
+      .output_rate_rx
+      .recv()
+      .await
+      .map_err(|_| PlayerError::OutputRateChannelClosed)
+  }
+
+  /// Lets the queued source play resampled on the current output stream
+  pub fn decline_output_rate(&self, sample_rate: SampleRate) {
+    self
+      .controls
+      .declined_output_rate
+      .store(sample_rate, Ordering::Release);
+  }
+
   pub async fn position(&self) -> Duration {
     *self.controls.position.lock().await
   }
@@ -449,53 +1434,461 @@ impl Player {
       return Ok(());
     }
 
+    // A paused source may not be pulled from again until playback resumes, so the seek can't be waited for
+    if matches!(self.playback_state(), PlaybackState::Paused) {
+      let position = self.resolve_seek(seek_position).await?;
+      println!("Seeked {seek_position:?} while paused");
+      return self.seek_without_waiting(position).await;
+    }
+
     let (tx, rx) = oneshot::oneshot();
     *self.controls.seek_position.lock().await = Some((seek_position, tx));
 
-    rx.await.map_err(|_| SeekError::ErrorChannelClosed)??;
+    let position = rx.await.map_err(|_| SeekError::ErrorChannelClosed)??;
     println!("Seeked {seek_position:?}");
+    self.emit(Event::Seeked(position))?;
+
+    Ok(())
+  }
+
+  /// The position `seek_position` refers to, limited to the current track's duration if it is known
+  async fn resolve_seek(&self, seek_position: SeekPosition) -> Result<Duration, PlayerError> {
+    let total_duration = self
+      .current_track()
+      .await
+      .and_then(|track| track.total_duration);
+    let position = seek_position
+      .resolve(self.position().await, total_duration)
+      .ok_or(SeekError::UnknownDuration)?;
+
+    match total_duration {
+      Some(total_duration) => Ok(position.min(total_duration)),
+      None => Ok(position),
+    }
+  }
+
+  /// Reports `position` right away, and leaves the seek for the source to apply the next time it runs
+  async fn seek_without_waiting(&self, position: Duration) -> Result<(), PlayerError> {
+    let (tx, _) = oneshot::oneshot();
+    let replaced = self
+      .controls
+      .seek_position
+      .lock()
+      .await
+      .replace((SeekPosition::To(position), tx));
+
+    // A seek that was still waiting to be applied ends up at the new position instead
+    if let Some((_, mut replaced_tx)) = replaced {
+      let _ = replaced_tx.send(Ok(position));
+    }
+
+    *self.controls.position.lock().await = position;
+    self.controls.shared.set_position(position);
+    self.emit(Event::Seeked(position))
+  }
+
+  /// Plays a sine wave through the same queue, controls, and output as tracks, then stops playback again
+  ///
+  /// Only allowed while stopped, so it never replaces a track. Returns how much of the tone the output played
+  pub async fn play_test_tone(
+    &self,
+    frequency: f32,
+    duration: Duration,
+  ) -> Result<TestToneResult, PlayerError> {
+    if !self.is_stopped() {
+      return Err(PlayerError::TestToneWhilePlaying);
+    }
+
+    let sample_rate = self.output_rate.load(Ordering::Relaxed);
+    if !(20.0..sample_rate as f32 / 2.0).contains(&frequency) {
+      return Err(PlayerError::InvalidFrequency(frequency));
+    }
+
+    let tone = TestTone::new(frequency, duration.min(MAX_TEST_TONE), sample_rate);
+    let total_frames = tone.total_frames();
+    let played = tone.played();
+    let source = wrap_source(tone, self.controls.clone(), self.source_tx.clone(), None);
+
+    let (tx, rx) = oneshot::oneshot();
+    *self.test_tone_done.lock().await = Some(tx);
+    {
+      let mut source_queue = self.controls.source_queue.lock().await;
+      self.controls.to_skip.store(0, Ordering::Release);
+      *source_queue = SourceQueueState::Queued(TEST_TONE_ID, Box::new(source));
+    }
+    println!("Playing a {frequency} Hz test tone");
+    self.set_playback_state(PlaybackState::Playing)?;
+
+    let finished = async {
+      let _ = rx.await;
+    };
+    // Gives the output time to start, and a stalled output time to fail, before giving up
+    let timeout = async {
+      smol::Timer::after(duration.min(MAX_TEST_TONE) + Duration::from_secs(3)).await;
+    };
+    (finished, timeout).race().await;
+
+    self.test_tone_done.lock().await.take();
+    if played.load(Ordering::Relaxed) == 0 {
+      // The tone never started, so there is no source for a skip to apply to
+      self.detach_sources().await;
+    } else {
+      self.clear_source_queue().await;
+    }
+    self.set_playback_state(PlaybackState::Stopped)?;
+    *self.controls.position.lock().await = Duration::ZERO;
+    self.controls.shared.set_position(Duration::ZERO);
+
+    let played_frames = played.load(Ordering::Relaxed);
+    println!("Test tone played {played_frames} of {total_frames} frames");
+
+    Ok(TestToneResult {
+      played: Duration::from_secs_f64(played_frames as f64 / sample_rate as f64),
+      completed: played_frames >= total_frames,
+    })
+  }
+
+  /// Plays `track` over the track list without adding it, holding the current track until it ends
+  ///
+  /// The track list, current index, and position are not changed. Only one oneshot plays at a time,
+  /// others are rejected until `Event::InterruptFinished`
+  pub async fn play_oneshot(&self, track: Arc<LoadedTrack>) -> Result<(), PlayerError> {
+    if self.oneshot_active.swap(true, Ordering::AcqRel) {
+      return Err(PlayerError::OneshotActive);
+    }
+
+    let decoder = match TrackDecoder::new(track.clone(), &self.scheduler).await {
+      Ok(decoder) => decoder,
+      Err(error) => {
+        self.oneshot_active.store(false, Ordering::Release);
+        return Err(error.into());
+      }
+    };
+
+    // Follows the player's volume while it plays, but not the rate or normalization
+    let controls = self.controls.clone();
+    let source = decoder
+      .amplify(1.0)
+      .periodic_access(SOURCE_UPDATE_INTERVAL, move |amplify| {
+        let volume = match controls.system_volume.load(Ordering::Relaxed) {
+          true => 1.0,
+          false => *controls.volume.lock_blocking(),
+        };
+        amplify.set_factor(volume);
+      });
+
+    *self
+      .controls
+      .interrupt
+      .lock()
+      .expect("Interrupt lock should not be poisoned") = Some(Interrupt {
+      source: Box::new(source),
+      guard: InterruptGuard(self.source_tx.clone()),
+    });
+    self
+      .controls
+      .interrupt_pending
+      .store(true, Ordering::Release);
+
+    let path = track.file_path().to_path_buf();
+    println!("Playing oneshot {path:?}");
+    self.emit(Event::InterruptStarted { path })
+  }
+
+  /// The queue and settings to save, so they can be restored after a restart
+  pub async fn saved_state(&self) -> SavedState {
+    let (tracks, shuffle_indicies, current_index) = self.tracks.saved_order().await;
+
+    SavedState {
+      tracks,
+      shuffle_indicies,
+      current_index,
+      position: self.position().await,
+      stopped: self.is_stopped(),
+      volume: self.volume().await,
+      loop_mode: self.loop_mode(),
+      shuffle: self.shuffle().await,
+    }
+  }
+
+  /// Replaces the track list with tracks restored from a saved state, without starting playback
+  ///
+  /// `play_order` must contain every index of `tracks` once
+  pub async fn restore_track_list(
+    &self,
+    tracks: &[Arc<LoadedTrack>],
+    play_order: Vec<usize>,
+    shuffle: bool,
+    current_index: usize,
+  ) -> Result<(), PlayerError> {
+    self
+      .insert_tracks(
+        InsertPosition::Replace,
+        InsertShufflePolicy::Scatter,
+        tracks,
+      )
+      .await?;
+
+    let change = self
+      .tracks
+      .restore_play_order(play_order, shuffle, current_index)
+      .await;
+    self.emit(change.into())?;
+    if shuffle {
+      self.emit(Event::ShuffleChanged(shuffle))?;
+    }
+
+    self.emit_track_changed().await
+  }
+
+  /// Pauses on the current track at `position`, so resuming continues where the saved state left off
+  ///
+  /// The seek is applied once the source starts, without waiting for it
+  pub async fn restore_paused_at(&self, position: Duration) -> Result<(), PlayerError> {
+    if self.current_track_id().await.is_none() {
+      return Ok(());
+    }
+
+    // Paused before queueing, so the track never plays a sample from the start
+    self.set_playback_state(PlaybackState::Paused)?;
+    self.queue_current_track(true).await?;
+
+    if !position.is_zero() {
+      self.seek_without_waiting(position).await?;
+    }
+
+    Ok(())
+  }
+
+  /// Removes the tracks at `positions` in play order, returning the positions that were out of range
+  ///
+  /// If the playing track is removed, it is either detached and plays until it ends, or the track after it starts playing.
+  /// Playback stops or wraps if there is no track after it
+  pub async fn remove_tracks(&self, positions: &[usize]) -> Result<Vec<usize>, PlayerError> {
+    let prev_track_id = self.current_track_id().await;
+    let detached = self.track_detached().await;
+    // A looping track never ends, so it can't play until it does
+    let detach = !self.is_stopped()
+      && !self.skip_removed_current.load(Ordering::Relaxed)
+      && self.loop_mode() != LoopMode::Track;
+
+    // The index may be stale if the current track finishes at the same time, which only affects the session stats
+    if !detached && !detach && positions.contains(&self.current_track_index()) {
+      self.record_listened(TrackOutcome::Left).await;
+    }
+
+    let mut removed = self.tracks.remove_tracks(positions).await;
+    if let Some(change) = removed.change.take() {
+      println!("Removed {} tracks", removed.track_ids.len());
+      self.emit(change.into())?;
+      self.clear_unmatched_filter().await?;
+    }
+
+    match removed.removed_current {
+      // The entry that took a detached track's place hasn't started, so only the queued source changes
+      Some(_) if detached => self.requeue_next_track().await?,
+      Some(_) if self.is_stopped() => {
+        if self.current_track_index() >= self.tracks.len() {
+          self.stop_or_wrap_track(false).await?;
+        }
+      }
+      Some(removed_current) => {
+        println!(
+          "Removed the playing track {:?}, {}",
+          removed_current.loaded_track().file_path(),
+          if detach {
+            "playing it until it ends"
+          } else {
+            "skipping it"
+          }
+        );
+        self.emit(Event::CurrentTrackRemoved {
+          track: removed_current.loaded_track().clone_track(),
+          detached: detach,
+        })?;
+
+        if detach {
+          *self.detached.lock().await = Some(removed_current);
+          // The entry after the removed one is usually already queued
+          let queued_id = self.controls.source_queue.lock().await.queued_track_id();
+          if queued_id.is_none() || queued_id != self.tracks.current_track_id().await {
+            self.requeue_next_track().await?;
+          }
+        } else if !self.queue_current_track(false).await? {
+          self.stop_or_wrap_track(false).await?;
+        }
+      }
+      None => {
+        self
+          .invalidate_prequeued_if(|queued_id| removed.track_ids.contains(&queued_id))
+          .await?
+      }
+    }
+
+    self.emit_if_track_changed(prev_track_id).await?;
+
+    Ok(removed.out_of_range)
+  }
+
+  pub async fn swap_tracks(&self, a: TrackRef, b: TrackRef) -> Result<(), PlayerError> {
+    let prev_next = self.next_track_to_queue().await;
+    let change = self.tracks.swap(a, b).await?;
+    println!("Swapped tracks {a:?} and {b:?}");
+    self.emit(change.into())?;
+
+    self.requeue_if_next_changed(prev_next).await
+  }
+
+  pub async fn move_track(&self, from: usize, to: usize) -> Result<(), PlayerError> {
+    let prev_next = self.next_track_to_queue().await;
+    let change = self.tracks.move_track(from, to).await?;
+    println!("Moved track {from} to {to}");
+    self.emit(change.into())?;
+
+    self.requeue_if_next_changed(prev_next).await
+  }
+
+  pub async fn reverse_queue(&self) -> Result<(), PlayerError> {
+    let prev_next = self.next_track_to_queue().await;
+    let change = self.tracks.reverse().await;
+    println!("Reversed the queue");
+    self.emit(change.into())?;
+
+    self.requeue_if_next_changed(prev_next).await
+  }
+
+  /// The id of the entry that plays after the current one
+  async fn next_track_to_queue(&self) -> Option<TrackId> {
+    let (current_track, next_track) = self.tracks_to_queue().await?;
+    if self.track_detached().await {
+      return Some(current_track.track_id());
+    }
+
+    next_track.map(|next_track| next_track.track_id())
+  }
+
+  /// Queues the new next track if reordering the track list changed it from `prev_next`
+  async fn requeue_if_next_changed(&self, prev_next: Option<TrackId>) -> Result<(), PlayerError> {
+    if self.next_track_to_queue().await != prev_next {
+      self.requeue_next_track().await?;
+    }
 
     Ok(())
   }
 
+  /// Paths of the tracks removed by the last clear, replace, or removal, kept so they can be added back
+  pub async fn last_removed(&self) -> Vec<PathBuf> {
+    self.tracks.last_removed().await
+  }
+
   pub async fn clear_tracks(&self) -> Result<(), PlayerError> {
-    self.stop().await?;
-    self.tracks.clear().await?;
-    self.current_track_index.store(0, Ordering::Release);
+    let prev_track_id = self.current_track_id().await;
+    self.stop(StopReason::QueueCleared).await?;
+    let change = self.tracks.clear().await?;
     println!("Clearing track list");
+    self.emit(change.into())?;
+    self.clear_unmatched_filter().await?;
 
-    Ok(())
+    self.emit_if_track_changed(prev_track_id).await
+  }
+
+  /// Fails if `expected_generation` is provided and the track list has changed since then
+  pub fn check_generation(&self, expected_generation: Option<u64>) -> Result<(), PlayerError> {
+    let current = self.tracks.generation();
+
+    match expected_generation {
+      Some(expected) if expected != current => {
+        Err(PlayerError::GenerationMismatch { expected, current })
+      }
+      _ => Ok(()),
+    }
   }
 
   pub async fn get_track_list(&self) -> TrackListSnapshot {
     self.tracks.get_snapshot().await
   }
 
+  pub fn get_queue_summary(&self) -> QueueSummary {
+    self.tracks.summary()
+  }
+
+  /// Estimated time until the track at `index` in play order starts
+  pub async fn time_until_track(&self, index: usize) -> Option<Duration> {
+    self
+      .tracks
+      .time_until(index, self.position().await, self.loop_mode())
+      .await
+  }
+
+  pub async fn get_track_list_diff(&self, since_generation: u64) -> TrackListDiff {
+    self.tracks.diff_since(since_generation).await
+  }
+
   /// Inserts new tracks at a specified position in the track list
   pub async fn insert_tracks(
     &self,
     position: InsertPosition,
+    shuffle_policy: InsertShufflePolicy,
     tracks: &[Arc<LoadedTrack>],
   ) -> Result<(), PlayerError> {
-    let current_index = self.current_track_index.load(Ordering::Acquire);
+    let prev_track_id = self.current_track_id().await;
+
+    if matches!(position, InsertPosition::Replace) {
+      self.set_stop_after_current(false)?;
+      self.record_listened(TrackOutcome::Left).await;
+    }
 
-    let new_current_index = self
+    let (dropped, change) = self
       .tracks
-      .insert_tracks(current_index, position, tracks)
+      .insert_tracks(position, shuffle_policy, tracks)
       .await?;
+    self.emit(change.into())?;
 
-    self
-      .current_track_index
-      .store(new_current_index, Ordering::Release);
+    if matches!(position, InsertPosition::Replace) {
+      self.clear_unmatched_filter().await?;
+    }
 
     // If the track list was replaced, a new song must begin playing
     if matches!(position, InsertPosition::Replace) && !self.is_stopped() {
       self.queue_current_track(false).await?;
     }
 
+    self.emit_if_track_changed(prev_track_id).await?;
+
+    if dropped > 0 {
+      return Err(PlayerError::QueueFull {
+        max_length: self.tracks.max_length(),
+        dropped,
+      });
+    }
+
     Ok(())
   }
 
+  /// Moves past tracks that fail to load after `error` stopped the track list from advancing
+  ///
+  /// Each track is tried at most once, so playback stops with `StopReason::Error` only if no track can be played
+  async fn skip_unplayable_tracks(&self, mut error: PlayerError) -> Result<(), PlayerError> {
+    for _ in 0..self.tracks.len() {
+      if !error.is_recoverable() {
+        return Err(error);
+      }
+
+      eprintln!("Skipping track: {error}");
+      error = match self.go_to_next_track(1, true).await {
+        Ok(()) => return Ok(()),
+        Err(error) => error,
+      };
+    }
+
+    if !error.is_recoverable() {
+      return Err(error);
+    }
+
+    eprintln!("{error}");
+    self.stop(StopReason::Error(error.to_string())).await
+  }
+
   pub async fn run(&self) -> Result<(), PlayerError> {
     loop {
       let event = self
@@ -503,22 +1896,43 @@ impl Player {
         .recv()
         .await
         .map_err(|_| PlayerError::SourceChannelClosed)?;
+      self.source_events_taken.fetch_add(1, Ordering::Relaxed);
 
       if event.indicates_end() {
+        if let Some(mut test_tone_done) = self.test_tone_done.lock().await.take() {
+          let _ = test_tone_done.send(());
+          continue;
+        }
+
         if !matches!(event, SourceEvent::Skipped) {
-          if let Err(error) = self.go_to_next_track().await {
-            if error.is_recoverable() {
-              eprintln!("{error}");
-            } else {
-              return Err(error);
-            }
+          self.record_listened(TrackOutcome::Finished).await;
+
+          if let Err(error) = self.go_to_next_track(1, true).await {
+            self.skip_unplayable_tracks(error).await?;
           }
         }
       }
 
       match event {
         SourceEvent::LoopError(error) => eprintln!("Error looping source: {}", error),
-        SourceEvent::Seeked(position) => self.emit(Event::Seeked(position))?,
+        // The source already paused itself, so only the shared state and plugins need to know
+        SourceEvent::PausedAtEnd => {
+          println!("Pausing at the end of the track");
+          self
+            .controls
+            .shared
+            .set_playback_state(PlaybackState::Paused);
+          self.emit(Event::PlaybackStateChanged(PlaybackState::Paused))?;
+          // The source also turned stop after current off
+          self.emit(Event::StopAfterCurrentChanged(false))?;
+          self.emit(Event::EndBehaviorChanged(self.end_behavior()))?;
+        }
+        SourceEvent::Ending(remaining) => self.emit(Event::TrackEnding { remaining })?,
+        SourceEvent::InterruptFinished => {
+          self.oneshot_active.store(false, Ordering::Release);
+          println!("Oneshot finished");
+          self.emit(Event::InterruptFinished)?;
+        }
         _ => (),
       }
     }
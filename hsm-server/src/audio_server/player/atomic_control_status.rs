@@ -1,6 +1,6 @@
 use std::sync::atomic::{AtomicUsize, Ordering};
 
-use hsm_ipc::{LoopMode, PlaybackState};
+use hsm_ipc::{LoopMode, NormalizationMode, PlaybackState};
 
 #[derive(Debug)]
 pub struct AtomicPlaybackState(AtomicUsize);
@@ -103,3 +103,40 @@ impl AtomicLoopMode {
       .map_err(Self::from_usize)
   }
 }
+
+#[derive(Debug)]
+pub struct AtomicNormalizationMode(AtomicUsize);
+
+#[allow(dead_code)]
+impl AtomicNormalizationMode {
+  fn from_usize(val: usize) -> NormalizationMode {
+    #![allow(non_upper_case_globals)]
+    const OFF: usize = NormalizationMode::Off as usize;
+    const TRACK: usize = NormalizationMode::Track as usize;
+    const ALBUM: usize = NormalizationMode::Album as usize;
+    match val {
+      OFF => NormalizationMode::Off,
+      TRACK => NormalizationMode::Track,
+      ALBUM => NormalizationMode::Album,
+      _ => {
+        unreachable!("Invalid enum discriminant")
+      }
+    }
+  }
+
+  pub const fn new(v: NormalizationMode) -> Self {
+    Self(AtomicUsize::new(v as usize))
+  }
+
+  pub fn load(&self, order: Ordering) -> NormalizationMode {
+    Self::from_usize(self.0.load(order))
+  }
+
+  pub fn store(&self, val: NormalizationMode, order: Ordering) {
+    self.0.store(val as usize, order)
+  }
+
+  pub fn swap(&self, val: NormalizationMode, order: Ordering) -> NormalizationMode {
+    Self::from_usize(self.0.swap(val as usize, order))
+  }
+}
@@ -3,10 +3,10 @@ use std::{
   time::Duration,
 };
 
-use hsm_ipc::SeekPosition;
+use hsm_ipc::{NormalizationMode, ReplayGain};
 use rodio::{
   Source,
-  source::{Amplify, Pausable, SeekError as RodioSeekError, TrackPosition},
+  source::{Amplify, Pausable, SeekError as RodioSeekError, Speed, TrackPosition},
 };
 use smol::channel::Sender;
 use thiserror::Error;
@@ -14,11 +14,16 @@ use thiserror::Error;
 use super::{Controls, LoopMode, PlaybackState, output::SourceQueueState};
 
 pub enum SourceEvent {
-  Seeked(Duration),
+  /// The source will finish in the contained duration
+  Ending(Duration),
   LoopError(RodioSeekError),
   Finished,
   Skipped,
   Looped,
+  /// The source reached its end with `Controls::stop_after_current` set, and paused instead of finishing
+  PausedAtEnd,
+  /// The interrupt started by `Player::play_oneshot` ended, or was dropped with the output playing it
+  InterruptFinished,
 }
 
 impl SourceEvent {
@@ -32,7 +37,7 @@ impl SourceEvent {
   }
 }
 
-type WrappedSourceInner<S> = ControlledSource<Pausable<Amplify<TrackPosition<S>>>>;
+type WrappedSourceInner<S> = ControlledSource<Pausable<Amplify<Speed<TrackPosition<S>>>>>;
 
 pub const SOURCE_UPDATE_INTERVAL: Duration = Duration::from_millis(5);
 
@@ -41,6 +46,49 @@ pub struct ControlledSource<I> {
   controls: Arc<Controls>,
   source_tx: Sender<SourceEvent>,
   should_skip: bool,
+  /// Set when a seek went past the end of the input, which then ends as if it had been played to the end
+  seeked_past_end: bool,
+  track_ending: TrackEndingNotifier,
+  /// `None` for sources that are never normalized, such as the test tone
+  replay_gain: Option<ReplayGain>,
+}
+
+/// Decides when to send `SourceEvent::Ending` for a single source
+///
+/// Fires once when the remaining time drops below the notice,
+/// and is re-armed if a seek moves the position back out of the notice window
+#[derive(Debug, Default)]
+pub struct TrackEndingNotifier {
+  fired: bool,
+}
+
+impl TrackEndingNotifier {
+  /// Returns the remaining duration if the event should be sent now
+  pub fn update(
+    &mut self,
+    position: Duration,
+    total_duration: Option<Duration>,
+    notice: Duration,
+    looping: bool,
+  ) -> Option<Duration> {
+    let total_duration = total_duration?;
+    if notice.is_zero() || looping {
+      return None;
+    }
+
+    let remaining = total_duration.saturating_sub(position);
+    if remaining > notice {
+      self.fired = false;
+      return None;
+    }
+
+    if self.fired || remaining.is_zero() {
+      return None;
+    }
+
+    self.fired = true;
+    Some(remaining)
+  }
 }
 
 impl<I> ControlledSource<I>
@@ -50,20 +98,36 @@ where
   #[inline]
   pub fn with_controls(
     &mut self,
-    f: impl FnOnce(&mut I, &Arc<Controls>, &Sender<SourceEvent>, &mut bool),
+    f: impl FnOnce(
+      &mut I,
+      &Arc<Controls>,
+      &Sender<SourceEvent>,
+      &mut bool,
+      &mut bool,
+      &mut TrackEndingNotifier,
+      Option<&ReplayGain>,
+    ),
   ) {
     f(
       &mut self.input,
       &self.controls,
       &self.source_tx,
       &mut self.should_skip,
+      &mut self.seeked_past_end,
+      &mut self.track_ending,
+      self.replay_gain.as_ref(),
     )
   }
 
+  /// Must be called before the source sends the event for its end,
+  /// so the player doesn't see a source that already ended as still playing
   fn clear_playing_source(&self) {
     let mut next_source = self.controls.source_queue.lock_blocking();
     if matches!(*next_source, SourceQueueState::Playing) {
       *next_source = SourceQueueState::None;
+
+      // There is no source left for a pending skip to apply to, it would skip the next track played instead
+      self.controls.to_skip.store(0, Ordering::Release);
     }
   }
 }
@@ -77,30 +141,64 @@ where
   #[inline]
   fn next(&mut self) -> Option<Self::Item> {
     if self.should_skip {
-      let _ = self.source_tx.try_send(SourceEvent::Skipped);
       self.clear_playing_source();
+      let _ = self.source_tx.try_send(SourceEvent::Skipped);
       return None;
     }
 
-    if let Some(value) = self.input.next() {
+    let next = match self.seeked_past_end {
+      true => None,
+      false => self.input.next(),
+    };
+    if let Some(value) = next {
       return Some(value);
     }
 
+    // Checked here instead of when `Finished` is handled, since by then the next source is already playing
+    if self.controls.stop_after_current.load(Ordering::Acquire)
+      && self
+        .controls
+        .playback_state
+        .compare_exchange(
+          PlaybackState::Playing,
+          PlaybackState::Paused,
+          Ordering::AcqRel,
+          Ordering::Acquire,
+        )
+        .is_ok()
+    {
+      self
+        .controls
+        .stop_after_current
+        .store(false, Ordering::Release);
+      let _ = self.source_tx.try_send(SourceEvent::PausedAtEnd);
+    }
+
+    // The pause is only applied to the `Pausable` every `SOURCE_UPDATE_INTERVAL`, so the input can run out after pausing.
+    // Hold on the last sample until playback resumes, instead of starting the next track while paused
+    if matches!(
+      self.controls.playback_state.load(Ordering::Relaxed),
+      PlaybackState::Paused
+    ) {
+      return Some(0.0);
+    }
+
     if matches!(
       self.controls.loop_mode.load(Ordering::Relaxed),
       LoopMode::Track,
     ) {
       if let Err(error) = self.input.try_seek(Duration::ZERO) {
-        let _ = self.source_tx.try_send(SourceEvent::LoopError(error));
         self.clear_playing_source();
+        let _ = self.source_tx.try_send(SourceEvent::LoopError(error));
         return None;
       }
 
+      self.seeked_past_end = false;
       let _ = self.source_tx.try_send(SourceEvent::Looped);
       self.input.next()
     } else {
-      let _ = self.source_tx.try_send(SourceEvent::Finished);
       self.clear_playing_source();
+      let _ = self.source_tx.try_send(SourceEvent::Finished);
       None
     }
   }
@@ -118,6 +216,12 @@ pub enum SeekError {
 
   #[error("{0}")]
   SeekFailed(String),
+
+  #[error("Playback stopped before the seek was applied")]
+  PlaybackStopped,
+
+  #[error("Can't seek by a fraction of a track with an unknown duration")]
+  UnknownDuration,
 }
 
 impl<I> Source for ControlledSource<I>
@@ -150,57 +254,311 @@ where
 }
 
 fn control_wrapped_source<S: Source>(controlled: &mut WrappedSourceInner<S>) {
-  controlled.with_controls(|pauseable, controls, source_tx, should_skip| {
-    let to_skip = controls.to_skip.load(Ordering::Acquire);
-    if to_skip > 0 {
-      *should_skip = true;
-      controls.to_skip.fetch_sub(1, Ordering::Release);
-      return;
-    }
-
-    pauseable.set_paused(!matches!(
-      controls.playback_state.load(Ordering::Relaxed),
-      PlaybackState::Playing
-    ));
+  controlled.with_controls(
+    |pauseable, controls, source_tx, should_skip, seeked_past_end, track_ending, replay_gain| {
+      let to_skip = controls.to_skip.load(Ordering::Acquire);
+      if to_skip > 0 {
+        *should_skip = true;
+        controls.to_skip.fetch_sub(1, Ordering::Release);
+        return;
+      }
 
-    let volume_controlled = pauseable.inner_mut();
-    volume_controlled.set_factor(*controls.volume.lock_blocking());
+      pauseable.set_paused(!matches!(
+        controls.playback_state.load(Ordering::Relaxed),
+        PlaybackState::Playing
+      ));
 
-    let position_tracked = volume_controlled.inner_mut();
-    if let Some((seek_position, mut tx)) = controls.seek_position.lock_blocking().take() {
-      let current_position = position_tracked.get_pos();
-      let seek_position = match seek_position {
-        SeekPosition::Forward(duration) => current_position.saturating_add(duration),
-        SeekPosition::Backward(duration) => current_position.saturating_sub(duration),
-        SeekPosition::To(position) => position,
+      let volume_controlled = pauseable.inner_mut();
+      // The system mixer applies the volume instead while it is the volume backend
+      let volume = match controls.system_volume.load(Ordering::Relaxed) {
+        true => 1.0,
+        false => *controls.volume.lock_blocking(),
       };
+      volume_controlled.set_factor(volume * normalization_factor(replay_gain, controls));
+
+      let speed_controlled = volume_controlled.inner_mut();
+      speed_controlled.set_factor(*controls.rate.lock_blocking());
+
+      // Inside the speed control, so positions and seeks are in the track's own time
+      let position_tracked = speed_controlled.inner_mut();
+      if let Some((seek_position, mut tx)) = controls.seek_position.lock_blocking().take() {
+        let total_duration = position_tracked.total_duration();
+        let result = match (
+          seek_position.resolve(position_tracked.get_pos(), total_duration),
+          total_duration,
+        ) {
+          (None, _) => Err(SeekError::UnknownDuration),
+          // Ends the source the same way as playing it to the end, so playback moves on to the next track
+          (Some(position), Some(total_duration)) if position >= total_duration => {
+            *seeked_past_end = true;
+            Ok(total_duration)
+          }
+          (Some(position), _) => {
+            *seeked_past_end = false;
+            position_tracked
+              .try_seek(position)
+              .map(|()| position)
+              .map_err(|error| SeekError::SeekFailed(error.to_string()))
+          }
+        };
+
+        let _ = tx.send(result);
+      }
 
-      let _ = tx.send(
-        position_tracked
-          .try_seek(seek_position)
-          .map_err(|error| SeekError::SeekFailed(error.to_string())),
-      );
+      let position = position_tracked.get_pos();
+      *controls.position.lock_blocking() = position;
+      controls.shared.set_position(position);
+
+      let looping = matches!(controls.loop_mode.load(Ordering::Relaxed), LoopMode::Track);
+      let notice =
+        Duration::from_micros(controls.track_ending_notice_micros.load(Ordering::Relaxed));
+      if let Some(remaining) =
+        track_ending.update(position, position_tracked.total_duration(), notice, looping)
+      {
+        let _ = source_tx.try_send(SourceEvent::Ending(remaining));
+      }
+    },
+  );
+}
 
-      let _ = source_tx.try_send(SourceEvent::Seeked(seek_position));
-    }
+/// Read every update, so changing the normalization mode also applies to the playing track
+fn normalization_factor(replay_gain: Option<&ReplayGain>, controls: &Controls) -> f32 {
+  let Some(replay_gain) = replay_gain else {
+    return 1.0;
+  };
 
-    *controls.position.lock_blocking() = position_tracked.get_pos();
-  });
+  match controls.normalization.load(Ordering::Relaxed) {
+    NormalizationMode::Off => 1.0,
+    mode => replay_gain
+      .factor(mode)
+      .unwrap_or_else(|| *controls.normalization_fallback.lock_blocking()),
+  }
 }
 
 pub fn wrap_source<S: Source>(
   source: S,
   controls: Arc<Controls>,
   source_tx: Sender<SourceEvent>,
+  replay_gain: Option<ReplayGain>,
 ) -> impl Source {
-  let wrapped = source.track_position().amplify(1.0).pausable(false);
+  let wrapped = source
+    .track_position()
+    .speed(1.0)
+    .amplify(1.0)
+    .pausable(false);
 
   let controlled = ControlledSource {
     input: wrapped,
     controls,
     source_tx,
     should_skip: false,
+    seeked_past_end: false,
+    track_ending: TrackEndingNotifier::default(),
+    replay_gain,
   };
 
   controlled.periodic_access(SOURCE_UPDATE_INTERVAL, control_wrapped_source)
 }
+
+#[cfg(test)]
+mod tests {
+  use hsm_ipc::SeekPosition;
+  use hsm_plugin::SharedPlayerState;
+  use rodio::buffer::SamplesBuffer;
+  use smol::channel::{self, Receiver};
+
+  use super::*;
+  use crate::audio_server::{
+    player::decoder::tests::{frame, load_ramp},
+    track::GaplessTrim,
+  };
+
+  const TOTAL: Duration = Duration::from_secs(60);
+  const NOTICE: Duration = Duration::from_secs(5);
+
+  fn secs(secs: u64) -> Duration {
+    Duration::from_secs(secs)
+  }
+
+  /// Feeds `positions` to a new notifier, returning the positions it fired at with the remaining time
+  fn fire_times(
+    positions: &[Duration],
+    total_duration: Option<Duration>,
+    notice: Duration,
+    looping: bool,
+  ) -> Vec<(Duration, Duration)> {
+    let mut notifier = TrackEndingNotifier::default();
+    positions
+      .iter()
+      .filter_map(|&position| {
+        notifier
+          .update(position, total_duration, notice, looping)
+          .map(|remaining| (position, remaining))
+      })
+      .collect()
+  }
+
+  #[test]
+  fn fires_once_inside_the_notice() {
+    let positions = [secs(50), secs(54), secs(55), secs(56), secs(58), secs(59)];
+    assert_eq!(
+      fire_times(&positions, Some(TOTAL), NOTICE, false),
+      [(secs(55), secs(5))]
+    );
+  }
+
+  #[test]
+  fn fires_again_after_seeking_back_out_of_the_notice() {
+    let positions = [secs(56), secs(57), secs(20), secs(21), secs(57), secs(58)];
+    assert_eq!(
+      fire_times(&positions, Some(TOTAL), NOTICE, false),
+      [(secs(56), secs(4)), (secs(57), secs(3))]
+    );
+  }
+
+  #[test]
+  fn seeking_within_the_notice_does_not_fire_again() {
+    let positions = [secs(56), secs(58), secs(56), secs(57)];
+    assert_eq!(
+      fire_times(&positions, Some(TOTAL), NOTICE, false),
+      [(secs(56), secs(4))]
+    );
+  }
+
+  #[test]
+  fn stays_silent_while_looping() {
+    let positions = [secs(50), secs(56), secs(59)];
+    assert_eq!(fire_times(&positions, Some(TOTAL), NOTICE, true), []);
+  }
+
+  #[test]
+  fn stays_silent_without_a_notice() {
+    let positions = [secs(50), secs(56), secs(59)];
+    assert_eq!(
+      fire_times(&positions, Some(TOTAL), Duration::ZERO, false),
+      []
+    );
+  }
+
+  #[test]
+  fn stays_silent_with_an_unknown_duration() {
+    let positions = [secs(50), secs(56), secs(59)];
+    assert_eq!(fire_times(&positions, None, NOTICE, false), []);
+  }
+
+  #[test]
+  fn stays_silent_once_nothing_remains() {
+    // A seek straight to the end, or past it, finishes the track without any notice
+    assert_eq!(
+      fire_times(&[secs(50), TOTAL, secs(70)], Some(TOTAL), NOTICE, false),
+      []
+    );
+  }
+
+  const SAMPLE_RATE: u32 = 8000;
+  /// Samples in one `SOURCE_UPDATE_INTERVAL`
+  const UPDATE_SAMPLES: usize = 40;
+
+  /// A playing source of 100ms of samples at 0.5
+  fn playing_source() -> (impl Source, Arc<Controls>, Receiver<SourceEvent>) {
+    play(SamplesBuffer::new(1, SAMPLE_RATE, vec![0.5; 800]))
+  }
+
+  fn play(input: impl Source) -> (impl Source, Arc<Controls>, Receiver<SourceEvent>) {
+    let controls = Arc::new(Controls::new(Arc::new(SharedPlayerState::new())));
+    controls
+      .playback_state
+      .store(PlaybackState::Playing, Ordering::Release);
+    *controls.source_queue.lock_blocking() = SourceQueueState::Playing;
+
+    let (source_tx, source_rx) = channel::unbounded();
+    let source = wrap_source(input, controls.clone(), source_tx, None);
+    (source, controls, source_rx)
+  }
+
+  fn events(source_rx: &Receiver<SourceEvent>) -> Vec<SourceEvent> {
+    std::iter::from_fn(|| source_rx.try_recv().ok()).collect()
+  }
+
+  #[test]
+  fn pausing_right_before_the_end_holds_the_track() {
+    let (mut source, controls, source_rx) = playing_source();
+
+    // Pause with less than 10ms left, before the next update applies it
+    for _ in 0..730 {
+      assert_eq!(source.next(), Some(0.5));
+    }
+    controls
+      .playback_state
+      .store(PlaybackState::Paused, Ordering::Release);
+
+    // Nothing past the update interval is played, and the source doesn't end however long it stays paused
+    let paused: Vec<_> = (0..SAMPLE_RATE).map(|_| source.next()).collect();
+    assert!(paused.iter().all(Option::is_some));
+    assert!(
+      paused[UPDATE_SAMPLES..]
+        .iter()
+        .all(|&sample| sample == Some(0.0))
+    );
+    assert!(!events(&source_rx).iter().any(SourceEvent::indicates_end));
+    assert!(matches!(
+      *controls.source_queue.lock_blocking(),
+      SourceQueueState::Playing
+    ));
+
+    // The rest of the track plays after resuming, then it finishes
+    controls
+      .playback_state
+      .store(PlaybackState::Playing, Ordering::Release);
+    let resumed: Vec<_> = source.by_ref().take(SAMPLE_RATE as usize).collect();
+    assert!(resumed.len() < 80 + UPDATE_SAMPLES, "{}", resumed.len());
+    assert!(matches!(events(&source_rx)[..], [SourceEvent::Finished]));
+    assert!(matches!(
+      *controls.source_queue.lock_blocking(),
+      SourceQueueState::None
+    ));
+  }
+
+  #[test]
+  fn seeks_within_one_update_of_the_target() {
+    let dir = tempfile::tempdir().unwrap();
+    let (mut source, controls, _source_rx) = play(load_ramp(dir.path(), GaplessTrim::default()));
+
+    // Forward, backward, and to a time between two frames
+    let targets = [
+      Duration::from_millis(250),
+      Duration::from_micros(612_560),
+      Duration::from_millis(100),
+      Duration::from_millis(990),
+    ];
+    for target in targets {
+      let (tx, rx) = async_oneshot::oneshot();
+      *controls.seek_position.lock_blocking() = Some((SeekPosition::To(target), tx));
+
+      // The seek is applied by the next update, before the sample it returns with
+      let first_sample = loop {
+        let sample = source.next().expect("The track should not end");
+        if controls.seek_position.lock_blocking().is_none() {
+          break sample;
+        }
+      };
+
+      let reported = smol::block_on(rx).unwrap().unwrap();
+      let position = *controls.position.lock_blocking();
+      for position in [reported, position, controls.shared.position()] {
+        assert!(
+          position.abs_diff(target) <= SOURCE_UPDATE_INTERVAL,
+          "{position:?} is too far from {target:?}"
+        );
+      }
+
+      // The ramp's samples are their frame, so the first sample shows where decoding continued
+      let target_frame = target.as_secs_f64() * SAMPLE_RATE as f64;
+      assert!(
+        (frame(first_sample) as f64 - target_frame).abs() <= 1.0,
+        "Continued at frame {}, expected {target_frame}",
+        frame(first_sample)
+      );
+    }
+  }
+}
@@ -0,0 +1,321 @@
+use std::{
+  collections::VecDeque,
+  sync::{
+    Arc, Condvar, Mutex, MutexGuard,
+    atomic::{AtomicBool, Ordering},
+  },
+  thread,
+  time::Duration,
+};
+
+use rodio::{ChannelCount, Sample, SampleRate, Source, source::SeekError as RodioSeekError};
+
+use super::decoder::{DecodedChunk, TrackDecoder};
+
+#[derive(Debug, Default)]
+struct ChunkQueue {
+  chunks: VecDeque<DecodedChunk>,
+  buffered: Duration,
+  /// The decoder reached the end of the stream, cleared by seeking
+  finished: bool,
+}
+
+impl ChunkQueue {
+  fn pop(&mut self) -> Option<DecodedChunk> {
+    let chunk = self.chunks.pop_front()?;
+    self.buffered = self.buffered.saturating_sub(chunk.duration());
+    Some(chunk)
+  }
+
+  fn push(&mut self, chunk: Option<DecodedChunk>) {
+    match chunk {
+      Some(chunk) => {
+        self.buffered += chunk.duration();
+        self.chunks.push_back(chunk);
+      }
+      None => self.finished = true,
+    }
+  }
+}
+
+/// State shared with the decoding thread
+///
+/// Locks are always taken in the order `decoder`, then `queue`
+struct Shared {
+  decoder: Mutex<TrackDecoder>,
+  queue: Mutex<ChunkQueue>,
+  /// Notified when the queue has room again, or the source was dropped
+  queue_changed: Condvar,
+  closed: AtomicBool,
+  capacity: Duration,
+}
+
+impl Shared {
+  fn decoder(&self) -> MutexGuard<'_, TrackDecoder> {
+    self
+      .decoder
+      .lock()
+      .expect("Decoder lock should not be poisoned")
+  }
+
+  fn queue(&self) -> MutexGuard<'_, ChunkQueue> {
+    self
+      .queue
+      .lock()
+      .expect("Decode ahead queue lock should not be poisoned")
+  }
+
+  /// Keeps the queue topped up until the source is dropped, run on the decoding thread
+  fn fill(&self) {
+    loop {
+      {
+        let mut queue = self.queue();
+        while !self.closed.load(Ordering::Acquire)
+          && (queue.finished || queue.buffered >= self.capacity)
+        {
+          queue = self
+            .queue_changed
+            .wait(queue)
+            .expect("Decode ahead queue lock should not be poisoned");
+        }
+      }
+
+      if self.closed.load(Ordering::Acquire) {
+        return;
+      }
+
+      // The decoder stays locked until the chunk is queued, so a seek can't happen in between
+      let mut decoder = self.decoder();
+      let chunk = decoder.next_chunk();
+      self.queue().push(chunk);
+    }
+  }
+}
+
+/// Decodes a `TrackDecoder` ahead of playback on its own thread,
+/// so an expensive packet doesn't have to be decoded within the output's deadline
+///
+/// The thread lives as long as the source, so it isn't run on the blocking thread pool
+/// where it would hold a thread for the whole track.
+/// If the buffer runs dry, packets are decoded on the output thread like an unbuffered decoder
+pub struct DecodeAhead {
+  shared: Arc<Shared>,
+  current: DecodedChunk,
+  offset: usize,
+  total_duration: Option<Duration>,
+}
+
+impl DecodeAhead {
+  /// Buffers up to `capacity` of decoded audio
+  pub fn new(decoder: TrackDecoder, capacity: Duration) -> Self {
+    let total_duration = decoder.total_duration();
+    let current = DecodedChunk {
+      channels: decoder.channels(),
+      sample_rate: decoder.sample_rate(),
+      samples: Vec::new(),
+    };
+
+    let shared = Arc::new(Shared {
+      decoder: Mutex::new(decoder),
+      queue: Mutex::new(ChunkQueue::default()),
+      queue_changed: Condvar::new(),
+      closed: AtomicBool::new(false),
+      capacity,
+    });
+
+    let thread_shared = shared.clone();
+    let spawned = thread::Builder::new()
+      .name("hsm-decode-ahead".to_owned())
+      .spawn(move || thread_shared.fill());
+
+    // Without the thread every chunk is decoded inline, which is how an unbuffered decoder plays
+    if let Err(error) = spawned {
+      eprintln!("Could not start decode ahead thread, decoding inline: {error}");
+    }
+
+    Self {
+      shared,
+      current,
+      offset: 0,
+      total_duration,
+    }
+  }
+
+  fn decode_chunk(&self) -> Option<DecodedChunk> {
+    {
+      let mut queue = self.shared.queue();
+      if let Some(chunk) = queue.pop() {
+        self.shared.queue_changed.notify_one();
+        return Some(chunk);
+      }
+
+      if queue.finished {
+        return None;
+      }
+    }
+
+    // The buffer ran dry, so decode inline instead of playing silence
+    let mut decoder = self.shared.decoder();
+
+    // A chunk may have been queued while waiting for the decoder
+    let mut queue = self.shared.queue();
+    if let Some(chunk) = queue.pop() {
+      self.shared.queue_changed.notify_one();
+      return Some(chunk);
+    }
+
+    if queue.finished {
+      return None;
+    }
+
+    let chunk = decoder.next_chunk();
+    if chunk.is_none() {
+      queue.finished = true;
+    }
+
+    chunk
+  }
+}
+
+impl Drop for DecodeAhead {
+  fn drop(&mut self) {
+    self.shared.closed.store(true, Ordering::Release);
+
+    // Taking the lock makes sure the thread is either waiting or will see `closed` before it waits
+    let _queue = self.shared.queue();
+    self.shared.queue_changed.notify_one();
+  }
+}
+
+impl Iterator for DecodeAhead {
+  type Item = Sample;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    while self.offset >= self.current.samples.len() {
+      self.current = self.decode_chunk()?;
+      self.offset = 0;
+    }
+
+    let sample = self.current.samples[self.offset];
+    self.offset += 1;
+
+    Some(sample)
+  }
+}
+
+impl Source for DecodeAhead {
+  #[inline]
+  fn current_span_len(&self) -> Option<usize> {
+    Some(self.current.samples.len())
+  }
+
+  #[inline]
+  fn channels(&self) -> ChannelCount {
+    self.current.channels
+  }
+
+  #[inline]
+  fn sample_rate(&self) -> SampleRate {
+    self.current.sample_rate
+  }
+
+  #[inline]
+  fn total_duration(&self) -> Option<Duration> {
+    self.total_duration
+  }
+
+  fn try_seek(&mut self, pos: Duration) -> Result<(), RodioSeekError> {
+    // The decoder is ahead of playback, so the channel to restore is the one about to be played
+    let active_channel = self.offset % self.channels().max(1) as usize;
+
+    {
+      let mut decoder = self.shared.decoder();
+      decoder.seek_to_frame(pos)?;
+
+      let mut queue = self.shared.queue();
+      *queue = ChunkQueue::default();
+      self.shared.queue_changed.notify_one();
+    }
+
+    self.current.samples.clear();
+    self.offset = 0;
+
+    for _ in 0..active_channel {
+      self.next();
+    }
+
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::audio_server::{
+    player::decoder::tests::{FRAMES, frame, load_ramp},
+    track::GaplessTrim,
+  };
+
+  fn chunk(frames: usize) -> DecodedChunk {
+    DecodedChunk {
+      channels: 1,
+      sample_rate: 8000,
+      samples: vec![0.0; frames],
+    }
+  }
+
+  #[test]
+  fn queue_tracks_buffered_duration() {
+    let mut queue = ChunkQueue::default();
+    queue.push(Some(chunk(800)));
+    queue.push(Some(chunk(400)));
+    assert_eq!(queue.buffered, Duration::from_millis(150));
+
+    assert_eq!(queue.pop().map(|chunk| chunk.samples.len()), Some(800));
+    assert_eq!(queue.buffered, Duration::from_millis(50));
+
+    queue.push(None);
+    assert!(queue.finished);
+    assert!(queue.pop().is_some());
+    assert!(queue.pop().is_none());
+    assert_eq!(queue.buffered, Duration::ZERO);
+  }
+
+  #[test]
+  fn plays_the_same_samples_as_the_decoder() {
+    let dir = tempfile::tempdir().unwrap();
+
+    for capacity in [
+      Duration::ZERO,
+      Duration::from_millis(10),
+      Duration::from_secs(5),
+    ] {
+      let decoder = load_ramp(dir.path(), GaplessTrim::default());
+      let frames: Vec<i64> = DecodeAhead::new(decoder, capacity).map(frame).collect();
+
+      assert_eq!(
+        frames,
+        (0..FRAMES as i64).collect::<Vec<_>>(),
+        "{capacity:?}"
+      );
+    }
+  }
+
+  #[test]
+  fn seeking_drops_queued_chunks() {
+    let dir = tempfile::tempdir().unwrap();
+    let decoder = load_ramp(dir.path(), GaplessTrim::default());
+    let mut source = DecodeAhead::new(decoder, Duration::from_secs(5));
+
+    assert_eq!(source.next().map(frame), Some(0));
+    // Let the thread fill the queue from the start of the track
+    thread::sleep(Duration::from_millis(50));
+
+    source.try_seek(Duration::from_millis(500)).unwrap();
+    assert_eq!(source.next().map(frame), Some(4000));
+
+    source.try_seek(Duration::from_millis(250)).unwrap();
+    let frames: Vec<i64> = source.map(frame).collect();
+    assert_eq!(frames, (2000..FRAMES as i64).collect::<Vec<_>>());
+  }
+}
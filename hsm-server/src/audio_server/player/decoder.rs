@@ -1,4 +1,4 @@
-use std::{sync::Arc, time::Duration};
+use std::{ops::Range, sync::Arc, time::Duration};
 
 use symphonia::core::{
   audio::{SampleBuffer, SignalSpec},
@@ -9,27 +9,46 @@ use symphonia::core::{
 
 use rodio::{ChannelCount, Sample, SampleRate, Source, source::SeekError as RodioSeekError};
 
-use crate::audio_server::track::{self, LoadTrackError, LoadedTrack};
+use crate::audio_server::{
+  blocking::{BlockingScheduler, Lane},
+  track::{self, GaplessTrim, LoadTrackError, LoadedTrack, TrackPath},
+};
 
 /// A `Source` that decodes `Track`s using symphonia
 pub(crate) struct TrackDecoder {
   decoder: Box<dyn Decoder>,
   current_span_offset: usize,
+  /// Samples in `buffer` from here on are encoder padding
+  span_end: usize,
   format: Box<dyn FormatReader>,
   total_duration: Option<Duration>,
   buffer: SampleBuffer<Sample>,
   spec: SignalSpec,
+  /// Encoder delay and padding that symphonia doesn't remove
+  trim: GaplessTrim,
+  /// The first frame of padding, counting the encoder delay, if the stream's length is known
+  end_frame: Option<u64>,
+}
+
+fn duration_to_frames(duration: Duration, sample_rate: SampleRate) -> u64 {
+  (duration.as_secs_f64() * sample_rate as f64).round() as u64
 }
 
 impl TrackDecoder {
-  pub async fn new(track: Arc<LoadedTrack>) -> Result<Self, LoadTrackError> {
-    smol::unblock(move || Self::new_sync(track)).await
+  pub async fn new(
+    track: Arc<LoadedTrack>,
+    scheduler: &BlockingScheduler,
+  ) -> Result<Self, LoadTrackError> {
+    scheduler
+      .unblock(Lane::Interactive, move || Self::new_sync(track))
+      .await
   }
 
   fn new_sync(track: Arc<LoadedTrack>) -> Result<Self, LoadTrackError> {
     println!("Creating decoder for track {:?}", track.file_path());
 
-    let probed = track::probe_track_sync(track.file_path())?;
+    // The track was checked against `QueueConfig::allowed_roots` when it was loaded
+    let probed = track::probe_track_sync(&mut TrackPath::new(track.file_path().into()), &[])?;
     let audio_track = probed
       .format
       .tracks()
@@ -41,105 +60,157 @@ impl TrackDecoder {
       .make(&audio_track.codec_params, &DecoderOptions::default())
       .map_err(|_| LoadTrackError::CodecNotSupported)?;
 
+    let trim = track.trim.unapplied();
+    let codec_params = &audio_track.codec_params;
+    let end_frame = codec_params
+      .time_base
+      .zip(codec_params.n_frames)
+      .filter(|_| trim.padding > 0)
+      .map(|(base, n_frames)| {
+        duration_to_frames(base.calc_time(n_frames).into(), track.spec.rate)
+          .saturating_sub(trim.padding)
+      });
+
     let buffer = SampleBuffer::new(0, track.spec);
     Ok(TrackDecoder {
       decoder,
       current_span_offset: 0,
+      span_end: 0,
       format: probed.format,
       total_duration: track.inner.total_duration,
       buffer,
       spec: track.spec,
+      trim,
+      end_frame,
     })
   }
 
-  /// Note span offset must be set after
-  fn try_refine_position(&mut self, seek_res: SeekedTo) -> Result<(), RodioSeekError> {
-    let Some(time_base) = self.decoder.codec_params().time_base else {
-      return Ok(());
+  /// The frames of a packet that are neither encoder delay nor padding
+  fn untrimmed_frames(&self, packet_ts: u64, frames: u64) -> Range<u64> {
+    let first_frame = match self.decoder.codec_params().time_base {
+      Some(time_base) => duration_to_frames(time_base.calc_time(packet_ts).into(), self.spec.rate),
+      None => return 0..frames,
     };
 
-    // Calculate the number of samples to skip.
-    let mut samples_to_skip =
-      (Duration::from(time_base.calc_time(seek_res.required_ts.saturating_sub(seek_res.actual_ts)))
-        .as_secs_f32()
-        * self.sample_rate() as f32
-        * self.channels() as f32)
-        .ceil() as usize;
+    let start = self.trim.delay.saturating_sub(first_frame).min(frames);
+    let end = self.end_frame.map_or(frames, |end_frame| {
+      end_frame.saturating_sub(first_frame).min(frames)
+    });
 
-    // Re-align the seek position to the first channel.
-    samples_to_skip -= samples_to_skip % self.channels() as usize;
+    start..end.max(start)
+  }
 
-    // Skip ahead to the precise position.
-    for _ in 0..samples_to_skip {
-      self.next();
-    }
+  /// Decodes the next packet with audio frames into `buffer`, returning the packet's timestamp
+  ///
+  /// Encoder delay and padding are skipped, by starting `current_span_offset` after the delay and ending the span before the padding.
+  /// Returns `None` at the end of the stream or if decoding fails
+  fn decode_next_packet(&mut self) -> Option<u64> {
+    loop {
+      let packet_ts = self.decode_next_untrimmed_packet()?;
+
+      let channels = self.spec.channels.count();
+      let frames = (self.buffer.len() / channels) as u64;
+      let untrimmed = self.untrimmed_frames(packet_ts, frames);
+
+      if untrimmed.is_empty() {
+        if untrimmed.start < frames {
+          // The rest of the stream is padding
+          return None;
+        }
 
-    Ok(())
-  }
-}
+        // The whole packet is encoder delay
+        continue;
+      }
 
-impl Iterator for TrackDecoder {
-  type Item = Sample;
+      self.current_span_offset = untrimmed.start as usize * channels;
+      self.span_end = untrimmed.end as usize * channels;
+      return Some(packet_ts);
+    }
+  }
 
-  fn next(&mut self) -> Option<Self::Item> {
-    if self.current_span_offset >= self.buffer.len() {
-      let decoded = loop {
-        let packet = self.format.next_packet().ok()?;
-        let decoded = match self.decoder.decode(&packet) {
-          Ok(decoded) => decoded,
-          Err(SymphoniaError::DecodeError(_)) => {
-            // Skip over packets that cannot be decoded. This ensures the iterator
-            // continues processing subsequent packets instead of terminating due to
-            // non-critical decode errors.
-            continue;
-          }
-          Err(_) => return None,
-        };
-
-        // Loop until we get a packet with audio frames. This is necessary because some
-        // formats can have packets with only metadata, particularly when rewinding, in
-        // which case the iterator would otherwise end with `None`.
-        // Note: checking `decoded.frames()` is more reliable than `packet.dur()`, which
-        // can resturn non-zero durations for packets without audio frames.
-        if decoded.frames() > 0 {
-          break decoded;
+  fn decode_next_untrimmed_packet(&mut self) -> Option<u64> {
+    let (packet_ts, decoded) = loop {
+      let packet = self.format.next_packet().ok()?;
+      let decoded = match self.decoder.decode(&packet) {
+        Ok(decoded) => decoded,
+        Err(SymphoniaError::DecodeError(_)) => {
+          // Skip over packets that cannot be decoded. This ensures the iterator
+          // continues processing subsequent packets instead of terminating due to
+          // non-critical decode errors.
+          continue;
         }
+        Err(_) => return None,
       };
 
-      self.buffer = SampleBuffer::new(decoded.capacity() as u64, self.spec);
-      self.buffer.copy_interleaved_ref(decoded);
-      self.current_span_offset = 0;
-    }
+      // Loop until we get a packet with audio frames. This is necessary because some
+      // formats can have packets with only metadata, particularly when rewinding, in
+      // which case the iterator would otherwise end with `None`.
+      // Note: checking `decoded.frames()` is more reliable than `packet.dur()`, which
+      // can resturn non-zero durations for packets without audio frames.
+      if decoded.frames() > 0 {
+        break (packet.ts(), decoded);
+      }
+    };
 
-    let sample = *self.buffer.samples().get(self.current_span_offset)?;
-    self.current_span_offset += 1;
+    // The spec can change between packets in chained streams
+    self.spec = *decoded.spec();
+    self.buffer = SampleBuffer::new(decoded.capacity() as u64, self.spec);
+    self.buffer.copy_interleaved_ref(decoded);
 
-    Some(sample)
+    Some(packet_ts)
   }
-}
 
-impl Source for TrackDecoder {
-  #[inline]
-  fn current_span_len(&self) -> Option<usize> {
-    Some(self.buffer.len())
-  }
+  /// Decodes packets until the one containing `seek_res.required_ts`, and sets the span offset to the target frame
+  ///
+  /// The offset is calculated from each packet's own timestamp and spec, so it stays accurate
+  /// when packets are shorter or longer than expected
+  fn try_refine_position(&mut self, seek_res: SeekedTo) -> Result<(), RodioSeekError> {
+    let Some(time_base) = self.decoder.codec_params().time_base else {
+      return Ok(());
+    };
 
-  #[inline]
-  fn channels(&self) -> ChannelCount {
-    self.spec.channels.count() as ChannelCount
-  }
+    loop {
+      let Some(packet_ts) = self.decode_next_packet() else {
+        // The target is past the end of the stream
+        return Ok(());
+      };
 
-  #[inline]
-  fn sample_rate(&self) -> SampleRate {
-    self.spec.rate
+      let channels = self.channels() as usize;
+      let packet_frames = self.span_end / channels;
+
+      let time_to_skip =
+        Duration::from(time_base.calc_time(seek_res.required_ts.saturating_sub(packet_ts)));
+      let frames_to_skip =
+        (time_to_skip.as_secs_f64() * self.sample_rate() as f64).round() as usize;
+
+      if frames_to_skip < packet_frames {
+        // Aligned to the first channel, and never before the end of the encoder delay
+        self.current_span_offset = self.current_span_offset.max(frames_to_skip * channels);
+        return Ok(());
+      }
+    }
   }
 
-  #[inline]
-  fn total_duration(&self) -> Option<Duration> {
-    self.total_duration
+  /// Takes the rest of the decoded packet, or decodes the next one if it was used up
+  ///
+  /// Returns `None` at the end of the stream
+  pub fn next_chunk(&mut self) -> Option<DecodedChunk> {
+    if self.current_span_offset >= self.span_end {
+      self.decode_next_packet()?;
+    }
+
+    let samples = self.buffer.samples()[self.current_span_offset..self.span_end].to_vec();
+    self.current_span_offset = self.span_end;
+
+    Some(DecodedChunk {
+      channels: self.channels(),
+      sample_rate: self.sample_rate(),
+      samples,
+    })
   }
 
-  fn try_seek(&mut self, pos: Duration) -> Result<(), RodioSeekError> {
+  /// Seeks to the frame at `pos`, leaving the next sample on the first channel
+  pub fn seek_to_frame(&mut self, pos: Duration) -> Result<(), RodioSeekError> {
     // Seeking should be "saturating", meaning: target positions beyond the end of the stream
     // are clamped to the end.
     let mut target = pos;
@@ -149,8 +220,8 @@ impl Source for TrackDecoder {
       }
     }
 
-    // Remember the current channel, so we can restore it after seeking.
-    let active_channel = self.current_span_offset % self.channels() as usize;
+    // `total_duration` leaves out the encoder delay, but the stream's timestamps include it
+    target += Duration::from_secs_f64(self.trim.delay as f64 / self.sample_rate().max(1) as f64);
 
     let seek_res = match self.format.seek(
       SeekMode::Accurate,
@@ -177,7 +248,66 @@ impl Source for TrackDecoder {
 
     // Symphonia does not seek to the exact position, it seeks to the closest keyframe.
     // If accurate seeking is required, fast-forward to the exact position.
-    self.try_refine_position(seek_res)?;
+    self.try_refine_position(seek_res)
+  }
+}
+
+/// Samples decoded from one packet, with the spec they were decoded with
+#[derive(Debug, Default)]
+pub struct DecodedChunk {
+  pub channels: ChannelCount,
+  pub sample_rate: SampleRate,
+  pub samples: Vec<Sample>,
+}
+
+impl DecodedChunk {
+  pub fn duration(&self) -> Duration {
+    let frames = self.samples.len() / (self.channels.max(1) as usize);
+    Duration::from_secs_f64(frames as f64 / self.sample_rate.max(1) as f64)
+  }
+}
+
+impl Iterator for TrackDecoder {
+  type Item = Sample;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    if self.current_span_offset >= self.span_end {
+      self.decode_next_packet()?;
+    }
+
+    let sample = *self.buffer.samples()[..self.span_end].get(self.current_span_offset)?;
+    self.current_span_offset += 1;
+
+    Some(sample)
+  }
+}
+
+impl Source for TrackDecoder {
+  #[inline]
+  fn current_span_len(&self) -> Option<usize> {
+    Some(self.span_end)
+  }
+
+  #[inline]
+  fn channels(&self) -> ChannelCount {
+    self.spec.channels.count() as ChannelCount
+  }
+
+  #[inline]
+  fn sample_rate(&self) -> SampleRate {
+    self.spec.rate
+  }
+
+  #[inline]
+  fn total_duration(&self) -> Option<Duration> {
+    self.total_duration
+  }
+
+  fn try_seek(&mut self, pos: Duration) -> Result<(), RodioSeekError> {
+    // Remember the current channel, so we can restore it after seeking.
+    let active_channel = self.current_span_offset % self.channels() as usize;
+
+    self.seek_to_frame(pos)?;
 
     // After seeking, we are at the beginning of an inter-sample frame, i.e. the first
     // channel. We need to advance the iterator to the right channel.
@@ -188,3 +318,86 @@ impl Source for TrackDecoder {
     Ok(())
   }
 }
+
+#[cfg(test)]
+pub mod tests {
+  use std::path::Path;
+
+  use super::*;
+  use crate::{audio_server::player::tests::write_wav_samples, config::TagConfig};
+
+  pub const FRAMES: usize = 8000;
+
+  /// Loads a WAV file whose samples count up from 0, so a sample's value is its frame
+  pub fn load_ramp(dir: &Path, trim: GaplessTrim) -> TrackDecoder {
+    let path = dir.join("ramp.wav");
+    let samples: Vec<i16> = (0..FRAMES as i16).collect();
+    write_wav_samples(&path, &samples);
+
+    let scheduler = BlockingScheduler::new();
+    let track = smol::block_on(track::load_file(
+      TrackPath::new(path),
+      &[],
+      &scheduler,
+      Lane::Interactive,
+      &TagConfig::default(),
+    ))
+    .unwrap();
+
+    TrackDecoder::new_sync(Arc::new(LoadedTrack { trim, ..track })).unwrap()
+  }
+
+  pub fn frame(sample: Sample) -> i64 {
+    (sample * 32768.0).round() as i64
+  }
+
+  #[test]
+  fn plays_untrimmed_track_whole() {
+    let dir = tempfile::tempdir().unwrap();
+    let frames: Vec<i64> = load_ramp(dir.path(), GaplessTrim::default())
+      .map(frame)
+      .collect();
+
+    assert_eq!(frames, (0..FRAMES as i64).collect::<Vec<_>>());
+  }
+
+  #[test]
+  fn trims_delay_and_padding() {
+    let dir = tempfile::tempdir().unwrap();
+    let trim = GaplessTrim {
+      delay: 1000,
+      padding: 500,
+      applied_by_symphonia: false,
+    };
+    let frames: Vec<i64> = load_ramp(dir.path(), trim).map(frame).collect();
+
+    assert_eq!(frames, (1000..FRAMES as i64 - 500).collect::<Vec<_>>());
+  }
+
+  #[test]
+  fn leaves_trim_applied_by_symphonia() {
+    let dir = tempfile::tempdir().unwrap();
+    let trim = GaplessTrim {
+      delay: 1000,
+      padding: 500,
+      applied_by_symphonia: true,
+    };
+
+    assert_eq!(load_ramp(dir.path(), trim).count(), FRAMES);
+  }
+
+  #[test]
+  fn seeks_past_the_delay() {
+    let dir = tempfile::tempdir().unwrap();
+    let trim = GaplessTrim {
+      delay: 1000,
+      padding: 0,
+      applied_by_symphonia: false,
+    };
+    let mut decoder = load_ramp(dir.path(), trim);
+
+    decoder.try_seek(Duration::from_millis(250)).unwrap();
+    // A quarter of a second at 8 kHz is 2000 frames, after the 1000 frames of delay
+    assert_eq!(decoder.next().map(frame), Some(3000));
+  }
+}
@@ -1,11 +1,22 @@
-use std::{fmt::Debug, mem, sync::Arc, time::Duration};
+use std::{
+  fmt::Debug,
+  mem,
+  sync::{Arc, atomic::Ordering},
+  time::Duration,
+};
 
-use rodio::{Sample, Source, source};
+use hsm_ipc::TrackId;
+use rodio::{
+  ChannelCount, Sample, SampleRate, Source,
+  source::{self, UniformSourceIterator},
+};
+use smol::channel::Sender;
 
-use super::Controls;
+use super::{Controls, controlled_source::SourceEvent};
 
 pub enum SourceQueueState {
-  Queued(Box<dyn Source + Send>),
+  /// A source waiting to be played, and the id of the track it was loaded from
+  Queued(TrackId, Box<dyn Source + Send>),
   Playing,
   None,
 }
@@ -13,7 +24,7 @@ pub enum SourceQueueState {
 impl Debug for SourceQueueState {
   fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
     match self {
-      Self::Queued(_) => write!(f, "Queued(Box<dyn Source>)"),
+      Self::Queued(track_id, _) => write!(f, "Queued({track_id:?}, Box<dyn Source>)"),
       Self::Playing => write!(f, "Playing"),
       Self::None => write!(f, "None"),
     }
@@ -22,25 +33,39 @@ impl Debug for SourceQueueState {
 
 impl SourceQueueState {
   pub fn is_queued(&self) -> bool {
-    return matches!(self, Self::Queued(_));
+    return matches!(self, Self::Queued(..));
+  }
+
+  pub fn queued_track_id(&self) -> Option<TrackId> {
+    match self {
+      Self::Queued(track_id, _) => Some(*track_id),
+      Self::Playing | Self::None => None,
+    }
   }
 
   pub fn is_playing(&self) -> bool {
     return !matches!(self, Self::None);
   }
 
+  pub fn queued_sample_rate(&self) -> Option<SampleRate> {
+    match self {
+      Self::Queued(_, source) => Some(source.sample_rate()),
+      Self::Playing | Self::None => None,
+    }
+  }
+
   pub fn invalidate(&mut self) {
     match self {
-      Self::Queued(_) => *self = Self::Playing,
+      Self::Queued(..) => *self = Self::Playing,
       Self::Playing | Self::None => (),
     }
   }
 
   pub fn consume(&mut self) -> Option<Box<dyn Source + Send>> {
     match self {
-      Self::Queued(_) => {
+      Self::Queued(..) => {
         let state = mem::replace(self, Self::Playing);
-        let Self::Queued(source) = state else {
+        let Self::Queued(_, source) = state else {
           unreachable!("Moved out of a SourceQueueState::Queued")
         };
 
@@ -55,28 +80,209 @@ impl SourceQueueState {
   }
 }
 
+/// Sends `SourceEvent::InterruptFinished` when dropped, so the player hears about the end of an interrupt
+/// even if the output playing it is replaced
+pub struct InterruptGuard(pub Sender<SourceEvent>);
+
+impl Drop for InterruptGuard {
+  fn drop(&mut self) {
+    let _ = self.0.try_send(SourceEvent::InterruptFinished);
+  }
+}
+
+/// A source played instead of the track queue until it ends, see `Player::play_oneshot`
+pub struct Interrupt {
+  pub source: Box<dyn Source + Send>,
+  pub guard: InterruptGuard,
+}
+
+impl Debug for Interrupt {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "Interrupt(Box<dyn Source>)")
+  }
+}
+
+/// The source that was playing when an interrupt started, resumed where it was once the interrupt ends
+struct Interrupted {
+  source: Box<dyn Source + Send>,
+  samples: usize,
+  _guard: InterruptGuard,
+}
+
+/// Silence played while no source is queued
+struct Filler {
+  channels: ChannelCount,
+  sample_rate: SampleRate,
+  remaining: usize,
+}
+
+impl Iterator for Filler {
+  type Item = Sample;
+
+  #[inline]
+  fn next(&mut self) -> Option<Self::Item> {
+    self.remaining = self.remaining.checked_sub(1)?;
+    Some(0.0)
+  }
+
+  #[inline]
+  fn size_hint(&self) -> (usize, Option<usize>) {
+    (self.remaining, Some(self.remaining))
+  }
+}
+
+impl Source for Filler {
+  #[inline]
+  fn current_span_len(&self) -> Option<usize> {
+    Some(self.remaining)
+  }
+
+  #[inline]
+  fn channels(&self) -> ChannelCount {
+    self.channels
+  }
+
+  #[inline]
+  fn sample_rate(&self) -> SampleRate {
+    self.sample_rate
+  }
+
+  #[inline]
+  fn total_duration(&self) -> Option<Duration> {
+    None
+  }
+
+  #[inline]
+  fn try_seek(&mut self, _pos: Duration) -> Result<(), source::SeekError> {
+    Ok(())
+  }
+}
+
 pub struct PlayerAudioOutput {
   current: Box<dyn Source + Send>,
+  /// Samples pulled from `current`, so an interrupt only starts between frames
+  current_samples: usize,
+  interrupted: Option<Interrupted>,
+  /// The spec of the last source that was played, so the filler doesn't make the mixer resample or remix
+  filler_spec: (ChannelCount, SampleRate),
   controls: Arc<Controls>,
+  output_rate_tx: Sender<SampleRate>,
+  /// The sample rate of the output stream this is connected to
+  sample_rate: SampleRate,
+  /// The sample rate a new output stream was last requested with
+  requested_rate: Option<SampleRate>,
+  /// Samples pulled since `Controls::samples_pulled` was last updated
+  unpublished_samples: usize,
 }
 
 impl PlayerAudioOutput {
   const THRESHOLD: usize = 512;
 
-  pub(super) fn new(controls: Arc<Controls>) -> Self {
+  pub(super) fn new(
+    controls: Arc<Controls>,
+    output_rate_tx: Sender<SampleRate>,
+    sample_rate: SampleRate,
+    channels: ChannelCount,
+  ) -> Self {
     Self {
       current: Box::new(source::Empty::new()) as Box<_>,
+      current_samples: 0,
+      interrupted: None,
+      filler_spec: (channels, sample_rate),
       controls,
+      output_rate_tx,
+      sample_rate,
+      requested_rate: None,
+      unpublished_samples: 0,
     }
   }
 
+  /// In bit perfect mode, returns true if the queued source must wait for the output stream
+  /// to be reopened with its sample rate
+  ///
+  /// A rate change is requested once per queued rate, and the source will stop waiting if it is declined
+  fn should_wait_for_rate_change(&mut self, queued_rate: Option<SampleRate>) -> bool {
+    let Some(queued_rate) = queued_rate else {
+      return false;
+    };
+
+    if !self.controls.bit_perfect.load(Ordering::Relaxed) || queued_rate == self.sample_rate {
+      return false;
+    }
+
+    if self.requested_rate == Some(queued_rate) {
+      return self.controls.declined_output_rate.load(Ordering::Acquire) != queued_rate;
+    }
+
+    self
+      .controls
+      .declined_output_rate
+      .store(0, Ordering::Release);
+    self.requested_rate = Some(queued_rate);
+    self.output_rate_tx.try_send(queued_rate).is_ok()
+  }
+
+  fn filler(&self) -> Filler {
+    let (channels, sample_rate) = self.filler_spec;
+
+    Filler {
+      channels,
+      sample_rate,
+      // Whole frames, so the channels don't swap when the next source starts
+      remaining: Self::THRESHOLD - Self::THRESHOLD % usize::from(channels.max(1)),
+    }
+  }
+
+  /// Holds the current source and plays the interrupt waiting in `Controls::interrupt` instead
+  fn start_interrupt(&mut self) {
+    self
+      .controls
+      .interrupt_pending
+      .store(false, Ordering::Release);
+    let interrupt = self
+      .controls
+      .interrupt
+      .lock()
+      .expect("Interrupt lock should not be poisoned")
+      .take();
+    let Some(Interrupt { source, guard }) = interrupt else {
+      return;
+    };
+
+    // Converted to the format of the held source, so the output's format doesn't change in the middle of a span
+    let source =
+      UniformSourceIterator::new(source, self.current.channels(), self.current.sample_rate());
+    let held = mem::replace(&mut self.current, Box::new(source));
+    self.interrupted = Some(Interrupted {
+      source: held,
+      samples: mem::take(&mut self.current_samples),
+      _guard: guard,
+    });
+  }
+
   fn load_next(&mut self) {
+    // Lock through a clone, so `self` can still be borrowed mutably while the queue is locked
+    let controls = self.controls.clone();
+    self.current_samples = 0;
+
     self.current = {
-      let mut next = self.controls.source_queue.lock_blocking();
+      let mut next = controls.source_queue.lock_blocking();
 
-      match next.consume() {
-        Some(next) => next,
-        None => Box::new(source::Zero::new_samples(1, 44100, Self::THRESHOLD)) as Box<_>,
+      let next_source = if self.should_wait_for_rate_change(next.queued_sample_rate()) {
+        None
+      } else {
+        next.consume()
+      };
+
+      match next_source {
+        Some(next) => {
+          // A skip still pending was meant for a source that already ended, such as one racing the filler
+          controls.to_skip.store(0, Ordering::Release);
+          controls.wake_queue_waiters();
+          self.filler_spec = (next.channels(), next.sample_rate());
+          next
+        }
+        None => Box::new(self.filler()) as Box<_>,
       }
     }
   }
@@ -87,12 +293,38 @@ impl Iterator for PlayerAudioOutput {
 
   #[inline]
   fn next(&mut self) -> Option<Self::Item> {
+    self.unpublished_samples += 1;
+    if self.unpublished_samples == Self::THRESHOLD {
+      self
+        .controls
+        .samples_pulled
+        .fetch_add(Self::THRESHOLD as u64, Ordering::Relaxed);
+      self.unpublished_samples = 0;
+    }
+
+    if self.controls.interrupt_pending.load(Ordering::Acquire)
+      && self.interrupted.is_none()
+      && self
+        .current_samples
+        .is_multiple_of(usize::from(self.current.channels().max(1)))
+    {
+      self.start_interrupt();
+    }
+
     loop {
       if let Some(sample) = self.current.next() {
+        self.current_samples += 1;
         return Some(sample);
       }
 
-      self.load_next();
+      // The held source continues from the sample it stopped at
+      match self.interrupted.take() {
+        Some(interrupted) => {
+          self.current = interrupted.source;
+          self.current_samples = interrupted.samples;
+        }
+        None => self.load_next(),
+      }
     }
   }
 
@@ -121,8 +353,8 @@ impl Source for PlayerAudioOutput {
       if val != 0 {
         return Some(val);
       } else {
-        // The next source will be a filler silence which will have the length of `THRESHOLD`
-        return Some(Self::THRESHOLD);
+        // The next source will be filler silence, unless a source is queued
+        return Some(self.filler().remaining);
       }
     }
 
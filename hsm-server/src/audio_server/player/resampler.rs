@@ -0,0 +1,286 @@
+use std::{array, collections::VecDeque, f64::consts::PI, time::Duration};
+
+use rodio::{ChannelCount, Sample, SampleRate, Source, source::SeekError as RodioSeekError};
+
+/// Input frames on each side of an output frame that contribute to it
+const HALF_TAPS: usize = 16;
+/// Kernel values stored per input frame of distance, values in between are interpolated
+const PHASES: usize = 256;
+/// Fraction of the lower nyquist frequency that is kept, the rest is the filter's transition band
+const PASSBAND: f64 = 0.95;
+
+/// Converts a `Source` to another sample rate with a windowed-sinc filter
+///
+/// Costs more cpu than rodio's linear conversion, but doesn't alias on sustained high frequencies.
+/// The channels and sample rate are read once, so the source must not change them between spans
+pub struct SincResampler<S> {
+  input: S,
+  channels: usize,
+  from_rate: SampleRate,
+  to_rate: SampleRate,
+  /// Half of a symmetric kernel, indexed by distance from the output frame times `PHASES`
+  kernel: Vec<f32>,
+  /// Interleaved input frames from `HALF_TAPS - 1` before the current input frame to `HALF_TAPS` after it
+  window: VecDeque<Sample>,
+  /// How far the output frame is past the current input frame, in units of `1 / to_rate`
+  frac: u32,
+  /// Silent frames added after the input ended, so the last frames fade out through the filter
+  padding: usize,
+  frame: Vec<Sample>,
+  offset: usize,
+}
+
+impl<S: Source> SincResampler<S> {
+  pub fn new(input: S, to_rate: SampleRate) -> Self {
+    let from_rate = input.sample_rate();
+    let channels = usize::from(input.channels().max(1));
+
+    let mut resampler = Self {
+      input,
+      channels,
+      from_rate,
+      to_rate,
+      kernel: kernel(from_rate, to_rate),
+      window: VecDeque::with_capacity(2 * HALF_TAPS * channels),
+      frac: 0,
+      padding: 0,
+      frame: Vec::with_capacity(channels),
+      offset: 0,
+    };
+    resampler.fill_window();
+
+    resampler
+  }
+
+  /// Starts the window at the input's current frame, with silence before it
+  fn fill_window(&mut self) {
+    self.window.clear();
+    self.window.resize((HALF_TAPS - 1) * self.channels, 0.0);
+    self.frac = 0;
+    self.padding = 0;
+    self.frame.clear();
+    self.offset = 0;
+
+    for _ in 0..=HALF_TAPS {
+      self.push_input_frame();
+    }
+  }
+
+  fn push_input_frame(&mut self) {
+    let mut ended = false;
+    for _ in 0..self.channels {
+      let sample = self.input.next().unwrap_or_else(|| {
+        ended = true;
+        0.0
+      });
+      self.window.push_back(sample);
+    }
+
+    if ended {
+      self.padding += 1;
+    }
+  }
+
+  fn advance_input_frame(&mut self) {
+    self.window.drain(..self.channels);
+    self.push_input_frame();
+  }
+
+  /// Computes the next output frame into `frame`, or returns false once the input is used up
+  fn next_frame(&mut self) -> bool {
+    // The current input frame is the `HALF_TAPS + 1`th frame pushed after the input ended
+    if self.padding > HALF_TAPS {
+      return false;
+    }
+
+    let position = self.frac as f64 / self.to_rate as f64;
+    // Taps start `HALF_TAPS - 1` frames before the current input frame
+    let weights: [f32; 2 * HALF_TAPS] =
+      array::from_fn(|tap| self.weight(position + (HALF_TAPS - 1) as f64 - tap as f64));
+
+    self.frame.clear();
+    self.frame.resize(self.channels, 0.0);
+
+    let window = self.window.make_contiguous();
+    for (frame, weight) in window.chunks_exact(self.channels).zip(weights) {
+      for (output, &input) in self.frame.iter_mut().zip(frame) {
+        *output += input * weight;
+      }
+    }
+
+    self.frac += self.from_rate;
+    while self.frac >= self.to_rate {
+      self.frac -= self.to_rate;
+      self.advance_input_frame();
+    }
+
+    true
+  }
+
+  fn weight(&self, distance: f64) -> f32 {
+    let index = distance.abs() * PHASES as f64;
+    let lower = index as usize;
+    let Some(&low) = self.kernel.get(lower) else {
+      return 0.0;
+    };
+    let high = self.kernel.get(lower + 1).copied().unwrap_or(0.0);
+
+    low + (high - low) * (index - lower as f64) as f32
+  }
+}
+
+/// Lowpass at the lower of the two nyquist frequencies, scaled so the gain is 1 when downsampling
+fn kernel(from_rate: SampleRate, to_rate: SampleRate) -> Vec<f32> {
+  let cutoff = PASSBAND * (to_rate as f64 / from_rate as f64).min(1.0);
+
+  (0..=HALF_TAPS * PHASES)
+    .map(|index| {
+      let distance = index as f64 / PHASES as f64;
+      let sinc = if index == 0 {
+        1.0
+      } else {
+        (PI * cutoff * distance).sin() / (PI * cutoff * distance)
+      };
+      // Blackman window, reaching zero at `HALF_TAPS` frames away
+      let x = PI * (distance / HALF_TAPS as f64 + 1.0);
+      let window = 0.42 - 0.5 * x.cos() + 0.08 * (2.0 * x).cos();
+
+      (cutoff * sinc * window) as f32
+    })
+    .collect()
+}
+
+impl<S: Source> Iterator for SincResampler<S> {
+  type Item = Sample;
+
+  #[inline]
+  fn next(&mut self) -> Option<Self::Item> {
+    if self.offset >= self.frame.len() {
+      if !self.next_frame() {
+        return None;
+      }
+      self.offset = 0;
+    }
+
+    let sample = self.frame[self.offset];
+    self.offset += 1;
+
+    Some(sample)
+  }
+}
+
+impl<S: Source> Source for SincResampler<S> {
+  #[inline]
+  fn current_span_len(&self) -> Option<usize> {
+    None
+  }
+
+  #[inline]
+  fn channels(&self) -> ChannelCount {
+    self.channels as ChannelCount
+  }
+
+  #[inline]
+  fn sample_rate(&self) -> SampleRate {
+    self.to_rate
+  }
+
+  #[inline]
+  fn total_duration(&self) -> Option<Duration> {
+    self.input.total_duration()
+  }
+
+  fn try_seek(&mut self, pos: Duration) -> Result<(), RodioSeekError> {
+    self.input.try_seek(pos)?;
+    self.fill_window();
+
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use rodio::{buffer::SamplesBuffer, source::UniformSourceIterator};
+
+  use super::*;
+
+  const FROM_RATE: SampleRate = 44100;
+  const TO_RATE: SampleRate = 48000;
+
+  fn rms(samples: &[f32]) -> f64 {
+    let sum: f64 = samples.iter().map(|&sample| (sample as f64).powi(2)).sum();
+    (sum / samples.len() as f64).sqrt()
+  }
+
+  fn dbfs(rms: f64) -> f64 {
+    20.0 * rms.log10()
+  }
+
+  /// Drops the frames the filter blends with the silence around the input
+  fn trim(samples: &[f32]) -> &[f32] {
+    &samples[2 * HALF_TAPS..samples.len() - 2 * HALF_TAPS]
+  }
+
+  /// Exponential sweep from 20Hz to 16kHz, sampled at `rate`
+  fn sweep(rate: SampleRate, duration: f64) -> Vec<f32> {
+    let (start, end) = (20.0f64, 16000.0f64);
+    let growth = (end / start).ln();
+    let frames = (duration * rate as f64) as usize;
+
+    (0..frames)
+      .map(|frame| {
+        let time = frame as f64 / rate as f64;
+        let phase = 2.0 * PI * start * duration / growth * ((time * growth / duration).exp() - 1.0);
+        (0.5 * phase.sin()) as f32
+      })
+      .collect()
+  }
+
+  #[test]
+  fn keeps_the_rms_of_a_sine() {
+    let sine = (0..FROM_RATE)
+      .map(|frame| (0.5 * (2.0 * PI * 1000.0 * frame as f64 / FROM_RATE as f64).sin()) as f32)
+      .collect::<Vec<_>>();
+    let sine = SamplesBuffer::new(1, FROM_RATE, sine);
+    let output: Vec<_> = SincResampler::new(sine, TO_RATE).collect();
+
+    // A sine's RMS is its amplitude over the square root of 2, -9.03 dBFS at half amplitude
+    let output_rms = rms(trim(&output));
+    assert!(
+      (output_rms - 0.5 / 2f64.sqrt()).abs() < 1e-3,
+      "{output_rms}"
+    );
+    assert!(
+      (dbfs(output_rms) - -9.03).abs() < 0.01,
+      "{}",
+      dbfs(output_rms)
+    );
+  }
+
+  #[test]
+  fn sweep_is_closer_to_the_reference_than_linear_conversion() {
+    let duration = 2.0;
+    let input = sweep(FROM_RATE, duration);
+    // The sweep computed directly at the output rate is the ideal resample
+    let reference = sweep(TO_RATE, duration);
+
+    let error = |output: Vec<f32>| {
+      let error: Vec<_> = reference
+        .iter()
+        .zip(&output)
+        .map(|(expected, actual)| actual - expected)
+        .collect();
+      dbfs(rms(trim(&error)))
+    };
+
+    let sinc =
+      error(SincResampler::new(SamplesBuffer::new(1, FROM_RATE, input.clone()), TO_RATE).collect());
+    let linear = error(
+      UniformSourceIterator::new(SamplesBuffer::new(1, FROM_RATE, input), 1, TO_RATE).collect(),
+    );
+
+    println!("RMS error: sinc {sinc:.1} dBFS, linear {linear:.1} dBFS");
+    assert!(sinc < -80.0, "{sinc}");
+    assert!(sinc < linear - 20.0, "sinc {sinc}, linear {linear}");
+  }
+}
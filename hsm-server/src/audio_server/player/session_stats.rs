@@ -0,0 +1,113 @@
+use std::{
+  collections::HashMap,
+  sync::{Mutex, MutexGuard},
+  time::{Duration, Instant, SystemTime},
+};
+
+use hsm_ipc::{SessionStats, Track};
+
+/// Why the player moved away from a track
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrackOutcome {
+  Finished,
+  /// The user skipped to the next track
+  Skipped,
+  /// Playback was stopped, or the user went back to the previous track
+  Left,
+}
+
+#[derive(Debug)]
+struct Counters {
+  since: SystemTime,
+  listening_time: Duration,
+  tracks_finished: u64,
+  tracks_skipped: u64,
+  /// Listening time per artist, artists that did not fit are counted under `None`
+  artists: HashMap<Option<String>, Duration>,
+}
+
+impl Counters {
+  fn new() -> Self {
+    Self {
+      since: SystemTime::now(),
+      listening_time: Duration::ZERO,
+      tracks_finished: 0,
+      tracks_skipped: 0,
+      artists: HashMap::new(),
+    }
+  }
+}
+
+/// Collects the `SessionStats` as the player moves between tracks
+#[derive(Debug)]
+pub struct SessionTracker {
+  started: Instant,
+  counters: Mutex<Counters>,
+}
+
+impl SessionTracker {
+  /// Keeps the artist map small during long sessions
+  const MAX_ARTISTS: usize = 1000;
+
+  pub fn new() -> Self {
+    Self {
+      started: Instant::now(),
+      counters: Mutex::new(Counters::new()),
+    }
+  }
+
+  fn counters(&self) -> MutexGuard<'_, Counters> {
+    self
+      .counters
+      .lock()
+      .expect("Session stats lock should not be poisoned")
+  }
+
+  /// Records that `track` was played for `listened` before the player moved away from it
+  pub fn record(&self, track: &Track, listened: Duration, outcome: TrackOutcome) {
+    let mut counters = self.counters();
+    counters.listening_time += listened;
+
+    match outcome {
+      TrackOutcome::Finished => counters.tracks_finished += 1,
+      TrackOutcome::Skipped => counters.tracks_skipped += 1,
+      TrackOutcome::Left => (),
+    }
+
+    for artist in track.metadata.artists_or_inferred() {
+      let key = Some(artist.to_owned());
+      let key = if counters.artists.len() < Self::MAX_ARTISTS || counters.artists.contains_key(&key)
+      {
+        key
+      } else {
+        None
+      };
+
+      *counters.artists.entry(key).or_default() += listened;
+    }
+  }
+
+  pub fn reset(&self) {
+    *self.counters() = Counters::new();
+  }
+
+  pub fn stats(&self) -> SessionStats {
+    let counters = self.counters();
+
+    let top_artist = counters
+      .artists
+      .iter()
+      .filter_map(|(artist, listened)| Some((artist.as_ref()?, *listened)))
+      .max_by_key(|(_, listened)| *listened)
+      .map(|(artist, listened)| (artist.clone(), listened));
+
+    SessionStats {
+      uptime: self.started.elapsed(),
+      since: counters.since,
+      listening_time: counters.listening_time,
+      tracks_finished: counters.tracks_finished,
+      tracks_skipped: counters.tracks_skipped,
+      top_artist,
+    }
+  }
+}
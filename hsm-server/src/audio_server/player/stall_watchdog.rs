@@ -0,0 +1,108 @@
+use std::time::{Duration, Instant};
+
+/// Decides when `Player::run` has stopped handling source events
+///
+/// Sources only send events while they play, so the loop is only considered stalled while events are waiting for it.
+/// Handling the end of a track loads the next one, so the timeout leaves room for slow drives
+#[derive(Debug)]
+pub struct StallWatchdog {
+  events_taken: u64,
+  /// When events were first seen waiting without the loop taking any
+  waiting_since: Option<Instant>,
+}
+
+impl StallWatchdog {
+  /// How often the event counts are checked
+  pub const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+  /// How long events can wait without any being taken before the loop is considered stalled
+  const STALL_TIMEOUT: Duration = Duration::from_secs(60);
+
+  pub fn new(events_taken: u64) -> Self {
+    Self {
+      events_taken,
+      waiting_since: None,
+    }
+  }
+
+  /// Returns how long events have waited if the loop is stalled
+  pub fn check(
+    &mut self,
+    events_taken: u64,
+    events_waiting: usize,
+    now: Instant,
+  ) -> Option<Duration> {
+    if events_taken != self.events_taken || events_waiting == 0 {
+      self.events_taken = events_taken;
+      self.waiting_since = None;
+      return None;
+    }
+
+    let waited = now.duration_since(*self.waiting_since.get_or_insert(now));
+    (waited >= Self::STALL_TIMEOUT).then_some(waited)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const SECOND: Duration = Duration::from_secs(1);
+
+  #[test]
+  fn fires_once_events_wait_past_the_timeout() {
+    let start = Instant::now();
+    let mut watchdog = StallWatchdog::new(0);
+
+    // An event starts waiting and is never taken
+    for secs in 0..60 {
+      assert_eq!(watchdog.check(0, 1, start + secs * SECOND), None, "{secs}s");
+    }
+    assert_eq!(watchdog.check(0, 1, start + 60 * SECOND), Some(60 * SECOND));
+    assert_eq!(watchdog.check(0, 2, start + 61 * SECOND), Some(61 * SECOND));
+  }
+
+  #[test]
+  fn taking_events_resets_the_wait() {
+    let start = Instant::now();
+    let mut watchdog = StallWatchdog::new(0);
+
+    // Events keep waiting, but the loop takes some between every check
+    for secs in 0..120 {
+      let taken = secs as u64 / 2;
+      assert_eq!(
+        watchdog.check(taken, 1, start + secs * SECOND),
+        None,
+        "{secs}s"
+      );
+    }
+
+    // The wait starts at the first check that finds no new events taken
+    assert_eq!(watchdog.check(59, 1, start + 178 * SECOND), None);
+    assert_eq!(
+      watchdog.check(59, 1, start + 179 * SECOND),
+      Some(60 * SECOND)
+    );
+  }
+
+  #[test]
+  fn idle_loop_is_not_stalled() {
+    let start = Instant::now();
+    let mut watchdog = StallWatchdog::new(5);
+
+    // Nothing waiting, such as while paused, however long nothing is taken
+    assert_eq!(watchdog.check(5, 0, start), None);
+    assert_eq!(watchdog.check(5, 0, start + 600 * SECOND), None);
+
+    // The wait only starts once events are waiting
+    assert_eq!(watchdog.check(5, 1, start + 601 * SECOND), None);
+    assert_eq!(watchdog.check(5, 1, start + 660 * SECOND), None);
+    assert_eq!(
+      watchdog.check(5, 1, start + 661 * SECOND),
+      Some(60 * SECOND)
+    );
+
+    // Draining the queue resets it
+    assert_eq!(watchdog.check(5, 0, start + 662 * SECOND), None);
+    assert_eq!(watchdog.check(5, 1, start + 663 * SECOND), None);
+  }
+}
@@ -0,0 +1,283 @@
+use std::{collections::HashMap, io, mem, process};
+
+use serde::Deserialize;
+use smol::{
+  io::{AsyncBufReadExt, BufReader},
+  lock::Mutex,
+  process::{Command, Stdio},
+  stream::StreamExt,
+};
+use thiserror::Error;
+
+/// The raw volume the sound server plays a stream at without changing it
+const VOLUME_NORM: f32 = 65536.0;
+/// Volumes closer than this are treated as the same, so reading back a volume hsm set isn't reported as a change
+const VOLUME_TOLERANCE: f32 = 0.001;
+
+#[derive(Debug, Error)]
+pub enum SystemVolumeError {
+  #[error("Failed to run pactl: {0}")]
+  SpawnFailed(#[from] io::Error),
+
+  #[error("pactl {command} failed: {stderr}")]
+  CommandFailed { command: String, stderr: String },
+
+  #[error("Failed to parse the output of pactl: {0}")]
+  ParseFailed(#[from] serde_json::Error),
+
+  #[error("pactl subscribe exited")]
+  SubscribeExited,
+}
+
+/// What changed about the server's stream in the system mixer
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StreamChange {
+  /// The stream was opened or replaced, such as after the output reconnected
+  Opened,
+  /// Something outside of hsm, such as a desktop volume key, changed the stream's volume
+  VolumeChanged(f32),
+}
+
+#[derive(Debug, Deserialize)]
+struct SinkInput {
+  index: u32,
+  #[serde(default)]
+  properties: HashMap<String, serde_json::Value>,
+  #[serde(default)]
+  volume: HashMap<String, ChannelVolume>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ChannelVolume {
+  value: u32,
+}
+
+impl SinkInput {
+  fn is_own_stream(&self) -> bool {
+    let own_pid = process::id().to_string();
+    self
+      .properties
+      .get("application.process.id")
+      .and_then(serde_json::Value::as_str)
+      .is_some_and(|pid| pid == own_pid)
+  }
+
+  /// The loudest channel's volume, from 0 to 1
+  fn volume(&self) -> f32 {
+    let raw = self
+      .volume
+      .values()
+      .map(|channel| channel.value)
+      .max()
+      .unwrap_or(0);
+    (raw as f32 / VOLUME_NORM).clamp(0.0, 1.0)
+  }
+}
+
+/// The volume of the server's own stream in the system mixer, which desktop volume keys and OSDs control
+///
+/// Uses `pactl`, which works with PulseAudio and with PipeWire through `pipewire-pulse`
+#[derive(Debug)]
+pub struct SystemVolume {
+  /// The sink input index and volume of the stream, as last seen
+  stream: Mutex<Option<(u32, f32)>>,
+}
+
+impl SystemVolume {
+  pub fn new() -> Self {
+    Self {
+      stream: Mutex::new(None),
+    }
+  }
+
+  async fn pactl(args: &[&str]) -> Result<Vec<u8>, SystemVolumeError> {
+    let output = Command::new("pactl")
+      .args(args)
+      .stdin(Stdio::null())
+      .output()
+      .await?;
+
+    if !output.status.success() {
+      return Err(SystemVolumeError::CommandFailed {
+        command: args.join(" "),
+        stderr: String::from_utf8_lossy(&output.stderr).trim().into(),
+      });
+    }
+
+    Ok(output.stdout)
+  }
+
+  /// The sink input index and volume of the server's stream, `None` while no output stream is open
+  async fn find_stream() -> Result<Option<(u32, f32)>, SystemVolumeError> {
+    let output = Self::pactl(&["--format=json", "list", "sink-inputs"]).await?;
+    let sink_inputs: Vec<SinkInput> = serde_json::from_slice(&output)?;
+    Ok(newest_own_stream(&sink_inputs))
+  }
+
+  /// Sets the stream's volume, does nothing while there is no stream since `watch` applies it once one opens
+  pub async fn set_volume(&self, volume: f32) -> Result<(), SystemVolumeError> {
+    let Some((index, _)) = Self::find_stream().await? else {
+      *self.stream.lock().await = None;
+      return Ok(());
+    };
+
+    let raw = ((volume * VOLUME_NORM).round() as u32).to_string();
+    Self::pactl(&["set-sink-input-volume", &index.to_string(), &raw]).await?;
+    *self.stream.lock().await = Some((index, volume));
+
+    Ok(())
+  }
+
+  /// Waits for the sound server to report a change to the server's stream
+  ///
+  /// Returns an error if `pactl subscribe` can't be started or exits, such as when the sound server restarts
+  pub async fn watch<F: Future<Output = ()>>(
+    &self,
+    mut on_change: impl FnMut(StreamChange) -> F,
+  ) -> Result<(), SystemVolumeError> {
+    let mut subscribe = Command::new("pactl")
+      .arg("subscribe")
+      .stdin(Stdio::null())
+      .stdout(Stdio::piped())
+      .kill_on_drop(true)
+      .spawn()?;
+    let stdout = subscribe
+      .stdout
+      .take()
+      .ok_or(SystemVolumeError::SubscribeExited)?;
+
+    // The stream may have opened before the subscription started
+    self.check_stream(&mut on_change).await?;
+
+    let mut lines = BufReader::new(stdout).lines();
+    while let Some(line) = lines.next().await {
+      // Such as `Event 'change' on sink-input #42`
+      if line?.contains("on sink-input #") {
+        self.check_stream(&mut on_change).await?;
+      }
+    }
+
+    Err(SystemVolumeError::SubscribeExited)
+  }
+
+  async fn check_stream<F: Future<Output = ()>>(
+    &self,
+    on_change: &mut impl FnMut(StreamChange) -> F,
+  ) -> Result<(), SystemVolumeError> {
+    let current = Self::find_stream().await?;
+    let previous = mem::replace(&mut *self.stream.lock().await, current);
+
+    if let Some(change) = stream_change(previous, current) {
+      on_change(change).await;
+    }
+
+    Ok(())
+  }
+}
+
+/// The sink input index and volume of the server's stream
+///
+/// The newest stream is the one being played through if the output was just reopened
+fn newest_own_stream(sink_inputs: &[SinkInput]) -> Option<(u32, f32)> {
+  sink_inputs
+    .iter()
+    .filter(|sink_input| sink_input.is_own_stream())
+    .max_by_key(|sink_input| sink_input.index)
+    .map(|sink_input| (sink_input.index, sink_input.volume()))
+}
+
+/// What changed between two sightings of the stream, `None` if nothing did or the stream closed
+fn stream_change(
+  previous: Option<(u32, f32)>,
+  current: Option<(u32, f32)>,
+) -> Option<StreamChange> {
+  match (previous, current) {
+    (_, None) => None,
+    (Some((prev_index, prev_volume)), Some((index, volume))) if prev_index == index => {
+      ((volume - prev_volume).abs() > VOLUME_TOLERANCE)
+        .then_some(StreamChange::VolumeChanged(volume))
+    }
+    (_, Some(_)) => Some(StreamChange::Opened),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// `pactl --format=json list sink-inputs` output with a stream of this process and one of another
+  fn sink_inputs_json(own_index: u32, own_volumes: [u32; 2]) -> String {
+    let pid = process::id();
+    format!(
+      r#"[
+        {{
+          "index": 7,
+          "properties": {{ "application.process.id": "1" }},
+          "volume": {{ "mono": {{ "value": 65536, "value_percent": "100%" }} }}
+        }},
+        {{
+          "index": {own_index},
+          "properties": {{ "application.process.id": "{pid}", "media.name": "hsm" }},
+          "volume": {{
+            "front-left": {{ "value": {}, "value_percent": "" }},
+            "front-right": {{ "value": {}, "value_percent": "" }}
+          }}
+        }}
+      ]"#,
+      own_volumes[0], own_volumes[1]
+    )
+  }
+
+  fn parse(json: &str) -> Vec<SinkInput> {
+    serde_json::from_str(json).unwrap()
+  }
+
+  #[test]
+  fn finds_own_stream() {
+    let sink_inputs = parse(&sink_inputs_json(42, [16384, 32768]));
+    // The loudest channel is the stream's volume
+    assert_eq!(newest_own_stream(&sink_inputs), Some((42, 0.5)));
+
+    let other = parse(r#"[{ "index": 7, "properties": { "application.process.id": "1" } }]"#);
+    assert_eq!(newest_own_stream(&other), None);
+  }
+
+  #[test]
+  fn prefers_newest_own_stream() {
+    let mut sink_inputs = parse(&sink_inputs_json(42, [65536, 65536]));
+    sink_inputs.extend(parse(&sink_inputs_json(43, [0, 0])));
+    assert_eq!(newest_own_stream(&sink_inputs), Some((43, 0.0)));
+  }
+
+  #[test]
+  fn clamps_boosted_volume() {
+    let sink_inputs = parse(&sink_inputs_json(42, [98304, 98304]));
+    assert_eq!(newest_own_stream(&sink_inputs), Some((42, 1.0)));
+  }
+
+  #[test]
+  fn detects_stream_changes() {
+    let cases = [
+      (None, None, None),
+      (Some((1, 0.5)), None, None),
+      (None, Some((1, 0.5)), Some(StreamChange::Opened)),
+      // A new stream after the output reconnected
+      (Some((1, 0.5)), Some((2, 0.5)), Some(StreamChange::Opened)),
+      (
+        Some((1, 0.5)),
+        Some((1, 0.25)),
+        Some(StreamChange::VolumeChanged(0.25)),
+      ),
+      // Reading back a volume hsm set
+      (Some((1, 0.5)), Some((1, 0.5004)), None),
+    ];
+
+    for (previous, current, expected) in cases {
+      assert_eq!(
+        stream_change(previous, current),
+        expected,
+        "{previous:?} -> {current:?}"
+      );
+    }
+  }
+}
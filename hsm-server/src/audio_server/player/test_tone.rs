@@ -0,0 +1,99 @@
+use std::{
+  f32::consts::TAU,
+  sync::{
+    Arc,
+    atomic::{AtomicU64, Ordering},
+  },
+  time::Duration,
+};
+
+use rodio::{ChannelCount, Sample, SampleRate, Source, source::SeekError as RodioSeekError};
+
+/// A mono sine wave played through the same queue and controls as tracks, for checking the output without media files
+///
+/// Counts the frames that were pulled from it, so the caller can tell if the output stream played it
+pub struct TestTone {
+  frequency: f32,
+  sample_rate: SampleRate,
+  total_frames: u64,
+  frame: u64,
+  played: Arc<AtomicU64>,
+}
+
+impl TestTone {
+  /// Quiet enough that a test doesn't startle anyone wearing headphones
+  const AMPLITUDE: f32 = 0.2;
+
+  pub fn new(frequency: f32, duration: Duration, sample_rate: SampleRate) -> Self {
+    Self {
+      frequency,
+      sample_rate,
+      total_frames: (duration.as_secs_f64() * sample_rate as f64) as u64,
+      frame: 0,
+      played: Arc::new(AtomicU64::new(0)),
+    }
+  }
+
+  pub fn total_frames(&self) -> u64 {
+    self.total_frames
+  }
+
+  /// The number of frames pulled from the tone so far
+  pub fn played(&self) -> Arc<AtomicU64> {
+    self.played.clone()
+  }
+}
+
+impl Iterator for TestTone {
+  type Item = Sample;
+
+  #[inline]
+  fn next(&mut self) -> Option<Self::Item> {
+    if self.frame >= self.total_frames {
+      return None;
+    }
+
+    // Wrapped to one period, so the phase stays precise in long tones
+    let period = self.sample_rate as f64 / self.frequency as f64;
+    let phase = (self.frame as f64 % period / period) as f32;
+    self.frame += 1;
+    self.played.store(self.frame, Ordering::Relaxed);
+
+    Some((phase * TAU).sin() * Self::AMPLITUDE)
+  }
+
+  #[inline]
+  fn size_hint(&self) -> (usize, Option<usize>) {
+    let remaining = (self.total_frames - self.frame) as usize;
+    (remaining, Some(remaining))
+  }
+}
+
+impl Source for TestTone {
+  #[inline]
+  fn current_span_len(&self) -> Option<usize> {
+    None
+  }
+
+  #[inline]
+  fn channels(&self) -> ChannelCount {
+    1
+  }
+
+  #[inline]
+  fn sample_rate(&self) -> SampleRate {
+    self.sample_rate
+  }
+
+  #[inline]
+  fn total_duration(&self) -> Option<Duration> {
+    Some(Duration::from_secs_f64(
+      self.total_frames as f64 / self.sample_rate as f64,
+    ))
+  }
+
+  fn try_seek(&mut self, pos: Duration) -> Result<(), RodioSeekError> {
+    self.frame = ((pos.as_secs_f64() * self.sample_rate as f64) as u64).min(self.total_frames);
+    Ok(())
+  }
+}
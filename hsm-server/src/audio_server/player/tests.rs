@@ -0,0 +1,1266 @@
+use std::{
+  fs,
+  future::Future,
+  path::{Path, PathBuf},
+  sync::{
+    Arc,
+    atomic::{AtomicBool, Ordering},
+  },
+  thread,
+  time::Duration,
+};
+
+use futures_concurrency::future::Join;
+use hsm_ipc::{
+  EndBehavior, Event, InsertPosition, InsertShufflePolicy, LoopMode, PlaybackState, SeekPosition,
+  StopReason, TrackId, TransitionMode,
+};
+use hsm_plugin::SharedPlayerState;
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use smol::{
+  Timer,
+  channel::{self, Receiver},
+  future,
+};
+use tempfile::TempDir;
+
+use super::{
+  Player, PlayerAudioOutput, PlayerError, controlled_source::SeekError, end_behavior_for,
+};
+use crate::{
+  audio_server::{
+    blocking::{BlockingScheduler, Lane},
+    coalesce::RequestCoalescer,
+    track::{self, LoadedTrack, TrackPath},
+  },
+  config::{GaplessPolicy, RemovedCurrent, TagConfig},
+};
+
+const SAMPLE_RATE: u32 = 8000;
+const CHANNELS: u16 = 1;
+/// Samples pulled from the output at a time, ten times faster than real time
+const PULL_CHUNK: usize = 80;
+const EVENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Writes a silent 16 bit PCM WAV file that plays for `duration`
+pub fn write_wav(path: &Path, duration: Duration) {
+  let frames = (duration.as_secs_f64() * SAMPLE_RATE as f64) as usize;
+  write_wav_samples(path, &vec![0; frames]);
+}
+
+/// Writes a mono 16 bit PCM WAV file holding `samples`
+pub fn write_wav_samples(path: &Path, samples: &[i16]) {
+  let block_align = CHANNELS as u32 * 2;
+  let data_len = samples.len() as u32 * block_align;
+
+  let mut wav = Vec::new();
+  wav.extend_from_slice(b"RIFF");
+  wav.extend_from_slice(&(36 + data_len).to_le_bytes());
+  wav.extend_from_slice(b"WAVEfmt ");
+  wav.extend_from_slice(&16u32.to_le_bytes());
+  wav.extend_from_slice(&1u16.to_le_bytes());
+  wav.extend_from_slice(&CHANNELS.to_le_bytes());
+  wav.extend_from_slice(&SAMPLE_RATE.to_le_bytes());
+  wav.extend_from_slice(&(SAMPLE_RATE * block_align).to_le_bytes());
+  wav.extend_from_slice(&(block_align as u16).to_le_bytes());
+  wav.extend_from_slice(&16u16.to_le_bytes());
+  wav.extend_from_slice(b"data");
+  wav.extend_from_slice(&data_len.to_le_bytes());
+  for sample in samples {
+    wav.extend_from_slice(&sample.to_le_bytes());
+  }
+
+  fs::write(path, wav).unwrap();
+}
+
+/// Pulls samples from a player's output on its own thread, like an output stream
+struct TestOutput {
+  stopped: Arc<AtomicBool>,
+  thread: Option<thread::JoinHandle<()>>,
+}
+
+impl TestOutput {
+  fn start(mut output: PlayerAudioOutput) -> Self {
+    let stopped = Arc::new(AtomicBool::new(false));
+    let thread_stopped = stopped.clone();
+
+    let thread = thread::spawn(move || {
+      while !thread_stopped.load(Ordering::Acquire) {
+        for _ in 0..PULL_CHUNK {
+          output.next();
+        }
+        thread::sleep(Duration::from_millis(1));
+      }
+    });
+
+    Self {
+      stopped,
+      thread: Some(thread),
+    }
+  }
+}
+
+impl Drop for TestOutput {
+  fn drop(&mut self) {
+    self.stopped.store(true, Ordering::Release);
+    if let Some(thread) = self.thread.take() {
+      let _ = thread.join();
+    }
+  }
+}
+
+/// A player playing into a `TestOutput`, with its events and a directory for its tracks
+pub struct TestPlayer {
+  pub player: Player,
+  pub events: Receiver<Event>,
+  pub scheduler: Arc<BlockingScheduler>,
+  pub dir: TempDir,
+  _output: TestOutput,
+}
+
+impl TestPlayer {
+  pub fn new() -> Self {
+    let (event_tx, events) = channel::unbounded();
+    let scheduler = Arc::new(BlockingScheduler::new());
+    let (player, output) = Player::new(
+      event_tx,
+      SAMPLE_RATE,
+      CHANNELS,
+      scheduler.clone(),
+      Arc::new(SharedPlayerState::new()),
+    );
+
+    Self {
+      player,
+      events,
+      scheduler,
+      dir: tempfile::tempdir().unwrap(),
+      _output: TestOutput::start(output),
+    }
+  }
+
+  /// Writes a track called `name` that plays for `duration`
+  pub fn write_track(&self, name: &str, duration: Duration) -> PathBuf {
+    let path = self.dir.path().join(name);
+    write_wav(&path, duration);
+    path
+  }
+
+  pub async fn load(&self, path: &Path) -> Arc<LoadedTrack> {
+    let track = track::load_file(
+      TrackPath::resolve(path.to_owned()).await.unwrap(),
+      &[],
+      &self.scheduler,
+      Lane::Interactive,
+      &TagConfig::default(),
+    )
+    .await
+    .unwrap();
+
+    Arc::new(track)
+  }
+
+  /// Writes, loads and appends tracks that each play for `duration`
+  pub async fn add_tracks(&self, names: &[&str], duration: Duration) -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    let mut tracks = Vec::new();
+    for name in names {
+      let path = self.write_track(name, duration);
+      tracks.push(self.load(&path).await);
+      paths.push(fs::canonicalize(path).unwrap());
+    }
+
+    self
+      .player
+      .insert_tracks(InsertPosition::End, InsertShufflePolicy::Scatter, &tracks)
+      .await
+      .unwrap();
+    paths
+  }
+
+  /// Runs `test` while the player handles source events
+  pub fn run(&self, test: impl Future<Output = ()>) {
+    smol::block_on(future::or(
+      async {
+        if let Err(error) = self.player.run().await {
+          panic!("Player stopped running: {error}");
+        }
+      },
+      test,
+    ))
+  }
+
+  /// Waits for the first event that `f` returns `Some` for, panicking if none is sent in time
+  pub async fn wait_for<T>(&self, mut f: impl FnMut(Event) -> Option<T>) -> T {
+    future::or(
+      async {
+        loop {
+          let event = self.events.recv().await.expect("Player should be alive");
+          if let Some(value) = f(event) {
+            return value;
+          }
+        }
+      },
+      async {
+        Timer::after(EVENT_TIMEOUT).await;
+        panic!("Timed out waiting for an event");
+      },
+    )
+    .await
+  }
+
+  /// Waits for playback to stop, returning the reason and the paths of the tracks changed to before it stopped
+  pub async fn wait_for_stop(&self) -> (StopReason, Vec<Option<PathBuf>>) {
+    let mut changed_to = Vec::new();
+    let reason = self
+      .wait_for(|event| match event {
+        Event::PlaybackStopped(reason) => Some(reason),
+        Event::TrackChanged(track) => {
+          changed_to.push(track.map(|track| track.file_path));
+          None
+        }
+        _ => None,
+      })
+      .await;
+
+    (reason, changed_to)
+  }
+}
+
+const SHORT: Duration = Duration::from_millis(100);
+
+/// Polls `f` until it returns true, panicking if it doesn't in time
+async fn wait_until(mut f: impl AsyncFnMut() -> bool) {
+  future::or(
+    async {
+      while !f().await {
+        Timer::after(Duration::from_millis(5)).await;
+      }
+    },
+    async {
+      Timer::after(EVENT_TIMEOUT).await;
+      panic!("Timed out waiting for a condition");
+    },
+  )
+  .await
+}
+
+#[test]
+fn end_behavior_follows_settings() {
+  let cases = [
+    (LoopMode::None, false, EndBehavior::WillStopAtQueueEnd),
+    (LoopMode::Track, false, EndBehavior::WillLoopTrack),
+    (LoopMode::Playlist, false, EndBehavior::WillWrap),
+    // Stop after current applies before any looping
+    (LoopMode::None, true, EndBehavior::WillStopAfterCurrent),
+    (LoopMode::Track, true, EndBehavior::WillStopAfterCurrent),
+    (LoopMode::Playlist, true, EndBehavior::WillStopAfterCurrent),
+  ];
+
+  for (loop_mode, stop_after_current, expected) in cases {
+    assert_eq!(
+      end_behavior_for(loop_mode, stop_after_current),
+      expected,
+      "{loop_mode:?}, stop after current {stop_after_current}"
+    );
+  }
+}
+
+#[test]
+fn end_of_queue_stop_reason() {
+  let test = TestPlayer::new();
+  test.run(async {
+    test.add_tracks(&["a.wav"], SHORT).await;
+    test.player.play().await.unwrap();
+
+    let (reason, _) = test.wait_for_stop().await;
+    assert_eq!(reason, StopReason::EndOfQueue);
+    assert_eq!(
+      test.player.stop_reason().await,
+      Some(StopReason::EndOfQueue)
+    );
+  });
+}
+
+#[test]
+fn user_requested_stop_reason() {
+  let test = TestPlayer::new();
+  test.run(async {
+    test.add_tracks(&["a.wav"], Duration::from_secs(60)).await;
+    test.player.play().await.unwrap();
+    test.player.stop(StopReason::UserRequested).await.unwrap();
+
+    let (reason, _) = test.wait_for_stop().await;
+    assert_eq!(reason, StopReason::UserRequested);
+  });
+}
+
+#[test]
+fn queue_cleared_stop_reason() {
+  let test = TestPlayer::new();
+  test.run(async {
+    test.add_tracks(&["a.wav"], Duration::from_secs(60)).await;
+    test.player.play().await.unwrap();
+    test.player.clear_tracks().await.unwrap();
+
+    let (reason, _) = test.wait_for_stop().await;
+    assert_eq!(reason, StopReason::QueueCleared);
+  });
+}
+
+#[test]
+fn playing_clears_stop_reason() {
+  let test = TestPlayer::new();
+  test.run(async {
+    test.add_tracks(&["a.wav"], Duration::from_secs(60)).await;
+    test.player.stop(StopReason::UserRequested).await.unwrap();
+    test.player.play().await.unwrap();
+
+    assert_eq!(test.player.stop_reason().await, None);
+  });
+}
+
+#[test]
+fn unplayable_next_track_is_skipped() {
+  let test = TestPlayer::new();
+  test.run(async {
+    let paths = test.add_tracks(&["a.wav", "b.wav", "c.wav"], SHORT).await;
+    // Loaded, but gone by the time its decoder is created
+    fs::remove_file(&paths[1]).unwrap();
+
+    test.player.play().await.unwrap();
+
+    let (reason, changed_to) = test.wait_for_stop().await;
+    assert_eq!(reason, StopReason::EndOfQueue);
+    assert!(changed_to.contains(&Some(paths[2].clone())));
+  });
+}
+
+#[test]
+fn stops_with_error_when_no_track_can_play() {
+  let test = TestPlayer::new();
+  test.run(async {
+    let paths = test.add_tracks(&["a.wav", "b.wav"], SHORT).await;
+    test.player.set_loop_mode(LoopMode::Playlist).await.unwrap();
+    test.player.play().await.unwrap();
+
+    // The first track already has a decoder, so only the tracks after it fail
+    for path in paths.iter() {
+      fs::remove_file(path).unwrap();
+    }
+
+    let (reason, _) = test.wait_for_stop().await;
+    assert!(matches!(reason, StopReason::Error(_)), "{reason:?}");
+  });
+}
+
+/// Waits for an `EndBehaviorChanged` event, returning it with the events `f` keeps that were sent before it
+async fn events_until_end_behavior_changed(
+  test: &TestPlayer,
+  f: impl Fn(&Event) -> bool,
+) -> Vec<Event> {
+  let mut events = Vec::new();
+  test
+    .wait_for(|event| {
+      let changed = matches!(event, Event::EndBehaviorChanged(_));
+      if changed || f(&event) {
+        events.push(event);
+      }
+      changed.then_some(())
+    })
+    .await;
+  events
+}
+
+#[test]
+fn stop_after_current_changes_end_behavior() {
+  let test = TestPlayer::new();
+  test.run(async {
+    test.add_tracks(&["a.wav", "b.wav"], SHORT).await;
+    test.player.set_stop_after_current(true).unwrap();
+    assert_eq!(
+      test.player.end_behavior(),
+      EndBehavior::WillStopAfterCurrent
+    );
+
+    let events = events_until_end_behavior_changed(&test, |event| {
+      matches!(event, Event::StopAfterCurrentChanged(_))
+    })
+    .await;
+    assert!(
+      matches!(
+        events.as_slice(),
+        [
+          Event::StopAfterCurrentChanged(true),
+          Event::EndBehaviorChanged(EndBehavior::WillStopAfterCurrent),
+        ]
+      ),
+      "{events:?}"
+    );
+
+    // Pausing at the end of the track turns it off again
+    test.player.play().await.unwrap();
+    let events = events_until_end_behavior_changed(&test, |event| {
+      matches!(
+        event,
+        Event::StopAfterCurrentChanged(_) | Event::PlaybackStateChanged(PlaybackState::Paused)
+      )
+    })
+    .await;
+    assert!(
+      matches!(
+        events.as_slice(),
+        [
+          Event::PlaybackStateChanged(PlaybackState::Paused),
+          Event::StopAfterCurrentChanged(false),
+          Event::EndBehaviorChanged(EndBehavior::WillStopAtQueueEnd),
+        ]
+      ),
+      "{events:?}"
+    );
+    assert!(!test.player.stop_after_current());
+  });
+}
+
+#[test]
+fn oneshot_plays_over_the_current_track() {
+  let test = TestPlayer::new();
+  test.run(async {
+    let paths = test
+      .add_tracks(&["a.wav", "b.wav"], Duration::from_secs(60))
+      .await;
+    test.player.play().await.unwrap();
+
+    let oneshot = test.load(&test.write_track("ding.wav", SHORT)).await;
+    test.player.play_oneshot(oneshot.clone()).await.unwrap();
+    // Only one oneshot plays at a time
+    assert!(matches!(
+      test.player.play_oneshot(oneshot.clone()).await,
+      Err(PlayerError::OneshotActive)
+    ));
+
+    test
+      .wait_for(|event| matches!(event, Event::InterruptStarted { .. }).then_some(()))
+      .await;
+    test
+      .wait_for(|event| matches!(event, Event::InterruptFinished).then_some(()))
+      .await;
+
+    // The track list was left alone
+    let current = test.player.current_track().await.unwrap();
+    assert_eq!(current.file_path, paths[0]);
+    assert_eq!(test.player.current_track_index(), 0);
+    assert_eq!(test.player.playback_state(), PlaybackState::Playing);
+
+    // Another oneshot can play once the first has finished
+    test.player.play_oneshot(oneshot).await.unwrap();
+  });
+}
+
+#[test]
+fn track_looping_drops_and_requeues_the_next_track() {
+  let test = TestPlayer::new();
+  test.run(async {
+    test
+      .add_tracks(&["a.wav", "b.wav"], Duration::from_secs(60))
+      .await;
+    test.player.play().await.unwrap();
+
+    let (_, next_track) = test.player.tracks.get_tracks_to_queue().await.unwrap();
+    let next_id = next_track.unwrap().track_id();
+    let queued_id = async || {
+      test
+        .player
+        .controls
+        .source_queue
+        .lock()
+        .await
+        .queued_track_id()
+    };
+    wait_until(async || queued_id().await == Some(next_id)).await;
+
+    // Nothing plays after a looping track
+    test.player.set_loop_mode(LoopMode::Track).await.unwrap();
+    assert_eq!(queued_id().await, None);
+    assert_eq!(test.player.next_track_to_queue().await, None);
+
+    test.player.set_loop_mode(LoopMode::None).await.unwrap();
+    wait_until(async || queued_id().await == Some(next_id)).await;
+  });
+}
+
+#[test]
+fn skipping_while_looping_a_track_loads_the_next_one() {
+  let test = TestPlayer::new();
+  test.run(async {
+    let paths = test
+      .add_tracks(&["a.wav", "b.wav"], Duration::from_secs(60))
+      .await;
+    test.player.set_loop_mode(LoopMode::Track).await.unwrap();
+    test.player.play().await.unwrap();
+    test.player.skip_to_next_track(1).await.unwrap();
+
+    test
+      .wait_for(|event| match event {
+        Event::TrackChanged(Some(track)) => (track.file_path == paths[1]).then_some(()),
+        _ => None,
+      })
+      .await;
+    assert_eq!(test.player.current_track_index(), 1);
+    assert_eq!(test.player.playback_state(), PlaybackState::Playing);
+  });
+}
+
+#[test]
+fn skips_several_tracks_at_once() {
+  let test = TestPlayer::new();
+  test.run(async {
+    let paths = test
+      .add_tracks(
+        &["a.wav", "b.wav", "c.wav", "d.wav"],
+        Duration::from_secs(60),
+      )
+      .await;
+    test.player.play().await.unwrap();
+    test.player.skip_to_next_track(2).await.unwrap();
+
+    test
+      .wait_for(|event| match event {
+        Event::TrackChanged(Some(track)) => (track.file_path == paths[2]).then_some(()),
+        _ => None,
+      })
+      .await;
+    assert_eq!(test.player.current_track_index(), 2);
+
+    // Restarting the current track counts as one of the tracks
+    test
+      .player
+      .seek(SeekPosition::To(Duration::from_secs(10)))
+      .await
+      .unwrap();
+    wait_until(async || test.player.position().await >= Duration::from_secs(10)).await;
+    test.player.go_to_previous_track(true, 2).await.unwrap();
+    assert_eq!(test.player.current_track_index(), 1);
+    assert_eq!(test.player.playback_state(), PlaybackState::Playing);
+  });
+}
+
+#[test]
+fn stopping_fails_a_pending_seek() {
+  let test = TestPlayer::new();
+  test.run(async {
+    test.add_tracks(&["a.wav"], Duration::from_secs(60)).await;
+
+    // Waiting for a source that will never run again
+    let (tx, rx) = async_oneshot::oneshot();
+    *test.player.controls.seek_position.lock().await =
+      Some((SeekPosition::To(Duration::from_secs(10)), tx));
+    test.player.stop(StopReason::QueueCleared).await.unwrap();
+
+    assert!(matches!(rx.await, Ok(Err(SeekError::PlaybackStopped))));
+    assert!(test.player.controls.seek_position.lock().await.is_none());
+  });
+}
+
+#[test]
+fn skips_without_a_source_are_dropped() {
+  let test = TestPlayer::new();
+  test.run(async {
+    test.add_tracks(&["a.wav"], Duration::from_secs(60)).await;
+
+    // Left over from a source that ended on its own before applying them
+    test.player.controls.to_skip.store(2, Ordering::Release);
+    test.player.clear_source_queue().await;
+    assert_eq!(test.player.controls.to_skip.load(Ordering::Acquire), 0);
+
+    // The track plays instead of being skipped
+    test.player.play().await.unwrap();
+    wait_until(async || test.player.position().await >= SHORT).await;
+    assert_eq!(test.player.current_track_index(), 0);
+    assert_eq!(test.player.playback_state(), PlaybackState::Playing);
+  });
+}
+
+#[test]
+fn spamming_skips_plays_the_last_track_skipped_to() {
+  const SKIPS: usize = 10;
+
+  let test = TestPlayer::new();
+  test.run(async {
+    let names: Vec<String> = (0..=SKIPS).map(|i| format!("{i}.wav")).collect();
+    let names: Vec<&str> = names.iter().map(String::as_str).collect();
+    let paths = test.add_tracks(&names, Duration::from_secs(60)).await;
+    test.player.play().await.unwrap();
+
+    // Each skip moves on from the track the one before it skipped to, while its source is still loading
+    let skips: Vec<_> = (0..SKIPS)
+      .map(|_| test.player.skip_to_next_track(1))
+      .collect();
+    let results = future::or(skips.join(), async {
+      Timer::after(EVENT_TIMEOUT).await;
+      panic!("Timed out waiting for the skips");
+    })
+    .await;
+    for result in results {
+      result.unwrap();
+    }
+
+    // Only the playing source was skipped, so the last track keeps playing
+    wait_until(async || test.player.position().await >= SHORT).await;
+    assert_eq!(test.player.current_track_index(), SKIPS);
+    assert_eq!(test.player.playback_state(), PlaybackState::Playing);
+    assert_eq!(
+      test
+        .player
+        .current_track()
+        .await
+        .map(|track| track.file_path),
+      Some(paths[SKIPS].clone())
+    );
+
+    // Nothing is left waiting on the queue
+    wait_until(async || {
+      test
+        .player
+        .controls
+        .queue_waiters
+        .lock()
+        .unwrap()
+        .is_empty()
+    })
+    .await;
+  });
+}
+
+#[test]
+fn seeking_past_the_end_moves_on() {
+  let test = TestPlayer::new();
+  test.run(async {
+    let paths = test
+      .add_tracks(&["a.wav", "b.wav"], Duration::from_secs(60))
+      .await;
+    test.player.play().await.unwrap();
+    test
+      .player
+      .seek(SeekPosition::ForwardFraction(1.0))
+      .await
+      .unwrap();
+
+    test
+      .wait_for(|event| match event {
+        Event::TrackChanged(Some(track)) => (track.file_path == paths[1]).then_some(()),
+        _ => None,
+      })
+      .await;
+    assert_eq!(test.player.playback_state(), PlaybackState::Playing);
+  });
+}
+
+/// Takes the events already sent, without waiting for more
+fn sent_events(test: &TestPlayer) -> Vec<Event> {
+  std::iter::from_fn(|| test.events.try_recv().ok()).collect()
+}
+
+#[test]
+fn events_are_sent_before_replies() {
+  let test = TestPlayer::new();
+  let coalescer = RequestCoalescer::new();
+  test.run(future::or(
+    async {
+      if let Err(error) = coalescer.run(&test.player).await {
+        panic!("Coalescer stopped running: {error}");
+      }
+    },
+    async {
+      let paths = test
+        .add_tracks(&["a.wav", "b.wav"], Duration::from_secs(60))
+        .await;
+      test.player.play().await.unwrap();
+      wait_until(async || test.player.position().await >= SHORT).await;
+      sent_events(&test);
+
+      // Requests performed by the player directly
+      test
+        .player
+        .seek(SeekPosition::To(Duration::from_secs(10)))
+        .await
+        .unwrap();
+      assert!(matches!(sent_events(&test)[..], [Event::Seeked(_)]));
+
+      // Requests merged by the coalescer are replied to once their receiver resolves
+      coalescer.set_volume(0.5).await.await.unwrap();
+      assert!(
+        sent_events(&test)
+          .iter()
+          .any(|event| matches!(event, Event::VolumeChanged(0.5)))
+      );
+
+      coalescer
+        .seek(SeekPosition::Forward(Duration::from_secs(5)))
+        .await
+        .await
+        .unwrap();
+      assert!(
+        sent_events(&test)
+          .iter()
+          .any(|event| matches!(event, Event::Seeked(_)))
+      );
+
+      coalescer.skip(1).await.await.unwrap();
+      assert!(sent_events(&test).iter().any(|event| matches!(
+        event,
+        Event::TrackChanged(Some(track)) if track.file_path == paths[1]
+      )));
+    },
+  ));
+}
+
+#[test]
+fn seeks_to_a_fraction_of_the_track() {
+  let test = TestPlayer::new();
+  test.run(async {
+    test.add_tracks(&["a.wav"], Duration::from_secs(60)).await;
+    test.player.play().await.unwrap();
+    test
+      .player
+      .seek(SeekPosition::ToFraction(0.5))
+      .await
+      .unwrap();
+
+    wait_until(async || test.player.position().await >= Duration::from_secs(30)).await;
+    assert!(test.player.position().await < Duration::from_secs(40));
+  });
+}
+
+#[test]
+fn stays_on_the_last_track_at_the_end_of_the_queue() {
+  let test = TestPlayer::new();
+  test.run(async {
+    let paths = test.add_tracks(&["a.wav", "b.wav"], SHORT).await;
+    test.player.play().await.unwrap();
+    test.wait_for_stop().await;
+
+    assert_eq!(test.player.current_track_index(), 1);
+    let entry = test.player.current_entry().await;
+    assert_eq!(entry.index, 1);
+    assert_eq!(entry.track.unwrap().file_path, paths[1]);
+    assert!(!entry.will_play_next);
+    assert_eq!(entry.up_next.unwrap().file_path, paths[0]);
+
+    // Playing again starts over
+    test.player.play().await.unwrap();
+    test
+      .wait_for(|event| match event {
+        Event::TrackChanged(Some(track)) => (track.file_path == paths[0]).then_some(()),
+        _ => None,
+      })
+      .await;
+    assert_eq!(test.player.current_track_index(), 0);
+    assert!(test.player.current_entry().await.will_play_next);
+  });
+}
+
+#[test]
+fn stopping_mid_queue_plays_the_same_track_next() {
+  let test = TestPlayer::new();
+  test.run(async {
+    let paths = test
+      .add_tracks(&["a.wav", "b.wav"], Duration::from_secs(60))
+      .await;
+    test.player.play().await.unwrap();
+    test.player.stop(StopReason::UserRequested).await.unwrap();
+
+    let entry = test.player.current_entry().await;
+    assert_eq!(entry.index, 0);
+    assert!(entry.will_play_next);
+    assert_eq!(entry.up_next.unwrap().file_path, paths[0]);
+  });
+}
+
+#[test]
+fn empty_queue_has_no_current_entry() {
+  let test = TestPlayer::new();
+  test.run(async {
+    let entry = test.player.current_entry().await;
+    assert!(entry.track.is_none());
+    assert!(entry.up_next.is_none());
+  });
+}
+
+#[test]
+fn restores_a_saved_track_list() {
+  let test = TestPlayer::new();
+  test.run(async {
+    let paths = test
+      .add_tracks(&["a.wav", "b.wav", "c.wav"], Duration::from_secs(60))
+      .await;
+    let mut tracks = Vec::new();
+    for path in &paths {
+      tracks.push(test.load(path).await);
+    }
+
+    test
+      .player
+      .restore_track_list(&tracks, vec![2, 0, 1], true, 1)
+      .await
+      .unwrap();
+    test
+      .player
+      .restore_paused_at(Duration::from_secs(5))
+      .await
+      .unwrap();
+
+    let state = test.player.saved_state().await;
+    assert_eq!(state.tracks, paths);
+    assert_eq!(state.shuffle_indicies, [2, 0, 1]);
+    assert_eq!(state.current_index, 1);
+    assert!(state.shuffle);
+    assert!(!state.stopped);
+    assert_eq!(test.player.playback_state(), PlaybackState::Paused);
+    assert_eq!(
+      test.player.current_track().await.unwrap().file_path,
+      paths[0]
+    );
+  });
+}
+
+/// Waits for the `CurrentTrackRemoved` event, returning the removed track's path and if it was detached
+async fn wait_for_removed_current(test: &TestPlayer) -> (PathBuf, bool) {
+  test
+    .wait_for(|event| match event {
+      Event::CurrentTrackRemoved { track, detached } => Some((track.file_path, detached)),
+      _ => None,
+    })
+    .await
+}
+
+async fn queued_track_id(test: &TestPlayer) -> Option<TrackId> {
+  test
+    .player
+    .controls
+    .source_queue
+    .lock()
+    .await
+    .queued_track_id()
+}
+
+async fn current_path(test: &TestPlayer) -> Option<PathBuf> {
+  test
+    .player
+    .current_track()
+    .await
+    .map(|track| track.file_path)
+}
+
+#[test]
+fn removed_current_track_plays_until_it_ends() {
+  let test = TestPlayer::new();
+  test.run(async {
+    let paths = test
+      .add_tracks(&["a.wav", "b.wav", "c.wav"], Duration::from_secs(2))
+      .await;
+    test.player.play().await.unwrap();
+    wait_until(async || test.player.position().await >= SHORT).await;
+
+    test.player.remove_tracks(&[0]).await.unwrap();
+    assert_eq!(
+      wait_for_removed_current(&test).await,
+      (paths[0].clone(), true)
+    );
+
+    // The index moved to the entry that took its place, but the status shows what is playing
+    assert!(test.player.track_detached().await);
+    assert_eq!(test.player.current_track_index(), 0);
+    assert_eq!(current_path(&test).await, Some(paths[0].clone()));
+    let entry = test.player.current_entry().await;
+    assert!(entry.detached);
+    assert_eq!(
+      entry.up_next.map(|track| track.file_path),
+      Some(paths[0].clone())
+    );
+
+    // The replacement is queued to play next
+    let replacement_id = test.player.tracks.current_track_id().await;
+    wait_until(async || queued_track_id(&test).await == replacement_id).await;
+
+    // Not skipped over once the removed track ends
+    let changed_to = test
+      .wait_for(|event| match event {
+        Event::TrackChanged(track) => Some(track.map(|track| track.file_path)),
+        _ => None,
+      })
+      .await;
+    assert_eq!(changed_to, Some(paths[1].clone()));
+    assert!(!test.player.track_detached().await);
+    assert_eq!(test.player.current_track_index(), 0);
+    assert_eq!(test.player.playback_state(), PlaybackState::Playing);
+  });
+}
+
+#[test]
+fn skip_policy_plays_the_replacement_right_away() {
+  let test = TestPlayer::new();
+  test.player.set_removed_current(RemovedCurrent::Skip);
+  test.run(async {
+    let paths = test
+      .add_tracks(&["a.wav", "b.wav", "c.wav"], Duration::from_secs(60))
+      .await;
+    test.player.play().await.unwrap();
+
+    test.player.remove_tracks(&[0]).await.unwrap();
+    assert_eq!(
+      wait_for_removed_current(&test).await,
+      (paths[0].clone(), false)
+    );
+    assert!(!test.player.track_detached().await);
+    assert_eq!(current_path(&test).await, Some(paths[1].clone()));
+
+    wait_until(async || test.player.position().await >= SHORT).await;
+    assert_eq!(test.player.current_track_index(), 0);
+    assert_eq!(current_path(&test).await, Some(paths[1].clone()));
+  });
+}
+
+#[test]
+fn removing_a_looping_track_skips_it() {
+  let test = TestPlayer::new();
+  test.run(async {
+    let paths = test
+      .add_tracks(&["a.wav", "b.wav"], Duration::from_secs(60))
+      .await;
+    test.player.set_loop_mode(LoopMode::Track).await.unwrap();
+    test.player.play().await.unwrap();
+
+    test.player.remove_tracks(&[0]).await.unwrap();
+    assert_eq!(
+      wait_for_removed_current(&test).await,
+      (paths[0].clone(), false)
+    );
+    assert!(!test.player.track_detached().await);
+    assert_eq!(current_path(&test).await, Some(paths[1].clone()));
+  });
+}
+
+#[test]
+fn removing_the_replacement_of_a_detached_track_queues_the_next_one() {
+  let test = TestPlayer::new();
+  test.run(async {
+    let paths = test
+      .add_tracks(&["a.wav", "b.wav", "c.wav"], Duration::from_secs(60))
+      .await;
+    test.player.play().await.unwrap();
+    test.player.remove_tracks(&[0]).await.unwrap();
+    wait_for_removed_current(&test).await;
+
+    // Only the playing track is detached, and the entry after b is queued in its place
+    test.player.remove_tracks(&[0]).await.unwrap();
+    assert!(test.player.track_detached().await);
+    assert_eq!(current_path(&test).await, Some(paths[0].clone()));
+    let replacement_id = test.player.tracks.current_track_id().await;
+    wait_until(async || queued_track_id(&test).await == replacement_id).await;
+
+    // Skipping the detached track plays the queued replacement
+    test.player.skip_to_next_track(1).await.unwrap();
+    assert!(!test.player.track_detached().await);
+    assert_eq!(test.player.current_track_index(), 0);
+    assert_eq!(current_path(&test).await, Some(paths[2].clone()));
+    wait_until(async || test.player.position().await >= SHORT).await;
+    assert_eq!(test.player.playback_state(), PlaybackState::Playing);
+  });
+}
+
+#[test]
+fn removing_the_last_track_plays_it_before_stopping() {
+  let test = TestPlayer::new();
+  test.run(async {
+    let paths = test
+      .add_tracks(&["a.wav", "b.wav"], Duration::from_secs(2))
+      .await;
+    test.player.go_to_track(1).await.unwrap();
+    test.player.play().await.unwrap();
+    wait_until(async || test.player.position().await >= SHORT).await;
+
+    test.player.remove_tracks(&[1]).await.unwrap();
+    assert!(test.player.track_detached().await);
+    assert_eq!(test.player.current_track_index(), 1);
+    assert_eq!(queued_track_id(&test).await, None);
+
+    let (reason, _) = test.wait_for_stop().await;
+    assert_eq!(reason, StopReason::EndOfQueue);
+    assert!(!test.player.track_detached().await);
+    assert_eq!(current_path(&test).await, Some(paths[0].clone()));
+  });
+}
+
+#[test]
+fn stopping_drops_the_detached_track() {
+  let test = TestPlayer::new();
+  test.run(async {
+    let paths = test
+      .add_tracks(&["a.wav", "b.wav"], Duration::from_secs(60))
+      .await;
+    test.player.play().await.unwrap();
+    test.player.remove_tracks(&[0]).await.unwrap();
+    wait_for_removed_current(&test).await;
+
+    test.player.stop(StopReason::UserRequested).await.unwrap();
+    let changed_to = test
+      .wait_for(|event| match event {
+        Event::TrackChanged(track) => Some(track.map(|track| track.file_path)),
+        _ => None,
+      })
+      .await;
+    assert_eq!(changed_to, Some(paths[1].clone()));
+    assert!(!test.player.track_detached().await);
+  });
+}
+
+#[test]
+fn reports_the_next_transition() {
+  let test = TestPlayer::new();
+  test.run(async {
+    test
+      .add_tracks(&["a.wav", "b.wav"], Duration::from_secs(60))
+      .await;
+
+    // The files have no album tags to group them by
+    assert_eq!(
+      test.player.next_transition().await,
+      Some(TransitionMode::Crossfade)
+    );
+    test.player.set_gapless_policy(GaplessPolicy::Always);
+    assert_eq!(
+      test.player.next_transition().await,
+      Some(TransitionMode::Gapless)
+    );
+
+    // Nothing plays after a looping track or the last one
+    test.player.set_loop_mode(LoopMode::Track).await.unwrap();
+    assert_eq!(test.player.next_transition().await, None);
+    test.player.set_loop_mode(LoopMode::None).await.unwrap();
+    test.player.go_to_track(1).await.unwrap();
+    assert_eq!(test.player.next_transition().await, None);
+  });
+}
+
+/// Removes `positions` like the request handler, checking the generation the client saw first
+#[test]
+fn the_same_file_can_be_queued_twice() {
+  let test = TestPlayer::new();
+  test.run(async {
+    let path = test.write_track("interlude.wav", Duration::from_secs(60));
+    let track = test.load(&path).await;
+    test
+      .player
+      .insert_tracks(
+        InsertPosition::End,
+        InsertShufflePolicy::Scatter,
+        &[track.clone(), track],
+      )
+      .await
+      .unwrap();
+
+    let snapshot = test.player.get_track_list().await;
+    assert_eq!(
+      snapshot.track_list[0].file_path,
+      snapshot.track_list[1].file_path
+    );
+    let [first, second] = &snapshot.instances[..] else {
+      panic!("Expected two entries, got {:?}", snapshot.instances);
+    };
+    assert_ne!(first.id, second.id);
+    let (first, second) = (first.id, second.id);
+
+    // Per-entry state only changes the entry it is set on
+    test.player.set_track_gain(first, Some(-6.0)).await.unwrap();
+    let instances = test.player.get_track_list().await.instances;
+    assert_eq!(instances[0].state.gain_db, Some(-6.0));
+    assert_eq!(instances[1].state.gain_db, None);
+
+    // Moving to the other entry of the same file is a track change
+    test.player.play().await.unwrap();
+    sent_events(&test);
+    test.player.skip_to_next_track(1).await.unwrap();
+    assert!(
+      sent_events(&test)
+        .iter()
+        .any(|event| matches!(event, Event::TrackChanged(Some(_))))
+    );
+    assert_eq!(test.player.current_track_id().await, Some(second));
+
+    // Removing one entry leaves the other
+    test.player.remove_tracks(&[0]).await.unwrap();
+    let snapshot = test.player.get_track_list().await;
+    assert_eq!(snapshot.track_list.len(), 1);
+    assert_eq!(snapshot.instances[0].id, second);
+    assert_eq!(snapshot.instances[0].state.gain_db, None);
+    assert_eq!(test.player.current_track_id().await, Some(second));
+    assert_eq!(test.player.playback_state(), PlaybackState::Playing);
+  });
+}
+
+/// Checks that the state shared with plugins matches what the player answers queries with
+async fn assert_shared_state_matches(test: &TestPlayer) {
+  let shared = &test.player.controls.shared;
+  assert_eq!(shared.playback_state(), test.player.playback_state());
+  assert_eq!(shared.volume(), test.player.volume().await);
+  assert_eq!(shared.rate(), test.player.rate().await);
+
+  let position = test.player.position().await;
+  let shared_position = shared.position();
+  if test.player.playback_state() == PlaybackState::Playing {
+    // The source can update the position between the two reads
+    assert!(
+      shared_position.abs_diff(position) < SHORT,
+      "{shared_position:?} != {position:?}"
+    );
+  } else {
+    assert_eq!(shared_position, position);
+  }
+}
+
+#[test]
+fn shared_state_matches_queries() {
+  let test = TestPlayer::new();
+  test.run(async {
+    test
+      .add_tracks(&["a.wav", "b.wav"], Duration::from_secs(60))
+      .await;
+    assert_shared_state_matches(&test).await;
+
+    test.player.play().await.unwrap();
+    wait_until(async || test.player.position().await >= SHORT).await;
+    assert_shared_state_matches(&test).await;
+
+    test.player.set_volume(0.25).await.unwrap();
+    test.player.set_rate(1.5).await.unwrap();
+    assert_shared_state_matches(&test).await;
+
+    test
+      .player
+      .seek(SeekPosition::To(Duration::from_secs(30)))
+      .await
+      .unwrap();
+    assert_shared_state_matches(&test).await;
+
+    test.player.pause().await.unwrap();
+    assert_shared_state_matches(&test).await;
+
+    // Plugins notice track changes through the generation
+    let generation = test.player.controls.shared.track_generation();
+    test.player.skip_to_next_track(1).await.unwrap();
+    assert!(test.player.controls.shared.track_generation() > generation);
+    assert_shared_state_matches(&test).await;
+
+    test.player.stop(StopReason::UserRequested).await.unwrap();
+    assert_shared_state_matches(&test).await;
+    assert_eq!(test.player.controls.shared.position(), Duration::ZERO);
+  });
+}
+
+async fn remove_as_client(
+  test: &TestPlayer,
+  positions: &[usize],
+  seen_generation: u64,
+) -> Result<Vec<usize>, PlayerError> {
+  test.player.check_generation(Some(seen_generation))?;
+  test.player.remove_tracks(positions).await
+}
+
+#[test]
+fn stale_queue_edits_are_rejected() {
+  let test = TestPlayer::new();
+  test.run(async {
+    let paths = test
+      .add_tracks(&["a.wav", "b.wav", "c.wav"], Duration::from_secs(60))
+      .await;
+
+    // Both clients want to remove a track, picking its position from the same snapshot
+    let seen = test.player.get_track_list().await.generation;
+    remove_as_client(&test, &[0], seen).await.unwrap();
+    let stale = remove_as_client(&test, &[2], seen).await;
+
+    let current = test.player.get_track_list().await.generation;
+    assert!(matches!(
+      stale,
+      Err(PlayerError::GenerationMismatch { expected, current: actual })
+        if expected == seen && actual == current
+    ));
+    // Position 2 would now be out of range, but nothing was removed for it
+    let snapshot = test.player.get_track_list().await;
+    let track_paths: Vec<_> = snapshot
+      .track_list
+      .iter()
+      .map(|t| t.file_path.clone())
+      .collect();
+    assert_eq!(track_paths, [paths[1].clone(), paths[2].clone()]);
+
+    // After refetching, the client finds the track at its new position
+    remove_as_client(&test, &[1], snapshot.generation)
+      .await
+      .unwrap();
+    let snapshot = test.player.get_track_list().await;
+    let track_paths: Vec<_> = snapshot
+      .track_list
+      .iter()
+      .map(|t| t.file_path.clone())
+      .collect();
+    assert_eq!(track_paths, [paths[1].clone()]);
+
+    // Clients that don't send a generation are never rejected
+    test.player.check_generation(None).unwrap();
+  });
+}
+
+#[test]
+fn random_skip_bursts_settle_on_the_expected_track() {
+  const TRACKS: usize = 21;
+  const ROUNDS: usize = 8;
+  // Bursts start from the middle and are at most this long, so no order of their skips reaches an end of the queue
+  const START: usize = TRACKS / 2;
+
+  let mut rng = StdRng::seed_from_u64(1715);
+  let test = TestPlayer::new();
+  test.run(async {
+    let names: Vec<String> = (0..TRACKS).map(|i| format!("{i}.wav")).collect();
+    let names: Vec<&str> = names.iter().map(String::as_str).collect();
+    let paths = test.add_tracks(&names, Duration::from_secs(60)).await;
+    test.player.play().await.unwrap();
+
+    for _ in 0..ROUNDS {
+      test.player.go_to_track(START).await.unwrap();
+      let forward: Vec<bool> = (0..rng.random_range(1..=START))
+        .map(|_| rng.random_bool(0.5))
+        .collect();
+      let nexts = forward.iter().filter(|&&forward| forward).count();
+      let expected = START + nexts - (forward.len() - nexts);
+
+      let player = &test.player;
+      let burst: Vec<_> = forward
+        .iter()
+        .map(|&forward| async move {
+          match forward {
+            true => player.skip_to_next_track(1).await,
+            false => player.go_to_previous_track(false, 1).await,
+          }
+        })
+        .collect();
+      let results = future::or(burst.join(), async {
+        Timer::after(EVENT_TIMEOUT).await;
+        panic!("Timed out waiting for the burst {forward:?}");
+      })
+      .await;
+      for result in results {
+        result.unwrap();
+      }
+
+      wait_until(async || test.player.position().await >= SHORT).await;
+      // A skip left over from the burst would end the track it settled on
+      Timer::after(SHORT * 2).await;
+      assert_eq!(test.player.controls.to_skip.load(Ordering::Acquire), 0);
+      assert_eq!(test.player.current_track_index(), expected, "{forward:?}");
+      assert_eq!(test.player.playback_state(), PlaybackState::Playing);
+      assert_eq!(current_path(&test).await, Some(paths[expected].clone()));
+    }
+  });
+}
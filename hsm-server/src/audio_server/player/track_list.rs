@@ -1,34 +1,56 @@
 use std::{
-  ops::Index,
+  collections::VecDeque,
+  mem,
+  ops::{Index, Range},
+  path::PathBuf,
   sync::{
-    Arc,
-    atomic::{AtomicBool, AtomicUsize, Ordering},
+    Arc, RwLock,
+    atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
   },
+  time::Duration,
 };
 
-use hsm_ipc::{InsertPosition, Track, TrackListSnapshot};
+use hsm_ipc::{
+  Event, FilterExpr, InsertPosition, InsertShufflePolicy, InstanceState, LoopMode, QueueSummary,
+  Track, TrackId, TrackInstanceInfo, TrackListDiff, TrackListSnapshot, TrackListUpdate, TrackRef,
+};
 use rand::{Rng, seq::SliceRandom};
 use smol::lock::Mutex;
 
-use crate::audio_server::track::LoadedTrack;
+use crate::audio_server::track::{LoadedTrack, update_size};
 
 use super::PlayerError;
 
 /// A `LoadedTrack` with a track_id to uniquely identify it
+///
+/// State that should not be shared when the same file is queued more than once is stored here
 #[derive(Debug, Clone)]
 pub struct TrackInstance {
   track: Arc<LoadedTrack>,
-  track_id: usize,
+  track_id: TrackId,
+  state: InstanceState,
 }
 
 impl TrackInstance {
-  pub fn loaded_track(&self) -> &LoadedTrack {
+  pub fn loaded_track(&self) -> &Arc<LoadedTrack> {
     &self.track
   }
 
-  pub fn track_id(&self) -> usize {
+  pub fn track_id(&self) -> TrackId {
     self.track_id
   }
+
+  pub fn state(&self) -> &InstanceState {
+    &self.state
+  }
+
+  fn info(&self, filtered_out: bool) -> TrackInstanceInfo {
+    TrackInstanceInfo {
+      id: self.track_id,
+      state: self.state.clone(),
+      filtered_out,
+    }
+  }
 }
 
 impl Into<Arc<LoadedTrack>> for TrackInstance {
@@ -42,21 +64,174 @@ impl Into<Arc<LoadedTrack>> for TrackInstance {
 struct TrackListInner {
   track_list: Vec<TrackInstance>,
   shuffled_track_indicies: Vec<usize>,
+  /// Index of the current track in play order, can be past the end if the list shrank or playback reached the end
+  current_index: usize,
   latest_track_id: usize,
+  /// Recent updates tagged with the generation they produced, oldest first
+  history: VecDeque<(u64, TrackListUpdate)>,
+  /// Estimated memory used by `history`, see `update_size`
+  history_bytes: usize,
+  /// Every change made after this generation is in `history`
+  history_start: u64,
+  /// Paths of the tracks removed by the last clear or removal, in track list order
+  ///
+  /// Only the first paths that fit in `MAX_LAST_REMOVED_BYTES` are kept
+  last_removed: Vec<PathBuf>,
+  /// Ids of the tracks that were moved forward from, oldest first, so going back under shuffle
+  /// returns to the track that actually played before the current one
+  played: VecDeque<TrackId>,
+  /// Entries that don't match are skipped when moving through the track list
+  filter: Option<FilterExpr>,
 }
 
 impl TrackListInner {
+  /// Number of updates kept for `TrackList::diff_since`
+  const HISTORY_LEN: usize = 32;
+  /// Estimated memory the updates in `history` may use, since inserts copy every inserted track
+  const MAX_HISTORY_BYTES: usize = 16 << 20;
+  const MAX_LAST_REMOVED_BYTES: usize = 4 << 20;
+  /// Number of track ids kept in `played`
+  const PLAYED_LEN: usize = 100;
+
   pub fn new() -> Self {
     Self {
       track_list: Vec::new(),
       shuffled_track_indicies: Vec::new(),
+      current_index: 0,
       latest_track_id: 0,
+      history: VecDeque::with_capacity(Self::HISTORY_LEN),
+      history_bytes: 0,
+      history_start: 0,
+      last_removed: Vec::new(),
+      played: VecDeque::with_capacity(Self::PLAYED_LEN),
+      filter: None,
+    }
+  }
+
+  /// If the track at `index` in play order doesn't match the filter
+  fn is_filtered_out(&self, index: usize) -> bool {
+    self
+      .filter
+      .as_ref()
+      .is_some_and(|filter| !filter.matches(self[index].loaded_track().metadata()))
+  }
+
+  /// The first track in play order that matches the filter, or 0 if none do
+  fn first_matching(&self) -> usize {
+    (0..self.len())
+      .find(|&index| !self.is_filtered_out(index))
+      .unwrap_or(0)
+  }
+
+  fn matching_count(&self, filter: &FilterExpr) -> usize {
+    self
+      .track_list
+      .iter()
+      .filter(|track_instance| filter.matches(track_instance.loaded_track().metadata()))
+      .count()
+  }
+
+  /// The index in play order `count` matching tracks after `index`, or `None` if that is past the end
+  fn step_forward(&self, index: usize, count: usize) -> Option<usize> {
+    (index.saturating_add(1)..self.len())
+      .filter(|&index| !self.is_filtered_out(index))
+      .nth(count.checked_sub(1)?)
+  }
+
+  /// The index in play order `count` matching tracks before `index`, or `None` if that is before the first track
+  fn step_back(&self, index: usize, count: usize) -> Option<usize> {
+    (0..index.min(self.len()))
+      .rev()
+      .filter(|&index| !self.is_filtered_out(index))
+      .nth(count.checked_sub(1)?)
+  }
+
+  /// Adds the current track to `played`, must be called before moving forward from it
+  fn record_played(&mut self) {
+    if self.current_index >= self.len() {
+      return;
+    }
+
+    if self.played.len() == Self::PLAYED_LEN {
+      self.played.pop_front();
+    }
+
+    let track_id = self[self.current_index].track_id;
+    self.played.push_back(track_id);
+  }
+
+  /// Removes the last `count` tracks from `played` that are still in the track list, and returns
+  /// the position of the last one removed in play order
+  ///
+  /// Ids of removed tracks are dropped along the way. Returns `None` without changing `played`
+  /// if fewer than `count` of its tracks are still in the track list
+  fn pop_played(&mut self, count: usize) -> Option<usize> {
+    let mut remaining = count;
+
+    for (history_index, &track_id) in self.played.iter().enumerate().rev() {
+      let Some(position) = self.play_position_of(track_id) else {
+        continue;
+      };
+
+      if self.is_filtered_out(position) {
+        continue;
+      }
+
+      remaining -= 1;
+      if remaining == 0 {
+        self.played.truncate(history_index);
+        return Some(position);
+      }
+    }
+
+    None
+  }
+
+  fn record(&mut self, generation: u64, update: TrackListUpdate) {
+    let size = update_size(&update);
+    if size > Self::MAX_HISTORY_BYTES {
+      // Clients that missed this update have to query the whole track list instead
+      self.history.clear();
+      self.history_bytes = 0;
+      self.history_start = generation;
+      return;
+    }
+
+    while self.history.len() >= Self::HISTORY_LEN
+      || self.history_bytes + size > Self::MAX_HISTORY_BYTES
+    {
+      let Some((evicted_generation, evicted)) = self.history.pop_front() else {
+        break;
+      };
+      self.history_start = evicted_generation;
+      self.history_bytes -= update_size(&evicted);
     }
+
+    self.history_bytes += size;
+    self.history.push_back((generation, update));
+  }
+
+  fn set_last_removed(&mut self, paths: impl Iterator<Item = PathBuf>) {
+    let mut bytes = 0;
+    self.last_removed = paths
+      .take_while(|path| {
+        bytes += mem::size_of::<PathBuf>() + path.as_os_str().len();
+        bytes <= Self::MAX_LAST_REMOVED_BYTES
+      })
+      .collect();
   }
 
+  /// Clearing an empty track list keeps the previously removed tracks
   pub fn clear(&mut self) {
     debug_assert_eq!(self.track_list.len(), self.shuffled_track_indicies.len());
 
+    if !self.track_list.is_empty() {
+      let paths = mem::take(&mut self.track_list)
+        .into_iter()
+        .map(|track| track.loaded_track().file_path().to_owned());
+      self.set_last_removed(paths);
+    }
+
     self.track_list.clear();
     self.shuffled_track_indicies.clear();
   }
@@ -67,6 +242,46 @@ impl TrackListInner {
     self.track_list.len()
   }
 
+  /// Removes the tracks at `positions` in play order, which must be sorted, deduplicated, and in range
+  ///
+  /// Returns the removed tracks' indicies in `track_list`, sorted
+  pub fn remove_tracks(&mut self, positions: &[usize]) -> Vec<usize> {
+    debug_assert_eq!(self.track_list.len(), self.shuffled_track_indicies.len());
+
+    let mut removed_indicies: Vec<usize> = positions
+      .iter()
+      .map(|&position| self.shuffled_track_indicies[position])
+      .collect();
+    removed_indicies.sort_unstable();
+
+    let paths: Vec<PathBuf> = removed_indicies
+      .iter()
+      .map(|&index| self.track_list[index].loaded_track().file_path().to_owned())
+      .collect();
+    self.set_last_removed(paths.into_iter());
+
+    let mut position = 0;
+    self.shuffled_track_indicies.retain(|_| {
+      let keep = positions.binary_search(&position).is_err();
+      position += 1;
+      keep
+    });
+
+    // Every remaining shuffle index moves down by the number of removed tracks before it
+    for shuffle_index in self.shuffled_track_indicies.iter_mut() {
+      *shuffle_index -= removed_indicies.partition_point(|&removed| removed < *shuffle_index);
+    }
+
+    let mut index = 0;
+    self.track_list.retain(|_| {
+      let keep = removed_indicies.binary_search(&index).is_err();
+      index += 1;
+      keep
+    });
+
+    removed_indicies
+  }
+
   /// Inserts tracks into the `track_list`
   /// Does not insert shuffle indicies, instead returns an iterator of shuffle indicies to insert
   /// These indicies must be added into `shuffled_track_indicies`` before calling any other method
@@ -80,7 +295,8 @@ impl TrackListInner {
     let track_instances = tracks.iter().map(|track| {
       let track_instance = TrackInstance {
         track: track.clone(),
-        track_id: self.latest_track_id,
+        track_id: TrackId(self.latest_track_id),
+        state: InstanceState::default(),
       };
 
       self.latest_track_id += 1;
@@ -100,6 +316,121 @@ impl TrackListInner {
     index..index + tracks.len()
   }
 
+  fn summary(&self, generation: u64) -> QueueSummary {
+    let mut summary = QueueSummary {
+      generation,
+      len: self.len(),
+      ..Default::default()
+    };
+
+    for track_instance in &self.track_list {
+      match track_instance.loaded_track().inner.total_duration {
+        Some(duration) => summary.total_duration += duration,
+        None => summary.unknown_durations += 1,
+      }
+    }
+
+    summary
+  }
+
+  /// Total duration of the tracks in `indicies` that match the filter, in play order, or `None` if any duration is unknown
+  fn play_order_duration(&self, indicies: Range<usize>) -> Option<Duration> {
+    indicies
+      .filter(|&index| !self.is_filtered_out(index))
+      .map(|index| self[index].loaded_track().inner.total_duration)
+      .sum()
+  }
+
+  /// The position of the track with `track_id` in `track_list`, ignoring shuffle
+  fn position_of(&self, track_id: TrackId) -> Option<usize> {
+    self
+      .track_list
+      .iter()
+      .position(|track_instance| track_instance.track_id == track_id)
+  }
+
+  /// The position of the track with `track_id` in play order
+  fn play_position_of(&self, track_id: TrackId) -> Option<usize> {
+    let index = self.position_of(track_id)?;
+
+    self
+      .shuffled_track_indicies
+      .iter()
+      .position(|&shuffle_index| shuffle_index == index)
+  }
+
+  /// The position in play order of the entry `track_ref` refers to
+  fn resolve_ref(&self, track_ref: TrackRef) -> Result<usize, PlayerError> {
+    match track_ref {
+      TrackRef::Position(index) if index < self.len() => Ok(index),
+      TrackRef::Position(index) => Err(PlayerError::TrackIndexOutOfRange {
+        index,
+        len: self.len(),
+      }),
+      TrackRef::Id(track_id) => self
+        .play_position_of(track_id)
+        .ok_or(PlayerError::UnknownTrackId(track_id)),
+    }
+  }
+
+  /// Rearranges the play order so the entry that was at `old_positions[position]` is at `position`
+  ///
+  /// With shuffle off the track list itself is reordered, since the play order has to match it.
+  /// Returns the update for the change, and the new index of `current_index`
+  fn reorder(
+    &mut self,
+    old_positions: &[usize],
+    current_index: usize,
+    shuffle_enabled: bool,
+  ) -> (TrackListUpdate, usize) {
+    debug_assert_eq!(old_positions.len(), self.len());
+
+    let new_shuffle_indicies: Vec<usize> = old_positions
+      .iter()
+      .map(|&position| self.shuffled_track_indicies[position])
+      .collect();
+    // The current index stays past the end if it was
+    let new_current_index = old_positions
+      .iter()
+      .position(|&position| position == current_index)
+      .unwrap_or(current_index);
+
+    if shuffle_enabled {
+      self.shuffled_track_indicies = new_shuffle_indicies.clone();
+      let update = TrackListUpdate::Shuffle {
+        new_shuffle_indicies,
+      };
+      return (update, new_current_index);
+    }
+
+    let mut old_tracks: Vec<Option<TrackInstance>> = mem::take(&mut self.track_list)
+      .into_iter()
+      .map(Some)
+      .collect();
+    self.track_list = new_shuffle_indicies
+      .iter()
+      .map(|&index| {
+        old_tracks[index]
+          .take()
+          .expect("Every entry should be moved exactly once")
+      })
+      .collect();
+    self.order_tracks();
+
+    let update = TrackListUpdate::Reorder {
+      new_order: new_shuffle_indicies,
+      new_shuffle_indicies: self.shuffled_track_indicies.clone(),
+    };
+    (update, new_current_index)
+  }
+
+  fn instance_mut(&mut self, track_id: TrackId) -> Option<&mut TrackInstance> {
+    self
+      .track_list
+      .iter_mut()
+      .find(|track_instance| track_instance.track_id == track_id)
+  }
+
   /// Shuffles the `shuffled_track_indicies`
   ///
   /// Returns the new index of `current_index`
@@ -122,6 +453,92 @@ impl TrackListInner {
     new_index
   }
 
+  /// Inserts `shuffle_indicies` at random positions in `shuffled_track_indicies`
+  ///
+  /// Returns the new index of `current_index`
+  fn insert_shuffled(
+    &mut self,
+    mut shuffle_indicies: Vec<usize>,
+    current_index: usize,
+    rng: &mut impl Rng,
+  ) -> usize {
+    let new_len = self.shuffled_track_indicies.len() + shuffle_indicies.len();
+
+    // Pick every slot up front and merge once, instead of shifting the whole vector for each inserted track
+    shuffle_indicies.shuffle(rng);
+    let mut slots = rand::seq::index::sample(rng, new_len, shuffle_indicies.len()).into_vec();
+    slots.sort_unstable();
+
+    let mut new_current_index = current_index;
+    for &slot in &slots {
+      if slot <= new_current_index {
+        new_current_index += 1;
+      }
+    }
+
+    let mut old_indicies = mem::take(&mut self.shuffled_track_indicies).into_iter();
+    let mut new_indicies = shuffle_indicies.into_iter();
+    let mut slots = slots.into_iter().peekable();
+
+    self.shuffled_track_indicies = (0..new_len)
+      .map(|position| {
+        let shuffle_index = if slots.next_if_eq(&position).is_some() {
+          new_indicies.next()
+        } else {
+          old_indicies.next()
+        };
+
+        shuffle_index.expect("There should be exactly one shuffle index for every slot")
+      })
+      .collect();
+
+    new_current_index
+  }
+
+  /// Inserts `shuffle_indicies` in order at `slot` in `shuffled_track_indicies`
+  ///
+  /// Returns the new index of `current_index`
+  fn insert_together(
+    &mut self,
+    shuffle_indicies: Vec<usize>,
+    slot: usize,
+    current_index: usize,
+  ) -> usize {
+    let inserted = shuffle_indicies.len();
+    let slot = slot.min(self.shuffled_track_indicies.len());
+    self
+      .shuffled_track_indicies
+      .splice(slot..slot, shuffle_indicies);
+
+    if slot <= current_index {
+      current_index + inserted
+    } else {
+      current_index
+    }
+  }
+
+  /// The slot in the play order that matches inserting tracks at `insert_index` in the track list
+  ///
+  /// Must be called after `insert_tracks`, before the new shuffle indicies are added
+  fn play_slot_for_insert(&self, insert_index: usize, inserted: usize) -> usize {
+    let play_len = self.shuffled_track_indicies.len();
+
+    if insert_index == 0 {
+      return 0;
+    }
+
+    if insert_index + inserted >= self.track_list.len() {
+      return play_len;
+    }
+
+    // After the track before them in the track list, whose index was not shifted by the insert
+    self
+      .shuffled_track_indicies
+      .iter()
+      .position(|&track_index| track_index == insert_index - 1)
+      .map_or(play_len, |position| position + 1)
+  }
+
   fn order_tracks(&mut self) {
     debug_assert_eq!(self.track_list.len(), self.shuffled_track_indicies.len());
 
@@ -142,14 +559,52 @@ impl Index<usize> for TrackListInner {
   }
 }
 
+/// The result of `TrackList::remove_tracks`
+#[derive(Debug, Default)]
+pub struct RemovedTracks {
+  pub track_ids: Vec<TrackId>,
+  /// The positions that were past the end of the track list, in the order they were given
+  pub out_of_range: Vec<usize>,
+  /// The current entry, if it was removed
+  pub removed_current: Option<TrackInstance>,
+  /// `None` if no tracks were removed
+  pub change: Option<TrackListChange>,
+}
+
+/// The updates made by one change to the track list, sent to plugins as `Event::TrackListChanged`
+#[derive(Debug)]
+pub struct TrackListChange {
+  pub generation: u64,
+  pub updates: Vec<TrackListUpdate>,
+}
+
+impl From<TrackListChange> for Event {
+  fn from(change: TrackListChange) -> Self {
+    Event::TrackListChanged {
+      generation: change.generation,
+      updates: change.updates,
+    }
+  }
+}
+
 /// Manages the track list and index.
 ///
 /// To reduce the need for locking, relevant data is stored in atomics insteadd of locking the track list
+///
+/// The atomics mirror values that are changed under the lock, so they can be stale by the time they are used.
+/// Anything that also reads or changes the track list must use the values under the lock instead.
 #[derive(Debug)]
 pub struct TrackList {
   inner: Mutex<TrackListInner>,
   track_list_len: AtomicUsize,
+  current_index: AtomicUsize,
   shuffle_enabled: AtomicBool,
+  /// Incremented whenever tracks are inserted, removed, or reordered
+  generation: AtomicU64,
+  /// Tracks past this length are not inserted
+  max_length: AtomicUsize,
+  /// Updated at the end of every change, so it can be read without waiting for the inner lock
+  summary: RwLock<QueueSummary>,
 }
 
 impl TrackList {
@@ -157,67 +612,219 @@ impl TrackList {
     Self {
       inner: Mutex::new(TrackListInner::new()),
       track_list_len: AtomicUsize::new(0),
+      current_index: AtomicUsize::new(0),
       shuffle_enabled: AtomicBool::new(false),
+      generation: AtomicU64::new(0),
+      max_length: AtomicUsize::new(usize::MAX),
+      summary: RwLock::new(QueueSummary::default()),
     }
   }
 
+  pub fn max_length(&self) -> usize {
+    self.max_length.load(Ordering::Acquire)
+  }
+
+  pub fn set_max_length(&self, max_length: usize) {
+    self.max_length.store(max_length, Ordering::Release);
+  }
+
+  pub fn generation(&self) -> u64 {
+    self.generation.load(Ordering::Acquire)
+  }
+
+  /// Must be called while the inner track list is locked, so snapshots always match their generation
+  ///
+  /// Returns the new generation
+  fn next_generation(&self) -> u64 {
+    self.generation.fetch_add(1, Ordering::AcqRel) + 1
+  }
+
   pub fn len(&self) -> usize {
     self.track_list_len.load(Ordering::Acquire)
   }
 
-  pub async fn get_track(&self, index: usize) -> Option<Track> {
-    let num_tracks = self.track_list_len.load(Ordering::Acquire);
+  /// The summary as of the last finished change
+  pub fn summary(&self) -> QueueSummary {
+    *self
+      .summary
+      .read()
+      .expect("Queue summary lock should not be poisoned")
+  }
 
-    if index >= num_tracks {
-      return None;
+  /// Must be called while the inner track list is locked, after `next_generation`
+  fn publish_summary(&self, inner: &TrackListInner, generation: u64) {
+    let summary = inner.summary(generation);
+    *self
+      .summary
+      .write()
+      .expect("Queue summary lock should not be poisoned") = summary;
+  }
+
+  /// Records `updates` as a single new generation, must be called while the inner track list is locked
+  fn commit(&self, inner: &mut TrackListInner, updates: Vec<TrackListUpdate>) -> TrackListChange {
+    let generation = self.next_generation();
+    for update in &updates {
+      inner.record(generation, update.clone());
     }
+    self.publish_summary(inner, generation);
 
-    let inner = self.inner.lock().await;
-    Some(inner[index].loaded_track().clone_track())
+    TrackListChange {
+      generation,
+      updates,
+    }
   }
 
-  /// Returns `None` if the track list length is zero
-  pub async fn get_tracks_to_queue(
-    &self,
-    index: usize,
-  ) -> Option<(Arc<LoadedTrack>, Option<Arc<LoadedTrack>>)> {
-    let num_tracks = self.track_list_len.load(Ordering::Acquire);
+  /// The current index in play order, which may be stale
+  pub fn current_index(&self) -> usize {
+    self.current_index.load(Ordering::Acquire)
+  }
 
-    if index >= num_tracks {
-      return None;
-    }
+  /// Must be called while the inner track list is locked
+  fn set_current_index(&self, inner: &mut TrackListInner, index: usize) {
+    inner.current_index = index;
+    self.current_index.store(index, Ordering::Release);
+  }
 
-    let inner = self.inner.lock().await;
+  /// Moves forward `count` tracks, skipping tracks that don't match the filter
+  ///
+  /// Returns false if the new index is past the end of the track list
+  pub async fn advance(&self, count: usize) -> bool {
+    let mut inner = self.inner.lock().await;
+    inner.record_played();
 
-    let current_track = inner[index].track.clone();
+    let new_index = inner.step_forward(inner.current_index, count);
+    let past_end = inner.len();
+    self.set_current_index(&mut inner, new_index.unwrap_or(past_end));
+
+    new_index.is_some()
+  }
+
+  /// Moves back `count` tracks, skipping tracks that don't match the filter
+  ///
+  /// With shuffle on, this goes back through the tracks in the order they played, wherever they are now.
+  /// Otherwise, or once there are not enough played tracks left, it moves back in play order.
+  ///
+  /// Returns false without changing the index if that would move before the first track
+  pub async fn retreat(&self, count: usize) -> bool {
+    let mut inner = self.inner.lock().await;
 
-    let is_last_track = index == num_tracks - 1;
-    let next_track = if !is_last_track {
-      Some(inner[index + 1].track.clone())
+    let played_index = if self.shuffle_enabled() {
+      inner.pop_played(count)
     } else {
       None
     };
+    if let Some(previous_index) = played_index {
+      self.set_current_index(&mut inner, previous_index);
+      return true;
+    }
+
+    // `step_back` handles a current index past the end, if the track list shrank since it was set
+    let Some(previous_index) = inner.step_back(inner.current_index, count) else {
+      return false;
+    };
+
+    self.set_current_index(&mut inner, previous_index);
+    true
+  }
+
+  /// Moves to the track at `index` in play order
+  ///
+  /// Returns the length of the track list without changing the index if `index` is out of range
+  pub async fn go_to(&self, index: usize) -> Result<(), usize> {
+    let mut inner = self.inner.lock().await;
+    if index >= inner.len() {
+      return Err(inner.len());
+    }
+
+    inner.record_played();
+    self.set_current_index(&mut inner, index);
+    Ok(())
+  }
+
+  /// Moves to the last matching track if `to_last` is set, otherwise the first
+  pub async fn wrap_current(&self, to_last: bool) {
+    let mut inner = self.inner.lock().await;
+    let len = inner.len();
+    let new_index = if to_last {
+      inner.step_back(len, 1).unwrap_or(len.saturating_sub(1))
+    } else {
+      inner.first_matching()
+    };
+
+    self.set_current_index(&mut inner, new_index);
+  }
+
+  pub async fn current_track(&self) -> Option<Track> {
+    let inner = self.inner.lock().await;
+    let index = inner.current_index;
+
+    (index < inner.len()).then(|| inner[index].loaded_track().clone_track())
+  }
+
+  /// The first track that matches the filter, where playback starts again after the end of the track list
+  pub async fn first_track(&self) -> Option<Track> {
+    let inner = self.inner.lock().await;
+    let index = inner.first_matching();
+
+    (index < inner.len()).then(|| inner[index].loaded_track().clone_track())
+  }
+
+  pub async fn current_track_id(&self) -> Option<TrackId> {
+    let inner = self.inner.lock().await;
+    let index = inner.current_index;
+
+    (index < inner.len()).then(|| inner[index].track_id)
+  }
+
+  /// The current track and the next one that matches the filter
+  ///
+  /// The current track is returned even if it doesn't match, since it was chosen directly or the filter changed while it played.
+  /// Returns `None` if the current index is past the end of the track list
+  pub async fn get_tracks_to_queue(&self) -> Option<(TrackInstance, Option<TrackInstance>)> {
+    let inner = self.inner.lock().await;
+    let index = inner.current_index;
+
+    if index >= inner.len() {
+      return None;
+    }
+
+    let current_track = inner[index].clone();
+    let next_track = inner
+      .step_forward(index, 1)
+      .map(|next_index| inner[next_index].clone());
 
     Some((current_track, next_track))
   }
 
+  /// Updates the state of the track with `track_id`, without affecting other entries of the same file
+  pub async fn update_instance(
+    &self,
+    track_id: TrackId,
+    f: impl FnOnce(&mut InstanceState),
+  ) -> Result<(), PlayerError> {
+    let mut inner = self.inner.lock().await;
+    let track_instance = inner
+      .instance_mut(track_id)
+      .ok_or(PlayerError::UnknownTrackId(track_id))?;
+
+    f(&mut track_instance.state);
+    Ok(())
+  }
+
   pub fn shuffle_enabled(&self) -> bool {
     self.shuffle_enabled.load(Ordering::Acquire)
   }
 
-  /// Returns the new position of `current_index` after the shuffle/order
-  pub async fn set_shuffle(
-    &self,
-    shuffle: bool,
-    current_index: usize,
-  ) -> Result<usize, PlayerError> {
+  /// Shuffles or orders the tracks, keeping the current track current
+  pub async fn set_shuffle(&self, shuffle: bool) -> Result<TrackListChange, PlayerError> {
     let mut inner = self.inner.lock().await;
     self.shuffle_enabled.store(shuffle, Ordering::Release);
 
-    if shuffle {
-      let new_index = inner.shuffle_tracks(current_index, &mut rand::rng());
+    // The current index can be past the end if the track list was cleared
+    let current_index = inner.current_index.min(inner.len().saturating_sub(1));
 
-      Ok(new_index)
+    let new_index = if shuffle {
+      inner.shuffle_tracks(current_index, &mut rand::rng())
     } else {
       // After `order_tracks` is run `shuffled_track_indicies` maps exactly to `track_list`
       let track_index = if inner.len() != 0 {
@@ -227,78 +834,373 @@ impl TrackList {
       };
 
       inner.order_tracks();
-      Ok(track_index)
-    }
+      track_index
+    };
+    self.set_current_index(&mut inner, new_index);
+
+    let new_shuffle_indicies = inner.shuffled_track_indicies.clone();
+    Ok(self.commit(
+      &mut inner,
+      vec![TrackListUpdate::Shuffle {
+        new_shuffle_indicies,
+      }],
+    ))
   }
 
-  pub async fn clear(&self) -> Result<(), PlayerError> {
+  /// Exchanges the entries `a` and `b` refer to in play order
+  pub async fn swap(&self, a: TrackRef, b: TrackRef) -> Result<TrackListChange, PlayerError> {
     let mut inner = self.inner.lock().await;
-    inner.clear();
-    self.track_list_len.store(0, Ordering::Release);
+    let (a, b) = (inner.resolve_ref(a)?, inner.resolve_ref(b)?);
 
-    Ok(())
+    let mut old_positions: Vec<usize> = (0..inner.len()).collect();
+    old_positions.swap(a, b);
+    Ok(self.reorder(&mut inner, &old_positions))
   }
 
-  /// Returns the new position of `current_index`
-  pub async fn insert_tracks(
-    &self,
-    current_index: usize,
-    position: InsertPosition,
-    tracks: &[Arc<LoadedTrack>],
-  ) -> Result<usize, PlayerError> {
+  /// Moves the entry at position `from` in play order to position `to`, shifting the entries between them
+  pub async fn move_track(&self, from: usize, to: usize) -> Result<TrackListChange, PlayerError> {
     let mut inner = self.inner.lock().await;
+    let from = inner.resolve_ref(TrackRef::Position(from))?;
+    let to = inner.resolve_ref(TrackRef::Position(to))?;
 
-    if matches!(position, InsertPosition::Replace) {
-      inner.clear();
-    }
+    let mut old_positions: Vec<usize> = (0..inner.len()).collect();
+    let moved = old_positions.remove(from);
+    old_positions.insert(to, moved);
+    Ok(self.reorder(&mut inner, &old_positions))
+  }
 
-    let track_list_started_empty = inner.len() == 0;
+  /// Reverses the play order
+  pub async fn reverse(&self) -> TrackListChange {
+    let mut inner = self.inner.lock().await;
+    let old_positions: Vec<usize> = (0..inner.len()).rev().collect();
+    self.reorder(&mut inner, &old_positions)
+  }
 
-    // Insert `Next` tracks into the tracks list after the current song, even if it has been shuffled
-    let track_index = if !track_list_started_empty {
-      inner.shuffled_track_indicies[current_index]
+  fn reorder(&self, inner: &mut TrackListInner, old_positions: &[usize]) -> TrackListChange {
+    let (update, new_index) =
+      inner.reorder(old_positions, inner.current_index, self.shuffle_enabled());
+    self.set_current_index(inner, new_index);
+    self.commit(inner, vec![update])
+  }
+
+  /// Sets the play order of tracks that were just restored, instead of shuffling them again
+  ///
+  /// `play_order` must contain every index of the track list once
+  pub async fn restore_play_order(
+    &self,
+    play_order: Vec<usize>,
+    shuffle: bool,
+    current_index: usize,
+  ) -> TrackListChange {
+    let mut inner = self.inner.lock().await;
+    debug_assert_eq!(inner.track_list.len(), play_order.len());
+
+    self.shuffle_enabled.store(shuffle, Ordering::Release);
+    inner.shuffled_track_indicies = play_order;
+    self.set_current_index(&mut inner, current_index);
+
+    let new_shuffle_indicies = inner.shuffled_track_indicies.clone();
+    self.commit(
+      &mut inner,
+      vec![TrackListUpdate::Shuffle {
+        new_shuffle_indicies,
+      }],
+    )
+  }
+
+  /// The paths of the tracks in track list order, the play order, and the current index, for saving the queue
+  pub async fn saved_order(&self) -> (Vec<PathBuf>, Vec<usize>, usize) {
+    let inner = self.inner.lock().await;
+    let paths = inner
+      .track_list
+      .iter()
+      .map(|track_instance| track_instance.loaded_track().file_path().to_owned())
+      .collect();
+
+    (
+      paths,
+      inner.shuffled_track_indicies.clone(),
+      inner.current_index,
+    )
+  }
+
+  /// Removes the tracks at `positions` in play order, keeping the current track current if it was not removed
+  ///
+  /// If the current track was removed, the track after it becomes current
+  pub async fn remove_tracks(&self, positions: &[usize]) -> RemovedTracks {
+    let mut inner = self.inner.lock().await;
+    let len = inner.len();
+
+    let (mut in_range, out_of_range): (Vec<usize>, Vec<usize>) = positions
+      .iter()
+      .copied()
+      .partition(|&position| position < len);
+    in_range.sort_unstable();
+    in_range.dedup();
+
+    let mut removed = RemovedTracks {
+      out_of_range,
+      ..Default::default()
+    };
+
+    if in_range.is_empty() {
+      return removed;
+    }
+
+    let current_index = inner.current_index;
+    let current_removed = in_range.binary_search(&current_index).is_ok();
+    removed.removed_current = current_removed.then(|| inner[current_index].clone());
+    removed.track_ids = in_range
+      .iter()
+      .map(|&position| inner[position].track_id)
+      .collect();
+
+    let removed_indicies = inner.remove_tracks(&in_range);
+    self.track_list_len.store(inner.len(), Ordering::Release);
+
+    let new_current_index =
+      current_index - in_range.partition_point(|&position| position < current_index);
+    // The track that becomes current must match the filter, like moving to the next track
+    let new_current_index = if current_removed
+      && new_current_index < inner.len()
+      && inner.is_filtered_out(new_current_index)
+    {
+      inner
+        .step_forward(new_current_index, 1)
+        .unwrap_or(inner.len())
     } else {
-      0
+      new_current_index
+    };
+    self.set_current_index(&mut inner, new_current_index);
+
+    let new_shuffle_indicies = inner.shuffled_track_indicies.clone();
+    removed.change = Some(self.commit(
+      &mut inner,
+      vec![TrackListUpdate::Remove {
+        removed_indicies,
+        new_shuffle_indicies,
+      }],
+    ));
+
+    removed
+  }
+
+  pub async fn filter(&self) -> Option<FilterExpr> {
+    self.inner.lock().await.filter.clone()
+  }
+
+  /// Sets or clears the filter, returning the number of entries that match it
+  ///
+  /// A filter that matches nothing is rejected, so it can't leave playback with nowhere to go
+  pub async fn set_filter(&self, filter: Option<FilterExpr>) -> Result<usize, PlayerError> {
+    let mut inner = self.inner.lock().await;
+
+    let matching = match &filter {
+      Some(filter) => inner.matching_count(filter),
+      None => inner.len(),
     };
 
+    if filter.is_some() && matching == 0 {
+      return Err(PlayerError::FilterMatchesNothing);
+    }
+
+    inner.filter = filter;
+    Ok(matching)
+  }
+
+  /// Clears the filter if no entry matches it anymore, such as after the matching tracks were removed
+  ///
+  /// Returns true if the filter was cleared
+  pub async fn clear_unmatched_filter(&self) -> bool {
+    let mut inner = self.inner.lock().await;
+
+    let unmatched = inner
+      .filter
+      .as_ref()
+      .is_some_and(|filter| inner.matching_count(filter) == 0);
+    if unmatched {
+      inner.filter = None;
+    }
+
+    unmatched
+  }
+
+  /// Paths of the tracks removed by the last clear, replace, or removal
+  pub async fn last_removed(&self) -> Vec<PathBuf> {
+    self.inner.lock().await.last_removed.clone()
+  }
+
+  pub async fn clear(&self) -> Result<TrackListChange, PlayerError> {
+    let mut inner = self.inner.lock().await;
+    inner.clear();
+    self.track_list_len.store(0, Ordering::Release);
+    self.set_current_index(&mut inner, 0);
+
+    Ok(self.commit(&mut inner, vec![TrackListUpdate::Clear]))
+  }
+
+  /// Keeps the current track current, unless the track list is replaced or was empty
+  ///
+  /// `shuffle_policy` is only used if shuffle is enabled, otherwise the play order matches the track list
+  ///
+  /// Returns the number of tracks that were not inserted because the track list would be longer than `max_length`
+  pub async fn insert_tracks(
+    &self,
+    position: InsertPosition,
+    shuffle_policy: InsertShufflePolicy,
+    tracks: &[Arc<LoadedTrack>],
+  ) -> Result<(usize, TrackListChange), PlayerError> {
+    // Only the list changes need the lock, so clone the track info for the update history first
+    let mut inserted_tracks: Vec<Track> = tracks.iter().map(|track| track.clone_track()).collect();
+
+    let mut inner = self.inner.lock().await;
+
+    if matches!(position, InsertPosition::Replace) {
+      inner.clear();
+    }
+
+    let available = self.max_length().saturating_sub(inner.len());
+    let dropped = tracks.len().saturating_sub(available);
+    let tracks = &tracks[..tracks.len() - dropped];
+    inserted_tracks.truncate(tracks.len());
+
+    let track_list_started_empty = inner.len() == 0;
+    let current_index = inner.current_index;
+
+    // Insert `Next` tracks into the tracks list after the current song, even if it has been shuffled
+    // If the current index is past the end, they are inserted after the last track
+    let track_index = inner
+      .shuffled_track_indicies
+      .get(current_index)
+      .or(inner.shuffled_track_indicies.last())
+      .copied()
+      .unwrap_or(0);
+
     let insert_index = match position {
       InsertPosition::Absolute(position) => position.clamp(0, inner.len()),
-      InsertPosition::Next => track_index.saturating_add_signed(1),
+      InsertPosition::After(track_id) => {
+        inner
+          .position_of(track_id)
+          .ok_or(PlayerError::UnknownTrackId(track_id))?
+          + 1
+      }
+      InsertPosition::Next => (track_index + 1).min(inner.len()),
       InsertPosition::Start => 0,
       InsertPosition::End => inner.len(),
       InsertPosition::Replace => 0,
     };
 
     let shuffle_indicies: Vec<usize> = inner.insert_tracks(insert_index, tracks).collect();
-    let shuffled_track_indicies = &mut inner.shuffled_track_indicies;
 
-    let mut new_current_index = current_index;
-    if self.shuffle_enabled.load(Ordering::Acquire) {
-      let mut rng = rand::rng();
-
-      // Move new shuffle indicies to random locations
-      for shuffle_index in shuffle_indicies {
-        let new_index = rng.random_range(0..=shuffled_track_indicies.len());
-
-        shuffled_track_indicies.insert(new_index, shuffle_index);
-        if new_index <= new_current_index {
-          new_current_index += 1;
+    let new_current_index = if !self.shuffle_enabled.load(Ordering::Acquire) {
+      inner.insert_together(shuffle_indicies, insert_index, current_index)
+    } else {
+      match shuffle_policy {
+        // Move new shuffle indicies to random locations
+        InsertShufflePolicy::Scatter => {
+          inner.insert_shuffled(shuffle_indicies, current_index, &mut rand::rng())
+        }
+        InsertShufflePolicy::KeepTogetherNext => {
+          let slot = if track_list_started_empty {
+            0
+          } else {
+            current_index + 1
+          };
+          inner.insert_together(shuffle_indicies, slot, current_index)
+        }
+        InsertShufflePolicy::KeepTogetherInPlace => {
+          let slot = inner.play_slot_for_insert(insert_index, shuffle_indicies.len());
+          inner.insert_together(shuffle_indicies, slot, current_index)
         }
       }
+    };
+
+    self.track_list_len.store(inner.len(), Ordering::Release);
+
+    let new_current_index = if !track_list_started_empty {
+      new_current_index
     } else {
-      if insert_index <= new_current_index {
-        new_current_index += shuffle_indicies.len();
+      inner.first_matching()
+    };
+    self.set_current_index(&mut inner, new_current_index);
+
+    let mut updates = Vec::with_capacity(2);
+    if matches!(position, InsertPosition::Replace) {
+      updates.push(TrackListUpdate::Clear);
+    }
+
+    let new_shuffle_indicies = inner.shuffled_track_indicies.clone();
+    updates.push(TrackListUpdate::Insert {
+      index: insert_index,
+      tracks: inserted_tracks,
+      new_shuffle_indicies,
+    });
+
+    Ok((dropped, self.commit(&mut inner, updates)))
+  }
+
+  /// Time until the track at `index` in play order starts, if playback continues from `position` in the current track
+  ///
+  /// `None` if the track will not play with `loop_mode`, or a track before it has an unknown duration
+  pub async fn time_until(
+    &self,
+    index: usize,
+    position: Duration,
+    loop_mode: LoopMode,
+  ) -> Option<Duration> {
+    let inner = self.inner.lock().await;
+    let len = inner.len();
+    let current_index = inner.current_index;
+
+    if index >= len || current_index >= len {
+      return None;
+    }
+
+    if index == current_index {
+      return Some(Duration::ZERO);
+    }
+
+    if inner.is_filtered_out(index) {
+      return None;
+    }
+
+    let remaining = inner
+      .play_order_duration(current_index..current_index + 1)?
+      .saturating_sub(position);
+
+    let between = match loop_mode {
+      // The current track repeats forever
+      LoopMode::Track => return None,
+      _ if index > current_index => inner.play_order_duration(current_index + 1..index)?,
+      // Tracks before the current one only play again after wrapping around
+      LoopMode::Playlist => {
+        inner.play_order_duration(current_index + 1..len)? + inner.play_order_duration(0..index)?
       }
+      LoopMode::None => return None,
+    };
+
+    Some(remaining + between)
+  }
+
+  /// The updates made since `since_generation`, or `TrackListDiff::TooOld` if they are no longer in the history
+  pub async fn diff_since(&self, since_generation: u64) -> TrackListDiff {
+    let inner = self.inner.lock().await;
+    let generation = self.generation();
 
-      shuffled_track_indicies.splice(insert_index..insert_index, shuffle_indicies);
+    if since_generation > generation || since_generation < inner.history_start {
+      return TrackListDiff::TooOld;
     }
 
-    self.track_list_len.store(inner.len(), Ordering::Release);
+    let updates = inner
+      .history
+      .iter()
+      .filter(|(update_generation, _)| *update_generation > since_generation)
+      .map(|(_, update)| update.clone())
+      .collect();
 
-    if !track_list_started_empty {
-      Ok(new_current_index)
-    } else {
-      Ok(0)
+    TrackListDiff::Updates {
+      generation,
+      updates,
     }
   }
 
@@ -311,9 +1213,1166 @@ impl TrackList {
       .map(|track_instance| track_instance.loaded_track().clone_track())
       .collect();
 
+    let instances = inner
+      .track_list
+      .iter()
+      .map(|track_instance| {
+        let filtered_out = inner
+          .filter
+          .as_ref()
+          .is_some_and(|filter| !filter.matches(track_instance.loaded_track().metadata()));
+        track_instance.info(filtered_out)
+      })
+      .collect();
+
     TrackListSnapshot {
       track_list,
       shuffle_indicies: inner.shuffled_track_indicies.clone(),
+      instances,
+      generation: self.generation(),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use hsm_ipc::TrackMetadata;
+  use rand::{SeedableRng, rngs::StdRng};
+  use symphonia::core::audio::{Channels, SignalSpec};
+
+  use super::*;
+  use crate::audio_server::track::GaplessTrim;
+
+  fn track(title: &str) -> Arc<LoadedTrack> {
+    Arc::new(LoadedTrack {
+      inner: Track {
+        file_path: PathBuf::from(format!("/music/{title}.flac")),
+        total_duration: Some(Duration::from_secs(60)),
+        metadata: TrackMetadata {
+          title: Some(title.to_owned()),
+          ..Default::default()
+        },
+      },
+      spec: SignalSpec::new(44100, Channels::FRONT_LEFT | Channels::FRONT_RIGHT),
+      spec_verified: true,
+      trim: GaplessTrim::default(),
+    })
+  }
+
+  fn tracks(titles: &[&str]) -> Vec<Arc<LoadedTrack>> {
+    titles.iter().map(|title| track(title)).collect()
+  }
+
+  /// A track list of `titles`, played in `play_order` with shuffle on, or in order with shuffle off if it is `None`
+  async fn track_list(
+    titles: &[&str],
+    play_order: Option<&[usize]>,
+    current_index: usize,
+  ) -> TrackList {
+    let track_list = TrackList::new();
+    track_list
+      .insert_tracks(
+        InsertPosition::End,
+        InsertShufflePolicy::Scatter,
+        &tracks(titles),
+      )
+      .await
+      .unwrap();
+
+    let shuffle = play_order.is_some();
+    let play_order = play_order.map_or_else(|| (0..titles.len()).collect(), <[usize]>::to_vec);
+    track_list
+      .restore_play_order(play_order, shuffle, current_index)
+      .await;
+    track_list
+  }
+
+  fn title(track_instance: &TrackInstance) -> String {
+    track_instance
+      .loaded_track()
+      .metadata()
+      .title
+      .clone()
+      .unwrap()
+  }
+
+  /// The titles in play order
+  async fn play_order(track_list: &TrackList) -> Vec<String> {
+    let inner = track_list.inner.lock().await;
+    (0..inner.len()).map(|index| title(&inner[index])).collect()
+  }
+
+  /// The titles in track list order
+  async fn list_order(track_list: &TrackList) -> Vec<String> {
+    let inner = track_list.inner.lock().await;
+    inner.track_list.iter().map(title).collect()
+  }
+
+  async fn current_title(track_list: &TrackList) -> Option<String> {
+    track_list
+      .current_track()
+      .await
+      .and_then(|track| track.metadata.title)
+  }
+
+  #[test]
+  fn insert_together_cases() {
+    // (play order, inserted shuffle indicies, slot, current index) => (play order, current index)
+    let cases = [
+      (vec![0, 1, 2], vec![3, 4], 1, 0, vec![0, 3, 4, 1, 2], 0),
+      // Inserting at the current index moves it after the inserted tracks
+      (vec![0, 1, 2], vec![3, 4], 1, 1, vec![0, 3, 4, 1, 2], 3),
+      (vec![2, 0, 1], vec![3], 3, 2, vec![2, 0, 1, 3], 2),
+      // A slot past the end inserts at the end
+      (vec![0, 1, 2], vec![3], 99, 1, vec![0, 1, 2, 3], 1),
+      // A current index past the end stays past the end
+      (vec![0, 1, 2], vec![3], 0, 3, vec![3, 0, 1, 2], 4),
+      // `insert_tracks` moves to the first track when the list started empty
+      (vec![], vec![0, 1], 0, 0, vec![0, 1], 2),
+    ];
+
+    for (order, inserted, slot, current_index, expected_order, expected_index) in cases {
+      let mut inner = TrackListInner::new();
+      inner.shuffled_track_indicies = order.clone();
+
+      let new_index = inner.insert_together(inserted.clone(), slot, current_index);
+      assert_eq!(
+        (inner.shuffled_track_indicies, new_index),
+        (expected_order, expected_index),
+        "{order:?} inserting {inserted:?} at {slot}"
+      );
+    }
+  }
+
+  #[test]
+  fn play_slot_for_insert_cases() {
+    // (play order, insert index, inserted) => slot
+    let cases: [(&[usize], usize, usize, usize); 6] = [
+      (&[2, 0, 3, 1], 0, 2, 0),
+      // After `b`, the track before them in the track list
+      (&[2, 0, 3, 1], 2, 2, 4),
+      (&[2, 0, 3, 1], 3, 1, 1),
+      (&[2, 0, 3, 1], 4, 1, 4),
+      (&[0, 1, 2, 3], 2, 1, 2),
+      (&[], 0, 2, 0),
+    ];
+    let titles = ["a", "b", "c", "d"];
+
+    for (order, insert_index, inserted, expected) in cases {
+      let mut inner = TrackListInner::new();
+      let _ = inner.insert_tracks(0, &tracks(&titles[..order.len()]));
+      inner.shuffled_track_indicies = order.to_vec();
+
+      let inserted_titles = vec!["x"; inserted];
+      let _ = inner.insert_tracks(insert_index, &tracks(&inserted_titles));
+      assert_eq!(
+        inner.play_slot_for_insert(insert_index, inserted),
+        expected,
+        "{order:?} inserting {inserted} at {insert_index}"
+      );
+    }
+  }
+
+  #[test]
+  fn insert_shuffled_keeps_the_current_track() {
+    // (tracks, inserted, current index)
+    let cases = [
+      (0, 3, 0),
+      (1, 1, 0),
+      (4, 2, 0),
+      (4, 2, 3),
+      (4, 3, 4),
+      (10, 5, 6),
+    ];
+
+    for seed in 0..20 {
+      let mut rng = StdRng::seed_from_u64(seed);
+
+      for (len, inserted, current_index) in cases {
+        let mut inner = TrackListInner::new();
+        inner.shuffled_track_indicies = (0..len).rev().collect();
+        let old_order = inner.shuffled_track_indicies.clone();
+
+        let new_indicies: Vec<usize> = (len..len + inserted).collect();
+        let new_index = inner.insert_shuffled(new_indicies, current_index, &mut rng);
+        let order = &inner.shuffled_track_indicies;
+
+        let mut sorted = order.clone();
+        sorted.sort_unstable();
+        assert_eq!(sorted, (0..len + inserted).collect::<Vec<_>>(), "{order:?}");
+
+        // The existing tracks keep their order
+        let kept: Vec<usize> = order.iter().copied().filter(|&index| index < len).collect();
+        assert_eq!(kept, old_order, "{order:?}");
+
+        match old_order.get(current_index) {
+          Some(&current) => assert_eq!(order[new_index], current, "{order:?}"),
+          // Past the end before, and still past the end
+          None => assert_eq!(new_index, len + inserted, "{order:?}"),
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn insert_keeping_tracks_together() {
+    use InsertPosition::*;
+    use InsertShufflePolicy::*;
+
+    // Played as c, a, d, b with `a` current
+    let titles = ["a", "b", "c", "d"];
+    let order: &[usize] = &[2, 0, 3, 1];
+    let cases = [
+      (KeepTogetherNext, Next, ["c", "a", "x", "y", "d", "b"]),
+      (KeepTogetherNext, Start, ["c", "a", "x", "y", "d", "b"]),
+      (KeepTogetherNext, End, ["c", "a", "x", "y", "d", "b"]),
+      (
+        KeepTogetherNext,
+        Absolute(99),
+        ["c", "a", "x", "y", "d", "b"],
+      ),
+      (KeepTogetherInPlace, Start, ["x", "y", "c", "a", "d", "b"]),
+      // After `b` in the track list, so after it in play order too
+      (
+        KeepTogetherInPlace,
+        Absolute(2),
+        ["c", "a", "d", "b", "x", "y"],
+      ),
+      (
+        KeepTogetherInPlace,
+        After(TrackId(2)),
+        ["c", "x", "y", "a", "d", "b"],
+      ),
+      (KeepTogetherInPlace, End, ["c", "a", "d", "b", "x", "y"]),
+      (
+        KeepTogetherInPlace,
+        Absolute(99),
+        ["c", "a", "d", "b", "x", "y"],
+      ),
+    ];
+
+    for (policy, position, expected) in cases {
+      smol::block_on(async {
+        let track_list = track_list(&titles, Some(order), 1).await;
+        track_list
+          .insert_tracks(position, policy, &tracks(&["x", "y"]))
+          .await
+          .unwrap();
+
+        assert_eq!(
+          play_order(&track_list).await,
+          expected,
+          "{policy:?} at {position:?}"
+        );
+        assert_eq!(current_title(&track_list).await.as_deref(), Some("a"));
+      });
+    }
+  }
+
+  #[test]
+  fn insert_keeping_tracks_together_into_empty_list() {
+    for policy in [
+      InsertShufflePolicy::KeepTogetherNext,
+      InsertShufflePolicy::KeepTogetherInPlace,
+    ] {
+      smol::block_on(async {
+        let track_list = track_list(&[], Some(&[]), 0).await;
+        track_list
+          .insert_tracks(InsertPosition::Next, policy, &tracks(&["x", "y"]))
+          .await
+          .unwrap();
+
+        assert_eq!(play_order(&track_list).await, ["x", "y"], "{policy:?}");
+        assert_eq!(track_list.current_index(), 0);
+      });
+    }
+  }
+
+  #[test]
+  fn insert_without_shuffle_follows_track_list() {
+    let cases = [
+      (InsertPosition::Next, ["a", "b", "x", "y", "c"]),
+      (InsertPosition::Start, ["x", "y", "a", "b", "c"]),
+      (InsertPosition::Absolute(99), ["a", "b", "c", "x", "y"]),
+    ];
+
+    for (position, expected) in cases {
+      smol::block_on(async {
+        let track_list = track_list(&["a", "b", "c"], None, 1).await;
+        track_list
+          .insert_tracks(
+            position,
+            InsertShufflePolicy::KeepTogetherInPlace,
+            &tracks(&["x", "y"]),
+          )
+          .await
+          .unwrap();
+
+        assert_eq!(play_order(&track_list).await, expected, "{position:?}");
+        assert_eq!(list_order(&track_list).await, expected, "{position:?}");
+        assert_eq!(current_title(&track_list).await.as_deref(), Some("b"));
+      });
+    }
+  }
+
+  const TITLES: [&str; 5] = ["a", "b", "c", "d", "e"];
+  /// Played as e, c, a, d, b while shuffled
+  const SHUFFLED: &[usize] = &[4, 2, 0, 3, 1];
+
+  #[test]
+  fn move_track_across_current() {
+    // (play order, current index, from, to) => (play order, current index)
+    let cases = [
+      (None, 2, 0, 4, ["b", "c", "d", "e", "a"], 1),
+      (None, 2, 4, 0, ["e", "a", "b", "c", "d"], 3),
+      (None, 2, 1, 3, ["a", "c", "d", "b", "e"], 1),
+      (None, 2, 3, 1, ["a", "d", "b", "c", "e"], 3),
+      (None, 2, 3, 4, ["a", "b", "c", "e", "d"], 2),
+      (None, 2, 2, 0, ["c", "a", "b", "d", "e"], 0),
+      // Stays past the end
+      (None, 5, 0, 4, ["b", "c", "d", "e", "a"], 5),
+      (Some(SHUFFLED), 2, 0, 4, ["c", "a", "d", "b", "e"], 1),
+      (Some(SHUFFLED), 2, 4, 0, ["b", "e", "c", "a", "d"], 3),
+      (Some(SHUFFLED), 2, 1, 3, ["e", "a", "d", "c", "b"], 1),
+      (Some(SHUFFLED), 2, 3, 1, ["e", "d", "c", "a", "b"], 3),
+      (Some(SHUFFLED), 2, 2, 4, ["e", "c", "d", "b", "a"], 4),
+    ];
+
+    for (order, current_index, from, to, expected, expected_index) in cases {
+      smol::block_on(async {
+        let track_list = track_list(&TITLES, order, current_index).await;
+        let change = track_list.move_track(from, to).await.unwrap();
+
+        let context = format!("{order:?} moving {from} to {to}");
+        assert_eq!(play_order(&track_list).await, expected, "{context}");
+        assert_eq!(track_list.current_index(), expected_index, "{context}");
+        assert_reordered(&track_list, order.is_some(), &change).await;
+      });
+    }
+  }
+
+  /// With shuffle on only the play order changes, otherwise the track list is reordered to match it
+  async fn assert_reordered(track_list: &TrackList, shuffled: bool, change: &TrackListChange) {
+    if shuffled {
+      assert_eq!(list_order(track_list).await, TITLES);
+      assert!(matches!(
+        change.updates.as_slice(),
+        [TrackListUpdate::Shuffle { .. }]
+      ));
+    } else {
+      assert_eq!(list_order(track_list).await, play_order(track_list).await);
+      assert!(matches!(
+        change.updates.as_slice(),
+        [TrackListUpdate::Reorder { .. }]
+      ));
+    }
+  }
+
+  #[test]
+  fn move_track_out_of_range() {
+    smol::block_on(async {
+      let track_list = track_list(&TITLES, None, 0).await;
+      let generation = track_list.generation();
+
+      assert!(matches!(
+        track_list.move_track(0, 5).await,
+        Err(PlayerError::TrackIndexOutOfRange { index: 5, len: 5 })
+      ));
+      assert!(matches!(
+        track_list.move_track(5, 0).await,
+        Err(PlayerError::TrackIndexOutOfRange { index: 5, len: 5 })
+      ));
+      assert_eq!(track_list.generation(), generation);
+      assert_eq!(play_order(&track_list).await, TITLES);
+    });
+  }
+
+  #[test]
+  fn swap_tracks() {
+    use TrackRef::{Id, Position};
+
+    // (play order, current index, a, b) => (play order, current index)
+    let cases = [
+      (
+        None,
+        2,
+        Position(0),
+        Position(4),
+        ["e", "b", "c", "d", "a"],
+        2,
+      ),
+      (
+        None,
+        2,
+        Position(2),
+        Position(4),
+        ["a", "b", "e", "d", "c"],
+        4,
+      ),
+      (
+        None,
+        2,
+        Position(0),
+        Position(2),
+        ["c", "b", "a", "d", "e"],
+        0,
+      ),
+      (
+        None,
+        2,
+        Position(1),
+        Position(1),
+        ["a", "b", "c", "d", "e"],
+        2,
+      ),
+      (
+        None,
+        2,
+        Id(TrackId(0)),
+        Id(TrackId(3)),
+        ["d", "b", "c", "a", "e"],
+        2,
+      ),
+      // Stays past the end
+      (
+        None,
+        5,
+        Position(0),
+        Position(4),
+        ["e", "b", "c", "d", "a"],
+        5,
+      ),
+      (
+        Some(SHUFFLED),
+        2,
+        Position(0),
+        Position(4),
+        ["b", "c", "a", "d", "e"],
+        2,
+      ),
+      (
+        Some(SHUFFLED),
+        2,
+        Position(2),
+        Position(0),
+        ["a", "c", "e", "d", "b"],
+        0,
+      ),
+      (
+        Some(SHUFFLED),
+        2,
+        Id(TrackId(1)),
+        Id(TrackId(4)),
+        ["b", "c", "a", "d", "e"],
+        2,
+      ),
+      (
+        Some(SHUFFLED),
+        2,
+        Position(1),
+        Id(TrackId(0)),
+        ["e", "a", "c", "d", "b"],
+        1,
+      ),
+    ];
+
+    for (order, current_index, a, b, expected, expected_index) in cases {
+      smol::block_on(async {
+        let track_list = track_list(&TITLES, order, current_index).await;
+        let change = track_list.swap(a, b).await.unwrap();
+
+        let context = format!("{order:?} swapping {a:?} and {b:?}");
+        assert_eq!(play_order(&track_list).await, expected, "{context}");
+        assert_eq!(track_list.current_index(), expected_index, "{context}");
+        assert_reordered(&track_list, order.is_some(), &change).await;
+      });
+    }
+  }
+
+  #[test]
+  fn swap_unknown_tracks() {
+    smol::block_on(async {
+      let track_list = track_list(&TITLES, Some(SHUFFLED), 2).await;
+      let generation = track_list.generation();
+
+      assert!(matches!(
+        track_list
+          .swap(TrackRef::Position(0), TrackRef::Position(5))
+          .await,
+        Err(PlayerError::TrackIndexOutOfRange { index: 5, len: 5 })
+      ));
+      assert!(matches!(
+        track_list
+          .swap(TrackRef::Id(TrackId(99)), TrackRef::Position(0))
+          .await,
+        Err(PlayerError::UnknownTrackId(TrackId(99)))
+      ));
+      assert_eq!(track_list.generation(), generation);
+      assert_eq!(play_order(&track_list).await, ["e", "c", "a", "d", "b"]);
+    });
+  }
+
+  #[test]
+  fn reverse_tracks() {
+    // (play order, current index) => (play order, current index)
+    let cases = [
+      (None, 1, ["e", "d", "c", "b", "a"], 3),
+      (None, 2, ["e", "d", "c", "b", "a"], 2),
+      // Stays past the end
+      (None, 5, ["e", "d", "c", "b", "a"], 5),
+      (Some(SHUFFLED), 2, ["b", "d", "a", "c", "e"], 2),
+      (Some(SHUFFLED), 0, ["b", "d", "a", "c", "e"], 4),
+    ];
+
+    for (order, current_index, expected, expected_index) in cases {
+      smol::block_on(async {
+        let track_list = track_list(&TITLES, order, current_index).await;
+        let change = track_list.reverse().await;
+
+        let context = format!("{order:?} at {current_index}");
+        assert_eq!(play_order(&track_list).await, expected, "{context}");
+        assert_eq!(track_list.current_index(), expected_index, "{context}");
+        assert_reordered(&track_list, order.is_some(), &change).await;
+
+        // Reversing again restores the play order
+        track_list.reverse().await;
+        let restored: Vec<&str> = expected.into_iter().rev().collect();
+        assert_eq!(play_order(&track_list).await, restored, "{context}");
+        assert_eq!(track_list.current_index(), current_index, "{context}");
+      });
+    }
+  }
+
+  #[test]
+  fn reverse_empty_track_list() {
+    smol::block_on(async {
+      let track_list = track_list(&[], None, 0).await;
+      track_list.reverse().await;
+
+      assert!(play_order(&track_list).await.is_empty());
+      assert_eq!(track_list.current_index(), 0);
+    });
+  }
+
+  /// An update holding one track with `lyrics_len` bytes of lyrics
+  fn insert_update(lyrics_len: usize) -> TrackListUpdate {
+    TrackListUpdate::Insert {
+      index: 0,
+      tracks: vec![Track {
+        file_path: PathBuf::from("/music/a.flac"),
+        total_duration: None,
+        metadata: TrackMetadata {
+          lyrics: Some("a".repeat(lyrics_len)),
+          ..Default::default()
+        },
+      }],
+      new_shuffle_indicies: Vec::new(),
+    }
+  }
+
+  #[test]
+  fn shuffling_keeps_the_current_track() {
+    for current_index in 0..TITLES.len() {
+      smol::block_on(async {
+        let track_list = track_list(&TITLES, None, current_index).await;
+        let current = current_title(&track_list).await;
+
+        track_list.set_shuffle(true).await.unwrap();
+        assert_eq!(current_title(&track_list).await, current);
+        track_list.set_shuffle(false).await.unwrap();
+        assert_eq!(current_title(&track_list).await, current);
+        assert_eq!(track_list.current_index(), current_index);
+      });
+    }
+  }
+
+  #[test]
+  fn concurrent_inserts_keep_the_current_track() {
+    const THREADS: usize = 8;
+    const INSERTS: usize = 25;
+
+    let track_list = Arc::new(smol::block_on(track_list(&TITLES, None, 2)));
+
+    let threads: Vec<_> = (0..THREADS)
+      .map(|_| {
+        let track_list = track_list.clone();
+        std::thread::spawn(move || {
+          smol::block_on(async {
+            for _ in 0..INSERTS {
+              track_list
+                .insert_tracks(
+                  InsertPosition::Start,
+                  InsertShufflePolicy::Scatter,
+                  &tracks(&["new"]),
+                )
+                .await
+                .unwrap();
+            }
+          })
+        })
+      })
+      .collect();
+    for thread in threads {
+      thread.join().unwrap();
+    }
+
+    smol::block_on(async {
+      assert_eq!(current_title(&track_list).await.as_deref(), Some("c"));
+      assert_eq!(track_list.current_index(), 2 + THREADS * INSERTS);
+      assert_eq!(
+        track_list.current_index(),
+        track_list.inner.lock().await.current_index
+      );
+    });
+  }
+
+  #[test]
+  fn time_until_tracks() {
+    let secs = Duration::from_secs;
+    // (index, loop mode) => time until it starts, playing 10 seconds into "b"
+    let cases = [
+      (1, LoopMode::None, Some(secs(0))),
+      (2, LoopMode::None, Some(secs(50))),
+      (4, LoopMode::Playlist, Some(secs(170))),
+      (0, LoopMode::None, None),
+      (0, LoopMode::Playlist, Some(secs(230))),
+      (2, LoopMode::Track, None),
+      (5, LoopMode::Playlist, None),
+    ];
+
+    smol::block_on(async {
+      let track_list = track_list(&TITLES, None, 1).await;
+
+      for (index, loop_mode, expected) in cases {
+        assert_eq!(
+          track_list.time_until(index, secs(10), loop_mode).await,
+          expected,
+          "{index} {loop_mode:?}"
+        );
+      }
+    });
+  }
+
+  #[test]
+  fn empty_track_list_has_nothing_to_play() {
+    smol::block_on(async {
+      let track_list = TrackList::new();
+
+      assert!(!track_list.retreat(1).await);
+      assert!(!track_list.advance(1).await);
+      assert!(track_list.get_tracks_to_queue().await.is_none());
+      assert!(track_list.current_track().await.is_none());
+
+      track_list.set_shuffle(true).await.unwrap();
+      track_list.set_shuffle(false).await.unwrap();
+      assert_eq!(track_list.current_index(), 0);
+    });
+  }
+
+  #[test]
+  fn current_index_past_the_end_is_clamped() {
+    smol::block_on(async {
+      let track_list = track_list(&TITLES, None, 0).await;
+      track_list.clear().await.unwrap();
+      track_list
+        .insert_tracks(
+          InsertPosition::End,
+          InsertShufflePolicy::Scatter,
+          &tracks(&["a", "b"]),
+        )
+        .await
+        .unwrap();
+      assert_eq!(current_title(&track_list).await.as_deref(), Some("a"));
+
+      // Shuffling past the end keeps the last track current
+      assert!(!track_list.advance(5).await);
+      assert_eq!(track_list.current_index(), 2);
+      track_list.set_shuffle(true).await.unwrap();
+      assert_eq!(current_title(&track_list).await.as_deref(), Some("b"));
+    });
+  }
+
+  #[test]
+  fn advance_and_retreat_by_count() {
+    smol::block_on(async {
+      let track_list = track_list(&TITLES, None, 0).await;
+
+      assert!(track_list.advance(3).await);
+      assert_eq!(current_title(&track_list).await.as_deref(), Some("d"));
+
+      assert!(track_list.retreat(2).await);
+      assert_eq!(current_title(&track_list).await.as_deref(), Some("b"));
+
+      // Going back past the first track doesn't move
+      assert!(!track_list.retreat(2).await);
+      assert_eq!(track_list.current_index(), 1);
+
+      // Going past the end stops there, once
+      assert!(!track_list.advance(10).await);
+      assert_eq!(track_list.current_index(), 5);
+      assert!(track_list.retreat(5).await);
+      assert_eq!(current_title(&track_list).await.as_deref(), Some("a"));
+    });
+  }
+
+  #[test]
+  fn retreat_follows_played_order_under_shuffle() {
+    smol::block_on(async {
+      // Played as e, c, a, d, b
+      let track_list = track_list(&TITLES, Some(SHUFFLED), 0).await;
+      track_list.advance(1).await;
+      track_list.go_to(4).await.unwrap();
+      assert_eq!(current_title(&track_list).await.as_deref(), Some("b"));
+
+      assert!(track_list.retreat(1).await);
+      assert_eq!(current_title(&track_list).await.as_deref(), Some("c"));
+      assert!(track_list.retreat(1).await);
+      assert_eq!(current_title(&track_list).await.as_deref(), Some("e"));
+
+      // Nothing is left to go back to, and e is first in play order
+      assert!(!track_list.retreat(1).await);
+    });
+  }
+
+  #[test]
+  fn retreat_finds_played_tracks_that_moved() {
+    smol::block_on(async {
+      let track_list = track_list(&TITLES, Some(SHUFFLED), 0).await;
+      track_list.go_to(3).await.unwrap();
+      track_list.go_to(1).await.unwrap();
+
+      // Moves d to the front, so "e" and "c" are after it
+      track_list.move_track(3, 0).await.unwrap();
+      assert!(track_list.retreat(1).await);
+      assert_eq!(current_title(&track_list).await.as_deref(), Some("d"));
+      assert!(track_list.retreat(1).await);
+      assert_eq!(current_title(&track_list).await.as_deref(), Some("e"));
+    });
+  }
+
+  #[test]
+  fn retreat_skips_removed_played_tracks() {
+    smol::block_on(async {
+      let track_list = track_list(&TITLES, Some(SHUFFLED), 0).await;
+      track_list.go_to(1).await.unwrap();
+      track_list.go_to(3).await.unwrap();
+      track_list.go_to(4).await.unwrap();
+
+      // Removes "d", which played before "b"
+      track_list.remove_tracks(&[3]).await;
+      assert!(track_list.retreat(2).await);
+      assert_eq!(current_title(&track_list).await.as_deref(), Some("e"));
+    });
+  }
+
+  #[test]
+  fn retreat_falls_back_to_play_order() {
+    smol::block_on(async {
+      let track_list = track_list(&TITLES, Some(SHUFFLED), 3).await;
+      track_list.advance(1).await;
+
+      // Only "d" was played, so this goes back two tracks in play order from "b"
+      assert!(track_list.retreat(2).await);
+      assert_eq!(current_title(&track_list).await.as_deref(), Some("a"));
+    });
+  }
+
+  #[test]
+  fn played_history_is_bounded() {
+    smol::block_on(async {
+      let track_list = track_list(&TITLES, Some(SHUFFLED), 0).await;
+      for i in 0..TrackListInner::PLAYED_LEN + 20 {
+        track_list.go_to(i % TITLES.len()).await.unwrap();
+      }
+
+      assert_eq!(
+        track_list.inner.lock().await.played.len(),
+        TrackListInner::PLAYED_LEN
+      );
+    });
+  }
+
+  fn paths(titles: &[&str]) -> Vec<PathBuf> {
+    titles
+      .iter()
+      .map(|title| PathBuf::from(format!("/music/{title}.flac")))
+      .collect()
+  }
+
+  #[test]
+  fn clearing_keeps_removed_paths() {
+    smol::block_on(async {
+      let track_list = track_list(&TITLES, Some(SHUFFLED), 2).await;
+      assert!(track_list.last_removed().await.is_empty());
+
+      // In track list order, not play order
+      track_list.clear().await.unwrap();
+      assert_eq!(track_list.last_removed().await, paths(&TITLES));
+
+      // Clearing again doesn't forget them
+      track_list.clear().await.unwrap();
+      assert_eq!(track_list.last_removed().await, paths(&TITLES));
+    });
+  }
+
+  #[test]
+  fn replacing_keeps_removed_paths() {
+    smol::block_on(async {
+      let track_list = track_list(&["a", "b"], None, 0).await;
+      track_list
+        .insert_tracks(
+          InsertPosition::Replace,
+          InsertShufflePolicy::Scatter,
+          &tracks(&["c"]),
+        )
+        .await
+        .unwrap();
+
+      assert_eq!(track_list.last_removed().await, paths(&["a", "b"]));
+      assert_eq!(play_order(&track_list).await, ["c"]);
+    });
+  }
+
+  #[test]
+  fn remove_tracks_around_current() {
+    // (play order, current index, positions) => (play order, current index, current removed)
+    let cases: [(_, _, &[usize], &[&str], _, _); 8] = [
+      (None, 2, &[0], &["b", "c", "d", "e"], 1, false),
+      (None, 2, &[2], &["a", "b", "d", "e"], 2, true),
+      (None, 2, &[3, 1], &["a", "c", "e"], 1, false),
+      (None, 2, &[2, 2, 3], &["a", "b", "e"], 2, true),
+      // Removing the last track while it is current stops past the end
+      (None, 4, &[4], &["a", "b", "c", "d"], 4, true),
+      (Some(SHUFFLED), 2, &[0], &["c", "a", "d", "b"], 1, false),
+      (Some(SHUFFLED), 2, &[2], &["e", "c", "d", "b"], 2, true),
+      (Some(SHUFFLED), 2, &[1, 3], &["e", "a", "b"], 1, false),
+    ];
+
+    for (order, current_index, positions, expected, expected_index, current_removed) in cases {
+      smol::block_on(async {
+        let track_list = track_list(&TITLES, order, current_index).await;
+        let removed = track_list.remove_tracks(positions).await;
+
+        let context = format!("{order:?} removing {positions:?}");
+        assert_eq!(play_order(&track_list).await, expected, "{context}");
+        assert_eq!(track_list.current_index(), expected_index, "{context}");
+        assert_eq!(
+          removed.removed_current.is_some(),
+          current_removed,
+          "{context}"
+        );
+        assert!(removed.out_of_range.is_empty(), "{context}");
+
+        // The track list keeps its order, only the removed tracks are gone
+        let mut remaining = expected.to_vec();
+        remaining.sort_unstable();
+        assert_eq!(list_order(&track_list).await, remaining, "{context}");
+
+        let change = removed.change.unwrap();
+        assert!(matches!(
+          change.updates.as_slice(),
+          [TrackListUpdate::Remove { .. }]
+        ));
+      });
+    }
+  }
+
+  #[test]
+  fn remove_tracks_reports_out_of_range_positions() {
+    smol::block_on(async {
+      let track_list = track_list(&TITLES, None, 0).await;
+      let removed = track_list.remove_tracks(&[7, 1, 5, 1]).await;
+
+      assert_eq!(removed.out_of_range, [7, 5]);
+      assert_eq!(removed.track_ids.len(), 1);
+      assert_eq!(play_order(&track_list).await, ["a", "c", "d", "e"]);
+      assert_eq!(track_list.last_removed().await, paths(&["b"]));
+    });
+  }
+
+  #[test]
+  fn remove_only_out_of_range_positions() {
+    smol::block_on(async {
+      let track_list = track_list(&TITLES, None, 0).await;
+      let generation = track_list.generation();
+      let removed = track_list.remove_tracks(&[5, 6]).await;
+
+      assert_eq!(removed.out_of_range, [5, 6]);
+      assert!(removed.track_ids.is_empty());
+      assert!(removed.change.is_none());
+      assert_eq!(track_list.generation(), generation);
+      assert_eq!(play_order(&track_list).await, TITLES);
+    });
+  }
+
+  #[test]
+  fn history_is_bounded_by_length() {
+    let mut inner = TrackListInner::new();
+    for generation in 1..=40 {
+      inner.record(generation, TrackListUpdate::Clear);
+    }
+
+    assert_eq!(inner.history.len(), TrackListInner::HISTORY_LEN);
+    assert_eq!(inner.history_start, 8);
+  }
+
+  #[test]
+  fn history_is_bounded_by_size() {
+    let mut inner = TrackListInner::new();
+    for generation in 1..=3 {
+      inner.record(generation, insert_update(6 << 20));
+    }
+
+    // Only two of the large updates fit
+    assert_eq!(inner.history.len(), 2);
+    assert_eq!(inner.history_start, 1);
+    let expected_bytes: usize = inner
+      .history
+      .iter()
+      .map(|(_, update)| update_size(update))
+      .sum();
+    assert_eq!(inner.history_bytes, expected_bytes);
+
+    // An update larger than the bound is not kept, and nothing before it can be diffed
+    inner.record(4, insert_update(17 << 20));
+    assert!(inner.history.is_empty());
+    assert_eq!(inner.history_bytes, 0);
+    assert_eq!(inner.history_start, 4);
+  }
+
+  #[test]
+  fn last_removed_is_bounded_by_size() {
+    let mut inner = TrackListInner::new();
+    let paths = (0..6).map(|i| PathBuf::from(format!("/{i}/{}", "a".repeat(1 << 20))));
+    inner.set_last_removed(paths);
+
+    // The first paths that fit are kept, in order
+    assert_eq!(inner.last_removed.len(), 3);
+    assert!(inner.last_removed[0].starts_with("/0"));
+    assert!(inner.last_removed[2].starts_with("/2"));
+  }
+
+  /// Matches every track but `titles`
+  fn filter_out(titles: &[&str]) -> FilterExpr {
+    use hsm_ipc::{FilterCondition, FilterField, FilterOp};
+
+    FilterExpr {
+      all: titles
+        .iter()
+        .map(|title| FilterCondition {
+          field: FilterField::Title,
+          op: FilterOp::IsNot,
+          value: (*title).into(),
+        })
+        .collect(),
+    }
+  }
+
+  #[test]
+  fn filter_skips_non_matching_tracks() {
+    smol::block_on(async {
+      // Played as e, c, a, d, b, but only c, a and d match
+      let track_list = track_list(&TITLES, Some(SHUFFLED), 0).await;
+      let matching = track_list.set_filter(Some(filter_out(&["b", "e"]))).await;
+      assert!(matches!(matching, Ok(3)));
+
+      assert!(track_list.advance(2).await);
+      assert_eq!(current_title(&track_list).await.as_deref(), Some("a"));
+      assert!(track_list.advance(1).await);
+      assert_eq!(current_title(&track_list).await.as_deref(), Some("d"));
+
+      // Only b is left, which doesn't match
+      assert!(!track_list.advance(1).await);
+      assert_eq!(track_list.current_index(), 5);
+
+      // Wrapping around the shuffled order skips the tracks at either end
+      track_list.wrap_current(false).await;
+      assert_eq!(current_title(&track_list).await.as_deref(), Some("c"));
+      track_list.wrap_current(true).await;
+      assert_eq!(current_title(&track_list).await.as_deref(), Some("d"));
+    });
+  }
+
+  #[test]
+  fn filter_can_start_on_a_non_matching_track() {
+    smol::block_on(async {
+      let track_list = track_list(&TITLES, None, 0).await;
+      track_list
+        .set_filter(Some(filter_out(&["a", "b", "d"])))
+        .await
+        .unwrap();
+
+      // The current track keeps playing, and the next one is the next match
+      assert_eq!(current_title(&track_list).await.as_deref(), Some("a"));
+      assert!(track_list.advance(1).await);
+      assert_eq!(current_title(&track_list).await.as_deref(), Some("c"));
+
+      // Going to a filtered out track is still allowed
+      track_list.go_to(3).await.unwrap();
+      assert!(track_list.advance(1).await);
+      assert_eq!(current_title(&track_list).await.as_deref(), Some("e"));
+
+      track_list.wrap_current(false).await;
+      assert_eq!(current_title(&track_list).await.as_deref(), Some("c"));
+
+      // Going back skips d, and nothing before c matches
+      track_list.go_to(4).await.unwrap();
+      assert!(track_list.retreat(1).await);
+      assert_eq!(current_title(&track_list).await.as_deref(), Some("c"));
+      assert!(!track_list.retreat(1).await);
+    });
+  }
+
+  #[test]
+  fn filter_matching_nothing_is_rejected() {
+    smol::block_on(async {
+      let track_list = track_list(&TITLES, None, 0).await;
+
+      let filter = filter_out(&TITLES);
+      assert!(matches!(
+        track_list.set_filter(Some(filter)).await,
+        Err(PlayerError::FilterMatchesNothing)
+      ));
+      assert_eq!(track_list.filter().await, None);
+
+      // Removing the only matching track clears the filter
+      track_list
+        .set_filter(Some(filter_out(&["a", "b", "c", "d"])))
+        .await
+        .unwrap();
+      assert!(!track_list.clear_unmatched_filter().await);
+      track_list.remove_tracks(&[4]).await;
+      assert!(track_list.clear_unmatched_filter().await);
+      assert_eq!(track_list.filter().await, None);
+    });
+  }
+
+  #[test]
+  fn random_edits_keep_the_client_mirror_in_sync() {
+    use rand::Rng;
+
+    let mut rng = StdRng::seed_from_u64(1717);
+
+    smol::block_on(async {
+      let track_list = track_list(&TITLES, Some(SHUFFLED), 0).await;
+      let mut mirror =
+        hsm_client::track_list::TrackList::from_snapshot(track_list.get_snapshot().await);
+      let mut next_title = 0;
+
+      for round in 0..300 {
+        let len = track_list.len();
+        match rng.random_range(0..8) {
+          0 | 1 => {
+            let count = rng.random_range(1..4);
+            let tracks: Vec<_> = (next_title..next_title + count)
+              .map(|title| track(&title.to_string()))
+              .collect();
+            next_title += count;
+            let position = match rng.random_range(0..5) {
+              0 => InsertPosition::Absolute(rng.random_range(0..=len + 1)),
+              1 => InsertPosition::Next,
+              2 => InsertPosition::Start,
+              3 => InsertPosition::End,
+              _ => InsertPosition::Replace,
+            };
+            let policy = match rng.random_range(0..3) {
+              0 => InsertShufflePolicy::Scatter,
+              1 => InsertShufflePolicy::KeepTogetherNext,
+              _ => InsertShufflePolicy::KeepTogetherInPlace,
+            };
+            track_list
+              .insert_tracks(position, policy, &tracks)
+              .await
+              .unwrap();
+          }
+          2 if len > 0 => {
+            let positions: Vec<usize> = (0..rng.random_range(1..4))
+              .map(|_| rng.random_range(0..len + 1))
+              .collect();
+            track_list.remove_tracks(&positions).await;
+          }
+          3 if len > 0 => {
+            let a = TrackRef::Position(rng.random_range(0..len));
+            let b = TrackRef::Position(rng.random_range(0..len));
+            track_list.swap(a, b).await.unwrap();
+          }
+          4 if len > 0 => {
+            let (from, to) = (rng.random_range(0..len), rng.random_range(0..len));
+            track_list.move_track(from, to).await.unwrap();
+          }
+          5 => {
+            track_list.reverse().await;
+          }
+          6 => {
+            track_list.set_shuffle(rng.random_bool(0.5)).await.unwrap();
+          }
+          _ if rng.random_bool(0.1) => {
+            track_list.clear().await.unwrap();
+          }
+          _ => {}
+        }
+
+        // Clients don't diff after every change, so several updates are often applied at once
+        if rng.random_bool(0.5) {
+          let diff = track_list.diff_since(mirror.generation()).await;
+          assert!(mirror.apply_diff(diff).is_ok(), "round {round}");
+
+          let expected =
+            hsm_client::track_list::TrackList::from_snapshot(track_list.get_snapshot().await);
+          assert_eq!(
+            serde_json::to_value(&mirror).unwrap(),
+            serde_json::to_value(&expected).unwrap(),
+            "round {round}"
+          );
+        }
+      }
+    });
+  }
+
+  /// Prints how long large track lists take to build, shuffle and snapshot
+  ///
+  /// Run with `cargo test --release -p hsm-server -- --ignored --nocapture track_list_timings`
+  #[test]
+  #[ignore = "timing, not a correctness check"]
+  fn track_list_timings() {
+    use std::time::Instant;
+
+    for len in [1_000, 10_000, 50_000] {
+      let titles: Vec<String> = (0..len).map(|index| index.to_string()).collect();
+      let batch = tracks(&["x"; 100]);
+      let tracks: Vec<_> = titles.iter().map(|title| track(title)).collect();
+
+      smol::block_on(async {
+        let track_list = TrackList::new();
+        track_list.set_shuffle(true).await.unwrap();
+
+        let start = Instant::now();
+        track_list
+          .insert_tracks(InsertPosition::End, InsertShufflePolicy::Scatter, &tracks)
+          .await
+          .unwrap();
+        let insert = start.elapsed();
+
+        let start = Instant::now();
+        track_list
+          .insert_tracks(InsertPosition::Next, InsertShufflePolicy::Scatter, &batch)
+          .await
+          .unwrap();
+        let insert_batch = start.elapsed();
+
+        let start = Instant::now();
+        track_list.set_shuffle(true).await.unwrap();
+        let shuffle = start.elapsed();
+
+        let start = Instant::now();
+        let snapshot = track_list.get_snapshot().await;
+        let snapshot_time = start.elapsed();
+        assert_eq!(snapshot.track_list.len(), len + batch.len());
+
+        println!(
+          "{len} tracks: insert {insert:?}, insert 100 more {insert_batch:?}, shuffle {shuffle:?}, snapshot {snapshot_time:?}"
+        );
+      });
     }
   }
 }
@@ -0,0 +1,148 @@
+use hsm_ipc::{TrackMetadata, TransitionMode};
+
+use crate::config::GaplessPolicy;
+
+/// How playback moves from the track with `current` metadata to the track with `next` metadata
+pub fn transition_mode(
+  policy: GaplessPolicy,
+  current: &TrackMetadata,
+  next: &TrackMetadata,
+) -> TransitionMode {
+  let gapless = match policy {
+    GaplessPolicy::Always => true,
+    GaplessPolicy::Never => false,
+    GaplessPolicy::Auto => continues_album(current, next),
+  };
+
+  if gapless {
+    TransitionMode::Gapless
+  } else {
+    TransitionMode::Crossfade
+  }
+}
+
+/// If `next` is the track after `current` on the same album, by the same album artist
+///
+/// Tracks without an album tag are never grouped, even if neither has one
+fn continues_album(current: &TrackMetadata, next: &TrackMetadata) -> bool {
+  let same_album = current.album.is_some()
+    && current.album == next.album
+    && current.album_artist == next.album_artist;
+
+  same_album
+    && current
+      .track_number
+      .zip(next.track_number)
+      .is_some_and(|(current_number, next_number)| {
+        current_number.checked_add(1) == Some(next_number)
+      })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn metadata(
+    album: Option<&str>,
+    album_artist: Option<&str>,
+    track_number: Option<usize>,
+  ) -> TrackMetadata {
+    TrackMetadata {
+      album: album.map(Into::into),
+      album_artist: album_artist.map(Into::into),
+      track_number,
+      ..Default::default()
+    }
+  }
+
+  #[test]
+  fn groups_consecutive_tracks_of_an_album() {
+    let current = metadata(Some("Live"), Some("Band"), Some(3));
+    let cases = [
+      (metadata(Some("Live"), Some("Band"), Some(4)), true),
+      // Not the next track
+      (metadata(Some("Live"), Some("Band"), Some(5)), false),
+      (metadata(Some("Live"), Some("Band"), Some(3)), false),
+      (metadata(Some("Live"), Some("Band"), Some(2)), false),
+      (metadata(Some("Live"), Some("Band"), None), false),
+      // Another album, or the same title by another artist
+      (metadata(Some("Studio"), Some("Band"), Some(4)), false),
+      (metadata(Some("Live"), Some("Other Band"), Some(4)), false),
+      (metadata(Some("Live"), None, Some(4)), false),
+      (metadata(None, Some("Band"), Some(4)), false),
+    ];
+
+    for (next, expected) in cases {
+      assert_eq!(continues_album(&current, &next), expected, "{next:?}");
+    }
+  }
+
+  #[test]
+  fn needs_album_tags_and_track_numbers() {
+    let cases = [
+      // Neither has an album artist tag, which many albums leave out
+      (
+        metadata(Some("Live"), None, Some(1)),
+        metadata(Some("Live"), None, Some(2)),
+        true,
+      ),
+      (
+        metadata(None, None, Some(1)),
+        metadata(None, None, Some(2)),
+        false,
+      ),
+      (
+        metadata(None, Some("Band"), Some(1)),
+        metadata(None, Some("Band"), Some(2)),
+        false,
+      ),
+      (
+        metadata(Some("Live"), Some("Band"), None),
+        metadata(Some("Live"), Some("Band"), Some(1)),
+        false,
+      ),
+      (
+        metadata(Some("Live"), Some("Band"), None),
+        metadata(Some("Live"), Some("Band"), None),
+        false,
+      ),
+      (
+        metadata(Some("Live"), Some("Band"), Some(usize::MAX)),
+        metadata(Some("Live"), Some("Band"), Some(0)),
+        false,
+      ),
+    ];
+
+    for (current, next, expected) in cases {
+      assert_eq!(
+        continues_album(&current, &next),
+        expected,
+        "{current:?} then {next:?}"
+      );
+    }
+  }
+
+  #[test]
+  fn policy_overrides_album_grouping() {
+    let first = metadata(Some("Live"), Some("Band"), Some(1));
+    let second = metadata(Some("Live"), Some("Band"), Some(2));
+    let other = metadata(Some("Studio"), Some("Band"), Some(7));
+
+    let cases = [
+      (GaplessPolicy::Auto, &second, TransitionMode::Gapless),
+      (GaplessPolicy::Auto, &other, TransitionMode::Crossfade),
+      (GaplessPolicy::Always, &second, TransitionMode::Gapless),
+      (GaplessPolicy::Always, &other, TransitionMode::Gapless),
+      (GaplessPolicy::Never, &second, TransitionMode::Crossfade),
+      (GaplessPolicy::Never, &other, TransitionMode::Crossfade),
+    ];
+
+    for (policy, next, expected) in cases {
+      assert_eq!(
+        transition_mode(policy, &first, next),
+        expected,
+        "{policy:?} {next:?}"
+      );
+    }
+  }
+}
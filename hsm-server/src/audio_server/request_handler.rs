@@ -1,10 +1,21 @@
-use std::{path::PathBuf, time::Duration};
+use std::{
+  num::NonZeroUsize,
+  path::{Path, PathBuf},
+  time::Duration,
+};
 
 use hsm_ipc::{
-  LoopMode, PlaybackState, Track, TrackListSnapshot, requests, server::RequestHandler,
+  CacheStats, ConnectionInfo, CurrentEntry, EndBehavior, Event, FilterExpr, InsertShufflePolicy,
+  InspectedTrack, LoadTrackErrorKind, LoopMode, NormalizationMode, OutputInfo, PlaybackState,
+  PlayerStatus, QueueSummary, SeekPosition, ServerStats, SessionStats, StopReason, TestToneResult,
+  Track, TrackId, TrackListDiff, TrackListSnapshot, TransitionMode, requests,
+  server::RequestHandler,
 };
 
-use super::{AudioServer, AudioServerError};
+use super::{
+  AudioServer, AudioServerError,
+  track::{self, ScanOptions},
+};
 
 impl RequestHandler for AudioServer {
   type Error = AudioServerError;
@@ -16,6 +27,83 @@ impl RequestHandler for AudioServer {
     Ok(hsm_ipc::version())
   }
 
+  async fn handle_query_server_stats(
+    &self,
+    _request: requests::QueryServerStats,
+  ) -> Result<ServerStats, Self::Error> {
+    Ok(self.stats())
+  }
+
+  async fn handle_query_cache_stats(
+    &self,
+    _request: requests::QueryCacheStats,
+  ) -> Result<CacheStats, Self::Error> {
+    Ok(self.track_cache.stats())
+  }
+
+  async fn handle_query_next_transition(
+    &self,
+    _request: requests::QueryNextTransition,
+  ) -> Result<Option<TransitionMode>, Self::Error> {
+    Ok(self.player.next_transition().await)
+  }
+
+  async fn handle_query_session_stats(
+    &self,
+    _request: requests::QuerySessionStats,
+  ) -> Result<SessionStats, Self::Error> {
+    Ok(self.player.session_stats())
+  }
+
+  async fn handle_reset_session_stats(
+    &self,
+    _request: requests::ResetSessionStats,
+  ) -> Result<(), Self::Error> {
+    self.player.reset_session_stats();
+    Ok(())
+  }
+
+  async fn handle_identify(&self, _request: requests::Identify) -> Result<(), Self::Error> {
+    // The name is stored on the connection by `hsm_ipc::server::handle_request`
+    Ok(())
+  }
+
+  async fn handle_query_connections(
+    &self,
+    _request: requests::QueryConnections,
+  ) -> Result<Vec<ConnectionInfo>, Self::Error> {
+    Ok(self.connections.list().await)
+  }
+
+  /// Subscriptions are handled by the ipc plugin, so this is only reached by plugins, which get events through `on_event`
+  async fn handle_subscribe(&self, _request: requests::Subscribe) -> Result<(), Self::Error> {
+    Err(AudioServerError::SubscribeWithoutConnection)
+  }
+
+  async fn handle_shutdown(&self, _request: requests::Shutdown) -> Result<(), Self::Error> {
+    println!("Shutdown requested");
+    self.request_shutdown();
+    Ok(())
+  }
+
+  async fn handle_query_status(
+    &self,
+    _request: requests::QueryStatus,
+  ) -> Result<PlayerStatus, Self::Error> {
+    Ok(PlayerStatus {
+      playback_state: self.player.playback_state(),
+      track: self.player.current_track().await,
+      position: self.player.position().await,
+      volume: self.handle_query_volume(requests::QueryVolume).await?,
+      loop_mode: self.player.loop_mode(),
+      shuffle: self.player.shuffle().await,
+      stop_after_current: self.player.stop_after_current(),
+      end_behavior: self.player.end_behavior(),
+      stop_reason: self.player.stop_reason().await,
+      track_detached: self.player.track_detached().await,
+    })
+  }
+
   async fn handle_query_playback_state(
     &self,
     _request: requests::QueryPlaybackState,
@@ -23,6 +111,13 @@ impl RequestHandler for AudioServer {
     Ok(self.player.playback_state())
   }
 
+  async fn handle_query_stop_reason(
+    &self,
+    _request: requests::QueryStopReason,
+  ) -> Result<Option<StopReason>, Self::Error> {
+    Ok(self.player.stop_reason().await)
+  }
+
   async fn handle_play(&self, _request: requests::Play) -> Result<(), Self::Error> {
     Ok(self.player.play().await?)
   }
@@ -35,7 +130,7 @@ impl RequestHandler for AudioServer {
     &self,
     _request: requests::StopPlayback,
   ) -> Result<(), Self::Error> {
-    Ok(self.player.stop().await?)
+    Ok(self.player.stop(StopReason::UserRequested).await?)
   }
 
   async fn handle_toggle_playback(
@@ -54,6 +149,13 @@ impl RequestHandler for AudioServer {
     Ok(track)
   }
 
+  async fn handle_query_current_entry(
+    &self,
+    _request: requests::QueryCurrentEntry,
+  ) -> Result<CurrentEntry, Self::Error> {
+    Ok(self.player.current_entry().await)
+  }
+
   async fn handle_query_current_track_index(
     &self,
     _request: requests::QueryCurrentTrackIndex,
@@ -61,15 +163,118 @@ impl RequestHandler for AudioServer {
     Ok(self.player.current_track_index())
   }
 
-  async fn handle_next_track(&self, _request: requests::NextTrack) -> Result<(), Self::Error> {
-    Ok(self.player.go_to_next_track().await?)
+  async fn handle_query_track_eta(
+    &self,
+    requests::QueryTrackEta(index): requests::QueryTrackEta,
+  ) -> Result<Option<Duration>, Self::Error> {
+    Ok(self.player.time_until_track(index).await)
+  }
+
+  async fn handle_query_current_track_id(
+    &self,
+    _request: requests::QueryCurrentTrackId,
+  ) -> Result<Option<TrackId>, Self::Error> {
+    Ok(self.player.current_track_id().await)
+  }
+
+  async fn handle_play_oneshot(
+    &self,
+    requests::PlayOneshot { path }: requests::PlayOneshot,
+  ) -> Result<(), Self::Error> {
+    let track = self
+      .load_single_track(path.clone())
+      .await?
+      .ok_or(AudioServerError::NotASingleTrack(path))?;
+
+    Ok(self.player.play_oneshot(track).await?)
+  }
+
+  async fn handle_inspect_track(
+    &self,
+    requests::InspectTrack(path): requests::InspectTrack,
+  ) -> Result<InspectedTrack, Self::Error> {
+    let track = self
+      .load_single_track(path.clone())
+      .await?
+      .ok_or(AudioServerError::NotASingleTrack(path))?;
+
+    Ok(track.inspect())
+  }
+
+  async fn handle_query_lyrics(
+    &self,
+    requests::QueryLyrics(path): requests::QueryLyrics,
+  ) -> Result<Option<String>, Self::Error> {
+    let track = match path {
+      Some(path) => self
+        .load_single_track(path)
+        .await?
+        .map(|track| track.clone_track()),
+      None => self.player.current_track().await,
+    };
+
+    let Some(track) = track else {
+      return Ok(None);
+    };
+
+    match track.metadata.lyrics {
+      Some(lyrics) => Ok(Some(lyrics)),
+      None => Ok(track::read_sidecar_lyrics(&track.file_path).await),
+    }
+  }
+
+  async fn handle_query_synced_lyrics(
+    &self,
+    _request: requests::QuerySyncedLyrics,
+  ) -> Result<Option<Vec<(Duration, String)>>, Self::Error> {
+    let Some(track) = self.player.current_track().await else {
+      return Ok(None);
+    };
+
+    Ok(track::read_synced_lyrics(&track).await)
+  }
+
+  async fn handle_next_track(
+    &self,
+    requests::NextTrack(count): requests::NextTrack,
+  ) -> Result<(), Self::Error> {
+    let count = count.map_or(1, NonZeroUsize::get);
+    let applied = self.coalescer.skip(count).await;
+    self.reply_after(applied).await;
+    Ok(())
   }
 
   async fn handle_previous_track(
     &self,
-    requests::PreviousTrack { soft }: requests::PreviousTrack,
+    requests::PreviousTrack { soft, count }: requests::PreviousTrack,
+  ) -> Result<(), Self::Error> {
+    let count = count.map_or(1, NonZeroUsize::get);
+    Ok(self.player.go_to_previous_track(soft, count).await?)
+  }
+
+  async fn handle_go_to_track(
+    &self,
+    requests::GoToTrack {
+      index,
+      expected_generation,
+    }: requests::GoToTrack,
   ) -> Result<(), Self::Error> {
-    Ok(self.player.go_to_previous_track(soft).await?)
+    self.player.check_generation(expected_generation)?;
+    Ok(self.player.go_to_track(index).await?)
+  }
+
+  async fn handle_query_stop_after_current(
+    &self,
+    _request: requests::QueryStopAfterCurrent,
+  ) -> Result<bool, Self::Error> {
+    Ok(self.player.stop_after_current())
+  }
+
+  async fn handle_set_stop_after_current(
+    &self,
+    requests::SetStopAfterCurrent(stop_after_current): requests::SetStopAfterCurrent,
+  ) -> Result<(), Self::Error> {
+    Ok(self.player.set_stop_after_current(stop_after_current)?)
   }
 
   async fn handle_query_loop_mode(
@@ -86,6 +291,13 @@ impl RequestHandler for AudioServer {
     Ok(self.player.set_loop_mode(loop_mode).await?)
   }
 
+  async fn handle_query_end_behavior(
+    &self,
+    _request: requests::QueryEndBehavior,
+  ) -> Result<EndBehavior, Self::Error> {
+    Ok(self.player.end_behavior())
+  }
+
   async fn handle_query_shuffle(
     &self,
     _request: requests::QueryShuffle,
@@ -100,15 +312,69 @@ impl RequestHandler for AudioServer {
     Ok(self.player.set_shuffle(shuffle).await?)
   }
 
+  async fn handle_query_queue_filter(
+    &self,
+    _request: requests::QueryQueueFilter,
+  ) -> Result<Option<FilterExpr>, Self::Error> {
+    Ok(self.player.queue_filter().await)
+  }
+
+  async fn handle_set_queue_filter(
+    &self,
+    requests::SetQueueFilter(filter): requests::SetQueueFilter,
+  ) -> Result<usize, Self::Error> {
+    Ok(self.player.set_queue_filter(filter).await?)
+  }
+
   async fn handle_query_volume(&self, _request: requests::QueryVolume) -> Result<f32, Self::Error> {
-    Ok(self.player.volume().await)
+    match self.coalescer.pending_volume().await {
+      Some(volume) => Ok(volume),
+      None => Ok(self.player.volume().await),
+    }
   }
 
   async fn handle_set_volume(
     &self,
     requests::SetVolume(volume): requests::SetVolume,
   ) -> Result<(), Self::Error> {
-    Ok(self.player.set_volume(volume).await?)
+    let applied = self.coalescer.set_volume(volume).await;
+    self.reply_after(applied).await;
+    Ok(())
+  }
+
+  async fn handle_query_normalization(
+    &self,
+    _request: requests::QueryNormalization,
+  ) -> Result<NormalizationMode, Self::Error> {
+    Ok(self.player.normalization())
+  }
+
+  async fn handle_set_normalization(
+    &self,
+    requests::SetNormalization(mode): requests::SetNormalization,
+  ) -> Result<(), Self::Error> {
+    self.player.set_normalization(mode);
+    Ok(())
+  }
+
+  async fn handle_query_rate(&self, _request: requests::QueryRate) -> Result<f32, Self::Error> {
+    Ok(self.player.rate().await)
+  }
+
+  async fn handle_set_rate(
+    &self,
+    requests::SetRate(rate): requests::SetRate,
+  ) -> Result<(), Self::Error> {
+    Ok(self.player.set_rate(rate).await?)
+  }
+
+  async fn handle_adjust_volume(
+    &self,
+    requests::AdjustVolume(delta): requests::AdjustVolume,
+  ) -> Result<(), Self::Error> {
+    let applied = self.coalescer.adjust_volume(delta, &self.player).await;
+    self.reply_after(applied).await;
+    Ok(())
   }
 
   async fn handle_query_position(
@@ -122,7 +388,52 @@ impl RequestHandler for AudioServer {
     &self,
     requests::Seek(seek_position): requests::Seek,
   ) -> Result<(), Self::Error> {
-    Ok(self.player.seek(seek_position).await?)
+    match seek_position {
+      SeekPosition::To(_) | SeekPosition::ToFraction(_) => {
+        self.coalescer.cancel_seek().await;
+        Ok(self.player.seek(seek_position).await?)
+      }
+      // Only resolved against the track's duration by the source, so they can't be summed with other seeks
+      SeekPosition::ForwardFraction(_) | SeekPosition::BackwardFraction(_) => {
+        Ok(self.player.seek(seek_position).await?)
+      }
+      SeekPosition::Forward(_) | SeekPosition::Backward(_) => {
+        let applied = self.coalescer.seek(seek_position).await;
+        self.reply_after(applied).await;
+        Ok(())
+      }
+    }
+  }
+
+  async fn handle_query_output_info(
+    &self,
+    _request: requests::QueryOutputInfo,
+  ) -> Result<OutputInfo, Self::Error> {
+    Ok(self.output_info().await)
+  }
+
+  async fn handle_play_test_tone(
+    &self,
+    requests::PlayTestTone {
+      frequency,
+      duration,
+    }: requests::PlayTestTone,
+  ) -> Result<TestToneResult, Self::Error> {
+    self.play_test_tone(frequency, duration).await
+  }
+
+  async fn handle_query_bit_perfect(
+    &self,
+    _request: requests::QueryBitPerfect,
+  ) -> Result<bool, Self::Error> {
+    Ok(self.player.bit_perfect())
+  }
+
+  async fn handle_set_bit_perfect(
+    &self,
+    requests::SetBitPerfect(bit_perfect): requests::SetBitPerfect,
+  ) -> Result<(), Self::Error> {
+    self.set_bit_perfect(bit_perfect).await
   }
 
   async fn handle_query_track_list(
@@ -132,16 +443,89 @@ impl RequestHandler for AudioServer {
     Ok(self.player.get_track_list().await)
   }
 
-  async fn handle_clear_tracks(&self, _request: requests::ClearTracks) -> Result<(), Self::Error> {
-    Ok(self.player.clear_tracks().await?)
+  async fn handle_query_queue_summary(
+    &self,
+    _request: requests::QueryQueueSummary,
+  ) -> Result<QueueSummary, Self::Error> {
+    Ok(self.player.get_queue_summary())
+  }
+
+  async fn handle_query_track_list_diff(
+    &self,
+    requests::QueryTrackListDiff { since_generation }: requests::QueryTrackListDiff,
+  ) -> Result<TrackListDiff, Self::Error> {
+    Ok(self.player.get_track_list_diff(since_generation).await)
+  }
+
+  async fn handle_clear_tracks(
+    &self,
+    requests::ClearTracks {
+      expected_generation,
+    }: requests::ClearTracks,
+  ) -> Result<(), Self::Error> {
+    self.player.check_generation(expected_generation)?;
+    self.player.clear_tracks().await?;
+    // A cleared queue should stay cleared after a restart
+    self.forget_saved_state().await;
+    // Cleared queues can be large, so their entries are removed now instead of on the next load
+    self.track_cache.evict_dead();
+
+    Ok(())
+  }
+
+  async fn handle_set_track_gain(
+    &self,
+    requests::SetTrackGain { track_id, gain_db }: requests::SetTrackGain,
+  ) -> Result<(), Self::Error> {
+    Ok(self.player.set_track_gain(track_id, gain_db).await?)
+  }
+
+  async fn handle_query_plugins(
+    &self,
+    _request: requests::QueryPlugins,
+  ) -> Result<Vec<(String, bool)>, Self::Error> {
+    Ok(self.plugins.plugins().await)
+  }
+
+  async fn handle_set_plugin_enabled(
+    &self,
+    requests::SetPluginEnabled { name, enabled }: requests::SetPluginEnabled,
+  ) -> Result<(), Self::Error> {
+    if !self.plugins.set_enabled(&name, enabled).await {
+      return Err(AudioServerError::UnknownPlugin(name));
+    }
+
+    Ok(())
   }
 
   async fn handle_load_tracks(
     &self,
-    requests::LoadTracks(position, paths): requests::LoadTracks,
-  ) -> Result<Vec<(PathBuf, String)>, Self::Error> {
+    requests::LoadTracks {
+      position,
+      paths,
+      expected_generation,
+      force,
+      progress_id,
+      shuffle_policy,
+    }: requests::LoadTracks,
+  ) -> Result<Vec<(PathBuf, LoadTrackErrorKind)>, Self::Error> {
+    // Requests are handled one at a time, so the track list can't change between this check and the insert
+    self.player.check_generation(expected_generation)?;
+
     println!("Loading tracks: {:?}", paths);
-    let (tracks, errors) = self.track_cache.get_or_load_tracks(paths).await;
+    let mut emit_progress = |loaded, errored, scanning: &Path| {
+      let _ = self.player.emit(Event::LoadProgress {
+        progress_id,
+        loaded,
+        errored,
+        scanning: Some(scanning.into()),
+      });
+    };
+
+    let (tracks, errors) = self
+      .track_cache
+      .get_or_load_tracks(paths, ScanOptions { force }, &mut emit_progress)
+      .await?;
 
     for (path, error) in errors.iter() {
       eprintln!("Could not load track {path:?}: {error}")
@@ -151,12 +535,102 @@ impl RequestHandler for AudioServer {
       println!("Loaded track {:?}", track.file_path());
     }
 
-    (self.player.insert_tracks(position, &tracks).await)?;
+    self
+      .player
+      .insert_tracks(position, shuffle_policy, &tracks)
+      .await?;
+
+    Ok(
+      errors
+        .into_iter()
+        .map(|(path, error)| (path, error.kind()))
+        .collect(),
+    )
+  }
+
+  async fn handle_remove_tracks(
+    &self,
+    requests::RemoveTracks {
+      positions,
+      expected_generation,
+    }: requests::RemoveTracks,
+  ) -> Result<Vec<usize>, Self::Error> {
+    self.player.check_generation(expected_generation)?;
+    Ok(self.player.remove_tracks(&positions).await?)
+  }
+
+  async fn handle_swap_tracks(
+    &self,
+    requests::SwapTracks {
+      a,
+      b,
+      expected_generation,
+    }: requests::SwapTracks,
+  ) -> Result<(), Self::Error> {
+    self.player.check_generation(expected_generation)?;
+    Ok(self.player.swap_tracks(a, b).await?)
+  }
+
+  async fn handle_move_track(
+    &self,
+    requests::MoveTrack {
+      from,
+      to,
+      expected_generation,
+    }: requests::MoveTrack,
+  ) -> Result<(), Self::Error> {
+    self.player.check_generation(expected_generation)?;
+    Ok(self.player.move_track(from, to).await?)
+  }
+
+  async fn handle_reverse_queue(
+    &self,
+    _request: requests::ReverseQueue,
+  ) -> Result<(), Self::Error> {
+    Ok(self.player.reverse_queue().await?)
+  }
+
+  async fn handle_query_last_removed(
+    &self,
+    _request: requests::QueryLastRemoved,
+  ) -> Result<Vec<PathBuf>, Self::Error> {
+    Ok(self.player.last_removed().await)
+  }
+
+  async fn handle_restore_last_removed(
+    &self,
+    requests::RestoreLastRemoved {
+      position,
+      expected_generation,
+    }: requests::RestoreLastRemoved,
+  ) -> Result<Vec<(PathBuf, LoadTrackErrorKind)>, Self::Error> {
+    self.player.check_generation(expected_generation)?;
+    let paths = self.player.last_removed().await;
+    if paths.is_empty() {
+      return Err(AudioServerError::NothingToRestore);
+    }
+
+    println!("Restoring {} removed tracks", paths.len());
+
+    // The paths were accepted when they were first loaded, and the cache may still hold them
+    let (tracks, errors) = self
+      .track_cache
+      .get_or_load_tracks(paths, ScanOptions { force: true }, &mut |_, _, _| ())
+      .await?;
+
+    for (path, error) in errors.iter() {
+      eprintln!("Could not load track {path:?}: {error}")
+    }
+
+    self
+      .player
+      .insert_tracks(position, InsertShufflePolicy::Scatter, &tracks)
+      .await?;
 
     Ok(
       errors
         .into_iter()
-        .map(|(path, error)| (path, error.to_string()))
+        .map(|(path, error)| (path, error.kind()))
         .collect(),
     )
   }
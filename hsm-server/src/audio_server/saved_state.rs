@@ -0,0 +1,230 @@
+use std::{
+  env,
+  path::{Path, PathBuf},
+  time::Duration,
+};
+
+use hsm_ipc::LoopMode;
+use serde::{Deserialize, Serialize};
+
+use super::persist::{self, PersistError};
+
+/// The queue and player settings, saved to `$XDG_STATE_HOME/homeslashmusic/state.json` so they survive restarts
+///
+/// Only paths are saved, the tracks are loaded through the track cache again when the state is restored
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SavedState {
+  /// Paths of the tracks in track list order
+  pub tracks: Vec<PathBuf>,
+  /// Indicies into `tracks` in play order
+  pub shuffle_indicies: Vec<usize>,
+  /// Index of the current track in play order
+  pub current_index: usize,
+  pub position: Duration,
+  /// If playback was stopped, otherwise it is restored paused at `position`
+  pub stopped: bool,
+  pub volume: f32,
+  pub loop_mode: LoopMode,
+  pub shuffle: bool,
+}
+
+/// The play order and current track of a saved state, after the tracks that could not be loaded were left out
+#[derive(Debug, PartialEq)]
+pub struct RestoredOrder {
+  pub play_order: Vec<usize>,
+  pub current_index: usize,
+  /// If the saved current track was loaded, otherwise the track after it is current
+  pub current_restored: bool,
+}
+
+impl SavedState {
+  pub fn state_dir() -> Option<PathBuf> {
+    env::var_os("XDG_STATE_HOME")
+      .map(PathBuf::from)
+      .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".local/state")))
+      .map(|state_home| state_home.join("homeslashmusic"))
+  }
+
+  pub fn state_path() -> Option<PathBuf> {
+    Self::state_dir().map(|state_dir| state_dir.join("state.json"))
+  }
+
+  /// Reads the saved state, `None` if nothing was saved or it could not be recovered
+  pub async fn load() -> Result<Option<Self>, PersistError> {
+    let Some(path) = Self::state_path() else {
+      return Ok(None);
+    };
+
+    persist::read_recovering(&path).await
+  }
+
+  /// Writes the state atomically, keeping the previous one as a backup, see `persist::write_atomic`
+  pub async fn save(&self) -> Result<(), PersistError> {
+    let Some(path) = Self::state_path() else {
+      return Ok(());
+    };
+
+    persist::write_atomic(&path, self).await
+  }
+
+  /// Deletes the saved state and its backup, so nothing is restored on the next start
+  pub async fn remove() -> Result<(), PersistError> {
+    let Some(path) = Self::state_path() else {
+      return Ok(());
+    };
+
+    persist::remove(&path).await
+  }
+
+  /// Maps the saved play order onto the restored tracks, `None` if none of them were loaded
+  ///
+  /// `new_indicies` holds the new index of every saved track, or `None` if it could not be loaded
+  pub fn restored_order(&self, new_indicies: &[Option<usize>]) -> Option<RestoredOrder> {
+    let restored_len = new_indicies.iter().flatten().count();
+    if restored_len == 0 {
+      return None;
+    }
+
+    let new_index = |&saved_index: &usize| new_indicies.get(saved_index).copied().flatten();
+    let mut play_order: Vec<usize> = self.shuffle_indicies.iter().filter_map(new_index).collect();
+
+    let mut sorted_order = play_order.clone();
+    sorted_order.sort_unstable();
+    if !sorted_order.into_iter().eq(0..restored_len) {
+      eprintln!(
+        "Warning: Saved play order does not match the saved tracks, restoring them in order"
+      );
+      play_order = (0..restored_len).collect();
+    }
+
+    // Tracks before the current one that were skipped move it back, if it was skipped the next track becomes current
+    let saved_current = self.current_index.min(self.shuffle_indicies.len());
+    let current_index = self.shuffle_indicies[..saved_current]
+      .iter()
+      .filter(|index| new_index(index).is_some())
+      .count()
+      .min(restored_len - 1);
+    let current_restored = self
+      .shuffle_indicies
+      .get(saved_current)
+      .and_then(new_index)
+      .is_some();
+
+    Some(RestoredOrder {
+      play_order,
+      current_index,
+      current_restored,
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// A saved state of `len` tracks played in `shuffle_indicies`
+  fn saved_state(len: usize, shuffle_indicies: Vec<usize>, current_index: usize) -> SavedState {
+    SavedState {
+      tracks: (0..len)
+        .map(|index| PathBuf::from(format!("/music/{index}.flac")))
+        .collect(),
+      shuffle_indicies,
+      current_index,
+      position: Duration::from_secs(30),
+      stopped: false,
+      volume: 0.5,
+      loop_mode: LoopMode::Playlist,
+      shuffle: true,
+    }
+  }
+
+  /// The new index of each saved track, `false` for tracks that could not be loaded
+  fn new_indicies(loaded: &[bool]) -> Vec<Option<usize>> {
+    let mut next = 0;
+    loaded
+      .iter()
+      .map(|&loaded| {
+        loaded.then(|| {
+          next += 1;
+          next - 1
+        })
+      })
+      .collect()
+  }
+
+  fn order(play_order: &[usize], current_index: usize, current_restored: bool) -> RestoredOrder {
+    RestoredOrder {
+      play_order: play_order.to_vec(),
+      current_index,
+      current_restored,
+    }
+  }
+
+  #[test]
+  fn restores_every_loaded_track() {
+    let state = saved_state(4, vec![2, 0, 3, 1], 2);
+    assert_eq!(
+      state.restored_order(&new_indicies(&[true; 4])),
+      Some(order(&[2, 0, 3, 1], 2, true))
+    );
+  }
+
+  #[test]
+  fn skips_tracks_that_did_not_load() {
+    let cases = [
+      // A track before the current one moves it back
+      ([true, true, false, true], order(&[0, 2, 1], 1, true)),
+      // A track after it doesn't
+      ([true, false, true, true], order(&[1, 0, 2], 2, true)),
+      // The track after the current one becomes current
+      ([true, true, true, false], order(&[2, 0, 1], 2, false)),
+    ];
+
+    for (loaded, expected) in cases {
+      let state = saved_state(4, vec![2, 0, 3, 1], 2);
+      assert_eq!(
+        state.restored_order(&new_indicies(&loaded)),
+        Some(expected),
+        "{loaded:?}"
+      );
+    }
+  }
+
+  #[test]
+  fn current_track_stays_in_range() {
+    // The current track was last in play order and could not be loaded
+    let state = saved_state(3, vec![0, 1, 2], 2);
+    assert_eq!(
+      state.restored_order(&new_indicies(&[true, true, false])),
+      Some(order(&[0, 1], 1, false))
+    );
+
+    let state = saved_state(3, vec![0, 1, 2], 10);
+    assert_eq!(
+      state.restored_order(&new_indicies(&[true; 3])),
+      Some(order(&[0, 1, 2], 2, false))
+    );
+  }
+
+  #[test]
+  fn mismatched_play_order_is_restored_in_order() {
+    let state = saved_state(3, vec![0, 0, 5], 0);
+    assert_eq!(
+      state.restored_order(&new_indicies(&[true; 3])),
+      Some(order(&[0, 1, 2], 0, true))
+    );
+  }
+
+  #[test]
+  fn nothing_to_restore_without_loaded_tracks() {
+    let state = saved_state(2, vec![1, 0], 0);
+    assert_eq!(state.restored_order(&new_indicies(&[false, false])), None);
+  }
+
+  #[test]
+  fn state_round_trips() {
+    let state = saved_state(3, vec![2, 0, 1], 1);
+    let json = serde_json::to_string(&state).unwrap();
+    assert_eq!(serde_json::from_str::<SavedState>(&json).unwrap(), state);
+  }
+}
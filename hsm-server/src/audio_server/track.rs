@@ -3,23 +3,33 @@ use std::{
   path::{Path, PathBuf},
 };
 
-pub use cache::TrackCache;
-use hsm_ipc::{Track, TrackMetadata};
+pub use cache::{ScanOptions, TrackCache};
+pub use gapless::GaplessTrim;
+use hsm_ipc::{InspectedTrack, LoadTrackErrorKind, Track, TrackMetadata};
 pub use loading::{load_file, probe_track_sync};
+pub use lyrics::{read_sidecar_lyrics, read_synced_lyrics};
+pub use memory::update_size;
 use smol::fs;
 use symphonia::core::{audio::SignalSpec, errors::Error as SymphoniaError};
 use thiserror::Error;
 
+mod art;
 mod cache;
+mod charset;
+mod gapless;
+mod inference;
 mod loading;
+mod lyrics;
+mod memory;
+mod playlist;
 
 #[derive(Debug, Error)]
 pub enum LoadTrackError {
   #[error("{0}")]
   CannonicalizeFailed(#[source] io::Error),
 
-  #[error("{0}")]
-  OpenFailed(#[source] io::Error),
+  #[error("{}", open_failed_message(.path, .source))]
+  OpenFailed { path: TrackPath, source: io::Error },
 
   #[error("{0}")]
   ReadDirFailed(#[source] io::Error),
@@ -32,6 +42,121 @@ pub enum LoadTrackError {
 
   #[error("{0}")]
   DecodingFailed(#[source] SymphoniaError),
+
+  #[error("Permission denied: not inside queue.allowed_roots")]
+  OutsideAllowedRoots,
+
+  #[error("Failed to read playlist: {0}")]
+  ReadPlaylistFailed(#[source] io::Error),
+
+  #[error("Playlists inside of playlists are not loaded")]
+  NestedPlaylist,
+
+  #[error("Unsupported playlist entry, only paths and file:// URIs can be loaded")]
+  UnsupportedPlaylistEntry,
+}
+
+impl LoadTrackError {
+  /// The category of the error that is sent to clients
+  pub fn kind(&self) -> LoadTrackErrorKind {
+    let io_kind = |error: &io::Error, fallback: LoadTrackErrorKind| match error.kind() {
+      io::ErrorKind::NotFound => LoadTrackErrorKind::NotFound,
+      io::ErrorKind::PermissionDenied => LoadTrackErrorKind::PermissionDenied,
+      _ => fallback,
+    };
+
+    match self {
+      Self::CannonicalizeFailed(source) | Self::OpenFailed { source, .. } => {
+        io_kind(source, LoadTrackErrorKind::Other(self.to_string()))
+      }
+      Self::ReadDirFailed(source) => io_kind(source, LoadTrackErrorKind::ReadDirFailed),
+      Self::ReadPlaylistFailed(source) => {
+        io_kind(source, LoadTrackErrorKind::Other(self.to_string()))
+      }
+      Self::NestedPlaylist | Self::UnsupportedPlaylistEntry => {
+        LoadTrackErrorKind::Other(self.to_string())
+      }
+      Self::ProbeFailed(SymphoniaError::Unsupported(_)) | Self::CodecNotSupported => {
+        LoadTrackErrorKind::UnsupportedCodec
+      }
+      Self::ProbeFailed(SymphoniaError::IoError(source)) => {
+        io_kind(source, LoadTrackErrorKind::ProbeFailed)
+      }
+      Self::ProbeFailed(_) => LoadTrackErrorKind::ProbeFailed,
+      Self::DecodingFailed(_) => LoadTrackErrorKind::DecodeFailed,
+      Self::OutsideAllowedRoots => LoadTrackErrorKind::PermissionDenied,
+    }
+  }
+}
+
+/// Names both paths if they differ, since the file may not be where the user expects
+fn open_failed_message(path: &TrackPath, source: &io::Error) -> String {
+  let reason = match source.kind() {
+    io::ErrorKind::NotFound => "not found".into(),
+    _ => source.to_string(),
+  };
+
+  if path.requested == path.resolved {
+    reason
+  } else {
+    format!(
+      "requested {:?} (resolved to {:?}): {reason}",
+      path.requested, path.resolved
+    )
+  }
+}
+
+/// A path as it was passed to the server, and the cannonical path of the file it pointed to
+#[derive(Debug, Clone)]
+pub struct TrackPath {
+  pub requested: PathBuf,
+  pub resolved: PathBuf,
+}
+
+impl TrackPath {
+  /// Uses `path` as both paths, for paths that are already cannonical or have not been resolved yet
+  pub fn new(path: PathBuf) -> Self {
+    Self {
+      requested: path.clone(),
+      resolved: path,
+    }
+  }
+
+  pub async fn resolve(requested: PathBuf) -> Result<Self, LoadTrackError> {
+    let resolved = get_cannonical_track_path(&requested).await?;
+    Ok(Self {
+      requested,
+      resolved,
+    })
+  }
+}
+
+/// Whether `cannonical_path` is inside one of `allowed_roots`, which must be cannonical too
+///
+/// No roots means that every path is allowed
+pub fn in_allowed_roots(allowed_roots: &[PathBuf], cannonical_path: &Path) -> bool {
+  allowed_roots.is_empty()
+    || allowed_roots
+      .iter()
+      .any(|root| cannonical_path.starts_with(root))
+}
+
+/// A limit from `QueueConfig` that stopped a whole request from loading tracks
+#[derive(Debug, Error)]
+pub enum ScanError {
+  #[error(
+    "Stopped after visiting {max_files} files, add fewer tracks at once or raise queue.max_scan_files"
+  )]
+  TooManyFiles { max_files: usize },
+
+  #[error("Refusing to scan filesystem root {0:?}, use --force to scan it anyway")]
+  FilesystemRoot(PathBuf),
+
+  #[error("{0:?} is not inside queue.allowed_dirs, use --force to load it anyway")]
+  NotAllowed(PathBuf),
+
+  #[error("Permission denied: {0:?} is not inside queue.allowed_roots")]
+  OutsideAllowedRoots(PathBuf),
 }
 
 /// A `Track` that has been loaded into the cache
@@ -39,6 +164,9 @@ pub enum LoadTrackError {
 pub struct LoadedTrack {
   pub inner: Track,
   pub spec: SignalSpec,
+  /// False if no frame could be decoded while loading, so `spec` is what the container reported
+  pub spec_verified: bool,
+  pub trim: GaplessTrim,
 }
 
 impl LoadedTrack {
@@ -53,6 +181,24 @@ impl LoadedTrack {
   pub fn clone_track(&self) -> Track {
     self.inner.clone()
   }
+
+  /// The track with what was found while loading it, for `InspectTrack`
+  pub fn inspect(&self) -> InspectedTrack {
+    InspectedTrack {
+      track: self.clone_track(),
+      sample_rate: self.spec.rate,
+      channels: self.spec.channels.count() as u16,
+      format_unverified: !self.spec_verified,
+      has_lyrics: self.metadata().lyrics.is_some(),
+      encoder_delay: self.trim.delay,
+      encoder_padding: self.trim.padding,
+    }
+  }
+
+  /// Estimates the memory used by this track, see `memory::track_size`
+  pub fn approx_size(&self) -> usize {
+    std::mem::size_of::<Self>() + memory::track_heap_size(&self.inner)
+  }
 }
 
 impl Into<Track> for LoadedTrack {
@@ -66,3 +212,139 @@ pub async fn get_cannonical_track_path(path: &Path) -> Result<PathBuf, LoadTrack
     .await
     .map_err(|error| LoadTrackError::CannonicalizeFailed(error))
 }
+
+#[cfg(test)]
+mod tests {
+  use std::time::Duration;
+
+  use symphonia::core::errors::Error as SymphoniaError;
+
+  use super::*;
+  use crate::{
+    audio_server::{
+      blocking::{BlockingScheduler, Lane},
+      player::tests::write_wav,
+    },
+    config::TagConfig,
+  };
+
+  fn io_error(kind: io::ErrorKind) -> io::Error {
+    io::Error::new(kind, "test")
+  }
+
+  #[test]
+  fn classifies_load_errors() {
+    let path = TrackPath::new("/music/a.flac".into());
+    let cases = [
+      (
+        LoadTrackError::CannonicalizeFailed(io_error(io::ErrorKind::NotFound)),
+        LoadTrackErrorKind::NotFound,
+      ),
+      (
+        LoadTrackError::OpenFailed {
+          path: path.clone(),
+          source: io_error(io::ErrorKind::PermissionDenied),
+        },
+        LoadTrackErrorKind::PermissionDenied,
+      ),
+      (
+        LoadTrackError::OpenFailed {
+          path,
+          source: io_error(io::ErrorKind::Interrupted),
+        },
+        LoadTrackErrorKind::Other("test".into()),
+      ),
+      (
+        LoadTrackError::ReadDirFailed(io_error(io::ErrorKind::Other)),
+        LoadTrackErrorKind::ReadDirFailed,
+      ),
+      (
+        LoadTrackError::ReadDirFailed(io_error(io::ErrorKind::NotFound)),
+        LoadTrackErrorKind::NotFound,
+      ),
+      (
+        LoadTrackError::ProbeFailed(SymphoniaError::Unsupported("format")),
+        LoadTrackErrorKind::UnsupportedCodec,
+      ),
+      (
+        LoadTrackError::ProbeFailed(SymphoniaError::IoError(io_error(
+          io::ErrorKind::UnexpectedEof,
+        ))),
+        LoadTrackErrorKind::ProbeFailed,
+      ),
+      (
+        LoadTrackError::ProbeFailed(SymphoniaError::DecodeError("bad header")),
+        LoadTrackErrorKind::ProbeFailed,
+      ),
+      (
+        LoadTrackError::CodecNotSupported,
+        LoadTrackErrorKind::UnsupportedCodec,
+      ),
+      (
+        LoadTrackError::DecodingFailed(SymphoniaError::DecodeError("bad frame")),
+        LoadTrackErrorKind::DecodeFailed,
+      ),
+      (
+        LoadTrackError::OutsideAllowedRoots,
+        LoadTrackErrorKind::PermissionDenied,
+      ),
+      (
+        LoadTrackError::NestedPlaylist,
+        LoadTrackErrorKind::Other("Playlists inside of playlists are not loaded".into()),
+      ),
+    ];
+
+    for (error, expected) in cases {
+      assert_eq!(error.kind(), expected, "{error:?}");
+    }
+  }
+
+  #[test]
+  fn missing_track_is_not_found() {
+    let dir = tempfile::tempdir().unwrap();
+    let error = smol::block_on(TrackPath::resolve(dir.path().join("missing.flac"))).unwrap_err();
+    assert_eq!(error.kind(), LoadTrackErrorKind::NotFound);
+  }
+
+  #[test]
+  fn open_errors_name_both_paths() {
+    let source = io_error(io::ErrorKind::NotFound);
+    assert_eq!(
+      open_failed_message(&TrackPath::new("/music/a.flac".into()), &source),
+      "not found"
+    );
+
+    let path = TrackPath {
+      requested: "a.flac".into(),
+      resolved: "/music/a.flac".into(),
+    };
+    assert_eq!(
+      open_failed_message(&path, &source),
+      r#"requested "a.flac" (resolved to "/music/a.flac"): not found"#
+    );
+  }
+
+  #[test]
+  fn inspects_loaded_track() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("a.wav");
+    write_wav(&path, Duration::from_secs(1));
+
+    let track = smol::block_on(load_file(
+      TrackPath::new(path.clone()),
+      &[],
+      &BlockingScheduler::new(),
+      Lane::Interactive,
+      &TagConfig::default(),
+    ))
+    .unwrap();
+    let inspected = track.inspect();
+
+    assert_eq!(inspected.track.file_path, path);
+    assert_eq!(inspected.track.total_duration, Some(Duration::from_secs(1)));
+    assert_eq!((inspected.sample_rate, inspected.channels), (8000, 1));
+    assert!(!inspected.format_unverified);
+    assert!(!inspected.has_lyrics);
+    assert_eq!((inspected.encoder_delay, inspected.encoder_padding), (0, 0));
+  }
+}
@@ -0,0 +1,483 @@
+use std::{
+  env,
+  fs::{self, File},
+  io::{self, BufReader, Read},
+  path::{Path, PathBuf},
+};
+
+use symphonia::core::meta::{StandardVisualKey, Visual};
+
+use crate::audio_server::persist::content_hash;
+
+/// File names, without their extension, of album art stored next to the audio files
+const SIDECAR_NAMES: &[&str] = &["cover", "folder", "front", "album"];
+const SIDECAR_EXTENSIONS: &[&str] = &["jpg", "jpeg", "png", "webp"];
+
+/// Embedded images larger than this are not cached or read from FLAC metadata blocks
+const MAX_ART_SIZE: usize = 16 * 1024 * 1024;
+
+const FLAC_MARKER: &[u8; 4] = b"fLaC";
+const FLAC_VORBIS_COMMENT: u8 = 4;
+const FLAC_PICTURE: u8 = 6;
+/// The picture type of front covers, in FLAC and ID3
+const FRONT_COVER: u32 = 3;
+/// The Vorbis comment holding a base64 encoded FLAC picture block
+const PICTURE_COMMENT_KEY: &[u8] = b"METADATA_BLOCK_PICTURE=";
+
+/// An image from a FLAC `METADATA_BLOCK_PICTURE`
+#[derive(Debug, PartialEq)]
+pub struct Picture {
+  pub picture_type: u32,
+  pub media_type: String,
+  pub data: Vec<u8>,
+}
+
+/// `$XDG_CACHE_HOME/homeslashmusic/art`, where embedded art is written so clients can read it by path
+fn art_dir() -> Option<PathBuf> {
+  env::var_os("XDG_CACHE_HOME")
+    .map(PathBuf::from)
+    .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))
+    .map(|cache_home| cache_home.join("homeslashmusic/art"))
+}
+
+fn extension_for(media_type: &str) -> &'static str {
+  match media_type.to_ascii_lowercase().as_str() {
+    "image/jpeg" | "image/jpg" => "jpg",
+    "image/png" => "png",
+    "image/gif" => "gif",
+    "image/webp" => "webp",
+    "image/bmp" => "bmp",
+    _ => "img",
+  }
+}
+
+/// The front cover, or the first visual if none of them are marked as one
+pub fn front_cover(visuals: &[Visual]) -> Option<&Visual> {
+  visuals
+    .iter()
+    .find(|visual| visual.usage == Some(StandardVisualKey::FrontCover))
+    .or(visuals.first())
+}
+
+/// Writes `visual` to the art cache, named after a hash of its contents so tracks with the same art share a file
+///
+/// This function is synchronous, so it must be called inside of `BlockingScheduler::unblock`
+pub fn cache_visual_sync(visual: &Visual) -> Option<PathBuf> {
+  cache_image_sync(&visual.data, &visual.media_type)
+}
+
+/// `cache_visual_sync` for a picture read by `read_flac_picture_sync`
+pub fn cache_picture_sync(picture: &Picture) -> Option<PathBuf> {
+  cache_image_sync(&picture.data, &picture.media_type)
+}
+
+fn cache_image_sync(data: &[u8], media_type: &str) -> Option<PathBuf> {
+  if data.is_empty() || data.len() > MAX_ART_SIZE {
+    return None;
+  }
+
+  let art_dir = art_dir()?;
+  let file_name = format!("{:016x}.{}", content_hash(data), extension_for(media_type));
+  let art_path = art_dir.join(file_name);
+
+  if art_path.exists() {
+    return Some(art_path);
+  }
+
+  // Written to a temporary file first, so another track loading the same art never reads a partial image
+  let tmp_path = art_path.with_extension("tmp");
+  let result = fs::create_dir_all(&art_dir)
+    .and_then(|()| fs::write(&tmp_path, data))
+    .and_then(|()| fs::rename(&tmp_path, &art_path));
+
+  match result {
+    Ok(()) => Some(art_path),
+    Err(error) => {
+      eprintln!("Failed to cache album art at {art_path:?}: {error}");
+      None
+    }
+  }
+}
+
+/// Finds an image such as `cover.jpg` or `folder.png` in the same directory as `track_path`
+///
+/// This function is synchronous, so it must be called inside of `BlockingScheduler::unblock`
+pub fn find_sidecar_art_sync(track_path: &Path) -> Option<PathBuf> {
+  let entries = fs::read_dir(track_path.parent()?).ok()?;
+
+  let mut candidates: Vec<(usize, PathBuf)> = entries
+    .filter_map(|entry| {
+      let path = entry.ok()?.path();
+      let stem = path.file_stem()?.to_str()?.to_ascii_lowercase();
+      let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+
+      let rank = SIDECAR_NAMES.iter().position(|name| *name == stem)?;
+      SIDECAR_EXTENSIONS
+        .contains(&extension.as_str())
+        .then_some((rank, path))
+    })
+    .collect();
+
+  // Prefer names earlier in `SIDECAR_NAMES`, and pick the same file every time if there are several
+  candidates.sort();
+  candidates.into_iter().next().map(|(_, path)| path)
+}
+
+/// Reads the front cover from the metadata blocks of a FLAC file, `None` if `path` is not a FLAC file
+///
+/// Symphonia doesn't return the pictures of some files, so this reads the `PICTURE` blocks,
+/// and the base64 `METADATA_BLOCK_PICTURE` in the Vorbis comment block, itself.
+/// This function is synchronous, so it must be called inside of `BlockingScheduler::unblock`
+pub fn read_flac_picture_sync(path: &Path) -> Option<Picture> {
+  let file = File::open(path).ok()?;
+  let pictures = read_flac_pictures(BufReader::new(file))?;
+
+  let front_cover = pictures
+    .iter()
+    .position(|picture| picture.picture_type == FRONT_COVER)
+    .unwrap_or(0);
+  pictures.into_iter().nth(front_cover)
+}
+
+/// The pictures in the metadata blocks before the first audio frame, `None` if `reader` is not a FLAC stream
+///
+/// A damaged block ends the search, keeping the pictures found before it
+fn read_flac_pictures(mut reader: impl Read) -> Option<Vec<Picture>> {
+  let mut marker = [0; 4];
+  reader.read_exact(&mut marker).ok()?;
+  if &marker != FLAC_MARKER {
+    return None;
+  }
+
+  let mut pictures = Vec::new();
+  loop {
+    let mut header = [0; 4];
+    if reader.read_exact(&mut header).is_err() {
+      break;
+    }
+    let is_last = header[0] & 0x80 != 0;
+    let block_type = header[0] & 0x7f;
+    let len = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+
+    if matches!(block_type, FLAC_PICTURE | FLAC_VORBIS_COMMENT) && len <= MAX_ART_SIZE {
+      let mut block = vec![0; len];
+      if reader.read_exact(&mut block).is_err() {
+        break;
+      }
+
+      if block_type == FLAC_PICTURE {
+        pictures.extend(parse_picture(&block));
+      } else {
+        pictures.extend(comment_pictures(&block).into_iter().flatten());
+      }
+    } else if io::copy(&mut (&mut reader).take(len as u64), &mut io::sink()).is_err() {
+      break;
+    }
+
+    if is_last {
+      break;
+    }
+  }
+
+  Some(pictures)
+}
+
+/// Parses the contents of a FLAC `PICTURE` block
+fn parse_picture(mut block: &[u8]) -> Option<Picture> {
+  let picture_type = take_u32(&mut block, u32::from_be_bytes)?;
+  let media_type_len = take_u32(&mut block, u32::from_be_bytes)?;
+  let media_type = String::from_utf8_lossy(take(&mut block, media_type_len as usize)?).into_owned();
+  let description_len = take_u32(&mut block, u32::from_be_bytes)?;
+  take(&mut block, description_len as usize)?;
+  // Width, height, color depth and the number of colors in an indexed image
+  take(&mut block, 16)?;
+  let data_len = take_u32(&mut block, u32::from_be_bytes)?;
+  let data = take(&mut block, data_len as usize)?.to_vec();
+
+  Some(Picture {
+    picture_type,
+    media_type,
+    data,
+  })
+}
+
+/// The pictures in the `METADATA_BLOCK_PICTURE` comments of a Vorbis comment block, which are little endian unlike FLAC
+fn comment_pictures(mut block: &[u8]) -> Option<Vec<Picture>> {
+  let vendor_len = take_u32(&mut block, u32::from_le_bytes)?;
+  take(&mut block, vendor_len as usize)?;
+
+  let comment_count = take_u32(&mut block, u32::from_le_bytes)?;
+  let mut pictures = Vec::new();
+  for _ in 0..comment_count {
+    let comment_len = take_u32(&mut block, u32::from_le_bytes)?;
+    let comment = take(&mut block, comment_len as usize)?;
+
+    // Keys are case insensitive
+    let Some((key, value)) = comment.split_at_checked(PICTURE_COMMENT_KEY.len()) else {
+      continue;
+    };
+    if key.eq_ignore_ascii_case(PICTURE_COMMENT_KEY) {
+      pictures.extend(decode_base64(value).as_deref().and_then(parse_picture));
+    }
+  }
+
+  Some(pictures)
+}
+
+/// Splits the first `len` bytes off of `bytes`
+fn take<'a>(bytes: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+  let (taken, rest) = bytes.split_at_checked(len)?;
+  *bytes = rest;
+  Some(taken)
+}
+
+fn take_u32(bytes: &mut &[u8], from_bytes: fn([u8; 4]) -> u32) -> Option<u32> {
+  let taken = take(bytes, 4)?;
+  Some(from_bytes(taken.try_into().ok()?))
+}
+
+/// Decodes standard base64, with or without padding
+fn decode_base64(encoded: &[u8]) -> Option<Vec<u8>> {
+  let encoded = encoded
+    .strip_suffix(b"==")
+    .or_else(|| encoded.strip_suffix(b"="))
+    .unwrap_or(encoded);
+  // A single character left over can't hold a whole byte
+  if encoded.len() % 4 == 1 {
+    return None;
+  }
+
+  let mut decoded = Vec::with_capacity(encoded.len() / 4 * 3 + 2);
+  for chunk in encoded.chunks(4) {
+    let mut bits = 0u32;
+    for (i, &digit) in chunk.iter().enumerate() {
+      bits |= u32::from(base64_value(digit)?) << (18 - 6 * i);
+    }
+
+    // Each character holds 6 bits, so `n` characters hold `n - 1` whole bytes
+    decoded.extend_from_slice(&bits.to_be_bytes()[1..chunk.len()]);
+  }
+
+  Some(decoded)
+}
+
+fn base64_value(digit: u8) -> Option<u8> {
+  match digit {
+    b'A'..=b'Z' => Some(digit - b'A'),
+    b'a'..=b'z' => Some(digit - b'a' + 26),
+    b'0'..=b'9' => Some(digit - b'0' + 52),
+    b'+' => Some(62),
+    b'/' => Some(63),
+    _ => None,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// A 1x1 transparent PNG
+  const TINY_PNG: &[u8] = &[
+    0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a, 0x00, 0x00, 0x00, 0x0d, 0x49, 0x48, 0x44, 0x52,
+    0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x08, 0x06, 0x00, 0x00, 0x00, 0x1f, 0x15, 0xc4,
+    0x89, 0x00, 0x00, 0x00, 0x0d, 0x49, 0x44, 0x41, 0x54, 0x78, 0x9c, 0x63, 0x00, 0x01, 0x00, 0x00,
+    0x05, 0x00, 0x01, 0x0d, 0x0a, 0x2d, 0xb4, 0x00, 0x00, 0x00, 0x00, 0x49, 0x45, 0x4e, 0x44, 0xae,
+    0x42, 0x60, 0x82,
+  ];
+  const STREAMINFO: u8 = 0;
+  const PADDING: u8 = 1;
+  const BACK_COVER: u32 = 4;
+
+  fn picture(picture_type: u32) -> Picture {
+    Picture {
+      picture_type,
+      media_type: "image/png".into(),
+      data: TINY_PNG.to_vec(),
+    }
+  }
+
+  /// The contents of a `PICTURE` block holding `picture`
+  fn picture_block(picture: &Picture) -> Vec<u8> {
+    let description = b"Cover";
+    let mut block = Vec::new();
+    block.extend_from_slice(&picture.picture_type.to_be_bytes());
+    block.extend_from_slice(&(picture.media_type.len() as u32).to_be_bytes());
+    block.extend_from_slice(picture.media_type.as_bytes());
+    block.extend_from_slice(&(description.len() as u32).to_be_bytes());
+    block.extend_from_slice(description);
+    for dimension in [1u32, 1, 32, 0] {
+      block.extend_from_slice(&dimension.to_be_bytes());
+    }
+    block.extend_from_slice(&(picture.data.len() as u32).to_be_bytes());
+    block.extend_from_slice(&picture.data);
+    block
+  }
+
+  /// The contents of a Vorbis comment block holding `comments`
+  fn comment_block(comments: &[&[u8]]) -> Vec<u8> {
+    let vendor = b"hsm test";
+    let mut block = Vec::new();
+    block.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+    block.extend_from_slice(vendor);
+    block.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+    for comment in comments {
+      block.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+      block.extend_from_slice(comment);
+    }
+    block
+  }
+
+  /// A FLAC stream with `blocks` as its metadata, followed by something that is not a metadata block
+  fn flac(blocks: &[(u8, Vec<u8>)]) -> Vec<u8> {
+    let mut flac = FLAC_MARKER.to_vec();
+    for (i, (block_type, block)) in blocks.iter().enumerate() {
+      let is_last = if i == blocks.len() - 1 { 0x80 } else { 0 };
+      flac.push(block_type | is_last);
+      flac.extend_from_slice(&(block.len() as u32).to_be_bytes()[1..]);
+      flac.extend_from_slice(block);
+    }
+    flac.extend_from_slice(&[0xff, 0xf8, 0x69, 0x08]);
+    flac
+  }
+
+  fn encode_base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut encoded = String::new();
+    for chunk in data.chunks(3) {
+      let mut bytes = [0; 4];
+      bytes[1..=chunk.len()].copy_from_slice(chunk);
+      let bits = u32::from_be_bytes(bytes);
+      for i in 0..4 {
+        if i <= chunk.len() {
+          encoded.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+        } else {
+          encoded.push('=');
+        }
+      }
+    }
+    encoded
+  }
+
+  #[test]
+  fn decodes_base64() {
+    let cases: &[(&str, Option<&[u8]>)] = &[
+      ("", Some(b"")),
+      ("Zg==", Some(b"f")),
+      ("Zm8=", Some(b"fo")),
+      ("Zm9v", Some(b"foo")),
+      ("Zm9vYmFy", Some(b"foobar")),
+      ("Zm8", Some(b"fo")),
+      ("+/+/", Some(&[0xfb, 0xff, 0xbf])),
+      ("Zm9vY", None),
+      ("Zm9v!A==", None),
+      ("Zg==Zg==", None),
+    ];
+
+    for &(encoded, expected) in cases {
+      assert_eq!(
+        decode_base64(encoded.as_bytes()).as_deref(),
+        expected,
+        "{encoded}"
+      );
+    }
+  }
+
+  #[test]
+  fn base64_round_trips() {
+    let data: Vec<u8> = (0..=255).collect();
+    for len in 0..8 {
+      let data = &data[..data.len() - len];
+      assert_eq!(decode_base64(encode_base64(data).as_bytes()).unwrap(), data);
+    }
+  }
+
+  #[test]
+  fn reads_picture_blocks() {
+    let flac = flac(&[
+      (STREAMINFO, vec![0; 34]),
+      (PADDING, vec![0; 100]),
+      (FLAC_PICTURE, picture_block(&picture(FRONT_COVER))),
+    ]);
+
+    assert_eq!(
+      read_flac_pictures(flac.as_slice()),
+      Some(vec![picture(FRONT_COVER)])
+    );
+  }
+
+  #[test]
+  fn reads_pictures_from_vorbis_comments() {
+    let comment = format!(
+      "metadata_block_picture={}",
+      encode_base64(&picture_block(&picture(FRONT_COVER)))
+    );
+    let flac = flac(&[
+      (STREAMINFO, vec![0; 34]),
+      (
+        FLAC_VORBIS_COMMENT,
+        comment_block(&[
+          b"TITLE=Title",
+          comment.as_bytes(),
+          b"METADATA_BLOCK_PICTURE=!",
+        ]),
+      ),
+    ]);
+
+    assert_eq!(
+      read_flac_pictures(flac.as_slice()),
+      Some(vec![picture(FRONT_COVER)])
+    );
+  }
+
+  #[test]
+  fn stops_at_damaged_blocks() {
+    let mut damaged = picture_block(&picture(FRONT_COVER));
+    damaged.truncate(damaged.len() - 1);
+
+    let cases = [
+      // The picture's data is shorter than its length
+      flac(&[(FLAC_PICTURE, damaged)]),
+      // The block is longer than the file
+      flac(&[(FLAC_PICTURE, picture_block(&picture(FRONT_COVER)))])[..20].to_vec(),
+    ];
+    for flac in cases {
+      assert_eq!(read_flac_pictures(flac.as_slice()), Some(Vec::new()));
+    }
+
+    // Blocks after the last one are audio frames
+    let mut flac = flac(&[(STREAMINFO, vec![0; 34])]);
+    flac.extend_from_slice(&[FLAC_PICTURE, 0, 0, 0]);
+    assert_eq!(read_flac_pictures(flac.as_slice()), Some(Vec::new()));
+  }
+
+  #[test]
+  fn ignores_other_formats() {
+    assert_eq!(read_flac_pictures(&b"RIFF\0\0\0\0WAVE"[..]), None);
+    assert_eq!(read_flac_pictures(&b"fL"[..]), None);
+  }
+
+  #[test]
+  fn prefers_the_front_cover() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("track.flac");
+
+    let cases = [
+      (vec![BACK_COVER, FRONT_COVER], FRONT_COVER),
+      (vec![BACK_COVER, 0], BACK_COVER),
+    ];
+    for (picture_types, expected) in cases {
+      let blocks: Vec<(u8, Vec<u8>)> = picture_types
+        .iter()
+        .map(|&picture_type| (FLAC_PICTURE, picture_block(&picture(picture_type))))
+        .collect();
+      fs::write(&path, flac(&blocks)).unwrap();
+
+      assert_eq!(
+        read_flac_picture_sync(&path),
+        Some(picture(expected)),
+        "{picture_types:?}"
+      );
+    }
+  }
+}
@@ -1,26 +1,232 @@
 use std::{
-  path::PathBuf,
-  sync::{Arc, Weak},
+  cell::RefCell,
+  collections::{BTreeMap, HashMap},
+  mem,
+  path::{self, Component, Path, PathBuf},
+  sync::{
+    Arc, Mutex, MutexGuard, Weak,
+    atomic::{AtomicU64, Ordering},
+  },
+  time::{Duration, Instant},
 };
 
 use dashmap::DashMap;
-use smol::{fs, stream::StreamExt};
+use futures_concurrency::future::Join;
+use hsm_ipc::CacheStats;
+use smol::{fs, lock::Semaphore, stream::StreamExt};
 
-use super::{LoadTrackError, LoadedTrack};
+use super::{LoadTrackError, LoadedTrack, ScanError, TrackPath, playlist};
+use crate::{
+  audio_server::blocking::{BlockingScheduler, Lane},
+  config::{QueueConfig, TagConfig},
+};
 
 type Tracks = Vec<Arc<LoadedTrack>>;
 type Errors = Vec<(PathBuf, LoadTrackError)>;
 
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ScanOptions {
+  /// Skip the filesystem root and `QueueConfig::allowed_dirs` checks, `QueueConfig::allowed_roots` still applies
+  pub force: bool,
+}
+
+/// Called with the number of loaded and failed tracks, and the path being scanned
+pub type ProgressCallback<'a> = &'a mut dyn FnMut(usize, usize, &Path);
+
+/// Counts for a single `get_or_load_tracks` call
+struct ScanProgress<'a> {
+  files_visited: usize,
+  loaded: usize,
+  errored: usize,
+  last_report: Instant,
+  on_progress: ProgressCallback<'a>,
+}
+
+impl<'a> ScanProgress<'a> {
+  /// Limits progress reports to a few per second
+  const REPORT_INTERVAL: Duration = Duration::from_millis(250);
+
+  fn new(on_progress: ProgressCallback<'a>) -> Self {
+    Self {
+      files_visited: 0,
+      loaded: 0,
+      errored: 0,
+      last_report: Instant::now(),
+      on_progress,
+    }
+  }
+
+  fn report(&mut self, scanning: &Path) {
+    if self.last_report.elapsed() < Self::REPORT_INTERVAL {
+      return;
+    }
+
+    self.last_report = Instant::now();
+    (self.on_progress)(self.loaded, self.errored, scanning);
+  }
+}
+
+enum ScanEntry {
+  Directory(PathBuf),
+  File(PathBuf),
+}
+
+/// Keeps the most recently used tracks loaded after they leave the track list, so adding them again doesn't read their tags
+#[derive(Debug)]
+struct RecentTracks {
+  max_len: usize,
+  next_use: u64,
+  tracks: HashMap<PathBuf, (u64, Arc<LoadedTrack>)>,
+  /// The paths in `tracks`, by when they were last used
+  by_use: BTreeMap<u64, PathBuf>,
+}
+
+impl RecentTracks {
+  fn new(max_len: usize) -> Self {
+    Self {
+      max_len,
+      next_use: 0,
+      tracks: HashMap::new(),
+      by_use: BTreeMap::new(),
+    }
+  }
+
+  /// Marks `track` as the most recently used, and returns how many tracks were dropped to stay under `max_len`
+  fn touch(&mut self, track: &Arc<LoadedTrack>) -> u64 {
+    let path = track.file_path().to_path_buf();
+    let use_id = self.next_use;
+    self.next_use += 1;
+
+    if let Some((last_use, _)) = self.tracks.insert(path.clone(), (use_id, track.clone())) {
+      self.by_use.remove(&last_use);
+    }
+    self.by_use.insert(use_id, path);
+
+    let mut evicted = 0;
+    while self.tracks.len() > self.max_len {
+      let Some((_, path)) = self.by_use.pop_first() else {
+        break;
+      };
+      self.tracks.remove(&path);
+      evicted += 1;
+    }
+
+    evicted
+  }
+}
+
 #[derive(Debug)]
 pub struct TrackCache {
+  /// Every track loaded through the cache that is still in use, tracks that were dropped are removed by `evict_dead`
   loaded_tracks: DashMap<PathBuf, Weak<LoadedTrack>>,
+  recent_tracks: Mutex<RecentTracks>,
+  /// Tracks dropped from `recent_tracks` since the server started
+  evicted: AtomicU64,
+  /// Dead entries removed from `loaded_tracks` since the server started
+  purged: AtomicU64,
+  scheduler: Arc<BlockingScheduler>,
+  tag_config: TagConfig,
+  queue_config: QueueConfig,
+  /// `QueueConfig::allowed_roots` with symlinks resolved, so they can be compared with cannonical paths
+  allowed_roots: Vec<PathBuf>,
+}
+
+/// Resolves `.` and `..` without touching the filesystem, for paths that can't be cannonicalized
+fn normalize_lexically(path: &Path) -> PathBuf {
+  let path = path::absolute(path).unwrap_or_else(|_| path.to_owned());
+  let mut normalized = PathBuf::new();
+
+  for component in path.components() {
+    match component {
+      Component::ParentDir => {
+        normalized.pop();
+      }
+      Component::CurDir => (),
+      component => normalized.push(component),
+    }
+  }
+
+  normalized
 }
 
 impl TrackCache {
-  pub fn new() -> Self {
+  pub fn new(
+    scheduler: Arc<BlockingScheduler>,
+    tag_config: TagConfig,
+    queue_config: QueueConfig,
+  ) -> Self {
+    let allowed_roots = queue_config
+      .allowed_roots
+      .iter()
+      .map(|root| std::fs::canonicalize(root).unwrap_or_else(|_| normalize_lexically(root)))
+      .collect();
+
     Self {
       loaded_tracks: DashMap::new(),
+      recent_tracks: Mutex::new(RecentTracks::new(queue_config.max_cached_tracks)),
+      evicted: AtomicU64::new(0),
+      purged: AtomicU64::new(0),
+      scheduler,
+      tag_config,
+      queue_config,
+      allowed_roots,
+    }
+  }
+
+  fn in_allowed_roots(&self, cannonical_path: &Path) -> bool {
+    super::in_allowed_roots(&self.allowed_roots, cannonical_path)
+  }
+
+  /// Checks `path` against `QueueConfig::allowed_roots` after following symlinks, before anything is opened
+  ///
+  /// Paths that can't be resolved are checked as written,
+  /// so the error doesn't reveal whether a path outside of the roots exists
+  async fn check_allowed_roots(&self, path: &Path) -> bool {
+    if self.allowed_roots.is_empty() {
+      return true;
     }
+
+    let resolved = match fs::canonicalize(path).await {
+      Ok(cannonical_path) => cannonical_path,
+      Err(_) => normalize_lexically(path),
+    };
+
+    self.in_allowed_roots(&resolved)
+  }
+
+  /// Checks that `path` is not a filesystem root and is inside `QueueConfig::allowed_dirs`
+  async fn check_scan_allowed(&self, path: &Path) -> Result<(), ScanError> {
+    // Paths that can't be resolved are reported as load errors later
+    let Ok(cannonical_path) = super::get_cannonical_track_path(path).await else {
+      return Ok(());
+    };
+
+    if cannonical_path.parent().is_none() {
+      return Err(ScanError::FilesystemRoot(cannonical_path));
+    }
+
+    let allowed_dirs = &self.queue_config.allowed_dirs;
+    if !allowed_dirs.is_empty()
+      && !allowed_dirs
+        .iter()
+        .any(|dir| cannonical_path.starts_with(dir))
+    {
+      return Err(ScanError::NotAllowed(cannonical_path));
+    }
+
+    Ok(())
+  }
+
+  /// Counts a visited file or directory against `QueueConfig::max_scan_files`
+  fn visit(&self, progress: &mut ScanProgress) -> Result<(), ScanError> {
+    progress.files_visited += 1;
+
+    let max_files = self.queue_config.max_scan_files;
+    if progress.files_visited > max_files {
+      return Err(ScanError::TooManyFiles { max_files });
+    }
+
+    Ok(())
   }
 
   /// Does not search directories or cannonicalize paths, only provide cannonical paths to files
@@ -28,39 +234,97 @@ impl TrackCache {
     &self,
     path: PathBuf,
   ) -> Result<Arc<LoadedTrack>, (PathBuf, LoadTrackError)> {
-    let cannnonical_path = super::get_cannonical_track_path(&path)
+    let track_path = TrackPath::resolve(path.clone())
       .await
       .map_err(|error| (path.clone(), error))?;
 
-    let Some(track) = self
+    // The file may have been replaced with a symlink since the scan checked it
+    if !self.in_allowed_roots(&track_path.resolved) {
+      return Err((path, LoadTrackError::OutsideAllowedRoots));
+    }
+
+    let track = match self
       .loaded_tracks
-      .get(&cannnonical_path)
+      .get(&track_path.resolved)
       .and_then(|weak| weak.upgrade())
-    else {
-      let track = Arc::new(
-        super::load_file(cannnonical_path)
+    {
+      Some(track) => track,
+      None => {
+        let track = Arc::new(
+          super::load_file(
+            track_path,
+            &self.allowed_roots,
+            &self.scheduler,
+            Lane::Bulk,
+            &self.tag_config,
+          )
           .await
           .map_err(|error| (path, error))?,
-      );
-      self
-        .loaded_tracks
-        .insert(track.file_path().to_path_buf(), Arc::downgrade(&track));
-
-      return Ok(track);
+        );
+        self
+          .loaded_tracks
+          .insert(track.file_path().to_path_buf(), Arc::downgrade(&track));
+        track
+      }
     };
 
-    return Ok(track);
+    let evicted = self.recent_tracks().touch(&track);
+    self.evicted.fetch_add(evicted, Ordering::Relaxed);
+
+    Ok(track)
+  }
+
+  fn recent_tracks(&self) -> MutexGuard<'_, RecentTracks> {
+    self
+      .recent_tracks
+      .lock()
+      .expect("Recent tracks lock should not be poisoned")
+  }
+
+  /// Removes the entries of tracks that are no longer loaded, and returns how many were removed
+  pub fn evict_dead(&self) -> usize {
+    let mut purged = 0;
+    self.loaded_tracks.retain(|_, track| {
+      let is_live = track.strong_count() > 0;
+      purged += usize::from(!is_live);
+      is_live
+    });
+
+    self.purged.fetch_add(purged as u64, Ordering::Relaxed);
+    purged
+  }
+
+  pub fn stats(&self) -> CacheStats {
+    let recent_tracks = self.recent_tracks();
+
+    let live_sizes: Vec<usize> = self
+      .loaded_tracks
+      .iter()
+      .filter_map(|entry| entry.value().upgrade())
+      .map(|track| track.approx_size())
+      .collect();
+
+    CacheStats {
+      entries: self.loaded_tracks.len(),
+      live: live_sizes.len(),
+      live_bytes: live_sizes.iter().sum::<usize>() as u64,
+      retained: recent_tracks.tracks.len(),
+      max_retained: recent_tracks.max_len,
+      evicted: self.evicted.load(Ordering::Relaxed),
+      purged: self.purged.load(Ordering::Relaxed),
+    }
   }
 
   /// Sorts by title, then track number, then album
   /// Tracks without these will be sorted to the end
+  ///
+  /// Ties are ordered by path, so the order doesn't depend on the order the tracks finished loading in
   async fn sort_tracks(&self, tracks: &mut Tracks) {
     // Sort by title if available, othewise by file name
     fn get_track_title(track: &Arc<LoadedTrack>) -> String {
       track
         .metadata()
-        .title
-        .as_ref()
+        .title_or_inferred()
         .map(|s| s.to_lowercase())
         .or_else(|| {
           track
@@ -71,18 +335,30 @@ impl TrackCache {
         .unwrap_or("".into())
     }
 
+    tracks.sort_by(|track_a, track_b| track_a.file_path().cmp(track_b.file_path()));
     tracks.sort_by_key(|track| get_track_title(track));
-    tracks.sort_by_key(|track| track.metadata().track_number);
-    tracks.sort_by(|track_a, track_b| track_a.metadata().album.cmp(&track_b.metadata().album));
+    tracks.sort_by_key(|track| track.metadata().track_number_or_inferred());
+    tracks.sort_by(|track_a, track_b| {
+      let album_a = track_a.metadata().album_or_inferred();
+      album_a.cmp(&track_b.metadata().album_or_inferred())
+    });
   }
 
-  async fn search_directory(&self, path: PathBuf, outer_tracks: &mut Tracks, errors: &mut Errors) {
+  async fn search_directory(
+    &self,
+    path: PathBuf,
+    outer_tracks: &mut Tracks,
+    errors: &mut Errors,
+    progress: &mut ScanProgress<'_>,
+  ) -> Result<(), ScanError> {
     let mut tracks = Vec::new();
+    let mut files = Vec::new();
 
     let mut entries = match fs::read_dir(&path).await {
       Ok(files) => files,
       Err(error) => {
-        return errors.push((path, LoadTrackError::ReadDirFailed(error)));
+        errors.push((path, LoadTrackError::ReadDirFailed(error)));
+        return Ok(());
       }
     };
 
@@ -95,46 +371,445 @@ impl TrackCache {
         }
       };
 
-      Box::pin(self.search_file_or_directory(entry_path, &mut tracks, errors)).await;
+      match self.check_entry(entry_path, errors, progress).await? {
+        Some(ScanEntry::Directory(path)) => {
+          Box::pin(self.search_directory(path, &mut tracks, errors, progress)).await?;
+        }
+        // Playlists are only loaded when they are passed directly, the tracks next to them are already being loaded
+        Some(ScanEntry::File(path)) if playlist::is_playlist(&path) => (),
+        Some(ScanEntry::File(path)) => files.push(path),
+        None => (),
+      }
     }
 
+    // The files are only loaded once the directory has been listed, so they can be loaded together
+    tracks.extend(self.load_files(files, errors, progress).await);
+
     self.sort_tracks(&mut tracks).await;
     outer_tracks.extend(tracks);
+    Ok(())
   }
 
-  async fn search_file_or_directory(
+  /// Loads `paths` concurrently, at most `BlockingScheduler::bulk_limit` at a time
+  ///
+  /// The tracks are returned in the same order as `paths`
+  async fn load_files(
+    &self,
+    paths: Vec<PathBuf>,
+    errors: &mut Errors,
+    progress: &mut ScanProgress<'_>,
+  ) -> Tracks {
+    let permits = Semaphore::new(self.scheduler.bulk_limit());
+    // Only borrowed between awaits, the loads all run on this task
+    let progress = RefCell::new(progress);
+
+    let results = paths
+      .into_iter()
+      .map(|path| {
+        let (permits, progress) = (&permits, &progress);
+        async move {
+          let _permit = permits.acquire().await;
+          let result = self.get_or_load_track(path.clone()).await;
+
+          let mut progress = progress.borrow_mut();
+          match result.is_ok() {
+            true => progress.loaded += 1,
+            false => progress.errored += 1,
+          }
+          progress.report(&path);
+
+          result
+        }
+      })
+      .collect::<Vec<_>>()
+      .join()
+      .await;
+
+    let mut tracks = Vec::new();
+    for result in results {
+      match result {
+        Ok(track) => tracks.push(track),
+        Err(error) => errors.push(error),
+      }
+    }
+
+    tracks
+  }
+
+  /// Counts `path` as visited and checks that it can be scanned, errors for entries that are skipped are added to `errors`
+  async fn check_entry(
     &self,
     path: PathBuf,
+    errors: &mut Errors,
+    progress: &mut ScanProgress<'_>,
+  ) -> Result<Option<ScanEntry>, ScanError> {
+    self.visit(progress)?;
+
+    // Checked for every entry, since a symlink inside a root can point outside of it
+    if !self.check_allowed_roots(&path).await {
+      errors.push((path, LoadTrackError::OutsideAllowedRoots));
+      progress.errored += 1;
+      return Ok(None);
+    }
+
+    match fs::metadata(&path).await {
+      Ok(metadata) if metadata.is_dir() => Ok(Some(ScanEntry::Directory(path))),
+      Ok(_) => Ok(Some(ScanEntry::File(path))),
+      Err(error) => {
+        errors.push((
+          path.clone(),
+          LoadTrackError::OpenFailed {
+            path: TrackPath::new(path),
+            source: error,
+          },
+        ));
+        Ok(None)
+      }
+    }
+  }
+
+  /// Loads the entries of the playlist at `path` in playlist order, instead of sorting them like a directory
+  ///
+  /// Entries that are directories are searched, and entries that are playlists are reported as errors.
+  /// Each entry is checked like a path passed to `get_or_load_tracks`, so a playlist can't reach past `options`
+  async fn search_playlist(
+    &self,
+    path: PathBuf,
+    options: ScanOptions,
     tracks: &mut Tracks,
     errors: &mut Errors,
-  ) {
-    let metadata = match fs::metadata(&path).await {
-      Ok(metadata) => metadata,
+    progress: &mut ScanProgress<'_>,
+  ) -> Result<(), ScanError> {
+    let contents = match fs::read(&path).await {
+      Ok(contents) => contents,
       Err(error) => {
-        return errors.push((path, LoadTrackError::OpenFailed(error)));
+        errors.push((path, LoadTrackError::ReadPlaylistFailed(error)));
+        progress.errored += 1;
+        return Ok(());
       }
     };
+    // Older playlists are often not UTF-8, the entries that are still ASCII can be loaded
+    let contents = String::from_utf8_lossy(&contents);
+    let base_dir = path.parent().unwrap_or(Path::new("/"));
 
-    if metadata.is_dir() {
-      self.search_directory(path, tracks, errors).await;
-    } else {
-      match self.get_or_load_track(path).await {
-        Ok(track) => tracks.push(track),
-        Err(error) => errors.push(error),
+    let mut files = Vec::new();
+    for entry in playlist::parse_m3u(&contents, base_dir) {
+      let entry_path = match entry {
+        Ok(entry_path) => entry_path,
+        Err(entry) => {
+          errors.push((entry.into(), LoadTrackError::UnsupportedPlaylistEntry));
+          progress.errored += 1;
+          continue;
+        }
+      };
+
+      if !options.force {
+        self.check_scan_allowed(&entry_path).await?;
+      }
+
+      match self.check_entry(entry_path, errors, progress).await? {
+        Some(ScanEntry::File(entry_path)) if playlist::is_playlist(&entry_path) => {
+          errors.push((entry_path, LoadTrackError::NestedPlaylist));
+          progress.errored += 1;
+        }
+        Some(ScanEntry::File(entry_path)) => files.push(entry_path),
+        Some(ScanEntry::Directory(dir)) => {
+          // The files before the directory are loaded first, so they stay in playlist order
+          tracks.extend(
+            self
+              .load_files(mem::take(&mut files), errors, progress)
+              .await,
+          );
+          Box::pin(self.search_directory(dir, tracks, errors, progress)).await?;
+        }
+        None => (),
       }
     }
+
+    tracks.extend(self.load_files(files, errors, progress).await);
+    Ok(())
+  }
+
+  async fn search_file_or_directory(
+    &self,
+    path: PathBuf,
+    options: ScanOptions,
+    tracks: &mut Tracks,
+    errors: &mut Errors,
+    progress: &mut ScanProgress<'_>,
+  ) -> Result<(), ScanError> {
+    match self.check_entry(path, errors, progress).await? {
+      Some(ScanEntry::Directory(path)) => {
+        self
+          .search_directory(path, tracks, errors, progress)
+          .await?
+      }
+      Some(ScanEntry::File(path)) if playlist::is_playlist(&path) => {
+        self
+          .search_playlist(path, options, tracks, errors, progress)
+          .await?
+      }
+      Some(ScanEntry::File(path)) => {
+        tracks.extend(self.load_files(vec![path], errors, progress).await)
+      }
+      None => (),
+    }
+
+    Ok(())
   }
 
-  pub async fn get_or_load_tracks(&self, paths: Vec<PathBuf>) -> (Tracks, Errors) {
+  /// Returns an error instead of the tracks if one of the `QueueConfig` limits is reached
+  ///
+  /// `on_progress` is called a few times per second while tracks are loading
+  pub async fn get_or_load_tracks(
+    &self,
+    paths: Vec<PathBuf>,
+    options: ScanOptions,
+    on_progress: ProgressCallback<'_>,
+  ) -> Result<(Tracks, Errors), ScanError> {
+    for path in paths.iter() {
+      if !self.check_allowed_roots(path).await {
+        return Err(ScanError::OutsideAllowedRoots(path.clone()));
+      }
+
+      if !options.force {
+        self.check_scan_allowed(path).await?;
+      }
+    }
+
     let mut tracks = Vec::new();
     let mut errors = Vec::new();
+    let mut progress = ScanProgress::new(on_progress);
 
     for path in paths {
       self
-        .search_file_or_directory(path, &mut tracks, &mut errors)
-        .await
+        .search_file_or_directory(path, options, &mut tracks, &mut errors, &mut progress)
+        .await?
+    }
+
+    // Tracks dropped from the track list or the recent tracks since the last load leave dead entries behind
+    self.evict_dead();
+
+    Ok((tracks, errors))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{fs as sync_fs, time::Duration};
+
+  use super::*;
+  use crate::audio_server::player::tests::write_wav;
+
+  fn cache_with_allowed_dirs(allowed_dirs: Vec<PathBuf>) -> TrackCache {
+    TrackCache::new(
+      Arc::new(BlockingScheduler::new()),
+      TagConfig::default(),
+      QueueConfig {
+        allowed_dirs,
+        ..Default::default()
+      },
+    )
+  }
+
+  fn load(cache: &TrackCache, path: PathBuf, force: bool) -> Result<(Tracks, Errors), ScanError> {
+    smol::block_on(cache.get_or_load_tracks(vec![path], ScanOptions { force }, &mut |_, _, _| ()))
+  }
+
+  /// A library directory containing `playlist.m3u`, and a directory next to the library
+  fn library_with_playlist(contents: &str) -> (tempfile::TempDir, PathBuf, PathBuf) {
+    let dir = tempfile::tempdir().unwrap();
+    let dir_path = sync_fs::canonicalize(dir.path()).unwrap();
+    let library = dir_path.join("library");
+    sync_fs::create_dir(&library).unwrap();
+    sync_fs::create_dir(dir_path.join("outside")).unwrap();
+
+    let playlist = library.join("playlist.m3u");
+    sync_fs::write(&playlist, contents).unwrap();
+
+    (dir, library, playlist)
+  }
+
+  #[test]
+  fn playlist_directory_outside_allowed_dirs_is_refused() {
+    let (_dir, library, playlist) = library_with_playlist("../outside\n");
+    let cache = cache_with_allowed_dirs(vec![library]);
+
+    assert!(matches!(
+      load(&cache, playlist, false),
+      Err(ScanError::NotAllowed(_))
+    ));
+  }
+
+  #[test]
+  fn playlist_filesystem_root_is_refused() {
+    let (_dir, _library, playlist) = library_with_playlist("/\n");
+    let cache = cache_with_allowed_dirs(Vec::new());
+
+    assert!(matches!(
+      load(&cache, playlist, false),
+      Err(ScanError::FilesystemRoot(_))
+    ));
+  }
+
+  #[test]
+  fn forced_playlist_searches_directory_outside_allowed_dirs() {
+    let (_dir, library, playlist) = library_with_playlist("#EXTM3U\n../outside\n");
+    let cache = cache_with_allowed_dirs(vec![library]);
+
+    let (tracks, errors) = load(&cache, playlist, true).unwrap();
+    assert!(tracks.is_empty());
+    assert!(errors.is_empty());
+  }
+
+  #[test]
+  fn playlist_directory_inside_allowed_dirs_is_searched() {
+    let (_dir, library, playlist) = library_with_playlist("album\n");
+    sync_fs::create_dir(library.join("album")).unwrap();
+    let cache = cache_with_allowed_dirs(vec![library]);
+
+    let (tracks, errors) = load(&cache, playlist, false).unwrap();
+    assert!(tracks.is_empty());
+    assert!(errors.is_empty());
+  }
+
+  #[test]
+  fn loads_directory_tracks_in_a_stable_order() {
+    let dir = tempfile::tempdir().unwrap();
+    let dir_path = sync_fs::canonicalize(dir.path()).unwrap();
+    sync_fs::create_dir(dir_path.join("more")).unwrap();
+    for name in [
+      "d.wav",
+      "b.wav",
+      "more/a.wav",
+      "e.wav",
+      "a.wav",
+      "more/c.wav",
+    ] {
+      write_wav(&dir_path.join(name), Duration::from_millis(10));
     }
+    sync_fs::write(dir_path.join("notes.txt"), "not audio").unwrap();
+
+    let cache = cache_with_allowed_dirs(Vec::new());
+    let (tracks, errors) = load(&cache, dir_path.clone(), false).unwrap();
+
+    let names: Vec<_> = tracks
+      .iter()
+      .map(|track| track.file_path().strip_prefix(&dir_path).unwrap())
+      .collect();
+    assert_eq!(
+      names,
+      // Tracks with the same title are ordered by path
+      [
+        "a.wav",
+        "more/a.wav",
+        "b.wav",
+        "more/c.wav",
+        "d.wav",
+        "e.wav",
+      ]
+      .map(Path::new)
+    );
+
+    // The error stays with the file that failed, even though the files load together
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].0, dir_path.join("notes.txt"));
+  }
+
+  #[test]
+  fn reports_progress_for_every_file() {
+    let dir = tempfile::tempdir().unwrap();
+    for name in ["a.wav", "b.wav"] {
+      write_wav(&dir.path().join(name), Duration::from_millis(10));
+    }
+    sync_fs::write(dir.path().join("notes.txt"), "not audio").unwrap();
+
+    let cache = cache_with_allowed_dirs(Vec::new());
+    let mut on_progress = |_, _, _: &Path| ();
+    let mut progress = ScanProgress::new(&mut on_progress);
+    let mut tracks = Vec::new();
+    let mut errors = Vec::new();
+    smol::block_on(cache.search_directory(
+      dir.path().to_owned(),
+      &mut tracks,
+      &mut errors,
+      &mut progress,
+    ))
+    .unwrap();
+
+    assert_eq!((progress.loaded, progress.errored), (2, 1));
+    assert_eq!(progress.files_visited, 3);
+  }
+
+  /// A cache that keeps `max_cached_tracks` recently used tracks, and a directory with `names` in it
+  fn cache_with_tracks(
+    max_cached_tracks: usize,
+    names: &[&str],
+  ) -> (tempfile::TempDir, TrackCache) {
+    let dir = tempfile::tempdir().unwrap();
+    for name in names {
+      write_wav(&dir.path().join(name), Duration::from_millis(10));
+    }
+
+    let cache = TrackCache::new(
+      Arc::new(BlockingScheduler::new()),
+      TagConfig::default(),
+      QueueConfig {
+        max_cached_tracks,
+        ..Default::default()
+      },
+    );
+    (dir, cache)
+  }
+
+  fn retained_names(cache: &TrackCache) -> Vec<String> {
+    let mut names: Vec<String> = cache
+      .recent_tracks()
+      .tracks
+      .keys()
+      .map(|path| path.file_name().unwrap().to_string_lossy().into_owned())
+      .collect();
+    names.sort();
+    names
+  }
+
+  #[test]
+  fn keeps_the_most_recently_used_tracks() {
+    let (dir, cache) = cache_with_tracks(2, &["a.wav", "b.wav", "c.wav"]);
+    for name in ["a.wav", "b.wav", "a.wav", "c.wav"] {
+      load(&cache, dir.path().join(name), false).unwrap();
+    }
+
+    // `b.wav` was used least recently, since `a.wav` was added again after it
+    assert_eq!(retained_names(&cache), ["a.wav", "c.wav"]);
+    let stats = cache.stats();
+    assert_eq!((stats.retained, stats.max_retained), (2, 2));
+    assert_eq!(stats.evicted, 1);
+  }
+
+  #[test]
+  fn purges_entries_of_dropped_tracks() {
+    let (dir, cache) = cache_with_tracks(1, &["a.wav", "b.wav"]);
+    let (kept, _) = load(&cache, dir.path().join("a.wav"), false).unwrap();
+    // Pushes `a.wav` out of the recent tracks, only `kept` holds it now
+    load(&cache, dir.path().join("b.wav"), false).unwrap();
+
+    let stats = cache.stats();
+    assert_eq!((stats.entries, stats.live, stats.purged), (2, 2, 0));
+
+    drop(kept);
+    assert_eq!(cache.evict_dead(), 1);
+    let stats = cache.stats();
+    assert_eq!((stats.entries, stats.live, stats.purged), (1, 1, 1));
+  }
+
+  #[test]
+  fn reuses_loaded_tracks() {
+    let (dir, cache) = cache_with_tracks(0, &["a.wav"]);
+    let (first, _) = load(&cache, dir.path().join("a.wav"), false).unwrap();
+    let (second, _) = load(&cache, dir.path().join("a.wav"), false).unwrap();
 
-    (tracks, errors)
+    assert!(Arc::ptr_eq(&first[0], &second[0]));
+    assert_eq!(cache.stats().entries, 1);
   }
 }
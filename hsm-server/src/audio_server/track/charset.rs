@@ -0,0 +1,191 @@
+use encoding_rs::{Encoding, GBK, SHIFT_JIS, UTF_8, WINDOWS_1251};
+
+/// Symphonia decodes legacy tags (such as ID3v1) as Latin-1, so each char maps directly back to the original byte.
+///
+/// Returns `None` if the string contains chars that could not have come from Latin-1,
+/// or if it is plain ASCII and needs no repair
+fn latin1_bytes(value: &str) -> Option<Vec<u8>> {
+  if value.is_ascii() {
+    return None;
+  }
+
+  value
+    .chars()
+    .map(|c| u8::try_from(u32::from(c)).ok())
+    .collect()
+}
+
+fn is_cjk(c: char) -> bool {
+  matches!(c,
+    '\u{3000}'..='\u{30FF}' // CJK punctuation, hiragana, katakana
+    | '\u{3400}'..='\u{4DBF}' // CJK extension A
+    | '\u{4E00}'..='\u{9FFF}' // CJK unified ideographs
+    | '\u{FF00}'..='\u{FFEF}' // Halfwidth and fullwidth forms
+  )
+}
+
+fn is_cyrillic(c: char) -> bool {
+  matches!(c, '\u{0400}'..='\u{04FF}')
+}
+
+fn is_halfwidth_katakana(c: char) -> bool {
+  matches!(c, '\u{FF61}'..='\u{FF9F}')
+}
+
+/// If most of the two byte chars in the GBK `bytes` are in the first level of GB2312, which has the common chars
+///
+/// Cyrillic windows-1251 text is usually valid GBK too, but its lowercase letters are read as rare chars
+fn is_common_gbk(bytes: &[u8]) -> bool {
+  let (mut chars, mut common) = (0, 0);
+  let mut rest = bytes;
+
+  while let Some((&lead, after_lead)) = rest.split_first() {
+    if lead < 0x81 {
+      rest = after_lead;
+      continue;
+    }
+
+    chars += 1;
+    if (0xB0..=0xD7).contains(&lead) {
+      common += 1;
+    }
+    rest = after_lead.get(1..).unwrap_or_default();
+  }
+
+  common * 2 > chars
+}
+
+/// If the decoded text looks like it belongs to the `encoding`'s script
+fn is_plausible(encoding: &'static Encoding, bytes: &[u8], decoded: &str) -> bool {
+  if encoding == GBK && !is_common_gbk(bytes) {
+    return false;
+  }
+
+  let in_script = if encoding == WINDOWS_1251 {
+    is_cyrillic
+  } else {
+    is_cjk
+  };
+
+  // Latin-1 text with a few accents also decodes as valid text in these encodings, such as `Motörhead` in GBK,
+  // so require most of the letters to be in the script
+  let letters = decoded.chars().filter(|c| c.is_alphabetic()).count();
+  let in_script_count = decoded.chars().filter(|&c| in_script(c)).count();
+  // Pairs of accented Latin-1 letters are also halfwidth katakana in Shift-JIS, which tags rarely use on their own
+  let only_halfwidth = !decoded
+    .chars()
+    .any(|c| !c.is_ascii() && !is_halfwidth_katakana(c));
+
+  in_script_count * 2 > letters
+    && decoded.chars().filter(|c| !c.is_ascii()).all(in_script)
+    && !only_halfwidth
+}
+
+/// Attempts to recover a tag string that was decoded as Latin-1 but was actually in another charset.
+///
+/// Handles doubly-encoded UTF-8 as well as common legacy encodings (Shift-JIS, GBK, windows-1251).
+/// Returns the repaired string and the encoding it was decoded with,
+/// or `None` if the string does not look misencoded.
+pub fn repair_charset(value: &str) -> Option<(String, &'static Encoding)> {
+  let bytes = latin1_bytes(value)?;
+
+  if let Some(decoded) = UTF_8.decode_without_bom_handling_and_without_replacement(&bytes) {
+    return Some((decoded.into_owned(), UTF_8));
+  }
+
+  [SHIFT_JIS, GBK, WINDOWS_1251]
+    .into_iter()
+    .filter_map(|encoding| {
+      let decoded = encoding.decode_without_bom_handling_and_without_replacement(&bytes)?;
+      is_plausible(encoding, &bytes, &decoded).then(|| (decoded.into_owned(), encoding))
+    })
+    // Most GBK text is also valid Shift-JIS, read as halfwidth katakana mixed with kanji
+    .min_by_key(|(decoded, _)| {
+      decoded
+        .chars()
+        .filter(|&c| is_halfwidth_katakana(c))
+        .count()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// How symphonia decodes `bytes` from a legacy tag
+  fn as_latin1(bytes: &[u8]) -> String {
+    bytes.iter().copied().map(char::from).collect()
+  }
+
+  fn repaired(bytes: &[u8]) -> Option<(String, &'static str)> {
+    repair_charset(&as_latin1(bytes)).map(|(value, encoding)| (value, encoding.name()))
+  }
+
+  #[test]
+  fn repairs_mojibake() {
+    let cases: &[(&[u8], &str, &str)] = &[
+      // Doubly encoded UTF-8
+      (b"Caf\xc3\xa9", "Café", "UTF-8"),
+      (b"\xe6\x97\xa5\xe6\x9c\xac", "日本", "UTF-8"),
+      // Shift-JIS
+      (b"\x93\xfa\x96\x7b\x8c\xea", "日本語", "Shift_JIS"),
+      (
+        b"\x82\xb1\x82\xf1\x82\xc9\x82\xbf\x82\xcd",
+        "こんにちは",
+        "Shift_JIS",
+      ),
+      (b"\x83\x65\x83\x58\x83\x67 01", "テスト 01", "Shift_JIS"),
+      // GBK
+      (b"\xd2\xf4\xc0\xd6", "音乐", "GBK"),
+      (b"\xd6\xd0\xce\xc4", "中文", "GBK"),
+      (b"\xc4\xe3\xba\xc3 2", "你好 2", "GBK"),
+      // windows-1251
+      (b"\xcf\xf0\xe8\xe2\xe5\xf2", "Привет", "windows-1251"),
+      (
+        b"01. \xca\xe8\xed\xee - \xc3\xf0\xf3\xef\xef\xe0 \xea\xf0\xee\xe2\xe8",
+        "01. Кино - Группа крови",
+        "windows-1251",
+      ),
+      (
+        b"\xc3\xf0\xe0\xe6\xe4\xe0\xed\xf1\xea\xe0\xff \xce\xe1\xee\xf0\xee\xed\xe0",
+        "Гражданская Оборона",
+        "windows-1251",
+      ),
+    ];
+
+    for &(bytes, expected, encoding) in cases {
+      assert_eq!(
+        repaired(bytes),
+        Some((expected.to_owned(), encoding)),
+        "{:?}",
+        as_latin1(bytes)
+      );
+    }
+  }
+
+  #[test]
+  fn keeps_correct_text() {
+    let cases = [
+      "",
+      "Plain ASCII - Title (2024)",
+      "Café",
+      "Beyoncé",
+      "Motörhead",
+      "Sigur Rós",
+      "Ñandú",
+      "Æther Øresund",
+      "naïve façade",
+      "Déjà vu",
+      "Ça va été",
+      // Mostly Latin, so a cyrillic reading is not trusted
+      "Êèíî - Track 1",
+      // Already decoded correctly, and not from Latin-1
+      "日本語",
+      "Привет",
+    ];
+
+    for value in cases {
+      assert_eq!(repair_charset(value), None, "{value}");
+    }
+  }
+}
@@ -0,0 +1,124 @@
+use symphonia::core::{
+  codecs::CodecParameters,
+  meta::{Tag, Value},
+};
+
+/// Frames of encoder delay at the start of a track and padding at its end, which are not part of the audio
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GaplessTrim {
+  pub delay: u64,
+  pub padding: u64,
+  /// Symphonia reads the trim from MP3 LAME tags and removes it while decoding
+  pub applied_by_symphonia: bool,
+}
+
+impl GaplessTrim {
+  /// The trim symphonia found in the codec headers, if any
+  pub fn from_codec_params(codec_params: &CodecParameters) -> Option<Self> {
+    if codec_params.delay.is_none() && codec_params.padding.is_none() {
+      return None;
+    }
+
+    Some(Self {
+      delay: codec_params.delay.unwrap_or(0).into(),
+      padding: codec_params.padding.unwrap_or(0).into(),
+      applied_by_symphonia: true,
+    })
+  }
+
+  /// Reads iTunes' `iTunSMPB` tag from MP4 files or ID3 `TXXX` frames
+  ///
+  /// The value is a list of hex numbers, the second and third are the delay and padding
+  pub fn from_tag(tag: &Tag) -> Option<Self> {
+    if !tag.key.to_ascii_lowercase().ends_with("itunsmpb") {
+      return None;
+    }
+
+    let Value::String(value) = &tag.value else {
+      return None;
+    };
+
+    let mut fields = value
+      .split_whitespace()
+      .skip(1)
+      .map(|field| u64::from_str_radix(field, 16).ok());
+    let (Some(Some(delay)), Some(Some(padding))) = (fields.next(), fields.next()) else {
+      return None;
+    };
+
+    Some(Self {
+      delay,
+      padding,
+      applied_by_symphonia: false,
+    })
+  }
+
+  /// The trim `TrackDecoder` has to remove itself
+  pub fn unapplied(&self) -> Self {
+    if self.applied_by_symphonia {
+      Self::default()
+    } else {
+      *self
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use symphonia::core::meta::StandardTagKey;
+
+  use super::*;
+
+  const SMPB: &str = " 00000000 00000840 000001CA 0000000000A6B6F6 00000000 00000000";
+
+  fn tag(key: &str, value: &str) -> Tag {
+    Tag::new(None, key, Value::String(value.into()))
+  }
+
+  #[test]
+  fn reads_itunsmpb_tags() {
+    let expected = Some(GaplessTrim {
+      delay: 0x840,
+      padding: 0x1ca,
+      applied_by_symphonia: false,
+    });
+
+    for key in ["iTunSMPB", "----:com.apple.iTunes:iTunSMPB", "ITUNSMPB"] {
+      assert_eq!(GaplessTrim::from_tag(&tag(key, SMPB)), expected, "{key}");
+    }
+  }
+
+  #[test]
+  fn ignores_other_tags() {
+    let cases = [
+      tag("iTunNORM", SMPB),
+      tag("iTunSMPB", ""),
+      tag("iTunSMPB", " 00000000 00000840"),
+      tag("iTunSMPB", " 00000000 zz 000001CA"),
+      Tag::new(
+        Some(StandardTagKey::Comment),
+        "iTunSMPB",
+        Value::UnsignedInt(5),
+      ),
+    ];
+
+    for tag in cases {
+      assert_eq!(GaplessTrim::from_tag(&tag), None, "{tag:?}");
+    }
+  }
+
+  #[test]
+  fn reads_codec_params() {
+    let mut codec_params = CodecParameters::new();
+    assert_eq!(GaplessTrim::from_codec_params(&codec_params), None);
+
+    codec_params.with_delay(576);
+    let trim = GaplessTrim::from_codec_params(&codec_params).unwrap();
+    assert_eq!((trim.delay, trim.padding), (576, 0));
+    assert!(trim.applied_by_symphonia);
+    assert_eq!(trim.unapplied(), GaplessTrim::default());
+
+    let tagged = GaplessTrim::from_tag(&tag("iTunSMPB", SMPB)).unwrap();
+    assert_eq!(tagged.unapplied(), tagged);
+  }
+}
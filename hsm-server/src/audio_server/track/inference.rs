@@ -0,0 +1,292 @@
+use std::{
+  path::{Component, Path},
+  sync::LazyLock,
+};
+
+use hsm_ipc::InferredMetadata;
+use regex::{Captures, Regex};
+
+use crate::config::FilenamePattern;
+
+/// Built in patterns, matched against the file name without its extension
+static FILE_NAME_PATTERNS: LazyLock<Vec<Regex>> = LazyLock::new(|| {
+  [
+    // `01 - Artist - Title`
+    r"^(?<track>\d{1,3})\s*[-.]\s*(?<artist>.+?)\s+-\s+(?<title>.+)$",
+    // `01. Title`, `01 - Title`, `01 Title`
+    r"^(?<track>\d{1,3})(?:\s*[-.]\s*|\s+)(?<title>.+)$",
+    // `Artist - Title`
+    r"^(?<artist>.+?)\s+-\s+(?<title>.+)$",
+  ]
+  .iter()
+  .map(|pattern| Regex::new(pattern).expect("Built in filename patterns should be valid"))
+  .collect()
+});
+
+/// Directories such as `CD1` or `Disc 2`, which sit between the album directory and its tracks
+static DISC_DIR: LazyLock<Regex> = LazyLock::new(|| {
+  Regex::new(r"(?i)^(cd|disc|disk)\s*\d+$").expect("Disc directory pattern should be valid")
+});
+
+/// Directory names that are never an album or artist
+const GENERIC_DIRS: &[&str] = &[
+  "music",
+  "downloads",
+  "unsorted",
+  "misc",
+  "various",
+  "various artists",
+  "unknown",
+  "unknown album",
+  "unknown artist",
+  "home",
+  "mnt",
+  "media",
+];
+
+fn capture(captures: &Captures, name: &str) -> Option<String> {
+  captures
+    .name(name)
+    .map(|value| value.as_str().replace('_', " ").trim().to_owned())
+    .filter(|value| !value.is_empty())
+}
+
+fn from_captures(captures: &Captures) -> InferredMetadata {
+  InferredMetadata {
+    title: capture(captures, "title"),
+    artist: capture(captures, "artist"),
+    album: capture(captures, "album"),
+    track_number: capture(captures, "track").and_then(|track| track.parse().ok()),
+  }
+}
+
+fn is_plausible_dir(name: &str) -> bool {
+  let lowercase = name.to_lowercase();
+  !name.starts_with('.') && !GENERIC_DIRS.contains(&lowercase.as_str())
+}
+
+/// The names of the directories containing `path`, nearest first, skipping disc directories
+fn parent_dir_names(path: &Path) -> impl Iterator<Item = String> {
+  path
+    .parent()
+    .into_iter()
+    .flat_map(Path::components)
+    .rev()
+    .filter_map(|component| match component {
+      Component::Normal(name) => Some(name.to_string_lossy().into_owned()),
+      _ => None,
+    })
+    .filter(|name| !DISC_DIR.is_match(name))
+}
+
+/// Guesses metadata from the name of a file without tags
+///
+/// Patterns from the config are matched against the whole path without its extension and are tried first.
+/// When a built in pattern finds a track number, the parent directories are used as the album and artist,
+/// as in `Artist/Album/01 Title.flac`
+pub fn infer_metadata(path: &Path, patterns: &[FilenamePattern]) -> InferredMetadata {
+  let path_without_extension = path.with_extension("");
+  let path_without_extension = path_without_extension.to_string_lossy();
+
+  for pattern in patterns {
+    if let Some(captures) = pattern.0.captures(&path_without_extension) {
+      return from_captures(&captures);
+    }
+  }
+
+  let Some(file_stem) = path.file_stem().map(|stem| stem.to_string_lossy()) else {
+    return InferredMetadata::default();
+  };
+
+  let mut inferred = FILE_NAME_PATTERNS
+    .iter()
+    .find_map(|pattern| pattern.captures(&file_stem))
+    .map(|captures| from_captures(&captures))
+    .unwrap_or_else(|| InferredMetadata {
+      title: Some(file_stem.replace('_', " ").trim().to_owned()).filter(|title| !title.is_empty()),
+      ..Default::default()
+    });
+
+  // Loose files without a track number are rarely sorted into album directories
+  if inferred.track_number.is_some() {
+    let mut dirs = parent_dir_names(path).take_while(|name| is_plausible_dir(name));
+
+    if let Some(album) = dirs.next() {
+      inferred.album = Some(album);
+
+      if let Some(artist) = dirs.next() {
+        inferred.artist.get_or_insert(artist);
+      }
+    }
+  }
+
+  inferred
+}
+
+#[cfg(test)]
+mod tests {
+  use regex::Regex;
+
+  use super::*;
+
+  fn inferred(
+    title: Option<&str>,
+    artist: Option<&str>,
+    album: Option<&str>,
+    track_number: Option<usize>,
+  ) -> InferredMetadata {
+    InferredMetadata {
+      title: title.map(str::to_owned),
+      artist: artist.map(str::to_owned),
+      album: album.map(str::to_owned),
+      track_number,
+    }
+  }
+
+  fn patterns(patterns: &[&str]) -> Vec<FilenamePattern> {
+    patterns
+      .iter()
+      .map(|pattern| FilenamePattern(Regex::new(pattern).unwrap()))
+      .collect()
+  }
+
+  #[test]
+  fn built_in_patterns() {
+    let cases = [
+      (
+        "/music/Artist/Album/01 - Other Artist - Title.flac",
+        inferred(Some("Title"), Some("Other Artist"), Some("Album"), Some(1)),
+      ),
+      (
+        "/music/Artist/Album/01. Title.flac",
+        inferred(Some("Title"), Some("Artist"), Some("Album"), Some(1)),
+      ),
+      (
+        "/music/Artist/Album/02 - Title.flac",
+        inferred(Some("Title"), Some("Artist"), Some("Album"), Some(2)),
+      ),
+      (
+        "/music/Artist/Album/CD2/03 Title.flac",
+        inferred(Some("Title"), Some("Artist"), Some("Album"), Some(3)),
+      ),
+      (
+        "/music/Artist/Album/Disc 1/04 Title.flac",
+        inferred(Some("Title"), Some("Artist"), Some("Album"), Some(4)),
+      ),
+      (
+        "/home/user/Downloads/Artist - Some_Title.mp3",
+        inferred(Some("Some Title"), Some("Artist"), None, None),
+      ),
+    ];
+
+    for (path, expected) in cases {
+      assert_eq!(infer_metadata(Path::new(path), &[]), expected, "{path}");
+    }
+  }
+
+  #[test]
+  fn stops_at_generic_and_hidden_directories() {
+    let cases = [
+      (
+        "/music/Album/01 Title.flac",
+        inferred(Some("Title"), None, Some("Album"), Some(1)),
+      ),
+      (
+        "/music/01 Title.flac",
+        inferred(Some("Title"), None, None, Some(1)),
+      ),
+      (
+        "/Various Artists/Album/01 Title.flac",
+        inferred(Some("Title"), None, Some("Album"), Some(1)),
+      ),
+      (
+        "/data/.cache/01 Title.flac",
+        inferred(Some("Title"), None, None, Some(1)),
+      ),
+      (
+        "01 Title.flac",
+        inferred(Some("Title"), None, None, Some(1)),
+      ),
+    ];
+
+    for (path, expected) in cases {
+      assert_eq!(infer_metadata(Path::new(path), &[]), expected, "{path}");
+    }
+  }
+
+  #[test]
+  fn no_pattern_matches() {
+    let cases = [
+      (
+        "/music/Artist/Album/Title.flac",
+        inferred(Some("Title"), None, None, None),
+      ),
+      (
+        "/music/Artist/Album/1234 Title.flac",
+        inferred(Some("1234 Title"), None, None, None),
+      ),
+      (
+        "/music/Artist/Album/01_Title.flac",
+        inferred(Some("01 Title"), None, None, None),
+      ),
+      (
+        "/music/Artist-Title.flac",
+        inferred(Some("Artist-Title"), None, None, None),
+      ),
+      (
+        "/music/_Title_.flac",
+        inferred(Some("Title"), None, None, None),
+      ),
+      ("/music/_.flac", InferredMetadata::default()),
+      ("/", InferredMetadata::default()),
+    ];
+
+    for (path, expected) in cases {
+      assert_eq!(infer_metadata(Path::new(path), &[]), expected, "{path}");
+    }
+  }
+
+  #[test]
+  fn config_patterns_come_first() {
+    let patterns = patterns(&[
+      r"/(?<artist>[^/]+)/[^/]+/(?<track>\d+) - (?<title>[^/]+)$",
+      r"/(?<album>[^/]+)/(?<title>[^/]+) \[(?<track>\d+)\]$",
+    ]);
+
+    let cases = [
+      // Only what the pattern captures is used, not the parent directories
+      (
+        "/music/Artist/Album/01 - Title.flac",
+        inferred(Some("Title"), Some("Artist"), None, Some(1)),
+      ),
+      // The first pattern doesn't match, so the second one is used
+      (
+        "/music/Album/Some_Title [05].flac",
+        inferred(Some("Some Title"), None, Some("Album"), Some(5)),
+      ),
+      // No pattern from the config matches, so the built in ones are used
+      (
+        "/music/Artist/Album/01. Title.flac",
+        inferred(Some("Title"), Some("Artist"), Some("Album"), Some(1)),
+      ),
+    ];
+
+    for (path, expected) in cases {
+      assert_eq!(
+        infer_metadata(Path::new(path), &patterns),
+        expected,
+        "{path}"
+      );
+    }
+  }
+
+  #[test]
+  fn empty_captures_are_missing() {
+    let patterns = patterns(&[r"/(?<artist>[^/]*)_(?<title>[^/]*)$"]);
+
+    assert_eq!(
+      infer_metadata(Path::new("/music/_Title.flac"), &patterns),
+      inferred(Some("Title"), None, None, None)
+    );
+  }
+}
@@ -1,12 +1,14 @@
 use std::{
-  fs::File as SyncFile,
-  path::{Path, PathBuf},
+  fs::{self, File as SyncFile},
+  io,
+  path::PathBuf,
+  time::Duration,
 };
 
-use hsm_ipc::{Track, TrackMetadata};
+use hsm_ipc::{CharsetRepair, Track, TrackMetadata};
 use symphonia::core::{
   audio::SignalSpec,
-  codecs::{CODEC_TYPE_NULL, Decoder, DecoderOptions},
+  codecs::{CODEC_TYPE_NULL, CodecParameters, Decoder, DecoderOptions},
   errors::Error as SymphoniaError,
   formats::{FormatOptions, FormatReader},
   io::MediaSourceStream,
@@ -14,14 +16,56 @@ use symphonia::core::{
   probe::{Hint, ProbeResult},
 };
 
-use super::{LoadTrackError, LoadedTrack};
+use super::{GaplessTrim, LoadTrackError, LoadedTrack, TrackPath, art, charset, inference, lyrics};
+use crate::{
+  audio_server::blocking::{BlockingScheduler, Lane},
+  config::TagConfig,
+};
+
+/// Opens `path.resolved`, resolving `path.requested` again if the file is not found
+///
+/// The file may have been moved or atomically replaced since the path was resolved, so it is retried once.
+/// The new path is checked against `allowed_roots` before it is opened, as it may lead anywhere
+fn open_track_sync(
+  path: &mut TrackPath,
+  allowed_roots: &[PathBuf],
+) -> Result<SyncFile, LoadTrackError> {
+  match SyncFile::open(&path.resolved) {
+    Err(error) if error.kind() == io::ErrorKind::NotFound => {
+      path.resolved =
+        fs::canonicalize(&path.requested).map_err(|source| LoadTrackError::OpenFailed {
+          path: path.clone(),
+          source,
+        })?;
+
+      if !super::in_allowed_roots(allowed_roots, &path.resolved) {
+        return Err(LoadTrackError::OutsideAllowedRoots);
+      }
+
+      SyncFile::open(&path.resolved).map_err(|source| LoadTrackError::OpenFailed {
+        path: path.clone(),
+        source,
+      })
+    }
+    result => result.map_err(|source| LoadTrackError::OpenFailed {
+      path: path.clone(),
+      source,
+    }),
+  }
+}
 
 /// Use the default symphonia probe and the path's extension as a `Hint`
 ///
-/// This function is synchronous, so it must be called inside of `smol::unblock`
-pub fn probe_track_sync(path: &Path) -> Result<ProbeResult, LoadTrackError> {
+/// `path.resolved` is updated if the file had to be resolved again, which is only allowed inside of `allowed_roots`.
+/// This function is synchronous, so it must be called inside of `BlockingScheduler::unblock`
+pub fn probe_track_sync(
+  path: &mut TrackPath,
+  allowed_roots: &[PathBuf],
+) -> Result<ProbeResult, LoadTrackError> {
+  let src = open_track_sync(path, allowed_roots)?;
+
   let mut hint = Hint::new();
-  if let Some(extension) = path.extension().and_then(|s| s.to_str()) {
+  if let Some(extension) = path.resolved.extension().and_then(|s| s.to_str()) {
     hint.with_extension(extension);
   };
 
@@ -32,7 +76,6 @@ pub fn probe_track_sync(path: &Path) -> Result<ProbeResult, LoadTrackError> {
     ..Default::default()
   };
 
-  let src = SyncFile::open(path).map_err(LoadTrackError::OpenFailed)?;
   let mss = MediaSourceStream::new(Box::new(src), Default::default());
   let probed = symphonia::default::get_probe()
     .format(&hint, mss, &fmt_opts, &meta_opts)
@@ -41,15 +84,28 @@ pub fn probe_track_sync(path: &Path) -> Result<ProbeResult, LoadTrackError> {
   Ok(probed)
 }
 
+/// Packets of the selected track that are tried before giving up on decoding the first frame
+const FIRST_FRAME_ATTEMPTS: usize = 16;
+
+/// Why the first frame could not be decoded
+enum FirstFrameError {
+  /// The decoder rejected every packet it was given, but the file may still play past them
+  Rejected(SymphoniaError),
+  /// No packet could be read
+  ReadFailed(SymphoniaError),
+}
+
 fn decode_first_frame_sync<'f, 'd>(
   format: &'f mut Box<dyn FormatReader>,
   decoder: &'d mut Box<dyn Decoder>,
   track_id: u32,
-) -> Result<SignalSpec, LoadTrackError> {
+) -> Result<SignalSpec, FirstFrameError> {
+  let mut attempts = 0;
+
   let decoded = loop {
     let current_span = match format.next_packet() {
       Ok(packet) => packet,
-      Err(error) => return Err(LoadTrackError::DecodingFailed(error)),
+      Err(error) => return Err(FirstFrameError::ReadFailed(error)),
     };
 
     // If the packet does not belong to the selected track, skip over it
@@ -57,38 +113,104 @@ fn decode_first_frame_sync<'f, 'd>(
       continue;
     }
 
+    attempts += 1;
     match decoder.decode(&current_span) {
       Ok(decoded) => break decoded,
-      Err(error) => match error {
-        SymphoniaError::DecodeError(_) => {
-          // Decode errors are intentionally ignored with no retry limit.
-          // This behavior ensures that the decoder skips over problematic packets
-          // and continues processing the rest of the stream.
-          continue;
+      // Skip over damaged packets like the playback decoder does, but only for so long
+      Err(error @ (SymphoniaError::DecodeError(_) | SymphoniaError::Unsupported(_))) => {
+        if attempts >= FIRST_FRAME_ATTEMPTS {
+          return Err(FirstFrameError::Rejected(error));
         }
-        _ => return Err(LoadTrackError::DecodingFailed(error)),
-      },
+      }
+      Err(error) => return Err(FirstFrameError::ReadFailed(error)),
     }
   };
 
   return Ok(decoded.spec().clone());
 }
 
-pub fn add_tag_to_metadata(metadata: &mut TrackMetadata, tag: &Tag) {
+/// The spec the container reports, used when no frame could be decoded
+fn spec_from_codec_params(codec_params: &CodecParameters) -> Option<SignalSpec> {
+  Some(SignalSpec::new(
+    codec_params.sample_rate?,
+    codec_params.channels?,
+  ))
+}
+
+/// Repairs strings that were decoded with the wrong charset if enabled in the `config`
+fn decode_tag_string(metadata: &mut TrackMetadata, value: &str, config: &TagConfig) -> String {
+  if !config.detect_charset {
+    return value.into();
+  }
+
+  let Some((repaired, encoding)) = charset::repair_charset(value) else {
+    return value.into();
+  };
+
+  metadata.charset_repairs.push(CharsetRepair {
+    original: value.into(),
+    repaired: repaired.clone(),
+    encoding: encoding.name().into(),
+  });
+
+  repaired
+}
+
+/// Parses ReplayGain values such as `-6.54 dB` or `0.988547`
+fn parse_replay_gain(value: &Value) -> Option<f32> {
+  let gain = match value {
+    Value::Float(gain) => *gain as f32,
+    Value::String(gain) => gain
+      .trim()
+      .trim_end_matches(|c: char| c.is_ascii_alphabetic())
+      .trim_end()
+      .parse()
+      .ok()?,
+    _ => return None,
+  };
+
+  gain.is_finite().then_some(gain)
+}
+
+/// Tag keys that contain unsynchronized lyrics in formats where symphonia doesn't map them to `StandardTagKey::Lyrics`
+const RAW_LYRICS_KEYS: &[&str] = &["USLT", "LYRICS", "UNSYNCEDLYRICS"];
+
+fn add_lyrics_to_metadata(metadata: &mut TrackMetadata, value: &Value, config: &TagConfig) {
+  if let Value::String(lyrics) = value {
+    let lyrics = decode_tag_string(metadata, lyrics, config);
+    metadata.lyrics = Some(lyrics::cap_lyrics(lyrics));
+  }
+}
+
+pub fn add_tag_to_metadata(metadata: &mut TrackMetadata, tag: &Tag, config: &TagConfig) {
   match tag.std_key {
+    Some(StandardTagKey::Lyrics) => add_lyrics_to_metadata(metadata, &tag.value, config),
+    None
+      if RAW_LYRICS_KEYS
+        .iter()
+        .any(|key| tag.key.eq_ignore_ascii_case(key)) =>
+    {
+      add_lyrics_to_metadata(metadata, &tag.value, config)
+    }
     Some(StandardTagKey::TrackTitle) => {
       if let Value::String(title) = &tag.value {
-        metadata.title = Some(title.into());
+        metadata.title = Some(decode_tag_string(metadata, title, config));
       }
     }
     Some(StandardTagKey::Artist) => {
       if let Value::String(artist) = &tag.value {
-        metadata.artists.insert(artist.into());
+        let artist = decode_tag_string(metadata, artist, config);
+        metadata.add_artist(artist);
       }
     }
     Some(StandardTagKey::Album) => {
       if let Value::String(album) = &tag.value {
-        metadata.album = Some(album.into());
+        metadata.album = Some(decode_tag_string(metadata, album, config));
+      }
+    }
+    Some(StandardTagKey::AlbumArtist) => {
+      if let Value::String(album_artist) = &tag.value {
+        metadata.album_artist = Some(decode_tag_string(metadata, album_artist, config));
       }
     }
     Some(StandardTagKey::TrackNumber) => {
@@ -107,7 +229,7 @@ pub fn add_tag_to_metadata(metadata: &mut TrackMetadata, tag: &Tag) {
     }
     Some(StandardTagKey::Genre) => {
       if let Value::String(genre) = &tag.value {
-        metadata.genres.insert(genre.into());
+        metadata.add_genre(genre.into());
       }
     }
     Some(StandardTagKey::Comment) => {
@@ -115,75 +237,304 @@ pub fn add_tag_to_metadata(metadata: &mut TrackMetadata, tag: &Tag) {
         metadata.comments.push(comment.into());
       }
     }
+    Some(StandardTagKey::ReplayGainTrackGain) => {
+      metadata.replay_gain.track_gain = parse_replay_gain(&tag.value);
+    }
+    Some(StandardTagKey::ReplayGainTrackPeak) => {
+      metadata.replay_gain.track_peak = parse_replay_gain(&tag.value);
+    }
+    Some(StandardTagKey::ReplayGainAlbumGain) => {
+      metadata.replay_gain.album_gain = parse_replay_gain(&tag.value);
+    }
+    Some(StandardTagKey::ReplayGainAlbumPeak) => {
+      metadata.replay_gain.album_peak = parse_replay_gain(&tag.value);
+    }
     _ => (),
   }
 }
 
-fn update_metadata(metadata: &mut TrackMetadata, metadata_log: &mut Metadata) {
+/// Returns the `GaplessTrim` from an `iTunSMPB` tag, if there is one
+fn update_metadata(
+  metadata: &mut TrackMetadata,
+  metadata_log: &mut Metadata,
+  config: &TagConfig,
+) -> Option<GaplessTrim> {
+  let mut trim = None;
+
   loop {
     let Some(revision) = metadata_log.current() else {
-      return;
+      return trim;
     };
 
     for tag in revision.tags() {
-      add_tag_to_metadata(metadata, tag);
+      add_tag_to_metadata(metadata, tag, config);
+      trim = GaplessTrim::from_tag(tag).or(trim);
+    }
+
+    if metadata.art_path.is_none() {
+      metadata.art_path = art::front_cover(revision.visuals()).and_then(art::cache_visual_sync);
     }
 
     if !metadata_log.is_latest() {
       metadata_log.pop();
     } else {
-      break;
+      return trim;
     }
   }
 }
 
 /// Load a `Track` from a specified file path
 /// This will attempt to decode the first audio packet to ensure a correct `AudioSpec`
-pub async fn load_file(path: PathBuf) -> Result<LoadedTrack, LoadTrackError> {
-  let outer_path = path.clone();
+///
+/// If the first packets are damaged, the spec from the container is used instead,
+/// and playback skips over them or fails like any other broken track
+///
+/// The track's `file_path` is `path.resolved`, or the path the file was found at if it moved while loading
+pub async fn load_file(
+  mut path: TrackPath,
+  allowed_roots: &[PathBuf],
+  scheduler: &BlockingScheduler,
+  lane: Lane,
+  config: &TagConfig,
+) -> Result<LoadedTrack, LoadTrackError> {
+  let config = config.clone();
+  let allowed_roots = allowed_roots.to_vec();
+
+  let (file_path, total_duration, (spec, spec_verified), metadata, trim) = scheduler
+    .unblock(lane, move || {
+      let mut probed = probe_track_sync(&mut path, &allowed_roots)?;
 
-  let (total_duration, spec, metadata) = smol::unblock(move || {
-    let mut probed = probe_track_sync(&path)?;
+      let audio_track = probed
+        .format
+        .tracks()
+        .iter()
+        .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
+        .ok_or(LoadTrackError::CodecNotSupported)?;
+      let track_id = audio_track.id;
 
-    let audio_track = probed
-      .format
-      .tracks()
-      .iter()
-      .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
-      .ok_or(LoadTrackError::CodecNotSupported)?;
-    let track_id = audio_track.id;
+      let codec_params = &audio_track.codec_params;
 
-    let codec_params = &audio_track.codec_params;
+      let total_duration: Option<Duration> = codec_params
+        .time_base
+        .zip(codec_params.n_frames)
+        .map(|(base, spans)| base.calc_time(spans).into());
+      let codec_trim = GaplessTrim::from_codec_params(codec_params);
+      let container_spec = spec_from_codec_params(codec_params);
 
-    let total_duration = codec_params
-      .time_base
-      .zip(codec_params.n_frames)
-      .map(|(base, spans)| base.calc_time(spans).into());
+      let mut decoder = symphonia::default::get_codecs()
+        .make(&audio_track.codec_params, &DecoderOptions::default())
+        .map_err(|_| LoadTrackError::CodecNotSupported)?;
 
-    let mut decoder = symphonia::default::get_codecs()
-      .make(&audio_track.codec_params, &DecoderOptions::default())
-      .map_err(|_| LoadTrackError::CodecNotSupported)?;
+      let spec = match decode_first_frame_sync(&mut probed.format, &mut decoder, track_id) {
+        Ok(spec) => (spec, true),
+        Err(FirstFrameError::Rejected(error)) => match container_spec {
+          Some(spec) => {
+            eprintln!(
+              "Could not decode the start of {:?}, loading it anyway: {error}",
+              path.resolved
+            );
+            (spec, false)
+          }
+          None => return Err(LoadTrackError::DecodingFailed(error)),
+        },
+        Err(FirstFrameError::ReadFailed(error)) => {
+          return Err(LoadTrackError::DecodingFailed(error));
+        }
+      };
 
-    let spec = decode_first_frame_sync(&mut probed.format, &mut decoder, track_id)?;
+      let mut track_metadata = TrackMetadata::default();
 
-    let mut track_metadata = Default::default();
+      let mut tag_trim = None;
+      if let Some(mut metadata) = probed.metadata.get() {
+        tag_trim = update_metadata(&mut track_metadata, &mut metadata, &config);
+      }
 
-    if let Some(mut metadata) = probed.metadata.get() {
-      update_metadata(&mut track_metadata, &mut metadata)
-    }
+      tag_trim =
+        update_metadata(&mut track_metadata, &mut probed.format.metadata(), &config).or(tag_trim);
 
-    update_metadata(&mut track_metadata, &mut probed.format.metadata());
+      // Symphonia's frame count already leaves out the trim it applies itself
+      let trim = codec_trim.or(tag_trim).unwrap_or_default();
+      let unapplied = trim.unapplied();
+      let total_duration = total_duration.map(|duration| {
+        let trimmed_frames = unapplied.delay + unapplied.padding;
+        duration.saturating_sub(Duration::from_secs_f64(
+          trimmed_frames as f64 / spec.0.rate.max(1) as f64,
+        ))
+      });
 
-    Ok((total_duration, spec, track_metadata))
-  })
-  .await?;
+      // Symphonia doesn't return the pictures of some FLAC files
+      if track_metadata.art_path.is_none() {
+        track_metadata.art_path = art::read_flac_picture_sync(&path.resolved)
+          .and_then(|picture| art::cache_picture_sync(&picture));
+      }
+
+      if track_metadata.art_path.is_none() {
+        track_metadata.art_path = art::find_sidecar_art_sync(&path.resolved);
+      }
+
+      if track_metadata.title.is_none() {
+        track_metadata.inferred =
+          inference::infer_metadata(&path.resolved, &config.filename_patterns);
+      }
+
+      Ok((path.resolved, total_duration, spec, track_metadata, trim))
+    })
+    .await?;
 
   Ok(LoadedTrack {
     inner: Track {
-      file_path: outer_path,
+      file_path,
       total_duration,
       metadata,
     },
     spec,
+    spec_verified,
+    trim,
   })
 }
+
+#[cfg(test)]
+mod tests {
+  use std::{fs, os::unix::fs::symlink, path::Path, time::Duration};
+
+  use super::*;
+  use crate::audio_server::player::tests::write_wav;
+
+  /// A `TrackPath` whose resolved path has since been removed
+  fn moved_path(requested: &Path, dir: &Path) -> TrackPath {
+    TrackPath {
+      requested: requested.to_owned(),
+      resolved: dir.join("removed.flac"),
+    }
+  }
+
+  #[test]
+  fn opens_resolved_path() {
+    let dir = tempfile::tempdir().unwrap();
+    let file = dir.path().join("track.flac");
+    fs::write(&file, b"audio").unwrap();
+
+    let mut path = TrackPath::new(file.clone());
+    assert!(open_track_sync(&mut path, &[]).is_ok());
+    assert_eq!(path.resolved, file);
+  }
+
+  #[test]
+  fn retries_moved_file_inside_roots() {
+    let root = tempfile::tempdir().unwrap();
+    let root_path = fs::canonicalize(root.path()).unwrap();
+    let file = root_path.join("track.flac");
+    fs::write(&file, b"audio").unwrap();
+
+    let mut path = moved_path(&file, &root_path);
+    assert!(open_track_sync(&mut path, &[root_path]).is_ok());
+    assert_eq!(path.resolved, file);
+  }
+
+  #[test]
+  fn retry_rejects_symlink_outside_roots() {
+    let root = tempfile::tempdir().unwrap();
+    let outside = tempfile::tempdir().unwrap();
+    let root_path = fs::canonicalize(root.path()).unwrap();
+
+    let secret = outside.path().join("secret.flac");
+    fs::write(&secret, b"audio").unwrap();
+    let link = root_path.join("link.flac");
+    symlink(&secret, &link).unwrap();
+
+    let mut path = moved_path(&link, &root_path);
+    assert!(matches!(
+      open_track_sync(&mut path, &[root_path]),
+      Err(LoadTrackError::OutsideAllowedRoots)
+    ));
+  }
+
+  #[test]
+  fn retry_rejects_parent_dir_outside_roots() {
+    let parent = tempfile::tempdir().unwrap();
+    let parent_path = fs::canonicalize(parent.path()).unwrap();
+    let root_path = parent_path.join("root");
+    fs::create_dir(&root_path).unwrap();
+    fs::write(parent_path.join("secret.flac"), b"audio").unwrap();
+
+    let mut path = moved_path(&root_path.join("../secret.flac"), &root_path);
+    assert!(matches!(
+      open_track_sync(&mut path, &[root_path]),
+      Err(LoadTrackError::OutsideAllowedRoots)
+    ));
+  }
+
+  #[test]
+  fn retry_follows_symlink_without_roots() {
+    let dir = tempfile::tempdir().unwrap();
+    let dir_path = fs::canonicalize(dir.path()).unwrap();
+    let file = dir_path.join("track.flac");
+    fs::write(&file, b"audio").unwrap();
+    let link = dir_path.join("link.flac");
+    symlink(&file, &link).unwrap();
+
+    let mut path = moved_path(&link, &dir_path);
+    assert!(open_track_sync(&mut path, &[]).is_ok());
+    assert_eq!(path.resolved, file);
+  }
+
+  #[test]
+  fn missing_file_is_not_found() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut path = moved_path(&dir.path().join("missing.flac"), dir.path());
+
+    let error = open_track_sync(&mut path, &[]).unwrap_err();
+    assert!(matches!(error, LoadTrackError::OpenFailed { .. }));
+    assert_eq!(error.kind(), hsm_ipc::LoadTrackErrorKind::NotFound);
+  }
+
+  #[test]
+  fn keeps_artists_and_genres_in_tag_order() {
+    let tags = [
+      (StandardTagKey::Artist, "Zed"),
+      (StandardTagKey::Genre, "Rock"),
+      (StandardTagKey::Artist, "Abba"),
+      (StandardTagKey::Artist, "Zed"),
+      (StandardTagKey::Genre, "Pop"),
+      (StandardTagKey::Genre, "Rock"),
+    ];
+
+    let mut metadata = TrackMetadata::default();
+    for (key, value) in tags {
+      let tag = Tag::new(Some(key), "", Value::String(value.into()));
+      add_tag_to_metadata(&mut metadata, &tag, &TagConfig::default());
+    }
+
+    assert_eq!(metadata.artists, ["Zed", "Abba"]);
+    assert_eq!(metadata.genres, ["Rock", "Pop"]);
+  }
+
+  #[test]
+  fn loads_file_replaced_after_resolving() {
+    let dir = tempfile::tempdir().unwrap();
+    let dir_path = fs::canonicalize(dir.path()).unwrap();
+    let (old, new) = (dir_path.join("old.wav"), dir_path.join("new.wav"));
+    write_wav(&old, Duration::from_millis(10));
+    write_wav(&new, Duration::from_millis(10));
+    let link = dir_path.join("current.wav");
+    symlink(&old, &link).unwrap();
+
+    let path = smol::block_on(TrackPath::resolve(link.clone())).unwrap();
+    assert_eq!(path.resolved, old);
+
+    // Replaced like a sync tool would, between resolving and loading
+    fs::remove_file(&old).unwrap();
+    fs::remove_file(&link).unwrap();
+    symlink(&new, &link).unwrap();
+
+    let track = smol::block_on(load_file(
+      path,
+      &[],
+      &BlockingScheduler::new(),
+      Lane::Interactive,
+      &TagConfig::default(),
+    ))
+    .unwrap();
+    assert_eq!(track.file_path(), new);
+  }
+}
@@ -0,0 +1,132 @@
+use std::{path::Path, time::Duration};
+
+use hsm_ipc::Track;
+use smol::fs;
+
+/// Lyrics longer than this are cut off, so a broken tag can't fill the track cache
+pub const MAX_LYRICS_LEN: usize = 64 * 1024;
+
+/// Cuts `lyrics` down to `MAX_LYRICS_LEN` bytes without splitting a character
+pub fn cap_lyrics(mut lyrics: String) -> String {
+  if lyrics.len() > MAX_LYRICS_LEN {
+    let mut end = MAX_LYRICS_LEN;
+    while !lyrics.is_char_boundary(end) {
+      end -= 1;
+    }
+
+    lyrics.truncate(end);
+  }
+
+  lyrics
+}
+
+/// Splits the leading `[...]` tags off of an lrc line
+fn split_lrc_tags(line: &str) -> (Vec<&str>, &str) {
+  let mut tags = Vec::new();
+  let mut rest = line.trim();
+
+  while let Some(tag) = rest.strip_prefix('[') {
+    let Some(end) = tag.find(']') else {
+      break;
+    };
+
+    tags.push(&tag[..end]);
+    rest = tag[end + 1..].trim_start();
+  }
+
+  (tags, rest)
+}
+
+/// Parses an lrc timestamp in the form `mm:ss`, `mm:ss.xx` or `mm:ss:xx`
+fn parse_lrc_timestamp(tag: &str) -> Option<Duration> {
+  let (minutes, seconds) = tag.split_once(':')?;
+  let minutes: u64 = minutes.parse().ok()?;
+  let seconds: f64 = seconds.replacen(':', ".", 1).parse().ok()?;
+
+  Some(Duration::from_secs(minutes * 60) + Duration::try_from_secs_f64(seconds).ok()?)
+}
+
+/// Parses the timestamped lines of an lrc file, sorted by time
+///
+/// A line with several timestamps is repeated at each of them, and the `[offset:ms]` tag is applied.
+fn parse_lrc(lrc: &str) -> Vec<(Duration, String)> {
+  let mut lines = Vec::new();
+  // Positive offsets make lyrics appear sooner
+  let mut offset_ms: i64 = 0;
+
+  for line in lrc.lines() {
+    let (tags, text) = split_lrc_tags(line);
+
+    for tag in tags {
+      if let Some(timestamp) = parse_lrc_timestamp(tag) {
+        lines.push((timestamp, text.to_string()));
+      } else if let Some(offset) = tag.strip_prefix("offset:") {
+        offset_ms = offset.trim().parse().unwrap_or(0);
+      }
+    }
+  }
+
+  let offset = Duration::from_millis(offset_ms.unsigned_abs());
+  for (time, _) in lines.iter_mut() {
+    *time = if offset_ms >= 0 {
+      time.saturating_sub(offset)
+    } else {
+      *time + offset
+    };
+  }
+
+  lines.sort_by_key(|(time, _)| *time);
+  lines
+}
+
+/// Removes the `[mm:ss.xx]` timestamps from each line of an lrc file
+///
+/// Lines that only contain id tags such as `[ar:Artist]` are removed.
+fn strip_lrc(lrc: &str) -> String {
+  let mut lines = Vec::new();
+
+  for line in lrc.lines() {
+    let (tags, text) = split_lrc_tags(line);
+    let has_timestamp = tags.iter().any(|tag| parse_lrc_timestamp(tag).is_some());
+
+    if !tags.is_empty() && !has_timestamp {
+      continue;
+    }
+
+    lines.push(text);
+  }
+
+  lines.join("\n").trim().into()
+}
+
+async fn read_sidecar_lrc(track_path: &Path) -> Option<String> {
+  let lrc_path = track_path.with_extension("lrc");
+  fs::read_to_string(&lrc_path).await.ok().map(cap_lyrics)
+}
+
+/// Reads the lyrics from an lrc file with the same name as the track, if there is one
+pub async fn read_sidecar_lyrics(track_path: &Path) -> Option<String> {
+  let lyrics = strip_lrc(&read_sidecar_lrc(track_path).await?);
+  if lyrics.is_empty() {
+    return None;
+  }
+
+  Some(lyrics)
+}
+
+/// Timestamped lyrics from the track's lyrics tag if it is in lrc format, otherwise from a sidecar lrc file
+pub async fn read_synced_lyrics(track: &Track) -> Option<Vec<(Duration, String)>> {
+  if let Some(lyrics) = &track.metadata.lyrics {
+    let lines = parse_lrc(lyrics);
+    if !lines.is_empty() {
+      return Some(lines);
+    }
+  }
+
+  let lines = parse_lrc(&read_sidecar_lrc(&track.file_path).await?);
+  if lines.is_empty() {
+    return None;
+  }
+
+  Some(lines)
+}
@@ -0,0 +1,146 @@
+use std::{mem, path::Path};
+
+use hsm_ipc::{Track, TrackListUpdate, TrackMetadata};
+
+fn optional_string_size(string: &Option<String>) -> usize {
+  string.as_deref().map_or(0, str::len)
+}
+
+fn strings_size(strings: &[String]) -> usize {
+  strings
+    .iter()
+    .map(|string| mem::size_of::<String>() + string.len())
+    .sum()
+}
+
+fn path_size(path: &Path) -> usize {
+  path.as_os_str().len()
+}
+
+/// Bytes used by the heap allocations of `metadata`
+fn metadata_heap_size(metadata: &TrackMetadata) -> usize {
+  let repairs: usize = metadata
+    .charset_repairs
+    .iter()
+    .map(|repair| {
+      mem::size_of_val(repair)
+        + repair.original.len()
+        + repair.repaired.len()
+        + repair.encoding.len()
+    })
+    .sum();
+
+  optional_string_size(&metadata.title)
+    + strings_size(&metadata.artists)
+    + optional_string_size(&metadata.album)
+    + optional_string_size(&metadata.album_artist)
+    + optional_string_size(&metadata.date)
+    + strings_size(&metadata.genres)
+    + strings_size(&metadata.comments)
+    + optional_string_size(&metadata.lyrics)
+    + repairs
+    + optional_string_size(&metadata.inferred.title)
+    + optional_string_size(&metadata.inferred.artist)
+    + optional_string_size(&metadata.inferred.album)
+    + metadata.art_path.as_deref().map_or(0, path_size)
+}
+
+/// Bytes used by the heap allocations of `track`
+pub fn track_heap_size(track: &Track) -> usize {
+  path_size(&track.file_path) + metadata_heap_size(&track.metadata)
+}
+
+/// Estimates the memory used by `track`, for stats and for bounding buffers by size
+///
+/// Only counts the data itself, not allocator overhead or spare capacity
+pub fn track_size(track: &Track) -> usize {
+  mem::size_of::<Track>() + track_heap_size(track)
+}
+
+fn tracks_size(tracks: &[Track]) -> usize {
+  tracks.iter().map(track_size).sum()
+}
+
+/// Bytes used by an update kept in the track list's history
+pub fn update_size(update: &TrackListUpdate) -> usize {
+  let indicies_size = |indicies: &[usize]| mem::size_of_val(indicies);
+
+  let heap_size = match update {
+    TrackListUpdate::Insert {
+      tracks,
+      new_shuffle_indicies,
+      ..
+    } => tracks_size(tracks) + indicies_size(new_shuffle_indicies),
+    TrackListUpdate::Remove {
+      removed_indicies,
+      new_shuffle_indicies,
+    } => indicies_size(removed_indicies) + indicies_size(new_shuffle_indicies),
+    TrackListUpdate::Replace(snapshot) => {
+      tracks_size(&snapshot.track_list)
+        + indicies_size(&snapshot.shuffle_indicies)
+        + mem::size_of_val(snapshot.instances.as_slice())
+    }
+    TrackListUpdate::Clear => 0,
+    TrackListUpdate::Shuffle {
+      new_shuffle_indicies,
+    } => indicies_size(new_shuffle_indicies),
+    TrackListUpdate::Reorder {
+      new_order,
+      new_shuffle_indicies,
+    } => indicies_size(new_order) + indicies_size(new_shuffle_indicies),
+  };
+
+  mem::size_of::<TrackListUpdate>() + heap_size
+}
+
+#[cfg(test)]
+mod tests {
+  use std::path::PathBuf;
+
+  use super::*;
+
+  fn track(file_path: &str, metadata: TrackMetadata) -> Track {
+    Track {
+      file_path: PathBuf::from(file_path),
+      total_duration: None,
+      metadata,
+    }
+  }
+
+  #[test]
+  fn counts_track_strings() {
+    let empty = track("", TrackMetadata::default());
+    assert_eq!(track_size(&empty), mem::size_of::<Track>());
+
+    let mut metadata = TrackMetadata {
+      title: Some("Title".into()),
+      artists: vec!["A".into(), "BC".into()],
+      ..Default::default()
+    };
+    metadata.inferred.album = Some("Album".into());
+    let tagged = track("/a.flac", metadata);
+
+    let strings =
+      "/a.flac".len() + "Title".len() + 2 * mem::size_of::<String>() + 3 + "Album".len();
+    assert_eq!(track_size(&tagged), mem::size_of::<Track>() + strings);
+  }
+
+  #[test]
+  fn counts_update_tracks_and_indicies() {
+    let tracks = vec![track("/a.flac", TrackMetadata::default())];
+    let insert = TrackListUpdate::Insert {
+      index: 0,
+      tracks: tracks.clone(),
+      new_shuffle_indicies: vec![0, 1, 2],
+    };
+
+    assert_eq!(
+      update_size(&insert),
+      mem::size_of::<TrackListUpdate>() + tracks_size(&tracks) + 3 * mem::size_of::<usize>()
+    );
+    assert_eq!(
+      update_size(&TrackListUpdate::Clear),
+      mem::size_of::<TrackListUpdate>()
+    );
+  }
+}
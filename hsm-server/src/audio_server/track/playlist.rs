@@ -0,0 +1,166 @@
+use std::{
+  ffi::OsStr,
+  os::unix::ffi::OsStrExt,
+  path::{Path, PathBuf},
+};
+
+/// Extensions of playlist files, compared ignoring case
+const PLAYLIST_EXTENSIONS: &[&str] = &["m3u", "m3u8"];
+
+pub fn is_playlist(path: &Path) -> bool {
+  path
+    .extension()
+    .and_then(OsStr::to_str)
+    .is_some_and(|extension| {
+      PLAYLIST_EXTENSIONS
+        .iter()
+        .any(|playlist_extension| extension.eq_ignore_ascii_case(playlist_extension))
+    })
+}
+
+/// The entries of an M3U playlist in the order they are listed, relative paths are resolved against `base_dir`
+///
+/// Entries that aren't paths or `file://` URIs are returned as errors, as they were written
+pub fn parse_m3u(contents: &str, base_dir: &Path) -> Vec<Result<PathBuf, String>> {
+  contents
+    .trim_start_matches('\u{feff}')
+    .lines()
+    .map(str::trim)
+    // Directives such as `#EXTM3U` and `#EXTINF` are comments to players that don't use them
+    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+    .map(|line| parse_entry(line, base_dir).ok_or_else(|| line.to_owned()))
+    .collect()
+}
+
+fn parse_entry(line: &str, base_dir: &Path) -> Option<PathBuf> {
+  let Some((scheme, rest)) = line.split_once("://") else {
+    // Absolute paths replace `base_dir`
+    return Some(base_dir.join(line));
+  };
+
+  let is_scheme = !scheme.is_empty()
+    && scheme
+      .chars()
+      .all(|char| char.is_ascii_alphanumeric() || matches!(char, '+' | '-' | '.'));
+  if !is_scheme {
+    return Some(base_dir.join(line));
+  }
+
+  if !scheme.eq_ignore_ascii_case("file") {
+    return None;
+  }
+
+  // `file:///music/a.flac` and `file://localhost/music/a.flac`
+  let path = rest.strip_prefix("localhost").unwrap_or(rest);
+  if !path.starts_with('/') {
+    return None;
+  }
+
+  // Invalid escapes are kept as written, like the file URIs MPRIS clients send
+  let path = urlencoding::decode_binary(path.as_bytes());
+  Some(PathBuf::from(OsStr::from_bytes(&path)))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const BASE_DIR: &str = "/music/playlists";
+
+  fn parse(contents: &str) -> Vec<Result<PathBuf, String>> {
+    parse_m3u(contents, Path::new(BASE_DIR))
+  }
+
+  #[test]
+  fn detects_playlist_extensions() {
+    let cases = [
+      ("mix.m3u", true),
+      ("mix.m3u8", true),
+      ("MIX.M3U", true),
+      ("mix.flac", false),
+      ("m3u", false),
+      ("mix.m3u.bak", false),
+    ];
+
+    for (path, expected) in cases {
+      assert_eq!(is_playlist(Path::new(path)), expected, "{path}");
+    }
+  }
+
+  #[test]
+  fn skips_comments_and_blank_lines() {
+    let contents =
+      "\u{feff}#EXTM3U\n\n#EXTINF:123,Artist - Title\n  a.flac  \n\r\n# comment\nb.flac\r\n";
+
+    assert_eq!(
+      parse(contents),
+      vec![
+        Ok(PathBuf::from("/music/playlists/a.flac")),
+        Ok(PathBuf::from("/music/playlists/b.flac")),
+      ]
+    );
+  }
+
+  #[test]
+  fn resolves_relative_paths() {
+    let cases = [
+      ("a.flac", "/music/playlists/a.flac"),
+      ("album/a.flac", "/music/playlists/album/a.flac"),
+      ("../album/a.flac", "/music/playlists/../album/a.flac"),
+      ("/other/a.flac", "/other/a.flac"),
+      // Not a URI scheme, so it's a file name
+      ("a b://c.flac", "/music/playlists/a b://c.flac"),
+    ];
+
+    for (line, expected) in cases {
+      assert_eq!(parse(line), vec![Ok(PathBuf::from(expected))], "{line}");
+    }
+  }
+
+  #[test]
+  fn decodes_file_uris() {
+    let cases = [
+      ("file:///music/a.flac", "/music/a.flac"),
+      ("FILE:///music/a.flac", "/music/a.flac"),
+      ("file://localhost/music/a.flac", "/music/a.flac"),
+      ("file:///music/A%20B%2Bc.flac", "/music/A B+c.flac"),
+      ("file:///music/caf%C3%A9.flac", "/music/café.flac"),
+    ];
+
+    for (line, expected) in cases {
+      assert_eq!(parse(line), vec![Ok(PathBuf::from(expected))], "{line}");
+    }
+  }
+
+  #[test]
+  fn keeps_non_utf8_escapes() {
+    let path = parse("file:///music/%FF.flac").remove(0).unwrap();
+    assert_eq!(path.as_os_str().as_bytes(), b"/music/\xff.flac");
+  }
+
+  #[test]
+  fn keeps_bad_escapes_as_written() {
+    let cases = [
+      ("file:///music/100%.flac", "/music/100%.flac"),
+      ("file:///music/%zz.flac", "/music/%zz.flac"),
+      ("file:///music/a%2", "/music/a%2"),
+    ];
+
+    for (line, expected) in cases {
+      assert_eq!(parse(line), vec![Ok(PathBuf::from(expected))], "{line}");
+    }
+  }
+
+  #[test]
+  fn rejects_unsupported_entries() {
+    let cases = [
+      "http://example.com/stream.mp3",
+      "https://example.com/a.flac",
+      "file://server/music/a.flac",
+    ];
+
+    for line in cases {
+      assert_eq!(parse(line), vec![Err(line.to_owned())], "{line}");
+    }
+  }
+}
@@ -0,0 +1,340 @@
+use std::{
+  env, fs, io,
+  path::{Path, PathBuf},
+};
+
+use hsm_ipc::NormalizationMode;
+use regex::Regex;
+use serde::{Deserialize, Deserializer, de};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+  #[error("Failed to read config file {path:?}: {source}")]
+  ReadFailed { path: PathBuf, source: io::Error },
+
+  #[error("Failed to parse config file {path:?}: {source}")]
+  ParseFailed {
+    path: PathBuf,
+    source: toml::de::Error,
+  },
+}
+
+/// `hsm-server` configuration, read from `$XDG_CONFIG_HOME/homeslashmusic/config.toml`
+///
+/// Every field has a default, so the file and any of its tables may be omitted
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+  pub player: PlayerConfig,
+  pub queue: QueueConfig,
+  pub tags: TagConfig,
+  pub ipc: IpcConfig,
+  pub mpris: MprisConfig,
+  pub statusfile: StatusFileConfig,
+}
+
+/// Options for playback
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PlayerConfig {
+  /// Seconds before the end of a track to send the `TrackEnding` event, 0 to disable
+  pub track_ending_notice: f64,
+  /// Seconds of audio decoded ahead of playback, 0 to decode on the audio output thread
+  pub decode_ahead: f64,
+  /// Resample tracks that don't match the output's sample rate with a windowed-sinc filter, which uses more cpu
+  pub high_quality_resampling: bool,
+  /// The normalization mode when the server starts, `Off`, `Track` or `Album`
+  pub normalization: NormalizationMode,
+  /// Gain in dB applied to tracks without ReplayGain tags while normalization is enabled
+  pub normalization_fallback_gain: f32,
+  /// What the volume requests control
+  pub volume_backend: VolumeBackend,
+  /// What happens when the playing track is removed from the track list
+  pub removed_current: RemovedCurrent,
+  /// Which transitions between tracks are gapless instead of crossfading
+  pub gapless: GaplessPolicy,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VolumeBackend {
+  /// hsm amplifies its own audio
+  #[default]
+  Software,
+  /// hsm's stream in the system mixer, so desktop volume keys and `hsm volume` control the same level
+  System,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RemovedCurrent {
+  /// Keep playing the removed track until it ends, then play the entry that took its place
+  #[default]
+  Continue,
+  /// Skip to the entry that took its place right away
+  Skip,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GaplessPolicy {
+  /// Every transition is gapless
+  Always,
+  /// Every transition crossfades
+  Never,
+  /// Consecutive tracks of the same album are gapless, so live albums and mixes flow into each other
+  #[default]
+  Auto,
+}
+
+impl Default for PlayerConfig {
+  fn default() -> Self {
+    Self {
+      track_ending_notice: 5.0,
+      decode_ahead: 0.25,
+      high_quality_resampling: false,
+      normalization: NormalizationMode::Off,
+      normalization_fallback_gain: 0.0,
+      volume_backend: VolumeBackend::Software,
+      removed_current: RemovedCurrent::Continue,
+      gapless: GaplessPolicy::Auto,
+    }
+  }
+}
+
+/// Limits that protect the server from accidentally queueing huge directories
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QueueConfig {
+  /// Maximum number of tracks in the track list
+  pub max_length: usize,
+  /// Maximum number of files and directories visited while loading tracks from one request
+  pub max_scan_files: usize,
+  /// If not empty, only paths inside these directories can be loaded without `force`
+  pub allowed_dirs: Vec<PathBuf>,
+  /// If not empty, no request can read files outside these directories, even with `force`
+  pub allowed_roots: Vec<PathBuf>,
+  /// Tracks kept loaded after they leave the track list, so adding them again doesn't read their tags
+  pub max_cached_tracks: usize,
+}
+
+impl Default for QueueConfig {
+  fn default() -> Self {
+    Self {
+      max_length: 50_000,
+      max_scan_files: 200_000,
+      allowed_dirs: Vec::new(),
+      allowed_roots: Vec::new(),
+      max_cached_tracks: 5_000,
+    }
+  }
+}
+
+/// Options for reading track metadata
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TagConfig {
+  /// Try to repair title, artist, and album tags that were decoded with the wrong character set
+  pub detect_charset: bool,
+  /// Regexes used to guess the metadata of tracks without a title tag, tried before the built in patterns
+  pub filename_patterns: Vec<FilenamePattern>,
+}
+
+/// A regex matched against a track's path without its extension,
+/// with `title`, `artist`, `album` and `track` named groups
+#[derive(Debug, Clone)]
+pub struct FilenamePattern(pub Regex);
+
+impl<'de> Deserialize<'de> for FilenamePattern {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    let pattern = String::deserialize(deserializer)?;
+    Regex::new(&pattern)
+      .map(FilenamePattern)
+      .map_err(de::Error::custom)
+  }
+}
+
+/// Options for the unix socket that `hsm` connects to
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IpcConfig {
+  /// Listen on this socket instead of `$HSM_SOCKET_PATH` or `$XDG_RUNTIME_DIR/homeslashmusic.sock`
+  pub socket_path: Option<PathBuf>,
+  /// Also listen on `homeslashmusic.ro.sock`, which only accepts queries
+  pub read_only_socket: bool,
+  /// Log requests that take at least this many seconds to handle, 0 to disable
+  pub slow_request_threshold: f64,
+}
+
+impl Default for IpcConfig {
+  fn default() -> Self {
+    Self {
+      socket_path: None,
+      read_only_socket: false,
+      slow_request_threshold: 0.25,
+    }
+  }
+}
+
+/// Options for the MPRIS d-bus interface
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MprisConfig {
+  /// Emit the `Seeked` signal every this many seconds while playing, for clients that rarely re-read the position
+  pub seeked_interval: Option<u64>,
+  /// Emit the `Seeked` signal when the current track changes
+  pub seeked_on_track_change: bool,
+}
+
+/// Options for the plugin that writes the now playing line and track list to files
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StatusFileConfig {
+  pub enabled: bool,
+  /// Format of the now playing line, such as `{artist} - {title|filename}`
+  pub format: String,
+}
+
+impl Default for StatusFileConfig {
+  fn default() -> Self {
+    Self {
+      enabled: false,
+      format: "{artist} - {title|filename}".into(),
+    }
+  }
+}
+
+impl Config {
+  pub fn config_dir() -> Option<PathBuf> {
+    env::var_os("XDG_CONFIG_HOME")
+      .map(PathBuf::from)
+      .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+      .map(|config_home| config_home.join("homeslashmusic"))
+  }
+
+  pub fn config_path() -> Option<PathBuf> {
+    Self::config_dir().map(|config_dir| config_dir.join("config.toml"))
+  }
+
+  /// Loads the config file, or the default config if it does not exist
+  pub fn load() -> Result<Self, ConfigError> {
+    let Some(path) = Self::config_path() else {
+      return Ok(Self::default());
+    };
+
+    Self::load_from(&path)
+  }
+
+  pub fn load_from(path: &Path) -> Result<Self, ConfigError> {
+    let config_data = match fs::read_to_string(path) {
+      Ok(config_data) => config_data,
+      Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+      Err(source) => {
+        return Err(ConfigError::ReadFailed {
+          path: path.into(),
+          source,
+        });
+      }
+    };
+
+    toml::from_str(&config_data).map_err(|source| ConfigError::ParseFailed {
+      path: path.into(),
+      source,
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn load(config_data: &str) -> Result<Config, ConfigError> {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("config.toml");
+    fs::write(&path, config_data).unwrap();
+    Config::load_from(&path)
+  }
+
+  #[test]
+  fn missing_file_uses_defaults() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = Config::load_from(&dir.path().join("config.toml")).unwrap();
+
+    assert_eq!(config.player.track_ending_notice, 5.0);
+    assert_eq!(config.queue.max_length, 50_000);
+    assert_eq!(config.ipc.slow_request_threshold, 0.25);
+    assert!(!config.statusfile.enabled);
+  }
+
+  #[test]
+  fn file_overrides_defaults() {
+    let config = load(
+      r#"
+      [player]
+      track_ending_notice = 0.0
+      gapless = "never"
+
+      [ipc]
+      socket_path = "/tmp/hsm.sock"
+      read_only_socket = true
+      slow_request_threshold = 1.0
+      "#,
+    )
+    .unwrap();
+
+    assert_eq!(config.player.track_ending_notice, 0.0);
+    assert_eq!(config.player.gapless, GaplessPolicy::Never);
+    assert_eq!(config.ipc.socket_path, Some(PathBuf::from("/tmp/hsm.sock")));
+    assert!(config.ipc.read_only_socket);
+    assert_eq!(config.ipc.slow_request_threshold, 1.0);
+  }
+
+  #[test]
+  fn partial_tables_keep_the_other_defaults() {
+    let config = load(
+      r#"
+      [player]
+      volume_backend = "system"
+
+      [queue]
+      max_length = 10
+
+      [statusfile]
+      enabled = true
+      "#,
+    )
+    .unwrap();
+
+    assert_eq!(config.player.volume_backend, VolumeBackend::System);
+    assert_eq!(config.player.track_ending_notice, 5.0);
+    assert_eq!(config.player.decode_ahead, 0.25);
+    assert_eq!(config.player.gapless, GaplessPolicy::Auto);
+
+    assert_eq!(config.queue.max_length, 10);
+    assert_eq!(config.queue.max_scan_files, 200_000);
+    assert_eq!(config.queue.max_cached_tracks, 5_000);
+
+    assert!(config.statusfile.enabled);
+    assert_eq!(config.statusfile.format, "{artist} - {title|filename}");
+
+    // Tables that were left out entirely
+    assert_eq!(config.ipc.slow_request_threshold, 0.25);
+    assert_eq!(config.mpris.seeked_interval, None);
+  }
+
+  #[test]
+  fn rejects_unknown_and_invalid_fields() {
+    for config_data in [
+      "[player]\ntrack_ending_notise = 1.0",
+      "[unknown]\nenabled = true",
+      "[player]\ngapless = \"sometimes\"",
+      "[tags]\nfilename_patterns = [\"(\"]",
+    ] {
+      assert!(
+        matches!(load(config_data), Err(ConfigError::ParseFailed { .. })),
+        "{config_data}"
+      );
+    }
+  }
+}
@@ -1,15 +1,32 @@
-use std::sync::Arc;
+use std::{process, sync::Arc, time::Duration};
 
 use audio_server::{AudioServer, AudioServerError};
-use futures_concurrency::future::Race;
-use hsm_plugin_ipc::IpcPlugin;
-use hsm_plugin_mpris::MprisPlugin;
-use plugin_manager::{PluginError, PluginManager, PluginRunner};
+use config::{Config, ConfigError};
+use futures_concurrency::future::{Race, TryJoin};
+use hsm_ipc::TestToneResult;
+use hsm_plugin::SharedPlayerState;
+#[cfg(feature = "hsm-plugin-ipc")]
+use hsm_plugin_ipc::{IpcOptions, IpcPlugin};
+#[cfg(feature = "hsm-plugin-mpris")]
+use hsm_plugin_mpris::{MprisOptions, MprisPlugin};
+#[cfg(feature = "hsm-plugin-statusfile")]
+use hsm_plugin_statusfile::{StatusFileOptions, StatusFilePlugin};
+#[cfg(feature = "example-plugin")]
+use hsm_plugin_template::{TemplateOptions, TemplatePlugin};
+#[cfg(any(
+  feature = "hsm-plugin-mpris",
+  feature = "hsm-plugin-ipc",
+  feature = "hsm-plugin-statusfile",
+  feature = "example-plugin"
+))]
+use plugin_manager::PluginRunner;
+use plugin_manager::{PluginError, PluginManager};
 use signals::{SignalHandler, SignalHandlerError};
 use smol::Executor;
 use thiserror::Error;
 
 mod audio_server;
+mod config;
 mod plugin_manager;
 mod signals;
 
@@ -23,46 +40,185 @@ pub enum MainError {
 
   #[error(transparent)]
   PluginError(#[from] PluginError),
+
+  #[error(transparent)]
+  ConfigError(#[from] ConfigError),
 }
 
 async fn run_servers(ex: &Arc<Executor<'static>>) -> Result<(), MainError> {
+  let config = Config::load()?;
   let mut signal_handler = SignalHandler::init()?;
 
-  let (plugin_manager, audio_server_channels) = PluginManager::new(ex.clone());
-  let audio_server = AudioServer::init(audio_server_channels);
+  let shared_state = Arc::new(SharedPlayerState::new());
+  let (plugin_manager, audio_server_channels) =
+    PluginManager::new(ex.clone(), shared_state.clone());
+  let audio_server = AudioServer::init(
+    audio_server_channels,
+    shared_state,
+    plugin_manager.registry(),
+    &config,
+  );
+
+  if std::env::args().skip(1).any(|arg| arg == "--no-restore") {
+    audio_server.forget_saved_state().await;
+  } else {
+    audio_server.restore_saved_state().await?;
+  }
 
   #[cfg(feature = "hsm-plugin-mpris")]
-  let mpris_server: PluginRunner<MprisPlugin<_>> = plugin_manager.load_plugin().await?;
+  let mpris_server: PluginRunner<MprisPlugin<_>> = plugin_manager
+    .load_plugin(
+      MprisOptions {
+        seeked_interval: config
+          .mpris
+          .seeked_interval
+          .filter(|secs| *secs > 0)
+          .map(std::time::Duration::from_secs),
+        seeked_on_track_change: config.mpris.seeked_on_track_change,
+      },
+      true,
+    )
+    .await?;
 
   #[cfg(feature = "hsm-plugin-ipc")]
-  let ipc_server: PluginRunner<IpcPlugin<_>> = plugin_manager.load_plugin().await?;
+  let ipc_server: PluginRunner<IpcPlugin<_>> = plugin_manager
+    .load_plugin(
+      IpcOptions {
+        socket_path: config.ipc.socket_path.clone(),
+        read_only_socket: config.ipc.read_only_socket,
+      },
+      true,
+    )
+    .await?;
 
-  let server_futures = (
-    async { audio_server.run().await.map_err(Into::into) },
-    async { plugin_manager.run().await.map_err(Into::into) },
+  #[cfg(feature = "hsm-plugin-statusfile")]
+  let statusfile_server: PluginRunner<StatusFilePlugin<_>> = plugin_manager
+    .load_plugin(
+      StatusFileOptions {
+        format: config.statusfile.format.clone(),
+      },
+      config.statusfile.enabled,
+    )
+    .await?;
+
+  #[cfg(feature = "example-plugin")]
+  let template_server: PluginRunner<TemplatePlugin<_>> = plugin_manager
+    .load_plugin(TemplateOptions::default(), true)
+    .await?;
+
+  // Plugins are given a chance to clean up before the server stops
+  let shutdown = async {
+    (
+      signal_handler.wait_for_quit(),
+      audio_server.wait_for_shutdown(),
+    )
+      .race()
+      .await;
+    audio_server.save_state().await;
+    println!("Shutting down plugins");
+    plugin_manager.shutdown();
+    Ok(())
+  };
+
+  let plugin_futures = (
     #[cfg(feature = "hsm-plugin-mpris")]
     async {
-      mpris_server.run().await.map_err(Into::into)
+      mpris_server.run().await.map_err(MainError::from)
     },
     #[cfg(feature = "hsm-plugin-ipc")]
     async {
-      ipc_server.run().await.map_err(Into::into)
+      ipc_server.run().await.map_err(MainError::from)
+    },
+    #[cfg(feature = "hsm-plugin-statusfile")]
+    async {
+      statusfile_server.run().await.map_err(MainError::from)
     },
+    #[cfg(feature = "example-plugin")]
     async {
-      signal_handler.wait_for_quit().await;
-      Ok(())
+      template_server.run().await.map_err(MainError::from)
     },
+    shutdown,
+  );
+
+  let server_futures = (
+    async { audio_server.run().await.map_err(Into::into) },
+    async { plugin_manager.run().await.map_err(Into::into) },
+    async { plugin_futures.try_join().await.map(|_| ()) },
   );
 
   server_futures.race().await
 }
 
+/// Plays a test tone through the player without plugins or the saved state, for checking a new audio setup
+async fn test_audio_output(ex: &Arc<Executor<'static>>) -> Result<TestToneResult, MainError> {
+  let config = Config::load()?;
+
+  let shared_state = Arc::new(SharedPlayerState::new());
+  let (plugin_manager, audio_server_channels) =
+    PluginManager::new(ex.clone(), shared_state.clone());
+  let audio_server = AudioServer::init(
+    audio_server_channels,
+    shared_state,
+    plugin_manager.registry(),
+    &config,
+  );
+
+  (
+    async {
+      Ok(
+        audio_server
+          .play_test_tone(440.0, Duration::from_secs(2))
+          .await?,
+      )
+    },
+    // The player only ends the tone while the server is running, and emits events through the plugin manager
+    async {
+      (
+        async { audio_server.run().await.map_err(MainError::from) },
+        async { plugin_manager.run().await.map_err(MainError::from) },
+      )
+        .race()
+        .await?;
+      std::future::pending().await
+    },
+  )
+    .race()
+    .await
+}
+
 fn main() {
+  #[cfg(feature = "schema")]
+  if std::env::args().skip(1).any(|arg| arg == "--dump-schema") {
+    println!("{}", hsm_ipc::schema().to_json());
+    return;
+  }
+
   let ex: Arc<Executor<'static>> = Arc::new(Executor::new());
-  match smol::block_on(ex.run(run_servers(&ex))) {
-    Ok(()) => (),
-    Err(error) => eprintln!("{error}"),
+
+  if std::env::args().skip(1).any(|arg| arg == "--test-audio") {
+    match smol::block_on(ex.run(test_audio_output(&ex))) {
+      Ok(result) if result.completed => println!("Audio output works"),
+      Ok(result) => {
+        eprintln!(
+          "The output device only played {:.2}s of the test tone",
+          result.played.as_secs_f64()
+        );
+        process::exit(1);
+      }
+      Err(error) => {
+        eprintln!("{error}");
+        process::exit(1);
+      }
+    }
+    return;
   }
 
+  let result = smol::block_on(ex.run(run_servers(&ex)));
   println!("hsm-server shutting down");
+
+  // A non-zero exit code lets a supervisor restart the server after an error
+  if let Err(error) = result {
+    eprintln!("{error}");
+    process::exit(1);
+  }
 }
@@ -1,9 +1,15 @@
-use std::sync::Arc;
+use std::{
+  mem,
+  sync::{
+    Arc,
+    atomic::{AtomicBool, Ordering},
+  },
+};
 
 use async_oneshot as oneshot;
 use futures_concurrency::future::Race;
-use hsm_ipc::Event;
-use hsm_plugin::Plugin;
+use hsm_ipc::{Event, requests, server::RequestOrigin};
+use hsm_plugin::{Plugin, RequestSender as _, SharedPlayerState};
 use smol::{
   Executor,
   channel::{self, Receiver, Sender},
@@ -20,7 +26,7 @@ pub enum PluginError {
   PluginError(Box<dyn std::error::Error>),
 }
 
-pub type RequestJson = (String, oneshot::Sender<String>);
+pub type RequestJson = (String, RequestOrigin, oneshot::Sender<String>);
 
 #[derive(Debug, Clone)]
 pub struct RequestSender {
@@ -28,10 +34,14 @@ pub struct RequestSender {
 }
 
 impl hsm_plugin::RequestSender for RequestSender {
-  async fn send_json(&self, request_data: String) -> String {
+  async fn send_json_from(&self, origin: RequestOrigin, request_data: String) -> String {
     let (reply_tx, reply_rx) = oneshot::oneshot();
 
-    if let Err(error) = self.request_data_tx.send((request_data, reply_tx)).await {
+    if let Err(error) = self
+      .request_data_tx
+      .send((request_data, origin, reply_tx))
+      .await
+    {
       return hsm_ipc::server::serialize_error(&error);
     }
 
@@ -41,60 +51,236 @@ impl hsm_plugin::RequestSender for RequestSender {
   }
 }
 
-pub struct PluginRunner<P> {
-  plugin: P,
-  event_rx: Receiver<Event>,
+/// Whether a plugin should be running, changed by `PluginRegistry::set_enabled`
+#[derive(Debug)]
+struct PluginSwitch {
+  name: &'static str,
+  enabled: AtomicBool,
+  changed_tx: Sender<()>,
+  changed_rx: Receiver<()>,
+}
+
+impl PluginSwitch {
+  fn new(name: &'static str, enabled: bool) -> Self {
+    let (changed_tx, changed_rx) = channel::bounded(1);
+
+    Self {
+      name,
+      enabled: AtomicBool::new(enabled),
+      changed_tx,
+      changed_rx,
+    }
+  }
+
+  fn enabled(&self) -> bool {
+    self.enabled.load(Ordering::Acquire)
+  }
+
+  fn set_enabled(&self, enabled: bool) {
+    self.enabled.store(enabled, Ordering::Release);
+    // If the channel is full, the runner will already check the new value
+    let _ = self.changed_tx.try_send(());
+  }
+
+  async fn wait_until(&self, enabled: bool) {
+    while self.enabled() != enabled {
+      // The switch holds the sender, so the channel can't close
+      let _ = self.changed_rx.recv().await;
+    }
+  }
+}
+
+/// The plugins loaded by a `PluginManager`, used to enable and disable them while the server is running
+#[derive(Debug, Default)]
+pub struct PluginRegistry {
+  switches: Mutex<Vec<Arc<PluginSwitch>>>,
 }
 
-impl<'ex, P: Plugin<'ex, RequestSender>> PluginRunner<P> {
+impl PluginRegistry {
+  /// The name of each plugin, and if it is enabled
+  pub async fn plugins(&self) -> Vec<(String, bool)> {
+    self
+      .switches
+      .lock()
+      .await
+      .iter()
+      .map(|switch| (switch.name.into(), switch.enabled()))
+      .collect()
+  }
+
+  /// Returns false if there is no plugin named `name`
+  pub async fn set_enabled(&self, name: &str, enabled: bool) -> bool {
+    let switches = self.switches.lock().await;
+    let Some(switch) = switches.iter().find(|switch| switch.name == name) else {
+      return false;
+    };
+
+    switch.set_enabled(enabled);
+    true
+  }
+
+  async fn register(&self, name: &'static str, enabled: bool) -> Arc<PluginSwitch> {
+    let switch = Arc::new(PluginSwitch::new(name, enabled));
+    self.switches.lock().await.push(switch.clone());
+    switch
+  }
+}
+
+/// Why a running plugin stopped
+enum Stop {
+  Finished,
+  Disabled,
+  Shutdown,
+}
+
+/// Supervises a plugin, dropping it when it is disabled and initializing it again when it is re-enabled
+pub struct PluginRunner<'m, 'ex, P: Plugin<'ex, RequestSender>> {
+  manager: &'m PluginManager<'ex>,
+  config: P::Config,
+  switch: Arc<PluginSwitch>,
+  /// Initialized by `PluginManager::load_plugin` if the plugin starts enabled, so startup errors are returned
+  loaded: Option<P>,
+}
+
+impl<'m, 'ex, P: Plugin<'ex, RequestSender>> PluginRunner<'m, 'ex, P>
+where
+  P::Config: Clone,
+{
   fn map_error(error: P::Error) -> PluginError {
     PluginError::PluginError(Box::new(error))
   }
 
-  async fn recieve_events(&self) -> Result<(), PluginError> {
+  async fn recieve_events(plugin: &P, event_rx: &Receiver<Event>) -> Result<(), PluginError> {
     loop {
-      let event = self
-        .event_rx
+      let event = event_rx
         .recv()
         .await
         .map_err(|_| PluginError::EventChannelClosed)?;
 
-      self.plugin.on_event(event).await.map_err(Self::map_error)?;
+      plugin.on_event(event).await.map_err(Self::map_error)?;
     }
   }
 
-  pub async fn run(&self) -> Result<(), PluginError> {
+  async fn run_plugin(plugin: &P, event_rx: &Receiver<Event>) -> Result<(), PluginError> {
     (
-      async { self.plugin.run().await.map_err(Self::map_error) },
-      self.recieve_events(),
+      async { plugin.run().await.map_err(Self::map_error) },
+      Self::recieve_events(plugin, event_rx),
     )
       .race()
       .await
   }
+
+  /// Returns once the plugin has shut down, after `PluginManager::shutdown` is called
+  pub async fn run(mut self) -> Result<(), PluginError> {
+    loop {
+      let Some(plugin) = self.loaded.take() else {
+        let enabled = (
+          async {
+            self.switch.wait_until(true).await;
+            true
+          },
+          async {
+            self.manager.wait_for_shutdown().await;
+            false
+          },
+        )
+          .race()
+          .await;
+
+        if !enabled {
+          return Ok(());
+        }
+
+        match self.manager.init_plugin::<P>(self.config.clone()).await {
+          Ok(loaded) => {
+            println!("Enabled plugin {}", P::NAME);
+            self.loaded = Some(loaded);
+          }
+          Err(error) => {
+            eprintln!("Could not enable plugin {}: {error}", P::NAME);
+            self.switch.set_enabled(false);
+          }
+        }
+
+        continue;
+      };
+
+      let event_rx = self.manager.subscribe(P::NAME).await;
+
+      let stop = (
+        async {
+          plugin.on_ready().await.map_err(Self::map_error)?;
+          Self::run_plugin(&plugin, &event_rx)
+            .await
+            .map(|()| Stop::Finished)
+        },
+        async {
+          self.switch.wait_until(false).await;
+          Ok(Stop::Disabled)
+        },
+        async {
+          self.manager.wait_for_shutdown().await;
+          Ok(Stop::Shutdown)
+        },
+      )
+        .race()
+        .await?;
+
+      if matches!(stop, Stop::Finished) {
+        return Ok(());
+      }
+
+      plugin.shutdown().await.map_err(Self::map_error)?;
+
+      if matches!(stop, Stop::Shutdown) {
+        println!("Shut down plugin {}", P::NAME);
+        return Ok(());
+      }
+
+      // Dropping the event receiver unsubscribes the plugin from events
+      mem::drop((plugin, event_rx));
+      println!("Disabled plugin {}", P::NAME);
+    }
+  }
 }
 
 #[derive(Debug)]
 pub struct PluginManager<'ex> {
   executor: Arc<Executor<'ex>>,
+  shared_state: Arc<SharedPlayerState>,
 
   request_data_tx: Sender<RequestJson>,
 
   event_rx: Receiver<Event>,
   event_broadcast_tx: Mutex<Vec<Sender<Event>>>,
+  registry: Arc<PluginRegistry>,
+
+  /// Closed to tell every `PluginRunner` to shut down its plugin
+  shutdown_tx: Sender<()>,
+  shutdown_rx: Receiver<()>,
 }
 
 impl<'ex> PluginManager<'ex> {
-  pub fn new(executor: Arc<Executor<'ex>>) -> (Self, (Receiver<RequestJson>, Sender<Event>)) {
+  pub fn new(
+    executor: Arc<Executor<'ex>>,
+    shared_state: Arc<SharedPlayerState>,
+  ) -> (Self, (Receiver<RequestJson>, Sender<Event>)) {
     let (request_data_tx, request_data_rx) = channel::unbounded();
     let (event_tx, event_rx) = channel::unbounded();
+    let (shutdown_tx, shutdown_rx) = channel::bounded(1);
 
     (
       Self {
         executor,
+        shared_state,
         request_data_tx,
 
         event_rx,
         event_broadcast_tx: Mutex::new(Vec::new()),
+        registry: Arc::new(PluginRegistry::default()),
+
+        shutdown_tx,
+        shutdown_rx,
       },
       (request_data_rx, event_tx),
     )
@@ -106,17 +292,102 @@ impl<'ex> PluginManager<'ex> {
     }
   }
 
-  pub async fn load_plugin<P: Plugin<'ex, RequestSender>>(
+  /// Makes every `PluginRunner` call its plugin's shutdown hook and return
+  pub fn shutdown(&self) {
+    self.shutdown_tx.close();
+  }
+
+  async fn wait_for_shutdown(&self) {
+    // Nothing is sent on the channel, `recv` fails once it is closed
+    let _ = self.shutdown_rx.recv().await;
+  }
+
+  pub fn registry(&self) -> Arc<PluginRegistry> {
+    self.registry.clone()
+  }
+
+  async fn init_plugin<P: Plugin<'ex, RequestSender>>(
     &self,
-  ) -> Result<PluginRunner<P>, PluginError> {
-    let plugin = P::init(self.request_sender(), self.executor.clone())
-      .await
-      .map_err(PluginRunner::<P>::map_error)?;
+    config: P::Config,
+  ) -> Result<P, PluginError> {
+    P::init(
+      config,
+      self.request_sender(),
+      self.shared_state.handle(),
+      self.executor.clone(),
+    )
+    .await
+    .map_err(|error| PluginError::PluginError(Box::new(error)))
+  }
+
+  /// Events describing the current state, so a new subscriber doesn't have to wait for it to change
+  async fn current_state_events(&self) -> Result<Vec<Event>, String> {
+    let request_tx = self.request_sender();
 
+    Ok(vec![
+      Event::TrackChanged(request_tx.send_request(requests::QueryCurrentTrack).await?),
+      Event::PlaybackStateChanged(
+        request_tx
+          .send_request(requests::QueryPlaybackState)
+          .await?,
+      ),
+      Event::LoopModeChanged(request_tx.send_request(requests::QueryLoopMode).await?),
+      Event::EndBehaviorChanged(request_tx.send_request(requests::QueryEndBehavior).await?),
+      Event::StopAfterCurrentChanged(
+        request_tx
+          .send_request(requests::QueryStopAfterCurrent)
+          .await?,
+      ),
+      Event::ShuffleChanged(request_tx.send_request(requests::QueryShuffle).await?),
+      Event::QueueFilterChanged(request_tx.send_request(requests::QueryQueueFilter).await?),
+      Event::VolumeChanged(request_tx.send_request(requests::QueryVolume).await?),
+      Event::RateChanged(request_tx.send_request(requests::QueryRate).await?),
+    ])
+  }
+
+  /// Subscribes a plugin to events, starting with the `current_state_events`
+  ///
+  /// The server must be handling requests, so this is called when the plugin starts running instead of in `init_plugin`
+  async fn subscribe(&self, name: &str) -> Receiver<Event> {
+    // Holding the lock pauses `broadcast`, so events sent while the state is queried are delivered after it
+    let mut event_broadcast_tx = self.event_broadcast_tx.lock().await;
     let (event_tx, event_rx) = channel::unbounded();
-    self.event_broadcast_tx.lock().await.push(event_tx);
 
-    Ok(PluginRunner { plugin, event_rx })
+    match self.current_state_events().await {
+      Ok(events) => {
+        for event in events {
+          // The receiver is returned below, so the channel can't be closed yet
+          let _ = event_tx.try_send(event);
+        }
+      }
+      Err(error) => eprintln!("Could not send the current state to plugin {name}: {error}"),
+    }
+
+    event_broadcast_tx.push(event_tx);
+    event_rx
+  }
+
+  /// Plugins that are not `enabled` are not initialized until they are enabled through the `PluginRegistry`
+  pub async fn load_plugin<P: Plugin<'ex, RequestSender>>(
+    &self,
+    config: P::Config,
+    enabled: bool,
+  ) -> Result<PluginRunner<'_, 'ex, P>, PluginError>
+  where
+    P::Config: Clone,
+  {
+    let loaded = if enabled {
+      Some(self.init_plugin::<P>(config.clone()).await?)
+    } else {
+      None
+    };
+
+    Ok(PluginRunner {
+      manager: self,
+      config,
+      switch: self.registry.register(P::NAME, enabled).await,
+      loaded,
+    })
   }
 
   async fn broadcast(&self, event: Event) {
@@ -138,3 +409,374 @@ impl<'ex> PluginManager<'ex> {
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use std::{cell::Cell, convert::Infallible, sync::Mutex as StdMutex, time::Duration};
+
+  use futures_concurrency::future::Join;
+  use hsm_ipc::{
+    EndBehavior, FilterExpr, LoopMode, PlaybackState, Track, client::serialize_request,
+  };
+  use hsm_plugin::SharedStateHandle;
+  use smol::{Timer, future};
+
+  use super::*;
+
+  /// The calls a `RecordingPlugin` received, in order
+  type Calls = Arc<StdMutex<Vec<String>>>;
+
+  /// Records every call into it, including being dropped
+  struct RecordingPlugin {
+    calls: Calls,
+  }
+
+  impl RecordingPlugin {
+    fn record(calls: &Calls, call: impl Into<String>) {
+      calls.lock().unwrap().push(call.into());
+    }
+  }
+
+  impl<'ex, Tx: hsm_plugin::RequestSender + Send + Sync + 'ex> Plugin<'ex, Tx> for RecordingPlugin {
+    type Error = Infallible;
+    type Config = Calls;
+    const NAME: &'static str = "recording";
+
+    async fn init(
+      calls: Calls,
+      _request_tx: Tx,
+      _shared_state: SharedStateHandle,
+      _executor: Arc<Executor<'ex>>,
+    ) -> Result<Self, Self::Error> {
+      Self::record(&calls, "init");
+      Ok(Self { calls })
+    }
+
+    async fn on_ready(&self) -> Result<(), Self::Error> {
+      Self::record(&self.calls, "ready");
+      Ok(())
+    }
+
+    async fn on_event(&self, event: Event) -> Result<(), Self::Error> {
+      Self::record(&self.calls, format!("event {event:?}"));
+      Ok(())
+    }
+
+    async fn run(&self) -> Result<(), Self::Error> {
+      std::future::pending().await
+    }
+
+    async fn shutdown(&self) -> Result<(), Self::Error> {
+      Self::record(&self.calls, "shutdown");
+      Ok(())
+    }
+  }
+
+  impl Drop for RecordingPlugin {
+    fn drop(&mut self) {
+      Self::record(&self.calls, "drop");
+    }
+  }
+
+  /// Asks the server to shut down once it runs, like the mpris plugin does on `Quit`
+  struct QuittingPlugin<Tx> {
+    calls: Calls,
+    request_tx: Tx,
+  }
+
+  impl<'ex, Tx: hsm_plugin::RequestSender + Send + Sync + 'ex> Plugin<'ex, Tx>
+    for QuittingPlugin<Tx>
+  {
+    type Error = Infallible;
+    type Config = Calls;
+    const NAME: &'static str = "quitting";
+
+    async fn init(
+      calls: Calls,
+      request_tx: Tx,
+      _shared_state: SharedStateHandle,
+      _executor: Arc<Executor<'ex>>,
+    ) -> Result<Self, Self::Error> {
+      Ok(Self { calls, request_tx })
+    }
+
+    async fn on_event(&self, _event: Event) -> Result<(), Self::Error> {
+      Ok(())
+    }
+
+    async fn run(&self) -> Result<(), Self::Error> {
+      RecordingPlugin::record(&self.calls, "shutdown requested");
+      // The plugin may be stopped before the reply arrives
+      let _ = self.request_tx.send_request(requests::Shutdown).await;
+      std::future::pending().await
+    }
+
+    async fn shutdown(&self) -> Result<(), Self::Error> {
+      // Requests are still answered while plugins shut down
+      let _ = self.request_tx.send_request(requests::QueryVersion).await;
+      RecordingPlugin::record(&self.calls, "shutdown");
+      Ok(())
+    }
+  }
+
+  impl<Tx> Drop for QuittingPlugin<Tx> {
+    fn drop(&mut self) {
+      RecordingPlugin::record(&self.calls, "drop");
+    }
+  }
+
+  fn reply(response: impl serde::Serialize) -> String {
+    let mut reply_data = serde_json::to_string(&Ok::<_, String>(response)).unwrap();
+    reply_data.push('\n');
+    reply_data
+  }
+
+  /// Replies to the queries `current_state_events` sends, with the state of a paused player
+  fn current_state_reply(request_data: &str) -> Option<String> {
+    let replies = [
+      (
+        serialize_request(requests::QueryCurrentTrack),
+        reply(None::<Track>),
+      ),
+      (
+        serialize_request(requests::QueryPlaybackState),
+        reply(PlaybackState::Paused),
+      ),
+      (
+        serialize_request(requests::QueryLoopMode),
+        reply(LoopMode::Playlist),
+      ),
+      (
+        serialize_request(requests::QueryEndBehavior),
+        reply(EndBehavior::WillWrap),
+      ),
+      (
+        serialize_request(requests::QueryStopAfterCurrent),
+        reply(false),
+      ),
+      (serialize_request(requests::QueryShuffle), reply(true)),
+      (
+        serialize_request(requests::QueryQueueFilter),
+        reply(None::<FilterExpr>),
+      ),
+      (serialize_request(requests::QueryVolume), reply(0.5)),
+      (serialize_request(requests::QueryRate), reply(1.0)),
+    ];
+
+    replies
+      .into_iter()
+      .find(|(request, _)| request == request_data)
+      .map(|(_, reply_data)| reply_data)
+  }
+
+  /// Answers requests in place of the audio server, shutting down the plugins on `Shutdown` like `main` does
+  ///
+  /// `on_query` is called before each query for the current state is answered, other requests are rejected
+  async fn answer_requests(
+    request_rx: Receiver<RequestJson>,
+    manager: &PluginManager<'_>,
+    on_query: impl Fn(),
+  ) {
+    while let Ok((request_data, _, mut reply_tx)) = request_rx.recv().await {
+      let reply_data = if request_data == serialize_request(requests::Shutdown) {
+        manager.shutdown();
+        reply(())
+      } else if let Some(reply_data) = current_state_reply(&request_data) {
+        on_query();
+        reply_data
+      } else {
+        hsm_ipc::server::serialize_error(&"No server in tests")
+      };
+
+      let _ = reply_tx.send(reply_data);
+    }
+  }
+
+  /// The calls other than `on_event`
+  fn lifecycle_calls(calls: &Calls) -> Vec<String> {
+    let calls = calls.lock().unwrap();
+    calls
+      .iter()
+      .filter(|call| !call.starts_with("event "))
+      .cloned()
+      .collect()
+  }
+
+  /// Polls `calls` until the calls other than `on_event` equal `expected`, panicking if they don't in time
+  async fn wait_for_calls(calls: &Calls, expected: &[&str]) {
+    future::or(
+      async {
+        while lifecycle_calls(calls) != expected {
+          Timer::after(Duration::from_millis(5)).await;
+        }
+      },
+      async {
+        Timer::after(Duration::from_secs(5)).await;
+        panic!(
+          "Expected calls {expected:?}, got {:?}",
+          lifecycle_calls(calls)
+        );
+      },
+    )
+    .await
+  }
+
+  /// Polls `calls` until it has at least `count` calls, panicking if it doesn't in time
+  async fn wait_until_calls(calls: &Calls, count: usize) {
+    future::or(
+      async {
+        while calls.lock().unwrap().len() < count {
+          Timer::after(Duration::from_millis(5)).await;
+        }
+      },
+      async {
+        Timer::after(Duration::from_secs(5)).await;
+        let calls = calls.lock().unwrap().clone();
+        panic!("Expected {count} calls, got {calls:?}");
+      },
+    )
+    .await
+  }
+
+  #[test]
+  fn disabling_drops_and_enabling_reinitializes_a_plugin() {
+    let executor = Arc::new(Executor::new());
+    let (manager, (request_rx, _event_tx)) =
+      PluginManager::new(executor, Arc::new(SharedPlayerState::new()));
+    let registry = manager.registry();
+    let calls = Calls::default();
+
+    smol::block_on(future::or(
+      async {
+        let runner = manager
+          .load_plugin::<RecordingPlugin>(calls.clone(), true)
+          .await
+          .unwrap();
+        // Startup errors are returned from `load_plugin`, so the plugin is initialized before it runs
+        wait_for_calls(&calls, &["init"]).await;
+
+        let test = async {
+          wait_for_calls(&calls, &["init", "ready"]).await;
+
+          assert!(registry.set_enabled("recording", false).await);
+          wait_for_calls(&calls, &["init", "ready", "shutdown", "drop"]).await;
+          assert_eq!(registry.plugins().await, [("recording".to_string(), false)]);
+
+          assert!(registry.set_enabled("recording", true).await);
+          wait_for_calls(
+            &calls,
+            &["init", "ready", "shutdown", "drop", "init", "ready"],
+          )
+          .await;
+
+          assert!(!registry.set_enabled("missing", true).await);
+          manager.shutdown();
+        };
+
+        let (result, ()) = (runner.run(), test).join().await;
+        result.unwrap();
+        wait_for_calls(
+          &calls,
+          &[
+            "init", "ready", "shutdown", "drop", "init", "ready", "shutdown", "drop",
+          ],
+        )
+        .await;
+      },
+      async {
+        answer_requests(request_rx, &manager, || ()).await;
+        panic!("The request channel closed");
+      },
+    ));
+  }
+
+  #[test]
+  fn shutdown_request_runs_the_shutdown_hook() {
+    let executor = Arc::new(Executor::new());
+    let (manager, (request_rx, _event_tx)) =
+      PluginManager::new(executor, Arc::new(SharedPlayerState::new()));
+    let calls = Calls::default();
+
+    smol::block_on(future::or(
+      async {
+        let runner = manager
+          .load_plugin::<QuittingPlugin<_>>(calls.clone(), true)
+          .await
+          .unwrap();
+
+        // The runner only returns once the hook has finished, which lets the server exit
+        runner.run().await.unwrap();
+        assert_eq!(
+          *calls.lock().unwrap(),
+          ["shutdown requested", "shutdown", "drop"]
+        );
+      },
+      async {
+        answer_requests(request_rx, &manager, || ()).await;
+        panic!("The request channel closed");
+      },
+    ));
+  }
+
+  #[test]
+  fn current_state_is_delivered_before_real_events() {
+    let executor = Arc::new(Executor::new());
+    let (manager, (request_rx, event_tx)) =
+      PluginManager::new(executor, Arc::new(SharedPlayerState::new()));
+    let calls = Calls::default();
+
+    // Happens while the current state is being queried for the plugin
+    let sent = Cell::new(false);
+    let send_event = || {
+      if !sent.replace(true) {
+        let _ = event_tx.try_send(Event::VolumeChanged(0.75));
+      }
+    };
+
+    smol::block_on(future::or(
+      async {
+        let runner = manager
+          .load_plugin::<RecordingPlugin>(calls.clone(), true)
+          .await
+          .unwrap();
+
+        let test = async {
+          wait_until_calls(&calls, 12).await;
+          manager.shutdown();
+        };
+        let (result, ()) = (runner.run(), test).join().await;
+        result.unwrap();
+      },
+      async {
+        (
+          async { answer_requests(request_rx, &manager, send_event).await },
+          async { manager.run().await.unwrap() },
+        )
+          .race()
+          .await;
+        panic!("The request or event channel closed");
+      },
+    ));
+
+    let calls = calls.lock().unwrap();
+    assert_eq!(
+      *calls,
+      [
+        "init",
+        "ready",
+        "event TrackChanged(None)",
+        "event PlaybackStateChanged(Paused)",
+        "event LoopModeChanged(Playlist)",
+        "event EndBehaviorChanged(WillWrap)",
+        "event StopAfterCurrentChanged(false)",
+        "event ShuffleChanged(true)",
+        "event QueueFilterChanged(None)",
+        "event VolumeChanged(0.5)",
+        "event RateChanged(1.0)",
+        "event VolumeChanged(0.75)",
+        "shutdown",
+        "drop",
+      ]
+    );
+  }
+}
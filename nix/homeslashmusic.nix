@@ -4,6 +4,9 @@
   rustPlatform,
   pkg-config,
   alsa-lib,
+  # `true` with `buildFeatures = ["hsm-server/hsm-plugin-ipc"]` builds without d-bus
+  buildNoDefaultFeatures ? false,
+  buildFeatures ? [],
 }:
 rustPlatform.buildRustPackage rec {
   pname = "homeslashmusic";
@@ -14,6 +17,8 @@ rustPlatform.buildRustPackage rec {
     lockFile = ../Cargo.lock;
   };
 
+  inherit buildNoDefaultFeatures buildFeatures;
+
   nativeBuildInputs = [pkg-config];
   buildInputs = [alsa-lib];
 
@@ -1,12 +1,22 @@
 use std::{
   fs,
   path::{Path, PathBuf},
-  sync::Arc,
+  sync::{
+    Arc, Mutex,
+    atomic::{AtomicU64, Ordering},
+  },
 };
 
-use hsm_plugin::{Plugin, RequestSender};
+use futures_concurrency::future::Race;
+use hsm_ipc::{
+  Event, EventKind,
+  server::{Connection, RequestOrigin},
+};
+use hsm_plugin::{Plugin, RequestSender, SharedStateHandle};
 use smol::{
   Executor,
+  channel::{self, Sender, TrySendError},
+  future,
   io::{self, AsyncBufReadExt, AsyncWriteExt, BufReader},
   net::unix::{UnixListener, UnixStream},
   stream::StreamExt,
@@ -23,14 +33,57 @@ pub enum IpcServerError {
   )]
   SocketInUse,
 
-  #[error("Failed to create ipc socket: {0}")]
-  FailedToCreateSocket(#[source] io::Error),
+  #[error(
+    "Failed to create ipc socket {path:?}: {source}, set HSM_SOCKET_PATH to listen somewhere else"
+  )]
+  FailedToCreateSocket { path: PathBuf, source: io::Error },
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct IpcOptions {
+  /// Listen here instead of `hsm_ipc::socket_path()`
+  pub socket_path: Option<PathBuf>,
+  /// Also listen on `hsm_ipc::read_only_socket_path_for(socket_path)`, which only accepts `Query*` requests
+  pub read_only_socket: bool,
+}
+
+/// A connection that sent `Subscribe`, and is sent events from `on_event`
+struct Subscriber {
+  /// Every kind of event is sent if this is empty
+  kinds: Vec<EventKind>,
+  event_tx: Sender<Event>,
+}
+
+impl Subscriber {
+  /// Events that haven't been written to the connection yet, past this the client is assumed to have stopped reading
+  const BUFFER_LEN: usize = 256;
+
+  /// Returns false if the subscriber should be removed
+  fn send(&self, event: &Event) -> bool {
+    if !self.kinds.is_empty() && !self.kinds.contains(&event.kind()) {
+      return !self.event_tx.is_closed();
+    }
+
+    match self.event_tx.try_send(event.clone()) {
+      Ok(()) => true,
+      Err(TrySendError::Full(_)) => {
+        eprintln!("Event subscriber stopped reading, disconnecting it");
+        false
+      }
+      Err(TrySendError::Closed(_)) => false,
+    }
+  }
 }
 
+type Subscribers = Arc<Mutex<Vec<Subscriber>>>;
+
 pub struct IpcPlugin<'ex, Tx> {
   socket_path: PathBuf,
+  read_only_socket_path: Option<PathBuf>,
   request_tx: Tx,
   executor: Arc<Executor<'ex>>,
+  next_connection_id: AtomicU64,
+  subscribers: Subscribers,
 }
 
 impl<'ex, Tx> IpcPlugin<'ex, Tx> {
@@ -39,90 +92,379 @@ impl<'ex, Tx> IpcPlugin<'ex, Tx> {
     Ok(socket_in_use)
   }
 
-  fn cleanup_socket(&self) {
-    let _ = fs::remove_file(&self.socket_path);
-    println!("Removing socket: {:?}", self.socket_path);
+  fn cleanup_socket(socket_path: &Path) {
+    let _ = fs::remove_file(socket_path);
+    println!("Removing socket: {:?}", socket_path);
+  }
+}
+
+impl<'ex, Tx: RequestSender + Send + Sync + Clone + 'ex> IpcPlugin<'ex, Tx> {
+  /// Handles connections to `socket_path`, tagging each request with `origin` and the connection it was sent on
+  async fn listen(&self, socket_path: &Path, origin: RequestOrigin) -> Result<(), IpcServerError> {
+    let listener =
+      UnixListener::bind(socket_path).map_err(|source| IpcServerError::FailedToCreateSocket {
+        path: socket_path.into(),
+        source,
+      })?;
+
+    while let Some(stream) = listener.incoming().next().await {
+      let request_tx = self.request_tx.clone();
+      let subscribers = self.subscribers.clone();
+      let connection_id = self.next_connection_id.fetch_add(1, Ordering::Relaxed);
+      let origin = RequestOrigin {
+        connection: Some(Arc::new(Connection::new(connection_id))),
+        ..origin.clone()
+      };
+
+      self
+        .executor
+        .spawn(async {
+          let res = if let Ok(stream) = stream {
+            StreamHandler::new(request_tx, origin, subscribers)
+              .handle_stream(stream)
+              .await
+          } else {
+            stream.map(|_| ())
+          };
+
+          if let Err(error) = res {
+            eprintln!("failed to connect to ipc client: {}", error);
+          }
+        })
+        .detach();
+    }
+
+    unreachable!("Iterating over Incoming should never return None")
   }
 }
 
 impl<'ex, Tx: RequestSender + Send + Sync + Clone + 'ex> Plugin<'ex, Tx> for IpcPlugin<'ex, Tx> {
   type Error = IpcServerError;
+  type Config = IpcOptions;
+  const NAME: &'static str = "ipc";
 
-  async fn init(request_tx: Tx, executor: Arc<Executor<'ex>>) -> Result<Self, Self::Error>
+  async fn init(
+    options: IpcOptions,
+    request_tx: Tx,
+    _shared_state: SharedStateHandle,
+    executor: Arc<Executor<'ex>>,
+  ) -> Result<Self, Self::Error>
   where
     Self: Sized,
   {
-    let socket_path = PathBuf::from(hsm_ipc::socket_path());
+    let socket_path = hsm_ipc::socket_path_with(options.socket_path.as_deref());
     if Self::is_socket_in_use(&socket_path)? {
       return Err(IpcServerError::SocketInUse);
     }
 
+    let read_only_socket_path = options
+      .read_only_socket
+      .then(|| hsm_ipc::read_only_socket_path_for(&socket_path));
+    if let Some(read_only_socket_path) = &read_only_socket_path
+      && Self::is_socket_in_use(read_only_socket_path)?
+    {
+      return Err(IpcServerError::SocketInUse);
+    }
+
     Ok(Self {
       socket_path,
+      read_only_socket_path,
       request_tx,
       executor,
+      next_connection_id: AtomicU64::new(0),
+      subscribers: Subscribers::default(),
     })
   }
 
-  async fn on_event(&self, _event: hsm_ipc::Event) -> Result<(), Self::Error> {
+  async fn on_event(&self, event: Event) -> Result<(), Self::Error> {
+    self
+      .subscribers
+      .lock()
+      .expect("Subscribers lock should not be poisoned")
+      .retain(|subscriber| subscriber.send(&event));
+
     Ok(())
   }
 
   async fn run(&self) -> Result<(), Self::Error> {
-    let listener =
-      UnixListener::bind(&self.socket_path).map_err(IpcServerError::FailedToCreateSocket)?;
-
-    while let Some(stream) = listener.incoming().next().await {
-      let request_tx = self.request_tx.clone();
-
-      self
-        .executor
-        .spawn(async {
-          let res = if let Ok(stream) = stream {
-            StreamHandler::new(request_tx).handle_stream(stream).await
-          } else {
-            stream.map(|_| ())
-          };
-
-          if let Err(error) = res {
-            eprintln!("failed to connect to ipc client: {}", error);
+    (
+      self.listen(&self.socket_path, RequestOrigin::default()),
+      async {
+        match &self.read_only_socket_path {
+          Some(read_only_socket_path) => {
+            let origin = RequestOrigin {
+              read_only: true,
+              ..Default::default()
+            };
+            self.listen(read_only_socket_path, origin).await
           }
-        })
-        .detach();
-    }
-
-    self.cleanup_socket();
-    unreachable!("Iterating over Incoming should never return None")
+          None => future::pending().await,
+        }
+      },
+    )
+      .race()
+      .await
   }
 }
 
 impl<'ex, Tx> Drop for IpcPlugin<'ex, Tx> {
   fn drop(&mut self) {
-    self.cleanup_socket();
+    // Closes the event channels, so subscribed connections are ended instead of waiting forever
+    self
+      .subscribers
+      .lock()
+      .expect("Subscribers lock should not be poisoned")
+      .clear();
+
+    Self::cleanup_socket(&self.socket_path);
+    if let Some(read_only_socket_path) = &self.read_only_socket_path {
+      Self::cleanup_socket(read_only_socket_path);
+    }
   }
 }
 
 struct StreamHandler<Tx> {
   request_tx: Tx,
+  origin: RequestOrigin,
+  subscribers: Subscribers,
 }
 
 impl<Tx> StreamHandler<Tx> {
-  fn new(request_tx: Tx) -> Self {
-    Self { request_tx }
+  fn new(request_tx: Tx, origin: RequestOrigin, subscribers: Subscribers) -> Self {
+    Self {
+      request_tx,
+      origin,
+      subscribers,
+    }
+  }
+
+  /// Writes events to the stream until the client disconnects or the plugin is dropped
+  async fn stream_events(
+    &self,
+    mut stream_reader: BufReader<UnixStream>,
+    kinds: Vec<EventKind>,
+  ) -> io::Result<()> {
+    let (event_tx, event_rx) = channel::bounded(Subscriber::BUFFER_LEN);
+    self
+      .subscribers
+      .lock()
+      .expect("Subscribers lock should not be poisoned")
+      .push(Subscriber { kinds, event_tx });
+
+    loop {
+      let event = (async { event_rx.recv().await.ok() }, async {
+        // Anything else the client sends is ignored, this only notices when it disconnects
+        let mut ignored = String::new();
+        while let Ok(1..) = stream_reader.read_line(&mut ignored).await {
+          ignored.clear();
+        }
+        None
+      })
+        .race()
+        .await;
+
+      let Some(event) = event else {
+        return Ok(());
+      };
+
+      let event_data = hsm_ipc::server::serialize_event(&event);
+      match stream_reader
+        .get_mut()
+        .write_all(event_data.as_bytes())
+        .await
+      {
+        Err(error) if error.kind() == io::ErrorKind::BrokenPipe => return Ok(()),
+        result => result?,
+      }
+    }
   }
 }
 
 impl<Tx: RequestSender> StreamHandler<Tx> {
+  /// Replies to each line sent on `stream` until the client disconnects or subscribes to events
   async fn handle_stream(&self, stream: UnixStream) -> io::Result<()> {
-    let mut request_data = String::new();
     let mut stream_reader = BufReader::new(stream);
-    stream_reader.read_line(&mut request_data).await?;
 
-    let reply_data = self.request_tx.send_json(request_data).await;
+    loop {
+      let mut request_data = String::new();
+      if stream_reader.read_line(&mut request_data).await? == 0 {
+        return Ok(());
+      }
 
-    let mut stream = stream_reader.into_inner();
-    stream.write_all(&reply_data.as_bytes()).await?;
+      if let Some((kinds, reply_data)) = hsm_ipc::server::parse_subscribe(&request_data) {
+        stream_reader
+          .get_mut()
+          .write_all(reply_data.as_bytes())
+          .await?;
+        return self.stream_events(stream_reader, kinds).await;
+      }
 
-    Ok(())
+      let reply_data = self
+        .request_tx
+        .send_json_from(self.origin.clone(), request_data)
+        .await;
+
+      stream_reader
+        .get_mut()
+        .write_all(&reply_data.as_bytes())
+        .await?;
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::time::Duration;
+
+  use hsm_ipc::{
+    LoopMode,
+    client::{deserialize_event, deserialize_reply, serialize_request},
+    requests,
+  };
+
+  use super::*;
+
+  /// Replies to every request the way the server replies to a `Subscribe` it can't handle, and records them
+  #[derive(Clone, Default)]
+  struct RecordingSender {
+    requests: Arc<Mutex<Vec<String>>>,
+  }
+
+  impl RequestSender for RecordingSender {
+    fn send_json_from(
+      &self,
+      _origin: RequestOrigin,
+      request_data: String,
+    ) -> impl Future<Output = String> + Send + Sync {
+      self.requests.lock().unwrap().push(request_data);
+      async { hsm_ipc::server::serialize_error(&"Subscribe can only be sent over the ipc socket") }
+    }
+  }
+
+  fn subscriber(kinds: Vec<EventKind>) -> (Subscriber, channel::Receiver<Event>) {
+    let (event_tx, event_rx) = channel::bounded(Subscriber::BUFFER_LEN);
+    (Subscriber { kinds, event_tx }, event_rx)
+  }
+
+  /// Sends `event` to every subscriber, like `IpcPlugin::on_event`
+  fn send_event(subscribers: &Subscribers, event: Event) {
+    subscribers
+      .lock()
+      .unwrap()
+      .retain(|subscriber| subscriber.send(&event));
+  }
+
+  async fn read_line(reader: &mut BufReader<UnixStream>) -> String {
+    let mut line = String::new();
+    let read = (
+      async { reader.read_line(&mut line).await.unwrap() },
+      async {
+        smol::Timer::after(Duration::from_secs(5)).await;
+        panic!("Timed out reading a line");
+      },
+    )
+      .race()
+      .await;
+    assert_ne!(read, 0, "The connection was closed");
+    line
+  }
+
+  #[test]
+  fn subscribers_only_receive_their_kinds() {
+    let (volume, volume_rx) = subscriber(vec![EventKind::VolumeChanged]);
+    let (every, every_rx) = subscriber(Vec::new());
+
+    for subscriber in [&volume, &every] {
+      assert!(subscriber.send(&Event::ShuffleChanged(true)));
+      assert!(subscriber.send(&Event::VolumeChanged(0.5)));
+    }
+
+    assert!(matches!(volume_rx.try_recv(), Ok(Event::VolumeChanged(_))));
+    assert!(volume_rx.try_recv().is_err());
+    assert!(matches!(every_rx.try_recv(), Ok(Event::ShuffleChanged(_))));
+    assert!(matches!(every_rx.try_recv(), Ok(Event::VolumeChanged(_))));
+  }
+
+  #[test]
+  fn stopped_subscribers_are_removed() {
+    let (full, _full_rx) = subscriber(Vec::new());
+    for _ in 0..Subscriber::BUFFER_LEN {
+      assert!(full.send(&Event::Seeked(Duration::ZERO)));
+    }
+    assert!(!full.send(&Event::Seeked(Duration::ZERO)));
+
+    // A closed subscriber is removed even by events it didn't subscribe to
+    let (closed, closed_rx) = subscriber(vec![EventKind::Seeked]);
+    drop(closed_rx);
+    assert!(!closed.send(&Event::ShuffleChanged(true)));
+  }
+
+  #[test]
+  fn subscribe_streams_events_over_the_connection() {
+    smol::block_on(async {
+      let (client, server) = UnixStream::pair().unwrap();
+      let request_tx = RecordingSender::default();
+      let subscribers = Subscribers::default();
+      let handler = StreamHandler::new(
+        request_tx.clone(),
+        RequestOrigin::default(),
+        subscribers.clone(),
+      );
+
+      let client = async {
+        let mut reader = BufReader::new(client);
+
+        // Other requests go to the server
+        let request = serialize_request(requests::QueryVolume);
+        reader
+          .get_mut()
+          .write_all(request.as_bytes())
+          .await
+          .unwrap();
+        let reply = deserialize_reply::<requests::QueryVolume>(&read_line(&mut reader).await);
+        assert!(reply.unwrap().is_err());
+
+        let request = serialize_request(requests::Subscribe(vec![
+          EventKind::LoopModeChanged,
+          EventKind::VolumeChanged,
+        ]));
+        reader
+          .get_mut()
+          .write_all(request.as_bytes())
+          .await
+          .unwrap();
+        let reply = deserialize_reply::<requests::Subscribe>(&read_line(&mut reader).await);
+        assert_eq!(reply.unwrap(), Ok(()));
+
+        // Wait for the subscriber to be added before sending it anything
+        while subscribers.lock().unwrap().is_empty() {
+          smol::Timer::after(Duration::from_millis(1)).await;
+        }
+        send_event(&subscribers, Event::ShuffleChanged(true));
+        send_event(&subscribers, Event::VolumeChanged(0.5));
+        send_event(&subscribers, Event::Seeked(Duration::ZERO));
+        send_event(&subscribers, Event::LoopModeChanged(LoopMode::Track));
+
+        let event = deserialize_event(&read_line(&mut reader).await).unwrap();
+        assert!(matches!(event, Event::VolumeChanged(0.5)));
+        let event = deserialize_event(&read_line(&mut reader).await).unwrap();
+        assert!(matches!(event, Event::LoopModeChanged(LoopMode::Track)));
+      };
+
+      let handled = async {
+        handler.handle_stream(server).await.unwrap();
+        panic!("The connection ended before the client did");
+      };
+      (handled, client).race().await;
+
+      // Only the request that wasn't a subscription reached the server
+      let sent = request_tx.requests.lock().unwrap().clone();
+      assert_eq!(sent, [serialize_request(requests::QueryVolume)]);
+
+      // The connection is gone, so its subscriber is removed by the next event
+      drop(handler);
+      send_event(&subscribers, Event::ShuffleChanged(false));
+      assert!(subscribers.lock().unwrap().is_empty());
+    });
   }
 }
@@ -6,7 +6,34 @@ use std::{
 };
 
 use hsm_ipc::{LoopMode, PlaybackState, Track};
-use mpris_server::zbus::zvariant::ObjectPath;
+use mpris_server::zbus::{self, fdo, zvariant::ObjectPath};
+
+/// Why a method call or property set from an MPRIS client failed
+#[derive(Debug, Clone)]
+pub enum MprisError {
+  /// hsm does not implement this part of the MPRIS interface
+  NotSupported(String),
+  /// The client sent a value that hsm can't use
+  InvalidArgs(String),
+  /// The server replied with an error
+  Failed(String),
+}
+
+impl From<MprisError> for fdo::Error {
+  fn from(error: MprisError) -> Self {
+    match error {
+      MprisError::NotSupported(message) => fdo::Error::NotSupported(message),
+      MprisError::InvalidArgs(message) => fdo::Error::InvalidArgs(message),
+      MprisError::Failed(message) => fdo::Error::Failed(format!("hsm-server: {message}")),
+    }
+  }
+}
+
+impl From<MprisError> for zbus::Error {
+  fn from(error: MprisError) -> Self {
+    fdo::Error::from(error).into()
+  }
+}
 
 pub fn as_playback_status(playback_state: PlaybackState) -> mpris_server::PlaybackStatus {
   match playback_state {
@@ -32,42 +59,70 @@ pub fn from_loop_status(loop_status: mpris_server::LoopStatus) -> LoopMode {
   }
 }
 
+/// The rate to request for a rate set by an MPRIS client, callers pause for a rate of 0 instead
+pub fn from_dbus_rate(rate: mpris_server::PlaybackRate) -> Result<f32, MprisError> {
+  if !rate.is_finite() {
+    return Err(MprisError::InvalidArgs(
+      "Rate must be a finite number".into(),
+    ));
+  }
+
+  Ok(rate as f32)
+}
+
+/// The volume to request for a volume set by an MPRIS client
+pub fn from_dbus_volume(volume: mpris_server::Volume) -> Result<f32, MprisError> {
+  if !volume.is_finite() {
+    return Err(MprisError::InvalidArgs(
+      "Volume must be a finite number".into(),
+    ));
+  }
+
+  // MPRIS treats negative volumes as 0
+  Ok(volume.max(0.0) as f32)
+}
+
 pub fn as_dbus_time(time: Duration) -> mpris_server::Time {
-  mpris_server::Time::from_micros(time.as_micros() as i64)
+  mpris_server::Time::from_micros(i64::try_from(time.as_micros()).unwrap_or(i64::MAX))
 }
 
+/// The magnitude of `time`, negative offsets are converted by the caller into backward seeks
 pub fn from_dbus_time(time: mpris_server::Time) -> Duration {
-  Duration::from_micros(time.as_micros() as u64)
+  Duration::from_micros(time.as_micros().unsigned_abs())
 }
 
 pub fn generate_metadata(track: &Track) -> mpris_server::Metadata {
   let track_id = ObjectPath::from_static_str_unchecked("/dev/djlaser/HomeSlashMusic/DefaultTrack");
 
-  let metadata = track.metadata.clone();
+  let metadata = &track.metadata;
   let mut builder = mpris_server::Metadata::builder()
     .trackid(track_id)
-    .artist(metadata.artists)
-    .genre(metadata.genres)
-    .comment(metadata.comments);
+    .artist(metadata.artists_or_inferred())
+    .genre(metadata.genres.clone())
+    .comment(metadata.comments.clone());
 
-  if let Some(title) = metadata.title {
+  if let Some(title) = metadata.title_or_inferred() {
     builder = builder.title(title);
   }
 
-  if let Some(album) = metadata.album {
+  if let Some(album) = metadata.album_or_inferred() {
     builder = builder.album(album);
   }
 
-  if let Some(track_number) = metadata.track_number {
+  if let Some(track_number) = metadata.track_number_or_inferred() {
     builder = builder.track_number(track_number as i32);
   }
 
-  if let Some(date) = metadata.date {
+  if let Some(date) = metadata.date.clone() {
     builder = builder.content_created(date);
   }
 
   if let Some(duration) = track.total_duration {
-    builder = builder.length(mpris_server::Time::from_micros(duration.as_micros() as i64));
+    builder = builder.length(as_dbus_time(duration));
+  }
+
+  if let Some(art_path) = &metadata.art_path {
+    builder = builder.art_url(encode_file_url(art_path));
   }
 
   let url = encode_file_url(&track.file_path);
@@ -76,6 +131,19 @@ pub fn generate_metadata(track: &Track) -> mpris_server::Metadata {
   builder.build()
 }
 
+/// Metadata of the current track, or the `NoTrack` id if the track list is empty
+pub fn current_track_metadata(track: Option<&Track>) -> mpris_server::Metadata {
+  match track {
+    Some(track) => generate_metadata(track),
+    None => mpris_server::Metadata::builder()
+      .trackid(mpris_server::TrackId::NO_TRACK)
+      .build(),
+  }
+}
+
+/// Encodes an absolute path as a `file://` URL, percent encoding every byte that isn't unreserved
+///
+/// Paths are encoded byte by byte, so paths that are not valid UTF-8 survive `decode_file_url`
 pub fn encode_file_url(path: &Path) -> String {
   let mut file_url = "file://".to_owned();
   for component in path.components() {
@@ -84,16 +152,172 @@ pub fn encode_file_url(path: &Path) -> String {
         file_url.push('/');
         file_url.push_str(&urlencoding::encode_binary(os_str.as_bytes()));
       }
-      _ => (),
+      Component::ParentDir => file_url.push_str("/.."),
+      // Unix paths have no prefix, and the root is the leading slash of the first component
+      Component::RootDir | Component::CurDir | Component::Prefix(_) => (),
     }
   }
 
+  // The root directory has no components to add a slash
+  if file_url == "file://" {
+    file_url.push('/');
+  }
+
   file_url
 }
 
+/// Decodes a `file://` URL into an absolute path
+///
+/// Accepts both percent encoded and unencoded paths, and an empty or `localhost` host
 pub fn decode_file_url(file_url: String) -> Option<PathBuf> {
-  let encoded_file_path = file_url.strip_prefix("file://")?;
-  let file_path = urlencoding::decode_binary(encoded_file_path.as_bytes());
+  let rest = file_url.strip_prefix("file://")?;
+  let encoded_file_path = rest.strip_prefix("localhost").unwrap_or(rest);
+
+  if !encoded_file_path.starts_with('/') {
+    return None;
+  }
 
+  let file_path = urlencoding::decode_binary(encoded_file_path.as_bytes());
   Some(PathBuf::from(OsStr::from_bytes(&file_path)))
 }
+
+#[cfg(test)]
+mod tests {
+  use hsm_ipc::TrackMetadata;
+
+  use super::*;
+
+  #[test]
+  fn maps_errors_to_the_errors_clients_see() {
+    let cases = [
+      (
+        MprisError::NotSupported("no fullscreen".into()),
+        fdo::Error::NotSupported("no fullscreen".into()),
+      ),
+      (
+        MprisError::InvalidArgs("rate is NaN".into()),
+        fdo::Error::InvalidArgs("rate is NaN".into()),
+      ),
+      (
+        MprisError::Failed("queue is empty".into()),
+        fdo::Error::Failed("hsm-server: queue is empty".into()),
+      ),
+    ];
+
+    for (error, expected) in cases {
+      assert_eq!(fdo::Error::from(error.clone()), expected, "{error:?}");
+      // Property setters return a `zbus::Error`, which wraps the same fdo error
+      assert_eq!(
+        zbus::Error::from(error),
+        zbus::Error::FDO(Box::new(expected))
+      );
+    }
+  }
+
+  #[test]
+  fn round_trips_loop_status() {
+    for loop_mode in [LoopMode::None, LoopMode::Track, LoopMode::Playlist] {
+      assert_eq!(from_loop_status(as_loop_status(loop_mode)), loop_mode);
+    }
+  }
+
+  #[test]
+  fn round_trips_rate_and_volume() {
+    for value in [hsm_ipc::MIN_RATE, 0.5, 1.0, 1.25, hsm_ipc::MAX_RATE] {
+      let rate = mpris_server::PlaybackRate::from(value);
+      assert_eq!(from_dbus_rate(rate).unwrap(), value);
+    }
+
+    for value in [0.0, 0.1, 0.5, 1.0, 1.5] {
+      let volume = mpris_server::Volume::from(value);
+      assert_eq!(from_dbus_volume(volume).unwrap(), value);
+    }
+  }
+
+  #[test]
+  fn rejects_rates_and_volumes_that_are_not_finite() {
+    for value in [f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+      assert!(matches!(
+        from_dbus_rate(value),
+        Err(MprisError::InvalidArgs(_))
+      ));
+      assert!(matches!(
+        from_dbus_volume(value),
+        Err(MprisError::InvalidArgs(_))
+      ));
+    }
+
+    assert_eq!(from_dbus_volume(-0.5).unwrap(), 0.0);
+  }
+
+  #[test]
+  fn round_trips_track_metadata() {
+    let track = Track {
+      file_path: PathBuf::from("/music/a b/%20ç.flac"),
+      total_duration: Some(Duration::from_millis(1500)),
+      metadata: TrackMetadata::default(),
+    };
+
+    let metadata = current_track_metadata(Some(&track));
+    let track_id = metadata.trackid().unwrap();
+    assert_ne!(track_id, mpris_server::TrackId::NO_TRACK);
+    assert_eq!(
+      mpris_server::TrackId::try_from(track_id.as_str()).unwrap(),
+      track_id
+    );
+    assert_eq!(
+      decode_file_url(metadata.url().unwrap()),
+      Some(track.file_path.clone())
+    );
+    assert_eq!(
+      metadata.length(),
+      Some(as_dbus_time(Duration::from_millis(1500)))
+    );
+
+    let empty = current_track_metadata(None);
+    assert_eq!(empty.trackid(), Some(mpris_server::TrackId::NO_TRACK));
+  }
+
+  #[test]
+  fn round_trips_file_urls() {
+    let paths = [
+      PathBuf::from("/"),
+      PathBuf::from("/music/song.flac"),
+      PathBuf::from("/music/../a b/#?%.mp3"),
+      PathBuf::from(OsStr::from_bytes(b"/music/\xff\xfe.flac")),
+    ];
+
+    for path in paths {
+      assert_eq!(decode_file_url(encode_file_url(&path)), Some(path.clone()));
+    }
+  }
+
+  #[test]
+  fn converts_dbus_times() {
+    let cases = [
+      (Duration::ZERO, 0),
+      (Duration::from_millis(1500), 1_500_000),
+      // Too long for D-Bus, so it is clamped instead of wrapping around
+      (Duration::MAX, i64::MAX),
+    ];
+
+    for (duration, micros) in cases {
+      assert_eq!(as_dbus_time(duration).as_micros(), micros, "{duration:?}");
+    }
+  }
+
+  #[test]
+  fn takes_the_magnitude_of_dbus_times() {
+    let cases = [
+      (0, Duration::ZERO),
+      (2_000_000, Duration::from_secs(2)),
+      (-2_000_000, Duration::from_secs(2)),
+      (i64::MIN, Duration::from_micros(i64::MIN.unsigned_abs())),
+    ];
+
+    for (micros, expected) in cases {
+      let time = mpris_server::Time::from_micros(micros);
+      assert_eq!(from_dbus_time(time), expected, "{micros}");
+    }
+  }
+}
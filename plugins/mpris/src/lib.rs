@@ -1,21 +1,28 @@
-use std::sync::Arc;
+use std::{
+  sync::Arc,
+  time::{Duration, Instant},
+};
 
 use conversions::{as_dbus_time, as_loop_status, as_playback_status};
+use futures_concurrency::future::Race;
 use hsm_ipc::Event;
-use hsm_plugin::{Plugin, RequestSender};
-use mpris_impl::MprisImpl;
+use hsm_plugin::{Plugin, RequestSender, SharedStateHandle};
+use mpris_impl::{FailedSet, MprisImpl};
 use mpris_server::{
-  Property, Server, Signal,
+  PlayerInterface, Property, Server, Signal,
   zbus::{self},
 };
+use seeked_timer::SeekedTimer;
 use smol::{
-  Executor,
-  channel::{self, Receiver},
+  Executor, Timer,
+  channel::{self, Receiver, Sender},
+  future,
 };
 use thiserror::Error;
 
 mod conversions;
 mod mpris_impl;
+mod seeked_timer;
 
 #[derive(Debug, Error)]
 pub enum MprisServerError {
@@ -26,30 +33,160 @@ pub enum MprisServerError {
   EventChannelClosed,
 }
 
+/// Controls when the `Seeked` signal is emitted in addition to after seeking
+///
+/// Some clients (such as KDE's media controller) only update their position slider when they receive `Seeked`
+#[derive(Debug, Clone, Default)]
+pub struct MprisOptions {
+  /// Emit `Seeked` with the current position this often while playing
+  pub seeked_interval: Option<Duration>,
+  /// Emit `Seeked` with a position of zero when the current track changes
+  pub seeked_on_track_change: bool,
+}
+
 pub struct MprisPlugin<Tx> {
   server: Server<MprisImpl<Tx>>,
+  options: MprisOptions,
+  shared_state: SharedStateHandle,
 
-  quit_rx: Receiver<()>,
+  state_changed_tx: Sender<()>,
+  state_changed_rx: Receiver<()>,
+  failed_set_rx: Receiver<FailedSet>,
 }
 
 impl<Tx> MprisPlugin<Tx> {
   pub const BUS_NAME: &str = "dev.djlaser.HomeSlashMusic";
 }
 
+impl<Tx: RequestSender + Send + Sync + 'static> MprisPlugin<Tx> {
+  async fn emit_seeked(&self, position: Duration) -> Result<(), MprisServerError> {
+    self
+      .server
+      .emit(Signal::Seeked {
+        position: as_dbus_time(position),
+      })
+      .await?;
+
+    Ok(())
+  }
+
+  async fn emit_metadata(&self) -> Result<(), MprisServerError> {
+    match self.server.imp().metadata().await {
+      Ok(metadata) => {
+        self
+          .server
+          .properties_changed([Property::Metadata(metadata)])
+          .await?
+      }
+      Err(error) => eprintln!("Could not query the current track's metadata: {error}"),
+    }
+
+    Ok(())
+  }
+
+  /// Emits the real value of properties that clients failed to set, so their widgets don't show the rejected value
+  async fn emit_failed_sets(&self) -> Result<(), MprisServerError> {
+    let imp = self.server.imp();
+
+    // The `MprisImpl` holds the sender, so the channel can't close
+    while let Ok(failed_set) = self.failed_set_rx.recv().await {
+      let property = match failed_set {
+        FailedSet::LoopStatus => imp.loop_status().await.map(Property::LoopStatus),
+        FailedSet::Shuffle => imp.shuffle().await.map(Property::Shuffle),
+        FailedSet::Volume => imp.volume().await.map(Property::Volume),
+        FailedSet::Rate => imp.rate().await.map(Property::Rate),
+      };
+
+      match property {
+        Ok(property) => self.server.properties_changed([property]).await?,
+        Err(error) => {
+          eprintln!("Could not query {failed_set:?} after it failed to be set: {error}")
+        }
+      }
+    }
+
+    Ok(())
+  }
+
+  /// Emits `Seeked` every `seeked_interval` while playing
+  ///
+  /// The timer restarts whenever the playback state changes, and waits without ticking while paused or stopped
+  async fn emit_periodic_seeked(&self) -> Result<(), MprisServerError> {
+    let Some(interval) = self.options.seeked_interval else {
+      return future::pending().await;
+    };
+
+    let mut timer = SeekedTimer::new(interval, self.shared_state.playback_state(), Instant::now());
+
+    loop {
+      let state_changed = async {
+        // The plugin holds the sender, so the channel can't close
+        let _ = self.state_changed_rx.recv().await;
+        true
+      };
+
+      let state_changed = match timer.deadline() {
+        Some(deadline) => {
+          (
+            async {
+              Timer::at(deadline).await;
+              false
+            },
+            state_changed,
+          )
+            .race()
+            .await
+        }
+        None => state_changed.await,
+      };
+
+      let now = Instant::now();
+      if state_changed {
+        timer.state_changed(self.shared_state.playback_state(), now);
+      } else if timer.tick(now) {
+        self.emit_seeked(self.shared_state.position()).await?;
+      }
+    }
+  }
+}
+
 impl<'ex, Tx: RequestSender + Send + Sync + 'static> Plugin<'ex, Tx> for MprisPlugin<Tx> {
   type Error = MprisServerError;
+  type Config = MprisOptions;
+  const NAME: &'static str = "mpris";
 
-  async fn init(request_tx: Tx, _ex: Arc<Executor<'ex>>) -> Result<Self, Self::Error> {
-    let (quit_tx, quit_rx) = channel::bounded(1);
+  async fn init(
+    options: MprisOptions,
+    request_tx: Tx,
+    shared_state: SharedStateHandle,
+    _ex: Arc<Executor<'ex>>,
+  ) -> Result<Self, Self::Error> {
+    let (state_changed_tx, state_changed_rx) = channel::bounded(1);
+    let (failed_set_tx, failed_set_rx) = channel::unbounded();
 
-    let server = Server::new(Self::BUS_NAME, MprisImpl::new(request_tx, quit_tx)).await?;
+    let server = Server::new(
+      Self::BUS_NAME,
+      MprisImpl::new(request_tx, shared_state.clone(), failed_set_tx),
+    )
+    .await?;
 
-    Ok(Self { server, quit_rx })
+    Ok(Self {
+      server,
+      options,
+      shared_state,
+
+      state_changed_tx,
+      state_changed_rx,
+      failed_set_rx,
+    })
   }
 
   async fn on_event(&self, event: Event) -> Result<(), Self::Error> {
     match event {
       Event::PlaybackStateChanged(playback_state) => {
+        // If the channel is full, the periodic timer will already be restarted
+        let _ = self.state_changed_tx.try_send(());
+
         self
           .server
           .properties_changed([Property::PlaybackStatus(as_playback_status(playback_state))])
@@ -73,23 +210,44 @@ impl<'ex, Tx: RequestSender + Send + Sync + 'static> Plugin<'ex, Tx> for MprisPl
           .properties_changed([Property::Volume(volume.into())])
           .await?;
       }
-      Event::Seeked(position) => {
+      Event::RateChanged(rate) => {
         self
           .server
-          .emit(Signal::Seeked {
-            position: as_dbus_time(position),
-          })
+          .properties_changed([Property::Rate(rate.into())])
           .await?;
       }
+      Event::Seeked(position) => self.emit_seeked(position).await?,
+      // The event has the track at the current index, which is not the one shown after the end of the track list
+      Event::TrackChanged(_) => {
+        self.emit_metadata().await?;
+
+        if self.options.seeked_on_track_change {
+          self.emit_seeked(Duration::ZERO).await?;
+        }
+      }
+      Event::TrackListChanged { .. } => self.emit_metadata().await?,
+      // The metadata stays on a detached track until `TrackChanged`, like the audio
+      Event::PlaybackStopped(_)
+      | Event::CurrentTrackRemoved { .. }
+      | Event::TrackEnding { .. }
+      | Event::LyricLine { .. }
+      | Event::LoadProgress { .. }
+      | Event::EndBehaviorChanged(_)
+      | Event::StopAfterCurrentChanged(_)
+      | Event::QueueFilterChanged(_)
+      | Event::OutputFormatChanged(_)
+      | Event::OutputReconnected(_)
+      | Event::PlayerStalled { .. }
+      | Event::InterruptStarted { .. }
+      | Event::InterruptFinished => (),
     }
 
     Ok(())
   }
 
   async fn run(&self) -> Result<(), Self::Error> {
-    let _ = self.quit_rx.recv().await;
-    println!("Recieved MPRIS Quit command");
-
-    Ok(())
+    (self.emit_periodic_seeked(), self.emit_failed_sets())
+      .race()
+      .await
   }
 }
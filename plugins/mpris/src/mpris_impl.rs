@@ -1,39 +1,55 @@
-use hsm_ipc::{InsertPosition, Request, SeekPosition, requests};
-use hsm_plugin::RequestSender;
+use hsm_ipc::{InsertPosition, InsertShufflePolicy, Request, SeekPosition, requests};
+use hsm_plugin::{RequestSender, SharedStateHandle};
 use mpris_server::{
   PlayerInterface, RootInterface,
   zbus::{self, fdo},
 };
-use smol::channel::{self, Sender};
+use smol::channel::Sender;
 
 use super::conversions::{
-  as_dbus_time, as_loop_status, as_playback_status, decode_file_url, from_dbus_time,
-  from_loop_status, generate_metadata,
+  MprisError, as_dbus_time, as_loop_status, as_playback_status, current_track_metadata,
+  decode_file_url, from_dbus_rate, from_dbus_time, from_dbus_volume, from_loop_status,
 };
 
+/// A settable property, sent to the plugin when setting it fails
+/// so clients can be told its real value instead of showing the one that was rejected
+#[derive(Debug, Clone, Copy)]
+pub enum FailedSet {
+  LoopStatus,
+  Shuffle,
+  Volume,
+  Rate,
+}
+
 pub struct MprisImpl<Tx> {
   request_tx: Tx,
-  quit_tx: Sender<()>,
+  /// Position, playback status, and volume are read from here instead of sending a request
+  shared_state: SharedStateHandle,
+  failed_set_tx: Sender<FailedSet>,
 }
 
 impl<Tx> MprisImpl<Tx> {
-  pub fn new(request_tx: Tx, quit_tx: channel::Sender<()>) -> Self {
+  pub fn new(
+    request_tx: Tx,
+    shared_state: SharedStateHandle,
+    failed_set_tx: Sender<FailedSet>,
+  ) -> Self {
     Self {
       request_tx,
-      quit_tx,
+      shared_state,
+      failed_set_tx,
     }
   }
 
   fn unsupported<T>(message: &str) -> fdo::Result<T> {
-    Err(fdo::Error::NotSupported(message.into()))
+    Err(MprisError::NotSupported(message.into()).into())
   }
 
-  fn unsupported_set<T>(message: &str) -> zbus::Result<T> {
-    Self::unsupported(message).map_err(zbus::Error::from)
-  }
-
-  fn channel_closed_error<T>(_t: T) -> fdo::Error {
-    fdo::Error::Failed("Channel was unexpectedly closed".into())
+  /// Converts a failed property set into a `zbus::Error`, and has the plugin emit the property's real value
+  fn set_failed(&self, property: FailedSet, error: fdo::Error) -> zbus::Error {
+    // The plugin holds the receiver for as long as the server exists
+    let _ = self.failed_set_tx.try_send(property);
+    error.into()
   }
 }
 
@@ -46,7 +62,7 @@ impl<Tx: RequestSender + Send + Sync> MprisImpl<Tx> {
       .request_tx
       .send_request(request)
       .await
-      .map_err(Self::channel_closed_error)
+      .map_err(|error| MprisError::Failed(error).into())
   }
 }
 
@@ -56,13 +72,8 @@ impl<Tx: RequestSender + Send + Sync> RootInterface for MprisImpl<Tx> {
   }
 
   async fn quit(&self) -> fdo::Result<()> {
-    match self.quit_tx.try_send(()) {
-      Ok(()) => Ok(()),
-      Err(channel::TrySendError::Closed(e)) => Err(Self::channel_closed_error(e)),
-
-      // If the channel is full, a quit message was already sent
-      Err(channel::TrySendError::Full(_)) => Ok(()),
-    }
+    println!("Recieved MPRIS Quit command");
+    self.try_send(requests::Shutdown).await
   }
 
   async fn can_quit(&self) -> fdo::Result<bool> {
@@ -74,7 +85,7 @@ impl<Tx: RequestSender + Send + Sync> RootInterface for MprisImpl<Tx> {
   }
 
   async fn set_fullscreen(&self, _fullscreen: bool) -> zbus::Result<()> {
-    Self::unsupported_set("Fullscreen is not supported")
+    Ok(Self::unsupported("Fullscreen is not supported")?)
   }
 
   async fn can_set_fullscreen(&self) -> fdo::Result<bool> {
@@ -113,11 +124,16 @@ impl<Tx: RequestSender + Send + Sync> RootInterface for MprisImpl<Tx> {
 
 impl<Tx: RequestSender + Send + Sync> PlayerInterface for MprisImpl<Tx> {
   async fn next(&self) -> fdo::Result<()> {
-    self.try_send(requests::NextTrack).await
+    self.try_send(requests::NextTrack(None)).await
   }
 
   async fn previous(&self) -> fdo::Result<()> {
-    self.try_send(requests::PreviousTrack { soft: true }).await
+    self
+      .try_send(requests::PreviousTrack {
+        soft: true,
+        count: None,
+      })
+      .await
   }
 
   async fn pause(&self) -> fdo::Result<()> {
@@ -166,11 +182,18 @@ impl<Tx: RequestSender + Send + Sync> PlayerInterface for MprisImpl<Tx> {
   async fn open_uri(&self, uri: String) -> fdo::Result<()> {
     if let Some(file_path) = decode_file_url(uri) {
       let errors = self
-        .try_send(requests::LoadTracks(InsertPosition::End, vec![file_path]))
+        .try_send(requests::LoadTracks {
+          position: InsertPosition::End,
+          paths: vec![file_path],
+          expected_generation: None,
+          force: false,
+          progress_id: None,
+          shuffle_policy: InsertShufflePolicy::Scatter,
+        })
         .await?;
 
       match errors.first() {
-        Some((_path, error)) => Err(fdo::Error::Failed(error.to_string())),
+        Some((_path, error)) => Err(MprisError::Failed(error.to_string()).into()),
         None => Ok(()),
       }
     } else {
@@ -179,8 +202,7 @@ impl<Tx: RequestSender + Send + Sync> PlayerInterface for MprisImpl<Tx> {
   }
 
   async fn playback_status(&self) -> fdo::Result<mpris_server::PlaybackStatus> {
-    let playback_state = self.try_send(requests::QueryPlaybackState).await?;
-    Ok(as_playback_status(playback_state))
+    Ok(as_playback_status(self.shared_state.playback_state()))
   }
 
   async fn loop_status(&self) -> fdo::Result<mpris_server::LoopStatus> {
@@ -192,21 +214,25 @@ impl<Tx: RequestSender + Send + Sync> PlayerInterface for MprisImpl<Tx> {
     self
       .try_send(requests::SetLoopMode(from_loop_status(loop_status)))
       .await
-      .map_err(zbus::Error::from)
+      .map_err(|error| self.set_failed(FailedSet::LoopStatus, error))
   }
 
   async fn rate(&self) -> fdo::Result<mpris_server::PlaybackRate> {
-    Ok(1.0)
+    Ok(self.shared_state.rate().into())
   }
 
   async fn set_rate(&self, rate: mpris_server::PlaybackRate) -> zbus::Result<()> {
-    if rate == 0.0 {
-      self.pause().await?;
-    } else if rate != 1.0 {
-      Self::unsupported_set("Unsupported rate")?
-    }
+    // MPRIS treats a rate of 0 as pausing
+    let result = if rate == 0.0 {
+      self.pause().await
+    } else {
+      match from_dbus_rate(rate) {
+        Ok(rate) => self.try_send(requests::SetRate(rate)).await,
+        Err(error) => Err(error.into()),
+      }
+    };
 
-    Ok(())
+    result.map_err(|error| self.set_failed(FailedSet::Rate, error))
   }
 
   async fn shuffle(&self) -> fdo::Result<bool> {
@@ -217,47 +243,39 @@ impl<Tx: RequestSender + Send + Sync> PlayerInterface for MprisImpl<Tx> {
     self
       .try_send(requests::SetShuffle(shuffle))
       .await
-      .map_err(zbus::Error::from)
+      .map_err(|error| self.set_failed(FailedSet::Shuffle, error))
   }
 
   async fn metadata(&self) -> fdo::Result<mpris_server::Metadata> {
-    let metadata = match self.try_send(requests::QueryCurrentTrack).await? {
-      Some(track) => generate_metadata(&track),
-      None => mpris_server::Metadata::builder()
-        .trackid(mpris_server::TrackId::NO_TRACK)
-        .build(),
-    };
-
-    Ok(metadata)
+    // While stopped this is the track playback starts with, so it is only empty if the track list is
+    let entry = self.try_send(requests::QueryCurrentEntry).await?;
+    Ok(current_track_metadata(entry.up_next.as_ref()))
   }
 
   async fn volume(&self) -> fdo::Result<mpris_server::Volume> {
-    self
-      .try_send(requests::QueryVolume)
-      .await
-      .map(|volume| volume.into())
+    Ok(self.shared_state.volume().into())
   }
 
   async fn set_volume(&self, volume: mpris_server::Volume) -> zbus::Result<()> {
+    let volume =
+      from_dbus_volume(volume).map_err(|error| self.set_failed(FailedSet::Volume, error.into()))?;
+
     self
-      .try_send(requests::SetVolume(volume as f32))
+      .try_send(requests::SetVolume(volume))
       .await
-      .map_err(zbus::Error::from)
+      .map_err(|error| self.set_failed(FailedSet::Volume, error))
   }
 
   async fn position(&self) -> fdo::Result<mpris_server::Time> {
-    self
-      .try_send(requests::QueryPosition)
-      .await
-      .map(as_dbus_time)
+    Ok(as_dbus_time(self.shared_state.position()))
   }
 
   async fn minimum_rate(&self) -> fdo::Result<mpris_server::PlaybackRate> {
-    Ok(1.0)
+    Ok(hsm_ipc::MIN_RATE.into())
   }
 
   async fn maximum_rate(&self) -> fdo::Result<mpris_server::PlaybackRate> {
-    Ok(1.0)
+    Ok(hsm_ipc::MAX_RATE.into())
   }
 
   async fn can_go_next(&self) -> fdo::Result<bool> {
@@ -0,0 +1,92 @@
+use std::time::{Duration, Instant};
+
+use hsm_ipc::PlaybackState;
+
+/// Decides when the periodic `Seeked` is due, separate from the plugin so it can be tested with any clock
+#[derive(Debug)]
+pub struct SeekedTimer {
+  interval: Duration,
+  /// When the next `Seeked` should be emitted, `None` while paused or stopped
+  next_at: Option<Instant>,
+}
+
+impl SeekedTimer {
+  pub fn new(interval: Duration, playback_state: PlaybackState, now: Instant) -> Self {
+    let mut timer = Self {
+      interval,
+      next_at: None,
+    };
+    timer.state_changed(playback_state, now);
+
+    timer
+  }
+
+  /// Restarts the interval if playing, or stops the timer otherwise
+  pub fn state_changed(&mut self, playback_state: PlaybackState, now: Instant) {
+    self.next_at = (playback_state == PlaybackState::Playing).then(|| now + self.interval);
+  }
+
+  /// When the timer should wake next, it should only wake when the playback state changes if this is `None`
+  pub fn deadline(&self) -> Option<Instant> {
+    self.next_at
+  }
+
+  /// Returns true if `Seeked` should be emitted now, and schedules the next one
+  pub fn tick(&mut self, now: Instant) -> bool {
+    match self.next_at {
+      Some(next_at) if now >= next_at => {
+        self.next_at = Some(now + self.interval);
+        true
+      }
+      _ => false,
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const SECOND: Duration = Duration::from_secs(1);
+
+  #[test]
+  fn ticks_every_interval_while_playing() {
+    let start = Instant::now();
+    let mut timer = SeekedTimer::new(2 * SECOND, PlaybackState::Playing, start);
+    assert_eq!(timer.deadline(), Some(start + 2 * SECOND));
+
+    assert!(!timer.tick(start + SECOND));
+    assert!(timer.tick(start + 2 * SECOND));
+    assert_eq!(timer.deadline(), Some(start + 4 * SECOND));
+
+    // A late wake counts the next interval from when it happened
+    assert!(timer.tick(start + 5 * SECOND));
+    assert_eq!(timer.deadline(), Some(start + 7 * SECOND));
+  }
+
+  #[test]
+  fn waits_without_ticking_while_not_playing() {
+    let start = Instant::now();
+
+    for playback_state in [PlaybackState::Paused, PlaybackState::Stopped] {
+      let mut timer = SeekedTimer::new(SECOND, playback_state, start);
+      assert_eq!(timer.deadline(), None);
+      assert!(!timer.tick(start + 10 * SECOND));
+    }
+  }
+
+  #[test]
+  fn state_changes_restart_the_interval() {
+    let start = Instant::now();
+    let mut timer = SeekedTimer::new(2 * SECOND, PlaybackState::Playing, start);
+
+    timer.state_changed(PlaybackState::Paused, start + SECOND);
+    assert_eq!(timer.deadline(), None);
+    assert!(!timer.tick(start + 3 * SECOND));
+
+    // Resuming starts a full interval
+    timer.state_changed(PlaybackState::Playing, start + 4 * SECOND);
+    assert!(!timer.tick(start + 5 * SECOND));
+    assert!(timer.tick(start + 6 * SECOND));
+  }
+}
@@ -0,0 +1,435 @@
+use std::{
+  fs,
+  path::{Path, PathBuf},
+  sync::Arc,
+  time::Duration,
+};
+
+use hsm_client::{
+  now_playing::{FormatError, NowPlaying},
+  track_list::TrackList,
+};
+use hsm_ipc::{Event, PlaybackState, Request, Track, requests};
+use hsm_plugin::{Plugin, RequestSender, SharedStateHandle};
+use serde::Serialize;
+use smol::{
+  Executor,
+  channel::{self, Receiver, Sender},
+  io,
+};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum StatusFileError {
+  #[error("Invalid status file format: {0}")]
+  InvalidFormat(#[from] FormatError),
+
+  #[error("Failed to create status directory {path:?}: {source}")]
+  CreateDirFailed { path: PathBuf, source: io::Error },
+}
+
+#[derive(Debug, Clone)]
+pub struct StatusFileOptions {
+  /// Format of the now playing line, see `NowPlaying::format`
+  pub format: String,
+}
+
+#[derive(Debug, Serialize)]
+struct QueueFile {
+  current_index: usize,
+  tracks: Vec<BriefTrack>,
+}
+
+#[derive(Debug, Serialize)]
+struct BriefTrack {
+  title: Option<String>,
+  artists: Vec<String>,
+  album: Option<String>,
+  file_path: PathBuf,
+}
+
+impl From<&Track> for BriefTrack {
+  fn from(track: &Track) -> Self {
+    let metadata = &track.metadata;
+
+    Self {
+      title: metadata.title_or_inferred().map(Into::into),
+      artists: metadata
+        .artists_or_inferred()
+        .into_iter()
+        .map(Into::into)
+        .collect(),
+      album: metadata.album_or_inferred().map(Into::into),
+      file_path: track.file_path.clone(),
+    }
+  }
+}
+
+/// Writes the now playing line to `$XDG_RUNTIME_DIR/homeslashmusic/status`
+/// and the track list to `$XDG_RUNTIME_DIR/homeslashmusic/queue.json`, for status bars that can only read files
+pub struct StatusFilePlugin<Tx> {
+  dir: PathBuf,
+  format: String,
+  request_tx: Tx,
+
+  dirty_tx: Sender<()>,
+  dirty_rx: Receiver<()>,
+}
+
+impl<Tx> StatusFilePlugin<Tx> {
+  /// Events are collected for this long before the files are rewritten
+  const DEBOUNCE: Duration = Duration::from_millis(100);
+
+  fn status_path(&self) -> PathBuf {
+    self.dir.join("status")
+  }
+
+  fn queue_path(&self) -> PathBuf {
+    self.dir.join("queue.json")
+  }
+
+  fn mark_dirty(&self) {
+    // If the channel is full, the files are already waiting to be rewritten
+    let _ = self.dirty_tx.try_send(());
+  }
+
+  fn cleanup_files(&self) {
+    let _ = fs::remove_file(self.status_path());
+    let _ = fs::remove_file(self.queue_path());
+    let _ = fs::remove_dir(&self.dir);
+    println!("Removing status files: {:?}", self.dir);
+  }
+}
+
+/// Writes to a temporary file and renames it over `path`, so readers never see a partially written file
+async fn write_atomic(path: &Path, contents: &[u8]) -> io::Result<()> {
+  let tmp_path = path.with_extension("tmp");
+  smol::fs::write(&tmp_path, contents).await?;
+  smol::fs::rename(&tmp_path, path).await
+}
+
+impl<Tx: RequestSender + Send + Sync> StatusFilePlugin<Tx> {
+  async fn query<R: Request>(&self, request: R) -> Option<R::Response>
+  where
+    R::Response: Send,
+  {
+    match self.request_tx.send_request(request).await {
+      Ok(response) => Some(response),
+      Err(error) => {
+        eprintln!("Status file could not query the server: {error}");
+        None
+      }
+    }
+  }
+
+  async fn write_status(&self) -> io::Result<()> {
+    let (Some(track), Some(playback_state)) = (
+      self.query(requests::QueryCurrentTrack).await,
+      self.query(requests::QueryPlaybackState).await,
+    ) else {
+      return Ok(());
+    };
+
+    let mut status = match (&track, playback_state) {
+      (None, _) | (_, PlaybackState::Stopped) => String::new(),
+      (Some(track), playback_state) => NowPlaying::new(Some(track), playback_state)
+        .format(&self.format)
+        .expect("The format should be validated when the plugin is initialized"),
+    };
+    status.push('\n');
+
+    write_atomic(&self.status_path(), status.as_bytes()).await
+  }
+
+  async fn write_queue(&self) -> io::Result<()> {
+    let (Some(snapshot), Some(current_index)) = (
+      self.query(requests::QueryTrackList).await,
+      self.query(requests::QueryCurrentTrackIndex).await,
+    ) else {
+      return Ok(());
+    };
+
+    let track_list = TrackList::from_snapshot(snapshot);
+    let queue = QueueFile {
+      current_index,
+      tracks: track_list.iter().map(BriefTrack::from).collect(),
+    };
+
+    let queue_data = serde_json::to_vec(&queue).expect("The queue should not fail to serialize");
+    write_atomic(&self.queue_path(), &queue_data).await
+  }
+
+  async fn write_files(&self) {
+    if let Err(error) = self.write_status().await {
+      eprintln!("Failed to write {:?}: {error}", self.status_path());
+    }
+
+    if let Err(error) = self.write_queue().await {
+      eprintln!("Failed to write {:?}: {error}", self.queue_path());
+    }
+  }
+}
+
+impl<'ex, Tx: RequestSender + Send + Sync + 'ex> Plugin<'ex, Tx> for StatusFilePlugin<Tx> {
+  type Error = StatusFileError;
+  type Config = StatusFileOptions;
+  const NAME: &'static str = "statusfile";
+
+  async fn init(
+    options: StatusFileOptions,
+    request_tx: Tx,
+    _shared_state: SharedStateHandle,
+    _executor: Arc<Executor<'ex>>,
+  ) -> Result<Self, Self::Error> {
+    NowPlaying::validate_format(&options.format)?;
+
+    let dir = Path::new(hsm_ipc::runtime_dir()).join("homeslashmusic");
+    fs::create_dir_all(&dir).map_err(|source| StatusFileError::CreateDirFailed {
+      path: dir.clone(),
+      source,
+    })?;
+
+    let (dirty_tx, dirty_rx) = channel::bounded(1);
+
+    Ok(Self {
+      dir,
+      format: options.format,
+      request_tx,
+
+      dirty_tx,
+      dirty_rx,
+    })
+  }
+
+  async fn on_event(&self, event: Event) -> Result<(), Self::Error> {
+    match event {
+      Event::PlaybackStateChanged(_) | Event::PlaybackStopped(_) | Event::TrackChanged(_) => {
+        self.mark_dirty()
+      }
+      _ => (),
+    }
+
+    Ok(())
+  }
+
+  async fn run(&self) -> Result<(), Self::Error> {
+    self.write_files().await;
+
+    loop {
+      // The plugin holds the sender, so the channel can't close
+      let _ = self.dirty_rx.recv().await;
+
+      smol::Timer::after(Self::DEBOUNCE).await;
+      let _ = self.dirty_rx.try_recv();
+
+      self.write_files().await;
+    }
+  }
+}
+
+impl<Tx> Drop for StatusFilePlugin<Tx> {
+  fn drop(&mut self) {
+    self.cleanup_files();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{
+    fs::File,
+    io::Read,
+    sync::{Arc, Mutex},
+  };
+
+  use hsm_ipc::{
+    StopReason, TrackListSnapshot, TrackMetadata, client::serialize_request, server::RequestOrigin,
+  };
+  use smol::future;
+  use tempfile::TempDir;
+
+  use super::*;
+
+  const FORMAT: &str = "{artist} - {title|filename}";
+
+  struct ServerState {
+    tracks: Vec<Track>,
+    current_index: usize,
+    playback_state: PlaybackState,
+  }
+
+  /// Answers the queries the plugin sends from a fake player state
+  #[derive(Clone)]
+  struct FakeServer(Arc<Mutex<ServerState>>);
+
+  impl FakeServer {
+    fn set(&self, current_index: usize, playback_state: PlaybackState) {
+      let mut state = self.0.lock().unwrap();
+      state.current_index = current_index;
+      state.playback_state = playback_state;
+    }
+  }
+
+  fn reply(response: impl Serialize) -> String {
+    let mut reply_data = serde_json::to_string(&Ok::<_, String>(response)).unwrap();
+    reply_data.push('\n');
+    reply_data
+  }
+
+  impl RequestSender for FakeServer {
+    fn send_json_from(
+      &self,
+      _origin: RequestOrigin,
+      request_data: String,
+    ) -> impl Future<Output = String> + Send + Sync {
+      let state = self.0.lock().unwrap();
+      let reply_data = if request_data == serialize_request(requests::QueryCurrentTrack) {
+        reply(state.tracks.get(state.current_index))
+      } else if request_data == serialize_request(requests::QueryPlaybackState) {
+        reply(state.playback_state)
+      } else if request_data == serialize_request(requests::QueryCurrentTrackIndex) {
+        reply(state.current_index)
+      } else if request_data == serialize_request(requests::QueryTrackList) {
+        reply(TrackListSnapshot {
+          track_list: state.tracks.clone(),
+          shuffle_indicies: (0..state.tracks.len()).collect(),
+          instances: Vec::new(),
+          generation: 0,
+        })
+      } else {
+        panic!("Unexpected request {request_data}");
+      };
+
+      async { reply_data }
+    }
+  }
+
+  fn track(title: &str) -> Track {
+    Track {
+      file_path: format!("/music/{title}.flac").into(),
+      total_duration: None,
+      metadata: TrackMetadata {
+        title: Some(title.into()),
+        artists: vec!["Artist".into()],
+        ..Default::default()
+      },
+    }
+  }
+
+  /// A plugin writing to a temporary directory, with the first of two tracks playing
+  fn plugin() -> (StatusFilePlugin<FakeServer>, FakeServer, TempDir) {
+    let server = FakeServer(Arc::new(Mutex::new(ServerState {
+      tracks: vec![track("First"), track("Second")],
+      current_index: 0,
+      playback_state: PlaybackState::Playing,
+    })));
+    let dir = tempfile::tempdir().unwrap();
+    let (dirty_tx, dirty_rx) = channel::bounded(1);
+
+    let plugin = StatusFilePlugin {
+      dir: dir.path().join("homeslashmusic"),
+      format: FORMAT.into(),
+      request_tx: server.clone(),
+      dirty_tx,
+      dirty_rx,
+    };
+    fs::create_dir(&plugin.dir).unwrap();
+
+    (plugin, server, dir)
+  }
+
+  /// Polls `path` until it holds `expected`, panicking if it doesn't in time
+  async fn wait_for_contents(path: &Path, expected: &str) {
+    future::or(
+      async {
+        while fs::read_to_string(path).ok().as_deref() != Some(expected) {
+          smol::Timer::after(Duration::from_millis(5)).await;
+        }
+      },
+      async {
+        smol::Timer::after(Duration::from_secs(5)).await;
+        panic!(
+          "{path:?} holds {:?} instead of {expected:?}",
+          fs::read_to_string(path)
+        );
+      },
+    )
+    .await
+  }
+
+  /// Runs `test` while the plugin rewrites its files
+  fn run(plugin: &StatusFilePlugin<FakeServer>, test: impl Future<Output = ()>) {
+    smol::block_on(future::or(
+      async {
+        let _ = plugin.run().await;
+        panic!("The plugin stopped running");
+      },
+      test,
+    ))
+  }
+
+  #[test]
+  fn write_atomic_replaces_the_whole_file() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("status");
+    fs::write(&path, "Old status\n").unwrap();
+    let mut old_file = File::open(&path).unwrap();
+
+    smol::block_on(write_atomic(&path, b"New status\n")).unwrap();
+
+    // The old file is replaced rather than rewritten, so a reader that opened it still reads all of it
+    let mut old_contents = String::new();
+    old_file.read_to_string(&mut old_contents).unwrap();
+    assert_eq!(old_contents, "Old status\n");
+    assert_eq!(fs::read_to_string(&path).unwrap(), "New status\n");
+    assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+  }
+
+  #[test]
+  fn files_follow_track_changes() {
+    let (plugin, server, _dir) = plugin();
+    run(&plugin, async {
+      wait_for_contents(&plugin.status_path(), "Artist - First\n").await;
+      let queue: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(plugin.queue_path()).unwrap()).unwrap();
+      assert_eq!(queue["current_index"], 0);
+      assert_eq!(queue["tracks"][1]["title"], "Second");
+      assert_eq!(queue["tracks"][1]["file_path"], "/music/Second.flac");
+
+      server.set(1, PlaybackState::Playing);
+      plugin
+        .on_event(Event::TrackChanged(Some(track("Second"))))
+        .await
+        .unwrap();
+      wait_for_contents(&plugin.status_path(), "Artist - Second\n").await;
+      let queue: serde_json::Value =
+        serde_json::from_str(&fs::read_to_string(plugin.queue_path()).unwrap()).unwrap();
+      assert_eq!(queue["current_index"], 1);
+    });
+  }
+
+  #[test]
+  fn status_is_empty_when_stopped() {
+    let (plugin, server, _dir) = plugin();
+    run(&plugin, async {
+      wait_for_contents(&plugin.status_path(), "Artist - First\n").await;
+
+      server.set(0, PlaybackState::Stopped);
+      plugin
+        .on_event(Event::PlaybackStopped(StopReason::UserRequested))
+        .await
+        .unwrap();
+      wait_for_contents(&plugin.status_path(), "\n").await;
+    });
+  }
+
+  #[test]
+  fn dropping_removes_the_files() {
+    let (plugin, _server, dir) = plugin();
+    smol::block_on(plugin.write_files());
+    assert!(plugin.status_path().exists());
+    assert!(plugin.queue_path().exists());
+
+    drop(plugin);
+    assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+  }
+}